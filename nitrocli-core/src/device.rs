@@ -0,0 +1,321 @@
+// device.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Selecting and connecting to devices.
+
+use std::ffi;
+use std::fmt;
+use std::os::raw;
+use std::result;
+
+use nitrokey::Device;
+
+use crate::Error;
+use crate::Result;
+
+/// A model of Nitrokey devices.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Model {
+  /// The Nitrokey Pro.
+  Pro,
+  /// The Nitrokey Storage.
+  Storage,
+}
+
+impl Model {
+  /// Determine the model of the given device.
+  pub fn of(device: &nitrokey::DeviceWrapper) -> Self {
+    match device {
+      nitrokey::DeviceWrapper::Pro(_) => Model::Pro,
+      nitrokey::DeviceWrapper::Storage(_) => Model::Storage,
+    }
+  }
+}
+
+impl fmt::Display for Model {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Model::Pro => write!(f, "Nitrokey Pro"),
+      Model::Storage => write!(f, "Nitrokey Storage"),
+    }
+  }
+}
+
+/// The criteria a device has to match to be connected to.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Selector {
+  /// The model of the device, if restricted.
+  pub model: Option<Model>,
+  /// The serial numbers one of which the device has to have, unless
+  /// empty.
+  pub serial_numbers: Vec<String>,
+}
+
+/// Normalize a serial number so that differently formatted
+/// representations of the same number compare equal.
+pub fn normalize_serial_number(serial: &str) -> String {
+  let serial = serial.trim().to_lowercase();
+  let serial = match serial.get(..2) {
+    Some("0x") => &serial[2..],
+    _ => &serial[..],
+  };
+  let serial = serial.trim_start_matches('0');
+  if serial.is_empty() {
+    "0".to_string()
+  } else {
+    serial.to_string()
+  }
+}
+
+/// Retrieve the serial number of the given device.
+pub fn serial_number<D>(device: &D) -> Result<String>
+where
+  D: Device,
+{
+  device
+    .get_serial_number()
+    .map_err(|err| Error::Command("Could not query the serial number", err))
+}
+
+/// Check that the given device has one of the selected serial numbers.
+fn check_serial_number<D>(selector: &Selector, device: &D) -> Result<()>
+where
+  D: Device,
+{
+  if selector.serial_numbers.is_empty() {
+    return Ok(());
+  }
+
+  let serial = serial_number(device)?;
+  let normalized = normalize_serial_number(&serial);
+  if selector
+    .serial_numbers
+    .iter()
+    .any(|s| normalize_serial_number(s) == normalized)
+  {
+    Ok(())
+  } else {
+    Err(Error::SerialNumberMismatch(serial))
+  }
+}
+
+/// Connect to a device matching the given selector.
+pub fn connect(selector: &Selector) -> Result<nitrokey::DeviceWrapper> {
+  let device = match selector.model {
+    Some(Model::Pro) => nitrokey::Pro::connect().map(nitrokey::DeviceWrapper::Pro),
+    Some(Model::Storage) => nitrokey::Storage::connect().map(nitrokey::DeviceWrapper::Storage),
+    None => nitrokey::connect(),
+  }
+  .map_err(|_| Error::NotFound)?;
+
+  check_serial_number(selector, &device)?;
+  Ok(device)
+}
+
+/// Connect to a Nitrokey Storage matching the given selector.
+pub fn connect_storage(selector: &Selector) -> Result<nitrokey::Storage> {
+  if selector.model == Some(Model::Pro) {
+    return Err(Error::UnsupportedModel(Model::Storage));
+  }
+
+  let device = nitrokey::Storage::connect().map_err(|_| Error::NotFound)?;
+  check_serial_number(selector, &device)?;
+  Ok(device)
+}
+
+extern "C" {
+  fn free(ptr: *mut raw::c_void);
+}
+
+/// Retrieve the IDs of the connected Nitrokey Storage devices.
+///
+/// libnitrokey can only tell apart multiple Nitrokey Storage devices,
+/// so Nitrokey Pro devices are not reported. The `nitrokey` crate does
+/// not provide this operation, so libnitrokey is used directly.
+pub fn list_storage_ids() -> Vec<String> {
+  let ptr = unsafe { nitrokey_sys::NK_list_devices_by_cpuID() };
+  if ptr.is_null() {
+    return Vec::new();
+  }
+  let ids = unsafe { ffi::CStr::from_ptr(ptr) }
+    .to_string_lossy()
+    .into_owned();
+  unsafe { free(ptr as *mut raw::c_void) };
+  ids
+    .split(';')
+    .filter(|id| !id.is_empty())
+    .map(ToString::to_string)
+    .collect()
+}
+
+/// Connect to the Nitrokey Storage with the given ID, as reported by
+/// `list_storage_ids`.
+///
+/// Only one device can be connected at a time, so any other connection
+/// has to be closed first.
+pub fn connect_storage_id(id: &str) -> Result<nitrokey::Storage> {
+  let id = ffi::CString::new(id).map_err(|_| Error::NotFound)?;
+  // libnitrokey only connects to devices it listed before.
+  let _ = list_storage_ids();
+  match unsafe { nitrokey_sys::NK_connect_with_ID(id.as_ptr()) } {
+    1 => Ok(nitrokey::Storage {}),
+    _ => Err(Error::NotFound),
+  }
+}
+
+/// Retrieve the status of the given Nitrokey Storage.
+pub fn storage_status(device: &nitrokey::Storage) -> Result<nitrokey::StorageStatus> {
+  device
+    .get_status()
+    .map_err(|err| Error::Command("Getting Storage status failed", err))
+}
+
+/// Convert the given PIN into a C string.
+fn pin_to_cstring(pin: &str) -> result::Result<ffi::CString, nitrokey::CommandError> {
+  ffi::CString::new(pin).map_err(|_| nitrokey::CommandError::InvalidString)
+}
+
+/// Convert the given return code of libnitrokey into a result.
+fn command_result(rc: raw::c_int) -> result::Result<(), nitrokey::CommandError> {
+  match rc {
+    0 => Ok(()),
+    rc => Err(nitrokey::CommandError::from(rc)),
+  }
+}
+
+/// Build a new AES key on the given device, using the given admin PIN.
+///
+/// The AES key protects the password safe and, on the Nitrokey Storage,
+/// the encrypted volumes, which become inaccessible once it is
+/// replaced. The `nitrokey` crate does not provide this operation, so
+/// libnitrokey is used directly. Errors are reported as the
+/// `nitrokey::CommandError` of the device, so that callers can tell a
+/// wrong PIN apart.
+pub fn build_aes_key<D>(_device: &D, admin_pin: &str) -> result::Result<(), nitrokey::CommandError>
+where
+  D: Device,
+{
+  let admin_pin = pin_to_cstring(admin_pin)?;
+  command_result(unsafe { nitrokey_sys::NK_build_aes_key(admin_pin.as_ptr()) })
+}
+
+/// Start filling the storage of the given Nitrokey Storage with random
+/// data, using the given admin PIN.
+///
+/// Filling the storage takes about an hour, during which the device
+/// does not process other commands. Errors are reported like for
+/// `build_aes_key`.
+pub fn fill_sd_card(
+  _device: &nitrokey::Storage,
+  admin_pin: &str,
+) -> result::Result<(), nitrokey::CommandError> {
+  let admin_pin = pin_to_cstring(admin_pin)?;
+  command_result(unsafe { nitrokey_sys::NK_fill_SD_card_with_random_data(admin_pin.as_ptr()) })
+}
+
+/// Retrieve the progress of the long-running operation the given
+/// Nitrokey Storage performs, such as filling its storage, in percent.
+///
+/// `None` is returned if the device is not busy.
+pub fn progress(_device: &nitrokey::Storage) -> Option<u8> {
+  match unsafe { nitrokey_sys::NK_get_progress_bar_value() } {
+    value @ 0..=100 => Some(value as u8),
+    _ => None,
+  }
+}
+
+/// Export the firmware of the given Nitrokey Storage to its
+/// unencrypted volume, using the given admin PIN.
+///
+/// The device writes the image of its firmware to a file on the
+/// unencrypted volume. Errors are reported like for `build_aes_key`.
+pub fn export_firmware(
+  _device: &nitrokey::Storage,
+  admin_pin: &str,
+) -> result::Result<(), nitrokey::CommandError> {
+  let admin_pin = pin_to_cstring(admin_pin)?;
+  command_result(unsafe { nitrokey_sys::NK_export_firmware(admin_pin.as_ptr()) })
+}
+
+/// Create a hidden volume in the given slot of the given Nitrokey
+/// Storage, protected by the given password.
+///
+/// The hidden volume spans the given range of the storage, in percent
+/// of its size, and is placed inside the encrypted volume, which has to
+/// be open. Errors are reported like for `build_aes_key`.
+pub fn create_hidden_volume(
+  _device: &nitrokey::Storage,
+  slot: u8,
+  start: u8,
+  end: u8,
+  password: &str,
+) -> result::Result<(), nitrokey::CommandError> {
+  let password = pin_to_cstring(password)?;
+  command_result(unsafe {
+    nitrokey_sys::NK_create_hidden_volume(slot, start, end, password.as_ptr())
+  })
+}
+
+/// Open the hidden volume of the given Nitrokey Storage that is
+/// protected by the given password.
+///
+/// The encrypted volume has to be open and is closed in favor of the
+/// hidden one. Errors are reported like for `build_aes_key`.
+pub fn unlock_hidden_volume(
+  _device: &nitrokey::Storage,
+  password: &str,
+) -> result::Result<(), nitrokey::CommandError> {
+  let password = pin_to_cstring(password)?;
+  command_result(unsafe { nitrokey_sys::NK_unlock_hidden_volume(password.as_ptr()) })
+}
+
+/// Close the open hidden volume of the given Nitrokey Storage.
+///
+/// Errors are reported like for `build_aes_key`.
+pub fn lock_hidden_volume(_device: &nitrokey::Storage) -> result::Result<(), nitrokey::CommandError> {
+  command_result(unsafe { nitrokey_sys::NK_lock_hidden_volume() })
+}
+
+/// Put the given Nitrokey Storage into update mode, using the given
+/// firmware password.
+///
+/// In update mode, the device no longer processes commands and
+/// identifies itself as the bootloader flashing software looks for,
+/// until the firmware is launched again. Errors are reported like for
+/// `build_aes_key`.
+pub fn enable_firmware_update(
+  _device: &nitrokey::Storage,
+  update_password: &str,
+) -> result::Result<(), nitrokey::CommandError> {
+  let update_password = pin_to_cstring(update_password)?;
+  command_result(unsafe { nitrokey_sys::NK_enable_firmware_update(update_password.as_ptr()) })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn normalize_serial_numbers() {
+    assert_eq!(normalize_serial_number("0x000012ab"), "12ab");
+    assert_eq!(normalize_serial_number("0X12AB"), "12ab");
+    assert_eq!(normalize_serial_number("12ab"), "12ab");
+    assert_eq!(normalize_serial_number("0x0000"), "0");
+  }
+}
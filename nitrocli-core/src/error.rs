@@ -0,0 +1,59 @@
+// error.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::fmt;
+
+use crate::device::Model;
+
+/// An error reported by an operation on a device.
+#[derive(Debug)]
+pub enum Error {
+  /// No device matching the selection is connected.
+  NotFound,
+  /// The operation is only available on devices of the given model.
+  UnsupportedModel(Model),
+  /// The connected device has a serial number other than the selected
+  /// ones.
+  SerialNumberMismatch(String),
+  /// There is no slot with the given name.
+  NoSuchSlot(String),
+  /// The device reported an error while performing the given
+  /// operation.
+  Command(&'static str, nitrokey::CommandError),
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::NotFound => write!(f, "Nitrokey device not found"),
+      Error::UnsupportedModel(model) => {
+        write!(f, "This command is only available on the {}", model)
+      }
+      Error::SerialNumberMismatch(serial) => write!(
+        f,
+        "Nitrokey device with serial number {} does not match any of the requested ones",
+        serial
+      ),
+      Error::NoSuchSlot(name) => write!(f, "No slot with name {} found", name),
+      Error::Command(operation, err) => write!(f, "{}: {:?}", operation, err),
+    }
+  }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,85 @@
+// lib.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+#![deny(
+  dead_code,
+  duplicate_associated_type_bindings,
+  illegal_floating_point_literal_pattern,
+  improper_ctypes,
+  intra_doc_link_resolution_failure,
+  late_bound_lifetime_arguments,
+  missing_copy_implementations,
+  missing_debug_implementations,
+  missing_docs,
+  no_mangle_generic_items,
+  non_shorthand_field_patterns,
+  overflowing_literals,
+  path_statements,
+  patterns_in_fns_without_body,
+  plugin_as_library,
+  private_in_public,
+  proc_macro_derive_resolution_fallback,
+  safe_packed_borrows,
+  stable_features,
+  trivial_bounds,
+  trivial_numeric_casts,
+  type_alias_bounds,
+  tyvar_behind_raw_pointer,
+  unconditional_recursion,
+  unions_with_drop_fields,
+  unreachable_code,
+  unreachable_patterns,
+  unstable_features,
+  unstable_name_collisions,
+  unused,
+  unused_comparisons,
+  unused_import_braces,
+  unused_lifetimes,
+  unused_qualifications,
+  unused_results,
+  where_clauses_object_safety,
+  while_true
+)]
+#![warn(
+  bad_style,
+  future_incompatible,
+  nonstandard_style,
+  renamed_and_removed_lints,
+  rust_2018_compatibility,
+  rust_2018_idioms
+)]
+
+//! Device operations of nitrocli, returning typed results.
+//!
+//! This crate provides the functionality of nitrocli that operates on
+//! Nitrokey devices, such as selecting a device by model and serial
+//! number, generating one-time passwords, and reading the password
+//! safe, to programs that want to embed it instead of running nitrocli
+//! and parsing its output. It builds on the `nitrokey` crate and does
+//! not interact with the user; PINs are passed in by the caller.
+
+pub mod device;
+mod error;
+pub mod otp;
+pub mod pws;
+
+pub use crate::error::Error;
+
+/// The result of an operation of this crate.
+pub type Result<T> = std::result::Result<T, Error>;
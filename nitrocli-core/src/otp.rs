@@ -0,0 +1,106 @@
+// otp.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Reading OTP slots and generating one-time passwords.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use nitrokey::GenerateOtp;
+
+use crate::Error;
+use crate::Result;
+
+/// The number of HOTP slots of a Nitrokey device.
+const HOTP_SLOT_COUNT: u8 = 3;
+/// The number of TOTP slots of a Nitrokey device.
+const TOTP_SLOT_COUNT: u8 = 15;
+
+/// An algorithm for generating one-time passwords.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+  /// The counter-based HOTP algorithm.
+  Hotp,
+  /// The time-based TOTP algorithm.
+  Totp,
+}
+
+impl Algorithm {
+  /// Retrieve the number of slots for this algorithm.
+  pub fn slot_count(self) -> u8 {
+    match self {
+      Algorithm::Hotp => HOTP_SLOT_COUNT,
+      Algorithm::Totp => TOTP_SLOT_COUNT,
+    }
+  }
+}
+
+impl fmt::Display for Algorithm {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Algorithm::Hotp => write!(f, "hotp"),
+      Algorithm::Totp => write!(f, "totp"),
+    }
+  }
+}
+
+/// Retrieve the name of the slot with the given number, or `None` if
+/// the slot is not programmed.
+pub fn slot_name<D>(device: &D, algorithm: Algorithm, slot: u8) -> Result<Option<String>>
+where
+  D: GenerateOtp,
+{
+  let name = match algorithm {
+    Algorithm::Hotp => device.get_hotp_slot_name(slot),
+    Algorithm::Totp => device.get_totp_slot_name(slot),
+  };
+  match name {
+    Ok(name) => Ok(Some(name)),
+    Err(nitrokey::CommandError::SlotNotProgrammed) => Ok(None),
+    Err(err) => Err(Error::Command("Could not read the OTP slot names", err)),
+  }
+}
+
+/// Retrieve the names of all programmed slots, indexed by slot number.
+pub fn slot_names<D>(device: &D, algorithm: Algorithm) -> Result<BTreeMap<u8, String>>
+where
+  D: GenerateOtp,
+{
+  let mut names = BTreeMap::new();
+  for slot in 0..algorithm.slot_count() {
+    if let Some(name) = slot_name(device, algorithm, slot)? {
+      let _ = names.insert(slot, name);
+    }
+  }
+  Ok(names)
+}
+
+/// Generate a one-time password using the slot with the given number.
+///
+/// For TOTP, the time of the device has to be set beforehand.
+pub fn generate<D>(device: &D, algorithm: Algorithm, slot: u8) -> Result<String>
+where
+  D: GenerateOtp,
+{
+  match algorithm {
+    Algorithm::Hotp => device.get_hotp_code(slot),
+    Algorithm::Totp => device.get_totp_code(slot),
+  }
+  .map_err(|err| Error::Command("Could not generate OTP", err))
+}
@@ -0,0 +1,109 @@
+// pws.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Reading the password safe.
+
+use std::collections::BTreeMap;
+
+use crate::Error;
+use crate::Result;
+
+/// An entry of the password safe.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+  /// The slot holding the entry.
+  pub slot: u8,
+  /// The name of the entry.
+  pub name: String,
+  /// The login of the entry.
+  pub login: String,
+  /// The password of the entry.
+  pub password: String,
+}
+
+/// Retrieve the names of all programmed slots of the given password
+/// safe, indexed by slot number.
+pub fn slot_names(pws: &nitrokey::PasswordSafe<'_>) -> Result<BTreeMap<u8, String>> {
+  let status = pws
+    .get_slot_status()
+    .map_err(|err| Error::Command("Could not read the password safe slot status", err))?;
+
+  let mut names = BTreeMap::new();
+  for (slot, _) in status
+    .iter()
+    .enumerate()
+    .filter(|&(_, programmed)| *programmed)
+  {
+    let slot = slot as u8;
+    let name = pws
+      .get_slot_name(slot)
+      .map_err(|err| Error::Command("Could not read the password safe slot names", err))?;
+    let _ = names.insert(slot, name);
+  }
+  Ok(names)
+}
+
+/// Find the slot with the given name.
+pub fn find_slot(names: &BTreeMap<u8, String>, name: &str) -> Option<u8> {
+  names
+    .iter()
+    .find(|&(_, n)| n == name)
+    .map(|(&slot, _)| slot)
+}
+
+/// Read the entry in the slot with the given number.
+pub fn read_entry(pws: &nitrokey::PasswordSafe<'_>, slot: u8) -> Result<Entry> {
+  fn read(result: std::result::Result<String, nitrokey::CommandError>) -> Result<String> {
+    match result {
+      Ok(value) => Ok(value),
+      // The nitrokey crate reports an empty field, such as the login
+      // of an entry created by `pass insert`, as an unknown error.
+      Err(nitrokey::CommandError::Unknown) => Ok(String::new()),
+      Err(err) => Err(Error::Command("Could not read the password safe slot", err)),
+    }
+  }
+
+  Ok(Entry {
+    slot,
+    name: read(pws.get_slot_name(slot))?,
+    login: read(pws.get_slot_login(slot))?,
+    password: read(pws.get_slot_password(slot))?,
+  })
+}
+
+/// Find the entry with the given name.
+pub fn find_entry(pws: &nitrokey::PasswordSafe<'_>, name: &str) -> Result<Entry> {
+  let names = slot_names(pws)?;
+  let slot = find_slot(&names, name).ok_or_else(|| Error::NoSuchSlot(name.to_string()))?;
+  read_entry(pws, slot)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn find_slots() {
+    let names = vec![(1, "github".to_string()), (4, "mail".to_string())]
+      .into_iter()
+      .collect::<BTreeMap<_, _>>();
+    assert_eq!(find_slot(&names, "mail"), Some(4));
+    assert_eq!(find_slot(&names, "gitlab"), None);
+  }
+}
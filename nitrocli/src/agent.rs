@@ -0,0 +1,408 @@
+// agent.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use std::net;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path;
+use std::process;
+use std::result;
+use std::time;
+
+use crate::args;
+use crate::commands;
+use crate::error::Error;
+use crate::metrics;
+use crate::storage;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The first file descriptor passed on by systemd's socket activation.
+const LISTEN_FDS_START: i32 = 3;
+
+/// The default interval at which the metrics file is updated, in
+/// seconds.
+pub const DEFAULT_METRICS_INTERVAL: u64 = 60;
+
+/// The maximum interval at which the metrics file is updated, in
+/// seconds.
+pub const MAX_METRICS_INTERVAL: u64 = 24 * 60 * 60;
+
+/// The maximum length of a request or a reply, in bytes.
+const MAX_MESSAGE: u64 = 4096;
+
+/// The time the agent waits for a client to send its request or to
+/// accept the reply, in seconds.
+const REQUEST_TIMEOUT: u64 = 5;
+
+/// The time a client waits for the reply of the agent, in seconds.
+///
+/// Serving a request may involve waiting for the device lock and for
+/// the user to enter the user PIN.
+const REPLY_TIMEOUT: u64 = 120;
+
+/// A file the agent writes metrics to.
+#[derive(Debug)]
+pub struct MetricsFile {
+  pub path: path::PathBuf,
+  pub interval: time::Duration,
+}
+
+/// A request to the agent.
+///
+/// Requests are sent as a single line of the form `otp-get <algorithm>
+/// <slot>`. The agent replies with `ok <password>` or `error <message>`
+/// and closes the connection.
+#[derive(Debug, PartialEq)]
+struct Request {
+  algorithm: args::OtpAlgorithm,
+  slot: String,
+}
+
+impl Request {
+  /// Parse a request from the given line.
+  fn parse(line: &str) -> Result<Self> {
+    let mut parts = line.trim_end_matches('\n').splitn(3, ' ');
+    match (parts.next(), parts.next(), parts.next()) {
+      (Some("otp-get"), Some(algorithm), Some(slot)) if !slot.is_empty() => {
+        let algorithm = algorithm
+          .parse()
+          .map_err(|()| Error::Error(format!("Invalid OTP algorithm: {}", algorithm)))?;
+        Ok(Request {
+          algorithm,
+          slot: slot.to_string(),
+        })
+      }
+      _ => Err(Error::Error(format!(
+        "Invalid request: {}",
+        line.trim_end()
+      ))),
+    }
+  }
+
+  /// Format the request as a line.
+  fn to_line(&self) -> String {
+    format!("otp-get {} {}\n", self.algorithm, self.slot)
+  }
+}
+
+/// Parse the reply of the agent.
+fn parse_reply(reply: &str) -> Result<String> {
+  let mut parts = reply.trim_end_matches('\n').splitn(2, ' ');
+  match (parts.next(), parts.next()) {
+    (Some("ok"), Some(otp)) => Ok(otp.to_string()),
+    (Some("error"), Some(error)) => Err(Error::Error(error.to_string())),
+    _ => Err(Error::Error(
+      "Received an invalid reply from the agent".to_string(),
+    )),
+  }
+}
+
+/// Request a one-time password for the given slot from the agent.
+///
+/// `None` is returned if no agent is running.
+pub fn request_otp(slot: &str, algorithm: args::OtpAlgorithm) -> Result<Option<String>> {
+  let path = storage::AGENT_SOCKET.path()?;
+  let mut stream = match UnixStream::connect(&path) {
+    Ok(stream) => stream,
+    Err(_) => return Ok(None),
+  };
+
+  let request = Request {
+    algorithm,
+    slot: slot.to_string(),
+  };
+  stream.set_read_timeout(Some(time::Duration::from_secs(REPLY_TIMEOUT)))?;
+  stream.write_all(request.to_line().as_bytes())?;
+  stream.shutdown(net::Shutdown::Write)?;
+
+  let mut reply = String::new();
+  let _ = stream
+    .take(MAX_MESSAGE)
+    .read_to_string(&mut reply)
+    .map_err(|err| Error::Error(format!("Could not read the reply of the agent: {}", err)))?;
+  parse_reply(&reply).map(Some)
+}
+
+/// Retrieve the socket passed on by systemd's socket activation, if
+/// any.
+fn activated_listener() -> Option<UnixListener> {
+  let pid = env::var("LISTEN_PID").ok()?.parse::<u32>().ok()?;
+  let fds = env::var("LISTEN_FDS").ok()?.parse::<u32>().ok()?;
+  if pid != process::id() || fds < 1 {
+    return None;
+  }
+  // The file descriptor was handed over to us and is owned by nobody
+  // else in this process.
+  Some(unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) })
+}
+
+/// Create the socket to listen on.
+///
+/// A stale socket left behind by an agent that did not terminate
+/// properly is replaced, but an error is reported if another agent is
+/// still listening.
+fn bind() -> Result<UnixListener> {
+  let path = storage::AGENT_SOCKET.path()?;
+  if UnixStream::connect(&path).is_ok() {
+    let error = format!("An agent is already listening on {}", path.display());
+    return Err(Error::Error(error));
+  }
+  if let Err(err) = fs::remove_file(&path) {
+    if err.kind() != io::ErrorKind::NotFound {
+      return Err(err.into());
+    }
+  }
+  storage::create_parent(&path)?;
+  Ok(UnixListener::bind(&path)?)
+}
+
+/// Handle the given request, reusing the given connection if possible.
+///
+/// A connection that fails to handle a request is discarded and the
+/// request is retried once using a new one, as the device may have
+/// been reconnected in the meantime.
+fn handle(
+  ctx: &args::ExecCtx,
+  connection: &mut Option<commands::Connection>,
+  request: &Request,
+) -> Result<String> {
+  if let Some(current) = connection.take() {
+    if let Ok((current, otp)) =
+      commands::generate_otp(ctx, current, &request.slot, request.algorithm)
+    {
+      *connection = Some(current);
+      return Ok(otp);
+    }
+  }
+
+  let current = commands::Connection::connect(ctx)?;
+  let (current, otp) = commands::generate_otp(ctx, current, &request.slot, request.algorithm)?;
+  *connection = Some(current);
+  Ok(otp)
+}
+
+/// Read a request line from the given stream.
+///
+/// At most `MAX_MESSAGE` bytes are read. `None` is returned if the
+/// client disconnected without sending a request.
+fn read_request(stream: &UnixStream) -> Result<Option<String>> {
+  let mut line = String::new();
+  let mut reader = io::BufReader::new(stream.take(MAX_MESSAGE));
+  if reader.read_line(&mut line)? == 0 {
+    Ok(None)
+  } else {
+    Ok(Some(line))
+  }
+}
+
+/// Serve a single client.
+///
+/// Returns whether the client sent a request.
+fn serve(
+  ctx: &args::ExecCtx,
+  connection: &mut Option<commands::Connection>,
+  metrics: &mut metrics::Metrics,
+  stream: UnixStream,
+) -> Result<bool> {
+  // A client that stalls must not keep the agent from serving others.
+  let timeout = Some(time::Duration::from_secs(REQUEST_TIMEOUT));
+  stream.set_read_timeout(timeout)?;
+  stream.set_write_timeout(timeout)?;
+  // Clients merely checking whether an agent is running disconnect
+  // without sending a request.
+  let line = match read_request(&stream)? {
+    Some(line) => line,
+    None => return Ok(false),
+  };
+  let start = time::Instant::now();
+  let result = if !line.ends_with('\n') && line.len() as u64 == MAX_MESSAGE {
+    Err(Error::Error("The request is too long".to_string()))
+  } else {
+    Request::parse(&line)
+  }
+  .and_then(|request| handle(ctx, connection, &request));
+  // Other processes may use the device while we wait for the next
+  // request.
+  commands::unlock_device(ctx);
+  metrics.record(result.is_ok(), start.elapsed());
+  match result {
+    Ok(_) => debug!("Served request {:?}", line.trim_end()),
+    Err(ref err) => info!("Request {:?} failed: {}", line.trim_end(), err),
+  }
+
+  let reply = match result {
+    Ok(otp) => format!("ok {}\n", otp),
+    Err(err) => format!("error {}\n", err),
+  };
+  (&stream).write_all(reply.as_bytes())?;
+  Ok(true)
+}
+
+/// Wait for the given listener to become readable, at most for the
+/// given time.
+///
+/// Returns whether the listener is readable.
+fn wait_readable(listener: &UnixListener, timeout: time::Duration) -> Result<bool> {
+  // Waiting for at most a day at once keeps the timeout within the
+  // range poll(2) accepts; waking up early is harmless.
+  let secs = timeout.as_secs().min(24 * 60 * 60);
+  let millis = secs as libc::c_int * 1000 + timeout.subsec_millis() as libc::c_int;
+  let mut fd = libc::pollfd {
+    fd: listener.as_raw_fd(),
+    events: libc::POLLIN,
+    revents: 0,
+  };
+  let rc = unsafe { libc::poll(&mut fd, 1, millis) };
+  if rc < 0 {
+    let err = io::Error::last_os_error();
+    if err.kind() == io::ErrorKind::Interrupted {
+      Ok(false)
+    } else {
+      Err(err.into())
+    }
+  } else {
+    Ok(rc > 0)
+  }
+}
+
+/// Update the given metrics file.
+fn update_metrics(
+  ctx: &args::ExecCtx,
+  connection: &mut Option<commands::Connection>,
+  metrics: &metrics::Metrics,
+  file: &MetricsFile,
+) -> Result<()> {
+  let state = commands::device_state(ctx, connection);
+  commands::unlock_device(ctx);
+  metrics::write(&file.path, &metrics.format(state.as_ref()))
+}
+
+/// Run the agent.
+///
+/// The agent listens on the socket passed on by systemd's socket
+/// activation or, if there is none, on its own socket in the runtime
+/// directory. It serves one-time password requests, keeping the
+/// connection to the device open and authenticated between them.
+///
+/// If a metrics file is given, it is updated after every request and
+/// at the configured interval.
+pub fn run(ctx: &args::ExecCtx, metrics_file: Option<MetricsFile>) -> Result<()> {
+  let listener = match activated_listener() {
+    Some(listener) => listener,
+    None => bind()?,
+  };
+
+  // The connection established for prefetching is reused for the first
+  // request.
+  if let Err(err) = commands::prefetch(ctx) {
+    info!("Could not prefetch the OTP slot names: {}", err);
+  }
+  commands::unlock_device(ctx);
+
+  let mut connection = None;
+  let mut metrics = metrics::Metrics::default();
+  let mut next_update = time::Instant::now();
+  loop {
+    if let Some(ref file) = metrics_file {
+      let now = time::Instant::now();
+      if now >= next_update {
+        if let Err(err) = update_metrics(ctx, &mut connection, &metrics, file) {
+          error!("Could not update the metrics: {}", err);
+        }
+        next_update = now + file.interval;
+        continue;
+      }
+      if !wait_readable(&listener, next_update - now)? {
+        continue;
+      }
+    }
+
+    // A failure to serve a single client must not stop the agent.
+    let stream = match listener.accept() {
+      Ok((stream, _)) => stream,
+      Err(_) => continue,
+    };
+    match serve(ctx, &mut connection, &mut metrics, stream) {
+      Ok(true) => {
+        if let Some(ref file) = metrics_file {
+          if let Err(err) = update_metrics(ctx, &mut connection, &metrics, file) {
+            error!("Could not update the metrics: {}", err);
+          }
+        }
+      }
+      Ok(false) => (),
+      Err(err) => error!("Could not serve a client: {}", err),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::thread;
+
+  #[test]
+  fn request_round_trip() {
+    let request = Request {
+      algorithm: args::OtpAlgorithm::Totp,
+      slot: "my bank".to_string(),
+    };
+    assert_eq!(Request::parse(&request.to_line()).unwrap(), request);
+    assert!(Request::parse("otp-get totp\n").is_err());
+    assert!(Request::parse("otp-get sha1 0\n").is_err());
+    assert!(Request::parse("status\n").is_err());
+  }
+
+  #[test]
+  fn parse_replies() {
+    assert_eq!(parse_reply("ok 123456\n").unwrap(), "123456");
+    let err = parse_reply("error Could not generate OTP\n").unwrap_err();
+    assert_eq!(err.to_string(), "Could not generate OTP");
+    assert!(parse_reply("").is_err());
+  }
+
+  #[test]
+  fn read_limited_requests() {
+    let (client, server) = UnixStream::pair().unwrap();
+    (&client).write_all(b"otp-get hotp 0\n").unwrap();
+    client.shutdown(net::Shutdown::Write).unwrap();
+    let line = read_request(&server).unwrap().unwrap();
+    assert_eq!(line, "otp-get hotp 0\n");
+    assert_eq!(read_request(&server).unwrap(), None);
+
+    let (client, server) = UnixStream::pair().unwrap();
+    let _ = thread::spawn(move || {
+      let request = format!("otp-get hotp {}\n", "0".repeat(2 * MAX_MESSAGE as usize));
+      let _ = (&client).write_all(request.as_bytes());
+    });
+    let line = read_request(&server).unwrap().unwrap();
+    assert_eq!(line.len() as u64, MAX_MESSAGE);
+    assert!(!line.ends_with('\n'));
+  }
+}
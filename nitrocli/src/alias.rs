@@ -0,0 +1,106 @@
+// alias.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::result;
+
+use crate::config;
+use crate::error::Error;
+use crate::shell;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The configuration table containing the aliases.
+pub const SECTION: &str = "alias";
+
+/// Expand the alias the given arguments start with, if any.
+///
+/// An alias is replaced by the words of its value, split like a shell
+/// would, and followed by the remaining arguments. The expansion may
+/// start with another alias, which is expanded in turn. Names for which
+/// the given function returns true, i.e., commands, are never expanded,
+/// so that aliases cannot shadow them.
+pub fn expand<F>(config: &config::Config, args: &[String], is_command: F) -> Result<Vec<String>>
+where
+  F: Fn(&str) -> bool,
+{
+  let mut args = args.to_vec();
+  let mut expanded = Vec::new();
+  while let Some(name) = args.first().cloned() {
+    if is_command(&name) {
+      break;
+    }
+    let value = match config.get_string(SECTION, &name)? {
+      Some(value) => value,
+      None => break,
+    };
+    if expanded.contains(&name) {
+      let error = format!("The alias {} expands to itself", name);
+      return Err(Error::Error(error));
+    }
+
+    let words = shell::split(value)?;
+    if words.is_empty() {
+      let error = format!("The alias {} is empty", name);
+      return Err(Error::Error(error));
+    }
+    debug!("Expanded the alias {} to: {}", name, value);
+    let _ = args.splice(..1, words);
+    expanded.push(name);
+  }
+  Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn expand_str(config: &str, args: &[&str]) -> Result<Vec<String>> {
+    let config = config.parse::<config::Config>().unwrap();
+    let args = args.iter().map(ToString::to_string).collect::<Vec<_>>();
+    expand(&config, &args, |name| name == "otp" || name == "status")
+  }
+
+  #[test]
+  fn expand_aliases() {
+    let config = r#"[alias]
+work-otp = "otp get 'work mail' --clip"
+w = "work-otp"
+status = "otp status"
+"#;
+    assert_eq!(
+      expand_str(config, &["work-otp", "-t"]).unwrap(),
+      vec!["otp", "get", "work mail", "--clip", "-t"]
+    );
+    assert_eq!(
+      expand_str(config, &["w"]).unwrap(),
+      vec!["otp", "get", "work mail", "--clip"]
+    );
+    assert_eq!(expand_str(config, &["status"]).unwrap(), vec!["status"]);
+    assert_eq!(expand_str(config, &["foo"]).unwrap(), vec!["foo"]);
+    assert!(expand_str(config, &[]).unwrap().is_empty());
+  }
+
+  #[test]
+  fn expand_invalid_aliases() {
+    let config = "[alias]\na = \"b --foo\"\nb = \"a\"\nc = \"\"\nd = 1";
+    assert!(expand_str(config, &["a"]).is_err());
+    assert!(expand_str(config, &["c"]).is_err());
+    assert!(expand_str(config, &["d"]).is_err());
+  }
+}
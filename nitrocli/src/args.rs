@@ -0,0 +1,3822 @@
+// args.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::cell;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path;
+use std::result;
+use std::str;
+use std::time;
+
+#[cfg(feature = "otp")]
+use crate::agent;
+use crate::alias;
+use crate::audit;
+#[cfg(feature = "pws")]
+use crate::autotype;
+#[cfg(any(feature = "otp", feature = "pws"))]
+use crate::clipboard;
+use crate::commands;
+use crate::completions;
+use crate::config;
+use crate::error::Error;
+#[cfg(feature = "extensions")]
+use crate::extensions;
+use crate::hooks;
+use crate::lock;
+use crate::log;
+use crate::manpage;
+use crate::module;
+use crate::monitor;
+#[cfg(any(feature = "otp", feature = "pws"))]
+use crate::native;
+use crate::nicknames;
+use crate::notify;
+use crate::pager;
+#[cfg(feature = "pws")]
+use crate::passphrase;
+use crate::pinentry;
+use crate::policy;
+use crate::retry;
+#[cfg(feature = "storage")]
+use crate::session;
+use crate::shell;
+#[cfg(feature = "otp")]
+use crate::tty;
+#[cfg(feature = "storage")]
+use crate::update;
+use crate::watchdog;
+
+type Result<T> = result::Result<T, Error>;
+
+// A macro for generating an enum with a fixed string representation
+// for each of its variants. The enum implements `fmt::Display` and
+// `str::FromStr` based on these strings. Variants may carry attributes,
+// e.g., to make them depend on a feature.
+macro_rules! Enum {
+  ( $name:ident, [ $( $(#[$attr:meta])* $var:ident => $str:expr ), *] ) => {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum $name {
+      $(
+        $(#[$attr])*
+        $var,
+      )*
+    }
+
+    impl $name {
+      /// Retrieve the string representations of all variants.
+      pub fn all_str() -> &'static [&'static str] {
+        &[ $( $(#[$attr])* $str, )* ]
+      }
+    }
+
+    impl fmt::Display for $name {
+      fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match *self {
+          $(
+            $(#[$attr])*
+            $name::$var => $str,
+          )*
+        };
+        write!(f, "{}", s)
+      }
+    }
+
+    impl str::FromStr for $name {
+      type Err = ();
+
+      fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+          $(
+            $(#[$attr])*
+            $str => Ok($name::$var),
+          )*
+          _ => Err(()),
+        }
+      }
+    }
+  };
+}
+
+Enum! {DeviceModel, [
+  Pro => "pro",
+  Storage => "storage"
+]}
+
+/// The context in which a command is executed.
+#[derive(Debug)]
+pub struct ExecCtx {
+  /// The effective configuration.
+  pub config: config::Config,
+  /// The model of the device to connect to, if restricted.
+  pub model: Option<DeviceModel>,
+  /// The serial numbers of the devices that may be connected to. An
+  /// empty list does not impose any restriction.
+  pub serial_numbers: Vec<String>,
+  /// The verbosity level, with zero being the least verbose.
+  pub verbosity: u64,
+  /// Whether to bypass the PIN cache.
+  pub no_cache: bool,
+  /// Whether to fail instead of asking for confirmations.
+  pub no_prompt: bool,
+  /// Whether to print long output directly instead of using a pager.
+  pub no_pager: bool,
+  /// Whether to print the changes to the device instead of making them.
+  pub dry_run: bool,
+  /// The time after which a command is aborted, if limited.
+  pub timeout: Option<time::Duration>,
+  /// The policy for retrying operations failing with a transient error.
+  pub retry: retry::Policy,
+  /// The model and serial number of the device connected to, recorded
+  /// for error reports if the verbosity is non-zero.
+  pub device: cell::RefCell<Option<String>>,
+  /// The connection to the device released by the last operation, kept
+  /// for reuse by later operations of the same invocation.
+  pub connection: cell::RefCell<Option<nitrokey::DeviceWrapper>>,
+  /// The connection to the device authenticated as admin by an earlier
+  /// operation, kept for reuse by later changes of the same invocation.
+  pub admin: cell::RefCell<Option<nitrokey::Admin<nitrokey::DeviceWrapper>>>,
+  /// The lock granting exclusive use of the device connected to.
+  pub lock: cell::RefCell<Option<lock::Lock>>,
+  /// The serial number of the device used, recorded for the audit log.
+  pub serial: cell::RefCell<Option<String>>,
+  /// The state of the shell the commands are executed in, if any.
+  pub session: cell::RefCell<Option<shell::Session>>,
+}
+
+impl ExecCtx {
+  /// Create an execution context from the given configuration.
+  fn new(config: config::Config) -> Result<Self> {
+    let model = match config.get_string("", "model")? {
+      Some(model) => Some(model.parse::<DeviceModel>().map_err(|()| {
+        let error = format!(
+          "Invalid device model: {} (expected one of {})",
+          model,
+          DeviceModel::all_str().join(", ")
+        );
+        Error::Error(error)
+      })?),
+      None => None,
+    };
+    let serial_numbers = config
+      .get_strings("", "serial-numbers")?
+      .unwrap_or_default()
+      .into_iter()
+      .map(String::from)
+      .collect::<Vec<_>>();
+    // Nicknames of devices are accepted wherever serial numbers are.
+    let serial_numbers = if serial_numbers.is_empty() {
+      serial_numbers
+    } else {
+      let nicknames = nicknames::Nicknames::load()?;
+      serial_numbers
+        .iter()
+        .map(|serial| nicknames.resolve(serial))
+        .collect()
+    };
+    let verbosity = match config.get_integer("", "verbosity")? {
+      Some(verbosity) if verbosity < 0 => {
+        let error = format!("Invalid verbosity: {}", verbosity);
+        return Err(Error::Error(error));
+      }
+      Some(verbosity) => verbosity as u64,
+      None => 0,
+    };
+    let no_cache = config.get_bool("", "no-cache")?.unwrap_or(false);
+    let no_prompt = config.get_bool("", "no-prompt")?.unwrap_or(false);
+    let no_pager = config.get_bool("", "no-pager")?.unwrap_or(false);
+    let dry_run = config.get_bool("", "dry-run")?.unwrap_or(false);
+    let timeout = match config.get_integer("", "timeout")? {
+      Some(timeout) if timeout < 0 => {
+        let error = format!("Invalid timeout: {}", timeout);
+        return Err(Error::Error(error));
+      }
+      Some(0) | None => None,
+      Some(timeout) => Some(time::Duration::from_secs(timeout as u64)),
+    };
+    let retry = retry::Policy::from_config(&config)?;
+
+    Ok(ExecCtx {
+      config,
+      model,
+      serial_numbers,
+      verbosity,
+      no_cache,
+      no_prompt,
+      no_pager,
+      dry_run,
+      timeout,
+      retry,
+      device: cell::RefCell::new(None),
+      connection: cell::RefCell::new(None),
+      admin: cell::RefCell::new(None),
+      lock: cell::RefCell::new(None),
+      serial: cell::RefCell::new(None),
+      session: cell::RefCell::new(None),
+    })
+  }
+}
+
+/// A command line option.
+#[derive(Debug)]
+struct Opt {
+  /// The long name of the option, without the leading dashes.
+  long: &'static str,
+  /// The short name of the option, if any.
+  short: Option<char>,
+  /// The name of the option's argument, if it accepts one.
+  ///
+  /// A name of the form `[=NAME]` denotes an optional argument, which
+  /// can only be supplied as `--option=value`.
+  arg: Option<&'static str>,
+  /// A short description of the option.
+  help: &'static str,
+}
+
+/// An example invocation of a command, shown in its help.
+#[derive(Debug)]
+struct Example {
+  /// What the example does.
+  description: &'static str,
+  /// The arguments of the program, including the command.
+  args: &'static str,
+}
+
+/// Format the given examples for the help of a command.
+fn format_examples(examples: &[Example]) -> String {
+  let mut text = String::new();
+  for example in examples {
+    text.push_str(&format!(
+      "  {}:\n    $ nitrocli {}\n",
+      example.description, example.args
+    ));
+  }
+  text
+}
+
+/// The options that may be supplied before the command.
+const GLOBAL_OPTIONS: &[Opt] = &[
+  Opt {
+    long: "dry-run",
+    short: None,
+    arg: None,
+    help: "Print the changes to the device instead of making them",
+  },
+  Opt {
+    long: "generate-manpage",
+    short: None,
+    arg: None,
+    help: "Print the man page and exit",
+  },
+  Opt {
+    long: "help",
+    short: Some('h'),
+    arg: None,
+    help: "Print this help message and exit",
+  },
+  Opt {
+    long: "model",
+    short: Some('m'),
+    arg: Some("MODEL"),
+    help: "Only connect to a device of the given model (pro|storage)",
+  },
+  Opt {
+    long: "no-cache",
+    short: None,
+    arg: None,
+    help: "Do not read PINs or device facts from or store them in the cache",
+  },
+  Opt {
+    long: "no-pager",
+    short: None,
+    arg: None,
+    help: "Do not pass long output to a pager",
+  },
+  Opt {
+    long: "no-prompt",
+    short: None,
+    arg: None,
+    help: "Fail instead of asking for confirmation",
+  },
+  Opt {
+    long: "profile",
+    short: Some('p'),
+    arg: Some("PROFILE"),
+    help: "Use the settings of the given device profile",
+  },
+  Opt {
+    long: "policy-file",
+    short: None,
+    arg: Some("PATH"),
+    help: "Enforce the PIN policy defined in the given file",
+  },
+  Opt {
+    long: "serial-number",
+    short: None,
+    arg: Some("SERIAL"),
+    help: "Only connect to a device with the given serial number (may be repeated)",
+  },
+  Opt {
+    long: "timeout",
+    short: None,
+    arg: Some("SECONDS"),
+    help: "Abort the command if it takes longer than the given time",
+  },
+  Opt {
+    long: "verbose",
+    short: Some('v'),
+    arg: None,
+    help: "Increase the log level (may be repeated)",
+  },
+  Opt {
+    long: "version",
+    short: Some('V'),
+    arg: None,
+    help: "Print the version and exit",
+  },
+];
+
+/// The configuration keys corresponding to global options, along with
+/// the kinds of their values.
+const GLOBAL_KEYS: &[(&str, &str, config::Kind)] = &[
+  ("", "dry-run", config::Kind::Boolean),
+  ("", "model", config::Kind::String),
+  ("", "no-cache", config::Kind::Boolean),
+  ("", "no-pager", config::Kind::Boolean),
+  ("", "no-prompt", config::Kind::Boolean),
+  ("", "profile", config::Kind::String),
+  ("", "serial-numbers", config::Kind::Strings),
+  ("", "timeout", config::Kind::Integer),
+  ("", "verbosity", config::Kind::Integer),
+];
+
+/// Check whether the given configuration key corresponds to a global
+/// option.
+fn is_global_key(key: &str) -> bool {
+  GLOBAL_KEYS.iter().any(|&(_, k, _)| k == key)
+}
+
+/// A parsed option, along with its argument if it accepts one.
+type ParsedOpt = (&'static Opt, Option<String>);
+
+/// Look up the option with the given long name.
+fn find_long(opts: &'static [Opt], name: &str) -> Result<&'static Opt> {
+  opts
+    .iter()
+    .find(|opt| opt.long == name)
+    .ok_or_else(|| Error::Error(format!("Unknown option: --{}", name)))
+}
+
+/// Look up the option with the given short name.
+fn find_short(opts: &'static [Opt], name: char) -> Result<&'static Opt> {
+  opts
+    .iter()
+    .find(|opt| opt.short == Some(name))
+    .ok_or_else(|| Error::Error(format!("Unknown option: -{}", name)))
+}
+
+/// Parse the options contained in the given arguments.
+///
+/// Options may be given in the forms `--long`, `--long=arg`, `--long
+/// arg`, `-s`, `-sarg`, and `-s arg`, and short options without
+/// argument may be combined, as in `-vv`. All arguments after a `--`
+/// are treated as positional ones. If `stop` is set, parsing stops at
+/// the first positional argument and all remaining arguments are
+/// treated as positional ones. The function returns the parsed options
+/// along with the positional arguments.
+fn parse_options(
+  opts: &'static [Opt],
+  args: &[String],
+  stop: bool,
+) -> Result<(Vec<ParsedOpt>, Vec<String>)> {
+  let mut parsed = Vec::new();
+  let mut positional = Vec::new();
+  let mut args = args.iter();
+
+  while let Some(arg) = args.next() {
+    if arg == "--" {
+      break;
+    }
+
+    let mut chars = arg.chars();
+    match (chars.next(), chars.next()) {
+      (Some('-'), Some('-')) => {
+        let (name, value) = match arg.find('=') {
+          Some(idx) => (&arg[2..idx], Some(arg[idx + 1..].to_string())),
+          None => (&arg[2..], None),
+        };
+        let opt = find_long(opts, name)?;
+        let value = match (opt.arg, value) {
+          (Some(_), Some(value)) => Some(value),
+          (Some(_), None) if is_optional(opt) => None,
+          (Some(_), None) => Some(args.next().cloned().ok_or_else(|| missing_arg(opt))?),
+          (None, Some(_)) => {
+            let error = format!("Option --{} does not accept an argument", opt.long);
+            return Err(Error::Error(error));
+          }
+          (None, None) => None,
+        };
+        parsed.push((opt, value));
+      }
+      (Some('-'), Some(_)) => {
+        for (idx, c) in arg.char_indices().skip(1) {
+          let opt = find_short(opts, c)?;
+          if opt.arg.is_some() {
+            let value = &arg[idx + c.len_utf8()..];
+            let value = if value.is_empty() {
+              args.next().cloned().ok_or_else(|| missing_arg(opt))?
+            } else {
+              value.to_string()
+            };
+            parsed.push((opt, Some(value)));
+            break;
+          }
+          parsed.push((opt, None));
+        }
+      }
+      _ => {
+        positional.push(arg.clone());
+        if stop {
+          break;
+        }
+      }
+    }
+  }
+
+  positional.extend(args.cloned());
+  Ok((parsed, positional))
+}
+
+/// Check whether the argument of the given option is optional.
+fn is_optional(opt: &Opt) -> bool {
+  match opt.arg {
+    Some(arg) => arg.starts_with("[="),
+    None => false,
+  }
+}
+
+/// Create an error for an option lacking its argument.
+fn missing_arg(opt: &Opt) -> Error {
+  Error::Error(format!("Option --{} requires an argument", opt.long))
+}
+
+/// Format the help text for the given options.
+fn format_options(opts: &[Opt]) -> String {
+  opts
+    .iter()
+    .map(|opt| {
+      let short = opt
+        .short
+        .map(|c| format!("-{}, ", c))
+        .unwrap_or_else(|| "    ".to_string());
+      let long = match opt.arg {
+        Some(arg) if is_optional(opt) => format!("--{}{}", opt.long, arg),
+        Some(arg) => format!("--{} {}", opt.long, arg),
+        None => format!("--{}", opt.long),
+      };
+      format!("  {}{:<23} {}\n", short, long, opt.help)
+    })
+    .collect()
+}
+
+Enum! {Command, [
+  #[cfg(any(feature = "otp", feature = "pws"))]
+  Alias => "alias",
+  #[cfg(feature = "otp")]
+  Agent => "agent",
+  #[cfg(feature = "pws")]
+  Askpass => "askpass",
+  Bench => "bench",
+  Clear => "clear",
+  #[cfg(feature = "storage")]
+  Close => "close",
+  Completions => "completions",
+  Config => "config",
+  Device => "device",
+  Fleet => "fleet",
+  Help => "help",
+  #[cfg(feature = "storage")]
+  Hidden => "hidden",
+  Module => "module",
+  Monitor => "monitor",
+  #[cfg(any(feature = "otp", feature = "pws"))]
+  NativeMessaging => "native-messaging",
+  #[cfg(feature = "storage")]
+  Open => "open",
+  #[cfg(feature = "otp")]
+  Otp => "otp",
+  #[cfg(feature = "pws")]
+  Pass => "pass",
+  Pin => "pin",
+  Provision => "provision",
+  Setup => "setup",
+  Shell => "shell",
+  #[cfg(feature = "storage")]
+  Status => "status",
+  #[cfg(feature = "storage")]
+  Update => "update",
+  Vault => "vault"
+]}
+
+impl Command {
+  /// Retrieve a short description of the command.
+  fn description(self) -> &'static str {
+    match self {
+      #[cfg(any(feature = "otp", feature = "pws"))]
+      Command::Alias => "Manage local aliases for slots",
+      #[cfg(feature = "otp")]
+      Command::Agent => "Run an agent serving one-time passwords",
+      #[cfg(feature = "pws")]
+      Command::Askpass => "Answer an askpass prompt from the password safe",
+      Command::Bench => "Measure the latency of device operations",
+      Command::Clear => "Remove the user PIN from gpg-agent's cache",
+      #[cfg(feature = "storage")]
+      Command::Close => "Close the encrypted volume",
+      Command::Completions => "Print a shell completion script",
+      Command::Config => "Inspect the configuration",
+      Command::Device => "Manage local nicknames for devices",
+      Command::Fleet => "Report on all known devices",
+      Command::Help => "Print the help of a command or topic",
+      #[cfg(feature = "storage")]
+      Command::Hidden => "Manage the hidden volumes",
+      Command::Module => "Apply a task in the format of an Ansible module",
+      Command::Monitor => "Publish events about the device",
+      #[cfg(any(feature = "otp", feature = "pws"))]
+      Command::NativeMessaging => "Serve requests of a browser extension",
+      #[cfg(feature = "storage")]
+      Command::Open => "Open the encrypted volume",
+      #[cfg(feature = "otp")]
+      Command::Otp => "Access one-time passwords",
+      #[cfg(feature = "pws")]
+      Command::Pass => "Access the password safe like pass(1)",
+      Command::Pin => "Change the user or admin PIN",
+      Command::Provision => "Bring the device into the state described by a profile",
+      Command::Setup => "Set up the system for accessing devices",
+      Command::Shell => "Execute commands entered at a prompt",
+      #[cfg(feature = "storage")]
+      Command::Status => "Print the status of the Nitrokey device",
+      #[cfg(feature = "storage")]
+      Command::Update => "Update the firmware of the Nitrokey Storage",
+      Command::Vault => "Encrypt and decrypt files using gpg",
+    }
+  }
+
+  /// Check whether the command keeps running until it is stopped, so
+  /// that the timeout does not apply to it.
+  fn is_long_running(self) -> bool {
+    match self {
+      #[cfg(feature = "otp")]
+      Command::Agent => true,
+      #[cfg(any(feature = "otp", feature = "pws"))]
+      Command::NativeMessaging => true,
+      Command::Monitor => true,
+      Command::Shell => true,
+      _ => false,
+    }
+  }
+
+  /// Check whether the command may access a device.
+  ///
+  /// The hooks of commands that never do are not provided with the
+  /// device, so that running them does not require connecting to it.
+  /// The `config` and `setup` commands only access a device for some
+  /// of their subcommands and are treated as not accessing one.
+  fn uses_device(self) -> bool {
+    match self {
+      #[cfg(any(feature = "otp", feature = "pws"))]
+      Command::Alias => true,
+      #[cfg(feature = "otp")]
+      Command::Agent => true,
+      #[cfg(feature = "pws")]
+      Command::Askpass => true,
+      Command::Bench => true,
+      #[cfg(feature = "storage")]
+      Command::Close => true,
+      Command::Fleet => true,
+      #[cfg(feature = "storage")]
+      Command::Hidden => true,
+      Command::Module => true,
+      Command::Monitor => true,
+      #[cfg(any(feature = "otp", feature = "pws"))]
+      Command::NativeMessaging => true,
+      #[cfg(feature = "storage")]
+      Command::Open => true,
+      #[cfg(feature = "otp")]
+      Command::Otp => true,
+      #[cfg(feature = "pws")]
+      Command::Pass => true,
+      Command::Pin => true,
+      Command::Provision => true,
+      #[cfg(feature = "storage")]
+      Command::Status => true,
+      #[cfg(feature = "storage")]
+      Command::Update => true,
+      _ => false,
+    }
+  }
+
+  /// Retrieve the usage information for the command.
+  pub fn usage(self) -> String {
+    match self {
+      #[cfg(any(feature = "otp", feature = "pws"))]
+      Command::Alias => format!(
+        "Usage: nitrocli alias {{{}}} [<alias> [<kind>/<slot>]]",
+        AliasCommand::all_str().join("|")
+      ),
+      #[cfg(feature = "otp")]
+      Command::Agent => format!(
+        "Usage: nitrocli agent [<options>]\n\nOptions:\n{}",
+        format_options(AGENT_OPTIONS).trim_end()
+      ),
+      #[cfg(feature = "pws")]
+      Command::Askpass => "Usage: nitrocli askpass <prompt>".to_string(),
+      Command::Bench => format!(
+        "Usage: nitrocli bench [<options>]\n\nOptions:\n{}",
+        format_options(BENCH_OPTIONS).trim_end()
+      ),
+      Command::Config => format!(
+        "Usage: nitrocli config {{{}}} [<key>]",
+        ConfigCommand::all_str().join("|")
+      ),
+      Command::Device => format!(
+        "Usage: nitrocli device {{{}}} [<serial> [<name>]]",
+        DeviceCommand::all_str().join("|")
+      ),
+      Command::Fleet => format!(
+        "Usage: nitrocli fleet {{{}}} [<options>]\n\nOptions of report:\n{}",
+        FleetCommand::all_str().join("|"),
+        format_options(FLEET_REPORT_OPTIONS).trim_end()
+      ),
+      Command::Help => {
+        let mut usage = "Usage: nitrocli help [<command>|<topic>]\n\nTopics:\n".to_string();
+        for topic in HelpTopic::all_str() {
+          let description = topic.parse::<HelpTopic>().unwrap().description();
+          usage.push_str(&format!("  {:<12}{}\n", topic, description));
+        }
+        usage.trim_end().to_string()
+      }
+      #[cfg(feature = "storage")]
+      Command::Hidden => format!(
+        "Usage: nitrocli hidden {{{}}} [<options>] [<slot> <start> <end>]\n\nOptions of create:\n{}",
+        HiddenCommand::all_str().join("|"),
+        format_options(HIDDEN_CREATE_OPTIONS).trim_end()
+      ),
+      Command::Module => "Usage: nitrocli module [<task-file>]".to_string(),
+      Command::Monitor => format!(
+        "Usage: nitrocli monitor [<options>]\n\nOptions:\n{}",
+        format_options(MONITOR_OPTIONS).trim_end()
+      ),
+      #[cfg(any(feature = "otp", feature = "pws"))]
+      Command::NativeMessaging => "Usage: nitrocli native-messaging [<args>]".to_string(),
+      #[cfg(feature = "otp")]
+      Command::Otp => format!(
+        "Usage: nitrocli otp {{{}}} [<options>] [<slot>...|<slot> <name>]\n\nOptions of get:\n{}\n\nOptions of pick:\n{}\n\nOptions of set:\n{}",
+        OtpCommand::all_str().join("|"),
+        format_options(OTP_GET_OPTIONS).trim_end(),
+        format_options(OTP_PICK_OPTIONS).trim_end(),
+        format_options(OTP_SET_OPTIONS).trim_end()
+      ),
+      #[cfg(feature = "pws")]
+      Command::Pass => format!(
+        "Usage: nitrocli pass {{{}}} [<options>] [<name>]\n\nOptions of insert:\n{}\n\nOptions of pick:\n{}\n\nOptions of show:\n{}\n\nOptions of type:\n{}",
+        PassCommand::all_str().join("|"),
+        format_options(PASS_INSERT_OPTIONS).trim_end(),
+        format_options(PASS_PICK_OPTIONS).trim_end(),
+        format_options(PASS_SHOW_OPTIONS).trim_end(),
+        format_options(PASS_TYPE_OPTIONS).trim_end()
+      ),
+      Command::Pin => format!(
+        "Usage: nitrocli pin {{{}}} [<options>] <admin|user>\n\nOptions:\n{}",
+        PinCommand::all_str().join("|"),
+        format_options(PIN_SET_OPTIONS).trim_end()
+      ),
+      Command::Provision => format!(
+        "Usage: nitrocli provision {{{}}} [<options>] <profile>\n\nOptions of apply:\n{}",
+        ProvisionCommand::all_str().join("|"),
+        format_options(PROVISION_APPLY_OPTIONS).trim_end()
+      ),
+      Command::Setup => format!(
+        "Usage: nitrocli setup {{{}}} [<options>]\n\nOptions of udev:\n{}",
+        SetupCommand::all_str().join("|"),
+        format_options(SETUP_UDEV_OPTIONS).trim_end()
+      ),
+      Command::Clear => format!("Usage: nitrocli {}", self),
+      Command::Shell => "Usage: nitrocli shell [<script>]".to_string(),
+      Command::Completions => format!(
+        "Usage: nitrocli completions [<options>] <{}>\n\nOptions:\n{}",
+        Shell::all_str().join("|"),
+        format_options(COMPLETIONS_OPTIONS).trim_end()
+      ),
+      #[cfg(feature = "storage")]
+      Command::Close => format!(
+        "Usage: nitrocli close [<options>]\n\nOptions:\n{}",
+        format_options(CLOSE_OPTIONS).trim_end()
+      ),
+      #[cfg(feature = "storage")]
+      Command::Open => format!(
+        "Usage: nitrocli open [<options>]\n\nOptions:\n{}",
+        format_options(OPEN_OPTIONS).trim_end()
+      ),
+      #[cfg(feature = "storage")]
+      Command::Status => format!(
+        "Usage: nitrocli status [<options>]\n\nOptions:\n{}",
+        format_options(STATUS_OPTIONS).trim_end()
+      ),
+      #[cfg(feature = "storage")]
+      Command::Update => format!(
+        "Usage: nitrocli update {{{}}} [<options>] [<image>]\n\n\
+         Options of download:\n{}\n\nOptions of flash:\n{}\n\nOptions of verify:\n{}",
+        UpdateCommand::all_str().join("|"),
+        format_options(UPDATE_DOWNLOAD_OPTIONS).trim_end(),
+        format_options(UPDATE_FLASH_OPTIONS).trim_end(),
+        format_options(UPDATE_VERIFY_OPTIONS).trim_end()
+      ),
+      Command::Vault => format!(
+        "Usage: nitrocli vault {{{}}} [<options>] <file>\n\nOptions of encrypt:\n{}",
+        VaultCommand::all_str().join("|"),
+        format_options(VAULT_ENCRYPT_OPTIONS).trim_end()
+      ),
+    }
+  }
+
+  /// Retrieve examples for the usage of the command.
+  fn examples(self) -> &'static [Example] {
+    match self {
+      #[cfg(any(feature = "otp", feature = "pws"))]
+      Command::Alias => &[
+        Example {
+          description: "Refer to OTP slot 3 as github",
+          args: "alias add github otp/3",
+        },
+        Example {
+          description: "List the aliases of the connected device",
+          args: "alias list",
+        },
+      ],
+      #[cfg(feature = "otp")]
+      Command::Agent => &[Example {
+        description: "Serve one-time passwords and publish metrics",
+        args: "agent --metrics /run/user/1000/nitrocli.prom",
+      }],
+      #[cfg(feature = "pws")]
+      Command::Askpass => &[Example {
+        description: "Let git retrieve a credential from the password safe",
+        args: "askpass 'Password for https://git.example.com:'",
+      }],
+      Command::Bench => &[Example {
+        description: "Measure the latencies over 100 iterations",
+        args: "bench --iterations 100",
+      }],
+      Command::Clear => &[Example {
+        description: "Forget the cached user PIN",
+        args: "clear",
+      }],
+      #[cfg(feature = "storage")]
+      Command::Close => &[
+        Example {
+          description: "Close the encrypted volume",
+          args: "close",
+        },
+        Example {
+          description: "Close the encrypted volume of every connected Nitrokey Storage",
+          args: "close --all-devices",
+        },
+      ],
+      Command::Completions => &[
+        Example {
+          description: "Install the bash completion script for the current user",
+          args: "completions bash > ~/.local/share/bash-completion/completions/nitrocli",
+        },
+        Example {
+          description: "List the names of the OTP slots",
+          args: "completions --list otp-slots",
+        },
+      ],
+      Command::Config => &[
+        Example {
+          description: "Print the configuration along with the origins of the values",
+          args: "config show-effective",
+        },
+        #[cfg(feature = "otp")]
+        Example {
+          description: "Check that pressing NumLock twice makes the device type a code",
+          args: "config test-binding numlock",
+        },
+      ],
+      Command::Device => &[
+        Example {
+          description: "Refer to the device with the given serial number as backup-stick",
+          args: "device name 0x1234abcd backup-stick",
+        },
+        Example {
+          description: "Remove the nickname of the device named backup-stick",
+          args: "device unname backup-stick",
+        },
+        Example {
+          description: "List the devices with a nickname",
+          args: "device list",
+        },
+      ],
+      Command::Fleet => &[Example {
+        description: "Write an inventory of all known devices as CSV",
+        args: "fleet report --format csv",
+      }],
+      Command::Help => &[
+        Example {
+          description: "Print the help of the otp command",
+          args: "help otp",
+        },
+        Example {
+          description: "Describe the exit statuses of the program",
+          args: "help exit-codes",
+        },
+      ],
+      #[cfg(feature = "storage")]
+      Command::Hidden => &[
+        Example {
+          description: "Create a decoy hidden volume on the second quarter of the storage",
+          args: "hidden create --decoy 0 25 50",
+        },
+        Example {
+          description: "Open a hidden volume",
+          args: "hidden open",
+        },
+      ],
+      Command::Module => &[Example {
+        description: "Apply the task read from a file",
+        args: "module task.json",
+      }],
+      Command::Monitor => &[
+        Example {
+          description: "Poll the device every ten seconds",
+          args: "monitor --interval 10",
+        },
+        Example {
+          description: "Print an event whenever a Nitrokey is plugged in or out",
+          args: "monitor --print",
+        },
+      ],
+      #[cfg(any(feature = "otp", feature = "pws"))]
+      Command::NativeMessaging => &[],
+      #[cfg(feature = "storage")]
+      Command::Open => &[
+        Example {
+          description: "Open the encrypted volume of a Nitrokey Storage",
+          args: "--model storage open",
+        },
+        Example {
+          description: "Open the decoy hidden volume instead",
+          args: "open --panic",
+        },
+      ],
+      #[cfg(feature = "otp")]
+      Command::Otp => &[
+        Example {
+          description: "Generate a TOTP using the slot named mail",
+          args: "otp get mail",
+        },
+        Example {
+          description: "Copy an eight-digit HOTP of slot 1 to the clipboard",
+          args: "otp get --hotp --digits 8 --clip 1",
+        },
+        Example {
+          description: "Print the TOTPs of slot 1 and all named TOTP slots",
+          args: "otp get 1 --all-totp",
+        },
+        Example {
+          description: "Enroll a new account, asking for its settings",
+          args: "otp set --interactive",
+        },
+      ],
+      #[cfg(feature = "pws")]
+      Command::Pass => &[
+        Example {
+          description: "List the entries of the password safe",
+          args: "pass ls",
+        },
+        Example {
+          description: "Copy the password of the mail entry to the clipboard",
+          args: "pass show --clip mail",
+        },
+        Example {
+          description: "Store a new entry, replacing the existing one",
+          args: "pass insert --force mail",
+        },
+        Example {
+          description: "Type the login and password of an entry after two seconds",
+          args: "pass type --delay 2 mail",
+        },
+      ],
+      Command::Pin => &[Example {
+        description: "Change the user PIN",
+        args: "pin set user",
+      }],
+      Command::Provision => &[
+        Example {
+          description: "Apply the profile read from an encrypted file",
+          args: "provision apply profile.toml.gpg",
+        },
+        Example {
+          description: "Apply a profile to every connected Nitrokey Storage",
+          args: "provision apply --all-devices profile.toml",
+        },
+        Example {
+          description: "Check whether the device still matches a profile",
+          args: "provision verify profile.toml",
+        },
+      ],
+      Command::Setup => &[
+        Example {
+          description: "Take a new device through its initial setup",
+          args: "setup device",
+        },
+        Example {
+          description: "Install the udev rules for all supported models",
+          args: "setup udev",
+        },
+        Example {
+          description: "Check whether the connected devices are accessible",
+          args: "setup udev --check",
+        },
+      ],
+      Command::Shell => &[
+        Example {
+          description: "Execute commands entered at a prompt",
+          args: "shell",
+        },
+        Example {
+          description: "Write two OTP slots, entering the admin PIN once",
+          args: "shell slots.txt < secrets.txt",
+        },
+      ],
+      #[cfg(feature = "storage")]
+      Command::Status => &[
+        Example {
+          description: "Print the status of the device with a given serial number",
+          args: "--serial-number 0x1234abcd status",
+        },
+        Example {
+          description: "Print only the state of the volumes",
+          args: "status --only volumes",
+        },
+        Example {
+          description: "Print the status of every connected Nitrokey Storage",
+          args: "status --all-devices",
+        },
+      ],
+      #[cfg(feature = "storage")]
+      Command::Update => &[
+        Example {
+          description: "Download and verify a given firmware version",
+          args: "update download --version 0.57",
+        },
+        Example {
+          description: "Put the device into update mode for flashing a firmware",
+          args: "update enter",
+        },
+        Example {
+          description: "Flash an image and check the version of the new firmware",
+          args: "update flash --version 0.57 storage.hex",
+        },
+        Example {
+          description: "Launch the firmware again without flashing",
+          args: "update leave",
+        },
+        Example {
+          description: "Check the exported firmware against the published checksum",
+          args: "update verify /media/nitrokey/firmware.bin",
+        },
+      ],
+      Command::Vault => &[
+        Example {
+          description: "Encrypt a task with a passphrase and remove the plaintext",
+          args: "vault encrypt --remove task.json",
+        },
+        Example {
+          description: "Print the encrypted task",
+          args: "vault decrypt task.json.gpg",
+        },
+      ],
+    }
+  }
+
+  /// Retrieve the help for the command, consisting of its usage
+  /// information followed by examples.
+  fn help(self) -> String {
+    let examples = self.examples();
+    if examples.is_empty() {
+      self.usage()
+    } else {
+      let examples = format_examples(examples);
+      format!("{}\n\nExamples:\n{}", self.usage(), examples.trim_end())
+    }
+  }
+
+  /// Execute this command with the given arguments, surrounded by the
+  /// hooks configured for it.
+  ///
+  /// A failing pre-hook prevents the execution of the command, and the
+  /// post-hook is only run if the command succeeded. The execution is
+  /// reported to the monitor, if one is running, and recorded in the
+  /// audit log, if it is enabled. Unless the command is long-running,
+  /// it fails once the configured timeout expired, at the next point at
+  /// which it can be stopped safely.
+  pub fn execute(self, ctx: &ExecCtx, args: &[String]) -> Result<()> {
+    if help_requested(args) {
+      println!("{}", self.help());
+      return Ok(());
+    }
+
+    let path = self.to_string();
+    let _watchdog = match ctx.timeout {
+      Some(timeout) if !self.is_long_running() => {
+        Some(watchdog::start(timeout, format!("The command {}", path)))
+      }
+      _ => None,
+    };
+    hooks::run(ctx, &path, hooks::Stage::Pre, self.uses_device())?;
+    debug!("Executing command {} with arguments {:?}", path, args);
+    let result = self
+      .execute_command(ctx, &path, args)
+      .and_then(|_| watchdog::check());
+    if let Err(ref err) = result {
+      debug!("Command {} failed: {}", path, err);
+    }
+    if self != Command::Monitor {
+      monitor::publish_command(ctx, &path, result.is_ok());
+    }
+    audit::record(ctx, &path, args, result.is_ok())?;
+    result?;
+    hooks::run(ctx, &path, hooks::Stage::Post, self.uses_device())
+  }
+
+  /// Execute this command with the given arguments, without hooks.
+  fn execute_command(self, ctx: &ExecCtx, path: &str, args: &[String]) -> Result<()> {
+    match self {
+      #[cfg(feature = "otp")]
+      Command::Agent => agent(ctx, path, args),
+      #[cfg(feature = "pws")]
+      Command::Askpass => askpass(ctx, args),
+      Command::Bench => bench(ctx, path, args),
+      Command::Clear => no_args(ctx, path, args).and_then(|_| commands::clear(ctx)),
+      #[cfg(any(feature = "otp", feature = "pws"))]
+      Command::Alias => alias(ctx, args),
+      #[cfg(feature = "storage")]
+      Command::Close => close(ctx, path, args),
+      Command::Completions => completions(ctx, path, args),
+      Command::Config => config(ctx, args),
+      Command::Device => device(ctx, args),
+      Command::Fleet => fleet(ctx, args),
+      Command::Help => help(ctx, args),
+      #[cfg(feature = "storage")]
+      Command::Hidden => hidden(ctx, args),
+      Command::Module => module::run(ctx, args),
+      Command::Monitor => monitor(ctx, path, args),
+      #[cfg(any(feature = "otp", feature = "pws"))]
+      Command::NativeMessaging => native::run(ctx, args),
+      #[cfg(feature = "storage")]
+      Command::Open => open(ctx, path, args),
+      #[cfg(feature = "otp")]
+      Command::Otp => otp(ctx, args),
+      #[cfg(feature = "pws")]
+      Command::Pass => pass(ctx, args),
+      Command::Pin => pin(ctx, args),
+      Command::Provision => provision(ctx, args),
+      Command::Setup => setup(ctx, args),
+      Command::Shell => shell(ctx, path, args),
+      #[cfg(feature = "storage")]
+      Command::Status => status(ctx, path, args),
+      #[cfg(feature = "storage")]
+      Command::Update => update(ctx, args),
+      Command::Vault => vault(ctx, args),
+    }
+  }
+}
+
+Enum! {HelpTopic, [
+  ExitCodes => "exit-codes",
+  Pinentry => "pinentry"
+]}
+
+impl HelpTopic {
+  /// Retrieve a short description of the topic.
+  fn description(self) -> &'static str {
+    match self {
+      HelpTopic::ExitCodes => "The exit statuses of the program",
+      HelpTopic::Pinentry => "How PINs are queried and cached",
+    }
+  }
+
+  /// Retrieve the text describing the topic.
+  fn text(self) -> &'static str {
+    match self {
+      HelpTopic::ExitCodes => {
+        "\
+Exit statuses:
+  0       The command succeeded
+  1       The command failed
+  124     The command did not finish within the time given by --timeout
+  128+N   The command was interrupted by signal N, e.g., 130 for SIGINT
+
+Extensions may exit with other statuses, which are passed on."
+      }
+      HelpTopic::Pinentry => {
+        "\
+PINs are queried using pinentry through gpg-agent. If either of them is
+not available, PINs are read from the controlling terminal instead,
+without being echoed and without being cached.
+
+PINs stay cached by gpg-agent for the time it is configured to cache
+passphrases, which can be shortened per PIN type by the user-cache-ttl
+and admin-cache-ttl keys of the pinentry table of the configuration
+file, in seconds. On macOS, PINs can be cached in the keychain instead
+by setting its cache key to keychain. The --no-cache option disables
+caching altogether, and the clear command removes the cached user PIN."
+      }
+    }
+  }
+}
+
+/// Check whether the given arguments of a command contain a `-h` or
+/// `--help` option.
+fn help_requested(args: &[String]) -> bool {
+  args
+    .iter()
+    .take_while(|arg| *arg != "--")
+    .any(|arg| arg == "-h" || arg == "--help")
+}
+
+Enum! {Shell, [
+  Bash => "bash",
+  Fish => "fish",
+  Zsh => "zsh"
+]}
+
+/// The options of the `completions` command.
+const COMPLETIONS_OPTIONS: &[Opt] = &[Opt {
+  long: "list",
+  short: None,
+  arg: Some("KIND"),
+  help: "Print the values of the given kind offered for completion (otp-slots|serial-numbers)",
+}];
+
+#[cfg(any(feature = "otp", feature = "pws"))]
+Enum! {AliasCommand, [
+  Add => "add",
+  List => "list",
+  Remove => "remove"
+]}
+
+Enum! {DeviceCommand, [
+  List => "list",
+  Name => "name",
+  Unname => "unname"
+]}
+
+Enum! {FleetCommand, [
+  Report => "report"
+]}
+
+Enum! {ReportFormat, [
+  Csv => "csv",
+  Json => "json"
+]}
+
+/// The options of the `fleet report` command.
+const FLEET_REPORT_OPTIONS: &[Opt] = &[Opt {
+  long: "format",
+  short: None,
+  arg: Some("FORMAT"),
+  help: "Print the report in the given format (csv|json, default: json)",
+}];
+
+Enum! {ConfigCommand, [
+  ShowEffective => "show-effective",
+  #[cfg(feature = "otp")]
+  TestBinding => "test-binding"
+]}
+
+#[cfg(feature = "otp")]
+Enum! {LockKey, [
+  Capslock => "capslock",
+  Numlock => "numlock",
+  Scrollock => "scrollock"
+]}
+
+/// The options of the `agent` command.
+#[cfg(feature = "otp")]
+const AGENT_OPTIONS: &[Opt] = &[
+  Opt {
+    long: "metrics",
+    short: None,
+    arg: Some("FILE"),
+    help: "Write metrics for the Prometheus textfile collector to the given file",
+  },
+  Opt {
+    long: "metrics-interval",
+    short: None,
+    arg: Some("SECONDS"),
+    help: "The interval at which the metrics are updated (default: 60, at most 86400)",
+  },
+];
+
+/// The options of the `bench` command.
+const BENCH_OPTIONS: &[Opt] = &[Opt {
+  long: "iterations",
+  short: Some('n'),
+  arg: Some("N"),
+  help: "The number of times each operation is performed (default: 10)",
+}];
+
+/// The options of the `monitor` command.
+const MONITOR_OPTIONS: &[Opt] = &[
+  Opt {
+    long: "interval",
+    short: None,
+    arg: Some("SECONDS"),
+    help: "The interval at which the device is polled (default: 2, at most 86400)",
+  },
+  Opt {
+    long: "socket",
+    short: None,
+    arg: Some("PATH"),
+    help: "The socket to publish events on",
+  },
+  Opt {
+    long: "print",
+    short: None,
+    arg: None,
+    help: "Print the events on the standard output as well",
+  },
+];
+
+#[cfg(feature = "storage")]
+Enum! {StatusField, [
+  Volumes => "volumes",
+  Sd => "sd",
+  Firmware => "firmware",
+  Retries => "retries"
+]}
+
+/// The options of the `status` command.
+#[cfg(feature = "storage")]
+const STATUS_OPTIONS: &[Opt] = &[
+  Opt {
+    long: "only",
+    short: None,
+    arg: Some("FIELDS"),
+    help: "Only query the given comma-separated parts (volumes|sd|firmware|retries)",
+  },
+  Opt {
+    long: "all-devices",
+    short: None,
+    arg: None,
+    help: "Print the status of every connected Nitrokey Storage",
+  },
+];
+
+/// The options of the `close` command.
+#[cfg(feature = "storage")]
+const CLOSE_OPTIONS: &[Opt] = &[Opt {
+  long: "all-devices",
+  short: None,
+  arg: None,
+  help: "Close the encrypted volume of every connected Nitrokey Storage",
+}];
+
+/// The options of the `open` command.
+#[cfg(feature = "storage")]
+const OPEN_OPTIONS: &[Opt] = &[Opt {
+  long: "panic",
+  short: None,
+  arg: None,
+  help: "Open the decoy hidden volume recorded by hidden create --decoy",
+}];
+
+#[cfg(feature = "storage")]
+Enum! {HiddenCommand, [
+  Close => "close",
+  Create => "create",
+  Open => "open"
+]}
+
+/// The options of the `hidden create` command.
+#[cfg(feature = "storage")]
+const HIDDEN_CREATE_OPTIONS: &[Opt] = &[
+  Opt {
+    long: "decoy",
+    short: None,
+    arg: None,
+    help: "Record the volume and its password, encrypted, for open --panic",
+  },
+  Opt {
+    long: "recipient",
+    short: Some('r'),
+    arg: Some("KEY"),
+    help: "Encrypt the record for the given key instead of with a passphrase (repeatable)",
+  },
+];
+
+#[cfg(feature = "otp")]
+Enum! {OtpCommand, [
+  Get => "get",
+  Pick => "pick",
+  Set => "set"
+]}
+
+#[cfg(feature = "otp")]
+Enum! {OtpAlgorithm, [
+  Hotp => "hotp",
+  Totp => "totp"
+]}
+
+/// The options of the `otp get` command.
+///
+/// Besides our own options, a subset of the ones of `oathtool` is
+/// supported, so that scripts written for it keep working.
+#[cfg(feature = "otp")]
+const OTP_GET_OPTIONS: &[Opt] = &[
+  Opt {
+    long: "algorithm",
+    short: Some('a'),
+    arg: Some("ALGORITHM"),
+    help: "The OTP algorithm to use (hotp|totp, default: totp)",
+  },
+  Opt {
+    long: "all-totp",
+    short: None,
+    arg: None,
+    help: "Generate OTPs using all programmed TOTP slots",
+  },
+  Opt {
+    long: "at",
+    short: None,
+    arg: Some("TIME"),
+    help: "Generate the TOTP for the given RFC 3339 or Unix time",
+  },
+  Opt {
+    long: "base32",
+    short: Some('b'),
+    arg: None,
+    help: "Accepted for compatibility with oathtool and ignored",
+  },
+  Opt {
+    long: "clip",
+    short: Some('c'),
+    arg: None,
+    help: "Copy the OTP to the clipboard instead of printing it",
+  },
+  Opt {
+    long: "force-show",
+    short: None,
+    arg: None,
+    help: "Print the OTP even if hide-secrets is set",
+  },
+  Opt {
+    long: "digits",
+    short: Some('d'),
+    arg: Some("DIGITS"),
+    help: "Fail unless the OTP has the given number of digits",
+  },
+  Opt {
+    long: "fresh",
+    short: Some('f'),
+    arg: Some("SECONDS"),
+    help: "Wait for the next TOTP time window if fewer seconds remain",
+  },
+  Opt {
+    long: "hotp",
+    short: None,
+    arg: None,
+    help: "Use HOTP, like --algorithm=hotp",
+  },
+  Opt {
+    long: "offset",
+    short: None,
+    arg: Some("SECONDS"),
+    help: "Add the given offset to the time for generating a TOTP",
+  },
+  Opt {
+    long: "totp",
+    short: None,
+    arg: None,
+    help: "Use TOTP, like --algorithm=totp",
+  },
+  Opt {
+    long: "validity",
+    short: None,
+    arg: None,
+    help: "Print the seconds the TOTP remains valid on a second line",
+  },
+];
+
+/// The options of the `otp pick` command.
+#[cfg(feature = "otp")]
+const OTP_PICK_OPTIONS: &[Opt] = &[
+  Opt {
+    long: "clip",
+    short: Some('c'),
+    arg: None,
+    help: "Copy the OTP to the clipboard instead of printing it",
+  },
+  Opt {
+    long: "force-show",
+    short: None,
+    arg: None,
+    help: "Print the OTP even if hide-secrets is set",
+  },
+];
+
+/// The options of the `otp set` command.
+#[cfg(feature = "otp")]
+const OTP_SET_OPTIONS: &[Opt] = &[
+  Opt {
+    long: "algorithm",
+    short: Some('a'),
+    arg: Some("ALGORITHM"),
+    help: "The OTP algorithm to use (hotp|totp, default: totp)",
+  },
+  Opt {
+    long: "digits",
+    short: Some('d'),
+    arg: Some("DIGITS"),
+    help: "The number of digits of the OTPs (6|8, default: 6)",
+  },
+  Opt {
+    long: "interactive",
+    short: Some('i'),
+    arg: None,
+    help: "Ask for all settings and verify the secret before writing",
+  },
+];
+
+#[cfg(feature = "pws")]
+Enum! {PassCommand, [
+  Insert => "insert",
+  Ls => "ls",
+  Pick => "pick",
+  Show => "show",
+  Type => "type"
+]}
+
+/// The options of the `pass show` command.
+#[cfg(feature = "pws")]
+const PASS_SHOW_OPTIONS: &[Opt] = &[
+  Opt {
+    long: "clip",
+    short: Some('c'),
+    arg: None,
+    help: "Copy the password to the clipboard instead of printing it",
+  },
+  Opt {
+    long: "force-show",
+    short: None,
+    arg: None,
+    help: "Print the password even if hide-secrets is set",
+  },
+];
+
+/// The options of the `pass pick` command.
+#[cfg(feature = "pws")]
+const PASS_PICK_OPTIONS: &[Opt] = &[
+  Opt {
+    long: "clip",
+    short: Some('c'),
+    arg: None,
+    help: "Copy the password to the clipboard instead of printing it",
+  },
+  Opt {
+    long: "force-show",
+    short: None,
+    arg: None,
+    help: "Print the password even if hide-secrets is set",
+  },
+  Opt {
+    long: "type",
+    short: Some('t'),
+    arg: None,
+    help: "Type the entry into the focused window like pass type",
+  },
+];
+
+/// The options of the `pass type` command.
+#[cfg(feature = "pws")]
+const PASS_TYPE_OPTIONS: &[Opt] = &[Opt {
+  long: "delay",
+  short: Some('d'),
+  arg: Some("SECONDS"),
+  help: "Wait before typing, e.g., to focus another window (default: 0)",
+}];
+
+/// The options of the `pass insert` command.
+#[cfg(feature = "pws")]
+const PASS_INSERT_OPTIONS: &[Opt] = &[
+  Opt {
+    long: "force",
+    short: Some('f'),
+    arg: None,
+    help: "Overwrite an existing entry",
+  },
+  Opt {
+    long: "generate-passphrase",
+    short: None,
+    arg: Some("[=WORDS]"),
+    help: "Generate a passphrase of random words (default: 4)",
+  },
+];
+
+Enum! {PinCommand, [
+  Set => "set"
+]}
+
+Enum! {ProvisionCommand, [
+  Apply => "apply",
+  Verify => "verify"
+]}
+
+/// The options of the `provision apply` command.
+const PROVISION_APPLY_OPTIONS: &[Opt] = &[Opt {
+  long: "all-devices",
+  short: None,
+  arg: None,
+  help: "Apply the profile to every connected Nitrokey Storage",
+}];
+
+/// The options of the `pin set` command.
+const PIN_SET_OPTIONS: &[Opt] = &[Opt {
+  long: "force",
+  short: Some('f'),
+  arg: None,
+  help: "Do not ask for confirmation before changing the admin PIN",
+}];
+
+#[cfg(feature = "storage")]
+Enum! {UpdateCommand, [
+  Download => "download",
+  Enter => "enter",
+  Export => "export",
+  Flash => "flash",
+  Leave => "leave",
+  Verify => "verify"
+]}
+
+/// The options of the `update download` command.
+#[cfg(feature = "storage")]
+const UPDATE_DOWNLOAD_OPTIONS: &[Opt] = &[Opt {
+  long: "version",
+  short: None,
+  arg: Some("VERSION"),
+  help: "Download the given firmware version instead of the latest one",
+}];
+
+/// The options of the `update flash` command.
+#[cfg(feature = "storage")]
+const UPDATE_FLASH_OPTIONS: &[Opt] = &[
+  Opt {
+    long: "force",
+    short: Some('f'),
+    arg: None,
+    help: "Do not ask for confirmation before erasing the firmware",
+  },
+  Opt {
+    long: "version",
+    short: None,
+    arg: Some("VERSION"),
+    help: "Check that the device comes back with the given firmware version",
+  },
+];
+
+/// The options of the `update verify` command.
+#[cfg(feature = "storage")]
+const UPDATE_VERIFY_OPTIONS: &[Opt] = &[Opt {
+  long: "version",
+  short: None,
+  arg: Some("VERSION"),
+  help: "Compare with the given firmware version instead of the one of the device",
+}];
+
+Enum! {SetupCommand, [
+  Device => "device",
+  Udev => "udev"
+]}
+
+/// The options of the `setup udev` command.
+const SETUP_UDEV_OPTIONS: &[Opt] = &[
+  Opt {
+    long: "check",
+    short: Some('c'),
+    arg: None,
+    help: "Check whether the current user can access the connected devices",
+  },
+  Opt {
+    long: "print",
+    short: Some('p'),
+    arg: None,
+    help: "Print the udev rules instead of installing them",
+  },
+];
+
+Enum! {VaultCommand, [
+  Decrypt => "decrypt",
+  Encrypt => "encrypt"
+]}
+
+/// The options of the `vault encrypt` command.
+const VAULT_ENCRYPT_OPTIONS: &[Opt] = &[
+  Opt {
+    long: "recipient",
+    short: Some('r'),
+    arg: Some("KEY"),
+    help: "Encrypt for the given key instead of with a passphrase (repeatable)",
+  },
+  Opt {
+    long: "remove",
+    short: None,
+    arg: None,
+    help: "Overwrite and remove the file once it was encrypted",
+  },
+];
+
+/// Convert a configuration value into an option argument.
+fn value_to_arg(value: &config::Value) -> String {
+  match value {
+    config::Value::String(s) => s.clone(),
+    value => value.to_string(),
+  }
+}
+
+/// Retrieve the options configured as defaults for the command with
+/// the given path.
+///
+/// Defaults are read from the configuration table named after the
+/// command's path, with the names of subcommands separated by dots (as
+/// in `[pin.set]`). Keys corresponding to global options are skipped.
+fn config_options(ctx: &ExecCtx, path: &str, opts: &'static [Opt]) -> Result<Vec<String>> {
+  for (key, _) in ctx.config.entries(path) {
+    if !is_global_key(key) && opts.iter().all(|opt| opt.long != key) {
+      let error = format!(
+        "Invalid configuration key {}.{}: command {} does not have such an option",
+        path,
+        key,
+        path.replace('.', " ")
+      );
+      return Err(Error::Error(error));
+    }
+  }
+
+  let mut args = Vec::new();
+  for opt in opts {
+    let key = opt.long;
+    let var = config::env_var(path, key);
+    let value = match env::var(&var) {
+      Ok(value) => {
+        let kind = match opt.arg {
+          Some(_) => config::Kind::String,
+          None => config::Kind::Boolean,
+        };
+        config::parse_env_value(&var, &value, kind)?
+      }
+      Err(_) => match ctx.config.get(path, key) {
+        Some(value) => value.clone(),
+        None => continue,
+      },
+    };
+
+    match (opt.arg, &value) {
+      (None, config::Value::Boolean(true)) => args.push(format!("--{}", key)),
+      (None, config::Value::Boolean(false)) => (),
+      (None, value) => return Err(config::type_error(path, key, value, "boolean")),
+      (Some(_), config::Value::Array(values)) => args.extend(
+        values
+          .iter()
+          .map(|v| format!("--{}={}", key, value_to_arg(v))),
+      ),
+      (Some(_), value) => args.push(format!("--{}={}", key, value_to_arg(value))),
+    }
+  }
+  Ok(args)
+}
+
+/// Parse the arguments of the command with the given path.
+///
+/// The options configured as defaults for the command are parsed
+/// before the given arguments, so that the latter take precedence.
+fn parse_command_options(
+  ctx: &ExecCtx,
+  path: &str,
+  opts: &'static [Opt],
+  args: &[String],
+) -> Result<(Vec<ParsedOpt>, Vec<String>)> {
+  let mut all = config_options(ctx, path, opts)?;
+  all.extend_from_slice(args);
+  parse_options(opts, &all, false)
+}
+
+/// Check that no arguments were supplied to the command with the given
+/// path.
+fn no_args(ctx: &ExecCtx, path: &str, args: &[String]) -> Result<()> {
+  let (_, args) = parse_command_options(ctx, path, &[], args)?;
+  if args.is_empty() {
+    Ok(())
+  } else {
+    let error = format!(
+      "Command {} does not accept arguments",
+      path.replace('.', " ")
+    );
+    Err(Error::Error(error))
+  }
+}
+
+/// Parse a PIN type from its string representation.
+fn parse_pin_type(s: &str) -> Result<pinentry::PinType> {
+  match s {
+    "admin" => Ok(pinentry::PinType::Admin),
+    "user" => Ok(pinentry::PinType::User),
+    _ => Err(Error::Error(format!("Invalid PIN type: {}", s))),
+  }
+}
+
+/// Retrieve all known configuration keys, along with the kinds of
+/// their values.
+fn config_keys() -> Vec<(&'static str, &'static str, config::Kind)> {
+  let keys = GLOBAL_KEYS.iter();
+  #[cfg(any(feature = "otp", feature = "pws"))]
+  let keys = keys.chain(clipboard::CONFIG_KEYS);
+  #[cfg(feature = "pws")]
+  let keys = keys.chain(autotype::CONFIG_KEYS);
+  let keys = keys
+    .chain(audit::CONFIG_KEYS)
+    .chain(lock::CONFIG_KEYS)
+    .chain(log::CONFIG_KEYS)
+    .chain(notify::CONFIG_KEYS)
+    .chain(pager::CONFIG_KEYS)
+    .chain(pinentry::CONFIG_KEYS)
+    .chain(policy::CONFIG_KEYS)
+    .chain(retry::CONFIG_KEYS);
+  #[cfg(feature = "storage")]
+  let keys = keys.chain(session::CONFIG_KEYS);
+  #[cfg(feature = "storage")]
+  let keys = keys.chain(update::CONFIG_KEYS);
+  keys.cloned().collect()
+}
+
+/// Execute the askpass command.
+#[cfg(feature = "pws")]
+fn askpass(ctx: &ExecCtx, args: &[String]) -> Result<()> {
+  let (_, args) = parse_command_options(ctx, "askpass", &[], args)?;
+  match args.as_slice() {
+    [prompt] => commands::askpass(ctx, prompt),
+    _ => Err(Error::Error(Command::Askpass.usage())),
+  }
+}
+
+/// Execute the completions command.
+fn completions(ctx: &ExecCtx, path: &str, args: &[String]) -> Result<()> {
+  let usage = Command::Completions.usage();
+
+  let (options, args) = parse_command_options(ctx, path, COMPLETIONS_OPTIONS, args)?;
+  let mut list = None;
+  for (opt, value) in &options {
+    if let ("list", Some(kind)) = (opt.long, value) {
+      list = Some(kind)
+    }
+  }
+  match (list, args.as_slice()) {
+    (Some(kind), []) => commands::completions_list(kind),
+    (None, [shell]) => {
+      let shell = shell.parse::<Shell>().map_err(|()| {
+        let error = format!(
+          "Invalid shell: {} (expected one of {})",
+          shell,
+          Shell::all_str().join(", ")
+        );
+        Error::Error(error)
+      })?;
+      commands::completions(shell, &program_spec())
+    }
+    _ => Err(Error::Error(usage)),
+  }
+}
+
+/// Describe the given options for completion and the man page.
+///
+/// Options with an optional argument are described as flags, as the
+/// argument cannot be supplied separately.
+fn spec_options(opts: &[Opt]) -> Vec<completions::Opt> {
+  opts
+    .iter()
+    .map(|opt| completions::Opt {
+      long: opt.long,
+      short: opt.short,
+      arg: opt
+        .arg
+        .filter(|_| !is_optional(opt))
+        .map(|name| completions::Arg {
+          name,
+          values: match name {
+            #[cfg(feature = "otp")]
+            "ALGORITHM" => completions::Values::Words(OtpAlgorithm::all_str()),
+            "DIGITS" => completions::Values::Words(&["6", "8"]),
+            "FILE" | "PATH" => completions::Values::Files,
+            "FORMAT" => completions::Values::Words(ReportFormat::all_str()),
+            #[cfg(feature = "storage")]
+            "FIELDS" => completions::Values::Words(StatusField::all_str()),
+            "KIND" => completions::Values::Words(&["otp-slots", "serial-numbers"]),
+            "MODEL" => completions::Values::Words(DeviceModel::all_str()),
+            "SERIAL" => completions::Values::SerialNumbers,
+            _ => completions::Values::None,
+          },
+        }),
+      help: opt.help,
+    })
+    .collect()
+}
+
+/// Describe a command without subcommands for completion and the man
+/// page.
+fn spec_command(
+  name: &'static str,
+  help: &'static str,
+  options: &[Opt],
+  (usage, args): (&'static str, completions::Values),
+) -> completions::Command {
+  completions::Command {
+    name,
+    help,
+    options: spec_options(options),
+    subcommands: Vec::new(),
+    usage,
+    args,
+  }
+}
+
+/// Describe the command line of the program, as used for completion
+/// and the man page.
+fn program_spec() -> completions::Command {
+  use crate::completions::Values;
+
+  let none = ("", Values::None);
+  let commands = Command::all_str()
+    .iter()
+    .map(|&name| {
+      let command = name.parse::<Command>().unwrap();
+      let (options, args, subcommands) = match command {
+        #[cfg(any(feature = "otp", feature = "pws"))]
+        Command::Alias => {
+          let subcommands = AliasCommand::all_str()
+            .iter()
+            .map(|&name| match name.parse::<AliasCommand>().unwrap() {
+              AliasCommand::Add => spec_command(
+                name,
+                "Register an alias for a slot",
+                &[],
+                ("<alias> <kind>/<slot>", Values::None),
+              ),
+              AliasCommand::List => spec_command(name, "List the aliases", &[], none),
+              AliasCommand::Remove => {
+                spec_command(name, "Remove an alias", &[], ("<alias>", Values::None))
+              }
+            })
+            .collect();
+          (&[][..], none, subcommands)
+        }
+        #[cfg(feature = "otp")]
+        Command::Agent => (AGENT_OPTIONS, none, Vec::new()),
+        #[cfg(feature = "pws")]
+        Command::Askpass => (&[][..], ("<prompt>", Values::None), Vec::new()),
+        Command::Bench => (BENCH_OPTIONS, none, Vec::new()),
+        Command::Help => {
+          // The names of commands are only known at run time, as they
+          // depend on the enabled features.
+          let names = Command::all_str()
+            .iter()
+            .chain(HelpTopic::all_str())
+            .cloned()
+            .collect::<Vec<_>>();
+          let names = Box::leak(names.into_boxed_slice());
+          (
+            &[][..],
+            ("[<command>|<topic>]", Values::Words(names)),
+            Vec::new(),
+          )
+        }
+        Command::Completions => (
+          COMPLETIONS_OPTIONS,
+          ("<bash|fish|zsh>", Values::Words(Shell::all_str())),
+          Vec::new(),
+        ),
+        Command::Config => {
+          let subcommands = ConfigCommand::all_str()
+            .iter()
+            .map(|&name| match name.parse::<ConfigCommand>().unwrap() {
+              ConfigCommand::ShowEffective => {
+                spec_command(name, "Print the effective configuration", &[], none)
+              }
+              #[cfg(feature = "otp")]
+              ConfigCommand::TestBinding => spec_command(
+                name,
+                "Test the HOTP slot bound to a lock key",
+                &[],
+                ("<key>", Values::Words(LockKey::all_str())),
+              ),
+            })
+            .collect();
+          (&[][..], none, subcommands)
+        }
+        Command::Device => {
+          let subcommands = DeviceCommand::all_str()
+            .iter()
+            .map(|&name| match name.parse::<DeviceCommand>().unwrap() {
+              DeviceCommand::List => {
+                spec_command(name, "List the devices with a nickname", &[], none)
+              }
+              DeviceCommand::Name => spec_command(
+                name,
+                "Set the nickname of a device",
+                &[],
+                ("<serial> <name>", Values::SerialNumbers),
+              ),
+              DeviceCommand::Unname => spec_command(
+                name,
+                "Remove the nickname of a device",
+                &[],
+                ("<serial>", Values::SerialNumbers),
+              ),
+            })
+            .collect();
+          (&[][..], none, subcommands)
+        }
+        Command::Fleet => {
+          let subcommands = FleetCommand::all_str()
+            .iter()
+            .map(|&name| match name.parse::<FleetCommand>().unwrap() {
+              FleetCommand::Report => spec_command(
+                name,
+                "Print an inventory of all known devices",
+                FLEET_REPORT_OPTIONS,
+                none,
+              ),
+            })
+            .collect();
+          (&[][..], none, subcommands)
+        }
+        Command::Module => (&[][..], ("[<task-file>]", Values::Files), Vec::new()),
+        Command::Shell => (&[][..], ("[<script>]", Values::Files), Vec::new()),
+        #[cfg(feature = "storage")]
+        Command::Status => (STATUS_OPTIONS, none, Vec::new()),
+        #[cfg(feature = "storage")]
+        Command::Close => (CLOSE_OPTIONS, none, Vec::new()),
+        #[cfg(feature = "storage")]
+        Command::Open => (OPEN_OPTIONS, none, Vec::new()),
+        #[cfg(feature = "storage")]
+        Command::Hidden => {
+          let subcommands = HiddenCommand::all_str()
+            .iter()
+            .map(|&name| match name.parse::<HiddenCommand>().unwrap() {
+              HiddenCommand::Close => spec_command(name, "Close the open hidden volume", &[], none),
+              HiddenCommand::Create => spec_command(
+                name,
+                "Create a hidden volume",
+                HIDDEN_CREATE_OPTIONS,
+                ("<slot> <start> <end>", Values::None),
+              ),
+              HiddenCommand::Open => spec_command(name, "Open a hidden volume", &[], none),
+            })
+            .collect();
+          (&[][..], none, subcommands)
+        }
+        Command::Monitor => (MONITOR_OPTIONS, none, Vec::new()),
+        #[cfg(feature = "otp")]
+        Command::Otp => {
+          let subcommands = OtpCommand::all_str()
+            .iter()
+            .map(|&name| match name.parse::<OtpCommand>().unwrap() {
+              OtpCommand::Get => spec_command(
+                name,
+                "Generate one-time passwords",
+                OTP_GET_OPTIONS,
+                ("[<slot>...]", Values::OtpSlots),
+              ),
+              OtpCommand::Pick => spec_command(
+                name,
+                "Pick an OTP slot from a list and generate a one-time password",
+                OTP_PICK_OPTIONS,
+                none,
+              ),
+              OtpCommand::Set => spec_command(
+                name,
+                "Write an OTP slot",
+                OTP_SET_OPTIONS,
+                ("[<slot> <name>]", Values::None),
+              ),
+            })
+            .collect();
+          (&[][..], none, subcommands)
+        }
+        #[cfg(feature = "pws")]
+        Command::Pass => {
+          let subcommands = PassCommand::all_str()
+            .iter()
+            .map(|&name| match name.parse::<PassCommand>().unwrap() {
+              PassCommand::Insert => spec_command(
+                name,
+                "Insert an entry into the password safe",
+                PASS_INSERT_OPTIONS,
+                ("<name>", Values::None),
+              ),
+              PassCommand::Ls => {
+                spec_command(name, "List the entries of the password safe", &[], none)
+              }
+              PassCommand::Pick => spec_command(
+                name,
+                "Pick an entry of the password safe from a list",
+                PASS_PICK_OPTIONS,
+                none,
+              ),
+              PassCommand::Show => spec_command(
+                name,
+                "Print an entry of the password safe",
+                PASS_SHOW_OPTIONS,
+                ("<name>", Values::None),
+              ),
+              PassCommand::Type => spec_command(
+                name,
+                "Type an entry of the password safe into the focused window",
+                PASS_TYPE_OPTIONS,
+                ("<name>", Values::None),
+              ),
+            })
+            .collect();
+          (&[][..], none, subcommands)
+        }
+        Command::Pin => {
+          let subcommands = PinCommand::all_str()
+            .iter()
+            .map(|&name| match name.parse::<PinCommand>().unwrap() {
+              PinCommand::Set => spec_command(
+                name,
+                "Change a PIN",
+                PIN_SET_OPTIONS,
+                ("<admin|user>", Values::Words(&["admin", "user"])),
+              ),
+            })
+            .collect();
+          (&[][..], none, subcommands)
+        }
+        Command::Provision => {
+          let subcommands = ProvisionCommand::all_str()
+            .iter()
+            .map(|&name| match name.parse::<ProvisionCommand>().unwrap() {
+              ProvisionCommand::Apply => spec_command(
+                name,
+                "Bring the device into the state described by a profile",
+                PROVISION_APPLY_OPTIONS,
+                ("<profile>", Values::Files),
+              ),
+              ProvisionCommand::Verify => spec_command(
+                name,
+                "Check whether the device is in the state described by a profile",
+                &[],
+                ("<profile>", Values::Files),
+              ),
+            })
+            .collect();
+          (&[][..], none, subcommands)
+        }
+        Command::Setup => {
+          let subcommands = SetupCommand::all_str()
+            .iter()
+            .map(|&name| match name.parse::<SetupCommand>().unwrap() {
+              SetupCommand::Device => {
+                spec_command(name, "Interactively set up a new device", &[], none)
+              }
+              SetupCommand::Udev => spec_command(
+                name,
+                "Install udev rules granting access to the devices",
+                SETUP_UDEV_OPTIONS,
+                none,
+              ),
+            })
+            .collect();
+          (&[][..], none, subcommands)
+        }
+        #[cfg(feature = "storage")]
+        Command::Update => {
+          let subcommands = UpdateCommand::all_str()
+            .iter()
+            .map(|&name| match name.parse::<UpdateCommand>().unwrap() {
+              UpdateCommand::Download => spec_command(
+                name,
+                "Download and verify a firmware image",
+                UPDATE_DOWNLOAD_OPTIONS,
+                none,
+              ),
+              UpdateCommand::Enter => {
+                spec_command(name, "Put the device into update mode", &[], none)
+              }
+              UpdateCommand::Flash => spec_command(
+                name,
+                "Flash a firmware image",
+                UPDATE_FLASH_OPTIONS,
+                ("<image>", Values::Files),
+              ),
+              UpdateCommand::Export => spec_command(
+                name,
+                "Export the firmware to the unencrypted volume",
+                &[],
+                none,
+              ),
+              UpdateCommand::Leave => {
+                spec_command(name, "Launch the firmware of the device again", &[], none)
+              }
+              UpdateCommand::Verify => spec_command(
+                name,
+                "Check a firmware image against the published checksum",
+                UPDATE_VERIFY_OPTIONS,
+                ("<image>", Values::Files),
+              ),
+            })
+            .collect();
+          (&[][..], none, subcommands)
+        }
+        Command::Vault => {
+          let subcommands = VaultCommand::all_str()
+            .iter()
+            .map(|&name| match name.parse::<VaultCommand>().unwrap() {
+              VaultCommand::Decrypt => spec_command(
+                name,
+                "Print a decrypted file",
+                &[],
+                ("<file>", Values::Files),
+              ),
+              VaultCommand::Encrypt => spec_command(
+                name,
+                "Encrypt a file",
+                VAULT_ENCRYPT_OPTIONS,
+                ("<file>", Values::Files),
+              ),
+            })
+            .collect();
+          (&[][..], none, subcommands)
+        }
+        _ => (&[][..], none, Vec::new()),
+      };
+      completions::Command {
+        subcommands,
+        ..spec_command(name, command.description(), options, args)
+      }
+    })
+    .collect();
+
+  completions::Command {
+    subcommands: commands,
+    ..spec_command("nitrocli", "", GLOBAL_OPTIONS, none)
+  }
+}
+
+/// Check whether the given name is that of a command, which aliases
+/// cannot shadow.
+fn is_command(name: &str) -> bool {
+  name.parse::<Command>().is_ok()
+}
+
+/// Execute the given line entered in a shell.
+///
+/// Returns whether the shell has to be left.
+fn execute_line(ctx: &ExecCtx, line: &str) -> Result<bool> {
+  let words = shell::split(line)?;
+  let words = alias::expand(&ctx.config, &words, |name| {
+    is_command(name) || shell::BUILTINS.iter().any(|(builtin, _)| *builtin == name)
+  })?;
+  let (name, args) = match words.split_first() {
+    Some((name, args)) => (name, args),
+    None => return Ok(false),
+  };
+  match name.as_str() {
+    "exit" => return Ok(true),
+    "help" if args.is_empty() => {
+      print!("{}\nShell commands:\n", commands_usage());
+      for (name, description) in shell::BUILTINS {
+        println!("  {:<8}{}", name, description);
+      }
+      return Ok(false);
+    }
+    _ => (),
+  }
+
+  #[cfg(feature = "extensions")]
+  {
+    if !is_command(name) {
+      if let Some(path) = extensions::find(name) {
+        return extensions::run(ctx, &path, args).map(|_| false);
+      }
+    }
+  }
+  match parse_command(name)? {
+    Command::Shell => Err(Error::Error("Already running a shell".to_string())),
+    command => command.execute(ctx, args).map(|_| false),
+  }
+}
+
+/// Execute the help command.
+///
+/// Besides the topics, the help of commands and extensions is printed,
+/// with commands and topics possibly being abbreviated.
+fn help(ctx: &ExecCtx, args: &[String]) -> Result<()> {
+  let (name, args) = match args.split_first() {
+    Some((name, args)) => (name, args),
+    None => return pager::page(ctx, &usage("nitrocli")),
+  };
+
+  #[cfg(feature = "extensions")]
+  {
+    if !is_command(name) {
+      if let Some(path) = extensions::find(name) {
+        return extensions::run(ctx, &path, &["--help".to_string()]);
+      }
+    }
+  }
+
+  let names = Command::all_str()
+    .iter()
+    .chain(HelpTopic::all_str())
+    .cloned()
+    .collect::<Vec<_>>();
+  match resolve(name, &names) {
+    Ok(name) => match (name.parse::<Command>(), name.parse::<HelpTopic>()) {
+      (Ok(command), _) => pager::page(ctx, &format!("{}\n", command.help())),
+      (_, Ok(topic)) if args.is_empty() => pager::page(ctx, &format!("{}\n", topic.text())),
+      _ => Err(Error::Error(Command::Help.usage())),
+    },
+    Err(ref candidates) if !candidates.is_empty() => Err(ambiguous_command(name, candidates)),
+    Err(_) => {
+      let error = format!(
+        "Invalid command or topic: {}\nAvailable topics: {}",
+        name,
+        HelpTopic::all_str().join(" ")
+      );
+      Err(Error::Error(error))
+    }
+  }
+}
+
+/// Execute the shell command.
+///
+/// If a script is given, the commands it contains are executed instead
+/// of the ones entered at the prompt. Like the latter, they share the
+/// connection to the device, its admin authentication, and the PINs
+/// entered.
+fn shell(ctx: &ExecCtx, path: &str, args: &[String]) -> Result<()> {
+  let (_, args) = parse_command_options(ctx, path, &[], args)?;
+  if args.len() > 1 {
+    return Err(Error::Error(Command::Shell.usage()));
+  }
+
+  *ctx.session.borrow_mut() = Some(Default::default());
+  let result = match args.first() {
+    Some(script) => run_script(ctx, script),
+    None => run_prompt(ctx),
+  };
+  // Dropping the session wipes the PINs it holds.
+  *ctx.session.borrow_mut() = None;
+  result
+}
+
+/// Execute the commands of the script at the given path, one per line,
+/// stopping at the first one failing.
+fn run_script(ctx: &ExecCtx, path: &str) -> Result<()> {
+  let script = fs::read_to_string(path)
+    .map_err(|err| Error::Error(format!("Could not read {}: {}", path, err)))?;
+  for (number, line) in script.lines().enumerate() {
+    let exit = execute_line(ctx, line)
+      .map_err(|err| err.context(format!("Line {} of {} failed", number + 1, path)))?;
+    if exit {
+      break;
+    }
+  }
+  Ok(())
+}
+
+/// Execute the commands entered at the prompt until the shell is left.
+fn run_prompt(ctx: &ExecCtx) -> Result<()> {
+  let program = program_spec();
+  let mut editor = shell::Editor::new();
+  #[cfg(feature = "otp")]
+  {
+    if tty::is_stdin_tty() {
+      if let Err(err) = commands::prefetch(ctx) {
+        debug!("Could not prefetch the OTP slot names: {}", err);
+      }
+      commands::unlock_device(ctx);
+    }
+  }
+  while let Some(line) = editor.read_line("nitrocli> ", &program)? {
+    let result = execute_line(ctx, &line);
+    // Other processes may use the device while we are waiting for the
+    // next command, but the connection is kept.
+    commands::unlock_device(ctx);
+    match report(ctx, result) {
+      Ok(true) => break,
+      Ok(false) | Err(Error::ExitStatus(_)) => (),
+      Err(err @ Error::Interrupted(_)) => return Err(err),
+      Err(err) => println!("{}", err),
+    }
+  }
+  Ok(())
+}
+
+/// Execute an alias command.
+#[cfg(any(feature = "otp", feature = "pws"))]
+fn alias(ctx: &ExecCtx, args: &[String]) -> Result<()> {
+  let usage = Command::Alias.usage();
+
+  let (subcommand, args) = args
+    .split_first()
+    .ok_or_else(|| Error::Error(usage.clone()))?;
+  let subcommand = parse_subcommand(Command::Alias, subcommand, AliasCommand::all_str())?;
+  let path = format!("alias.{}", subcommand);
+  let (_, args) = parse_command_options(ctx, &path, &[], args)?;
+
+  match (subcommand, args.as_slice()) {
+    (AliasCommand::Add, [alias, target]) => commands::alias_add(ctx, alias, target),
+    (AliasCommand::List, []) => commands::alias_list(ctx),
+    (AliasCommand::Remove, [alias]) => commands::alias_remove(ctx, alias),
+    _ => Err(Error::Error(usage)),
+  }
+}
+
+/// Execute a device command.
+fn device(ctx: &ExecCtx, args: &[String]) -> Result<()> {
+  let usage = Command::Device.usage();
+
+  let (subcommand, args) = args
+    .split_first()
+    .ok_or_else(|| Error::Error(usage.clone()))?;
+  let subcommand = parse_subcommand(Command::Device, subcommand, DeviceCommand::all_str())?;
+  let path = format!("device.{}", subcommand);
+  let (_, args) = parse_command_options(ctx, &path, &[], args)?;
+
+  match (subcommand, args.as_slice()) {
+    (DeviceCommand::List, []) => commands::device_list(),
+    (DeviceCommand::Name, [serial, name]) => commands::device_name(serial, name),
+    (DeviceCommand::Unname, [serial]) => commands::device_unname(serial),
+    _ => Err(Error::Error(usage)),
+  }
+}
+
+/// Execute a fleet command.
+fn fleet(ctx: &ExecCtx, args: &[String]) -> Result<()> {
+  let usage = Command::Fleet.usage();
+
+  let (subcommand, args) = args
+    .split_first()
+    .ok_or_else(|| Error::Error(usage.clone()))?;
+  let subcommand = parse_subcommand(Command::Fleet, subcommand, FleetCommand::all_str())?;
+  let path = format!("fleet.{}", subcommand);
+
+  match subcommand {
+    FleetCommand::Report => {
+      let (options, args) = parse_command_options(ctx, &path, FLEET_REPORT_OPTIONS, args)?;
+      if !args.is_empty() {
+        return Err(Error::Error(usage));
+      }
+      let mut format = ReportFormat::Json;
+      for (opt, value) in &options {
+        if let ("format", Some(value)) = (opt.long, value) {
+          format = value.parse().map_err(|()| {
+            let error = format!("Invalid report format: {} (expected csv or json)", value);
+            Error::Error(error)
+          })?;
+        }
+      }
+      commands::fleet_report(ctx, format)
+    }
+  }
+}
+
+/// Execute a config command.
+fn config(ctx: &ExecCtx, args: &[String]) -> Result<()> {
+  let usage = Command::Config.usage();
+
+  let (subcommand, args) = args
+    .split_first()
+    .ok_or_else(|| Error::Error(usage.clone()))?;
+  let subcommand = parse_subcommand(Command::Config, subcommand, ConfigCommand::all_str())?;
+  let path = format!("config.{}", subcommand);
+
+  match subcommand {
+    ConfigCommand::ShowEffective => {
+      no_args(ctx, &path, args)?;
+      commands::config_show_effective(ctx, &config_keys())
+    }
+    #[cfg(feature = "otp")]
+    ConfigCommand::TestBinding => {
+      let (_, args) = parse_command_options(ctx, &path, &[], args)?;
+      match args.as_slice() {
+        [key] => {
+          let key = key
+            .parse::<LockKey>()
+            .map_err(|_| Error::Error(usage.clone()))?;
+          commands::config_test_binding(ctx, key)
+        }
+        _ => Err(Error::Error(usage)),
+      }
+    }
+  }
+}
+
+/// Parse an OTP algorithm from its string representation.
+#[cfg(feature = "otp")]
+pub fn parse_otp_algorithm(s: &str) -> Result<OtpAlgorithm> {
+  s.parse::<OtpAlgorithm>().map_err(|()| {
+    let error = format!(
+      "Invalid OTP algorithm: {} (expected one of {})",
+      s,
+      OtpAlgorithm::all_str().join(", ")
+    );
+    Error::Error(error)
+  })
+}
+
+/// Parse the number of digits of an OTP.
+#[cfg(feature = "otp")]
+pub fn parse_digits(s: &str) -> Result<usize> {
+  match s.parse::<usize>() {
+    Ok(digits) if digits == 6 || digits == 8 => Ok(digits),
+    _ => Err(Error::Error(format!(
+      "Invalid number of digits: {} (expected 6 or 8)",
+      s
+    ))),
+  }
+}
+
+/// Parse the minimum validity of a TOTP requested with `otp get
+/// --fresh`, in seconds.
+///
+/// A TOTP never remains valid for longer than the time window, so
+/// larger values would make us wait forever.
+#[cfg(feature = "otp")]
+fn parse_fresh(s: &str) -> Result<u64> {
+  match s.parse::<u64>() {
+    Ok(seconds) if seconds < u64::from(commands::TOTP_TIME_WINDOW) => Ok(seconds),
+    _ => Err(Error::Error(format!(
+      "Invalid number of seconds: {} (expected less than {})",
+      s,
+      commands::TOTP_TIME_WINDOW
+    ))),
+  }
+}
+
+/// Parse the argument of `otp get --at`, either a Unix time or an RFC
+/// 3339 timestamp.
+#[cfg(feature = "otp")]
+fn parse_at(s: &str) -> Result<u64> {
+  let time = if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+    s.parse::<u64>().ok()
+  } else {
+    log::parse_time(s)
+  };
+  time.ok_or_else(|| {
+    Error::Error(format!(
+      "Invalid time: {} (expected an RFC 3339 timestamp or a Unix time)",
+      s
+    ))
+  })
+}
+
+/// Parse the number of words of a passphrase to generate.
+///
+/// Passphrases have to fit into the password of a password safe entry.
+#[cfg(feature = "pws")]
+fn parse_words(s: &str) -> Result<usize> {
+  let max = (commands::PWS_PASSWORD_LENGTH + 1) / (passphrase::length(1) + 1);
+  match s.parse::<usize>() {
+    Ok(words) if words >= 1 && words <= max => Ok(words),
+    _ => Err(Error::Error(format!(
+      "Invalid number of words: {} (expected 1 to {})",
+      s, max
+    ))),
+  }
+}
+
+/// Execute the agent command.
+#[cfg(feature = "otp")]
+fn agent(ctx: &ExecCtx, path: &str, args: &[String]) -> Result<()> {
+  let usage = Command::Agent.usage();
+
+  let (options, args) = parse_command_options(ctx, path, AGENT_OPTIONS, args)?;
+  if !args.is_empty() {
+    return Err(Error::Error(usage));
+  }
+
+  let mut metrics = None;
+  let mut interval = agent::DEFAULT_METRICS_INTERVAL;
+  for (opt, value) in &options {
+    match (opt.long, value) {
+      ("metrics", Some(value)) => metrics = Some(path::PathBuf::from(value)),
+      ("metrics-interval", Some(value)) => {
+        interval = match value.parse::<u64>() {
+          Ok(interval) if interval > 0 && interval <= agent::MAX_METRICS_INTERVAL => interval,
+          _ => {
+            let error = format!(
+              "Invalid metrics interval: {} (expected 1 to {})",
+              value,
+              agent::MAX_METRICS_INTERVAL
+            );
+            return Err(Error::Error(error));
+          }
+        }
+      }
+      _ => (),
+    }
+  }
+
+  let metrics = metrics.map(|path| agent::MetricsFile {
+    path,
+    interval: time::Duration::from_secs(interval),
+  });
+  agent::run(ctx, metrics)
+}
+
+/// Execute the bench command.
+fn bench(ctx: &ExecCtx, path: &str, args: &[String]) -> Result<()> {
+  let usage = Command::Bench.usage();
+
+  let (options, args) = parse_command_options(ctx, path, BENCH_OPTIONS, args)?;
+  if !args.is_empty() {
+    return Err(Error::Error(usage));
+  }
+
+  let mut iterations = commands::DEFAULT_BENCH_ITERATIONS;
+  for (opt, value) in &options {
+    if let ("iterations", Some(value)) = (opt.long, value) {
+      iterations = match value.parse::<usize>() {
+        Ok(iterations) if iterations > 0 => iterations,
+        _ => {
+          let error = format!("Invalid number of iterations: {}", value);
+          return Err(Error::Error(error));
+        }
+      }
+    }
+  }
+  commands::bench(ctx, iterations)
+}
+
+/// Execute the status command.
+#[cfg(feature = "storage")]
+fn status(ctx: &ExecCtx, path: &str, args: &[String]) -> Result<()> {
+  let (options, args) = parse_command_options(ctx, path, STATUS_OPTIONS, args)?;
+  if !args.is_empty() {
+    return Err(Error::Error(Command::Status.usage()));
+  }
+
+  let mut fields = Vec::new();
+  for (opt, value) in &options {
+    if let ("only", Some(value)) = (opt.long, value) {
+      for field in value.split(',') {
+        let field = field.parse::<StatusField>().map_err(|()| {
+          let error = format!(
+            "Invalid status field: {} (expected one of {})",
+            field,
+            StatusField::all_str().join(", ")
+          );
+          Error::Error(error)
+        })?;
+        if !fields.contains(&field) {
+          fields.push(field);
+        }
+      }
+    }
+  }
+  if options.iter().any(|(opt, _)| opt.long == "all-devices") {
+    commands::for_all_devices(ctx, |ctx| commands::status(ctx, &fields))
+  } else {
+    commands::status(ctx, &fields)
+  }
+}
+
+/// Execute the monitor command.
+fn monitor(ctx: &ExecCtx, path: &str, args: &[String]) -> Result<()> {
+  let usage = Command::Monitor.usage();
+
+  let (options, args) = parse_command_options(ctx, path, MONITOR_OPTIONS, args)?;
+  if !args.is_empty() {
+    return Err(Error::Error(usage));
+  }
+
+  let mut socket = None;
+  let mut interval = monitor::DEFAULT_INTERVAL;
+  let mut print = false;
+  for (opt, value) in &options {
+    match (opt.long, value) {
+      ("interval", Some(value)) => {
+        interval = match value.parse::<u64>() {
+          Ok(interval) if interval > 0 && interval <= monitor::MAX_INTERVAL => interval,
+          _ => {
+            let error = format!(
+              "Invalid interval: {} (expected 1 to {})",
+              value,
+              monitor::MAX_INTERVAL
+            );
+            return Err(Error::Error(error));
+          }
+        }
+      }
+      ("socket", Some(value)) => socket = Some(path::PathBuf::from(value)),
+      ("print", None) => print = true,
+      _ => (),
+    }
+  }
+
+  let socket = match socket {
+    Some(socket) => socket,
+    None => monitor::socket_path(ctx)?,
+  };
+  monitor::run(ctx, &socket, time::Duration::from_secs(interval), print)
+}
+
+/// Execute an OTP command.
+#[cfg(feature = "otp")]
+fn otp(ctx: &ExecCtx, args: &[String]) -> Result<()> {
+  let usage = Command::Otp.usage();
+
+  let (subcommand, args) = args
+    .split_first()
+    .ok_or_else(|| Error::Error(usage.clone()))?;
+  let subcommand = parse_subcommand(Command::Otp, subcommand, OtpCommand::all_str())?;
+  let path = format!("otp.{}", subcommand);
+
+  match subcommand {
+    OtpCommand::Get => {
+      let (options, args) = parse_command_options(ctx, &path, OTP_GET_OPTIONS, args)?;
+      let mut algorithm = OtpAlgorithm::Totp;
+      let mut all_totp = false;
+      let mut digits = None;
+      let mut clip = false;
+      let mut force_show = false;
+      let mut totp = commands::TotpOptions::default();
+      for (opt, value) in &options {
+        match (opt.long, value) {
+          ("algorithm", Some(value)) => algorithm = parse_otp_algorithm(value)?,
+          ("all-totp", _) => all_totp = true,
+          ("at", Some(value)) => totp.at = Some(parse_at(value)?),
+          ("clip", _) => clip = true,
+          ("digits", Some(value)) => digits = Some(parse_digits(value)?),
+          ("force-show", _) => force_show = true,
+          ("fresh", Some(value)) => totp.fresh = Some(parse_fresh(value)?),
+          ("hotp", _) => algorithm = OtpAlgorithm::Hotp,
+          ("offset", Some(value)) => {
+            let offset = value
+              .parse::<i64>()
+              .map_err(|_| Error::Error(format!("Invalid offset: {}", value)))?;
+            totp.offset = Some(offset);
+          }
+          ("totp", _) => algorithm = OtpAlgorithm::Totp,
+          ("validity", _) => totp.validity = true,
+          _ => (),
+        }
+      }
+      if algorithm == OtpAlgorithm::Hotp && !all_totp && totp != Default::default() {
+        let error = "--at, --fresh, --offset, and --validity only apply to TOTP slots";
+        return Err(Error::Error(error.to_string()));
+      }
+      if totp.at.is_some() && (totp.fresh.is_some() || totp.validity) {
+        let error = "--at cannot be combined with --fresh or --validity";
+        return Err(Error::Error(error.to_string()));
+      }
+
+      let clip = clipboard::hide_secret(&ctx.config, clip, force_show)?;
+      match args.as_slice() {
+        [] if !all_totp => Err(Error::Error(usage)),
+        [slot] if !all_totp => commands::otp_get(ctx, slot, algorithm, digits, clip, totp),
+        _ if clip => Err(Error::Error(
+          "Only the OTP of a single slot can be copied to the clipboard".to_string(),
+        )),
+        slots => commands::otp_get_many(ctx, slots, algorithm, all_totp, digits, totp),
+      }
+    }
+    OtpCommand::Pick => {
+      let (options, args) = parse_command_options(ctx, &path, OTP_PICK_OPTIONS, args)?;
+      let clip = options.iter().any(|(opt, _)| opt.long == "clip");
+      let force_show = options.iter().any(|(opt, _)| opt.long == "force-show");
+      let clip = clipboard::hide_secret(&ctx.config, clip, force_show)?;
+      match args.as_slice() {
+        [] => commands::otp_pick(ctx, clip),
+        _ => Err(Error::Error(usage)),
+      }
+    }
+    OtpCommand::Set => {
+      let (options, args) = parse_command_options(ctx, &path, OTP_SET_OPTIONS, args)?;
+      let mut algorithm = OtpAlgorithm::Totp;
+      let mut digits = 6;
+      let mut interactive = false;
+      for (opt, value) in &options {
+        match (opt.long, value) {
+          ("algorithm", Some(value)) => algorithm = parse_otp_algorithm(value)?,
+          ("digits", Some(value)) => digits = parse_digits(value)?,
+          ("interactive", _) => interactive = true,
+          _ => (),
+        }
+      }
+
+      if interactive {
+        if args.is_empty() {
+          commands::otp_set_interactive(ctx)
+        } else {
+          Err(Error::Error(usage))
+        }
+      } else {
+        match args.as_slice() {
+          [slot, name] => {
+            let slot = slot
+              .parse::<u8>()
+              .map_err(|_| Error::Error(format!("Invalid slot: {}", slot)))?;
+            commands::otp_set(ctx, algorithm, slot, name, digits)
+          }
+          _ => Err(Error::Error(usage)),
+        }
+      }
+    }
+  }
+}
+
+/// Execute a pass command.
+#[cfg(feature = "pws")]
+fn pass(ctx: &ExecCtx, args: &[String]) -> Result<()> {
+  let usage = Command::Pass.usage();
+
+  let (subcommand, args) = args
+    .split_first()
+    .ok_or_else(|| Error::Error(usage.clone()))?;
+  let subcommand = parse_subcommand(Command::Pass, subcommand, PassCommand::all_str())?;
+  let path = format!("pass.{}", subcommand);
+
+  match subcommand {
+    PassCommand::Insert => {
+      let (options, args) = parse_command_options(ctx, &path, PASS_INSERT_OPTIONS, args)?;
+      let mut force = false;
+      let mut generate = None;
+      for (opt, value) in &options {
+        match (opt.long, value) {
+          ("force", _) => force = true,
+          ("generate-passphrase", Some(value)) => generate = Some(parse_words(value)?),
+          ("generate-passphrase", None) => generate = Some(passphrase::DEFAULT_WORDS),
+          _ => (),
+        }
+      }
+      match args.as_slice() {
+        [name] => commands::pass_insert(ctx, name, force, generate),
+        _ => Err(Error::Error(usage)),
+      }
+    }
+    PassCommand::Ls => no_args(ctx, &path, args).and_then(|_| commands::pass_ls(ctx)),
+    PassCommand::Pick => {
+      let (options, args) = parse_command_options(ctx, &path, PASS_PICK_OPTIONS, args)?;
+      let clip = options.iter().any(|(opt, _)| opt.long == "clip");
+      let force_show = options.iter().any(|(opt, _)| opt.long == "force-show");
+      let typed = options.iter().any(|(opt, _)| opt.long == "type");
+      if typed && (clip || force_show) {
+        let error = "--type cannot be combined with --clip or --force-show".to_string();
+        return Err(Error::Error(error));
+      }
+      let clip = clipboard::hide_secret(&ctx.config, clip, force_show)?;
+      match args.as_slice() {
+        [] => commands::pass_pick(ctx, clip, typed),
+        _ => Err(Error::Error(usage)),
+      }
+    }
+    PassCommand::Show => {
+      let (options, args) = parse_command_options(ctx, &path, PASS_SHOW_OPTIONS, args)?;
+      let clip = options.iter().any(|(opt, _)| opt.long == "clip");
+      let force_show = options.iter().any(|(opt, _)| opt.long == "force-show");
+      let clip = clipboard::hide_secret(&ctx.config, clip, force_show)?;
+      match args.as_slice() {
+        [name] => commands::pass_show(ctx, name, clip),
+        _ => Err(Error::Error(usage)),
+      }
+    }
+    PassCommand::Type => {
+      let (options, args) = parse_command_options(ctx, &path, PASS_TYPE_OPTIONS, args)?;
+      let mut delay = 0;
+      for (opt, value) in &options {
+        if let ("delay", Some(value)) = (opt.long, value) {
+          delay = value
+            .parse::<u64>()
+            .map_err(|_| Error::Error(format!("Invalid delay: {}", value)))?;
+        }
+      }
+      match args.as_slice() {
+        [name] => commands::pass_type(ctx, name, time::Duration::from_secs(delay)),
+        _ => Err(Error::Error(usage)),
+      }
+    }
+  }
+}
+
+/// Execute a PIN command.
+fn pin(ctx: &ExecCtx, args: &[String]) -> Result<()> {
+  let usage = Command::Pin.usage();
+
+  let (subcommand, args) = args
+    .split_first()
+    .ok_or_else(|| Error::Error(usage.clone()))?;
+  let subcommand = parse_subcommand(Command::Pin, subcommand, PinCommand::all_str())?;
+  let path = format!("pin.{}", subcommand);
+  let (options, args) = parse_command_options(ctx, &path, PIN_SET_OPTIONS, args)?;
+  let force = options.iter().any(|(opt, _)| opt.long == "force");
+
+  match (subcommand, args.as_slice()) {
+    (PinCommand::Set, [pin_type]) => commands::pin_set(ctx, parse_pin_type(pin_type)?, force),
+    _ => Err(Error::Error(usage)),
+  }
+}
+
+/// Execute a provision command.
+fn provision(ctx: &ExecCtx, args: &[String]) -> Result<()> {
+  let usage = Command::Provision.usage();
+
+  let (subcommand, args) = args
+    .split_first()
+    .ok_or_else(|| Error::Error(usage.clone()))?;
+  let subcommand = parse_subcommand(Command::Provision, subcommand, ProvisionCommand::all_str())?;
+  let path = format!("provision.{}", subcommand);
+  let opts = match subcommand {
+    ProvisionCommand::Apply => PROVISION_APPLY_OPTIONS,
+    ProvisionCommand::Verify => &[],
+  };
+  let (options, args) = parse_command_options(ctx, &path, opts, args)?;
+  let all_devices = options.iter().any(|(opt, _)| opt.long == "all-devices");
+
+  match (subcommand, args.as_slice()) {
+    (ProvisionCommand::Apply, [profile]) => commands::provision_apply(ctx, profile, all_devices),
+    (ProvisionCommand::Verify, [profile]) => commands::provision_verify(ctx, profile),
+    _ => Err(Error::Error(usage)),
+  }
+}
+
+/// Execute a setup command.
+fn setup(ctx: &ExecCtx, args: &[String]) -> Result<()> {
+  let usage = Command::Setup.usage();
+
+  let (subcommand, args) = args
+    .split_first()
+    .ok_or_else(|| Error::Error(usage.clone()))?;
+  let subcommand = parse_subcommand(Command::Setup, subcommand, SetupCommand::all_str())?;
+  let path = format!("setup.{}", subcommand);
+
+  match subcommand {
+    SetupCommand::Device => no_args(ctx, &path, args).and_then(|_| commands::setup_device(ctx)),
+    SetupCommand::Udev => {
+      let (options, args) = parse_command_options(ctx, &path, SETUP_UDEV_OPTIONS, args)?;
+      if !args.is_empty() {
+        return Err(Error::Error(usage));
+      }
+      let check = options.iter().any(|(opt, _)| opt.long == "check");
+      let print = options.iter().any(|(opt, _)| opt.long == "print");
+      match (check, print) {
+        (true, true) => Err(Error::Error(
+          "The --check and --print options are mutually exclusive".to_string(),
+        )),
+        (true, false) => commands::setup_udev_check(),
+        (false, true) => commands::setup_udev_print(),
+        (false, false) => commands::setup_udev_install(ctx),
+      }
+    }
+  }
+}
+
+/// Execute the open command.
+#[cfg(feature = "storage")]
+fn open(ctx: &ExecCtx, path: &str, args: &[String]) -> Result<()> {
+  let (options, args) = parse_command_options(ctx, path, OPEN_OPTIONS, args)?;
+  if !args.is_empty() {
+    return Err(Error::Error(Command::Open.usage()));
+  }
+  if options.iter().any(|(opt, _)| opt.long == "panic") {
+    commands::open_panic(ctx)
+  } else {
+    commands::open(ctx)
+  }
+}
+
+/// Execute the close command.
+#[cfg(feature = "storage")]
+fn close(ctx: &ExecCtx, path: &str, args: &[String]) -> Result<()> {
+  let (options, args) = parse_command_options(ctx, path, CLOSE_OPTIONS, args)?;
+  if !args.is_empty() {
+    return Err(Error::Error(Command::Close.usage()));
+  }
+  if options.iter().any(|(opt, _)| opt.long == "all-devices") {
+    commands::for_all_devices(ctx, commands::close)
+  } else {
+    commands::close(ctx)
+  }
+}
+
+/// Execute a hidden command.
+#[cfg(feature = "storage")]
+fn hidden(ctx: &ExecCtx, args: &[String]) -> Result<()> {
+  let usage = Command::Hidden.usage();
+
+  let (subcommand, args) = args
+    .split_first()
+    .ok_or_else(|| Error::Error(usage.clone()))?;
+  let subcommand = parse_subcommand(Command::Hidden, subcommand, HiddenCommand::all_str())?;
+  let path = format!("hidden.{}", subcommand);
+
+  match subcommand {
+    HiddenCommand::Close => no_args(ctx, &path, args).and_then(|_| commands::hidden_close(ctx)),
+    HiddenCommand::Create => {
+      let (options, args) = parse_command_options(ctx, &path, HIDDEN_CREATE_OPTIONS, args)?;
+      let recipients = options
+        .iter()
+        .filter(|(opt, _)| opt.long == "recipient")
+        .filter_map(|(_, value)| value.clone())
+        .collect::<Vec<_>>();
+      let decoy = options.iter().any(|(opt, _)| opt.long == "decoy");
+      if !recipients.is_empty() && !decoy {
+        let error = "The --recipient option requires --decoy";
+        return Err(Error::Error(error.to_string()));
+      }
+      match args.as_slice() {
+        [slot, start, end] => {
+          let parse = |name: &str, value: &str| {
+            value
+              .parse::<u8>()
+              .map_err(|_| Error::Error(format!("Invalid hidden volume {}: {}", name, value)))
+          };
+          let slot = parse("slot", slot)?;
+          let start = parse("start", start)?;
+          let end = parse("end", end)?;
+          commands::hidden_create(ctx, slot, start, end, decoy, &recipients)
+        }
+        _ => Err(Error::Error(usage)),
+      }
+    }
+    HiddenCommand::Open => no_args(ctx, &path, args).and_then(|_| commands::hidden_open(ctx)),
+  }
+}
+
+/// Execute an update command.
+#[cfg(feature = "storage")]
+fn update(ctx: &ExecCtx, args: &[String]) -> Result<()> {
+  let usage = Command::Update.usage();
+
+  let (subcommand, args) = args
+    .split_first()
+    .ok_or_else(|| Error::Error(usage.clone()))?;
+  let subcommand = parse_subcommand(Command::Update, subcommand, UpdateCommand::all_str())?;
+  let path = format!("update.{}", subcommand);
+
+  match subcommand {
+    UpdateCommand::Download => {
+      let (options, args) = parse_command_options(ctx, &path, UPDATE_DOWNLOAD_OPTIONS, args)?;
+      if !args.is_empty() {
+        return Err(Error::Error(usage));
+      }
+      let mut version = None;
+      for (opt, value) in &options {
+        if let ("version", Some(value)) = (opt.long, value) {
+          version = Some(value.as_str())
+        }
+      }
+      commands::update_download(ctx, version)
+    }
+    UpdateCommand::Enter => no_args(ctx, &path, args).and_then(|_| commands::update_enter(ctx)),
+    UpdateCommand::Flash => {
+      let (options, args) = parse_command_options(ctx, &path, UPDATE_FLASH_OPTIONS, args)?;
+      let mut version = None;
+      let mut force = false;
+      for (opt, value) in &options {
+        match (opt.long, value) {
+          ("force", _) => force = true,
+          ("version", Some(value)) => version = Some(value.as_str()),
+          _ => (),
+        }
+      }
+      match args.as_slice() {
+        [image] => commands::update_flash(ctx, path::Path::new(image), version, force),
+        _ => Err(Error::Error(usage)),
+      }
+    }
+    UpdateCommand::Export => no_args(ctx, &path, args).and_then(|_| commands::update_export(ctx)),
+    UpdateCommand::Leave => no_args(ctx, &path, args).and_then(|_| commands::update_leave(ctx)),
+    UpdateCommand::Verify => {
+      let (options, args) = parse_command_options(ctx, &path, UPDATE_VERIFY_OPTIONS, args)?;
+      let mut version = None;
+      for (opt, value) in &options {
+        if let ("version", Some(value)) = (opt.long, value) {
+          version = Some(value.as_str())
+        }
+      }
+      match args.as_slice() {
+        [image] => commands::update_verify(ctx, path::Path::new(image), version),
+        _ => Err(Error::Error(usage)),
+      }
+    }
+  }
+}
+
+/// Execute a vault command.
+fn vault(ctx: &ExecCtx, args: &[String]) -> Result<()> {
+  let usage = Command::Vault.usage();
+
+  let (subcommand, args) = args
+    .split_first()
+    .ok_or_else(|| Error::Error(usage.clone()))?;
+  let subcommand = parse_subcommand(Command::Vault, subcommand, VaultCommand::all_str())?;
+  let path = format!("vault.{}", subcommand);
+
+  match subcommand {
+    VaultCommand::Decrypt => match args {
+      [file] => commands::vault_decrypt(path::Path::new(file)),
+      _ => Err(Error::Error(usage)),
+    },
+    VaultCommand::Encrypt => {
+      let (options, args) = parse_command_options(ctx, &path, VAULT_ENCRYPT_OPTIONS, args)?;
+      let recipients = options
+        .iter()
+        .filter(|(opt, _)| opt.long == "recipient")
+        .filter_map(|(_, value)| value.clone())
+        .collect::<Vec<_>>();
+      let remove = options.iter().any(|(opt, _)| opt.long == "remove");
+      match args.as_slice() {
+        [file] => commands::vault_encrypt(path::Path::new(file), &recipients, remove),
+        _ => Err(Error::Error(usage)),
+      }
+    }
+  }
+}
+
+/// Compute the edit distance between the given strings, i.e., the
+/// number of characters that have to be inserted, deleted, or replaced,
+/// or of adjacent characters that have to be swapped, to turn one into
+/// the other.
+fn edit_distance(a: &str, b: &str) -> usize {
+  let a = a.chars().collect::<Vec<_>>();
+  let b = b.chars().collect::<Vec<_>>();
+  // d[i][j] is the distance between the first i characters of a and the
+  // first j characters of b.
+  let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+  for (i, row) in d.iter_mut().enumerate() {
+    row[0] = i;
+  }
+  for (j, distance) in d[0].iter_mut().enumerate() {
+    *distance = j;
+  }
+  for i in 1..=a.len() {
+    for j in 1..=b.len() {
+      let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+      let mut distance = (d[i - 1][j] + 1)
+        .min(d[i][j - 1] + 1)
+        .min(d[i - 1][j - 1] + cost);
+      if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+        distance = distance.min(d[i - 2][j - 2] + 1);
+      }
+      d[i][j] = distance;
+    }
+  }
+  d[a.len()][b.len()]
+}
+
+/// Find the candidate closest to the given mistyped name, if any is
+/// close enough to be a plausible suggestion.
+fn suggest<'c, I>(name: &str, candidates: I) -> Option<&'c str>
+where
+  I: IntoIterator<Item = &'c str>,
+{
+  // Allow for about one typo per three characters, but not for
+  // replacing the name altogether.
+  let max_distance = (name.chars().count() / 3).max(1);
+  candidates
+    .into_iter()
+    .map(|candidate| (edit_distance(name, candidate), candidate))
+    .filter(|&(distance, _)| distance <= max_distance)
+    .min_by_key(|&(distance, _)| distance)
+    .map(|(_, candidate)| candidate)
+}
+
+/// Resolve the given name among the given names of commands.
+///
+/// Besides its full name, a command may be referred to by any prefix of
+/// it that is not a prefix of any other command. If the name cannot be
+/// resolved, the names of all the commands it is a prefix of are
+/// returned, which are none if it does not abbreviate any command.
+fn resolve<'n>(name: &str, names: &[&'n str]) -> result::Result<&'n str, Vec<&'n str>> {
+  if let Some(&name) = names.iter().find(|&&candidate| candidate == name) {
+    return Ok(name);
+  }
+  if name.is_empty() {
+    return Err(Vec::new());
+  }
+
+  let candidates = names
+    .iter()
+    .cloned()
+    .filter(|candidate| candidate.starts_with(name))
+    .collect::<Vec<_>>();
+  if candidates.len() == 1 {
+    Ok(candidates[0])
+  } else {
+    Err(candidates)
+  }
+}
+
+/// Create the error reporting the given ambiguous command along with
+/// the commands it could refer to.
+fn ambiguous_command(command: &str, candidates: &[&str]) -> Error {
+  let error = format!(
+    "Ambiguous command: {}\nCandidates: {}",
+    command,
+    candidates.join(" ")
+  );
+  Error::Error(error)
+}
+
+/// Parse the given, possibly abbreviated, name of a top-level command.
+fn parse_command(name: &str) -> Result<Command> {
+  match resolve(name, Command::all_str()) {
+    Ok(name) => Ok(name.parse().unwrap()),
+    Err(ref candidates) if !candidates.is_empty() => Err(ambiguous_command(name, candidates)),
+    Err(_) => Err(invalid_command(name)),
+  }
+}
+
+/// Parse the given, possibly abbreviated, name of a subcommand of the
+/// given command among the given names of its subcommands.
+fn parse_subcommand<T>(command: Command, name: &str, names: &[&str]) -> Result<T>
+where
+  T: str::FromStr<Err = ()>,
+{
+  match resolve(name, names) {
+    Ok(name) => Ok(name.parse().unwrap()),
+    Err(ref candidates) if !candidates.is_empty() => {
+      let name = format!("{} {}", command, name);
+      Err(ambiguous_command(&name, candidates))
+    }
+    Err(_) => Err(Error::Error(command.usage())),
+  }
+}
+
+/// Create the error reporting the given invalid command, suggesting the
+/// closest command or extension.
+fn invalid_command(command: &str) -> Error {
+  #[cfg(feature = "extensions")]
+  let extensions = extensions::list()
+    .into_iter()
+    .map(|(name, _)| name)
+    .collect::<Vec<_>>();
+  #[cfg(not(feature = "extensions"))]
+  let extensions = Vec::<String>::new();
+  let candidates = Command::all_str()
+    .iter()
+    .cloned()
+    .chain(extensions.iter().map(String::as_str));
+
+  let suggestion = match suggest(command, candidates) {
+    Some(suggestion) => format!(", did you mean {}?", suggestion),
+    None => String::new(),
+  };
+  let error = format!(
+    "Invalid command: {}{}\nAvailable commands: {}",
+    command,
+    suggestion,
+    Command::all_str().join(" ")
+  );
+  Error::Error(error)
+}
+
+/// Retrieve the list of the available commands for the usage
+/// information.
+fn commands_usage() -> String {
+  let mut usage = "Available commands (see <command> --help):\n".to_string();
+  for command in Command::all_str() {
+    // All strings we enumerate here originate from `Command` and so
+    // they are guaranteed to parse.
+    let description = command.parse::<Command>().unwrap().description();
+    usage += &format!("  {:<8}{}\n", command, description);
+  }
+
+  #[cfg(feature = "extensions")]
+  usage.push_str(&extensions_usage());
+  usage
+}
+
+/// Retrieve the usage information for the program.
+fn usage(program: &str) -> String {
+  format!(
+    "Usage: {} [<options>] <command> [<args>]\n\nOptions:\n{}\n{}",
+    program,
+    format_options(GLOBAL_OPTIONS),
+    commands_usage()
+  )
+}
+
+/// Retrieve the list of installed extensions for the usage information.
+#[cfg(feature = "extensions")]
+fn extensions_usage() -> String {
+  let extensions = extensions::list();
+  if extensions.is_empty() {
+    return String::new();
+  }
+
+  let mut usage = "\nInstalled extensions:\n".to_string();
+  for (name, path) in extensions {
+    let description = extensions::description(&path).unwrap_or_default();
+    // Names of extensions may be longer than those of commands, so we
+    // have to make sure to separate them from the description.
+    let line = format!("  {:<7} {}", name, description);
+    usage += line.trim_end();
+    usage += "\n";
+  }
+  usage
+}
+
+/// Apply the global settings configured for the command (or extension)
+/// with the given name.
+///
+/// Keys corresponding to global options in the table named after the
+/// command take precedence over the top-level ones.
+fn apply_command_config(config: &mut config::Config, command: &str) {
+  let section = command.to_string();
+  for &(_, key, _) in GLOBAL_KEYS {
+    if let Some(value) = config.get(&section, key).cloned() {
+      config.set("", key, value, config::Source::File(section.clone()));
+    }
+  }
+}
+
+/// Apply the device profile selected in the configuration, if any.
+///
+/// A profile is a table named `device.<name>` containing keys of global
+/// options other than `profile`. They take precedence over the
+/// top-level and per-command ones.
+fn apply_profile(config: &mut config::Config) -> Result<()> {
+  let name = match config.get_string("", "profile")? {
+    Some(name) => name.to_string(),
+    None => return Ok(()),
+  };
+  let section = format!("device.{}", name);
+  if !config.has_section(&section) {
+    return Err(Error::Error(format!("Unknown device profile: {}", name)));
+  }
+
+  let entries = config
+    .entries(&section)
+    .map(|(key, value)| (key.to_string(), value.clone()))
+    .collect::<Vec<_>>();
+  for (key, value) in entries {
+    if key == "usb-path" {
+      let error = format!(
+        "Invalid configuration key {}.{}: selecting devices by USB path is not supported",
+        section, key
+      );
+      return Err(Error::Error(error));
+    } else if key == "profile" || !is_global_key(&key) {
+      let error = format!("Invalid configuration key {}.{}", section, key);
+      return Err(Error::Error(error));
+    }
+    config.set("", &key, value, config::Source::File(section.clone()));
+  }
+  Ok(())
+}
+
+/// Apply the given global options to the configuration.
+///
+/// Options given on the command line take precedence over the values
+/// from the configuration file.
+fn apply_options(config: &mut config::Config, options: &[ParsedOpt]) -> Result<()> {
+  let mut serial_numbers = Vec::new();
+  let mut verbosity = 0;
+  let mut policy_file = None;
+
+  let mut set = |key, value| config.set("", key, value, config::Source::CommandLine);
+
+  for (opt, value) in options {
+    match (opt.long, value) {
+      ("dry-run", _) => set("dry-run", config::Value::Boolean(true)),
+      ("model", Some(model)) => set("model", config::Value::String(model.clone())),
+      ("no-cache", _) => set("no-cache", config::Value::Boolean(true)),
+      ("no-pager", _) => set("no-pager", config::Value::Boolean(true)),
+      ("no-prompt", _) => set("no-prompt", config::Value::Boolean(true)),
+      ("policy-file", Some(path)) => policy_file = Some(path.clone()),
+      ("serial-number", Some(serial)) => serial_numbers.push(config::Value::String(serial.clone())),
+      ("timeout", Some(value)) => match value.parse::<i64>() {
+        Ok(timeout) => set("timeout", config::Value::Integer(timeout)),
+        Err(_) => return Err(Error::Error(format!("Invalid timeout: {}", value))),
+      },
+      ("verbose", _) => verbosity += 1,
+      _ => (),
+    }
+  }
+
+  if !serial_numbers.is_empty() {
+    set("serial-numbers", config::Value::Array(serial_numbers));
+  }
+  if verbosity > 0 {
+    set("verbosity", config::Value::Integer(verbosity));
+  }
+  if let Some(path) = policy_file {
+    let path = config::Value::String(path);
+    config.set("pin-policy", "file", path, config::Source::CommandLine);
+  }
+  Ok(())
+}
+
+/// Merge the given configuration with the environment, as retrieved
+/// using the given function, and the global options for the command
+/// (or extension) with the given name.
+///
+/// Settings are applied in order of increasing precedence: top-level
+/// keys, keys from the command's table, keys from the selected device
+/// profile, environment variables, and options given on the command
+/// line.
+fn merge_config<F>(
+  mut config: config::Config,
+  command: &str,
+  options: &[ParsedOpt],
+  getenv: F,
+) -> Result<config::Config>
+where
+  F: Fn(&str) -> Option<String>,
+{
+  apply_command_config(&mut config, command);
+  // The profile has to be known before its settings can be applied.
+  config.apply_env(&[("", "profile", config::Kind::String)], &getenv)?;
+  for (opt, value) in options {
+    if let ("profile", Some(profile)) = (opt.long, value) {
+      let profile = config::Value::String(profile.clone());
+      config.set("", "profile", profile, config::Source::CommandLine);
+    }
+  }
+  apply_profile(&mut config)?;
+  config.apply_env(GLOBAL_KEYS, &getenv)?;
+  #[cfg(any(feature = "otp", feature = "pws"))]
+  config.apply_env(clipboard::CONFIG_KEYS, &getenv)?;
+  #[cfg(feature = "pws")]
+  config.apply_env(autotype::CONFIG_KEYS, &getenv)?;
+  config.apply_env(audit::CONFIG_KEYS, &getenv)?;
+  config.apply_env(lock::CONFIG_KEYS, &getenv)?;
+  config.apply_env(log::CONFIG_KEYS, &getenv)?;
+  config.apply_env(notify::CONFIG_KEYS, &getenv)?;
+  config.apply_env(pager::CONFIG_KEYS, &getenv)?;
+  config.apply_env(pinentry::CONFIG_KEYS, &getenv)?;
+  config.apply_env(policy::CONFIG_KEYS, &getenv)?;
+  config.apply_env(retry::CONFIG_KEYS, &getenv)?;
+  #[cfg(feature = "storage")]
+  config.apply_env(session::CONFIG_KEYS, &getenv)?;
+  #[cfg(feature = "storage")]
+  config.apply_env(update::CONFIG_KEYS, &getenv)?;
+  let hook_keys = hooks::config_keys(Command::all_str());
+  let hook_keys = hook_keys
+    .iter()
+    .map(|key| (hooks::SECTION, key.as_str(), config::Kind::String))
+    .collect::<Vec<_>>();
+  config.apply_env(&hook_keys, &getenv)?;
+  hooks::check_config(&config, Command::all_str())?;
+  apply_options(&mut config, options)?;
+  Ok(config)
+}
+
+/// Create the context for executing the command (or extension) with
+/// the given name.
+fn create_ctx(config: config::Config, command: &str, options: &[ParsedOpt]) -> Result<ExecCtx> {
+  let getenv = |var: &str| env::var(var).ok();
+  let config = merge_config(config, command, options, getenv)?;
+  let ctx = ExecCtx::new(config)?;
+  log::init(&ctx)?;
+  Ok(ctx)
+}
+
+/// Report the error contained in the given result of a command,
+/// executed in the given context, with all its causes if the verbosity
+/// is non-zero.
+///
+/// Other errors are left to the caller to report as a short message.
+fn report<T>(ctx: &ExecCtx, result: Result<T>) -> Result<T> {
+  match result {
+    Err(Error::ExitStatus(code)) => Err(Error::ExitStatus(code)),
+    Err(ref err) if ctx.verbosity > 0 => {
+      let device = ctx.device.borrow();
+      println!("{}", err.chain(device.as_ref().map(AsRef::as_ref)));
+      Err(Error::ExitStatus(err.exit_code()))
+    }
+    result => result,
+  }
+}
+
+/// The name under which the program acts as an askpass program.
+#[cfg(feature = "pws")]
+const ASKPASS_PROGRAM: &str = "nitrocli-askpass";
+
+/// Parse the command line arguments and execute the requested command.
+pub fn handle_arguments(argv: &[String]) -> Result<()> {
+  let program = argv.first().map(String::as_str).unwrap_or("nitrocli");
+  // When invoked through a link named like this, we act as an askpass
+  // program, which receives the prompt as its only argument.
+  #[cfg(feature = "pws")]
+  {
+    if path::Path::new(program).file_name() == Some(ASKPASS_PROGRAM.as_ref()) {
+      let ctx = create_ctx(config::Config::load()?, "askpass", &[])?;
+      let result = Command::Askpass.execute(&ctx, argv.get(1..).unwrap_or(&[]));
+      return report(&ctx, result);
+    }
+  }
+
+  let (options, args) = parse_options(GLOBAL_OPTIONS, argv.get(1..).unwrap_or(&[]), true)?;
+
+  if options.iter().any(|(opt, _)| opt.long == "help") {
+    print!("{}", usage(program));
+    return Ok(());
+  }
+  if options.iter().any(|(opt, _)| opt.long == "version") {
+    println!("nitrocli {}", env!("CARGO_PKG_VERSION"));
+    return Ok(());
+  }
+  if options
+    .iter()
+    .any(|(opt, _)| opt.long == "generate-manpage")
+  {
+    print!("{}", manpage::generate(&program_spec()));
+    return Ok(());
+  }
+
+  let config = config::Config::load()?;
+  let args = alias::expand(&config, &args, is_command)?;
+  let (command, args) = match args.split_first() {
+    Some((command, args)) => (command, args),
+    None => return Err(Error::Error(usage(program).trim_end().to_string())),
+  };
+  // Extensions are only found by their full name, but take precedence
+  // over abbreviations of commands.
+  #[cfg(feature = "extensions")]
+  {
+    if !is_command(command) {
+      if let Some(path) = extensions::find(command) {
+        let ctx = create_ctx(config, command, &options)?;
+        let result = extensions::run(&ctx, &path, args);
+        return report(&ctx, result);
+      }
+    }
+  }
+  let command = parse_command(command)?;
+  let ctx = create_ctx(config, &command.to_string(), &options)?;
+  let result = command.execute(&ctx, args);
+  report(&ctx, result)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn command_from_str() {
+    #[cfg(feature = "storage")]
+    assert_eq!("status".parse::<Command>(), Ok(Command::Status));
+    assert_eq!("pin".parse::<Command>(), Ok(Command::Pin));
+    assert_eq!("stat".parse::<Command>(), Err(()));
+  }
+
+  #[test]
+  fn resolve_abbreviations() {
+    let names = ["open", "otp", "pass", "pin", "status"];
+    assert_eq!(resolve("otp", &names), Ok("otp"));
+    assert_eq!(resolve("st", &names), Ok("status"));
+    assert_eq!(resolve("ot", &names), Ok("otp"));
+    assert_eq!(resolve("o", &names), Err(vec!["open", "otp"]));
+    assert_eq!(resolve("p", &names), Err(vec!["pass", "pin"]));
+    assert_eq!(resolve("x", &names), Err(vec![]));
+    assert_eq!(resolve("", &names), Err(vec![]));
+    assert_eq!(resolve("", &["get"]), Err(vec![]));
+  }
+
+  #[test]
+  fn command_display_round_trip() {
+    for command in Command::all_str() {
+      assert_eq!(&command.parse::<Command>().unwrap().to_string(), command);
+    }
+  }
+
+  #[test]
+  fn help_option() {
+    assert!(help_requested(&args(&["get", "-h"])));
+    assert!(help_requested(&args(&["--help", "get"])));
+    assert!(!help_requested(&args(&["get", "--", "-h"])));
+    assert!(!help_requested(&args(&["get", "-hotp"])));
+  }
+
+  #[test]
+  fn command_usage() {
+    for command in Command::all_str() {
+      let usage = command.parse::<Command>().unwrap().usage();
+      assert!(usage.starts_with(&format!("Usage: nitrocli {}", command)));
+    }
+  }
+
+  #[test]
+  fn command_help() {
+    for command in Command::all_str() {
+      let command = command.parse::<Command>().unwrap();
+      let help = command.help();
+      assert!(help.starts_with(&command.usage()));
+      for example in command.examples() {
+        let line = format!("    $ nitrocli {}", example.args);
+        assert!(help.lines().any(|l| l == line), "{}", help);
+        let words = shell::split(example.args).unwrap();
+        let (_, args) = parse_options(GLOBAL_OPTIONS, &words, true).unwrap();
+        assert_eq!(args.first(), Some(&command.to_string()), "{}", example.args);
+      }
+    }
+  }
+
+  #[test]
+  fn pin_type_from_str() {
+    assert_eq!(parse_pin_type("admin").unwrap(), pinentry::PinType::Admin);
+    assert_eq!(parse_pin_type("user").unwrap(), pinentry::PinType::User);
+    assert!(parse_pin_type("firmware").is_err());
+  }
+
+  fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+  }
+
+  fn names(options: &[ParsedOpt]) -> Vec<(&str, Option<&str>)> {
+    options
+      .iter()
+      .map(|(opt, value)| (opt.long, value.as_ref().map(String::as_str)))
+      .collect()
+  }
+
+  #[test]
+  fn parse_global_options() {
+    let argv = args(&[
+      "-vv",
+      "--model=pro",
+      "--serial-number",
+      "0x1",
+      "-mstorage",
+      "open",
+    ]);
+    let (options, args) = parse_options(GLOBAL_OPTIONS, &argv, true).unwrap();
+    let expected = vec![
+      ("verbose", None),
+      ("verbose", None),
+      ("model", Some("pro")),
+      ("serial-number", Some("0x1")),
+      ("model", Some("storage")),
+    ];
+    assert_eq!(names(&options), expected);
+    assert_eq!(args, vec!["open".to_string()]);
+  }
+
+  #[test]
+  fn parse_options_stop() {
+    let argv = args(&["--no-cache", "pin", "-v", "--", "-h"]);
+    let (options, args) = parse_options(GLOBAL_OPTIONS, &argv, true).unwrap();
+    assert_eq!(names(&options), vec![("no-cache", None)]);
+    assert_eq!(args, vec!["pin", "-v", "--", "-h"]);
+
+    let (options, args) = parse_options(GLOBAL_OPTIONS, &argv, false).unwrap();
+    assert_eq!(names(&options), vec![("no-cache", None), ("verbose", None)]);
+    assert_eq!(args, vec!["pin", "-h"]);
+  }
+
+  #[test]
+  fn parse_options_invalid() {
+    assert!(parse_options(GLOBAL_OPTIONS, &args(&["--foo"]), true).is_err());
+    assert!(parse_options(GLOBAL_OPTIONS, &args(&["-x"]), true).is_err());
+    assert!(parse_options(GLOBAL_OPTIONS, &args(&["--model"]), true).is_err());
+    assert!(parse_options(GLOBAL_OPTIONS, &args(&["--no-cache=1"]), true).is_err());
+  }
+
+  #[test]
+  fn options_override_config() {
+    let mut config = "model = 'pro'\nverbosity = 1"
+      .parse::<config::Config>()
+      .unwrap();
+    let argv = args(&[
+      "-m",
+      "storage",
+      "--serial-number",
+      "0x1",
+      "--no-prompt",
+      "--dry-run",
+      "--timeout",
+      "10",
+    ]);
+    let (options, _) = parse_options(GLOBAL_OPTIONS, &argv, true).unwrap();
+    apply_options(&mut config, &options).unwrap();
+
+    let ctx = ExecCtx::new(config).unwrap();
+    assert_eq!(ctx.model, Some(DeviceModel::Storage));
+    assert_eq!(ctx.serial_numbers, vec!["0x1".to_string()]);
+    assert_eq!(ctx.verbosity, 1);
+    assert!(!ctx.no_cache);
+    assert!(ctx.no_prompt);
+    assert!(ctx.dry_run);
+    assert_eq!(ctx.timeout, Some(time::Duration::from_secs(10)));
+  }
+
+  #[test]
+  fn command_config() {
+    let mut config = "model = 'pro'\n[open]\nmodel = 'storage'\n[status]\nverbosity = 2"
+      .parse::<config::Config>()
+      .unwrap();
+    apply_command_config(&mut config, "open");
+
+    let ctx = ExecCtx::new(config).unwrap();
+    assert_eq!(ctx.model, Some(DeviceModel::Storage));
+    assert_eq!(ctx.verbosity, 0);
+  }
+
+  const TEST_OPTIONS: &[Opt] = &[
+    Opt {
+      long: "digits",
+      short: None,
+      arg: Some("DIGITS"),
+      help: "",
+    },
+    Opt {
+      long: "force",
+      short: None,
+      arg: None,
+      help: "",
+    },
+  ];
+
+  #[test]
+  fn command_config_options() {
+    let config = "[test]\ndigits = 8\nforce = true\nverbosity = 1"
+      .parse::<config::Config>()
+      .unwrap();
+    let ctx = ExecCtx::new(config).unwrap();
+    let argv = args(&["--digits", "6", "foo"]);
+    let (options, args) = parse_command_options(&ctx, "test", TEST_OPTIONS, &argv).unwrap();
+    let expected = vec![
+      ("digits", Some("8")),
+      ("force", None),
+      ("digits", Some("6")),
+    ];
+    assert_eq!(names(&options), expected);
+    assert_eq!(args, vec!["foo"]);
+  }
+
+  #[test]
+  fn command_config_invalid() {
+    let config = "[test]\nfoo = 1".parse::<config::Config>().unwrap();
+    let ctx = ExecCtx::new(config).unwrap();
+    assert!(parse_command_options(&ctx, "test", TEST_OPTIONS, &[]).is_err());
+
+    let config = "[test]\nforce = 1".parse::<config::Config>().unwrap();
+    let ctx = ExecCtx::new(config).unwrap();
+    assert!(parse_command_options(&ctx, "test", TEST_OPTIONS, &[]).is_err());
+  }
+
+  fn no_env(_: &str) -> Option<String> {
+    None
+  }
+
+  #[test]
+  fn profile_precedence() {
+    let config = r#"
+model = "pro"
+verbosity = 1
+[device.work]
+model = "storage"
+serial-numbers = ["0x1", "0x2"]
+verbosity = 2
+"#;
+    let config = config.parse::<config::Config>().unwrap();
+    let argv = args(&["--profile", "work", "-v"]);
+    let (options, _) = parse_options(GLOBAL_OPTIONS, &argv, true).unwrap();
+
+    let ctx = ExecCtx::new(merge_config(config, "status", &options, no_env).unwrap()).unwrap();
+    assert_eq!(ctx.model, Some(DeviceModel::Storage));
+    assert_eq!(ctx.serial_numbers, vec!["0x1", "0x2"]);
+    assert_eq!(ctx.verbosity, 1);
+  }
+
+  #[test]
+  fn profile_invalid() {
+    let config = "[device.work]\nmodel = 'pro'"
+      .parse::<config::Config>()
+      .unwrap();
+    let argv = args(&["--profile", "home"]);
+    let (options, _) = parse_options(GLOBAL_OPTIONS, &argv, true).unwrap();
+    assert!(merge_config(config, "status", &options, no_env).is_err());
+
+    let config = "profile = 'work'\n[device.work]\nfoo = 1"
+      .parse::<config::Config>()
+      .unwrap();
+    assert!(merge_config(config, "status", &[], no_env).is_err());
+  }
+
+  #[test]
+  fn env_precedence() {
+    let config = r#"
+model = "pro"
+no-cache = false
+[device.work]
+verbosity = 2
+[device.home]
+serial-numbers = ["0x3"]
+"#;
+    let config = config.parse::<config::Config>().unwrap();
+    let argv = args(&["--model=pro"]);
+    let (options, _) = parse_options(GLOBAL_OPTIONS, &argv, true).unwrap();
+    let getenv = |var: &str| match var {
+      "NITROCLI_MODEL" => Some("storage".to_string()),
+      "NITROCLI_NO_CACHE" => Some("true".to_string()),
+      "NITROCLI_PROFILE" => Some("work".to_string()),
+      "NITROCLI_VERBOSITY" => Some("3".to_string()),
+      _ => None,
+    };
+
+    let config = merge_config(config, "status", &options, getenv).unwrap();
+    let ctx = ExecCtx::new(config).unwrap();
+    assert_eq!(ctx.model, Some(DeviceModel::Pro));
+    assert!(ctx.no_cache);
+    assert!(ctx.serial_numbers.is_empty());
+    assert_eq!(ctx.verbosity, 3);
+  }
+
+  #[test]
+  #[cfg(feature = "storage")]
+  fn hooks() {
+    let config = "[hooks]\npost-open = 'mount'"
+      .parse::<config::Config>()
+      .unwrap();
+    let getenv = |var: &str| match var {
+      "NITROCLI_HOOKS_PRE_CLOSE" => Some("sync".to_string()),
+      _ => None,
+    };
+    let config = merge_config(config, "open", &[], getenv).unwrap();
+    assert_eq!(
+      config.get_string("hooks", "post-open").unwrap(),
+      Some("mount")
+    );
+    assert_eq!(
+      config.get_string("hooks", "pre-close").unwrap(),
+      Some("sync")
+    );
+
+    let config = "[hooks]\npost-mount = 'mount'"
+      .parse::<config::Config>()
+      .unwrap();
+    assert!(merge_config(config, "open", &[], no_env).is_err());
+  }
+
+  #[test]
+  #[cfg(feature = "otp")]
+  fn digits_from_str() {
+    assert_eq!(parse_digits("6").unwrap(), 6);
+    assert_eq!(parse_digits("8").unwrap(), 8);
+    assert!(parse_digits("7").is_err());
+    assert!(parse_digits("six").is_err());
+  }
+
+  #[test]
+  #[cfg(feature = "otp")]
+  fn otp_algorithm_from_str() {
+    assert_eq!(parse_otp_algorithm("hotp").unwrap(), OtpAlgorithm::Hotp);
+    assert_eq!(parse_otp_algorithm("totp").unwrap(), OtpAlgorithm::Totp);
+    assert!(parse_otp_algorithm("TOTP").is_err());
+  }
+
+  #[test]
+  fn edit_distances() {
+    assert_eq!(edit_distance("status", "status"), 0);
+    assert_eq!(edit_distance("statsu", "status"), 1);
+    assert_eq!(edit_distance("stat", "status"), 2);
+    assert_eq!(edit_distance("", "open"), 4);
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+  }
+
+  #[test]
+  fn suggest_commands() {
+    let commands = ["close", "config", "open", "status"];
+    assert_eq!(suggest("statsu", commands.iter().cloned()), Some("status"));
+    assert_eq!(suggest("opne", commands.iter().cloned()), Some("open"));
+    assert_eq!(suggest("clsoe", commands.iter().cloned()), Some("close"));
+    assert_eq!(suggest("bogus", commands.iter().cloned()), None);
+    assert_eq!(suggest("x", commands.iter().cloned()), None);
+  }
+}
@@ -0,0 +1,163 @@
+// audit.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::ffi;
+
+use crate::args;
+use crate::config;
+use crate::error::Error;
+use crate::json;
+use crate::storage;
+
+/// The configuration keys controlling the audit log, along with the
+/// kinds of their values.
+pub const CONFIG_KEYS: &[(&str, &str, config::Kind)] = &[("audit", "target", config::Kind::String)];
+
+/// A destination for the audit log.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Target {
+  /// The `audit.log` file in the state directory.
+  File,
+  /// The system log.
+  Syslog,
+}
+
+/// Retrieve the destination of the audit log configured in the given
+/// configuration, if the audit log is enabled.
+fn target(config: &config::Config) -> Result<Option<Target>, Error> {
+  match config.get_string("audit", "target")? {
+    None => Ok(None),
+    Some("file") => Ok(Some(Target::File)),
+    Some("syslog") => Ok(Some(Target::Syslog)),
+    Some(target) => {
+      let error = format!(
+        "Invalid value for configuration key audit.target: {} (expected file or syslog)",
+        target
+      );
+      Err(Error::Error(error))
+    }
+  }
+}
+
+/// Create the audit log entry for the execution of the given command
+/// with the given arguments on the device with the given serial number.
+fn entry(command: &str, args: &[String], serial: Option<&str>, success: bool) -> json::Value {
+  let arguments = args.iter().map(|arg| json::Value::from(arg.as_str()));
+  json::Value::object(vec![
+    ("time", json::Value::from(storage::now())),
+    ("command", json::Value::from(command)),
+    ("arguments", json::Value::Array(arguments.collect())),
+    (
+      "serial_number",
+      serial.map_or(json::Value::Null, json::Value::from),
+    ),
+    (
+      "result",
+      json::Value::from(if success { "ok" } else { "error" }),
+    ),
+  ])
+}
+
+/// Write the given entry to the system log.
+fn syslog(entry: &str) -> Result<(), Error> {
+  let message = ffi::CString::new(entry)
+    .map_err(|_| Error::Error("The audit log entry contains a NUL byte".to_string()))?;
+  unsafe {
+    libc::syslog(
+      libc::LOG_AUTHPRIV | libc::LOG_NOTICE,
+      b"%s\0".as_ptr() as *const libc::c_char,
+      message.as_ptr(),
+    )
+  };
+  Ok(())
+}
+
+/// Record the execution of the given command with the given arguments
+/// in the audit log, if it is enabled.
+///
+/// The entry names the device the command used, if any, and whether the
+/// command succeeded. Secrets never show up in arguments, so they are
+/// never logged. Failure to write the entry is reported as a warning
+/// only, as the command has been executed already.
+pub fn record(
+  ctx: &args::ExecCtx,
+  command: &str,
+  args: &[String],
+  success: bool,
+) -> Result<(), Error> {
+  let target = match target(&ctx.config)? {
+    Some(target) => target,
+    None => return Ok(()),
+  };
+  let serial = ctx.serial.borrow();
+  let entry = entry(command, args, serial.as_ref().map(String::as_str), success).to_string();
+  let result = match target {
+    Target::File => storage::AUDIT_LOG.append(&entry),
+    Target::Syslog => syslog(&entry),
+  };
+  if let Err(err) = result {
+    warn!("Could not write the audit log: {}", err);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn audit_target() {
+    let config = config::Config::default();
+    assert_eq!(target(&config).unwrap(), None);
+    let config = "[audit]\ntarget = 'syslog'"
+      .parse::<config::Config>()
+      .unwrap();
+    assert_eq!(target(&config).unwrap(), Some(Target::Syslog));
+    let config = "[audit]\ntarget = 'journal'"
+      .parse::<config::Config>()
+      .unwrap();
+    assert!(target(&config).is_err());
+  }
+
+  #[test]
+  fn audit_entry() {
+    let args = vec!["set".to_string(), "admin".to_string()];
+    let pin = entry("pin", &args, Some("0x1234abcd"), false);
+    assert_eq!(
+      pin.get("command").and_then(json::Value::as_str),
+      Some("pin")
+    );
+    assert_eq!(
+      pin.get("serial_number").and_then(json::Value::as_str),
+      Some("0x1234abcd")
+    );
+    assert_eq!(
+      pin.get("result").and_then(json::Value::as_str),
+      Some("error")
+    );
+    let arguments = pin
+      .get("arguments")
+      .and_then(json::Value::as_array)
+      .unwrap();
+    assert_eq!(arguments.len(), 2);
+
+    let status = entry("status", &[], None, true);
+    assert_eq!(status.get("serial_number"), Some(&json::Value::Null));
+  }
+}
@@ -0,0 +1,245 @@
+// autotype.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::env;
+use std::io::Write;
+use std::process;
+use std::result;
+
+use crate::config;
+use crate::error::Error;
+use crate::secret::Secret;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The configuration keys controlling how credentials are typed, along
+/// with the kinds of their values.
+pub const CONFIG_KEYS: &[(&str, &str, config::Kind)] =
+  &[("autotype", "sequence", config::Kind::String)];
+
+/// The sequence typed unless configured otherwise.
+const DEFAULT_SEQUENCE: &str = "{login}{tab}{password}{enter}";
+
+/// A key pressed while typing a sequence.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Key {
+  Tab,
+  Enter,
+}
+
+/// A step of a sequence.
+#[derive(Debug, PartialEq)]
+enum Step {
+  Login,
+  Password,
+  Key(Key),
+  Text(String),
+}
+
+/// Parse the given sequence, consisting of text and the placeholders
+/// `{login}`, `{password}`, `{tab}`, and `{enter}`.
+fn parse(sequence: &str) -> Result<Vec<Step>> {
+  let mut steps = Vec::new();
+  let mut rest = sequence;
+  while let Some(start) = rest.find('{') {
+    if start > 0 {
+      steps.push(Step::Text(rest[..start].to_string()));
+    }
+    let end = rest[start..]
+      .find('}')
+      .map(|end| start + end)
+      .ok_or_else(|| {
+        Error::Error(format!(
+          "Unterminated placeholder in autotype sequence: {}",
+          sequence
+        ))
+      })?;
+    steps.push(match &rest[start + 1..end] {
+      "login" => Step::Login,
+      "password" => Step::Password,
+      "tab" => Step::Key(Key::Tab),
+      "enter" => Step::Key(Key::Enter),
+      name => {
+        let error = format!(
+          "Invalid placeholder in autotype sequence: {{{}}} (expected login, password, tab, or enter)",
+          name
+        );
+        return Err(Error::Error(error));
+      }
+    });
+    rest = &rest[end + 1..];
+  }
+  if !rest.is_empty() {
+    steps.push(Step::Text(rest.to_string()));
+  }
+  Ok(steps)
+}
+
+/// A program emulating a keyboard.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Typist {
+  /// `wtype`, for Wayland compositors supporting virtual keyboards.
+  Wtype,
+  /// `xdotool`, for X11 sessions.
+  Xdotool,
+  /// `ydotool`, which uses uinput and works without a graphical
+  /// session.
+  Ydotool,
+}
+
+impl Typist {
+  /// Retrieve the name of the executable.
+  fn program(self) -> &'static str {
+    match self {
+      Typist::Wtype => "wtype",
+      Typist::Xdotool => "xdotool",
+      Typist::Ydotool => "ydotool",
+    }
+  }
+
+  /// Retrieve the arguments making the program type the text read from
+  /// its standard input.
+  ///
+  /// Secrets are never passed as arguments, as those are visible to
+  /// other users of the system.
+  fn text_args(self) -> &'static [&'static str] {
+    match self {
+      Typist::Wtype => &["-"],
+      Typist::Xdotool => &["type", "--clearmodifiers", "--file", "-"],
+      Typist::Ydotool => &["type", "--file", "-"],
+    }
+  }
+
+  /// Retrieve the arguments making the program press the given key.
+  fn key_args(self, key: Key) -> &'static [&'static str] {
+    match (self, key) {
+      (Typist::Wtype, Key::Tab) => &["-k", "Tab"],
+      (Typist::Wtype, Key::Enter) => &["-k", "Return"],
+      (Typist::Xdotool, Key::Tab) => &["key", "--clearmodifiers", "Tab"],
+      (Typist::Xdotool, Key::Enter) => &["key", "--clearmodifiers", "Return"],
+      // ydotool expects Linux input event codes and key states.
+      (Typist::Ydotool, Key::Tab) => &["key", "15:1", "15:0"],
+      (Typist::Ydotool, Key::Enter) => &["key", "28:1", "28:0"],
+    }
+  }
+
+  /// Run the program with the given arguments and input.
+  fn run(self, args: &[&str], input: &[u8]) -> Result<()> {
+    let program = self.program();
+    let mut child = process::Command::new(program)
+      .args(args)
+      .stdin(process::Stdio::piped())
+      .stdout(process::Stdio::null())
+      .spawn()
+      .map_err(|err| Error::Error(format!("Could not run {}: {}", program, err)))?;
+    if let Some(mut stdin) = child.stdin.take() {
+      stdin.write_all(input)?;
+    }
+    let status = child.wait()?;
+    if status.success() {
+      Ok(())
+    } else {
+      let error = format!("{} failed: {}", program, status);
+      Err(Error::Error(error))
+    }
+  }
+}
+
+/// Check whether the given program is available in the `PATH`.
+fn is_available(program: &str) -> bool {
+  match env::var_os("PATH") {
+    Some(paths) => env::split_paths(&paths).any(|dir| dir.join(program).is_file()),
+    None => false,
+  }
+}
+
+/// Determine the program to type with, preferring the one matching the
+/// graphical session.
+fn typist() -> Result<Typist> {
+  let mut candidates = Vec::new();
+  if env::var_os("WAYLAND_DISPLAY").is_some() {
+    candidates.push(Typist::Wtype);
+  }
+  if env::var_os("DISPLAY").is_some() {
+    candidates.push(Typist::Xdotool);
+  }
+  candidates.push(Typist::Ydotool);
+  candidates
+    .iter()
+    .cloned()
+    .find(|typist| is_available(typist.program()))
+    .ok_or_else(|| {
+      let programs = candidates
+        .iter()
+        .map(|typist| typist.program())
+        .collect::<Vec<_>>();
+      Error::Error(format!(
+        "No program for typing available (tried {})",
+        programs.join(", ")
+      ))
+    })
+}
+
+/// Type the given login and password into the focused window, following
+/// the sequence configured in the given configuration.
+pub fn type_credentials(config: &config::Config, login: &str, password: &Secret) -> Result<()> {
+  let sequence = config.get_string("autotype", "sequence")?;
+  let steps = parse(sequence.unwrap_or(DEFAULT_SEQUENCE))?;
+  let typist = typist()?;
+  debug!("Typing the credentials using {}", typist.program());
+
+  for step in &steps {
+    match step {
+      Step::Login => typist.run(typist.text_args(), login.as_bytes())?,
+      Step::Password => typist.run(typist.text_args(), password.as_bytes())?,
+      Step::Key(key) => typist.run(typist.key_args(*key), &[])?,
+      Step::Text(text) => typist.run(typist.text_args(), text.as_bytes())?,
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_sequences() {
+    assert_eq!(
+      parse(DEFAULT_SEQUENCE).unwrap(),
+      vec![
+        Step::Login,
+        Step::Key(Key::Tab),
+        Step::Password,
+        Step::Key(Key::Enter)
+      ]
+    );
+    assert_eq!(
+      parse("user: {login}!").unwrap(),
+      vec![
+        Step::Text("user: ".to_string()),
+        Step::Login,
+        Step::Text("!".to_string())
+      ]
+    );
+    assert_eq!(parse("").unwrap(), vec![]);
+    assert!(parse("{login").is_err());
+    assert!(parse("{otp}").is_err());
+  }
+}
@@ -0,0 +1,198 @@
+// clipboard.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process;
+use std::result;
+
+use crate::config;
+use crate::error::Error;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The configuration keys controlling the output of secrets, along with
+/// the kinds of their values.
+pub const CONFIG_KEYS: &[(&str, &str, config::Kind)] =
+  &[("", "hide-secrets", config::Kind::Boolean)];
+
+/// The alphabet used for base64 encoding.
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encode the given data using base64 with padding.
+fn base64(data: &[u8]) -> String {
+  let mut encoded = String::new();
+  for chunk in data.chunks(3) {
+    let b = [
+      chunk[0],
+      chunk.get(1).cloned().unwrap_or(0),
+      chunk.get(2).cloned().unwrap_or(0),
+    ];
+    let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+    for i in 0..4 {
+      if i <= chunk.len() {
+        encoded.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+      } else {
+        encoded.push('=');
+      }
+    }
+  }
+  encoded
+}
+
+/// Create the OSC 52 escape sequence setting the clipboard of the
+/// terminal to the given text.
+///
+/// Inside of tmux, the sequence is wrapped so that tmux passes it on to
+/// the terminal it is running in.
+fn osc52(text: &str, tmux: bool) -> String {
+  let sequence = format!("\x1b]52;c;{}\x07", base64(text.as_bytes()));
+  if tmux {
+    format!("\x1bPtmux;{}\x1b\\", sequence.replace('\x1b', "\x1b\x1b"))
+  } else {
+    sequence
+  }
+}
+
+/// Retrieve the clipboard programs to try, along with their arguments,
+/// in order of preference.
+fn programs() -> Vec<(&'static str, &'static [&'static str])> {
+  let mut programs = Vec::new();
+  if cfg!(target_os = "macos") {
+    programs.push(("pbcopy", &[][..]));
+  }
+  if env::var_os("WAYLAND_DISPLAY").is_some() {
+    programs.push(("wl-copy", &[][..]));
+  }
+  if env::var_os("DISPLAY").is_some() {
+    programs.push(("xclip", &["-selection", "clipboard"][..]));
+    programs.push(("xsel", &["--clipboard", "--input"][..]));
+  }
+  programs
+}
+
+/// Copy the given text to the clipboard using the given program.
+fn copy_with(program: &str, args: &[&str], text: &str) -> bool {
+  let child = process::Command::new(program)
+    .args(args)
+    .stdin(process::Stdio::piped())
+    .stdout(process::Stdio::null())
+    .stderr(process::Stdio::null())
+    .spawn();
+  let mut child = match child {
+    Ok(child) => child,
+    Err(err) => {
+      trace!("Could not run clipboard program {}: {}", program, err);
+      return false;
+    }
+  };
+
+  let written = match child.stdin.take() {
+    Some(mut stdin) => stdin.write_all(text.as_bytes()).is_ok(),
+    None => false,
+  };
+  let status = child.wait();
+  let copied = written && status.map(|status| status.success()).unwrap_or(false);
+  if copied {
+    debug!("Copied the text to the clipboard using {}", program);
+  }
+  copied
+}
+
+/// Copy the given text to the clipboard.
+///
+/// The text is passed to the first available clipboard program of the
+/// graphical session. If there is none, as is the case in SSH sessions,
+/// an OSC 52 escape sequence is written to the terminal instead, which
+/// makes terminals supporting it copy the text to the local clipboard.
+pub fn copy(text: &str) -> Result<()> {
+  if programs()
+    .into_iter()
+    .any(|(program, args)| copy_with(program, args, text))
+  {
+    return Ok(());
+  }
+
+  let mut tty = fs::OpenOptions::new()
+    .write(true)
+    .open("/dev/tty")
+    .map_err(|err| Error::Error(format!("Could not access the clipboard: {}", err)))?;
+  let tmux = env::var_os("TMUX").is_some();
+  debug!("No clipboard program available, using an OSC 52 escape sequence");
+  tty.write_all(osc52(text, tmux).as_bytes())?;
+  tty.flush()?;
+  Ok(())
+}
+
+/// Decide whether a secret is copied to the clipboard instead of being
+/// printed.
+///
+/// Secrets are copied if requested using `clip`. With the
+/// `hide-secrets` setting of the given configuration, they are copied
+/// by default as well, so that they do not end up in the scrollback of
+/// the terminal or in a variable through command substitution, and are
+/// only printed if requested using `force_show`.
+pub fn hide_secret(config: &config::Config, clip: bool, force_show: bool) -> Result<bool> {
+  if clip {
+    Ok(true)
+  } else if force_show {
+    Ok(false)
+  } else {
+    let hide = config.get_bool("", "hide-secrets")?.unwrap_or(false);
+    if hide {
+      debug!("Copying the secret to the clipboard because hide-secrets is set");
+    }
+    Ok(hide)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn encode_base64() {
+    assert_eq!(base64(b""), "");
+    assert_eq!(base64(b"f"), "Zg==");
+    assert_eq!(base64(b"fo"), "Zm8=");
+    assert_eq!(base64(b"foo"), "Zm9v");
+    assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+  }
+
+  #[test]
+  fn hide_secrets() {
+    let config = config::Config::default();
+    assert!(!hide_secret(&config, false, false).unwrap());
+    assert!(hide_secret(&config, true, false).unwrap());
+    let config = "hide-secrets = true".parse::<config::Config>().unwrap();
+    assert!(hide_secret(&config, false, false).unwrap());
+    assert!(!hide_secret(&config, false, true).unwrap());
+    assert!(hide_secret(&config, true, true).unwrap());
+  }
+
+  #[test]
+  fn osc52_sequence() {
+    assert_eq!(osc52("123456", false), "\x1b]52;c;MTIzNDU2\x07");
+    assert_eq!(
+      osc52("123456", true),
+      "\x1bPtmux;\x1b\x1b]52;c;MTIzNDU2\x07\x1b\\"
+    );
+  }
+}
@@ -0,0 +1,4080 @@
+// commands.rs
+
+// *************************************************************************
+// * Copyright (C) 2017-2018 Daniel Mueller (deso@posteo.net)              *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::collections::BTreeMap;
+#[cfg(feature = "otp")]
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path;
+use std::result;
+use std::thread;
+use std::time;
+
+use nitrokey::Authenticate;
+#[cfg(feature = "otp")]
+use nitrokey::ConfigureOtp;
+use nitrokey::Device;
+#[cfg(feature = "otp")]
+use nitrokey::GenerateOtp;
+#[cfg(feature = "pws")]
+use nitrokey::GetPasswordSafe;
+
+#[cfg(feature = "otp")]
+use crate::agent;
+use crate::args;
+#[cfg(feature = "pws")]
+use crate::autotype;
+#[cfg(any(feature = "otp", feature = "pws"))]
+use crate::clipboard;
+use crate::completions;
+use crate::config;
+use crate::devices;
+use crate::error::Context;
+use crate::error::Error;
+use crate::inventory;
+use crate::json;
+use crate::lock;
+use crate::log;
+use crate::metrics;
+use crate::module;
+use crate::nicknames;
+use crate::notify;
+#[cfg(feature = "otp")]
+use crate::oath;
+#[cfg(any(feature = "pws", feature = "storage"))]
+use crate::pager;
+#[cfg(feature = "pws")]
+use crate::passphrase;
+#[cfg(any(feature = "otp", feature = "pws"))]
+use crate::picker;
+use crate::pinentry;
+use crate::policy;
+use crate::provision;
+#[cfg(any(feature = "otp", feature = "pws"))]
+use crate::registry;
+#[cfg(any(feature = "otp", feature = "pws", feature = "storage"))]
+use crate::secret::Secret;
+use crate::signal;
+#[cfg(feature = "otp")]
+use crate::slots;
+use crate::storage;
+use crate::tty;
+use crate::udev;
+#[cfg(feature = "storage")]
+use crate::update;
+use crate::vault;
+use crate::watchdog;
+
+type Result<T> = result::Result<T, Error>;
+
+/// Create an `error::Error` for the given libnitrokey error that
+/// occurred while performing the operation described by `msg`.
+fn get_error(msg: &str, err: nitrokey::CommandError) -> Error {
+  Error::Command(msg.to_string(), err)
+}
+
+/// Set the log level of libnitrokey according to the verbosity of the
+/// given context.
+fn set_log_level(ctx: &args::ExecCtx) {
+  let level = match ctx.verbosity {
+    0 => nitrokey::LogLevel::Error,
+    1 => nitrokey::LogLevel::Warning,
+    2 => nitrokey::LogLevel::Info,
+    3 => nitrokey::LogLevel::DebugL1,
+    4 => nitrokey::LogLevel::Debug,
+    _ => nitrokey::LogLevel::DebugL2,
+  };
+  nitrokey::set_log_level(level);
+}
+
+/// Retrieve the selection of devices to connect to of the given
+/// context.
+///
+/// If the context does not select a model, but all connected devices
+/// are of the same model, that model is selected, so that libnitrokey
+/// does not have to probe for the other models when connecting.
+fn get_selector(ctx: &args::ExecCtx) -> nitrocli_core::device::Selector {
+  let model = match ctx.model {
+    Some(args::DeviceModel::Pro) => Some(nitrocli_core::device::Model::Pro),
+    Some(args::DeviceModel::Storage) => Some(nitrocli_core::device::Model::Storage),
+    None => connected_model(),
+  };
+  nitrocli_core::device::Selector {
+    model,
+    serial_numbers: ctx.serial_numbers.clone(),
+  }
+}
+
+/// Determine the model of the connected devices, if they are all of the
+/// same model.
+fn connected_model() -> Option<nitrocli_core::device::Model> {
+  let devices = udev::find_devices();
+  let (name, _) = devices.first()?;
+  if devices.iter().any(|(other, _)| other != name) {
+    return None;
+  }
+  [
+    nitrocli_core::device::Model::Pro,
+    nitrocli_core::device::Model::Storage,
+  ]
+  .iter()
+  .find(|model| model.to_string() == *name)
+  .cloned()
+}
+
+/// Determine the path of the USB device node of the connected device of
+/// the given model, if it is the only one of its model.
+fn usb_path(model: nitrocli_core::device::Model) -> Option<String> {
+  let model = model.to_string();
+  let mut paths = udev::find_devices()
+    .into_iter()
+    .filter(|&(name, _)| name == model)
+    .map(|(_, path)| path.display().to_string());
+  match (paths.next(), paths.next()) {
+    (Some(path), None) => Some(path),
+    _ => None,
+  }
+}
+
+/// Retrieve the serial number of the given device of the given model.
+///
+/// Unless the context disables caching, the serial number is looked up
+/// in the device cache first. This only works if the device is the
+/// only connected one of its model, as otherwise we cannot tell which
+/// USB device node belongs to it.
+fn get_serial_number<D>(
+  ctx: &args::ExecCtx,
+  model: nitrocli_core::device::Model,
+  device: &D,
+) -> Result<String>
+where
+  D: Device,
+{
+  let query = || {
+    ctx
+      .retry
+      .run(|| nitrocli_core::device::serial_number(device))
+      .map_err(Error::from)
+  };
+  let path = match usb_path(model) {
+    Some(ref path) if !ctx.no_cache => path.clone(),
+    _ => return query(),
+  };
+
+  let now = storage::now();
+  let mut cache = devices::DeviceCache::load()?;
+  if let Some(serial) = cache.lookup(&path, &model.to_string(), now) {
+    debug!(
+      "Found the serial number of the device at {} in the cache",
+      path
+    );
+    return Ok(serial.to_string());
+  }
+  let serial = query()?;
+  cache.update(&path, &model.to_string(), &serial, now);
+  cache.save()?;
+  Ok(serial)
+}
+
+/// Record the model and serial number of the given device in the
+/// context for error reports, if these are verbose, and log them.
+fn record_device<D>(ctx: &args::ExecCtx, model: nitrocli_core::device::Model, device: &D)
+where
+  D: Device,
+{
+  if ctx.verbosity > 0 || log::enabled(log::Level::Info, module_path!()) {
+    let serial = get_serial_number(ctx, model, device)
+      .unwrap_or_else(|_| "with unknown serial number".to_string());
+    let description = format!("{} {}", model, serial);
+    info!("Connected to {}", description);
+    *ctx.device.borrow_mut() = Some(description);
+  }
+}
+
+/// Connect to a Nitrokey device matching the given context and return it.
+///
+/// If an earlier operation of the invocation released its device, that
+/// connection is reused instead of establishing a new one.
+fn get_device(ctx: &args::ExecCtx) -> Result<nitrokey::DeviceWrapper> {
+  watchdog::check()?;
+  let cached = take_connection(ctx);
+  if let Some(device) = cached {
+    debug!("Reusing the connection to the device");
+    lock_device(ctx, nitrocli_core::device::Model::of(&device), &device)?;
+    return Ok(device);
+  }
+
+  set_log_level(ctx);
+  let selector = get_selector(ctx);
+  debug!("Connecting to a device matching {:?}", selector);
+  let device = ctx
+    .retry
+    .run(|| nitrocli_core::device::connect(&selector))?;
+  record_device(ctx, nitrocli_core::device::Model::of(&device), &device);
+  lock_device(ctx, nitrocli_core::device::Model::of(&device), &device)?;
+  if let Some(serial) = ctx.serial.borrow().as_ref() {
+    record_seen(nitrocli_core::device::Model::of(&device), serial);
+  }
+  Ok(device)
+}
+
+/// Retrieve the command line representation of the given model.
+fn device_model(model: nitrocli_core::device::Model) -> args::DeviceModel {
+  match model {
+    nitrocli_core::device::Model::Pro => args::DeviceModel::Pro,
+    nitrocli_core::device::Model::Storage => args::DeviceModel::Storage,
+  }
+}
+
+/// Record in the inventory that the device with the given model and
+/// serial number was connected to.
+///
+/// Failure to do so is reported as a warning only, as the inventory is
+/// merely informational.
+fn record_seen(model: nitrocli_core::device::Model, serial: &str) {
+  let result = inventory::Inventory::load().and_then(|mut inventory| {
+    let model = device_model(model).to_string();
+    if inventory.seen(serial, &model, storage::now()) {
+      inventory.save()
+    } else {
+      Ok(())
+    }
+  });
+  if let Err(err) = result {
+    warn!("Could not update the inventory: {}", err);
+  }
+}
+
+/// Take the connection to the device kept by the given context, if any.
+///
+/// A connection authenticated as admin loses its authentication.
+fn take_connection(ctx: &args::ExecCtx) -> Option<nitrokey::DeviceWrapper> {
+  let connection = ctx.connection.borrow_mut().take();
+  connection.or_else(|| ctx.admin.borrow_mut().take().map(nitrokey::Admin::device))
+}
+
+/// Connect to the device matching the given context and authenticate as
+/// admin, unless an earlier operation of the invocation did so already.
+fn get_admin(ctx: &args::ExecCtx) -> Result<nitrokey::Admin<nitrokey::DeviceWrapper>> {
+  let cached = ctx.admin.borrow_mut().take();
+  if let Some(admin) = cached {
+    debug!("Reusing the admin authentication of the device");
+    lock_device(ctx, nitrocli_core::device::Model::of(&admin), &*admin)?;
+    return Ok(admin);
+  }
+  authenticate_admin(ctx, get_device(ctx)?)
+}
+
+/// Lock the given device for exclusive use by this process, unless it
+/// is locked already.
+///
+/// The lock is held until it is released using `unlock_device` or the
+/// program exits.
+fn lock_device<D>(
+  ctx: &args::ExecCtx,
+  model: nitrocli_core::device::Model,
+  device: &D,
+) -> Result<()>
+where
+  D: Device,
+{
+  if ctx.lock.borrow().is_some() {
+    return Ok(());
+  }
+  let serial = get_serial_number(ctx, model, device)?;
+  let lock = lock::Lock::acquire(&ctx.config, &serial)?;
+  *ctx.lock.borrow_mut() = Some(lock);
+  *ctx.serial.borrow_mut() = Some(serial);
+  Ok(())
+}
+
+/// Release the lock on the device held by this process, if any, so
+/// that other processes can use the device.
+pub fn unlock_device(ctx: &args::ExecCtx) {
+  *ctx.lock.borrow_mut() = None;
+}
+
+/// Determine the model and the serial number of the Nitrokey device
+/// matching the given context.
+pub fn resolve_device(ctx: &args::ExecCtx) -> Result<(args::DeviceModel, String)> {
+  let device = get_device(ctx)?;
+  let model = match device {
+    nitrokey::DeviceWrapper::Pro(_) => args::DeviceModel::Pro,
+    nitrokey::DeviceWrapper::Storage(_) => args::DeviceModel::Storage,
+  };
+  let serial = get_serial_number(ctx, nitrocli_core::device::Model::of(&device), &device)?;
+  release_device(ctx, device);
+  Ok((model, serial))
+}
+
+/// Retrieve the model and the serial number of the device the context is
+/// connected to already, if any, without connecting to one.
+pub fn connected_device(ctx: &args::ExecCtx) -> Option<(args::DeviceModel, String)> {
+  let connection = ctx.connection.borrow();
+  let admin = ctx.admin.borrow();
+  let model = match connection
+    .as_ref()
+    .or_else(|| admin.as_ref().map(|admin| &**admin))
+  {
+    Some(nitrokey::DeviceWrapper::Pro(_)) => args::DeviceModel::Pro,
+    Some(nitrokey::DeviceWrapper::Storage(_)) => args::DeviceModel::Storage,
+    None => return None,
+  };
+  let serial = ctx.serial.borrow().clone()?;
+  Some((model, serial))
+}
+
+/// Run the given operation on every connected Nitrokey Storage that
+/// matches the serial numbers of the given context, one after another.
+///
+/// The output of each run is preceded by the serial number of the
+/// device, and a run failing does not stop the others. A device that
+/// cannot be connected to or does not report its serial number is
+/// reported as failed by its ID, as it is unknown whether it matches.
+/// libnitrokey can only enumerate Nitrokey Storage devices, so Nitrokey
+/// Pro devices are not covered.
+pub fn for_all_devices<F>(ctx: &args::ExecCtx, mut op: F) -> Result<()>
+where
+  F: FnMut(&args::ExecCtx) -> Result<()>,
+{
+  if ctx.model == Some(args::DeviceModel::Pro) {
+    let error = "--all-devices is only supported for the Nitrokey Storage";
+    return Err(Error::Error(error.to_string()));
+  }
+
+  set_log_level(ctx);
+  let selector = get_selector(ctx);
+  let nicknames = nicknames::Nicknames::load()?;
+  let mut total = 0;
+  let mut failed = Vec::new();
+  for id in nitrocli_core::device::list_storage_ids() {
+    // Only one device can be connected at a time, so the previous one
+    // has to be released, along with its lock, before connecting.
+    drop(take_connection(ctx));
+    unlock_device(ctx);
+    *ctx.serial.borrow_mut() = None;
+
+    debug!("Connecting to the Nitrokey Storage with ID {}", id);
+    let result = ctx
+      .retry
+      .run(|| nitrocli_core::device::connect_storage_id(&id))
+      .and_then(|device| {
+        let serial = ctx
+          .retry
+          .run(|| nitrocli_core::device::serial_number(&device))?;
+        Ok((device, serial))
+      });
+    let (device, serial) = match result {
+      Ok(result) => result,
+      Err(err) => {
+        total += 1;
+        println!("Device {}:", id);
+        println!("{}", Error::from(err));
+        failed.push(id);
+        continue;
+      }
+    };
+    let normalized = nitrocli_core::device::normalize_serial_number(&serial);
+    if !selector.serial_numbers.is_empty()
+      && !selector
+        .serial_numbers
+        .iter()
+        .any(|s| nitrocli_core::device::normalize_serial_number(s) == normalized)
+    {
+      continue;
+    }
+
+    total += 1;
+    match nicknames.name(&serial) {
+      Some(name) => println!("Device {} ({}):", serial, name),
+      None => println!("Device {}:", serial),
+    }
+    record_device(ctx, nitrocli_core::device::Model::Storage, &device);
+    record_seen(nitrocli_core::device::Model::Storage, &serial);
+    release_device(ctx, nitrokey::DeviceWrapper::Storage(device));
+    if let Err(err) = op(ctx) {
+      println!("{}", err);
+      failed.push(serial);
+    }
+  }
+
+  if total == 0 {
+    Err(nitrocli_core::Error::NotFound.into())
+  } else if failed.is_empty() {
+    println!("Succeeded on all {} devices", total);
+    Ok(())
+  } else {
+    let error = format!(
+      "Failed on {} of {} devices: {}",
+      failed.len(),
+      total,
+      failed.join(", ")
+    );
+    Err(Error::Error(error))
+  }
+}
+
+/// Print the given change to the device instead of making it, if the
+/// context is in dry-run mode.
+///
+/// Returns whether the change has to be skipped.
+pub fn dry_run(ctx: &args::ExecCtx, change: &str) -> bool {
+  if ctx.dry_run {
+    println!("Would {}", change);
+  }
+  ctx.dry_run
+}
+
+/// Hand the given device back to the context, so that later operations
+/// of the invocation can use it without connecting again.
+fn release_device(ctx: &args::ExecCtx, device: nitrokey::DeviceWrapper) {
+  *ctx.connection.borrow_mut() = Some(device);
+}
+
+/// Hand the given device authenticated as admin back to the context, so
+/// that later changes of the invocation can use it without
+/// authenticating again.
+fn release_admin(ctx: &args::ExecCtx, admin: nitrokey::Admin<nitrokey::DeviceWrapper>) {
+  *ctx.admin.borrow_mut() = Some(admin);
+}
+
+/// Connect to a Nitrokey Storage device matching the given context and
+/// return it.
+#[cfg(feature = "storage")]
+fn get_storage_device(ctx: &args::ExecCtx) -> Result<nitrokey::Storage> {
+  let cached = take_connection(ctx);
+  // A connection to a Nitrokey Pro is of no use here and has to be
+  // closed before connecting to another device.
+  if let Some(nitrokey::DeviceWrapper::Storage(device)) = cached {
+    debug!("Reusing the connection to the device");
+    lock_device(ctx, nitrocli_core::device::Model::Storage, &device)?;
+    return Ok(device);
+  }
+
+  set_log_level(ctx);
+  let selector = get_selector(ctx);
+  debug!("Connecting to a Nitrokey Storage matching {:?}", selector);
+  let device = ctx
+    .retry
+    .run(|| nitrocli_core::device::connect_storage(&selector))?;
+  record_device(ctx, nitrocli_core::device::Model::Storage, &device);
+  lock_device(ctx, nitrocli_core::device::Model::Storage, &device)?;
+  Ok(device)
+}
+
+/// Return a string representation of the given volume status.
+#[cfg(feature = "storage")]
+fn get_volume_status(status: &nitrokey::VolumeStatus) -> &'static str {
+  if status.active {
+    if status.read_only {
+      "read-only"
+    } else {
+      "active"
+    }
+  } else {
+    "inactive"
+  }
+}
+
+/// Pretty format the response of a status command.
+#[cfg(feature = "storage")]
+fn format_status(status: &nitrokey::StorageStatus) -> String {
+  // We omit displaying information about the smartcard here as this
+  // program really is only about the SD card portion of the device.
+  format!(
+    r#"Status:
+  SD card ID:        {id:#x}
+  firmware version:  {fwv0}.{fwv1}
+  firmware:          {fw}
+  storage keys:      {sk}
+  user retry count:  {urc}
+  admin retry count: {arc}
+  volumes:
+    unencrypted:     {vu}
+    encrypted:       {ve}
+    hidden:          {vh}"#,
+    id = status.serial_number_sd_card,
+    fwv0 = status.firmware_version_major,
+    fwv1 = status.firmware_version_minor,
+    fw = if status.firmware_locked {
+      "locked"
+    } else {
+      "unlocked"
+    },
+    sk = if status.stick_initialized {
+      "created"
+    } else {
+      "not created"
+    },
+    urc = status.user_retry_count,
+    arc = status.admin_retry_count,
+    vu = get_volume_status(&status.unencrypted_volume),
+    ve = get_volume_status(&status.encrypted_volume),
+    vh = get_volume_status(&status.hidden_volume),
+  )
+}
+
+/// Warn if the device with the given status probably still uses the
+/// factory default PINs.
+///
+/// Testing the PINs would use up an attempt if they were changed, so
+/// the warning is based on the storage keys, which are created during
+/// the initial setup of a device.
+#[cfg(feature = "storage")]
+fn warn_factory_state(status: &nitrokey::StorageStatus) {
+  if !status.stick_initialized {
+    warn!(
+      "The storage keys were never created, so the device probably still uses the default user PIN ({}), admin PIN ({}), and firmware password. Run `nitrocli setup device` to change the PINs and create the keys.",
+      DEFAULT_USER_PIN, DEFAULT_ADMIN_PIN
+    );
+  }
+}
+
+/// Query the given parts of the status of the given device and format
+/// them like `format_status`.
+///
+/// The status of the storage is only read if a part contained in it is
+/// requested, as the retry counters can be queried on their own.
+#[cfg(feature = "storage")]
+fn query_status_fields(
+  ctx: &args::ExecCtx,
+  device: &nitrokey::Storage,
+  fields: &[args::StatusField],
+) -> Result<String> {
+  let status = if fields
+    .iter()
+    .any(|field| *field != args::StatusField::Retries)
+  {
+    let status = ctx
+      .retry
+      .run(|| nitrocli_core::device::storage_status(device))?;
+    warn_factory_state(&status);
+    Some(status)
+  } else {
+    None
+  };
+
+  let mut output = "Status:".to_string();
+  for field in &[
+    args::StatusField::Sd,
+    args::StatusField::Firmware,
+    args::StatusField::Retries,
+    args::StatusField::Volumes,
+  ] {
+    if !fields.contains(field) {
+      continue;
+    }
+    match (field, &status) {
+      (args::StatusField::Sd, Some(status)) => {
+        output.push_str(&format!(
+          "\n  SD card ID:        {:#x}",
+          status.serial_number_sd_card
+        ));
+        output.push_str(&format!(
+          "\n  storage keys:      {}",
+          if status.stick_initialized {
+            "created"
+          } else {
+            "not created"
+          }
+        ));
+      }
+      (args::StatusField::Firmware, Some(status)) => {
+        output.push_str(&format!(
+          "\n  firmware version:  {}.{}",
+          status.firmware_version_major, status.firmware_version_minor
+        ));
+        output.push_str(&format!(
+          "\n  firmware:          {}",
+          if status.firmware_locked {
+            "locked"
+          } else {
+            "unlocked"
+          }
+        ));
+      }
+      (args::StatusField::Retries, _) => {
+        output.push_str(&format!(
+          "\n  user retry count:  {}",
+          device.get_user_retry_count()
+        ));
+        output.push_str(&format!(
+          "\n  admin retry count: {}",
+          device.get_admin_retry_count()
+        ));
+      }
+      (args::StatusField::Volumes, Some(status)) => {
+        output.push_str(&format!(
+          "\n  volumes:\n    unencrypted:     {}\n    encrypted:       {}\n    hidden:          {}",
+          get_volume_status(&status.unencrypted_volume),
+          get_volume_status(&status.encrypted_volume),
+          get_volume_status(&status.hidden_volume)
+        ));
+      }
+      _ => (),
+    }
+  }
+  Ok(output)
+}
+
+/// Inquire the status of the nitrokey.
+///
+/// If fields are given, only these parts of the status are queried and
+/// printed.
+#[cfg(feature = "storage")]
+pub fn status(ctx: &args::ExecCtx, fields: &[args::StatusField]) -> Result<()> {
+  let device = get_storage_device(ctx)?;
+  let output = if fields.is_empty() {
+    let status = ctx
+      .retry
+      .run(|| nitrocli_core::device::storage_status(&device))?;
+    warn_factory_state(&status);
+    format_status(&status)
+  } else {
+    query_status_fields(ctx, &device, fields)?
+  };
+  let nicknames = nicknames::Nicknames::load()?;
+  let output = if nicknames.is_empty() {
+    output
+  } else {
+    let serial = get_serial_number(ctx, nitrocli_core::device::Model::Storage, &device)?;
+    match nicknames.name(&serial) {
+      Some(name) => output.replacen(
+        "Status:",
+        &format!("Status:\n  name:              {}", name),
+        1,
+      ),
+      None => output,
+    }
+  };
+  release_device(ctx, nitrokey::DeviceWrapper::Storage(device));
+  pager::page(ctx, &format!("{}\n", output))
+}
+
+/// Retrieve the number of remaining authentication attempts for the given PIN type.
+fn get_retry_count<D>(device: &D, pin_type: pinentry::PinType) -> u8
+where
+  D: Device,
+{
+  match pin_type {
+    pinentry::PinType::Admin => device.get_admin_retry_count(),
+    pinentry::PinType::User => device.get_user_retry_count(),
+  }
+}
+
+/// Try to execute the given function with a passphrase queried using pinentry.
+///
+/// This function will query the passphrase of the given type from the
+/// user using pinentry.  It will then execute the given function.  If
+/// this function returns a result, the result will be passed on.  If it
+/// returns a `CommandError::WrongPassword`, the user will be asked
+/// again to enter the passphrase.  Otherwise, this function returns an
+/// error containing the given error message.  Every prompt displays the
+/// number of attempts the device has left.  The user is asked at most
+/// as many times as the device had attempts left when this function
+/// was invoked and not again once the device reports that only one
+/// attempt is left, so that mistyping the PIN repeatedly does not block
+/// it.  If only one attempt is left to begin with, the prompt warns
+/// that a wrong PIN blocks it.
+///
+/// The given data is passed to the function along with the passphrase
+/// and has to be handed back by it in case of an error.  The number of
+/// remaining attempts is determined from the data using the given
+/// retry count function.
+fn try_with_passphrase_and_data<D, C, F, R>(
+  ctx: &args::ExecCtx,
+  data: D,
+  pin_type: pinentry::PinType,
+  msg: &str,
+  retry_count: C,
+  op: F,
+) -> Result<R>
+where
+  C: Fn(&D) -> u8,
+  F: Fn(D, &str) -> result::Result<R, (D, nitrokey::CommandError)>,
+{
+  let mut retry = retry_count(&data);
+  if retry == 0 {
+    let error = format!("{}: The {} is blocked", msg, pin_type);
+    return Err(Error::Error(error));
+  }
+
+  let attempts = |count| {
+    if count == 1 {
+      "1 attempt left".to_string()
+    } else {
+      format!("{} attempts left", count)
+    }
+  };
+  let mut data = data;
+  let mut error_msg = if retry == 1 {
+    format!("Last attempt, a wrong PIN blocks the {}", pin_type)
+  } else {
+    attempts(retry)
+  };
+  loop {
+    let secret =
+      pinentry::inquire_passphrase(ctx, pin_type, pinentry::Mode::Query, Some(&error_msg))?;
+    let passphrase = secret.as_str()?;
+    // Transient errors are retried with the same passphrase, without
+    // bothering the user again.
+    let mut attempt = 0;
+    let result = loop {
+      match op(data, passphrase) {
+        Err((new_data, err)) => {
+          if !ctx.retry.retry(attempt, &err) {
+            break Err((new_data, err));
+          }
+          data = new_data;
+          attempt += 1;
+        }
+        result => break result,
+      }
+    };
+    match result {
+      Ok(result) => {
+        debug!("Authenticated using the {}", pin_type);
+        pinentry::remember_passphrase(ctx, pin_type, &secret)?;
+        return Ok(result);
+      }
+      Err((new_data, err)) => match err {
+        nitrokey::CommandError::WrongPassword => {
+          data = new_data;
+          pinentry::clear_passphrase(ctx, pin_type)?;
+          retry -= 1;
+
+          let remaining = retry_count(&data);
+          info!("Wrong {}, {} attempts left", pin_type, remaining);
+          notify::retry_count(ctx, pin_type, remaining)?;
+          if remaining == 0 {
+            let error = format!("{}: Wrong password, the {} is now blocked", msg, pin_type);
+            return Err(Error::Error(error));
+          } else if remaining == 1 || retry == 0 {
+            let error = format!("{}: Wrong password, {}", msg, attempts(remaining));
+            return Err(Error::Error(error));
+          }
+          // A user interrupting a failed attempt does not want to be
+          // asked again.
+          signal::check()?;
+
+          error_msg = format!("Wrong password, {}", attempts(remaining));
+          continue;
+        }
+        err => return Err(get_error(msg, err)),
+      },
+    };
+  }
+}
+
+/// Try to execute the given function with a passphrase queried using
+/// pinentry, as described for `try_with_passphrase_and_data`.
+fn try_with_passphrase<D, F, R>(
+  ctx: &args::ExecCtx,
+  device: &D,
+  pin_type: pinentry::PinType,
+  msg: &str,
+  op: F,
+) -> Result<R>
+where
+  D: Device,
+  F: Fn(&str) -> result::Result<R, nitrokey::CommandError>,
+{
+  try_with_passphrase_and_data(
+    ctx,
+    (),
+    pin_type,
+    msg,
+    |_| get_retry_count(device, pin_type),
+    |_, passphrase| op(passphrase).map_err(|err| ((), err)),
+  )
+}
+
+/// Authenticate the given device using the user PIN.
+#[cfg(feature = "otp")]
+fn authenticate_user<D>(ctx: &args::ExecCtx, device: D) -> Result<nitrokey::User<D>>
+where
+  D: Device,
+{
+  try_with_passphrase_and_data(
+    ctx,
+    device,
+    pinentry::PinType::User,
+    "Could not authenticate as user",
+    |device| device.get_user_retry_count(),
+    |device, passphrase| device.authenticate_user(passphrase),
+  )
+}
+
+/// Authenticate the given device using the admin PIN.
+fn authenticate_admin<D>(ctx: &args::ExecCtx, device: D) -> Result<nitrokey::Admin<D>>
+where
+  D: Device,
+{
+  try_with_passphrase_and_data(
+    ctx,
+    device,
+    pinentry::PinType::Admin,
+    "Could not authenticate as admin",
+    |device| device.get_admin_retry_count(),
+    |device, passphrase| device.authenticate_admin(passphrase),
+  )
+}
+
+/// Open the encrypted volume on the given device, using the given user
+/// PIN or, if there is none, one queried using pinentry.
+#[cfg(feature = "storage")]
+fn open_volume(ctx: &args::ExecCtx, device: &nitrokey::Storage, pin: Option<&str>) -> Result<()> {
+  let msg = "Opening encrypted volume failed";
+  // Opening the volume takes a while and must not be interrupted
+  // halfway.
+  let enable = |pin: &str| {
+    let _guard = signal::defer();
+    device.enable_encrypted_volume(pin)
+  };
+  match pin {
+    Some(pin) => ctx
+      .retry
+      .run(|| enable(pin))
+      .map_err(|err| get_error(msg, err))?,
+    None => try_with_passphrase(ctx, device, pinentry::PinType::User, msg, enable)?,
+  }
+  signal::check().context(|| "Stopped after opening the encrypted volume")?;
+  notify::notify(ctx, "Encrypted volume opened")
+}
+
+/// Open the encrypted volume on the nitrokey.
+#[cfg(feature = "storage")]
+pub fn open(ctx: &args::ExecCtx) -> Result<()> {
+  let device = get_storage_device(ctx)?;
+  if !dry_run(ctx, "open the encrypted volume") {
+    open_volume(ctx, &device, None)?;
+  }
+  release_device(ctx, nitrokey::DeviceWrapper::Storage(device));
+  Ok(())
+}
+
+#[cfg(feature = "storage")]
+#[link(name = "c")]
+extern "C" {
+  fn sync();
+}
+
+/// Close the previously opened encrypted volume on the given device.
+#[cfg(feature = "storage")]
+fn close_volume(ctx: &args::ExecCtx, device: &nitrokey::Storage) -> Result<()> {
+  // Flush all filesystem caches to disk. We are mostly interested in
+  // making sure that the encrypted volume on the nitrokey we are
+  // about to close is not closed while not all data was written to
+  // it.
+  let guard = signal::defer();
+  unsafe { sync() };
+
+  ctx
+    .retry
+    .run(|| device.disable_encrypted_volume())
+    .map_err(|err| get_error("Closing encrypted volume failed", err))?;
+  drop(guard);
+  signal::check().context(|| "Stopped after closing the encrypted volume")?;
+  notify::notify(ctx, "Encrypted volume closed")
+}
+
+/// Close the previously opened encrypted volume.
+#[cfg(feature = "storage")]
+pub fn close(ctx: &args::ExecCtx) -> Result<()> {
+  let device = get_storage_device(ctx)?;
+  if !dry_run(ctx, "close the encrypted volume") {
+    close_volume(ctx, &device)?;
+  }
+  release_device(ctx, nitrokey::DeviceWrapper::Storage(device));
+  Ok(())
+}
+
+/// Close the encrypted or the hidden volume on the nitrokey, if one of
+/// them is open.
+///
+/// Returns whether a volume was open.
+#[cfg(feature = "storage")]
+pub fn close_open_volume(ctx: &args::ExecCtx) -> Result<bool> {
+  let device = get_storage_device(ctx)?;
+  let status = ctx
+    .retry
+    .run(|| nitrocli_core::device::storage_status(&device))?;
+  // The hidden volume takes the place of the encrypted one, so closing
+  // the latter closes both.
+  let open = status.encrypted_volume.active || status.hidden_volume.active;
+  if open && !dry_run(ctx, "close the open volume") {
+    close_volume(ctx, &device)?;
+  }
+  release_device(ctx, nitrokey::DeviceWrapper::Storage(device));
+  Ok(open)
+}
+
+/// The number of hidden volumes of the Nitrokey Storage.
+#[cfg(feature = "storage")]
+const HIDDEN_VOLUME_COUNT: u8 = 4;
+/// The maximum length of the password of a hidden volume.
+#[cfg(feature = "storage")]
+const MAX_HIDDEN_PASSWORD_LENGTH: usize = 20;
+
+/// Read the password of a hidden volume.
+///
+/// If the standard input is a terminal, the password is inquired
+/// without echo, twice if `retype` is set. Otherwise, the first line of
+/// the standard input is used.
+#[cfg(feature = "storage")]
+fn read_hidden_password(retype: bool) -> Result<Secret> {
+  let password = if tty::is_stdin_tty() {
+    let password = tty::read_passphrase("Hidden volume password: ", tty::Echo::None)?;
+    if retype {
+      let confirm = tty::read_passphrase("Retype hidden volume password: ", tty::Echo::None)?;
+      if password != confirm {
+        return Err(Error::Error(
+          "The entered passwords do not match".to_string(),
+        ));
+      }
+    }
+    password
+  } else {
+    read_secret_line()?
+  };
+  let length = password.as_bytes().len();
+  if length == 0 || length > MAX_HIDDEN_PASSWORD_LENGTH {
+    let error = format!(
+      "The hidden volume password has to have 1 to {} characters, but has {}",
+      MAX_HIDDEN_PASSWORD_LENGTH, length
+    );
+    return Err(Error::Error(error));
+  }
+  Ok(password)
+}
+
+/// Open the encrypted volume on the given device using a user PIN
+/// queried using pinentry, unless it is open already.
+///
+/// Hidden volumes can only be created and opened while it is open.
+#[cfg(feature = "storage")]
+fn ensure_volume_open(ctx: &args::ExecCtx, device: &nitrokey::Storage) -> Result<()> {
+  let status = ctx
+    .retry
+    .run(|| nitrocli_core::device::storage_status(device))?;
+  if status.encrypted_volume.active {
+    Ok(())
+  } else {
+    open_volume(ctx, device, None)
+  }
+}
+
+/// Open the hidden volume protected by the given password on the given
+/// device, opening the encrypted volume first if necessary.
+#[cfg(feature = "storage")]
+fn open_hidden_volume(
+  ctx: &args::ExecCtx,
+  device: &nitrokey::Storage,
+  password: &Secret,
+) -> Result<()> {
+  let password = password.as_str()?;
+  ensure_volume_open(ctx, device)?;
+  let guard = signal::defer();
+  ctx
+    .retry
+    .run(|| nitrocli_core::device::unlock_hidden_volume(device, password))
+    .map_err(|err| get_error("Opening hidden volume failed", err))?;
+  drop(guard);
+  signal::check().context(|| "Stopped after opening the hidden volume")
+}
+
+/// Read the records of decoy hidden volumes, one line of the form
+/// `<serial> <slot> <password>` per device.
+#[cfg(feature = "storage")]
+fn read_decoys() -> Result<Secret> {
+  let path = storage::DECOYS.path()?;
+  match fs::read(&path) {
+    Ok(data) => vault::decrypt(&data).context(|| format!("Could not decrypt {}", path.display())),
+    Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Secret::new()),
+    Err(err) => Err(Error::IoError(err).context(format!("Could not read {}", path.display()))),
+  }
+}
+
+/// Record the hidden volume in the given slot of the device with the
+/// given serial number as the decoy of the device, along with its
+/// password.
+///
+/// The records are stored encrypted for the given recipients or, if
+/// there are none, with a passphrase, and the record of the device
+/// replaces any earlier one.
+#[cfg(feature = "storage")]
+fn record_decoy(serial: &str, slot: u8, password: &Secret, recipients: &[String]) -> Result<()> {
+  let decoys = read_decoys()?;
+  let prefix = format!("{} ", serial);
+  let mut records = Secret::new();
+  for line in decoys.as_str()?.lines() {
+    if !line.is_empty() && !line.starts_with(&prefix) {
+      records.extend_from_slice(line.as_bytes());
+      records.push(b'\n');
+    }
+  }
+  records.extend_from_slice(format!("{}{} ", prefix, slot).as_bytes());
+  records.extend_from_slice(password.as_bytes());
+  records.push(b'\n');
+  vault::encrypt_data(records.as_bytes(), &storage::DECOYS.path()?, recipients)
+}
+
+/// Look up the password of the decoy hidden volume of the device with
+/// the given serial number.
+#[cfg(feature = "storage")]
+fn decoy_password(serial: &str) -> Result<Secret> {
+  let decoys = read_decoys()?;
+  decoys
+    .as_str()?
+    .lines()
+    .filter_map(|line| {
+      let mut parts = line.splitn(3, ' ');
+      match (parts.next(), parts.next(), parts.next()) {
+        (Some(record), Some(_), Some(password)) if record == serial => Some(Secret::from(password)),
+        _ => None,
+      }
+    })
+    .next()
+    .ok_or_else(|| {
+      let error = format!("No decoy hidden volume recorded for the device {}", serial);
+      Error::Error(error)
+    })
+}
+
+/// Open the decoy hidden volume recorded for the nitrokey by `hidden
+/// create --decoy` in place of the encrypted volume.
+///
+/// The decoy record is decrypted before the device is touched, so that
+/// nothing happens on the device if that fails.
+#[cfg(feature = "storage")]
+pub fn open_panic(ctx: &args::ExecCtx) -> Result<()> {
+  let device = get_storage_device(ctx)?;
+  if !dry_run(ctx, "open the decoy hidden volume") {
+    let serial = ctx
+      .retry
+      .run(|| nitrocli_core::device::serial_number(&device))?;
+    let password = decoy_password(&serial)?;
+    open_hidden_volume(ctx, &device, &password)?;
+  }
+  release_device(ctx, nitrokey::DeviceWrapper::Storage(device));
+  Ok(())
+}
+
+/// Create a hidden volume in the given slot of the nitrokey, spanning
+/// the given range of the storage in percent.
+///
+/// If `decoy` is set, the volume is recorded as the decoy that `open
+/// --panic` opens, as described for `record_decoy`.
+#[cfg(feature = "storage")]
+pub fn hidden_create(
+  ctx: &args::ExecCtx,
+  slot: u8,
+  start: u8,
+  end: u8,
+  decoy: bool,
+  recipients: &[String],
+) -> Result<()> {
+  if slot >= HIDDEN_VOLUME_COUNT {
+    let error = format!(
+      "Invalid hidden volume slot: {} (expected 0 to {})",
+      slot,
+      HIDDEN_VOLUME_COUNT - 1
+    );
+    return Err(Error::Error(error));
+  }
+  if start >= end || end > 100 {
+    let error = format!(
+      "Invalid hidden volume range: {} to {} (expected a start below the end of at most 100)",
+      start, end
+    );
+    return Err(Error::Error(error));
+  }
+
+  let device = get_storage_device(ctx)?;
+  if dry_run(ctx, &format!("create the hidden volume {}", slot)) {
+    release_device(ctx, nitrokey::DeviceWrapper::Storage(device));
+    return Ok(());
+  }
+  let serial = ctx
+    .retry
+    .run(|| nitrocli_core::device::serial_number(&device))?;
+  let password = read_hidden_password(true)?;
+  let password_str = password.as_str()?;
+  ensure_volume_open(ctx, &device)?;
+  let guard = signal::defer();
+  ctx
+    .retry
+    .run(|| nitrocli_core::device::create_hidden_volume(&device, slot, start, end, password_str))
+    .map_err(|err| get_error("Creating hidden volume failed", err))?;
+  drop(guard);
+  release_device(ctx, nitrokey::DeviceWrapper::Storage(device));
+
+  if decoy {
+    record_decoy(&serial, slot, &password, recipients)
+      .context(|| "Created the hidden volume, but could not record it as the decoy")?;
+  }
+  signal::check().context(|| "Stopped after creating the hidden volume")
+}
+
+/// Open a hidden volume on the nitrokey, using a password read as
+/// described for `read_hidden_password`.
+#[cfg(feature = "storage")]
+pub fn hidden_open(ctx: &args::ExecCtx) -> Result<()> {
+  let device = get_storage_device(ctx)?;
+  if !dry_run(ctx, "open a hidden volume") {
+    let password = read_hidden_password(false)?;
+    open_hidden_volume(ctx, &device, &password)?;
+    notify::notify(ctx, "Hidden volume opened")?;
+  }
+  release_device(ctx, nitrokey::DeviceWrapper::Storage(device));
+  Ok(())
+}
+
+/// Close the open hidden volume on the nitrokey.
+#[cfg(feature = "storage")]
+pub fn hidden_close(ctx: &args::ExecCtx) -> Result<()> {
+  let device = get_storage_device(ctx)?;
+  if !dry_run(ctx, "close the hidden volume") {
+    // Like for the encrypted volume, pending writes have to reach the
+    // hidden volume before it is closed.
+    let guard = signal::defer();
+    unsafe { sync() };
+    ctx
+      .retry
+      .run(|| nitrocli_core::device::lock_hidden_volume(&device))
+      .map_err(|err| get_error("Closing hidden volume failed", err))?;
+    drop(guard);
+    signal::check().context(|| "Stopped after closing the hidden volume")?;
+    notify::notify(ctx, "Hidden volume closed")?;
+  }
+  release_device(ctx, nitrokey::DeviceWrapper::Storage(device));
+  Ok(())
+}
+
+/// Register the given alias for the given slot of the device matching
+/// the given context.
+#[cfg(any(feature = "otp", feature = "pws"))]
+pub fn alias_add(ctx: &args::ExecCtx, alias: &str, target: &str) -> Result<()> {
+  let target = target.parse::<registry::Target>()?;
+  if alias.parse::<u8>().is_ok() {
+    let error = format!("The alias {} is a slot number", alias);
+    return Err(Error::Error(error));
+  }
+  let (_, serial) = resolve_device(ctx)?;
+  let mut registry = registry::Registry::load()?;
+  registry.add(&serial, alias, target);
+  registry.save()
+}
+
+/// Remove the given alias of the device matching the given context.
+#[cfg(any(feature = "otp", feature = "pws"))]
+pub fn alias_remove(ctx: &args::ExecCtx, alias: &str) -> Result<()> {
+  let (_, serial) = resolve_device(ctx)?;
+  let mut registry = registry::Registry::load()?;
+  if !registry.remove(&serial, alias) {
+    let error = format!("No alias {} registered for the device {}", alias, serial);
+    return Err(Error::Error(error));
+  }
+  registry.save()
+}
+
+/// Print the aliases of the device matching the given context along
+/// with the slots they refer to.
+#[cfg(any(feature = "otp", feature = "pws"))]
+pub fn alias_list(ctx: &args::ExecCtx) -> Result<()> {
+  let (_, serial) = resolve_device(ctx)?;
+  let registry = registry::Registry::load()?;
+  for (alias, target) in registry.aliases(&serial) {
+    println!("{}: {}", alias, target);
+  }
+  Ok(())
+}
+
+/// Clear the PIN stored when opening the nitrokey's encrypted volume.
+pub fn clear(ctx: &args::ExecCtx) -> Result<()> {
+  if dry_run(ctx, "clear the cached user PIN") {
+    return Ok(());
+  }
+  pinentry::clear_passphrase(ctx, pinentry::PinType::User)
+}
+
+/// Print the effective configuration, along with the origin of each
+/// value.
+///
+/// The given keys are listed even if they are not set.
+pub fn config_show_effective(
+  ctx: &args::ExecCtx,
+  keys: &[(&str, &str, config::Kind)],
+) -> Result<()> {
+  let path = config::config_path()?;
+  let state = if path.exists() { "" } else { " (not present)" };
+  println!("# Configuration file: {}{}", path.display(), state);
+  print!("{}", ctx.config.describe(keys));
+  Ok(())
+}
+
+/// Print the nicknames of devices along with their serial numbers.
+pub fn device_list() -> Result<()> {
+  for (serial, name) in nicknames::Nicknames::load()?.iter() {
+    println!("{}: {}", name, serial);
+  }
+  Ok(())
+}
+
+/// Set the nickname of the device with the given serial number or
+/// nickname.
+pub fn device_name(serial: &str, name: &str) -> Result<()> {
+  if name.is_empty() || name.chars().any(char::is_whitespace) {
+    let error = format!(
+      "Invalid device name: '{}' (names must not be empty or contain whitespace)",
+      name
+    );
+    return Err(Error::Error(error));
+  }
+  let mut nicknames = nicknames::Nicknames::load()?;
+  let serial = nicknames.resolve(serial);
+  nicknames.set(&serial, name)?;
+  nicknames.save()
+}
+
+/// Remove the nickname of the device with the given serial number or
+/// nickname.
+pub fn device_unname(serial: &str) -> Result<()> {
+  let mut nicknames = nicknames::Nicknames::load()?;
+  let serial = nicknames.resolve(serial);
+  if !nicknames.remove(&serial) {
+    let error = format!("The device {} has no name", serial);
+    return Err(Error::Error(error));
+  }
+  nicknames.save()
+}
+
+/// The columns of the report printed by `fleet_report`.
+const REPORT_COLUMNS: &[&str] = &[
+  "serial",
+  "name",
+  "model",
+  "firmware",
+  "user_retry_count",
+  "admin_retry_count",
+  "last_seen",
+  "connected",
+];
+
+/// Retrieve the serial numbers or nicknames selected by the context
+/// and by the device profiles of the configuration.
+fn configured_serial_numbers(ctx: &args::ExecCtx) -> Result<Vec<String>> {
+  let mut serials = ctx.serial_numbers.clone();
+  for section in ctx.config.sections() {
+    if section.starts_with("device.") {
+      let profile = ctx.config.get_strings(section, "serial-numbers")?;
+      serials.extend(profile.unwrap_or_default().into_iter().map(String::from));
+    }
+  }
+  Ok(serials)
+}
+
+/// Quote the given CSV field, if necessary.
+fn csv_field(field: &str) -> String {
+  if field.contains(&[',', '"', '\n', '\r'][..]) {
+    format!("\"{}\"", field.replace('"', "\"\""))
+  } else {
+    field.to_string()
+  }
+}
+
+/// Print an inventory of the devices known to the program, i.e., the
+/// connected one, the ones recorded in the inventory or given a
+/// nickname, and the ones selected by the configuration.
+///
+/// The firmware version and the retry counters are queried from the
+/// connected device and taken from the inventory for the others, with
+/// the time they were last seen.
+pub fn fleet_report(ctx: &args::ExecCtx, format: args::ReportFormat) -> Result<()> {
+  let mut inventory = inventory::Inventory::load()?;
+  let connected = match get_device(ctx) {
+    Ok(device) => {
+      let model = nitrocli_core::device::Model::of(&device);
+      let serial = get_serial_number(ctx, model, &device)?;
+      let entry = inventory::Entry {
+        model: Some(device_model(model).to_string()),
+        firmware: Some(format!(
+          "{}.{}",
+          device.get_major_firmware_version(),
+          device.get_minor_firmware_version()
+        )),
+        user_retry_count: Some(get_retry_count(&device, pinentry::PinType::User)),
+        admin_retry_count: Some(get_retry_count(&device, pinentry::PinType::Admin)),
+        last_seen: storage::now(),
+      };
+      inventory.record(&serial, entry);
+      inventory.save()?;
+      release_device(ctx, device);
+      Some(serial)
+    }
+    Err(Error::Core(nitrocli_core::Error::NotFound))
+    | Err(Error::Core(nitrocli_core::Error::SerialNumberMismatch(_))) => None,
+    Err(err) => return Err(err),
+  };
+
+  // Devices are identified by their normalized serial numbers, as the
+  // sources may format them differently.
+  let normalize = |serial: &str| nitrocli_core::device::normalize_serial_number(serial);
+  let nicknames = nicknames::Nicknames::load()?;
+  let mut devices = BTreeMap::new();
+  for (serial, entry) in inventory.iter() {
+    let _ = devices.insert(normalize(serial), (serial.to_string(), Some(entry)));
+  }
+  let configured = configured_serial_numbers(ctx)?;
+  let others = nicknames
+    .iter()
+    .map(|(serial, _)| serial.to_string())
+    .chain(configured.iter().map(|serial| nicknames.resolve(serial)));
+  for serial in others {
+    let _ = devices
+      .entry(normalize(&serial))
+      .or_insert_with(|| (serial, None));
+  }
+
+  let rows = devices
+    .values()
+    .map(|(serial, entry)| {
+      let entry = entry.cloned().unwrap_or_default();
+      let optional = |value: Option<json::Value>| value.unwrap_or(json::Value::Null);
+      vec![
+        json::Value::from(serial.as_str()),
+        optional(nicknames.name(serial).map(json::Value::from)),
+        optional(entry.model.map(json::Value::from)),
+        optional(entry.firmware.map(json::Value::from)),
+        optional(entry.user_retry_count.map(u64::from).map(json::Value::from)),
+        optional(
+          entry
+            .admin_retry_count
+            .map(u64::from)
+            .map(json::Value::from),
+        ),
+        optional(
+          Some(entry.last_seen)
+            .filter(|&time| time > 0)
+            .map(|time| json::Value::from(log::format_time(time::Duration::from_secs(time)))),
+        ),
+        json::Value::from(connected.as_ref().map(|c| normalize(c)) == Some(normalize(serial))),
+      ]
+    })
+    .collect::<Vec<_>>();
+
+  match format {
+    args::ReportFormat::Json => {
+      let report = rows
+        .into_iter()
+        .map(|row| json::Value::object(REPORT_COLUMNS.iter().cloned().zip(row)))
+        .collect();
+      println!("{}", json::Value::Array(report));
+    }
+    args::ReportFormat::Csv => {
+      println!("{}", REPORT_COLUMNS.join(","));
+      for row in rows {
+        let fields = row
+          .iter()
+          .map(|value| match value {
+            json::Value::Null => String::new(),
+            json::Value::String(s) => csv_field(s),
+            value => value.to_string(),
+          })
+          .collect::<Vec<_>>();
+        println!("{}", fields.join(","));
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Check whether the given input is a one-time password, i.e., six or
+/// eight digits.
+#[cfg(feature = "otp")]
+fn is_otp(input: &str) -> bool {
+  (input.len() == 6 || input.len() == 8) && input.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Check that pressing the given lock key twice makes the device type
+/// a code from the HOTP slot bound to the key.
+///
+/// The device emulates a keyboard, so the code is typed into the
+/// terminal and read from it like any other input.
+#[cfg(feature = "otp")]
+pub fn config_test_binding(ctx: &args::ExecCtx, key: args::LockKey) -> Result<()> {
+  let device = get_device(ctx)?;
+  let config = ctx
+    .retry
+    .run(|| device.get_config())
+    .map_err(|err| get_error("Could not get the device configuration", err))?;
+  let slot = match key {
+    args::LockKey::Capslock => config.capslock,
+    args::LockKey::Numlock => config.numlock,
+    args::LockKey::Scrollock => config.scrollock,
+  }
+  .ok_or_else(|| Error::Error(format!("No HOTP slot is bound to {}", key)))?;
+  let name = ctx
+    .retry
+    .run(|| nitrocli_core::otp::slot_name(&device, nitrocli_core::otp::Algorithm::Hotp, slot))?;
+  release_device(ctx, device);
+  let name = name.ok_or_else(|| {
+    let error = format!(
+      "{} is bound to HOTP slot {}, which is not programmed",
+      key, slot
+    );
+    Error::Error(error)
+  })?;
+
+  println!("{} is bound to HOTP slot {} ({})", key, slot, name);
+  let prompt = format!(
+    "Press {} twice to make the device type a code (and Enter if it does not): ",
+    key
+  );
+  let input = tty::read_line(&prompt)?;
+  let input = input.trim();
+  if is_otp(input) {
+    println!("The device typed a code, the binding works");
+    Ok(())
+  } else {
+    let error = format!("Expected a code from HOTP slot {}, got: {}", slot, input);
+    Err(Error::Error(error))
+  }
+}
+
+/// Print the completion script for the given shell.
+pub fn completions(shell: args::Shell, program: &completions::Command) -> Result<()> {
+  print!("{}", completions::generate(shell, program));
+  Ok(())
+}
+
+/// Print the values of the given kind offered for completion.
+pub fn completions_list(kind: &str) -> Result<()> {
+  for value in completions::list(kind)? {
+    println!("{}", value);
+  }
+  Ok(())
+}
+
+/// Convert the given OTP algorithm into the one of the core library.
+#[cfg(feature = "otp")]
+fn get_otp_algorithm(algorithm: args::OtpAlgorithm) -> nitrocli_core::otp::Algorithm {
+  match algorithm {
+    args::OtpAlgorithm::Hotp => nitrocli_core::otp::Algorithm::Hotp,
+    args::OtpAlgorithm::Totp => nitrocli_core::otp::Algorithm::Totp,
+  }
+}
+
+/// Resolve the given OTP slot, given either by number or by name, to a
+/// slot number and the time offset in seconds to apply for TOTP.
+///
+/// Aliases registered using `alias add` take precedence over the names
+/// of the slots, and only they can carry a time offset. Names are resolved using the slot cache, if possible. A slot found
+/// in the cache is verified to still carry the requested name, which
+/// requires a single query instead of reading the names of all slots.
+/// If the name is not cached, the cache is stale, or verification
+/// fails, the names of all slots are read and the cache is refreshed.
+#[cfg(feature = "otp")]
+fn resolve_otp_slot<D>(
+  ctx: &args::ExecCtx,
+  device: &D,
+  slot: &str,
+  algorithm: args::OtpAlgorithm,
+) -> Result<(u8, i64)>
+where
+  D: Device,
+{
+  if let Ok(slot) = slot.parse::<u8>() {
+    return Ok((slot, 0));
+  }
+
+  let serial = ctx
+    .retry
+    .run(|| nitrocli_core::device::serial_number(device))?;
+  let registry = registry::Registry::load()?;
+  if let Some(aliased) = registry.lookup(&serial, "otp", slot) {
+    debug!("Resolved the alias {} to OTP slot {}", slot, aliased);
+    return Ok((aliased, registry.offset(&serial, slot)));
+  }
+
+  let kind = algorithm.to_string();
+  let core_algorithm = get_otp_algorithm(algorithm);
+  let now = storage::now();
+  let mut cache = slots::SlotCache::load()?;
+
+  if let Some(cached) = cache.lookup(&serial, &kind, slot, now) {
+    if let Ok(Some(name)) = nitrocli_core::otp::slot_name(device, core_algorithm, cached) {
+      if name == slot {
+        debug!("Found {} slot {} in the cache", kind, slot);
+        return Ok((cached, 0));
+      }
+    }
+  }
+
+  debug!("Refreshing the cached names of the {} slots", kind);
+  let names = ctx
+    .retry
+    .run(|| nitrocli_core::otp::slot_names(device, core_algorithm))?;
+  let found = names
+    .iter()
+    .find(|&(_, name)| name == slot)
+    .map(|(&number, _)| number);
+  cache.update(&serial, &kind, names, now);
+  cache.save()?;
+
+  found
+    .map(|found| (found, 0))
+    .ok_or_else(|| Error::Error(format!("No {} slot with name {} found", algorithm, slot)))
+}
+
+/// Connect to the device matching the given context and cache the names
+/// of its OTP slots, unless current names are cached already.
+///
+/// The shell and the agent do so before they are first used, so that
+/// resolving a slot name does not require reading all slots then.
+#[cfg(feature = "otp")]
+pub fn prefetch(ctx: &args::ExecCtx) -> Result<()> {
+  let device = get_device(ctx)?;
+  let serial = ctx
+    .retry
+    .run(|| nitrocli_core::device::serial_number(&device))?;
+  let now = storage::now();
+  let mut cache = slots::SlotCache::load()?;
+
+  for &algorithm in &[args::OtpAlgorithm::Hotp, args::OtpAlgorithm::Totp] {
+    let kind = algorithm.to_string();
+    if !cache.is_current(&serial, &kind, now) {
+      debug!("Prefetching the names of the {} slots", kind);
+      let names = ctx
+        .retry
+        .run(|| nitrocli_core::otp::slot_names(&device, get_otp_algorithm(algorithm)))?;
+      cache.update(&serial, &kind, names, now);
+    }
+  }
+  cache.save()?;
+  release_device(ctx, device);
+  Ok(())
+}
+
+/// A connection to a device, possibly authenticated as user.
+#[derive(Debug)]
+pub enum Connection {
+  /// An unauthenticated connection.
+  Device(nitrokey::DeviceWrapper),
+  /// A connection authenticated using the user PIN.
+  #[cfg(feature = "otp")]
+  User(nitrokey::User<nitrokey::DeviceWrapper>),
+}
+
+impl Connection {
+  /// Connect to the device matching the given context.
+  pub fn connect(ctx: &args::ExecCtx) -> Result<Self> {
+    get_device(ctx).map(Connection::Device)
+  }
+
+  /// Retrieve the connected device.
+  fn device(&self) -> &nitrokey::DeviceWrapper {
+    match self {
+      Connection::Device(device) => device,
+      #[cfg(feature = "otp")]
+      Connection::User(user) => user,
+    }
+  }
+
+  /// Retrieve the connected device, dropping the authentication.
+  #[cfg(any(feature = "otp", feature = "pws"))]
+  fn into_device(self) -> nitrokey::DeviceWrapper {
+    match self {
+      Connection::Device(device) => device,
+      #[cfg(feature = "otp")]
+      Connection::User(user) => user.device(),
+    }
+  }
+
+  /// Hand the connection back to the given context for reuse by later
+  /// operations.
+  #[cfg(any(feature = "otp", feature = "pws"))]
+  fn release(self, ctx: &args::ExecCtx) {
+    release_device(ctx, self.into_device())
+  }
+}
+
+/// Query the state of the given device for the metrics of the agent.
+fn query_device_state(device: &nitrokey::DeviceWrapper) -> Option<metrics::DeviceState> {
+  let serial_number = device.get_serial_number().ok()?;
+  let (model, encrypted_volume_active) = match device {
+    nitrokey::DeviceWrapper::Pro(_) => (args::DeviceModel::Pro, None),
+    nitrokey::DeviceWrapper::Storage(storage) => {
+      let status = storage.get_status().ok()?;
+      (
+        args::DeviceModel::Storage,
+        Some(status.encrypted_volume.active),
+      )
+    }
+  };
+  Some(metrics::DeviceState {
+    model,
+    serial_number,
+    user_retry_count: device.get_user_retry_count(),
+    admin_retry_count: device.get_admin_retry_count(),
+    encrypted_volume_active,
+  })
+}
+
+/// Retrieve the state of the device for the metrics of the agent.
+///
+/// The given connection is used if possible. If there is none or it
+/// does not work anymore, a new connection is established. `None` is
+/// returned if no matching device is connected.
+pub fn device_state(
+  ctx: &args::ExecCtx,
+  connection: &mut Option<Connection>,
+) -> Option<metrics::DeviceState> {
+  if let Some(state) = connection
+    .as_ref()
+    .filter(|current| {
+      lock_device(
+        ctx,
+        nitrocli_core::device::Model::of(current.device()),
+        current.device(),
+      )
+      .is_ok()
+    })
+    .and_then(|current| query_device_state(current.device()))
+  {
+    return Some(state);
+  }
+
+  *connection = None;
+  let current = Connection::connect(ctx).ok()?;
+  let state = query_device_state(current.device());
+  if state.is_some() {
+    *connection = Some(current);
+  }
+  state
+}
+
+/// Generate a one-time password using the given OTP slot, given either
+/// by number or by name.
+///
+/// If the device requires the user PIN for generating one-time
+/// passwords, the connection is authenticated first. The connection is
+/// returned along with the password, so that it can be reused without
+/// authenticating again.
+#[cfg(feature = "otp")]
+pub fn generate_otp(
+  ctx: &args::ExecCtx,
+  connection: Connection,
+  slot: &str,
+  algorithm: args::OtpAlgorithm,
+) -> Result<(Connection, String)> {
+  let options = TotpOptions::default();
+  let (connection, otp, _) = generate_otp_with(ctx, connection, slot, algorithm, &options)?;
+  Ok((connection, otp))
+}
+
+/// Shift the given time by the given offset in seconds.
+#[cfg(feature = "otp")]
+fn shift(time: u64, offset: i64) -> u64 {
+  (time as i64).saturating_add(offset).max(0) as u64
+}
+
+/// The options for generating a one-time password that only apply to
+/// TOTP.
+#[cfg(feature = "otp")]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TotpOptions {
+  /// The number of seconds the password has to remain valid at least.
+  /// If fewer remain, we wait for the next time window.
+  pub fresh: Option<u64>,
+  /// Whether to print the number of seconds the password remains valid.
+  pub validity: bool,
+  /// The offset in seconds to add to the current time, overriding the
+  /// one registered for the alias of the slot, if any.
+  pub offset: Option<i64>,
+  /// The time to generate the password for instead of the current
+  /// one, in seconds since the Unix epoch.
+  pub at: Option<u64>,
+}
+
+/// Generate a one-time password like `generate_otp`, applying the given
+/// TOTP options.
+///
+/// For TOTP, the time of the device is set to the current or the given
+/// time shifted by the offset, and the number of seconds the password
+/// remains valid is returned along with it.
+#[cfg(feature = "otp")]
+fn generate_otp_with(
+  ctx: &args::ExecCtx,
+  connection: Connection,
+  slot: &str,
+  algorithm: args::OtpAlgorithm,
+  options: &TotpOptions,
+) -> Result<(Connection, String, Option<u64>)> {
+  lock_device(
+    ctx,
+    nitrocli_core::device::Model::of(connection.device()),
+    connection.device(),
+  )?;
+  let (slot, registered) = resolve_otp_slot(ctx, connection.device(), slot, algorithm)?;
+  // The end of the time window of a TOTP and the offset it was
+  // generated with.
+  let expiry = if algorithm == args::OtpAlgorithm::Totp {
+    let offset = options.offset.unwrap_or(registered);
+    if offset != 0 {
+      debug!("Generating a TOTP with a time offset of {} seconds", offset);
+    }
+    let current = || shift(options.at.unwrap_or_else(storage::now), offset);
+    let mut now = current();
+    if let Some(fresh) = options.fresh {
+      let remaining = totp_validity(now);
+      if remaining < fresh {
+        debug!(
+          "Waiting {} seconds for the next TOTP time window",
+          remaining
+        );
+        thread::sleep(time::Duration::from_secs(remaining));
+        now = current();
+      }
+    }
+    ctx
+      .retry
+      .run(|| connection.device().set_time(now))
+      .map_err(|err| get_error("Could not set the time", err))?;
+    Some((now + totp_validity(now), offset))
+  } else {
+    None
+  };
+
+  let connection = match connection {
+    Connection::Device(device) => {
+      let config = ctx
+        .retry
+        .run(|| device.get_config())
+        .map_err(|err| get_error("Could not get the device configuration", err))?;
+      if config.user_password {
+        Connection::User(authenticate_user(ctx, device)?)
+      } else {
+        Connection::Device(device)
+      }
+    }
+    connection => connection,
+  };
+  let algorithm = get_otp_algorithm(algorithm);
+  let otp = match connection {
+    Connection::Device(ref device) => ctx
+      .retry
+      .run(|| nitrocli_core::otp::generate(device, algorithm, slot))?,
+    Connection::User(ref user) => ctx
+      .retry
+      .run(|| nitrocli_core::otp::generate(user, algorithm, slot))?,
+  };
+  // Authenticating may have taken a while, so the time the password
+  // remains valid is determined only now.
+  let validity = expiry.map(|(end, offset)| {
+    let now = shift(options.at.unwrap_or_else(storage::now), offset);
+    end.saturating_sub(now)
+  });
+  Ok((connection, otp, validity))
+}
+
+/// The number of times each operation is performed by `bench`, unless
+/// requested otherwise.
+pub const DEFAULT_BENCH_ITERATIONS: usize = 10;
+
+/// Perform the given operation the given number of times and return the
+/// time each one took.
+fn measure<F>(iterations: usize, mut op: F) -> Result<Vec<time::Duration>>
+where
+  F: FnMut() -> Result<()>,
+{
+  let mut latencies = Vec::with_capacity(iterations);
+  for _ in 0..iterations {
+    let start = time::Instant::now();
+    op()?;
+    latencies.push(start.elapsed());
+  }
+  Ok(latencies)
+}
+
+/// Convert the given duration into milliseconds.
+fn millis(duration: time::Duration) -> f64 {
+  duration.as_secs() as f64 * 1000.0 + f64::from(duration.subsec_nanos()) / 1_000_000.0
+}
+
+/// Format the given latencies of the operation with the given name as a
+/// row of the table printed by `bench`.
+fn format_latencies(name: &str, latencies: &[time::Duration]) -> String {
+  let millis = latencies.iter().cloned().map(millis).collect::<Vec<_>>();
+  let min = millis.iter().cloned().fold(f64::INFINITY, f64::min);
+  let max = millis.iter().cloned().fold(0.0, f64::max);
+  let avg = millis.iter().sum::<f64>() / millis.len() as f64;
+  format!("{:<10}{:>10.1}{:>10.1}{:>10.1}", name, min, avg, max)
+}
+
+/// Measure the latency of generating a one-time password using the
+/// first programmed TOTP slot of the given device, print it, and hand
+/// back the device.
+#[cfg(feature = "otp")]
+fn bench_otp(
+  ctx: &args::ExecCtx,
+  device: nitrokey::DeviceWrapper,
+  iterations: usize,
+) -> Result<nitrokey::DeviceWrapper> {
+  let algorithm = nitrocli_core::otp::Algorithm::Totp;
+  let names = ctx
+    .retry
+    .run(|| nitrocli_core::otp::slot_names(&device, algorithm))?;
+  let slot = match names.keys().next() {
+    Some(&slot) => slot,
+    None => {
+      println!("{:<10}skipped, no TOTP slot is programmed", "otp");
+      return Ok(device);
+    }
+  };
+
+  // Generating a password once takes care of setting the time and of
+  // authenticating, if required.
+  let (connection, _) = generate_otp(
+    ctx,
+    Connection::Device(device),
+    &slot.to_string(),
+    args::OtpAlgorithm::Totp,
+  )?;
+  let latencies = match connection {
+    Connection::Device(ref device) => measure(iterations, || {
+      nitrocli_core::otp::generate(device, algorithm, slot)
+        .map(|_| ())
+        .map_err(Error::from)
+    }),
+    Connection::User(ref user) => measure(iterations, || {
+      nitrocli_core::otp::generate(user, algorithm, slot)
+        .map(|_| ())
+        .map_err(Error::from)
+    }),
+  }?;
+  println!("{}", format_latencies("otp", &latencies));
+  Ok(connection.into_device())
+}
+
+/// Measure the latencies of device operations over the given number of
+/// iterations and print them.
+///
+/// The operations are connecting to the device, reading its status,
+/// reading the slot status of the password safe, and generating a
+/// one-time password using the first programmed TOTP slot, which sets
+/// the time of the device. The user PIN is asked for once before the
+/// operations requiring it are measured.
+pub fn bench(ctx: &args::ExecCtx, iterations: usize) -> Result<()> {
+  let mut device = get_device(ctx)?;
+  let model = nitrocli_core::device::Model::of(&device);
+  let serial = get_serial_number(ctx, model, &device)?;
+  println!(
+    "{} {}, firmware v{}.{}, {} iterations\n",
+    model,
+    serial,
+    device.get_major_firmware_version(),
+    device.get_minor_firmware_version(),
+    iterations
+  );
+  println!(
+    "{:<10}{:>10}{:>10}{:>10}",
+    "operation", "min (ms)", "avg (ms)", "max (ms)"
+  );
+
+  let selector = get_selector(ctx);
+  let mut connect = Vec::with_capacity(iterations);
+  for _ in 0..iterations {
+    drop(device);
+    let start = time::Instant::now();
+    device = nitrocli_core::device::connect(&selector)?;
+    connect.push(start.elapsed());
+  }
+  println!("{}", format_latencies("connect", &connect));
+
+  let status = measure(iterations, || {
+    query_device_state(&device)
+      .map(|_| ())
+      .ok_or_else(|| Error::Error("Could not read the status of the device".to_string()))
+  })?;
+  println!("{}", format_latencies("status", &status));
+
+  #[cfg(feature = "pws")]
+  {
+    let pws = get_password_safe(ctx, &device)?;
+    let latencies = measure(iterations, || {
+      pws
+        .get_slot_status()
+        .map(|_| ())
+        .map_err(|err| get_error("Could not read the password safe slot status", err))
+    })?;
+    println!("{}", format_latencies("pws", &latencies));
+  }
+
+  #[cfg(feature = "otp")]
+  let device = bench_otp(ctx, device, iterations)?;
+  release_device(ctx, device);
+  Ok(())
+}
+
+/// Print the changes to the device generating a one-time password using
+/// the given OTP slot would make.
+///
+/// Even a read-only looking operation like this changes the device:
+/// generating an HOTP increments the counter of the slot, and TOTP
+/// generation sets the time of the device.
+#[cfg(feature = "otp")]
+fn otp_get_dry_run(ctx: &args::ExecCtx, slot: &str, algorithm: args::OtpAlgorithm) -> Result<()> {
+  let connection = Connection::connect(ctx)?;
+  lock_device(
+    ctx,
+    nitrocli_core::device::Model::of(connection.device()),
+    connection.device(),
+  )?;
+  let (slot, _) = resolve_otp_slot(ctx, connection.device(), slot, algorithm)?;
+  let change = match algorithm {
+    args::OtpAlgorithm::Hotp => format!(
+      "generate a one-time password using HOTP slot {} and increment its counter",
+      slot
+    ),
+    args::OtpAlgorithm::Totp => format!(
+      "set the time and generate a one-time password using TOTP slot {}",
+      slot
+    ),
+  };
+  let _ = dry_run(ctx, &change);
+  connection.release(ctx);
+  Ok(())
+}
+
+/// Generate a one-time password using the given OTP slot, given either
+/// by number or by name, and print it.
+///
+/// If an agent is running, the password is requested from it, unless
+/// the PIN cache is bypassed or TOTP options are given, which the agent
+/// does not support. If `digits` is given, it is an error if the
+/// password has a different number of digits. If `clip` is set, the
+/// password is copied to the clipboard instead of being printed. If
+/// requested, the number of seconds a TOTP remains valid is printed
+/// after it.
+#[cfg(feature = "otp")]
+pub fn otp_get(
+  ctx: &args::ExecCtx,
+  slot: &str,
+  algorithm: args::OtpAlgorithm,
+  digits: Option<usize>,
+  clip: bool,
+  options: TotpOptions,
+) -> Result<()> {
+  if ctx.dry_run {
+    return otp_get_dry_run(ctx, slot, algorithm);
+  }
+
+  // The agent uses the device selected when it was started, so it
+  // cannot serve requests restricted to a model or serial number.
+  let selected = ctx.model.is_some() || !ctx.serial_numbers.is_empty();
+  let agent_otp = if ctx.no_cache || selected || options != TotpOptions::default() {
+    None
+  } else {
+    agent::request_otp(slot, algorithm)?
+  };
+  let (otp, validity) = match agent_otp {
+    Some(otp) => (otp, None),
+    None => {
+      let connection = Connection::connect(ctx)?;
+      let (connection, otp, validity) =
+        generate_otp_with(ctx, connection, slot, algorithm, &options)?;
+      connection.release(ctx);
+      (otp, validity)
+    }
+  };
+
+  check_otp_digits(slot, &otp, digits)?;
+  if clip {
+    clipboard::copy(&otp)?;
+  } else {
+    println!("{}", otp);
+  }
+  if let (true, Some(validity)) = (options.validity, validity) {
+    println!("validity: {}", validity);
+  }
+  Ok(())
+}
+
+/// Check that the given one-time password generated using the given
+/// slot has the given number of digits, if any.
+#[cfg(feature = "otp")]
+fn check_otp_digits(slot: &str, otp: &str, digits: Option<usize>) -> Result<()> {
+  match digits {
+    Some(digits) if otp.len() != digits => {
+      let error = format!(
+        "The slot {} generates OTPs with {} digits instead of {}",
+        slot,
+        otp.len(),
+        digits
+      );
+      Err(Error::Error(error))
+    }
+    _ => Ok(()),
+  }
+}
+
+/// Format the given pairs of slot names and one-time passwords as a
+/// table.
+#[cfg(feature = "otp")]
+fn format_otp_table(otps: &[(String, String)]) -> String {
+  let width = otps.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+  otps
+    .iter()
+    .map(|(name, otp)| format!("{:<width$}  {}\n", name, otp, width = width))
+    .collect()
+}
+
+/// Generate one-time passwords using the given OTP slots and, if
+/// `all_totp` is set, all programmed TOTP slots not given explicitly,
+/// and print them as a table of slot names and passwords.
+///
+/// The device is connected to and, if it requires the user PIN for
+/// generating one-time passwords, authenticated with only once. The
+/// agent is not involved. `digits` and the TOTP options apply to all
+/// slots like for `otp_get`, with the validity printed being the one
+/// of the password expiring first.
+#[cfg(feature = "otp")]
+pub fn otp_get_many(
+  ctx: &args::ExecCtx,
+  slots: &[String],
+  algorithm: args::OtpAlgorithm,
+  all_totp: bool,
+  digits: Option<usize>,
+  options: TotpOptions,
+) -> Result<()> {
+  if ctx.dry_run {
+    for slot in slots {
+      otp_get_dry_run(ctx, slot, algorithm)?;
+    }
+    if all_totp {
+      let _ = dry_run(
+        ctx,
+        "set the time and generate one-time passwords using all TOTP slots",
+      );
+    }
+    return Ok(());
+  }
+
+  let mut connection = Connection::connect(ctx)?;
+  let mut otps = Vec::new();
+  let mut validities = Vec::new();
+  let mut totp_slots = BTreeSet::new();
+  for slot in slots {
+    let (used, otp, validity) = generate_otp_with(ctx, connection, slot, algorithm, &options)?;
+    connection = used;
+    check_otp_digits(slot, &otp, digits)?;
+    if algorithm == args::OtpAlgorithm::Totp {
+      let (number, _) = resolve_otp_slot(ctx, connection.device(), slot, algorithm)?;
+      let _ = totp_slots.insert(number);
+    }
+    otps.push((slot.clone(), otp));
+    validities.extend(validity);
+  }
+
+  if all_totp {
+    let totp = args::OtpAlgorithm::Totp;
+    let names = ctx
+      .retry
+      .run(|| nitrocli_core::otp::slot_names(connection.device(), get_otp_algorithm(totp)))?;
+    for (number, name) in names {
+      if totp_slots.contains(&number) {
+        continue;
+      }
+      let slot = number.to_string();
+      let (used, otp, validity) = generate_otp_with(ctx, connection, &slot, totp, &options)?;
+      connection = used;
+      check_otp_digits(&name, &otp, digits)?;
+      otps.push((name, otp));
+      validities.extend(validity);
+    }
+  }
+  connection.release(ctx);
+
+  print!("{}", format_otp_table(&otps));
+  if let (true, Some(validity)) = (options.validity, validities.iter().min()) {
+    println!("validity: {}", validity);
+  }
+  Ok(())
+}
+
+/// Let the user pick one of the programmed OTP slots by fuzzy matching
+/// its name and generate a one-time password using it like `otp_get`.
+#[cfg(feature = "otp")]
+pub fn otp_pick(ctx: &args::ExecCtx, clip: bool) -> Result<()> {
+  let connection = Connection::connect(ctx)?;
+  let mut slots = Vec::new();
+  for &algorithm in &[args::OtpAlgorithm::Totp, args::OtpAlgorithm::Hotp] {
+    let names = ctx
+      .retry
+      .run(|| nitrocli_core::otp::slot_names(connection.device(), get_otp_algorithm(algorithm)))?;
+    slots.extend(
+      names
+        .into_iter()
+        .map(|(slot, name)| (algorithm, slot, name)),
+    );
+  }
+  connection.release(ctx);
+
+  let labels = slots
+    .iter()
+    .map(|(algorithm, slot, name)| format!("{} ({} slot {})", name, algorithm, slot))
+    .collect::<Vec<_>>();
+  let (algorithm, slot, _) = slots[picker::pick("> ", &labels)?];
+  let options = TotpOptions::default();
+  otp_get(ctx, &slot.to_string(), algorithm, None, clip, options)
+}
+
+/// The maximum length of the name of an OTP slot.
+#[cfg(feature = "otp")]
+const OTP_NAME_LENGTH: usize = 15;
+
+/// Check that the given name of an OTP slot is valid.
+#[cfg(feature = "otp")]
+fn check_otp_name(name: &str) -> Result<()> {
+  if name.is_empty() {
+    Err(Error::Error("The name must not be empty".to_string()))
+  } else if name.len() > OTP_NAME_LENGTH {
+    let error = format!(
+      "The name must not be longer than {} characters, but is {}",
+      OTP_NAME_LENGTH,
+      name.len()
+    );
+    Err(Error::Error(error))
+  } else {
+    Ok(())
+  }
+}
+
+/// Check that the given slot number is valid for the given algorithm.
+#[cfg(feature = "otp")]
+fn check_otp_slot(algorithm: args::OtpAlgorithm, slot: u8) -> Result<()> {
+  let count = get_otp_algorithm(algorithm).slot_count();
+  if slot < count {
+    Ok(())
+  } else {
+    let error = format!(
+      "Invalid {} slot: {} (expected 0 to {})",
+      algorithm,
+      slot,
+      count - 1
+    );
+    Err(Error::Error(error))
+  }
+}
+
+/// Decode the given base32 secret of an OTP slot.
+#[cfg(feature = "otp")]
+fn decode_otp_secret(secret: &Secret) -> Result<Secret> {
+  let invalid = || Error::Error("The secret is not a valid base32 string".to_string());
+  match oath::decode_base32(secret.as_str().map_err(|_| invalid())?) {
+    Some(ref secret) if !secret.as_bytes().is_empty() => Ok(secret.clone()),
+    _ => Err(invalid()),
+  }
+}
+
+/// Read the base32 secret of an OTP slot from the terminal, without
+/// echoing it, or from the standard input, if it is no terminal.
+#[cfg(feature = "otp")]
+fn read_otp_secret() -> Result<Secret> {
+  let secret = if tty::is_stdin_tty() {
+    tty::read_passphrase("Enter the secret (base32): ", tty::Echo::None)?
+  } else {
+    let mut line = String::new();
+    let _ = io::stdin().read_line(&mut line)?;
+    Secret::from(line.trim_end_matches(&['\r', '\n'][..]))
+  };
+  decode_otp_secret(&secret)
+}
+
+/// Create the task writing an OTP slot with the given settings and the
+/// given raw secret.
+#[cfg(feature = "otp")]
+fn otp_slot_task(
+  algorithm: args::OtpAlgorithm,
+  slot: u8,
+  name: &str,
+  secret: &Secret,
+  digits: usize,
+  counter: u64,
+) -> module::OtpSlotTask {
+  module::OtpSlotTask {
+    algorithm,
+    slot,
+    state: module::OtpSlotState::Present {
+      name: name.to_string(),
+      secret: oath::encode_hex(secret.as_bytes()),
+      digits: digits as u8,
+      time_window: TOTP_TIME_WINDOW,
+      counter,
+    },
+  }
+}
+
+/// The time window of TOTP slots written by `otp set`, in seconds.
+#[cfg(feature = "otp")]
+pub const TOTP_TIME_WINDOW: u16 = 30;
+
+/// Retrieve the number of seconds a TOTP generated at the given time
+/// remains valid.
+///
+/// The time window of a slot cannot be read from the device, so we
+/// assume the one used by `otp set`.
+#[cfg(feature = "otp")]
+fn totp_validity(now: u64) -> u64 {
+  let window = u64::from(TOTP_TIME_WINDOW);
+  window - now % window
+}
+
+/// Write an OTP slot with the given settings and a secret read from the
+/// terminal or the standard input.
+#[cfg(feature = "otp")]
+pub fn otp_set(
+  ctx: &args::ExecCtx,
+  algorithm: args::OtpAlgorithm,
+  slot: u8,
+  name: &str,
+  digits: usize,
+) -> Result<()> {
+  check_otp_slot(algorithm, slot)?;
+  check_otp_name(name)?;
+  let secret = read_otp_secret()?;
+
+  let task = otp_slot_task(algorithm, slot, name, &secret, digits, 0);
+  apply_change(ctx, Change::WriteOtpSlot(task))
+}
+
+/// Ask the given question on the terminal, returning the given default
+/// answer if the user does not enter one.
+fn ask(question: &str, default: &str) -> Result<String> {
+  let prompt = if default.is_empty() {
+    format!("{}: ", question)
+  } else {
+    format!("{} [{}]: ", question, default)
+  };
+  let answer = tty::read_line(&prompt)?;
+  match answer.trim() {
+    "" => Ok(default.to_string()),
+    answer => Ok(answer.to_string()),
+  }
+}
+
+/// Ask the given question on the terminal until the given function
+/// accepts the answer, reporting the reason for rejecting it otherwise.
+#[cfg(feature = "otp")]
+fn ask_until<F, T>(question: &str, default: &str, mut accept: F) -> Result<T>
+where
+  F: FnMut(String) -> Result<T>,
+{
+  loop {
+    match accept(ask(question, default)?) {
+      Ok(value) => return Ok(value),
+      Err(Error::Error(err)) => println!("{}", err),
+      Err(err) => return Err(err),
+    }
+  }
+}
+
+/// Ask the given yes/no question on the terminal, defaulting to no.
+fn ask_yes(question: &str) -> Result<bool> {
+  let answer = ask(&format!("{} (yes/no)", question), "no")?;
+  Ok(answer == "y" || answer == "yes")
+}
+
+/// Ask the given question about changing a PIN with the given factory
+/// default, unless the given PIN policy does not permit keeping the
+/// default, in which case the PIN has to be changed.
+fn ask_change_pin(policy: &policy::Policy, default: &str, question: &str) -> Result<bool> {
+  if policy.check(default).is_err() {
+    println!("The PIN policy does not permit keeping it, so it has to be changed.");
+    Ok(true)
+  } else {
+    ask_yes(question)
+  }
+}
+
+/// Interactively write an OTP slot, asking the user for its settings.
+///
+/// The user chooses the algorithm and the slot, with the free slots
+/// being shown, and enters the name, the secret, and the number of
+/// digits. Before anything is written, the user has to confirm that the
+/// service accepts a code generated from the secret.
+#[cfg(feature = "otp")]
+pub fn otp_set_interactive(ctx: &args::ExecCtx) -> Result<()> {
+  if ctx.no_prompt || !tty::is_stdin_tty() {
+    let error = "The interactive mode requires a terminal and may not be used with --no-prompt";
+    return Err(Error::Error(error.to_string()));
+  }
+
+  let device = get_device(ctx)?;
+  let algorithm = ask_until("Algorithm (hotp|totp)", "totp", |answer| {
+    args::parse_otp_algorithm(&answer)
+  })?;
+  let names = ctx
+    .retry
+    .run(|| nitrocli_core::otp::slot_names(&device, get_otp_algorithm(algorithm)))?;
+  release_device(ctx, device);
+  let free = (0..get_otp_algorithm(algorithm).slot_count())
+    .filter(|slot| !names.contains_key(slot))
+    .map(|slot| slot.to_string())
+    .collect::<Vec<_>>();
+  for (slot, name) in &names {
+    println!("The {} slot {} is programmed as {}", algorithm, slot, name);
+  }
+  if free.is_empty() {
+    println!("All {} slots are programmed", algorithm);
+  } else {
+    println!("Free {} slots: {}", algorithm, free.join(" "));
+  }
+
+  let default = free.first().map(String::as_str).unwrap_or("");
+  let slot = ask_until("Slot", default, |answer| {
+    let slot = answer
+      .parse::<u8>()
+      .map_err(|_| Error::Error(format!("Invalid slot: {}", answer)))?;
+    check_otp_slot(algorithm, slot)?;
+    match names.get(&slot) {
+      Some(name) if !ask_yes(&format!("Overwrite the slot {} ({})?", slot, name))? => {
+        Err(Error::Error("Choose another slot".to_string()))
+      }
+      _ => Ok(slot),
+    }
+  })?;
+  let name = ask_until("Name", "", |name| check_otp_name(&name).map(|_| name))?;
+  let secret = loop {
+    let secret = tty::read_passphrase("Secret (base32, not echoed): ", tty::Echo::None)?;
+    match decode_otp_secret(&secret) {
+      Ok(secret) => break secret,
+      Err(Error::Error(err)) => println!("{}", err),
+      Err(err) => return Err(err),
+    }
+  };
+  let digits = ask_until("Digits (6|8)", "6", |answer| args::parse_digits(&answer))?;
+
+  // The service usually has to be given a code to finish the
+  // enrollment, which also checks the secret.
+  let counter = match algorithm {
+    args::OtpAlgorithm::Hotp => 0,
+    args::OtpAlgorithm::Totp => {
+      let now = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map_err(|_| Error::Error("The system time is before the Unix epoch".to_string()))?;
+      now.as_secs() / u64::from(TOTP_TIME_WINDOW)
+    }
+  };
+  let code = oath::hotp(secret.as_bytes(), counter, digits as u32);
+  println!(
+    "The current code is {}, check that the service accepts it",
+    code
+  );
+  if !ask_yes(&format!("Write the {} slot {}?", algorithm, slot))? {
+    return Err(Error::Error("Aborted".to_string()));
+  }
+
+  // An HOTP code accepted by the service is used up, so the slot
+  // starts with the next one.
+  let counter = match algorithm {
+    args::OtpAlgorithm::Hotp => 1,
+    args::OtpAlgorithm::Totp => 0,
+  };
+  let task = otp_slot_task(algorithm, slot, &name, &secret, digits, counter);
+  apply_change(ctx, Change::WriteOtpSlot(task))?;
+  if !ctx.dry_run {
+    println!("Wrote the {} slot {} ({})", algorithm, slot, name);
+  }
+  Ok(())
+}
+
+/// Open the password safe of the given device using the user PIN.
+#[cfg(feature = "pws")]
+fn get_password_safe<'d, D>(
+  ctx: &args::ExecCtx,
+  device: &'d D,
+) -> Result<nitrokey::PasswordSafe<'d>>
+where
+  D: Device + GetPasswordSafe,
+{
+  try_with_passphrase(
+    ctx,
+    device,
+    pinentry::PinType::User,
+    "Could not access the password safe",
+    |passphrase| device.get_password_safe(passphrase),
+  )
+}
+
+/// Find the password safe slot matching the given askpass prompt.
+///
+/// A slot matches if its name is contained in the prompt, ignoring
+/// case. If multiple slots match, the one with the longest name is
+/// chosen, as it is the most specific one.
+#[cfg(feature = "pws")]
+fn match_askpass_slot(prompt: &str, names: &BTreeMap<u8, String>) -> Option<u8> {
+  let prompt = prompt.to_lowercase();
+  names
+    .iter()
+    .filter(|&(_, name)| !name.is_empty() && prompt.contains(&name.to_lowercase()))
+    .max_by_key(|&(_, name)| name.len())
+    .map(|(&slot, _)| slot)
+}
+
+/// Print the credential requested by the given askpass prompt.
+///
+/// The credential is taken from the password safe slot matching the
+/// prompt. Prompts asking for a user name, such as the ones of git,
+/// are answered with the login of the slot, all others with its
+/// password.
+#[cfg(feature = "pws")]
+pub fn askpass(ctx: &args::ExecCtx, prompt: &str) -> Result<()> {
+  let device = get_device(ctx)?;
+  let credential = read_askpass_credential(ctx, &device, prompt)?;
+  release_device(ctx, device);
+  println!("{}", credential.as_str()?);
+  Ok(())
+}
+
+/// Read the credential requested by the given askpass prompt from the
+/// password safe of the given device.
+#[cfg(feature = "pws")]
+fn read_askpass_credential(
+  ctx: &args::ExecCtx,
+  device: &nitrokey::DeviceWrapper,
+  prompt: &str,
+) -> Result<Secret> {
+  let pws = get_password_safe(ctx, device)?;
+  let names = ctx.retry.run(|| nitrocli_core::pws::slot_names(&pws))?;
+  let slot = match_askpass_slot(prompt, &names).ok_or_else(|| {
+    let error = format!(
+      "No password safe slot matches the prompt: {}",
+      prompt.trim()
+    );
+    Error::Error(error)
+  })?;
+
+  let username = prompt.trim_start().to_lowercase().starts_with("username");
+  ctx
+    .retry
+    .run(|| {
+      if username {
+        pws.get_slot_login(slot)
+      } else {
+        pws.get_slot_password(slot)
+      }
+    })
+    .map(Secret::from)
+    .map_err(|err| get_error("Could not read the password safe slot", err))
+}
+
+/// The maximum length of the name of a password safe slot.
+#[cfg(feature = "pws")]
+const PWS_NAME_LENGTH: usize = 11;
+/// The maximum length of the login of a password safe slot.
+#[cfg(feature = "pws")]
+const PWS_LOGIN_LENGTH: usize = 32;
+/// The maximum length of the password of a password safe slot.
+#[cfg(feature = "pws")]
+pub const PWS_PASSWORD_LENGTH: usize = 20;
+
+/// Format the given names of password safe entries as a tree, the way
+/// `pass ls` does.
+#[cfg(feature = "pws")]
+fn format_pass_tree<'n, I>(names: I) -> String
+where
+  I: IntoIterator<Item = &'n str>,
+{
+  let mut names = names.into_iter().collect::<Vec<_>>();
+  names.sort();
+
+  let mut tree = String::from("Password Store\n");
+  for (i, name) in names.iter().enumerate() {
+    let branch = if i + 1 == names.len() {
+      "└──"
+    } else {
+      "├──"
+    };
+    tree += &format!("{} {}\n", branch, name);
+  }
+  tree
+}
+
+/// List the names of the entries of the password safe.
+#[cfg(feature = "pws")]
+pub fn pass_ls(ctx: &args::ExecCtx) -> Result<()> {
+  let device = get_device(ctx)?;
+  let names = {
+    let pws = get_password_safe(ctx, &device)?;
+    ctx.retry.run(|| nitrocli_core::pws::slot_names(&pws))?
+  };
+  release_device(ctx, device);
+  pager::page(ctx, &format_pass_tree(names.values().map(String::as_str)))
+}
+
+/// Retrieve the login and the password of the password safe entry with
+/// the given name or alias using the given connection.
+#[cfg(feature = "pws")]
+pub fn get_pws_entry(
+  ctx: &args::ExecCtx,
+  connection: &Connection,
+  name: &str,
+) -> Result<(String, Secret)> {
+  lock_device(
+    ctx,
+    nitrocli_core::device::Model::of(connection.device()),
+    connection.device(),
+  )?;
+  let pws = get_password_safe(ctx, connection.device())?;
+  let registry = registry::Registry::load()?;
+  let aliased = if registry.contains(name) {
+    let serial = ctx
+      .retry
+      .run(|| nitrocli_core::device::serial_number(connection.device()))?;
+    registry.lookup(&serial, "pws", name)
+  } else {
+    None
+  };
+  let slot = match aliased {
+    Some(slot) => slot,
+    None => {
+      let names = ctx.retry.run(|| nitrocli_core::pws::slot_names(&pws))?;
+      nitrocli_core::pws::find_slot(&names, name)
+        .ok_or_else(|| Error::Error(format!("{} is not in the password safe", name)))?
+    }
+  };
+
+  let entry = ctx
+    .retry
+    .run(|| nitrocli_core::pws::read_entry(&pws, slot))?;
+  Ok((entry.login, Secret::from(entry.password)))
+}
+
+/// Print the password safe entry with the given login and password.
+///
+/// As with `pass show`, the password is printed on the first line,
+/// followed by the login, if any. If `clip` is set, only the password
+/// is copied to the clipboard instead.
+#[cfg(feature = "pws")]
+fn print_pws_entry(login: &str, password: &Secret, clip: bool) -> Result<()> {
+  if clip {
+    return clipboard::copy(password.as_str()?);
+  }
+
+  println!("{}", password.as_str()?);
+  if !login.is_empty() {
+    println!("login: {}", login);
+  }
+  Ok(())
+}
+
+/// Print the password safe entry with the given name like
+/// `print_pws_entry`.
+#[cfg(feature = "pws")]
+pub fn pass_show(ctx: &args::ExecCtx, name: &str, clip: bool) -> Result<()> {
+  let connection = Connection::connect(ctx)?;
+  let (login, password) = get_pws_entry(ctx, &connection, name)?;
+  connection.release(ctx);
+  print_pws_entry(&login, &password, clip)
+}
+
+/// Type the login and the password of the password safe entry with the
+/// given name into the focused window, after waiting for the given
+/// time.
+#[cfg(feature = "pws")]
+pub fn pass_type(ctx: &args::ExecCtx, name: &str, delay: time::Duration) -> Result<()> {
+  let connection = Connection::connect(ctx)?;
+  let (login, password) = get_pws_entry(ctx, &connection, name)?;
+  connection.release(ctx);
+  thread::sleep(delay);
+  autotype::type_credentials(&ctx.config, &login, &password)
+}
+
+/// Let the user pick an entry of the password safe by fuzzy matching its
+/// name and print it like `pass_show` or, if `typed` is set, type it
+/// like `pass_type`.
+///
+/// The password safe is unlocked only once for listing the entries and
+/// reading the picked one.
+#[cfg(feature = "pws")]
+pub fn pass_pick(ctx: &args::ExecCtx, clip: bool, typed: bool) -> Result<()> {
+  let connection = Connection::connect(ctx)?;
+  lock_device(
+    ctx,
+    nitrocli_core::device::Model::of(connection.device()),
+    connection.device(),
+  )?;
+  let entry = {
+    let pws = get_password_safe(ctx, connection.device())?;
+    let names = ctx.retry.run(|| nitrocli_core::pws::slot_names(&pws))?;
+    let slots = names.keys().cloned().collect::<Vec<_>>();
+    let labels = names.values().cloned().collect::<Vec<_>>();
+    let slot = slots[picker::pick("> ", &labels)?];
+    ctx
+      .retry
+      .run(|| nitrocli_core::pws::read_entry(&pws, slot))?
+  };
+  connection.release(ctx);
+
+  let password = Secret::from(entry.password);
+  if typed {
+    autotype::type_credentials(&ctx.config, &entry.login, &password)
+  } else {
+    print_pws_entry(&entry.login, &password, clip)
+  }
+}
+
+/// Read the password for the password safe entry with the given name.
+///
+/// If the standard input is a terminal, the password is inquired twice
+/// without echo, the way `pass insert` does. Otherwise, the first line
+/// of the standard input is used.
+#[cfg(feature = "pws")]
+fn read_pass_password(name: &str) -> Result<Secret> {
+  if tty::is_stdin_tty() {
+    let password =
+      tty::read_passphrase(&format!("Enter password for {}: ", name), tty::Echo::None)?;
+    let confirm =
+      tty::read_passphrase(&format!("Retype password for {}: ", name), tty::Echo::None)?;
+    if password != confirm {
+      return Err(Error::Error(
+        "The entered passwords do not match".to_string(),
+      ));
+    }
+    Ok(password)
+  } else {
+    read_secret_line()
+  }
+}
+
+/// Read the first line of the standard input, without its line ending,
+/// as a secret.
+#[cfg(any(feature = "pws", feature = "storage"))]
+fn read_secret_line() -> Result<Secret> {
+  let mut line = String::new();
+  let _ = io::stdin().read_line(&mut line)?;
+  let mut secret = Secret::from(line);
+  if secret.last() == Some(b'\n') {
+    let _ = secret.pop();
+  }
+  if secret.last() == Some(b'\r') {
+    let _ = secret.pop();
+  }
+  Ok(secret)
+}
+
+/// Check that the given value fits into a password safe field with the
+/// given name and maximum length.
+#[cfg(feature = "pws")]
+fn check_pws_length(field: &str, value: &str, max: usize) -> Result<()> {
+  if value.len() > max {
+    let error = format!(
+      "The {} must not be longer than {} characters, but is {}",
+      field,
+      max,
+      value.len()
+    );
+    Err(Error::Error(error))
+  } else {
+    Ok(())
+  }
+}
+
+/// Insert an entry with the given name into the password safe.
+///
+/// The entry is written to the first free slot. An existing entry with
+/// the same name is only replaced if `force` is set, in which case its
+/// login is preserved.
+#[cfg(feature = "pws")]
+pub fn pass_insert(
+  ctx: &args::ExecCtx,
+  name: &str,
+  force: bool,
+  generate: Option<usize>,
+) -> Result<()> {
+  check_pws_length("name", name, PWS_NAME_LENGTH)?;
+
+  let device = get_device(ctx)?;
+  insert_pws_entry(ctx, &device, name, force, generate)?;
+  release_device(ctx, device);
+  Ok(())
+}
+
+/// Insert an entry with the given name into the password safe of the
+/// given device.
+#[cfg(feature = "pws")]
+fn insert_pws_entry(
+  ctx: &args::ExecCtx,
+  device: &nitrokey::DeviceWrapper,
+  name: &str,
+  force: bool,
+  generate: Option<usize>,
+) -> Result<()> {
+  let pws = get_password_safe(ctx, device)?;
+  let names = ctx.retry.run(|| nitrocli_core::pws::slot_names(&pws))?;
+  let (slot, login) = match nitrocli_core::pws::find_slot(&names, name) {
+    Some(slot) if force => {
+      let entry = ctx
+        .retry
+        .run(|| nitrocli_core::pws::read_entry(&pws, slot))?;
+      (slot, entry.login)
+    }
+    Some(_) => {
+      let error = format!(
+        "An entry already exists for {}, use --force to overwrite it",
+        name
+      );
+      return Err(Error::Error(error));
+    }
+    None => {
+      let slot = (0..nitrokey::SLOT_COUNT)
+        .find(|slot| !names.contains_key(slot))
+        .ok_or_else(|| Error::Error("The password safe is full".to_string()))?;
+      (slot, String::new())
+    }
+  };
+  check_pws_length("login", &login, PWS_LOGIN_LENGTH)?;
+  let change = format!(
+    "write the entry for {} to slot {} of the password safe",
+    name, slot
+  );
+  if dry_run(ctx, &change) {
+    return Ok(());
+  }
+
+  let password = match generate {
+    Some(words) => passphrase::generate(words)?,
+    None => read_pass_password(name)?,
+  };
+  let password = password.as_str()?;
+  check_pws_length("password", password, PWS_PASSWORD_LENGTH)?;
+  let guard = signal::defer();
+  ctx
+    .retry
+    .run(|| pws.write_slot(slot, name, &login, password))
+    .map_err(|err| get_error("Could not write the password safe slot", err))?;
+  drop(guard);
+  if generate.is_some() {
+    println!("{}", password);
+  }
+  signal::check().context(|| format!("Stopped after writing the entry for {}", name))
+}
+
+/// Print the udev rules granting access to the supported devices.
+pub fn setup_udev_print() -> Result<()> {
+  print!("{}", udev::rules());
+  Ok(())
+}
+
+/// Install the udev rules granting access to the supported devices.
+pub fn setup_udev_install(ctx: &args::ExecCtx) -> Result<()> {
+  let path = path::Path::new(udev::RULES_PATH);
+  if dry_run(
+    ctx,
+    &format!("install the udev rules to {}", path.display()),
+  ) {
+    return Ok(());
+  }
+  udev::install(path)?;
+  println!("Installed udev rules to {}", path.display());
+  Ok(())
+}
+
+/// Check whether the current user can access the connected devices.
+pub fn setup_udev_check() -> Result<()> {
+  udev::check()
+}
+
+/// Read the firmware password of a Nitrokey Storage.
+///
+/// If the standard input is a terminal, the password is inquired
+/// without echo. Otherwise, the first line of the standard input is
+/// used, so that scripts can supply it.
+#[cfg(feature = "storage")]
+fn read_firmware_password() -> Result<Secret> {
+  if tty::is_stdin_tty() {
+    tty::read_passphrase("Firmware password: ", tty::Echo::None)
+  } else {
+    read_secret_line()
+  }
+}
+
+/// Put the connected Nitrokey Storage into update mode.
+///
+/// In update mode, the device can only be accessed by flashing
+/// software, such as dfu-programmer, until its firmware is launched
+/// again using `update_leave`.
+#[cfg(feature = "storage")]
+pub fn update_enter(ctx: &args::ExecCtx) -> Result<()> {
+  let device = match get_device(ctx)? {
+    nitrokey::DeviceWrapper::Storage(device) => device,
+    nitrokey::DeviceWrapper::Pro(_) => {
+      let error = "The Nitrokey Pro has no update mode that can be entered by a command";
+      return Err(Error::Error(error.to_string()));
+    }
+  };
+  if dry_run(ctx, "put the device into update mode") {
+    return Ok(());
+  }
+
+  let password = read_firmware_password()?;
+  let password = password.as_str()?;
+  let guard = signal::defer();
+  ctx
+    .retry
+    .run(|| nitrocli_core::device::enable_firmware_update(&device, password))
+    .map_err(|err| match err {
+      nitrokey::CommandError::WrongPassword => Error::Error("Wrong firmware password".to_string()),
+      err => get_error("Could not enter update mode", err),
+    })?;
+  drop(guard);
+  // The device no longer responds to commands, so the connection is
+  // not kept for later operations.
+  drop(device);
+  info!("The device is in update mode");
+  signal::check().context(|| "Stopped after entering update mode")
+}
+
+/// Make a Nitrokey Storage in update mode leave it by launching its
+/// firmware.
+#[cfg(feature = "storage")]
+pub fn update_leave(ctx: &args::ExecCtx) -> Result<()> {
+  if dry_run(ctx, "launch the firmware of the device in update mode") {
+    return Ok(());
+  }
+  update::launch()
+}
+
+/// The time in seconds to wait for a device to be connected again after
+/// launching its new firmware.
+#[cfg(feature = "storage")]
+const UPDATE_RECONNECT_TIMEOUT: u64 = 30;
+
+/// Wait for a device matching the given context to be connected, as it
+/// is once it left update mode, and return it.
+#[cfg(feature = "storage")]
+fn wait_for_device(ctx: &args::ExecCtx) -> Result<nitrokey::DeviceWrapper> {
+  let selector = get_selector(ctx);
+  let start = time::Instant::now();
+  loop {
+    match nitrocli_core::device::connect(&selector) {
+      Ok(device) => return Ok(device),
+      Err(err) => {
+        if start.elapsed() >= time::Duration::from_secs(UPDATE_RECONNECT_TIMEOUT) {
+          let error = "The device did not come back after launching the new firmware";
+          return Err(Error::from(err).context(error));
+        }
+      }
+    }
+    signal::check()?;
+    thread::sleep(time::Duration::from_millis(200));
+  }
+}
+
+/// Flash the given image onto the Nitrokey Storage in update mode and
+/// check the firmware version it comes back with.
+///
+/// The firmware is erased, the image written, and the new firmware
+/// launched using dfu-programmer, whose progress is reported on the
+/// standard error output. Erasing the firmware has to be confirmed by
+/// the user first, unless `force` is set.
+#[cfg(feature = "storage")]
+pub fn update_flash(
+  ctx: &args::ExecCtx,
+  image: &path::Path,
+  version: Option<&str>,
+  force: bool,
+) -> Result<()> {
+  let _ = fs::metadata(image).context(|| format!("Could not read {}", image.display()))?;
+  if dry_run(ctx, &format!("flash {}", image.display())) {
+    return Ok(());
+  }
+  if !force {
+    let action = format!(
+      "erase the firmware of the device in update mode and flash {}",
+      image.display()
+    );
+    confirm(ctx, &action, None)?;
+  }
+
+  // An interrupted update leaves the device without a firmware, so the
+  // steps are always completed.
+  let guard = signal::defer();
+  println!("Erasing the firmware");
+  update::erase()?;
+  println!("Flashing {}", image.display());
+  update::write(image)?;
+  println!("Launching the new firmware");
+  update::launch()?;
+  drop(guard);
+
+  println!("Waiting for the device");
+  let device = wait_for_device(ctx)?;
+  let flashed = format!(
+    "{}.{}",
+    device.get_major_firmware_version(),
+    device.get_minor_firmware_version()
+  );
+  drop(device);
+  match version.map(|version| version.trim_start_matches(&['v', 'V'][..])) {
+    Some(version) if version != flashed => {
+      let error = format!(
+        "The device came back with firmware version {} instead of {}",
+        flashed, version
+      );
+      Err(Error::Error(error))
+    }
+    _ => {
+      println!("The device runs firmware version {}", flashed);
+      Ok(())
+    }
+  }
+}
+
+/// Export the firmware of the connected Nitrokey Storage to its
+/// unencrypted volume, authenticating using the admin PIN.
+#[cfg(feature = "storage")]
+pub fn update_export(ctx: &args::ExecCtx) -> Result<()> {
+  let device = get_storage_device(ctx)?;
+  if !dry_run(ctx, "export the firmware to the unencrypted volume") {
+    try_with_passphrase(
+      ctx,
+      &device,
+      pinentry::PinType::Admin,
+      "Could not export the firmware",
+      |admin_pin| {
+        let _guard = signal::defer();
+        nitrocli_core::device::export_firmware(&device, admin_pin)
+      },
+    )?;
+    signal::check().context(|| "Stopped after exporting the firmware")?;
+    println!("Exported the firmware to the unencrypted volume");
+  }
+  release_device(ctx, nitrokey::DeviceWrapper::Storage(device));
+  Ok(())
+}
+
+/// Check that the given firmware image, e.g., one exported by
+/// `update_export`, matches the checksum published for the firmware
+/// with the given version or, if none is given, for the firmware the
+/// connected device runs.
+///
+/// A mismatch, or a release without a checksum for the image, fails the
+/// check.
+#[cfg(feature = "storage")]
+pub fn update_verify(ctx: &args::ExecCtx, image: &path::Path, version: Option<&str>) -> Result<()> {
+  let (model, version) = match (get_selector(ctx).model, version) {
+    (Some(model), Some(version)) => (model, version.to_string()),
+    _ => {
+      let device = get_device(ctx)?;
+      let model = nitrocli_core::device::Model::of(&device);
+      let running = format!(
+        "{}.{}",
+        device.get_major_firmware_version(),
+        device.get_minor_firmware_version()
+      );
+      release_device(ctx, device);
+      (model, version.map(String::from).unwrap_or(running))
+    }
+  };
+  let version = version.trim_start_matches(&['v', 'V'][..]);
+  let (asset, checksums) = update::check_image(model, version, image).context(|| "Not verified")?;
+  println!(
+    "Verified: {} matches {} of firmware version {} as listed in {}",
+    image.display(),
+    asset,
+    version,
+    checksums
+  );
+  Ok(())
+}
+
+/// Download and verify the firmware image with the given version or,
+/// if none is given, the latest one for the model of the device.
+///
+/// The model is taken from the options or the connected device, which
+/// is only connected to if it cannot be determined otherwise.
+#[cfg(feature = "storage")]
+pub fn update_download(ctx: &args::ExecCtx, version: Option<&str>) -> Result<()> {
+  let keys = update::signing_keys(&ctx.config)?;
+  let model = match get_selector(ctx).model {
+    Some(model) => model,
+    None => {
+      let device = get_device(ctx)?;
+      let model = nitrocli_core::device::Model::of(&device);
+      release_device(ctx, device);
+      model
+    }
+  };
+  let (version, path) = update::download(model, version, &keys)?;
+  println!(
+    "Downloaded firmware version {} for the {} to {}",
+    version,
+    model,
+    path.display()
+  );
+  Ok(())
+}
+
+/// Print the decrypted contents of the given file.
+///
+/// The plaintext is only written to the standard output, so that it
+/// can be piped into another program without ever being stored.
+pub fn vault_decrypt(path: &path::Path) -> Result<()> {
+  let data = fs::read(path).context(|| format!("Could not read {}", path.display()))?;
+  if !vault::is_encrypted(&data) {
+    let error = format!("{} is not encrypted", path.display());
+    return Err(Error::Error(error));
+  }
+  let data = vault::decrypt(&data).context(|| format!("Could not decrypt {}", path.display()))?;
+  let stdout = io::stdout();
+  let mut stdout = stdout.lock();
+  stdout.write_all(data.as_bytes())?;
+  stdout.flush()?;
+  Ok(())
+}
+
+/// Encrypt the given file for the given recipients or, if there are
+/// none, with a passphrase.
+pub fn vault_encrypt(path: &path::Path, recipients: &[String], remove: bool) -> Result<()> {
+  let output = vault::encrypt(path, recipients, remove)?;
+  println!("Encrypted {} to {}", path.display(), output.display());
+  Ok(())
+}
+
+/// Ask the user to confirm the given destructive action on the given
+/// device.
+///
+/// The user confirms by typing either the serial number of the device
+/// or "yes". In no-prompt mode, or if there is no terminal to ask on,
+/// the action is refused.
+fn confirm_action<D>(ctx: &args::ExecCtx, device: &D, action: &str) -> Result<()>
+where
+  D: Device,
+{
+  if ctx.no_prompt {
+    return confirm(ctx, action, None);
+  }
+  let serial = ctx
+    .retry
+    .run(|| nitrocli_core::device::serial_number(device))?;
+  confirm(ctx, action, Some(&serial))
+}
+
+/// Ask the user to confirm the given destructive action, on the device
+/// with the given serial number, if known.
+///
+/// The user confirms by typing "yes" or the serial number. In no-prompt
+/// mode, or if there is no terminal to ask on, the action is refused.
+fn confirm(ctx: &args::ExecCtx, action: &str, serial: Option<&str>) -> Result<()> {
+  let refuse = |reason: &str| {
+    let error = format!(
+      "Refusing to {} without confirmation ({}), use --force to skip it",
+      action, reason
+    );
+    Err(Error::Error(error))
+  };
+  if ctx.no_prompt {
+    return refuse("--no-prompt is set");
+  }
+
+  let prompt = match serial {
+    Some(serial) => format!(
+      "This will {} of the device {}.\nType its serial number or \"yes\" to continue: ",
+      action, serial
+    ),
+    None => format!("This will {}.\nType \"yes\" to continue: ", action),
+  };
+  let answer = match tty::read_line(&prompt) {
+    Ok(answer) => answer,
+    Err(Error::IoError(_)) => return refuse("no terminal available"),
+    Err(err) => return Err(err),
+  };
+  let answer = answer.trim();
+  if answer == "yes" || Some(answer) == serial {
+    Ok(())
+  } else {
+    Err(Error::Error("Aborted".to_string()))
+  }
+}
+
+/// Change the PIN of the given type.
+///
+/// The new PIN is inquired twice and nothing is sent to the device
+/// unless both entries match. Changing the admin PIN has to be
+/// confirmed by the user first, unless `force` is set.
+pub fn pin_set(ctx: &args::ExecCtx, pin_type: pinentry::PinType, force: bool) -> Result<()> {
+  let device = get_device(ctx)?;
+  if dry_run(ctx, &format!("change the {}", pin_type)) {
+    release_device(ctx, device);
+    return Ok(());
+  }
+  if pin_type == pinentry::PinType::Admin && !force {
+    confirm_action(ctx, &device, "change the admin PIN")?;
+  }
+  let new_pin = pinentry::choose_passphrase(ctx, pin_type)?;
+  let new_pin = new_pin.as_str()?;
+
+  try_with_passphrase(
+    ctx,
+    &device,
+    pin_type,
+    "Changing the PIN failed",
+    |current_pin| {
+      let _guard = signal::defer();
+      match pin_type {
+        pinentry::PinType::Admin => device.change_admin_pin(current_pin, new_pin),
+        pinentry::PinType::User => device.change_user_pin(current_pin, new_pin),
+      }
+    },
+  )?;
+
+  release_device(ctx, device);
+
+  // We just changed the PIN but confirmed the action with the old PIN,
+  // which may have caused it to be cached. Since it no longer applies,
+  // make sure to evict the corresponding entry from the cache.
+  pinentry::clear_passphrase(ctx, pin_type)?;
+  signal::check().context(|| format!("Stopped after changing the {}", pin_type))
+}
+
+/// Build a new AES key on the given device, authenticating using the
+/// admin PIN.
+fn build_aes_key(ctx: &args::ExecCtx, device: &nitrokey::DeviceWrapper) -> Result<()> {
+  if dry_run(ctx, "build a new AES key") {
+    return Ok(());
+  }
+  try_with_passphrase(
+    ctx,
+    device,
+    pinentry::PinType::Admin,
+    "Could not build the AES key",
+    |admin_pin| {
+      let _guard = signal::defer();
+      nitrocli_core::device::build_aes_key(device, admin_pin)
+    },
+  )?;
+  signal::check().context(|| "Stopped after building the AES key")
+}
+
+/// Start filling the storage of the given Nitrokey Storage with random
+/// data, authenticating using the admin PIN.
+fn fill_sd_card(ctx: &args::ExecCtx, device: &nitrokey::Storage) -> Result<()> {
+  if dry_run(ctx, "fill the storage with random data") {
+    return Ok(());
+  }
+  try_with_passphrase(
+    ctx,
+    device,
+    pinentry::PinType::Admin,
+    "Could not fill the storage with random data",
+    |admin_pin| {
+      let _guard = signal::defer();
+      nitrocli_core::device::fill_sd_card(device, admin_pin)
+    },
+  )?;
+  signal::check().context(|| "Stopped after starting to fill the storage")
+}
+
+/// The interval in seconds in which the progress of filling the
+/// storage is polled.
+///
+/// Every poll is a command the device has to answer while it writes
+/// the storage, so it is polled rarely.
+const FILL_POLL_INTERVAL: u64 = 10;
+
+/// Estimate the time left until an operation completes that reached the
+/// given progress in percent after the given time, assuming that it
+/// keeps its throughput.
+fn estimate_remaining(elapsed: time::Duration, progress: u8) -> Option<time::Duration> {
+  if progress == 0 || progress > 100 {
+    return None;
+  }
+  let left = elapsed.as_secs() * u64::from(100 - progress) / u64::from(progress);
+  Some(time::Duration::from_secs(left))
+}
+
+/// Format the given duration in hours and minutes, rounded to full
+/// minutes.
+fn format_remaining(duration: time::Duration) -> String {
+  let minutes = (duration.as_secs() + 30) / 60;
+  if minutes < 60 {
+    format!("{} min", minutes)
+  } else {
+    format!("{} h {} min", minutes / 60, minutes % 60)
+  }
+}
+
+/// Wait for the given Nitrokey Storage to finish filling its storage
+/// with random data, printing the progress along with an estimate of
+/// the time left.
+///
+/// The estimate is based on the throughput measured since the fill was
+/// started. Interrupting the wait does not stop the fill.
+fn wait_for_fill(ctx: &args::ExecCtx, device: &nitrokey::Storage) -> Result<()> {
+  let start = time::Instant::now();
+  let mut last = None;
+  loop {
+    for _ in 0..FILL_POLL_INTERVAL {
+      thread::sleep(time::Duration::from_secs(1));
+      signal::check()
+        .context(|| "Stopped waiting for the storage to be filled, which the device continues")?;
+    }
+    match nitrocli_core::device::progress(device) {
+      Some(progress) if last != Some(progress) => {
+        match estimate_remaining(start.elapsed(), progress) {
+          Some(left) => println!(
+            "Filled {}% of the storage, about {} left",
+            progress,
+            format_remaining(left)
+          ),
+          None => println!("Filled {}% of the storage", progress),
+        }
+        last = Some(progress);
+      }
+      Some(_) => (),
+      None => break,
+    }
+  }
+  debug!("Filling the storage took {:?}", start.elapsed());
+  notify::notify(ctx, "Filled the storage with random data")
+}
+
+/// The factory default admin PIN.
+const DEFAULT_ADMIN_PIN: &str = "12345678";
+/// The factory default user PIN.
+const DEFAULT_USER_PIN: &str = "123456";
+
+/// Interactively take a device through its initial setup.
+///
+/// The user is asked whether to change the admin and the user PIN, to
+/// build a new AES key, to fill the storage of a Nitrokey Storage with
+/// random data, and whether generating one-time passwords requires the
+/// user PIN. Each step is performed right after it was confirmed and a
+/// summary of the performed steps is printed at the end.
+pub fn setup_device(ctx: &args::ExecCtx) -> Result<()> {
+  if ctx.no_prompt || !tty::is_stdin_tty() {
+    let error = "The device setup requires a terminal and may not be used with --no-prompt";
+    return Err(Error::Error(error.to_string()));
+  }
+
+  let device = get_device(ctx)?;
+  let model = nitrocli_core::device::Model::of(&device);
+  let serial = ctx
+    .retry
+    .run(|| nitrocli_core::device::serial_number(&device))?;
+  println!("Setting up the {} {}", model, serial);
+  release_device(ctx, device);
+  let mut summary = Vec::new();
+  let policy = policy::Policy::load(&ctx.config)?;
+
+  println!(
+    "\nThe admin PIN is required for changing the device. Its factory default is {}.",
+    DEFAULT_ADMIN_PIN
+  );
+  if ask_change_pin(&policy, DEFAULT_ADMIN_PIN, "Change the admin PIN?")? {
+    pin_set(ctx, pinentry::PinType::Admin, true)?;
+    summary.push("Changed the admin PIN");
+  }
+  println!(
+    "\nThe user PIN is required for accessing the data on the device. Its factory default is {}.",
+    DEFAULT_USER_PIN
+  );
+  if ask_change_pin(&policy, DEFAULT_USER_PIN, "Change the user PIN?")? {
+    pin_set(ctx, pinentry::PinType::User, true)?;
+    summary.push("Changed the user PIN");
+  }
+
+  println!(
+    "\nThe AES key encrypts the password safe{}. A new key makes the data encrypted with the old one inaccessible.",
+    if model == nitrocli_core::device::Model::Storage {
+      " and the encrypted volumes"
+    } else {
+      ""
+    }
+  );
+  if ask_yes("Build a new AES key?")? {
+    let device = get_device(ctx)?;
+    build_aes_key(ctx, &device)?;
+    release_device(ctx, device);
+    summary.push("Built a new AES key");
+  }
+
+  if model == nitrocli_core::device::Model::Storage {
+    println!(
+      "\nFilling the storage with random data hides which parts of the encrypted volumes are in use. It takes about an hour, during which the device cannot be used and the setup waits for it."
+    );
+    if ask_yes("Fill the storage with random data?")? {
+      let device = get_device(ctx)?;
+      if let nitrokey::DeviceWrapper::Storage(ref storage) = device {
+        fill_sd_card(ctx, storage)?;
+        if !ctx.dry_run {
+          wait_for_fill(ctx, storage)?;
+        }
+      }
+      release_device(ctx, device);
+      summary.push("Filled the storage with random data");
+    }
+  }
+
+  println!("\nOne-time passwords can be generated without entering the user PIN.");
+  let user_password = ask_yes("Require the user PIN for generating one-time passwords?")?;
+  let device = get_device(ctx)?;
+  let config = ctx
+    .retry
+    .run(|| device.get_config())
+    .map_err(|err| get_error("Could not read the configuration", err))?;
+  release_device(ctx, device);
+  if config.user_password != user_password {
+    let config = nitrokey::Config {
+      user_password,
+      ..config
+    };
+    apply_change(ctx, Change::WriteConfig(config))?;
+    summary.push(if user_password {
+      "Required the user PIN for generating one-time passwords"
+    } else {
+      "Allowed generating one-time passwords without the user PIN"
+    });
+  }
+
+  if !ctx.dry_run {
+    println!("\nSummary:");
+    if summary.is_empty() {
+      println!("  Nothing was changed");
+    }
+    for step in &summary {
+      println!("  {}", step);
+    }
+  }
+  Ok(())
+}
+
+/// Apply the given change, which requires admin authentication, to the
+/// device matching the given context.
+///
+/// The admin authentication is kept, so that further changes made by
+/// the invocation neither ask for the admin PIN nor authenticate again.
+fn apply_change(ctx: &args::ExecCtx, change: Change) -> Result<()> {
+  if dry_run(ctx, &change.describe()) {
+    return Ok(());
+  }
+
+  let reused = ctx.admin.borrow().is_some();
+  let admin = get_admin(ctx)?;
+  let result = {
+    let _guard = signal::defer();
+    change.apply_admin(ctx, &admin)
+  };
+  let admin = match result {
+    // Another process may have authenticated in the meantime, which
+    // invalidates our temporary password.
+    Err(Error::Command(_, nitrokey::CommandError::NotAuthorized)) if reused => {
+      debug!("The admin authentication is no longer valid");
+      let admin = authenticate_admin(ctx, admin.device())?;
+      let _guard = signal::defer();
+      change.apply_admin(ctx, &admin)?;
+      admin
+    }
+    result => {
+      result?;
+      admin
+    }
+  };
+  release_admin(ctx, admin);
+  signal::check().context(|| format!("Stopped after changing the device to {}", change.describe()))
+}
+
+/// A change to the device required by a module task.
+enum Change {
+  #[cfg(feature = "otp")]
+  EraseOtpSlot(args::OtpAlgorithm, u8),
+  #[cfg(feature = "otp")]
+  WriteOtpSlot(module::OtpSlotTask),
+  WriteConfig(nitrokey::Config),
+  #[cfg(feature = "storage")]
+  OpenVolume,
+  #[cfg(feature = "storage")]
+  CloseVolume,
+}
+
+impl Change {
+  /// Describe the change.
+  fn describe(&self) -> String {
+    match self {
+      #[cfg(feature = "otp")]
+      Change::EraseOtpSlot(algorithm, slot) => format!("erase {} slot {}", algorithm, slot),
+      #[cfg(feature = "otp")]
+      Change::WriteOtpSlot(task) => format!("write {} slot {}", task.algorithm, task.slot),
+      Change::WriteConfig(_) => "write configuration".to_string(),
+      #[cfg(feature = "storage")]
+      Change::OpenVolume => "open encrypted volume".to_string(),
+      #[cfg(feature = "storage")]
+      Change::CloseVolume => "close encrypted volume".to_string(),
+    }
+  }
+
+  /// Apply the change, which requires admin authentication, to the
+  /// given device.
+  fn apply_admin<D>(&self, ctx: &args::ExecCtx, admin: &nitrokey::Admin<D>) -> Result<()>
+  where
+    D: Device,
+  {
+    ctx
+      .retry
+      .run(|| self.write_admin(admin))
+      .map_err(|err| get_error(&format!("Could not {}", self.describe()), err))
+  }
+
+  /// Write the change, which requires admin authentication, to the
+  /// given device.
+  fn write_admin<D>(&self, admin: &nitrokey::Admin<D>) -> result::Result<(), nitrokey::CommandError>
+  where
+    D: Device,
+  {
+    match self {
+      #[cfg(feature = "otp")]
+      Change::EraseOtpSlot(args::OtpAlgorithm::Hotp, slot) => admin.erase_hotp_slot(*slot),
+      #[cfg(feature = "otp")]
+      Change::EraseOtpSlot(args::OtpAlgorithm::Totp, slot) => admin.erase_totp_slot(*slot),
+      #[cfg(feature = "otp")]
+      Change::WriteOtpSlot(task) => match &task.state {
+        module::OtpSlotState::Present {
+          name,
+          secret,
+          digits,
+          time_window,
+          counter,
+        } => {
+          let mode = if *digits == 8 {
+            nitrokey::OtpMode::EightDigits
+          } else {
+            nitrokey::OtpMode::SixDigits
+          };
+          let secret = secret
+            .as_str()
+            .map_err(|_| nitrokey::CommandError::InvalidString)?;
+          let data = nitrokey::OtpSlotData::new(task.slot, name.as_str(), secret, mode);
+          match task.algorithm {
+            args::OtpAlgorithm::Hotp => admin.write_hotp_slot(data, *counter),
+            args::OtpAlgorithm::Totp => admin.write_totp_slot(data, *time_window),
+          }
+        }
+        module::OtpSlotState::Absent => Ok(()),
+      },
+      Change::WriteConfig(config) => admin.write_config(*config),
+      #[cfg(feature = "storage")]
+      Change::OpenVolume | Change::CloseVolume => Ok(()),
+    }
+  }
+}
+
+/// Determine the changes to the OTP slots of the given device required
+/// by the given task.
+///
+/// As secrets cannot be read back from the device, a slot is considered
+/// to be in the desired state if it carries the desired name.
+#[cfg(feature = "otp")]
+fn otp_slot_changes<D>(device: &D, task: &module::Task) -> Result<Vec<Change>>
+where
+  D: GenerateOtp,
+{
+  let mut changes = Vec::new();
+  for slot in &task.otp_slots {
+    let current =
+      nitrocli_core::otp::slot_name(device, get_otp_algorithm(slot.algorithm), slot.slot)?;
+    match (&slot.state, current) {
+      (module::OtpSlotState::Present { name, .. }, Some(ref current)) if current == name => (),
+      (module::OtpSlotState::Present { .. }, _) => changes.push(Change::WriteOtpSlot(slot.clone())),
+      (module::OtpSlotState::Absent, Some(_)) => {
+        changes.push(Change::EraseOtpSlot(slot.algorithm, slot.slot))
+      }
+      (module::OtpSlotState::Absent, None) => (),
+    }
+  }
+  Ok(changes)
+}
+
+/// Determine the configuration of the given device required by the
+/// given task, if it differs from the current one.
+fn config_change<D>(device: &D, task: &module::ConfigTask) -> Result<Option<nitrokey::Config>>
+where
+  D: Device,
+{
+  let current = device
+    .get_config()
+    .map_err(|err| get_error("Could not get the device configuration", err))?;
+  let desired = nitrokey::Config {
+    numlock: task.numlock.unwrap_or(current.numlock),
+    capslock: task.capslock.unwrap_or(current.capslock),
+    scrollock: task.scrollock.unwrap_or(current.scrollock),
+    user_password: task.user_password.unwrap_or(current.user_password),
+  };
+  Ok(if desired != current {
+    Some(desired)
+  } else {
+    None
+  })
+}
+
+/// Describe the change to a password safe slot required by a task.
+#[cfg(feature = "pws")]
+fn describe_pws_change(task: &module::PwsSlotTask) -> String {
+  match task.state {
+    module::PwsSlotState::Present { .. } => format!("write password safe slot {}", task.slot),
+    module::PwsSlotState::Absent => format!("erase password safe slot {}", task.slot),
+  }
+}
+
+/// Access the password safe of the given device using the user PIN of
+/// the given task or, if it has none, the one queried from the user.
+#[cfg(feature = "pws")]
+fn get_task_password_safe<'d>(
+  ctx: &args::ExecCtx,
+  device: &'d nitrokey::DeviceWrapper,
+  task: &module::Task,
+) -> Result<nitrokey::PasswordSafe<'d>> {
+  match task.user_pin {
+    Some(ref pin) => device
+      .get_password_safe(pin.as_str()?)
+      .map_err(|err| get_error("Could not access the password safe", err)),
+    None => get_password_safe(ctx, device),
+  }
+}
+
+/// Check whether the encrypted volume of the given device is open.
+#[cfg(feature = "storage")]
+fn is_volume_active(ctx: &args::ExecCtx, device: &nitrokey::DeviceWrapper) -> Result<bool> {
+  match device {
+    nitrokey::DeviceWrapper::Storage(storage) => {
+      let status = ctx
+        .retry
+        .run(|| nitrocli_core::device::storage_status(storage))?;
+      Ok(status.encrypted_volume.active)
+    }
+    nitrokey::DeviceWrapper::Pro(_) => {
+      let error = "The encrypted volume is only available on the Nitrokey Storage";
+      Err(Error::Error(error.to_string()))
+    }
+  }
+}
+
+/// Bring the password safe slots of the given device into the state
+/// described by the given task, authenticating as user only once.
+///
+/// The descriptions of the required changes are returned. Other is the
+/// number of changes made to the device afterwards, which is only used
+/// for reporting interruptions.
+#[cfg(feature = "pws")]
+fn apply_pws_slots(
+  ctx: &args::ExecCtx,
+  device: &nitrokey::DeviceWrapper,
+  task: &module::Task,
+  check_mode: bool,
+  other: usize,
+) -> Result<Vec<String>> {
+  let pws = get_task_password_safe(ctx, device, task)?;
+  let names = ctx.retry.run(|| nitrocli_core::pws::slot_names(&pws))?;
+
+  let mut changes = Vec::new();
+  for slot in &task.pws_slots {
+    match (&slot.state, names.get(&slot.slot)) {
+      (
+        module::PwsSlotState::Present {
+          name,
+          login,
+          password,
+        },
+        current,
+      ) => {
+        let password = password.as_str()?;
+        check_pws_length("name", name, PWS_NAME_LENGTH)?;
+        check_pws_length("login", login, PWS_LOGIN_LENGTH)?;
+        check_pws_length("password", password, PWS_PASSWORD_LENGTH)?;
+        let unchanged = match current {
+          Some(current) if current == name => {
+            let entry = ctx
+              .retry
+              .run(|| nitrocli_core::pws::read_entry(&pws, slot.slot))?;
+            entry.login == *login && entry.password == password
+          }
+          _ => false,
+        };
+        if !unchanged {
+          changes.push(slot);
+        }
+      }
+      (module::PwsSlotState::Absent, Some(_)) => changes.push(slot),
+      (module::PwsSlotState::Absent, None) => (),
+    }
+  }
+
+  let descriptions = changes
+    .iter()
+    .map(|slot| describe_pws_change(slot))
+    .collect();
+  if check_mode {
+    return Ok(descriptions);
+  }
+
+  let _guard = signal::defer();
+  for (applied, slot) in changes.iter().enumerate() {
+    signal::check().context(|| {
+      format!(
+        "Stopped after applying {} of {} changes",
+        applied,
+        changes.len() + other
+      )
+    })?;
+    let result = match &slot.state {
+      module::PwsSlotState::Present {
+        name,
+        login,
+        password,
+      } => {
+        let password = password.as_str()?;
+        ctx
+          .retry
+          .run(|| pws.write_slot(slot.slot, name, login, password))
+      }
+      module::PwsSlotState::Absent => ctx.retry.run(|| pws.erase_slot(slot.slot)),
+    };
+    result.map_err(|err| get_error(&format!("Could not {}", describe_pws_change(slot)), err))?;
+  }
+  Ok(descriptions)
+}
+
+/// Bring the device into the state described by the given module task.
+///
+/// The descriptions of the required changes are returned. In check
+/// mode, as well as in dry-run mode, the changes are determined but not
+/// applied.
+///
+/// Password safe slots are changed first, under a single user
+/// authentication, followed by the changes requiring a single admin
+/// authentication and, finally, the state of the encrypted volume.
+pub fn apply_task(ctx: &args::ExecCtx, task: &module::Task) -> Result<Vec<String>> {
+  let device = get_device(ctx)?;
+  let check_mode = task.check_mode || ctx.dry_run;
+
+  #[cfg(feature = "otp")]
+  let mut changes = otp_slot_changes(&device, task)?;
+  #[cfg(not(feature = "otp"))]
+  let mut changes = Vec::new();
+  if let Some(ref config) = task.config {
+    if let Some(config) = config_change(&device, config)? {
+      changes.push(Change::WriteConfig(config));
+    }
+  }
+  let admin_changes = changes.len();
+
+  #[cfg(feature = "storage")]
+  {
+    if let Some(open) = task.encrypted_volume {
+      match (is_volume_active(ctx, &device)?, open) {
+        (false, true) => changes.push(Change::OpenVolume),
+        (true, false) => changes.push(Change::CloseVolume),
+        _ => (),
+      }
+    }
+  }
+
+  #[cfg(feature = "pws")]
+  let mut descriptions = if task.pws_slots.is_empty() {
+    Vec::new()
+  } else {
+    apply_pws_slots(ctx, &device, task, check_mode, changes.len())?
+  };
+  #[cfg(not(feature = "pws"))]
+  let mut descriptions = Vec::new();
+  let done = descriptions.len();
+  descriptions.extend(changes.iter().map(Change::describe));
+  if check_mode {
+    release_device(ctx, device);
+    return Ok(descriptions);
+  }
+
+  // An interruption stops the application of the changes once the
+  // current one is complete.
+  let _guard = signal::defer();
+  let check_signal = |applied: usize| {
+    signal::check().context(|| {
+      format!(
+        "Stopped after applying {} of {} changes",
+        done + applied,
+        descriptions.len()
+      )
+    })
+  };
+
+  let device = if admin_changes > 0 {
+    let admin = match task.admin_pin {
+      Some(ref pin) => {
+        let pin = pin.as_str()?;
+        device
+          .authenticate_admin(pin)
+          .map_err(|(_, err)| get_error("Could not authenticate as admin", err))?
+      }
+      None => authenticate_admin(ctx, device)?,
+    };
+    for (applied, change) in changes[..admin_changes].iter().enumerate() {
+      check_signal(applied)?;
+      change.apply_admin(ctx, &admin)?;
+    }
+    admin.device()
+  } else {
+    device
+  };
+
+  #[cfg(feature = "storage")]
+  {
+    if let nitrokey::DeviceWrapper::Storage(ref storage) = device {
+      for (applied, change) in changes.iter().enumerate().skip(admin_changes) {
+        check_signal(applied)?;
+        match change {
+          Change::OpenVolume => {
+            let pin = match task.user_pin {
+              Some(ref pin) => Some(pin.as_str()?),
+              None => None,
+            };
+            open_volume(ctx, storage, pin)?
+          }
+          Change::CloseVolume => close_volume(ctx, storage)?,
+          _ => (),
+        }
+      }
+    }
+  }
+  check_signal(changes.len())?;
+  release_device(ctx, device);
+  Ok(descriptions)
+}
+
+/// Describe the difference between the current and the desired content
+/// of a slot, given by their names, if any.
+#[cfg(any(feature = "otp", feature = "pws"))]
+fn slot_drift(slot: &str, current: Option<&str>, desired: Option<&str>) -> Option<String> {
+  match (current, desired) {
+    (None, Some(desired)) => Some(format!("{}: missing, expected {}", slot, desired)),
+    (Some(current), Some(desired)) if current != desired => {
+      Some(format!("{}: named {}, expected {}", slot, current, desired))
+    }
+    (Some(current), None) => Some(format!("{}: unexpectedly populated with {}", slot, current)),
+    _ => None,
+  }
+}
+
+/// Describe the HOTP slot a key of the keyboard is bound to.
+fn describe_binding(slot: Option<u8>) -> String {
+  match slot {
+    Some(slot) => format!("HOTP slot {}", slot),
+    None => "disabled".to_string(),
+  }
+}
+
+/// Describe whether a setting is enabled.
+fn describe_enabled(enabled: bool) -> &'static str {
+  if enabled {
+    "enabled"
+  } else {
+    "disabled"
+  }
+}
+
+/// Determine how the given device differs from the state described by
+/// the given task, ignoring secrets, which cannot be read back.
+fn task_drift(
+  ctx: &args::ExecCtx,
+  device: &nitrokey::DeviceWrapper,
+  task: &module::Task,
+) -> Result<Vec<String>> {
+  let mut drift = Vec::new();
+
+  if let Some(ref config) = task.config {
+    let current = ctx
+      .retry
+      .run(|| device.get_config())
+      .map_err(|err| get_error("Could not get the device configuration", err))?;
+    let bindings = [
+      ("numlock", current.numlock, config.numlock),
+      ("capslock", current.capslock, config.capslock),
+      ("scrollock", current.scrollock, config.scrollock),
+    ];
+    for &(key, current, desired) in &bindings {
+      match desired {
+        Some(desired) if desired != current => drift.push(format!(
+          "config.{}: {}, expected {}",
+          key,
+          describe_binding(current),
+          describe_binding(desired)
+        )),
+        _ => (),
+      }
+    }
+    match config.user_password {
+      Some(desired) if desired != current.user_password => drift.push(format!(
+        "config.user-password: {}, expected {}",
+        describe_enabled(current.user_password),
+        describe_enabled(desired)
+      )),
+      _ => (),
+    }
+  }
+
+  #[cfg(feature = "otp")]
+  {
+    for slot in &task.otp_slots {
+      let current =
+        nitrocli_core::otp::slot_name(device, get_otp_algorithm(slot.algorithm), slot.slot)?;
+      let desired = match slot.state {
+        module::OtpSlotState::Present { ref name, .. } => Some(name.as_str()),
+        module::OtpSlotState::Absent => None,
+      };
+      let name = format!("{} slot {}", slot.algorithm, slot.slot);
+      drift.extend(slot_drift(
+        &name,
+        current.as_ref().map(AsRef::as_ref),
+        desired,
+      ));
+    }
+  }
+
+  #[cfg(feature = "pws")]
+  {
+    if !task.pws_slots.is_empty() {
+      let pws = get_task_password_safe(ctx, device, task)?;
+      let names = ctx.retry.run(|| nitrocli_core::pws::slot_names(&pws))?;
+      for slot in &task.pws_slots {
+        let current = names.get(&slot.slot).map(String::as_str);
+        let name = format!("password safe slot {}", slot.slot);
+        match slot.state {
+          module::PwsSlotState::Present {
+            name: ref desired,
+            ref login,
+            ..
+          } => match slot_drift(&name, current, Some(desired)) {
+            Some(line) => drift.push(line),
+            None => {
+              let entry = ctx
+                .retry
+                .run(|| nitrocli_core::pws::read_entry(&pws, slot.slot))?;
+              if entry.login != *login {
+                drift.push(format!(
+                  "{}: login {}, expected {}",
+                  name, entry.login, login
+                ));
+              }
+            }
+          },
+          module::PwsSlotState::Absent => drift.extend(slot_drift(&name, current, None)),
+        }
+      }
+    }
+  }
+
+  #[cfg(feature = "storage")]
+  {
+    if let Some(open) = task.encrypted_volume {
+      let active = is_volume_active(ctx, device)?;
+      if active != open {
+        let describe = |open| if open { "open" } else { "closed" };
+        drift.push(format!(
+          "encrypted volume: {}, expected {}",
+          describe(active),
+          describe(open)
+        ));
+      }
+    }
+  }
+  Ok(drift)
+}
+
+/// Check whether the device is in the state described by the
+/// provisioning profile at the given path, reporting every difference.
+///
+/// Secrets, i.e., OTP secrets and passwords, cannot be read back from
+/// the device and are not compared.
+pub fn provision_verify(ctx: &args::ExecCtx, path: &str) -> Result<()> {
+  let task = provision::read(path)?;
+  let device = get_device(ctx)?;
+  let drift = task_drift(ctx, &device, &task)?;
+  release_device(ctx, device);
+
+  if drift.is_empty() {
+    println!("The device matches the profile");
+    Ok(())
+  } else {
+    for line in &drift {
+      println!("{}", line);
+    }
+    let error = format!("The device does not match the profile {}", path);
+    Err(Error::Error(error))
+  }
+}
+
+/// Bring the device, or with `all_devices` every connected Nitrokey
+/// Storage, into the state described by the provisioning profile at
+/// the given path.
+pub fn provision_apply(ctx: &args::ExecCtx, path: &str, all_devices: bool) -> Result<()> {
+  let task = provision::read(path)?;
+  if all_devices {
+    for_all_devices(ctx, |ctx| apply_profile(ctx, &task))
+  } else {
+    apply_profile(ctx, &task)
+  }
+}
+
+/// Bring the device into the state described by the given profile and
+/// print the changes.
+fn apply_profile(ctx: &args::ExecCtx, task: &module::Task) -> Result<()> {
+  let changes = apply_task(ctx, task)?;
+  if changes.is_empty() {
+    println!("The device is in the desired state");
+  } else if !ctx.dry_run {
+    for change in changes {
+      println!("Applied: {}", change);
+    }
+  } else {
+    for change in changes {
+      println!("Would {}", change);
+    }
+  }
+  Ok(())
+}
+
+#[cfg(all(test, feature = "pws"))]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn match_askpass_prompts() {
+    let names = [
+      (0, "github.com"),
+      (1, "gist.github.com"),
+      (2, "id_ed25519"),
+      (3, ""),
+    ]
+    .iter()
+    .map(|&(slot, name)| (slot, name.to_string()))
+    .collect();
+
+    let prompt = "Password for 'https://user@GitHub.com': ";
+    assert_eq!(match_askpass_slot(prompt, &names), Some(0));
+    let prompt = "Username for 'https://gist.github.com': ";
+    assert_eq!(match_askpass_slot(prompt, &names), Some(1));
+    let prompt = "Enter passphrase for key '/home/user/.ssh/id_ed25519': ";
+    assert_eq!(match_askpass_slot(prompt, &names), Some(2));
+    let prompt = "Password for 'https://gitlab.com': ";
+    assert_eq!(match_askpass_slot(prompt, &names), None);
+  }
+
+  #[test]
+  fn format_bench_latencies() {
+    let latencies = [
+      time::Duration::from_micros(1500),
+      time::Duration::from_millis(2),
+      time::Duration::from_micros(3100),
+    ];
+    assert_eq!(
+      format_latencies("connect", &latencies),
+      "connect          1.5       2.2       3.1"
+    );
+  }
+
+  #[test]
+  fn estimate_fill_time() {
+    let elapsed = time::Duration::from_secs(600);
+    assert_eq!(
+      estimate_remaining(elapsed, 25),
+      Some(time::Duration::from_secs(1800))
+    );
+    assert_eq!(
+      estimate_remaining(elapsed, 100),
+      Some(time::Duration::from_secs(0))
+    );
+    assert_eq!(estimate_remaining(elapsed, 0), None);
+    assert_eq!(format_remaining(time::Duration::from_secs(1800)), "30 min");
+    assert_eq!(format_remaining(time::Duration::from_secs(89)), "1 min");
+    assert_eq!(
+      format_remaining(time::Duration::from_secs(4500)),
+      "1 h 15 min"
+    );
+  }
+
+  #[test]
+  fn describe_slot_drift() {
+    let slot = "password safe slot 1";
+    assert_eq!(slot_drift(slot, Some("mail"), Some("mail")), None);
+    assert_eq!(slot_drift(slot, None, None), None);
+    assert_eq!(
+      slot_drift(slot, None, Some("mail")).unwrap(),
+      "password safe slot 1: missing, expected mail"
+    );
+    assert_eq!(
+      slot_drift(slot, Some("wiki"), Some("mail")).unwrap(),
+      "password safe slot 1: named wiki, expected mail"
+    );
+    assert_eq!(
+      slot_drift(slot, Some("wiki"), None).unwrap(),
+      "password safe slot 1: unexpectedly populated with wiki"
+    );
+  }
+
+  #[test]
+  fn pass_tree() {
+    assert_eq!(format_pass_tree(Vec::new()), "Password Store\n");
+    let tree = "Password Store\n├── email\n└── github\n";
+    assert_eq!(format_pass_tree(vec!["github", "email"]), tree);
+  }
+
+  #[test]
+  #[cfg(feature = "otp")]
+  fn otp_input() {
+    assert!(is_otp("755224"));
+    assert!(is_otp("84755224"));
+    assert!(!is_otp("75522"));
+    assert!(!is_otp("7552241"));
+    assert!(!is_otp("75522a"));
+    assert!(!is_otp(""));
+  }
+
+  #[test]
+  #[cfg(feature = "otp")]
+  fn otp_table() {
+    assert_eq!(format_otp_table(&[]), "");
+    let otps = [
+      ("1".to_string(), "755224".to_string()),
+      ("github".to_string(), "287082".to_string()),
+    ];
+    assert_eq!(format_otp_table(&otps), "1       755224\ngithub  287082\n");
+  }
+
+  #[test]
+  #[cfg(feature = "otp")]
+  fn totp_remaining_validity() {
+    assert_eq!(totp_validity(0), 30);
+    assert_eq!(totp_validity(59), 1);
+    assert_eq!(totp_validity(1_111_111_109), 1);
+    assert_eq!(totp_validity(1_234_567_890), 30);
+  }
+}
@@ -0,0 +1,684 @@
+// completions.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::fmt::Write;
+use std::result;
+
+use crate::args;
+use crate::error::Error;
+use crate::nicknames;
+#[cfg(feature = "otp")]
+use crate::registry;
+#[cfg(feature = "otp")]
+use crate::slots;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The values an argument is completed with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Values {
+  /// Nothing is offered.
+  None,
+  /// The given fixed words.
+  Words(&'static [&'static str]),
+  /// Paths of files.
+  Files,
+  /// The names of OTP slots, as cached by the program.
+  #[cfg_attr(not(feature = "otp"), allow(dead_code))]
+  OtpSlots,
+  /// The serial numbers of devices, as cached by the program.
+  SerialNumbers,
+}
+
+impl Values {
+  /// Retrieve the name under which the values are listed by
+  /// `completions --list`, if they are determined at completion time.
+  fn list_kind(self) -> Option<&'static str> {
+    match self {
+      Values::OtpSlots => Some("otp-slots"),
+      Values::SerialNumbers => Some("serial-numbers"),
+      _ => None,
+    }
+  }
+}
+
+/// The argument of an option as described for completion.
+#[derive(Clone, Copy, Debug)]
+pub struct Arg {
+  /// The name of the argument, as shown in the usage information.
+  pub name: &'static str,
+  /// The values of the argument.
+  pub values: Values,
+}
+
+/// An option as described for completion.
+#[derive(Debug)]
+pub struct Opt {
+  /// The long name of the option, without the leading dashes.
+  pub long: &'static str,
+  /// The short name of the option, if any.
+  pub short: Option<char>,
+  /// The argument of the option, if it accepts one.
+  pub arg: Option<Arg>,
+  /// A short description of the option.
+  pub help: &'static str,
+}
+
+/// A command, or subcommand, as described for completion.
+#[derive(Debug)]
+pub struct Command {
+  /// The name of the command.
+  pub name: &'static str,
+  /// A short description of the command.
+  pub help: &'static str,
+  /// The options of the command.
+  pub options: Vec<Opt>,
+  /// The subcommands of the command.
+  pub subcommands: Vec<Command>,
+  /// The positional arguments of the command as shown in the usage
+  /// information, e.g., `<slot>`, or an empty string.
+  pub usage: &'static str,
+  /// The values of the positional arguments of the command.
+  pub args: Values,
+}
+
+/// Collect the given command and all its subcommands, along with their
+/// paths, i.e., the names of the subcommands leading to them separated
+/// by spaces. The path of the given command is empty.
+pub fn walk<'c>(command: &'c Command, path: &str, commands: &mut Vec<(String, &'c Command)>) {
+  commands.push((path.to_string(), command));
+  for subcommand in &command.subcommands {
+    let path = if path.is_empty() {
+      subcommand.name.to_string()
+    } else {
+      format!("{} {}", path, subcommand.name)
+    };
+    walk(subcommand, &path, commands);
+  }
+}
+
+/// Quote the given string for a POSIX shell.
+fn quote(s: &str) -> String {
+  format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Quote the given string for fish.
+fn quote_fish(s: &str) -> String {
+  format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Retrieve the command line forms of the given option.
+fn option_names(opt: &Opt) -> Vec<String> {
+  let mut names = vec![format!("--{}", opt.long)];
+  if let Some(short) = opt.short {
+    names.push(format!("-{}", short));
+  }
+  names
+}
+
+/// Describe the given values for the completion functions of bash and
+/// zsh, which interpret the description.
+fn describe_values(values: Values) -> Option<String> {
+  match values {
+    Values::None => None,
+    Values::Words(words) => Some(format!("words:{}", words.join(" "))),
+    Values::Files => Some("files".to_string()),
+    values => values.list_kind().map(String::from),
+  }
+}
+
+/// Generate the shell functions shared by the bash and zsh scripts.
+///
+/// The functions answer questions about the command line structure for
+/// the commands with the given paths, which are passed to them as the
+/// first argument.
+fn sh_functions(commands: &[(String, &Command)]) -> String {
+  let mut takes_arg = Vec::new();
+  let mut options = String::new();
+  let mut subcommands = String::new();
+  let mut values = String::new();
+  for (path, command) in commands {
+    for opt in &command.options {
+      let names = option_names(opt);
+      if let Some(arg) = opt.arg {
+        let patterns = names
+          .iter()
+          .map(|name| quote(&format!("{}:{}", path, name)))
+          .collect::<Vec<_>>()
+          .join("|");
+        takes_arg.push(patterns.clone());
+        if let Some(description) = describe_values(arg.values) {
+          let _ = writeln!(values, "    {}) echo {} ;;", patterns, quote(&description));
+        }
+      }
+    }
+    let names = command
+      .options
+      .iter()
+      .flat_map(option_names)
+      .collect::<Vec<_>>();
+    if !names.is_empty() {
+      let _ = writeln!(
+        options,
+        "    {}) echo {} ;;",
+        quote(path),
+        quote(&names.join(" "))
+      );
+    }
+    if !command.subcommands.is_empty() {
+      let names = command
+        .subcommands
+        .iter()
+        .map(|subcommand| subcommand.name)
+        .collect::<Vec<_>>();
+      let _ = writeln!(
+        subcommands,
+        "    {}) echo {} ;;",
+        quote(path),
+        quote(&names.join(" "))
+      );
+    }
+    if let Some(description) = describe_values(command.args) {
+      let pattern = quote(&format!("{}:", path));
+      let _ = writeln!(values, "    {}) echo {} ;;", pattern, quote(&description));
+    }
+  }
+
+  let paths = commands
+    .iter()
+    .skip(1)
+    .map(|(path, _)| quote(path))
+    .collect::<Vec<_>>()
+    .join("|");
+  let takes_arg = if takes_arg.is_empty() {
+    String::new()
+  } else {
+    format!("    {}) return 0 ;;\n", takes_arg.join("|"))
+  };
+
+  format!(
+    r#"# Check whether the option $2 of the command $1 takes an argument.
+_nitrocli_takes_arg() {{
+  case "$1:$2" in
+{takes_arg}  esac
+  return 1
+}}
+
+# Check whether $1 is the path of a command.
+_nitrocli_is_command() {{
+  case "$1" in
+    {paths}) return 0 ;;
+  esac
+  return 1
+}}
+
+# Print the options of the command $1.
+_nitrocli_options() {{
+  case "$1" in
+{options}  esac
+}}
+
+# Print the subcommands of the command $1.
+_nitrocli_subcommands() {{
+  case "$1" in
+{subcommands}  esac
+}}
+
+# Print the kind of values of the option $2 of the command $1 or, if $2
+# is empty, of its arguments.
+_nitrocli_values() {{
+  case "$1:$2" in
+{values}  esac
+}}
+"#,
+    takes_arg = takes_arg,
+    paths = paths,
+    options = options,
+    subcommands = subcommands,
+    values = values,
+  )
+}
+
+/// The loop determining the command being completed and, in `option`,
+/// the option whose argument is being completed, if any, shared by the
+/// bash and zsh scripts. `args` holds the words before the current one,
+/// without the program name.
+const SH_WALK: &str = r#"  local cmd="" option="" word
+  for word in "${args[@]}"; do
+    if [[ -n $option ]]; then
+      option=""
+      continue
+    fi
+    case "$word" in
+      --*=*) ;;
+      -*) _nitrocli_takes_arg "$cmd" "$word" && option="$word" ;;
+      *) _nitrocli_is_command "${cmd:+$cmd }$word" && cmd="${cmd:+$cmd }$word" ;;
+    esac
+  done
+"#;
+
+/// Generate the completion script for bash.
+fn bash(commands: &[(String, &Command)]) -> String {
+  format!(
+    r#"# bash completion for nitrocli, generated by `nitrocli completions bash`.
+
+{functions}
+_nitrocli() {{
+  local cur="${{COMP_WORDS[COMP_CWORD]}}" kind candidates
+  local -a args
+  args=("${{COMP_WORDS[@]:1:COMP_CWORD-1}}")
+{walk}
+  if [[ -n $option ]]; then
+    kind=$(_nitrocli_values "$cmd" "$option")
+  elif [[ $cur == -* ]]; then
+    COMPREPLY=($(compgen -W "$(_nitrocli_options "$cmd")" -- "$cur"))
+    return
+  else
+    kind=$(_nitrocli_values "$cmd" "")
+    candidates=$(_nitrocli_subcommands "$cmd")
+  fi
+
+  case "$kind" in
+    words:*) candidates="$candidates ${{kind#words:}}" ;;
+    files)
+      COMPREPLY=($(compgen -f -- "$cur"))
+      return
+      ;;
+    ?*)
+      # Values determined at completion time may contain spaces.
+      local IFS=$'\n' i
+      candidates=$("${{COMP_WORDS[0]}}" completions --list "$kind" 2>/dev/null)
+      COMPREPLY=($(compgen -W "$candidates" -- "$cur"))
+      for i in "${{!COMPREPLY[@]}}"; do
+        printf -v 'COMPREPLY[i]' '%q' "${{COMPREPLY[i]}}"
+      done
+      return
+      ;;
+  esac
+  COMPREPLY=($(compgen -W "$candidates" -- "$cur"))
+}}
+
+complete -F _nitrocli nitrocli
+"#,
+    functions = sh_functions(commands),
+    walk = SH_WALK,
+  )
+}
+
+/// Generate the completion script for zsh.
+fn zsh(commands: &[(String, &Command)]) -> String {
+  format!(
+    r#"#compdef nitrocli
+# zsh completion for nitrocli, generated by `nitrocli completions zsh`.
+
+{functions}
+_nitrocli() {{
+  local cur="${{words[CURRENT]}}" kind
+  local -a args candidates
+  args=("${{(@)words[2,CURRENT-1]}}")
+{walk}
+  if [[ -n $option ]]; then
+    kind=$(_nitrocli_values "$cmd" "$option")
+  elif [[ $cur == -* ]]; then
+    candidates=(${{=$(_nitrocli_options "$cmd")}})
+    compadd -a candidates
+    return
+  else
+    kind=$(_nitrocli_values "$cmd" "")
+    candidates=(${{=$(_nitrocli_subcommands "$cmd")}})
+  fi
+
+  case "$kind" in
+    words:*) candidates+=(${{=kind#words:}}) ;;
+    files)
+      _files
+      return
+      ;;
+    ?*) candidates+=(${{(f)"$("${{words[1]}}" completions --list "$kind" 2>/dev/null)"}}) ;;
+  esac
+  compadd -a candidates
+}}
+
+_nitrocli "$@"
+"#,
+    functions = sh_functions(commands),
+    walk = SH_WALK,
+  )
+}
+
+/// Generate the completion script for fish.
+fn fish(commands: &[(String, &Command)]) -> String {
+  let mut takes_arg = Vec::new();
+  let mut completions = String::new();
+  for (path, command) in commands {
+    let condition = quote_fish(&format!("__nitrocli_at {}", quote_fish(path)));
+    for subcommand in &command.subcommands {
+      let _ = writeln!(
+        completions,
+        "complete -c nitrocli -n {} -a {} -d {}",
+        condition,
+        subcommand.name,
+        quote_fish(subcommand.help)
+      );
+    }
+    for opt in &command.options {
+      let mut line = format!("complete -c nitrocli -n {} -l {}", condition, opt.long);
+      if let Some(short) = opt.short {
+        let _ = write!(line, " -s {}", short);
+      }
+      if let Some(arg) = opt.arg {
+        takes_arg.extend(
+          option_names(opt)
+            .iter()
+            .map(|name| quote_fish(&format!("{}:{}", path, name))),
+        );
+        line.push_str(" -r");
+        line.push_str(&fish_values(arg.values));
+      }
+      let _ = writeln!(completions, "{} -d {}", line, quote_fish(opt.help));
+    }
+    if command.args != Values::None {
+      let _ = writeln!(
+        completions,
+        "complete -c nitrocli -n {}{}",
+        condition,
+        fish_values(command.args)
+      );
+    }
+  }
+
+  let paths = commands
+    .iter()
+    .skip(1)
+    .map(|(path, _)| quote_fish(path))
+    .collect::<Vec<_>>()
+    .join(" ");
+
+  format!(
+    r#"# fish completion for nitrocli, generated by `nitrocli completions fish`.
+
+# Check whether the option $argv[2] of the command $argv[1] takes an
+# argument.
+function __nitrocli_takes_arg
+  switch "$argv[1]:$argv[2]"
+    case {takes_arg}
+      return 0
+  end
+  return 1
+end
+
+# Check whether $argv[1] is the path of a command.
+function __nitrocli_is_command
+  switch "$argv[1]"
+    case {paths}
+      return 0
+  end
+  return 1
+end
+
+# Check whether the command being completed is the one with the path
+# $argv[1].
+function __nitrocli_at
+  set -l words (commandline -opc)
+  set -e words[1]
+  set -l cmd ''
+  set -l option ''
+  for word in $words
+    if test -n "$option"
+      set option ''
+      continue
+    end
+    switch $word
+      case '--*=*'
+      case '-*'
+        if __nitrocli_takes_arg "$cmd" $word
+          set option $word
+        end
+      case '*'
+        set -l next (string trim -- "$cmd $word")
+        if __nitrocli_is_command "$next"
+          set cmd $next
+        end
+    end
+  end
+  test -z "$option" -a "$cmd" = "$argv[1]"
+end
+
+complete -c nitrocli -f
+{completions}"#,
+    takes_arg = if takes_arg.is_empty() {
+      "''".to_string()
+    } else {
+      takes_arg.join(" ")
+    },
+    paths = paths,
+    completions = completions,
+  )
+}
+
+/// Format the arguments of `complete` offering the given values in a
+/// fish completion.
+fn fish_values(values: Values) -> String {
+  match values {
+    Values::None => String::new(),
+    Values::Words(words) => format!(" -f -a {}", quote_fish(&words.join(" "))),
+    Values::Files => " -F".to_string(),
+    values => match values.list_kind() {
+      Some(kind) => format!(" -f -a '(nitrocli completions --list {})'", kind),
+      None => String::new(),
+    },
+  }
+}
+
+/// Generate the completion script for the given shell, covering the
+/// given program description.
+pub fn generate(shell: args::Shell, program: &Command) -> String {
+  let mut commands = Vec::new();
+  walk(program, "", &mut commands);
+  match shell {
+    args::Shell::Bash => bash(&commands),
+    args::Shell::Fish => fish(&commands),
+    args::Shell::Zsh => zsh(&commands),
+  }
+}
+
+/// Determine the completions of the word `partial` following the
+/// given words of a command line of the given program.
+///
+/// Like the generated scripts, values determined at completion time
+/// are taken from the caches of the program.
+pub fn complete(program: &Command, words: &[&str], partial: &str) -> Vec<String> {
+  let mut command = program;
+  // The option expecting the next word as its argument, if any.
+  let mut option = None;
+  for word in words {
+    if option.take().is_some() || (word.starts_with("--") && word.contains('=')) {
+      continue;
+    }
+    if word.starts_with('-') {
+      option = command
+        .options
+        .iter()
+        .find(|opt| option_names(opt).iter().any(|name| name == word))
+        .and_then(|opt| opt.arg);
+    } else if let Some(subcommand) = command.subcommands.iter().find(|c| c.name == *word) {
+      command = subcommand;
+    }
+  }
+
+  let candidates = if let Some(arg) = option {
+    values(arg.values)
+  } else if partial.starts_with('-') {
+    command.options.iter().flat_map(option_names).collect()
+  } else if !command.subcommands.is_empty() {
+    command
+      .subcommands
+      .iter()
+      .map(|subcommand| subcommand.name.to_string())
+      .collect()
+  } else {
+    values(command.args)
+  };
+  candidates
+    .into_iter()
+    .filter(|candidate| candidate.starts_with(partial))
+    .collect()
+}
+
+/// Retrieve the given values, as far as they are known.
+fn values(values: Values) -> Vec<String> {
+  match values {
+    Values::Words(words) => words.iter().map(|word| word.to_string()).collect(),
+    values => match values.list_kind() {
+      Some(kind) => list(kind).unwrap_or_default(),
+      None => Vec::new(),
+    },
+  }
+}
+
+/// Retrieve the values of the given kind determined at completion time.
+///
+/// The values are read from the caches of the program, as completion
+/// must be fast and must not ask for a PIN.
+pub fn list(kind: &str) -> Result<Vec<String>> {
+  let values: Vec<String> = match kind {
+    #[cfg(feature = "otp")]
+    "otp-slots" => {
+      let registry = registry::Registry::load()?;
+      let mut names = slots::SlotCache::load()?
+        .names()
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>();
+      names.extend(registry.names("otp").map(String::from));
+      names.sort();
+      names.dedup();
+      names
+    }
+    "serial-numbers" => {
+      let values = nicknames::Nicknames::load()?
+        .iter()
+        .map(|(_, name)| name.to_string())
+        .collect::<Vec<_>>();
+      #[cfg(feature = "otp")]
+      let values = [
+        values,
+        slots::SlotCache::load()?
+          .serial_numbers()
+          .into_iter()
+          .map(String::from)
+          .collect(),
+      ]
+      .concat();
+      values
+    }
+    #[cfg(not(feature = "otp"))]
+    "otp-slots" => Vec::new(),
+    _ => {
+      let error = format!(
+        "Invalid kind of values: {} (expected otp-slots or serial-numbers)",
+        kind
+      );
+      return Err(Error::Error(error));
+    }
+  };
+  Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn program() -> Command {
+    Command {
+      name: "nitrocli",
+      help: "",
+      options: vec![Opt {
+        long: "model",
+        short: Some('m'),
+        arg: Some(Arg {
+          name: "MODEL",
+          values: Values::Words(&["pro", "storage"]),
+        }),
+        help: "Only connect to a device of the given model",
+      }],
+      subcommands: vec![Command {
+        name: "otp",
+        help: "Access one-time passwords",
+        options: Vec::new(),
+        subcommands: vec![Command {
+          name: "get",
+          help: "Generate a one-time password",
+          options: Vec::new(),
+          subcommands: Vec::new(),
+          usage: "<slot>",
+          args: Values::OtpSlots,
+        }],
+        usage: "",
+        args: Values::None,
+      }],
+      usage: "",
+      args: Values::None,
+    }
+  }
+
+  #[test]
+  fn complete_words() {
+    let program = program();
+    assert_eq!(complete(&program, &[], "o"), vec!["otp"]);
+    assert_eq!(complete(&program, &["otp"], ""), vec!["get"]);
+    assert_eq!(complete(&program, &[], "--"), vec!["--model"]);
+    assert_eq!(complete(&program, &["-m"], "s"), vec!["storage"]);
+    assert_eq!(
+      complete(&program, &["--model", "pro"], ""),
+      vec!["otp".to_string()]
+    );
+    assert!(complete(&program, &[], "x").is_empty());
+  }
+
+  #[test]
+  fn quoting() {
+    assert_eq!(quote("gpg-agent's"), "'gpg-agent'\\''s'");
+    assert_eq!(quote_fish("gpg-agent's"), "'gpg-agent\\'s'");
+  }
+
+  #[test]
+  fn sh_completion() {
+    let script = generate(args::Shell::Bash, &program());
+    assert!(script.contains("    ':--model'|':-m') return 0 ;;\n"));
+    assert!(script.contains("    'otp'|'otp get') return 0 ;;\n"));
+    assert!(script.contains("    '') echo 'otp' ;;\n"));
+    assert!(script.contains("    ':--model'|':-m') echo 'words:pro storage' ;;\n"));
+    assert!(script.contains("    'otp get:') echo 'otp-slots' ;;\n"));
+    assert!(script.ends_with("complete -F _nitrocli nitrocli\n"));
+    assert!(generate(args::Shell::Zsh, &program()).starts_with("#compdef nitrocli\n"));
+  }
+
+  #[test]
+  fn fish_completion() {
+    let script = generate(args::Shell::Fish, &program());
+    assert!(script.contains(
+      "complete -c nitrocli -n '__nitrocli_at \\'\\'' -a otp -d 'Access one-time passwords'\n"
+    ));
+    assert!(script.contains(
+      "complete -c nitrocli -n '__nitrocli_at \\'otp get\\'' -f -a '(nitrocli completions --list otp-slots)'\n"
+    ));
+    assert!(script.contains(" -l model -s m -r -f -a 'pro storage' -d "));
+  }
+}
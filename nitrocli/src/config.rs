@@ -0,0 +1,714 @@
+// config.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path;
+use std::result;
+use std::str;
+
+use crate::error::Error;
+use crate::storage;
+
+type Result<T> = result::Result<T, Error>;
+
+/// A value as it may appear in the configuration file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+  Array(Vec<Value>),
+  Boolean(bool),
+  Integer(i64),
+  String(String),
+}
+
+impl fmt::Display for Value {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match *self {
+      Value::Array(ref values) => {
+        let values = values.iter().map(Value::to_string).collect::<Vec<_>>();
+        write!(f, "[{}]", values.join(", "))
+      }
+      Value::Boolean(b) => write!(f, "{}", b),
+      Value::Integer(i) => write!(f, "{}", i),
+      Value::String(ref s) => write!(f, "{:?}", s),
+    }
+  }
+}
+
+/// The type of a configuration value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+  Boolean,
+  Integer,
+  String,
+  Strings,
+}
+
+/// The origin of a configuration value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+  /// The value was read from the given table of the configuration file.
+  File(String),
+  /// The value was read from the given environment variable.
+  Env(String),
+  /// The value was given on the command line.
+  CommandLine,
+}
+
+impl fmt::Display for Source {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match *self {
+      Source::File(ref section) if section.is_empty() => write!(f, "configuration file"),
+      Source::File(ref section) => write!(f, "configuration file, table {}", section),
+      Source::Env(ref var) => write!(f, "environment variable {}", var),
+      Source::CommandLine => write!(f, "command line"),
+    }
+  }
+}
+
+/// The key-value pairs of a single section of the configuration.
+type Section = BTreeMap<String, Value>;
+
+/// The configuration of the program.
+///
+/// The configuration is read from a file in a subset of the TOML
+/// format: tables, bare keys, and boolean, integer, string, and
+/// (single line) array values are supported. Values that are not part
+/// of any table are stored in the section with the empty name.
+#[derive(Debug, Default)]
+pub struct Config {
+  sections: BTreeMap<String, Section>,
+  /// The origins of values that were not read from their own table of
+  /// the configuration file.
+  sources: BTreeMap<(String, String), Source>,
+}
+
+impl Config {
+  /// Load the configuration from the default configuration file.
+  ///
+  /// A missing configuration file is not an error but results in an
+  /// empty configuration.
+  pub fn load() -> Result<Self> {
+    let path = config_path()?;
+    match fs::read_to_string(&path) {
+      Ok(data) => data.parse::<Config>().map_err(|err| {
+        let error = format!("Invalid configuration file {}: {}", path.display(), err);
+        Error::Error(error)
+      }),
+      Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Default::default()),
+      Err(err) => {
+        let context = format!("Could not read configuration file {}", path.display());
+        Err(Error::IoError(err).context(context))
+      }
+    }
+  }
+
+  /// Retrieve the value of the given key in the given section.
+  pub fn get(&self, section: &str, key: &str) -> Option<&Value> {
+    self.sections.get(section).and_then(|s| s.get(key))
+  }
+
+  /// Check whether the given section is present.
+  pub fn has_section(&self, section: &str) -> bool {
+    self.sections.contains_key(section)
+  }
+
+  /// Retrieve the names of the sections.
+  pub fn sections(&self) -> impl Iterator<Item = &str> {
+    self.sections.keys().map(String::as_str)
+  }
+
+  /// Retrieve all keys along with their values in the given section.
+  pub fn entries<'s>(&'s self, section: &str) -> impl Iterator<Item = (&'s str, &'s Value)> {
+    self
+      .sections
+      .get(section)
+      .into_iter()
+      .flat_map(|s| s.iter())
+      .map(|(key, value)| (key.as_str(), value))
+  }
+
+  /// Set the value of the given key in the given section, overriding
+  /// any value present already, and record where it originates from.
+  pub fn set(&mut self, section: &str, key: &str, value: Value, source: Source) {
+    let _ = self
+      .sections
+      .entry(section.to_string())
+      .or_default()
+      .insert(key.to_string(), value);
+    let _ = self
+      .sources
+      .insert((section.to_string(), key.to_string()), source);
+  }
+
+  /// Retrieve the origin of the value of the given key in the given
+  /// section.
+  pub fn source(&self, section: &str, key: &str) -> Option<Source> {
+    self.get(section, key).map(|_| {
+      self
+        .sources
+        .get(&(section.to_string(), key.to_string()))
+        .cloned()
+        .unwrap_or_else(|| Source::File(section.to_string()))
+    })
+  }
+
+  /// Describe the configuration, listing all values along with their
+  /// origins.
+  ///
+  /// The given keys are listed even if they are not set.
+  pub fn describe(&self, keys: &[(&str, &str, Kind)]) -> String {
+    let mut entries = BTreeMap::new();
+    for &(section, key, _) in keys {
+      let _ = entries.insert((section, key), None);
+    }
+    for (section, values) in &self.sections {
+      for (key, value) in values {
+        let _ = entries.insert((section.as_str(), key.as_str()), Some(value));
+      }
+    }
+
+    let mut description = String::new();
+    for ((section, key), value) in entries {
+      let name = if section.is_empty() {
+        key.to_string()
+      } else {
+        format!("{}.{}", section, key)
+      };
+      description += &match value {
+        // We have an entry for the value, so it has a source as well.
+        Some(value) => format!(
+          "{} = {}  # {}\n",
+          name,
+          value,
+          self.source(section, key).unwrap()
+        ),
+        None => format!("# {} is not set\n", name),
+      };
+    }
+    description
+  }
+
+  /// Override the given keys with the values of the corresponding
+  /// environment variables, as retrieved using the given function.
+  ///
+  /// Each key is described by its section, its name, and the kind of
+  /// value it holds.
+  pub fn apply_env<F>(&mut self, keys: &[(&str, &str, Kind)], getenv: F) -> Result<()>
+  where
+    F: Fn(&str) -> Option<String>,
+  {
+    for &(section, key, kind) in keys {
+      let var = env_var(section, key);
+      if let Some(value) = getenv(&var) {
+        let value = parse_env_value(&var, &value, kind)?;
+        self.set(section, key, value, Source::Env(var));
+      }
+    }
+    Ok(())
+  }
+
+  /// Retrieve the boolean value of the given key in the given section.
+  ///
+  /// An error is reported if the key is present but its value is not
+  /// a boolean.
+  pub fn get_bool(&self, section: &str, key: &str) -> Result<Option<bool>> {
+    match self.get(section, key) {
+      Some(Value::Boolean(b)) => Ok(Some(*b)),
+      Some(value) => Err(type_error(section, key, value, "a boolean")),
+      None => Ok(None),
+    }
+  }
+
+  /// Retrieve the integer value of the given key in the given section.
+  ///
+  /// An error is reported if the key is present but its value is not
+  /// an integer.
+  pub fn get_integer(&self, section: &str, key: &str) -> Result<Option<i64>> {
+    match self.get(section, key) {
+      Some(Value::Integer(i)) => Ok(Some(*i)),
+      Some(value) => Err(type_error(section, key, value, "an integer")),
+      None => Ok(None),
+    }
+  }
+
+  /// Retrieve the string value of the given key in the given section.
+  ///
+  /// An error is reported if the key is present but its value is not
+  /// a string.
+  pub fn get_string(&self, section: &str, key: &str) -> Result<Option<&str>> {
+    match self.get(section, key) {
+      Some(Value::String(s)) => Ok(Some(s)),
+      Some(value) => Err(type_error(section, key, value, "a string")),
+      None => Ok(None),
+    }
+  }
+
+  /// Retrieve the string array value of the given key in the given
+  /// section.
+  ///
+  /// An error is reported if the key is present but its value is not
+  /// an array of strings.
+  pub fn get_strings(&self, section: &str, key: &str) -> Result<Option<Vec<&str>>> {
+    match self.get(section, key) {
+      Some(value) => {
+        let strings = match value {
+          Value::Array(values) => values
+            .iter()
+            .map(|value| match value {
+              Value::String(s) => Some(s.as_str()),
+              _ => None,
+            })
+            .collect::<Option<Vec<_>>>(),
+          _ => None,
+        };
+        strings
+          .map(Some)
+          .ok_or_else(|| type_error(section, key, value, "an array of strings"))
+      }
+      None => Ok(None),
+    }
+  }
+}
+
+/// Create an error describing a configuration value of an unexpected type.
+pub fn type_error(section: &str, key: &str, value: &Value, expected: &str) -> Error {
+  let key = if section.is_empty() {
+    key.to_string()
+  } else {
+    format!("{}.{}", section, key)
+  };
+  let error = format!(
+    "Invalid value for configuration key {}: expected {}, got {}",
+    key, expected, value
+  );
+  Error::Error(error)
+}
+
+impl str::FromStr for Config {
+  type Err = String;
+
+  fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+    let mut sections = BTreeMap::new();
+    let mut current = String::new();
+    let _ = sections.insert(current.clone(), Section::new());
+
+    for (idx, line) in s.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+
+      parse_line(line, &mut sections, &mut current)
+        .map_err(|err| format!("line {}: {}", idx + 1, err))?;
+    }
+    Ok(Config {
+      sections,
+      sources: BTreeMap::new(),
+    })
+  }
+}
+
+/// Check whether the given character may be part of a bare key.
+fn is_key_char(c: char) -> bool {
+  c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+/// Parse a single non-empty line of a configuration file.
+fn parse_line(
+  line: &str,
+  sections: &mut BTreeMap<String, Section>,
+  current: &mut String,
+) -> result::Result<(), String> {
+  if line.starts_with('[') {
+    let end = line.find(']').ok_or("unterminated table header")?;
+    let name = line[1..end].trim();
+    if name.is_empty()
+      || !name
+        .split('.')
+        .all(|p| !p.is_empty() && p.chars().all(is_key_char))
+    {
+      return Err(format!("invalid table name: {}", name));
+    }
+    check_trailing(&line[end + 1..])?;
+
+    if sections.contains_key(name) {
+      return Err(format!("duplicate table: {}", name));
+    }
+    *current = name.to_string();
+    let _ = sections.insert(current.clone(), Section::new());
+    return Ok(());
+  }
+
+  let end = line.find(|c| !is_key_char(c)).unwrap_or(line.len());
+  let (key, rest) = line.split_at(end);
+  if key.is_empty() {
+    return Err(format!("invalid key: {}", line));
+  }
+
+  let rest = rest.trim_start();
+  if !rest.starts_with('=') {
+    return Err(format!("expected '=' after key {}", key));
+  }
+  let (value, rest) = parse_value(rest[1..].trim_start())?;
+  check_trailing(rest)?;
+
+  // The current section is always present in the map.
+  let section = sections.get_mut(current.as_str()).unwrap();
+  if section.insert(key.to_string(), value).is_some() {
+    return Err(format!("duplicate key: {}", key));
+  }
+  Ok(())
+}
+
+/// Check that nothing but an optional comment follows a definition.
+fn check_trailing(rest: &str) -> result::Result<(), String> {
+  let rest = rest.trim_start();
+  if rest.is_empty() || rest.starts_with('#') {
+    Ok(())
+  } else {
+    Err(format!("unexpected trailing characters: {}", rest))
+  }
+}
+
+/// Parse a value at the start of the given string.
+///
+/// The function returns the parsed value along with the remainder of
+/// the string.
+fn parse_value(s: &str) -> result::Result<(Value, &str), String> {
+  match s.chars().next() {
+    Some('[') => parse_array(&s[1..]),
+    Some('"') => parse_basic_string(&s[1..]),
+    Some('\'') => {
+      let end = s[1..].find('\'').ok_or("unterminated string")?;
+      Ok((Value::String(s[1..=end].to_string()), &s[end + 2..]))
+    }
+    _ => parse_word(s),
+  }
+}
+
+/// Parse an array, with the opening bracket already removed.
+fn parse_array(mut s: &str) -> result::Result<(Value, &str), String> {
+  let mut values = Vec::new();
+  loop {
+    s = s.trim_start();
+    if let Some(']') = s.chars().next() {
+      return Ok((Value::Array(values), &s[1..]));
+    }
+
+    let (value, rest) = parse_value(s)?;
+    values.push(value);
+
+    s = rest.trim_start();
+    match s.chars().next() {
+      Some(',') => s = &s[1..],
+      Some(']') => (),
+      _ => return Err("unterminated array".to_string()),
+    }
+  }
+}
+
+/// Parse a boolean or integer value at the start of the given string.
+fn parse_word(s: &str) -> result::Result<(Value, &str), String> {
+  let end = s
+    .find(|c: char| c.is_whitespace() || c == '#' || c == ',' || c == ']')
+    .unwrap_or(s.len());
+  let (word, rest) = s.split_at(end);
+  let value = match word {
+    "true" => Value::Boolean(true),
+    "false" => Value::Boolean(false),
+    _ => word
+      .replace('_', "")
+      .parse::<i64>()
+      .map(Value::Integer)
+      .map_err(|_| format!("invalid value: {}", word))?,
+  };
+  Ok((value, rest))
+}
+
+/// Parse a double quoted string, with the opening quote already removed.
+fn parse_basic_string(s: &str) -> result::Result<(Value, &str), String> {
+  let mut string = String::new();
+  let mut chars = s.char_indices();
+  while let Some((idx, c)) = chars.next() {
+    match c {
+      '"' => return Ok((Value::String(string), &s[idx + 1..])),
+      '\\' => {
+        let escaped = match chars.next() {
+          Some((_, 'n')) => '\n',
+          Some((_, 't')) => '\t',
+          Some((_, 'r')) => '\r',
+          Some((_, '"')) => '"',
+          Some((_, '\\')) => '\\',
+          Some((_, c)) => return Err(format!("invalid escape sequence: \\{}", c)),
+          None => break,
+        };
+        string.push(escaped)
+      }
+      c => string.push(c),
+    }
+  }
+  Err("unterminated string".to_string())
+}
+
+/// Retrieve the name of the environment variable corresponding to the
+/// given configuration key.
+///
+/// The name is formed by the prefix `NITROCLI_`, followed by the
+/// section and the key, separated by an underscore, in upper case and
+/// with dashes and dots replaced by underscores.
+pub fn env_var(section: &str, key: &str) -> String {
+  let name = if section.is_empty() {
+    key.to_string()
+  } else {
+    format!("{}_{}", section, key)
+  };
+  format!(
+    "NITROCLI_{}",
+    name.replace(&['-', '.'][..], "_").to_uppercase()
+  )
+}
+
+/// Parse the value of the given environment variable as a configuration
+/// value of the given kind.
+///
+/// Booleans are represented as `true`/`false` or `1`/`0` and lists of
+/// strings are separated by commas.
+pub fn parse_env_value(var: &str, value: &str, kind: Kind) -> Result<Value> {
+  let parsed = match kind {
+    Kind::Boolean => match value {
+      "true" | "1" => Some(Value::Boolean(true)),
+      "false" | "0" => Some(Value::Boolean(false)),
+      _ => None,
+    },
+    Kind::Integer => value.parse::<i64>().ok().map(Value::Integer),
+    Kind::String => Some(Value::String(value.to_string())),
+    Kind::Strings => Some(Value::Array(
+      value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| Value::String(s.to_string()))
+        .collect(),
+    )),
+  };
+  parsed.ok_or_else(|| {
+    let error = format!("Invalid value for environment variable {}: {}", var, value);
+    Error::Error(error)
+  })
+}
+
+/// Retrieve the path of the configuration file.
+pub fn config_path() -> Result<path::PathBuf> {
+  Ok(
+    storage::xdg_dir("XDG_CONFIG_HOME", ".config")?
+      .join("nitrocli")
+      .join("config.toml"),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_empty() {
+    let config = "".parse::<Config>().unwrap();
+    assert_eq!(config.get("", "foo"), None);
+  }
+
+  #[test]
+  fn parse_values() {
+    let config = r#"
+# A comment.
+top = 'level'
+
+[pinentry]
+user-cache-ttl = 600 # ten minutes
+admin-cache-ttl = 0
+string = "a \"quoted\" string"
+flag = true
+large = 1_000
+"#
+    .parse::<Config>()
+    .unwrap();
+
+    let string = Value::String("a \"quoted\" string".to_string());
+    assert_eq!(
+      config.get("", "top"),
+      Some(&Value::String("level".to_string()))
+    );
+    assert_eq!(
+      config.get_integer("pinentry", "user-cache-ttl").unwrap(),
+      Some(600)
+    );
+    assert_eq!(
+      config.get_integer("pinentry", "admin-cache-ttl").unwrap(),
+      Some(0)
+    );
+    assert_eq!(config.get("pinentry", "string"), Some(&string));
+    assert_eq!(config.get("pinentry", "flag"), Some(&Value::Boolean(true)));
+    assert_eq!(config.get_integer("pinentry", "large").unwrap(), Some(1000));
+    assert_eq!(config.get("pinentry", "top"), None);
+  }
+
+  #[test]
+  fn parse_arrays() {
+    let config = r#"
+empty = []
+strings = ["0x1234", '0x5678',]
+nested = [[1, 2], [true]]
+"#
+    .parse::<Config>()
+    .unwrap();
+
+    let strings = vec!["0x1234", "0x5678"];
+    assert_eq!(config.get_strings("", "empty").unwrap(), Some(vec![]));
+    assert_eq!(config.get_strings("", "strings").unwrap(), Some(strings));
+    assert!(config.get_strings("", "nested").is_err());
+  }
+
+  #[test]
+  fn set_overrides() {
+    let mut config = "verbosity = 1".parse::<Config>().unwrap();
+    config.set("", "verbosity", Value::Integer(3), Source::CommandLine);
+    config.set(
+      "pinentry",
+      "user-cache-ttl",
+      Value::Integer(0),
+      Source::CommandLine,
+    );
+
+    assert_eq!(config.get_integer("", "verbosity").unwrap(), Some(3));
+    assert_eq!(
+      config.get_integer("pinentry", "user-cache-ttl").unwrap(),
+      Some(0)
+    );
+  }
+
+  #[test]
+  fn parse_invalid() {
+    let invalid = [
+      "foo",
+      "foo = ",
+      "foo = bar",
+      "foo = \"bar",
+      "foo = 1 2",
+      "foo = [1, 2",
+      "foo = [1 2]",
+      "foo = 1]",
+      "[foo",
+      "[foo..bar]",
+      "foo = 1\nfoo = 2",
+      "[foo]\n[foo]",
+    ];
+    for config in invalid.iter() {
+      assert!(config.parse::<Config>().is_err(), "{}", config);
+    }
+  }
+
+  #[test]
+  fn parse_error_line() {
+    let err = "[pinentry]\nuser-cache-ttl = ten".parse::<Config>();
+    assert_eq!(err.unwrap_err(), "line 2: invalid value: ten");
+  }
+
+  #[test]
+  fn get_integer_type_error() {
+    let config = "[pinentry]\nuser-cache-ttl = '600'"
+      .parse::<Config>()
+      .unwrap();
+    assert!(config.get_integer("pinentry", "user-cache-ttl").is_err());
+  }
+
+  #[test]
+  fn env_var_names() {
+    assert_eq!(env_var("", "no-cache"), "NITROCLI_NO_CACHE");
+    assert_eq!(
+      env_var("pinentry", "user-cache-ttl"),
+      "NITROCLI_PINENTRY_USER_CACHE_TTL"
+    );
+    assert_eq!(env_var("pin.set", "force"), "NITROCLI_PIN_SET_FORCE");
+  }
+
+  #[test]
+  fn apply_env_values() {
+    let mut config = "verbosity = 1\nmodel = 'pro'".parse::<Config>().unwrap();
+    let keys = [
+      ("", "model", Kind::String),
+      ("", "no-cache", Kind::Boolean),
+      ("", "serial-numbers", Kind::Strings),
+      ("", "verbosity", Kind::Integer),
+    ];
+    let getenv = |var: &str| match var {
+      "NITROCLI_NO_CACHE" => Some("1".to_string()),
+      "NITROCLI_SERIAL_NUMBERS" => Some("0x1, 0x2".to_string()),
+      "NITROCLI_VERBOSITY" => Some("3".to_string()),
+      _ => None,
+    };
+    config.apply_env(&keys, getenv).unwrap();
+
+    assert_eq!(config.get_string("", "model").unwrap(), Some("pro"));
+    assert_eq!(config.get_bool("", "no-cache").unwrap(), Some(true));
+    assert_eq!(
+      config.get_strings("", "serial-numbers").unwrap(),
+      Some(vec!["0x1", "0x2"])
+    );
+    assert_eq!(config.get_integer("", "verbosity").unwrap(), Some(3));
+  }
+
+  #[test]
+  fn parse_env_value_invalid() {
+    assert!(parse_env_value("X", "yes", Kind::Boolean).is_err());
+    assert!(parse_env_value("X", "1.5", Kind::Integer).is_err());
+  }
+
+  #[test]
+  fn describe_sources() {
+    let mut config = "model = 'pro'\n[device.work]\nverbosity = 2"
+      .parse::<Config>()
+      .unwrap();
+    config.set(
+      "",
+      "verbosity",
+      Value::Integer(2),
+      Source::File("device.work".to_string()),
+    );
+    config.set(
+      "",
+      "model",
+      Value::String("storage".to_string()),
+      Source::Env("NITROCLI_MODEL".to_string()),
+    );
+
+    let keys = [
+      ("", "model", Kind::String),
+      ("", "no-cache", Kind::Boolean),
+      ("", "verbosity", Kind::Integer),
+    ];
+    let expected = r#"model = "storage"  # environment variable NITROCLI_MODEL
+# no-cache is not set
+verbosity = 2  # configuration file, table device.work
+device.work.verbosity = 2  # configuration file, table device.work
+"#;
+    assert_eq!(config.describe(&keys), expected);
+  }
+}
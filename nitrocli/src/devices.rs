@@ -0,0 +1,201 @@
+// devices.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::result;
+use std::str;
+
+use crate::error::Error;
+use crate::storage;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The time in seconds after which cached device facts are considered
+/// stale.
+const MAX_AGE: u64 = 60;
+
+/// The facts about a device, as cached at a certain time.
+#[derive(Debug, PartialEq)]
+struct Entry {
+  /// The time at which the facts were retrieved.
+  time: u64,
+  /// The model of the device, as reported by udev.
+  model: String,
+  /// The serial number of the device.
+  serial: String,
+}
+
+/// A cache of the facts about devices that do not change while they
+/// are connected, currently their serial numbers.
+///
+/// Entries are indexed by the path of the USB device node of the
+/// device. As the node is numbered anew whenever a device is plugged
+/// in, an entry does not outlive the connection of the device it
+/// describes, barring the unlikely reuse of the number for another
+/// device within `MAX_AGE`.
+#[derive(Debug, Default, PartialEq)]
+pub struct DeviceCache {
+  entries: BTreeMap<String, Entry>,
+}
+
+impl DeviceCache {
+  /// Load the cache from its file.
+  pub fn load() -> Result<Self> {
+    let data = storage::DEVICE_CACHE.read()?.unwrap_or_default();
+    // The cache is parsed leniently, so parsing cannot fail.
+    Ok(data.parse().unwrap())
+  }
+
+  /// Save the cache to its file.
+  pub fn save(&self) -> Result<()> {
+    storage::DEVICE_CACHE.write(&self.to_string())
+  }
+
+  /// Look up the serial number of the device of the given model at the
+  /// given path.
+  ///
+  /// `None` is returned if no such device is cached or if the cached
+  /// facts are older than `MAX_AGE` at the given time.
+  pub fn lookup(&self, path: &str, model: &str, now: u64) -> Option<&str> {
+    let entry = self.entries.get(path)?;
+    if entry.model != model || now.saturating_sub(entry.time) >= MAX_AGE {
+      return None;
+    }
+    Some(&entry.serial)
+  }
+
+  /// Replace the cached facts about the device at the given path,
+  /// dropping the stale entries of other devices.
+  pub fn update(&mut self, path: &str, model: &str, serial: &str, now: u64) {
+    self
+      .entries
+      .retain(|_, entry| now.saturating_sub(entry.time) < MAX_AGE);
+    let entry = Entry {
+      time: now,
+      model: model.to_string(),
+      serial: serial.to_string(),
+    };
+    let _ = self.entries.insert(path.to_string(), entry);
+  }
+}
+
+/// The cache is stored with one line per device, containing the path,
+/// the time of retrieval, the serial number, and the model, separated
+/// by a space.
+impl fmt::Display for DeviceCache {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for (path, entry) in &self.entries {
+      writeln!(
+        f,
+        "{} {} {} {}",
+        path, entry.time, entry.serial, entry.model
+      )?;
+    }
+    Ok(())
+  }
+}
+
+impl str::FromStr for DeviceCache {
+  type Err = ();
+
+  /// Parse a cache, ignoring malformed lines.
+  fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+    let mut cache = DeviceCache::default();
+    for line in s.lines() {
+      let mut parts = line.splitn(4, ' ');
+      let parsed = (
+        parts.next(),
+        parts.next().and_then(|time| time.parse::<u64>().ok()),
+        parts.next(),
+        parts.next(),
+      );
+      if let (Some(path), Some(time), Some(serial), Some(model)) = parsed {
+        let entry = Entry {
+          time,
+          model: model.to_string(),
+          serial: serial.to_string(),
+        };
+        let _ = cache.entries.insert(path.to_string(), entry);
+      }
+    }
+    Ok(cache)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const PRO: &str = "Nitrokey Pro";
+  const STORAGE: &str = "Nitrokey Storage";
+
+  #[test]
+  fn lookup() {
+    let mut cache = DeviceCache::default();
+    cache.update("/dev/bus/usb/001/004", STORAGE, "0x1", 100);
+
+    assert_eq!(
+      cache.lookup("/dev/bus/usb/001/004", STORAGE, 100),
+      Some("0x1")
+    );
+    assert_eq!(cache.lookup("/dev/bus/usb/001/004", PRO, 100), None);
+    assert_eq!(cache.lookup("/dev/bus/usb/001/005", STORAGE, 100), None);
+    assert_eq!(
+      cache.lookup("/dev/bus/usb/001/004", STORAGE, 100 + MAX_AGE),
+      None
+    );
+  }
+
+  #[test]
+  fn update_drops_stale_entries() {
+    let mut cache = DeviceCache::default();
+    cache.update("/dev/bus/usb/001/004", STORAGE, "0x1", 100);
+    cache.update("/dev/bus/usb/001/005", PRO, "0x2", 100 + MAX_AGE);
+    assert_eq!(cache.entries.len(), 1);
+    assert_eq!(
+      cache.lookup("/dev/bus/usb/001/005", PRO, 100 + MAX_AGE),
+      Some("0x2")
+    );
+  }
+
+  #[test]
+  fn round_trip() {
+    let mut cache = DeviceCache::default();
+    cache.update("/dev/bus/usb/001/004", STORAGE, "0x1", 100);
+    cache.update("/dev/bus/usb/002/010", PRO, "0x2", 110);
+
+    let data = cache.to_string();
+    assert_eq!(
+      data,
+      "/dev/bus/usb/001/004 100 0x1 Nitrokey Storage\n/dev/bus/usb/002/010 110 0x2 Nitrokey Pro\n"
+    );
+    assert_eq!(data.parse::<DeviceCache>().unwrap(), cache);
+  }
+
+  #[test]
+  fn parse_malformed() {
+    let cache = "/dev/bus/usb/001/004 100 0x1 Nitrokey Pro\n/dev/x y 0x2 Nitrokey Pro\ngarbage\n"
+      .parse::<DeviceCache>()
+      .unwrap();
+    let mut expected = DeviceCache::default();
+    expected.update("/dev/bus/usb/001/004", PRO, "0x1", 100);
+    assert_eq!(cache, expected);
+  }
+}
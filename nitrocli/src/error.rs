@@ -21,11 +21,145 @@ use std::fmt;
 use std::io;
 use std::string;
 
+use crate::signal;
+use crate::watchdog;
+
 #[derive(Debug)]
 pub enum Error {
   IoError(io::Error),
   Utf8Error(string::FromUtf8Error),
   Error(String),
+  ExitStatus(i32),
+  /// An error reported by libnitrokey while performing the given
+  /// operation.
+  Command(String, nitrokey::CommandError),
+  /// An error of the device layer other than a failed command.
+  Core(nitrocli_core::Error),
+  /// An error that occurred while performing the given operation.
+  Context(String, Box<Error>),
+  /// The operation was interrupted by the given signal.
+  Interrupted(i32),
+  /// The operation did not finish within the configured timeout.
+  TimedOut,
+}
+
+impl Error {
+  /// Wrap this error with a message describing the operation that
+  /// failed.
+  pub fn context<S>(self, context: S) -> Error
+  where
+    S: Into<String>,
+  {
+    Error::Context(context.into(), Box::new(self))
+  }
+
+  /// Retrieve the error that caused this one, if any.
+  pub fn cause(&self) -> Option<&Error> {
+    match *self {
+      Error::Context(_, ref cause) => Some(cause),
+      _ => None,
+    }
+  }
+
+  /// Retrieve the innermost cause of this error.
+  pub fn root_cause(&self) -> &Error {
+    let mut error = self;
+    while let Some(cause) = error.cause() {
+      error = cause;
+    }
+    error
+  }
+
+  /// Retrieve the status the program exits with because of this error.
+  ///
+  /// Following the convention of shells, a program interrupted by a
+  /// signal exits with 128 plus the number of the signal.
+  pub fn exit_code(&self) -> i32 {
+    match *self.root_cause() {
+      Error::ExitStatus(code) => code,
+      Error::Interrupted(signal) => 128 + signal,
+      Error::TimedOut => watchdog::EXIT_STATUS,
+      _ => 1,
+    }
+  }
+
+  /// Retrieve a hint on how to remedy this error, if we know one.
+  pub fn hint(&self) -> Option<&'static str> {
+    match *self.root_cause() {
+      Error::Command(_, ref err) => match err {
+        nitrokey::CommandError::WrongPassword => {
+          Some("Check the PIN; the number of remaining attempts is shown by `nitrocli status`")
+        }
+        nitrokey::CommandError::NotAuthorized => {
+          Some("Authenticate with the admin PIN or clear a stale PIN using `nitrocli clear`")
+        }
+        nitrokey::CommandError::SlotNotProgrammed => Some("Program the slot before using it"),
+        nitrokey::CommandError::NotSupported => {
+          Some("The connected device or its firmware does not support this command")
+        }
+        nitrokey::CommandError::Unknown | nitrokey::CommandError::WrongCrc => {
+          Some("Reconnect the device and make sure that no other program is using it")
+        }
+        _ => None,
+      },
+      Error::Core(nitrocli_core::Error::NotFound) => Some(
+        "Make sure that the device is plugged in and accessible, e.g., using `nitrocli setup udev --check`",
+      ),
+      Error::Core(nitrocli_core::Error::SerialNumberMismatch(_)) => {
+        Some("Check the --serial-number option and the serial-numbers setting")
+      }
+      _ => None,
+    }
+  }
+
+  /// Format the message of this error alone, without its cause.
+  fn message(&self) -> String {
+    match *self {
+      Error::Context(ref context, _) => context.clone(),
+      ref error => error.to_string(),
+    }
+  }
+
+  /// Format the full chain of causes of this error, along with the
+  /// device it occurred on and a hint on how to remedy it.
+  pub fn chain(&self, device: Option<&str>) -> String {
+    let mut text = self.message();
+    let mut error = self;
+    while let Some(cause) = error.cause() {
+      text += &format!("\n  Caused by: {}", cause.message());
+      error = cause;
+    }
+    if let Some(device) = device {
+      text += &format!("\n  Device: {}", device);
+    }
+    if let Some(hint) = self.hint() {
+      text += &format!("\n  Hint: {}", hint);
+    }
+    text
+  }
+}
+
+/// An extension trait for adding context to the errors of results.
+pub trait Context<T> {
+  /// Wrap the error, if any, with the message produced by the given
+  /// function.
+  fn context<F, S>(self, f: F) -> Result<T, Error>
+  where
+    F: FnOnce() -> S,
+    S: Into<String>;
+}
+
+impl<T, E> Context<T> for Result<T, E>
+where
+  E: Into<Error>,
+{
+  fn context<F, S>(self, f: F) -> Result<T, Error>
+  where
+    F: FnOnce() -> S,
+    S: Into<String>,
+  {
+    self.map_err(|err| err.into().context(f()))
+  }
 }
 
 impl From<io::Error> for Error {
@@ -34,6 +168,15 @@ impl From<io::Error> for Error {
   }
 }
 
+impl From<nitrocli_core::Error> for Error {
+  fn from(e: nitrocli_core::Error) -> Error {
+    match e {
+      nitrocli_core::Error::Command(op, err) => Error::Command(op.to_string(), err),
+      e => Error::Core(e),
+    }
+  }
+}
+
 impl From<string::FromUtf8Error> for Error {
   fn from(e: string::FromUtf8Error) -> Error {
     Error::Utf8Error(e)
@@ -41,11 +184,74 @@ impl From<string::FromUtf8Error> for Error {
 }
 
 impl fmt::Display for Error {
+  /// Format the error as a short message, consisting of the outermost
+  /// context and the root cause.
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     match *self {
       Error::Utf8Error(_) => write!(f, "Encountered UTF-8 conversion error"),
-      Error::IoError(ref e) => write!(f, "IO error: {}", e.get_ref().unwrap()),
+      Error::IoError(ref e) => write!(f, "IO error: {}", e),
       Error::Error(ref e) => write!(f, "{}", e),
+      Error::ExitStatus(code) => write!(f, "Exited with status {}", code),
+      Error::Command(ref op, ref err) => write!(f, "{}: {:?}", op, err),
+      Error::Core(ref err) => write!(f, "{}", err),
+      Error::Context(ref context, _) => write!(f, "{}: {}", context, self.root_cause()),
+      Error::Interrupted(signal) => write!(f, "Interrupted by {}", signal::name(signal)),
+      Error::TimedOut => write!(f, "Timed out"),
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn context_chain() {
+    let err = Error::Command(
+      "Opening encrypted volume failed".to_string(),
+      nitrokey::CommandError::WrongPassword,
+    );
+    let err = Err::<(), _>(err)
+      .context(|| "Could not apply the task")
+      .context(|| "Module failed")
+      .unwrap_err();
+
+    assert_eq!(
+      err.to_string(),
+      "Module failed: Opening encrypted volume failed: WrongPassword"
+    );
+    assert!(err.hint().unwrap().contains("PIN"));
+    let chain = err.chain(Some("Nitrokey Storage 0x00053141"));
+    let lines = chain.lines().collect::<Vec<_>>();
+    assert_eq!(
+      lines,
+      vec![
+        "Module failed",
+        "  Caused by: Could not apply the task",
+        "  Caused by: Opening encrypted volume failed: WrongPassword",
+        "  Device: Nitrokey Storage 0x00053141",
+        "  Hint: Check the PIN; the number of remaining attempts is shown by `nitrocli status`",
+      ]
+    );
+  }
+
+  #[test]
+  fn io_error_without_hint() {
+    let err = Error::from(io::Error::from(io::ErrorKind::NotFound));
+    assert_eq!(err.hint(), None);
+    assert_eq!(err.chain(None), err.to_string());
+  }
+
+  #[test]
+  fn exit_codes() {
+    assert_eq!(Error::Error("test".to_string()).exit_code(), 1);
+    assert_eq!(Error::ExitStatus(3).exit_code(), 3);
+    let err =
+      Error::Interrupted(libc::SIGINT).context("Stopped after opening the encrypted volume");
+    assert_eq!(err.exit_code(), 130);
+    assert_eq!(
+      err.to_string(),
+      "Stopped after opening the encrypted volume: Interrupted by SIGINT"
+    );
+  }
+}
@@ -0,0 +1,314 @@
+// extensions.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+#[cfg(feature = "extensions")]
+use std::collections::BTreeMap;
+use std::env;
+#[cfg(feature = "extensions")]
+use std::fs;
+#[cfg(feature = "extensions")]
+use std::io::Read;
+#[cfg(feature = "extensions")]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(feature = "extensions")]
+use std::path;
+#[cfg(feature = "extensions")]
+use std::process;
+use std::result;
+#[cfg(feature = "extensions")]
+use std::thread;
+#[cfg(feature = "extensions")]
+use std::time;
+
+use crate::args;
+#[cfg(feature = "extensions")]
+use crate::commands;
+use crate::config;
+use crate::error::Error;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The prefix of the names of extension executables.
+#[cfg(feature = "extensions")]
+const PREFIX: &str = "nitrocli-";
+
+/// Check whether the file at the given path is an executable.
+#[cfg(feature = "extensions")]
+fn is_executable(path: &path::Path) -> bool {
+  fs::metadata(path)
+    .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    .unwrap_or(false)
+}
+
+/// Find the executable implementing the extension with the given name
+/// in the given directories.
+#[cfg(feature = "extensions")]
+fn find_in<I>(name: &str, dirs: I) -> Option<path::PathBuf>
+where
+  I: IntoIterator<Item = path::PathBuf>,
+{
+  if name.is_empty() || name.contains('/') {
+    return None;
+  }
+
+  let file = format!("{}{}", PREFIX, name);
+  dirs
+    .into_iter()
+    .map(|dir| dir.join(&file))
+    .find(|path| is_executable(path))
+}
+
+/// Find the executable implementing the extension with the given name,
+/// `nitrocli-<name>`, in the directories listed in the `PATH`.
+#[cfg(feature = "extensions")]
+pub fn find(name: &str) -> Option<path::PathBuf> {
+  let paths = env::var_os("PATH")?;
+  find_in(name, env::split_paths(&paths))
+}
+
+/// List the extensions available in the given directories, along with
+/// the paths of their executables, sorted by name.
+///
+/// If an extension is present in multiple directories, the first
+/// occurrence takes precedence, just as for `find_in`.
+#[cfg(feature = "extensions")]
+fn list_in<I>(dirs: I) -> Vec<(String, path::PathBuf)>
+where
+  I: IntoIterator<Item = path::PathBuf>,
+{
+  let mut extensions = BTreeMap::new();
+  for dir in dirs {
+    let entries = match fs::read_dir(&dir) {
+      Ok(entries) => entries,
+      // Directories in the PATH that cannot be read are not an error.
+      Err(_) => continue,
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+      let file = entry.file_name();
+      let name = match file.to_str() {
+        Some(file) if file.len() > PREFIX.len() && file.starts_with(PREFIX) => {
+          file[PREFIX.len()..].to_string()
+        }
+        _ => continue,
+      };
+      let path = entry.path();
+      if !extensions.contains_key(&name) && is_executable(&path) {
+        let _ = extensions.insert(name, path);
+      }
+    }
+  }
+  extensions.into_iter().collect()
+}
+
+/// List the extensions available in the directories listed in the
+/// `PATH`, along with the paths of their executables, sorted by name.
+#[cfg(feature = "extensions")]
+pub fn list() -> Vec<(String, path::PathBuf)> {
+  match env::var_os("PATH") {
+    Some(paths) => list_in(env::split_paths(&paths)),
+    None => Vec::new(),
+  }
+}
+
+/// Retrieve the short description of the extension at the given path.
+///
+/// Extensions supporting it print their description when invoked with
+/// the `--nitrocli-description` argument. Extensions that do not
+/// support it, fail, or take longer than a second to respond are
+/// considered to have no description.
+#[cfg(feature = "extensions")]
+pub fn description(path: &path::Path) -> Option<String> {
+  let mut child = process::Command::new(path)
+    .arg("--nitrocli-description")
+    .stdin(process::Stdio::null())
+    .stdout(process::Stdio::piped())
+    .stderr(process::Stdio::null())
+    .spawn()
+    .ok()?;
+
+  let deadline = time::Instant::now() + time::Duration::from_secs(1);
+  let status = loop {
+    match child.try_wait() {
+      Ok(Some(status)) => break status,
+      Ok(None) if time::Instant::now() < deadline => thread::sleep(time::Duration::from_millis(10)),
+      _ => {
+        let _ = child.kill();
+        let _ = child.wait();
+        return None;
+      }
+    }
+  };
+  if !status.success() {
+    return None;
+  }
+
+  let mut output = String::new();
+  let _ = child.stdout.take()?.read_to_string(&mut output).ok()?;
+  output
+    .lines()
+    .next()
+    .map(str::trim)
+    .filter(|line| !line.is_empty())
+    .map(String::from)
+}
+
+/// Retrieve the environment variables describing the given context and
+/// the given device, if any, to an extension.
+///
+/// Besides the path of the `nitrocli` binary and of the configuration
+/// file, the environment contains the settings of the context under the
+/// names of the environment variables overriding the respective
+/// configuration keys. That way, `nitrocli` invocations by the
+/// extension behave consistently with the invocation of the extension.
+/// If the device was resolved, its model and serial number replace the
+/// ones from the context, so that the extension talks to the very same
+/// device.
+pub fn context_env(
+  ctx: &args::ExecCtx,
+  device: Option<(args::DeviceModel, String)>,
+) -> Result<Vec<(String, String)>> {
+  let binary = env::current_exe()?;
+  let config = config::config_path()?;
+  let (model, serial_numbers) = match device {
+    Some((model, serial)) => (Some(model), vec![serial]),
+    None => (ctx.model, ctx.serial_numbers.clone()),
+  };
+
+  let mut env = vec![
+    (
+      "NITROCLI_BINARY".to_string(),
+      binary.to_string_lossy().into_owned(),
+    ),
+    (
+      "NITROCLI_CONFIG_FILE".to_string(),
+      config.to_string_lossy().into_owned(),
+    ),
+    (config::env_var("", "dry-run"), ctx.dry_run.to_string()),
+    (config::env_var("", "no-cache"), ctx.no_cache.to_string()),
+    (config::env_var("", "no-prompt"), ctx.no_prompt.to_string()),
+    (config::env_var("", "verbosity"), ctx.verbosity.to_string()),
+  ];
+  if let Some(model) = model {
+    env.push((config::env_var("", "model"), model.to_string()));
+  }
+  if !serial_numbers.is_empty() {
+    let var = config::env_var("", "serial-numbers");
+    env.push((var, serial_numbers.join(",")));
+  }
+  Ok(env)
+}
+
+/// Run the extension executable at the given path with the given
+/// arguments, passing on the given context through the environment.
+///
+/// The device matching the context is resolved before running the
+/// extension. Failure to do so is not an error, as not every extension
+/// needs to access a device; the extension then merely receives the
+/// settings of the context.
+///
+/// A non-zero exit status of the extension is reported as an
+/// `Error::ExitStatus`.
+#[cfg(feature = "extensions")]
+pub fn run(ctx: &args::ExecCtx, path: &path::Path, args: &[String]) -> Result<()> {
+  let device = commands::resolve_device(ctx).ok();
+  // Extensions typically run nitrocli themselves.
+  commands::unlock_device(ctx);
+  info!("Executing extension {}", path.display());
+  let status = process::Command::new(path)
+    .args(args)
+    .envs(context_env(ctx, device)?)
+    .status()
+    .map_err(|err| {
+      let error = format!("Failed to execute extension {}: {}", path.display(), err);
+      Error::Error(error)
+    })?;
+
+  match status.code() {
+    Some(0) => Ok(()),
+    Some(code) => Err(Error::ExitStatus(code)),
+    None => {
+      let error = format!("Extension {} was terminated by a signal", path.display());
+      Err(Error::Error(error))
+    }
+  }
+}
+
+#[cfg(all(test, feature = "extensions"))]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn find_executable() {
+    let dir = env::temp_dir().join(format!("nitrocli-extensions-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let (first, second) = (dir.join("first"), dir.join("second"));
+    fs::create_dir_all(&first).unwrap();
+    fs::create_dir_all(&second).unwrap();
+
+    let write = |path: path::PathBuf, mode| {
+      fs::write(&path, "#!/bin/sh\n").unwrap();
+      fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+      path
+    };
+    let _ = write(first.join("nitrocli-foo"), 0o644);
+    let foo = write(second.join("nitrocli-foo"), 0o755);
+    let dirs = vec![first.clone(), second.clone()];
+
+    assert_eq!(find_in("foo", dirs.clone()), Some(foo));
+    assert_eq!(find_in("bar", dirs.clone()), None);
+    assert_eq!(find_in("../second/nitrocli-foo", dirs), None);
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn list_and_describe() {
+    let dir = env::temp_dir().join(format!("nitrocli-list-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let (first, second) = (dir.join("first"), dir.join("second"));
+    fs::create_dir_all(&first).unwrap();
+    fs::create_dir_all(&second).unwrap();
+
+    let write = |path: path::PathBuf, script: &str| {
+      fs::write(&path, script).unwrap();
+      fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+      path
+    };
+    let foo = write(
+      first.join("nitrocli-foo"),
+      "#!/bin/sh\necho 'Do foo things'\n",
+    );
+    let _ = write(second.join("nitrocli-foo"), "#!/bin/sh\n");
+    let bar = write(second.join("nitrocli-bar"), "#!/bin/sh\nexit 1\n");
+    let _ = write(second.join("nitrocli-"), "#!/bin/sh\n");
+    let _ = write(second.join("other"), "#!/bin/sh\n");
+
+    let extensions = list_in(vec![first.clone(), second.clone()]);
+    let expected = vec![
+      ("bar".to_string(), bar.clone()),
+      ("foo".to_string(), foo.clone()),
+    ];
+    assert_eq!(extensions, expected);
+    assert_eq!(description(&foo), Some("Do foo things".to_string()));
+    assert_eq!(description(&bar), None);
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
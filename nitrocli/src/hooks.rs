@@ -0,0 +1,267 @@
+// hooks.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::fmt;
+use std::process;
+use std::result;
+use std::thread;
+use std::time;
+
+use crate::args;
+use crate::commands;
+use crate::config;
+use crate::error::Error;
+use crate::extensions;
+use crate::nicknames;
+use crate::watchdog;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The configuration table containing the hooks.
+pub const SECTION: &str = "hooks";
+
+/// The interval at which a running hook is checked for having finished
+/// or timed out, in milliseconds.
+const POLL_INTERVAL: u64 = 10;
+
+/// The point in time at which a hook is run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+  /// Before the execution of a command.
+  Pre,
+  /// After the successful execution of a command.
+  Post,
+}
+
+impl fmt::Display for Stage {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match *self {
+      Stage::Pre => write!(f, "pre"),
+      Stage::Post => write!(f, "post"),
+    }
+  }
+}
+
+/// A change of the devices connected, as detected by the monitor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceEvent {
+  /// A device was plugged in.
+  Attach,
+  /// A device was unplugged.
+  Detach,
+}
+
+impl fmt::Display for DeviceEvent {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match *self {
+      DeviceEvent::Attach => write!(f, "on-attach"),
+      DeviceEvent::Detach => write!(f, "on-detach"),
+    }
+  }
+}
+
+/// Retrieve the configuration keys of the hooks for the given commands,
+/// along with the ones of the device events.
+pub fn config_keys(commands: &[&str]) -> Vec<String> {
+  let mut keys = commands
+    .iter()
+    .flat_map(|command| {
+      vec![
+        format!("{}-{}", Stage::Pre, command),
+        format!("{}-{}", Stage::Post, command),
+      ]
+    })
+    .collect::<Vec<_>>();
+  keys.push(DeviceEvent::Attach.to_string());
+  keys.push(DeviceEvent::Detach.to_string());
+  keys
+}
+
+/// Check that the given configuration only contains hooks for the
+/// given commands.
+pub fn check_config(config: &config::Config, commands: &[&str]) -> Result<()> {
+  let keys = config_keys(commands);
+  for (key, value) in config.entries(SECTION) {
+    if !keys.iter().any(|k| k == key) {
+      let error = format!("Invalid configuration key {}.{}", SECTION, key);
+      return Err(Error::Error(error));
+    }
+    match value {
+      config::Value::String(_) => (),
+      _ => return Err(config::type_error(SECTION, key, value, "a string")),
+    }
+  }
+  Ok(())
+}
+
+/// Run the given hook using the shell, with the given additional
+/// environment variables.
+///
+/// The hook is killed if the command times out while it runs.
+fn run_hook(name: &str, hook: &str, env: Vec<(String, String)>) -> Result<()> {
+  info!("Running the {} hook: {}", name, hook);
+  let mut child = process::Command::new("sh")
+    .arg("-c")
+    .arg(hook)
+    .envs(env)
+    .spawn()
+    .map_err(|err| Error::Error(format!("Failed to run the {} hook: {}", name, err)))?;
+  let status = loop {
+    if let Some(status) = child.try_wait()? {
+      break status;
+    }
+    if watchdog::expired() {
+      let _ = child.kill();
+      let _ = child.wait();
+      return Err(Error::TimedOut);
+    }
+    thread::sleep(time::Duration::from_millis(POLL_INTERVAL));
+  };
+
+  match status.code() {
+    Some(0) => Ok(()),
+    Some(code) => {
+      let error = format!("The {} hook failed with exit status {}", name, code);
+      Err(Error::Error(error))
+    }
+    None => {
+      let error = format!("The {} hook was terminated by a signal", name);
+      Err(Error::Error(error))
+    }
+  }
+}
+
+/// Run the hook configured for the given stage of the given command,
+/// if any.
+///
+/// The hook is provided with the same environment as extensions, plus
+/// the name of the command in `NITROCLI_COMMAND`. The device is only
+/// connected to for that purpose if the command uses a device; the hooks
+/// of other commands merely receive the device connected to already, if
+/// any. An error is returned if the hook fails. In dry-run mode, the
+/// hook is printed instead.
+pub fn run(ctx: &args::ExecCtx, command: &str, stage: Stage, uses_device: bool) -> Result<()> {
+  let name = format!("{}-{}", stage, command);
+  let hook = match ctx.config.get_string(SECTION, &name)? {
+    Some(hook) => hook,
+    None => return Ok(()),
+  };
+  if commands::dry_run(ctx, &format!("run the {} hook: {}", name, hook)) {
+    return Ok(());
+  }
+
+  let device = if uses_device {
+    commands::resolve_device(ctx).ok()
+  } else {
+    commands::connected_device(ctx)
+  };
+  // The hook may well run nitrocli itself.
+  commands::unlock_device(ctx);
+  let mut env = extensions::context_env(ctx, device)?;
+  env.push(("NITROCLI_COMMAND".to_string(), command.to_string()));
+  run_hook(&name, hook, env)
+}
+
+/// Run the hook configured for the given event of the given device, if
+/// any.
+///
+/// The hook is provided with the same environment as extensions, with
+/// the model and, if known, the serial number of the device, plus its
+/// nickname, if it has one, in `NITROCLI_NICKNAME` and its path in
+/// sysfs, if given, in `NITROCLI_DEVPATH`. The device is not connected
+/// to while the hook runs, so that the hook can run nitrocli itself. An
+/// error is returned if the hook fails. In dry-run mode, the hook is
+/// printed instead.
+pub fn run_device(
+  ctx: &args::ExecCtx,
+  event: DeviceEvent,
+  model: args::DeviceModel,
+  serial_number: Option<&str>,
+  devpath: Option<&str>,
+) -> Result<()> {
+  let name = event.to_string();
+  let hook = match ctx.config.get_string(SECTION, &name)? {
+    Some(hook) => hook,
+    None => return Ok(()),
+  };
+  let device = serial_number.or(devpath).unwrap_or("the device");
+  let action = format!("run the {} hook for {}: {}", name, device, hook);
+  if commands::dry_run(ctx, &action) {
+    return Ok(());
+  }
+
+  let serial = serial_number.unwrap_or_default().to_string();
+  let mut env = extensions::context_env(ctx, Some((model, serial)))?;
+  match serial_number {
+    Some(serial_number) => {
+      if let Some(nickname) = nicknames::Nicknames::load()?.name(serial_number) {
+        env.push(("NITROCLI_NICKNAME".to_string(), nickname.to_string()));
+      }
+    }
+    // The serial numbers selected for the monitor do not describe the
+    // device.
+    None => {
+      let var = config::env_var("", "serial-numbers");
+      env.retain(|(name, _)| *name != var);
+    }
+  }
+  if let Some(devpath) = devpath {
+    env.push(("NITROCLI_DEVPATH".to_string(), devpath.to_string()));
+  }
+  run_hook(&name, hook, env)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn check_hooks() {
+    let commands = ["close", "open"];
+    let config = "[hooks]\npost-open = 'mount'\npre-close = 'umount'"
+      .parse::<config::Config>()
+      .unwrap();
+    assert!(check_config(&config, &commands).is_ok());
+
+    let config = "[hooks]\npost-foo = 'mount'"
+      .parse::<config::Config>()
+      .unwrap();
+    assert!(check_config(&config, &commands).is_err());
+
+    let config = "[hooks]\npost-open = 1".parse::<config::Config>().unwrap();
+    assert!(check_config(&config, &commands).is_err());
+
+    let config = "[hooks]\non-attach = 'open'\non-detach = 'log'"
+      .parse::<config::Config>()
+      .unwrap();
+    assert!(check_config(&config, &commands).is_ok());
+  }
+
+  #[test]
+  fn run_hooks() {
+    let env = vec![("NITROCLI_COMMAND".to_string(), "open".to_string())];
+    assert!(run_hook("post-open", "test \"$NITROCLI_COMMAND\" = open", env).is_ok());
+
+    let err = run_hook("pre-close", "exit 3", Vec::new()).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "The pre-close hook failed with exit status 3"
+    );
+  }
+}
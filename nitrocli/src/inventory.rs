@@ -0,0 +1,236 @@
+// inventory.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::result;
+use std::str;
+
+use crate::error::Error;
+use crate::storage;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The time in seconds for which a device is not recorded as seen
+/// again, so that not every invocation has to write the inventory.
+const SEEN_INTERVAL: u64 = 60;
+
+/// The facts about a device, as recorded when it was last seen.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Entry {
+  /// The model of the device, `pro` or `storage`.
+  pub model: Option<String>,
+  /// The firmware version, e.g., `0.53`.
+  pub firmware: Option<String>,
+  pub user_retry_count: Option<u8>,
+  pub admin_retry_count: Option<u8>,
+  /// The time the device was last connected to.
+  pub last_seen: u64,
+}
+
+/// An inventory of the devices the program connected to, indexed by
+/// their serial numbers.
+///
+/// The model and the time a device was last seen are recorded whenever
+/// the program connects to it, while the other facts are only queried
+/// by `fleet report`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Inventory {
+  entries: BTreeMap<String, Entry>,
+}
+
+impl Inventory {
+  /// Load the inventory from its file.
+  pub fn load() -> Result<Self> {
+    let data = storage::INVENTORY.read()?.unwrap_or_default();
+    // The inventory is parsed leniently, so parsing cannot fail.
+    Ok(data.parse().unwrap())
+  }
+
+  /// Save the inventory to its file.
+  pub fn save(&self) -> Result<()> {
+    storage::INVENTORY.write(&self.to_string())
+  }
+
+  /// Record that the device with the given serial number and model was
+  /// seen at the given time.
+  ///
+  /// Returns whether the inventory changed, which it does not if the
+  /// device was seen within `SEEN_INTERVAL` already.
+  pub fn seen(&mut self, serial: &str, model: &str, now: u64) -> bool {
+    let entry = self.entries.entry(serial.to_string()).or_default();
+    let same_model = match entry.model {
+      Some(ref m) => m == model,
+      None => false,
+    };
+    if same_model && now.saturating_sub(entry.last_seen) < SEEN_INTERVAL {
+      return false;
+    }
+    entry.model = Some(model.to_string());
+    entry.last_seen = now;
+    true
+  }
+
+  /// Replace the facts about the device with the given serial number.
+  pub fn record(&mut self, serial: &str, entry: Entry) {
+    let _ = self.entries.insert(serial.to_string(), entry);
+  }
+
+  /// Retrieve the devices in the inventory along with their facts.
+  pub fn iter(&self) -> impl Iterator<Item = (&str, &Entry)> {
+    self
+      .entries
+      .iter()
+      .map(|(serial, entry)| (serial.as_str(), entry))
+  }
+}
+
+/// Format the given optional fact, using `-` if it is unknown.
+fn format_fact<T>(fact: &Option<T>) -> String
+where
+  T: fmt::Display,
+{
+  match fact {
+    Some(fact) => fact.to_string(),
+    None => "-".to_string(),
+  }
+}
+
+/// Parse the given optional fact, formatted by `format_fact`.
+fn parse_fact<T>(fact: &str) -> Option<Option<T>>
+where
+  T: str::FromStr,
+{
+  match fact {
+    "-" => Some(None),
+    _ => fact.parse().ok().map(Some),
+  }
+}
+
+/// The inventory is stored with one line per device, containing the
+/// serial number, the time it was last seen, its model, its firmware
+/// version, and its user and admin retry counts, separated by a space.
+impl fmt::Display for Inventory {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for (serial, entry) in &self.entries {
+      writeln!(
+        f,
+        "{} {} {} {} {} {}",
+        serial,
+        entry.last_seen,
+        format_fact(&entry.model),
+        format_fact(&entry.firmware),
+        format_fact(&entry.user_retry_count),
+        format_fact(&entry.admin_retry_count)
+      )?;
+    }
+    Ok(())
+  }
+}
+
+impl str::FromStr for Inventory {
+  type Err = ();
+
+  /// Parse an inventory, ignoring malformed lines.
+  fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+    let mut inventory = Inventory::default();
+    for line in s.lines() {
+      let parts = line.split(' ').collect::<Vec<_>>();
+      let parsed = match parts.as_slice() {
+        [serial, last_seen, model, firmware, user, admin] => (
+          serial,
+          last_seen.parse::<u64>().ok(),
+          parse_fact(model),
+          parse_fact(firmware),
+          parse_fact(user),
+          parse_fact(admin),
+        ),
+        _ => continue,
+      };
+      if let (serial, Some(last_seen), Some(model), Some(firmware), Some(user), Some(admin)) =
+        parsed
+      {
+        let entry = Entry {
+          model,
+          firmware,
+          user_retry_count: user,
+          admin_retry_count: admin,
+          last_seen,
+        };
+        inventory.record(serial, entry);
+      }
+    }
+    Ok(inventory)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn seen_devices() {
+    let mut inventory = Inventory::default();
+    assert!(inventory.seen("0x1", "pro", 100));
+    assert!(!inventory.seen("0x1", "pro", 159));
+    assert!(inventory.seen("0x1", "pro", 160));
+    assert!(inventory.seen("0x1", "storage", 161));
+    let entries = inventory.iter().collect::<Vec<_>>();
+    assert_eq!(
+      entries,
+      vec![(
+        "0x1",
+        &Entry {
+          model: Some("storage".to_string()),
+          last_seen: 161,
+          ..Default::default()
+        }
+      )]
+    );
+  }
+
+  #[test]
+  fn round_trip() {
+    let mut inventory = Inventory::default();
+    assert!(inventory.seen("0x1", "pro", 100));
+    let entry = Entry {
+      model: Some("storage".to_string()),
+      firmware: Some("0.53".to_string()),
+      user_retry_count: Some(3),
+      admin_retry_count: Some(2),
+      last_seen: 200,
+    };
+    inventory.record("0x2", entry);
+    let s = inventory.to_string();
+    assert_eq!(s, "0x1 100 pro - - -\n0x2 200 storage 0.53 3 2\n");
+    assert_eq!(s.parse::<Inventory>().unwrap(), inventory);
+  }
+
+  #[test]
+  fn parse_malformed() {
+    let inventory = "0x1 x pro - - -\n0x2 1 pro - -\n0x3 1 pro - 4 x\n0x4 1 - - - -\n"
+      .parse::<Inventory>()
+      .unwrap();
+    let serials = inventory
+      .iter()
+      .map(|(serial, _)| serial)
+      .collect::<Vec<_>>();
+    assert_eq!(serials, vec!["0x4"]);
+  }
+}
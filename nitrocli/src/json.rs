@@ -0,0 +1,408 @@
+// json.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::char;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::iter;
+use std::result;
+use std::str;
+
+use crate::error::Error;
+
+type Result<T> = result::Result<T, Error>;
+
+/// A JSON value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+  /// The `null` value.
+  Null,
+  /// A boolean.
+  Bool(bool),
+  /// A number.
+  Number(f64),
+  /// A string.
+  String(String),
+  /// An array of values.
+  Array(Vec<Value>),
+  /// An object, with its members sorted by name.
+  Object(BTreeMap<String, Value>),
+}
+
+impl Value {
+  /// Create an object from the given members.
+  pub fn object<I, K>(members: I) -> Self
+  where
+    I: IntoIterator<Item = (K, Value)>,
+    K: Into<String>,
+  {
+    Value::Object(
+      members
+        .into_iter()
+        .map(|(key, value)| (key.into(), value))
+        .collect(),
+    )
+  }
+
+  /// Retrieve the member with the given name, if this value is an
+  /// object containing it.
+  pub fn get(&self, key: &str) -> Option<&Value> {
+    match self {
+      Value::Object(members) => members.get(key),
+      _ => None,
+    }
+  }
+
+  /// Retrieve the string this value holds, if any.
+  pub fn as_str(&self) -> Option<&str> {
+    match self {
+      Value::String(s) => Some(s),
+      _ => None,
+    }
+  }
+
+  /// Retrieve the boolean this value holds, if any.
+  pub fn as_bool(&self) -> Option<bool> {
+    match self {
+      Value::Bool(b) => Some(*b),
+      _ => None,
+    }
+  }
+
+  /// Retrieve the number this value holds, if it is a non-negative
+  /// integer.
+  pub fn as_u64(&self) -> Option<u64> {
+    match self {
+      Value::Number(n) if *n >= 0.0 && n.fract() == 0.0 && *n < 2f64.powi(64) => Some(*n as u64),
+      _ => None,
+    }
+  }
+
+  /// Retrieve the values of this value, if it is an array.
+  #[cfg(any(feature = "otp", feature = "storage", feature = "virtual", test))]
+  pub fn as_array(&self) -> Option<&[Value]> {
+    match self {
+      Value::Array(values) => Some(values),
+      _ => None,
+    }
+  }
+}
+
+impl From<bool> for Value {
+  fn from(b: bool) -> Self {
+    Value::Bool(b)
+  }
+}
+
+impl From<u64> for Value {
+  fn from(n: u64) -> Self {
+    Value::Number(n as f64)
+  }
+}
+
+impl From<&str> for Value {
+  fn from(s: &str) -> Self {
+    Value::String(s.to_string())
+  }
+}
+
+impl From<String> for Value {
+  fn from(s: String) -> Self {
+    Value::String(s)
+  }
+}
+
+/// Write the given string as a JSON string literal.
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+  write!(f, "\"")?;
+  for c in s.chars() {
+    match c {
+      '"' => write!(f, "\\\"")?,
+      '\\' => write!(f, "\\\\")?,
+      '\n' => write!(f, "\\n")?,
+      '\r' => write!(f, "\\r")?,
+      '\t' => write!(f, "\\t")?,
+      c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+      c => write!(f, "{}", c)?,
+    }
+  }
+  write!(f, "\"")
+}
+
+/// Values are formatted compactly, without any whitespace.
+impl fmt::Display for Value {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Value::Null => write!(f, "null"),
+      Value::Bool(b) => write!(f, "{}", b),
+      Value::Number(n) if n.is_finite() => write!(f, "{}", n),
+      Value::Number(_) => write!(f, "null"),
+      Value::String(s) => write_string(f, s),
+      Value::Array(values) => {
+        write!(f, "[")?;
+        for (i, value) in values.iter().enumerate() {
+          if i > 0 {
+            write!(f, ",")?;
+          }
+          write!(f, "{}", value)?;
+        }
+        write!(f, "]")
+      }
+      Value::Object(members) => {
+        write!(f, "{{")?;
+        for (i, (key, value)) in members.iter().enumerate() {
+          if i > 0 {
+            write!(f, ",")?;
+          }
+          write_string(f, key)?;
+          write!(f, ":{}", value)?;
+        }
+        write!(f, "}}")
+      }
+    }
+  }
+}
+
+/// Create an error for invalid JSON text.
+fn error(msg: &str) -> Error {
+  Error::Error(format!("Invalid JSON: {}", msg))
+}
+
+/// A parser for JSON text.
+struct Parser<'s> {
+  chars: iter::Peekable<str::Chars<'s>>,
+}
+
+impl<'s> Parser<'s> {
+  fn skip_whitespace(&mut self) {
+    while let Some(' ') | Some('\t') | Some('\n') | Some('\r') = self.chars.peek() {
+      let _ = self.chars.next();
+    }
+  }
+
+  fn expect(&mut self, expected: &str) -> Result<()> {
+    for c in expected.chars() {
+      if self.chars.next() != Some(c) {
+        return Err(error(&format!("expected {}", expected)));
+      }
+    }
+    Ok(())
+  }
+
+  fn parse_value(&mut self) -> Result<Value> {
+    self.skip_whitespace();
+    match self.chars.peek() {
+      Some('n') => self.expect("null").map(|_| Value::Null),
+      Some('t') => self.expect("true").map(|_| Value::Bool(true)),
+      Some('f') => self.expect("false").map(|_| Value::Bool(false)),
+      Some('"') => self.parse_string().map(Value::String),
+      Some('[') => self.parse_array(),
+      Some('{') => self.parse_object(),
+      Some(c) if *c == '-' || c.is_ascii_digit() => self.parse_number(),
+      Some(c) => Err(error(&format!("unexpected character {}", c))),
+      None => Err(error("unexpected end of input")),
+    }
+  }
+
+  fn parse_number(&mut self) -> Result<Value> {
+    let mut number = String::new();
+    while let Some(&c) = self.chars.peek() {
+      if c.is_ascii_digit() || c == '-' || c == '+' || c == '.' || c == 'e' || c == 'E' {
+        number.push(c);
+        let _ = self.chars.next();
+      } else {
+        break;
+      }
+    }
+    number
+      .parse()
+      .map(Value::Number)
+      .map_err(|_| error(&format!("invalid number {}", number)))
+  }
+
+  fn parse_hex4(&mut self) -> Result<u32> {
+    let mut value = 0;
+    for _ in 0..4 {
+      let digit = self
+        .chars
+        .next()
+        .and_then(|c| c.to_digit(16))
+        .ok_or_else(|| error("invalid unicode escape"))?;
+      value = value * 16 + digit;
+    }
+    Ok(value)
+  }
+
+  fn parse_string(&mut self) -> Result<String> {
+    self.expect("\"")?;
+    let mut s = String::new();
+    loop {
+      match self.chars.next() {
+        Some('"') => return Ok(s),
+        Some('\\') => {
+          let c = match self.chars.next() {
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some('/') => '/',
+            Some('b') => '\x08',
+            Some('f') => '\x0c',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('u') => {
+              let mut code = self.parse_hex4()?;
+              // Characters outside of the basic multilingual plane are
+              // encoded as a surrogate pair.
+              if let 0xd800..=0xdbff = code {
+                self.expect("\\u")?;
+                let low = self.parse_hex4()?;
+                code = 0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+              }
+              char::from_u32(code).ok_or_else(|| error("invalid unicode escape"))?
+            }
+            _ => return Err(error("invalid escape sequence")),
+          };
+          s.push(c);
+        }
+        Some(c) => s.push(c),
+        None => return Err(error("unterminated string")),
+      }
+    }
+  }
+
+  fn parse_array(&mut self) -> Result<Value> {
+    self.expect("[")?;
+    let mut values = Vec::new();
+    self.skip_whitespace();
+    if self.chars.peek() == Some(&']') {
+      let _ = self.chars.next();
+      return Ok(Value::Array(values));
+    }
+    loop {
+      values.push(self.parse_value()?);
+      self.skip_whitespace();
+      match self.chars.next() {
+        Some(',') => continue,
+        Some(']') => return Ok(Value::Array(values)),
+        _ => return Err(error("expected , or ]")),
+      }
+    }
+  }
+
+  fn parse_object(&mut self) -> Result<Value> {
+    self.expect("{")?;
+    let mut members = BTreeMap::new();
+    self.skip_whitespace();
+    if self.chars.peek() == Some(&'}') {
+      let _ = self.chars.next();
+      return Ok(Value::Object(members));
+    }
+    loop {
+      self.skip_whitespace();
+      let key = self.parse_string()?;
+      self.skip_whitespace();
+      self.expect(":")?;
+      let value = self.parse_value()?;
+      let _ = members.insert(key, value);
+      self.skip_whitespace();
+      match self.chars.next() {
+        Some(',') => continue,
+        Some('}') => return Ok(Value::Object(members)),
+        _ => return Err(error("expected , or }")),
+      }
+    }
+  }
+}
+
+impl str::FromStr for Value {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    let mut parser = Parser {
+      chars: s.chars().peekable(),
+    };
+    let value = parser.parse_value()?;
+    parser.skip_whitespace();
+    match parser.chars.next() {
+      None => Ok(value),
+      Some(_) => Err(error("trailing characters")),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse() {
+    let json = r#" {"a": [1, -2.5e1, true, null], "b": "x\"ä\ud83d\ude00\n", "c": {}} "#;
+    let value = json.parse::<Value>().unwrap();
+    let expected = Value::object(vec![
+      (
+        "a",
+        Value::Array(vec![
+          Value::Number(1.0),
+          Value::Number(-25.0),
+          Value::Bool(true),
+          Value::Null,
+        ]),
+      ),
+      ("b", Value::from("x\"ä😀\n")),
+      ("c", Value::Object(BTreeMap::new())),
+    ]);
+    assert_eq!(value, expected);
+  }
+
+  #[test]
+  fn parse_invalid() {
+    assert!("".parse::<Value>().is_err());
+    assert!("[1,]".parse::<Value>().is_err());
+    assert!("{\"a\" 1}".parse::<Value>().is_err());
+    assert!("\"abc".parse::<Value>().is_err());
+    assert!("true false".parse::<Value>().is_err());
+  }
+
+  #[test]
+  fn accessors() {
+    assert_eq!(Value::from(true).as_bool(), Some(true));
+    assert_eq!(Value::Null.as_bool(), None);
+    assert_eq!(Value::Number(8.0).as_u64(), Some(8));
+    assert_eq!(Value::Number(-1.0).as_u64(), None);
+    assert_eq!(Value::Number(1.5).as_u64(), None);
+    assert_eq!(Value::from("8").as_u64(), None);
+    assert_eq!(
+      Value::Array(vec![Value::Null]).as_array(),
+      Some(&[Value::Null][..])
+    );
+  }
+
+  #[test]
+  fn format() {
+    let value = Value::object(vec![
+      ("code", Value::from("123456")),
+      ("count", Value::from(3u64)),
+      ("list", Value::Array(vec![Value::Null, Value::from(false)])),
+      ("text", Value::from("a\"b\\c\n\u{1}")),
+    ]);
+    let json = r#"{"code":"123456","count":3,"list":[null,false],"text":"a\"b\\c\n\u0001"}"#;
+    assert_eq!(value.to_string(), json);
+    assert_eq!(json.parse::<Value>().unwrap(), value);
+  }
+}
@@ -0,0 +1,138 @@
+// keychain.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::io::Write;
+use std::process;
+use std::result;
+
+use crate::error::Error;
+use crate::secret::Secret;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The service the items stored by the program belong to.
+const SERVICE: &str = "nitrocli";
+
+/// The exit code of `security` if the requested item does not exist.
+const ERR_ITEM_NOT_FOUND: i32 = 44;
+
+/// Append the given option and its argument, quoted for a command read
+/// by `security -i`, to the given command.
+fn push_option(command: &mut Secret, option: &str, arg: &[u8]) {
+  command.extend_from_slice(b" ");
+  command.extend_from_slice(option.as_bytes());
+  command.extend_from_slice(b" \"");
+  for &byte in arg {
+    if byte == b'\\' || byte == b'"' {
+      command.push(b'\\');
+    }
+    command.push(byte);
+  }
+  command.push(b'"');
+}
+
+/// Run `security` with the given arguments.
+fn security(args: &[&str]) -> Result<process::Output> {
+  trace!("Running security {}", args.join(" "));
+  process::Command::new("security")
+    .args(args)
+    .stdin(process::Stdio::null())
+    .output()
+    .map_err(|err| Error::Error(format!("Could not access the keychain: {}", err)))
+}
+
+/// Create the error reporting the failure of the given `security`
+/// invocation.
+fn error(what: &str, output: &process::Output) -> Error {
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  Error::Error(format!("Could not {}: {}", what, stderr.trim()))
+}
+
+/// Retrieve the secret stored for the given account, if any.
+pub fn find(account: &str) -> Result<Option<Secret>> {
+  let output = security(&["find-generic-password", "-s", SERVICE, "-a", account, "-w"])?;
+  match output.status.code() {
+    Some(0) => {
+      let mut secret = Secret::from(output.stdout);
+      if secret.last() == Some(b'\n') {
+        let _ = secret.pop();
+      }
+      Ok(Some(secret))
+    }
+    Some(ERR_ITEM_NOT_FOUND) => Ok(None),
+    _ => Err(error("read from the keychain", &output)),
+  }
+}
+
+/// Store the given secret for the given account, replacing any secret
+/// stored already.
+///
+/// The secret is passed to `security` through its standard input, as
+/// its arguments are visible to other users of the system.
+pub fn store(account: &str, secret: &Secret) -> Result<()> {
+  let mut command = Secret::from("add-generic-password -U");
+  push_option(&mut command, "-s", SERVICE.as_bytes());
+  push_option(&mut command, "-a", account.as_bytes());
+  push_option(&mut command, "-w", secret.as_bytes());
+  command.push(b'\n');
+
+  let mut child = process::Command::new("security")
+    .arg("-i")
+    .stdin(process::Stdio::piped())
+    .stdout(process::Stdio::null())
+    .stderr(process::Stdio::piped())
+    .spawn()
+    .map_err(|err| Error::Error(format!("Could not access the keychain: {}", err)))?;
+  if let Some(mut stdin) = child.stdin.take() {
+    stdin.write_all(command.as_bytes())?;
+  }
+  let output = child.wait_with_output()?;
+  // In interactive mode, failing commands do not affect the exit code
+  // but only print an error.
+  if output.status.success() && output.stderr.is_empty() {
+    Ok(())
+  } else {
+    Err(error("write to the keychain", &output))
+  }
+}
+
+/// Delete the secret stored for the given account, if any.
+pub fn delete(account: &str) -> Result<()> {
+  let output = security(&["delete-generic-password", "-s", SERVICE, "-a", account])?;
+  match output.status.code() {
+    Some(0) | Some(ERR_ITEM_NOT_FOUND) => Ok(()),
+    _ => Err(error("delete from the keychain", &output)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn quote_arguments() {
+    let mut command = Secret::from("add-generic-password");
+    push_option(&mut command, "-a", b"user");
+    push_option(&mut command, "-w", b"12 \"34\\");
+    assert_eq!(
+      command.as_str().unwrap(),
+      "add-generic-password -a \"user\" -w \"12 \\\"34\\\\\""
+    );
+  }
+}
@@ -0,0 +1,178 @@
+// lock.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path;
+use std::process;
+use std::result;
+use std::thread;
+use std::time;
+
+use crate::config;
+use crate::error::Context;
+use crate::error::Error;
+use crate::storage;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The configuration keys controlling device locking, along with the
+/// kinds of their values.
+pub const CONFIG_KEYS: &[(&str, &str, config::Kind)] =
+  &[("lock", "timeout", config::Kind::Integer)];
+
+/// The time to wait for a device used by another process, in seconds,
+/// unless configured otherwise.
+const DEFAULT_TIMEOUT: u64 = 30;
+/// The interval at which the lock of a device used by another process
+/// is checked.
+const POLL_INTERVAL: u64 = 100;
+
+/// Retrieve the time to wait for a device used by another process
+/// configured in the given configuration.
+fn timeout(config: &config::Config) -> Result<time::Duration> {
+  match config.get_integer("lock", "timeout")? {
+    Some(timeout) if timeout < 0 => {
+      let error = format!(
+        "Invalid value for configuration key lock.timeout: {}",
+        timeout
+      );
+      Err(Error::Error(error))
+    }
+    Some(timeout) => Ok(time::Duration::from_secs(timeout as u64)),
+    None => Ok(time::Duration::from_secs(DEFAULT_TIMEOUT)),
+  }
+}
+
+/// Retrieve the path of the lock file for the device with the given
+/// serial number.
+fn lock_path(serial: &str) -> Result<path::PathBuf> {
+  Ok(
+    storage::Dir::Runtime
+      .path()?
+      .join(format!("device-{}.lock", serial)),
+  )
+}
+
+/// Try to place an exclusive lock on the given file without blocking.
+///
+/// Returns whether the lock was placed.
+fn try_lock(file: &fs::File) -> Result<bool> {
+  let rc = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+  if rc == 0 {
+    return Ok(true);
+  }
+  let err = io::Error::last_os_error();
+  match err.raw_os_error() {
+    Some(libc::EWOULDBLOCK) => Ok(false),
+    _ if err.kind() == io::ErrorKind::Interrupted => Ok(false),
+    _ => Err(err.into()),
+  }
+}
+
+/// Describe the holder of the lock file at the given path.
+fn describe_holder(path: &path::Path) -> String {
+  match fs::read_to_string(path) {
+    Ok(ref pid) if !pid.trim().is_empty() => format!("PID {}", pid.trim()),
+    _ => "another process".to_string(),
+  }
+}
+
+/// An advisory lock granting the current process exclusive use of a
+/// device.
+///
+/// The lock is held until the object is dropped. It is placed on a
+/// file in the runtime directory that also records the ID of the
+/// holding process.
+#[derive(Debug)]
+pub struct Lock {
+  /// The locked file.
+  file: fs::File,
+}
+
+impl Lock {
+  /// Lock the device with the given serial number.
+  ///
+  /// If another process uses the device, we wait for it to finish at
+  /// most for the time configured in the `lock` table of the given
+  /// configuration. If it does not finish in time, an error naming the
+  /// process is returned.
+  pub fn acquire(config: &config::Config, serial: &str) -> Result<Self> {
+    let timeout = timeout(config)?;
+    let path = lock_path(serial)?;
+    storage::create_parent(&path)?;
+    // The file must not be truncated before we hold the lock, as it
+    // records the ID of the holder.
+    let mut file = fs::OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .mode(0o600)
+      .open(&path)
+      .context(|| format!("Could not open {}", path.display()))?;
+
+    let start = time::Instant::now();
+    let mut waiting = false;
+    while !try_lock(&file)? {
+      let holder = describe_holder(&path);
+      if start.elapsed() >= timeout {
+        let error = format!("The device {} is in use by {}", serial, holder);
+        return Err(Error::Error(error));
+      }
+      if !waiting {
+        warn!(
+          "Waiting for the device {}, which is in use by {}",
+          serial, holder
+        );
+        waiting = true;
+      }
+      thread::sleep(time::Duration::from_millis(POLL_INTERVAL));
+    }
+
+    debug!("Locked the device {}", serial);
+    file.set_len(0)?;
+    write!(file, "{}", process::id())?;
+    Ok(Lock { file })
+  }
+}
+
+impl Drop for Lock {
+  fn drop(&mut self) {
+    let _ = unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn lock_timeout() {
+    let config = config::Config::default();
+    assert_eq!(timeout(&config).unwrap(), time::Duration::from_secs(30));
+    let config = "[lock]\ntimeout = 0".parse::<config::Config>().unwrap();
+    assert_eq!(timeout(&config).unwrap(), time::Duration::from_secs(0));
+    let config = "[lock]\ntimeout = -1".parse::<config::Config>().unwrap();
+    assert!(timeout(&config).is_err());
+  }
+}
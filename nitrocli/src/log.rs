@@ -0,0 +1,441 @@
+// log.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::cell;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::result;
+use std::str;
+use std::time;
+
+use crate::args;
+use crate::config;
+use crate::error::Error;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The configuration keys controlling logging, along with the kinds of
+/// their values.
+pub const CONFIG_KEYS: &[(&str, &str, config::Kind)] = &[("", "log", config::Kind::String)];
+
+/// Log a message at the given level.
+macro_rules! log {
+  ($level:expr, $($arg:tt)+) => {
+    if $crate::log::enabled($level, module_path!()) {
+      $crate::log::log($level, module_path!(), format_args!($($arg)+))
+    }
+  };
+}
+
+/// Log a message about an error that does not end the program.
+#[allow(unused_macros)]
+macro_rules! error {
+  ($($arg:tt)+) => { log!($crate::log::Level::Error, $($arg)+) };
+}
+
+/// Log a message about a potential problem.
+macro_rules! warn {
+  ($($arg:tt)+) => { log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+/// Log a message about a step the program takes.
+macro_rules! info {
+  ($($arg:tt)+) => { log!($crate::log::Level::Info, $($arg)+) };
+}
+
+/// Log a message with details helpful for debugging.
+macro_rules! debug {
+  ($($arg:tt)+) => { log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+/// Log a message with all the details.
+macro_rules! trace {
+  ($($arg:tt)+) => { log!($crate::log::Level::Trace, $($arg)+) };
+}
+
+/// The level of a log message, ordered by increasing verbosity.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Level {
+  Error,
+  Warn,
+  Info,
+  Debug,
+  Trace,
+}
+
+impl Level {
+  /// Retrieve the level enabled by the given verbosity.
+  fn from_verbosity(verbosity: u64) -> Self {
+    match verbosity {
+      0 => Level::Warn,
+      1 => Level::Info,
+      2 => Level::Debug,
+      _ => Level::Trace,
+    }
+  }
+}
+
+impl fmt::Display for Level {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Level::Error => "ERROR",
+      Level::Warn => "WARN",
+      Level::Info => "INFO",
+      Level::Debug => "DEBUG",
+      Level::Trace => "TRACE",
+    };
+    f.pad(name)
+  }
+}
+
+/// Parse the most verbose level to log, with `None` disabling logging.
+fn parse_level(s: &str) -> result::Result<Option<Level>, ()> {
+  match s {
+    "off" => Ok(None),
+    "error" => Ok(Some(Level::Error)),
+    "warn" => Ok(Some(Level::Warn)),
+    "info" => Ok(Some(Level::Info)),
+    "debug" => Ok(Some(Level::Debug)),
+    "trace" => Ok(Some(Level::Trace)),
+    _ => Err(()),
+  }
+}
+
+/// The selection of messages to log.
+///
+/// A filter is given as a comma separated list of directives, each of
+/// which is either a level applying to all targets or a target and a
+/// level separated by `=`, e.g., `info,pinentry=trace`. Targets are
+/// module paths and also apply to the modules below them; the crate
+/// name may be omitted.
+#[derive(Debug, PartialEq)]
+struct Filter {
+  /// The most verbose level logged for targets without a directive.
+  default: Option<Level>,
+  /// The targets with a level of their own.
+  targets: Vec<(String, Option<Level>)>,
+}
+
+impl Filter {
+  /// Create a filter logging messages up to the given level.
+  fn new(level: Option<Level>) -> Self {
+    Filter {
+      default: level,
+      targets: Vec::new(),
+    }
+  }
+
+  /// Check whether a message of the given level and target passes the
+  /// filter.
+  fn enabled(&self, level: Level, target: &str) -> bool {
+    // Targets may be given without the crate name.
+    let short = target.find("::").map(|index| &target[index + 2..]);
+    let matches = |name: &str| {
+      Some(target).iter().chain(short.iter()).any(|path| {
+        path.starts_with(name) && (path.len() == name.len() || path[name.len()..].starts_with("::"))
+      })
+    };
+    // The most specific directive, i.e., the one with the longest
+    // target, takes precedence.
+    let max = self
+      .targets
+      .iter()
+      .filter(|(name, _)| matches(name))
+      .max_by_key(|(name, _)| name.len())
+      .map(|&(_, level)| level)
+      .unwrap_or(self.default);
+    match max {
+      Some(max) => level <= max,
+      None => false,
+    }
+  }
+}
+
+impl Default for Filter {
+  fn default() -> Self {
+    Filter::new(Some(Level::Warn))
+  }
+}
+
+impl str::FromStr for Filter {
+  type Err = String;
+
+  fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+    let mut filter = Filter::new(Some(Level::Warn));
+    for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+      let mut parts = directive.splitn(2, '=');
+      // `splitn` always yields at least one part.
+      let first = parts.next().unwrap().trim();
+      match parts.next() {
+        Some(level) => {
+          let level = parse_level(level.trim()).map_err(|()| directive.to_string())?;
+          filter.targets.push((first.to_string(), level));
+        }
+        None => match parse_level(first) {
+          Ok(level) => filter.default = level,
+          // A target on its own enables all its messages.
+          Err(()) => filter.targets.push((first.to_string(), Some(Level::Trace))),
+        },
+      }
+    }
+    Ok(filter)
+  }
+}
+
+thread_local! {
+  static FILTER: cell::RefCell<Filter> = Default::default();
+}
+
+/// Configure logging for the given context.
+///
+/// Messages are selected by the `log` setting, typically provided
+/// using the `NITROCLI_LOG` environment variable. Without it, the
+/// verbosity determines the level: warnings are logged by default and
+/// every `-v` enables the next more verbose level.
+pub fn init(ctx: &args::ExecCtx) -> Result<()> {
+  let filter = match ctx.config.get_string("", "log")? {
+    Some(spec) => spec
+      .parse::<Filter>()
+      .map_err(|directive| Error::Error(format!("Invalid log directive: {}", directive)))?,
+    None => Filter::new(Some(Level::from_verbosity(ctx.verbosity))),
+  };
+  FILTER.with(|f| *f.borrow_mut() = filter);
+  Ok(())
+}
+
+/// Check whether a message of the given level from the given target is
+/// logged.
+pub fn enabled(level: Level, target: &str) -> bool {
+  FILTER.with(|filter| filter.borrow().enabled(level, target))
+}
+
+/// Convert the given number of days since the Unix epoch into a date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+  // See http://howardhinnant.github.io/date_algorithms.html.
+  let z = days + 719_468;
+  let era = (if z >= 0 { z } else { z - 146_096 }) / 146_097;
+  let doe = z - era * 146_097;
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+  let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+  let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+  (year, month, day)
+}
+
+/// Convert the given date into the number of days since the Unix epoch.
+#[cfg_attr(not(feature = "otp"), allow(dead_code))]
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+  // See http://howardhinnant.github.io/date_algorithms.html.
+  let year = if month <= 2 { year - 1 } else { year };
+  let era = (if year >= 0 { year } else { year - 399 }) / 400;
+  let yoe = year - era * 400;
+  let mp = i64::from(if month > 2 { month - 3 } else { month + 9 });
+  let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+  let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+  era * 146_097 + doe - 719_468
+}
+
+/// Parse the given RFC 3339 timestamp, e.g.,
+/// `2020-02-29T12:00:00+01:00`, into the number of seconds since the
+/// Unix epoch.
+///
+/// Fractions of seconds are ignored. `None` is returned if the
+/// timestamp is malformed or before the epoch.
+#[cfg_attr(not(feature = "otp"), allow(dead_code))]
+pub fn parse_time(s: &str) -> Option<u64> {
+  let number = |start: usize, end: usize| {
+    s.get(start..end)
+      .filter(|digits| digits.bytes().all(|b| b.is_ascii_digit()))
+      .and_then(|digits| digits.parse::<u32>().ok())
+  };
+  let separators = [(4, b'-'), (7, b'-'), (13, b':'), (16, b':')];
+  let bytes = s.as_bytes();
+  if bytes.len() < 20
+    || !separators.iter().all(|&(index, sep)| bytes[index] == sep)
+    || !b"Tt ".contains(&bytes[10])
+  {
+    return None;
+  }
+
+  let (year, month, day) = (number(0, 4)?, number(5, 7)?, number(8, 10)?);
+  let (hour, minute, second) = (number(11, 13)?, number(14, 16)?, number(17, 19)?);
+  let days = days_from_civil(i64::from(year), month, day);
+  // Dates like February 30 are rejected, as they do not survive the
+  // round trip.
+  if civil_from_days(days) != (i64::from(year), month, day)
+    || hour > 23
+    || minute > 59
+    || second > 60
+  {
+    return None;
+  }
+
+  let mut rest = &s[19..];
+  if rest.starts_with('.') {
+    let digits = rest[1..].bytes().take_while(|b| b.is_ascii_digit()).count();
+    if digits == 0 {
+      return None;
+    }
+    rest = &rest[1 + digits..];
+  }
+  let offset = match rest {
+    "Z" | "z" => 0,
+    _ if rest.len() == 6 && rest.as_bytes()[3] == b':' => {
+      let offset = i64::from(number(s.len() - 5, s.len() - 3)? * 3600)
+        + i64::from(number(s.len() - 2, s.len())? * 60);
+      match rest.as_bytes()[0] {
+        b'+' => offset,
+        b'-' => -offset,
+        _ => return None,
+      }
+    }
+    _ => return None,
+  };
+
+  let time = days * 86400 + i64::from(hour * 3600 + minute * 60 + second) - offset;
+  if time >= 0 {
+    Some(time as u64)
+  } else {
+    None
+  }
+}
+
+/// Format the given time since the Unix epoch as an RFC 3339 timestamp
+/// in UTC.
+pub fn format_time(time: time::Duration) -> String {
+  let secs = time.as_secs();
+  let (year, month, day) = civil_from_days((secs / 86400) as i64);
+  let secs = secs % 86400;
+  format!(
+    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+    year,
+    month,
+    day,
+    secs / 3600,
+    secs / 60 % 60,
+    secs % 60,
+    time.subsec_millis()
+  )
+}
+
+/// Write a message of the given level from the given target to the
+/// standard error, prefixed with a timestamp.
+///
+/// Use the `error!`, `warn!`, `info!`, `debug!`, and `trace!` macros
+/// instead of calling this function directly.
+pub fn log(level: Level, target: &str, args: fmt::Arguments<'_>) {
+  let now = time::SystemTime::now()
+    .duration_since(time::UNIX_EPOCH)
+    .unwrap_or_default();
+  let stderr = io::stderr();
+  let _ = writeln!(
+    stderr.lock(),
+    "{} {:<5} {}: {}",
+    format_time(now),
+    level,
+    target,
+    args
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_filter() {
+    assert_eq!("".parse::<Filter>().unwrap(), Filter::default());
+    assert_eq!("off".parse::<Filter>().unwrap(), Filter::new(None));
+
+    let filter = "info, pinentry=trace,nitrocli::hooks=off,agent"
+      .parse::<Filter>()
+      .unwrap();
+    assert_eq!(filter.default, Some(Level::Info));
+    assert_eq!(
+      filter.targets,
+      vec![
+        ("pinentry".to_string(), Some(Level::Trace)),
+        ("nitrocli::hooks".to_string(), None),
+        ("agent".to_string(), Some(Level::Trace)),
+      ]
+    );
+
+    assert_eq!(
+      "pinentry=loud".parse::<Filter>().unwrap_err(),
+      "pinentry=loud"
+    );
+  }
+
+  #[test]
+  fn filter_targets() {
+    let filter = "warn,commands=debug,nitrocli::commands::otp=off"
+      .parse::<Filter>()
+      .unwrap();
+    assert!(filter.enabled(Level::Warn, "nitrocli::args"));
+    assert!(!filter.enabled(Level::Info, "nitrocli::args"));
+    assert!(filter.enabled(Level::Debug, "nitrocli::commands"));
+    assert!(filter.enabled(Level::Debug, "nitrocli::commands::pws"));
+    assert!(!filter.enabled(Level::Trace, "nitrocli::commands"));
+    assert!(!filter.enabled(Level::Error, "nitrocli::commands::otp"));
+    assert!(!filter.enabled(Level::Debug, "nitrocli::commandsx"));
+  }
+
+  #[test]
+  fn verbosity_levels() {
+    assert_eq!(Level::from_verbosity(0), Level::Warn);
+    assert_eq!(Level::from_verbosity(1), Level::Info);
+    assert_eq!(Level::from_verbosity(2), Level::Debug);
+    assert_eq!(Level::from_verbosity(7), Level::Trace);
+  }
+
+  #[test]
+  fn timestamps() {
+    assert_eq!(
+      format_time(time::Duration::from_secs(0)),
+      "1970-01-01T00:00:00.000Z"
+    );
+    assert_eq!(
+      format_time(time::Duration::from_millis(951_782_400_123)),
+      "2000-02-29T00:00:00.123Z"
+    );
+    assert_eq!(
+      format_time(time::Duration::from_secs(1_792_068_245)),
+      "2026-10-15T12:44:05.000Z"
+    );
+  }
+
+  #[test]
+  fn parse_timestamps() {
+    assert_eq!(parse_time("1970-01-01T00:00:00Z"), Some(0));
+    assert_eq!(parse_time("2000-02-29T00:00:00.123Z"), Some(951_782_400));
+    assert_eq!(parse_time("2026-10-15 14:44:05+02:00"), Some(1_792_068_245));
+    assert_eq!(parse_time("2026-10-15t10:14:05-02:30"), Some(1_792_068_245));
+    assert_eq!(parse_time("1970-01-01T00:00:00+00:01"), None);
+    assert_eq!(parse_time("2001-02-29T00:00:00Z"), None);
+    assert_eq!(parse_time("2000-01-01T24:00:00Z"), None);
+    assert_eq!(parse_time("2000-01-01T00:00:00"), None);
+    assert_eq!(parse_time("2000-01-01T00:00:00.Z"), None);
+    assert_eq!(parse_time("2000-01-01T00:00:00+0100"), None);
+    assert_eq!(parse_time("2000-1-01T00:00:00Z"), None);
+    assert_eq!(parse_time("1234567890"), None);
+  }
+}
@@ -68,176 +68,89 @@
 //! Nitrocli is a program providing a command line interface to certain
 //! commands of the Nitrokey Storage device.
 
+// The logging macros have to be defined before the modules using them.
+#[macro_use]
+mod log;
+
+#[cfg(feature = "otp")]
+mod agent;
+mod alias;
+mod args;
+mod audit;
+#[cfg(feature = "pws")]
+mod autotype;
+#[cfg(any(feature = "otp", feature = "pws"))]
+mod clipboard;
+mod commands;
+mod completions;
+mod config;
+mod devices;
 mod error;
+mod extensions;
+mod hooks;
+mod inventory;
+mod json;
+mod keychain;
+mod lock;
+mod manpage;
+mod metrics;
+mod module;
+mod monitor;
+#[cfg(any(feature = "otp", feature = "pws"))]
+mod native;
+mod nicknames;
+mod notify;
+#[cfg(any(feature = "otp", feature = "virtual"))]
+mod oath;
+mod pager;
+#[cfg(feature = "pws")]
+mod passphrase;
+#[cfg(any(feature = "otp", feature = "pws"))]
+mod picker;
 mod pinentry;
-
+mod policy;
+mod provision;
+#[cfg(any(feature = "otp", feature = "pws"))]
+mod registry;
+mod retry;
+mod secret;
+#[cfg(feature = "storage")]
+mod session;
+mod shell;
+mod signal;
+#[cfg(feature = "otp")]
+mod slots;
+mod storage;
+mod tempfile;
+mod tty;
+mod udev;
+#[cfg(target_os = "linux")]
+mod uevent;
+#[cfg(feature = "storage")]
+mod update;
+mod vault;
+#[cfg(feature = "virtual")]
+mod virtual_device;
+mod watchdog;
+
+use std::env;
 use std::process;
-use std::result;
-
-use nitrokey;
-
-use crate::error::Error;
-
-type Result<T> = result::Result<T, Error>;
-
-const PIN_TYPE: pinentry::PinType = pinentry::PinType::User;
-
-/// Create an `error::Error` with an error message of the format `msg: err`.
-fn get_error(msg: &str, err: &nitrokey::CommandError) -> Error {
-  Error::Error(format!("{}: {:?}", msg, err))
-}
-
-/// Connect to a Nitrokey Storage device and return it.
-fn get_storage_device() -> Result<nitrokey::Storage> {
-  nitrokey::Storage::connect()
-    .or_else(|_| Err(Error::Error("Nitrokey device not found".to_string())))
-}
-
-/// Return a string representation of the given volume status.
-fn get_volume_status(status: &nitrokey::VolumeStatus) -> &'static str {
-  if status.active {
-    if status.read_only {
-      "read-only"
-    } else {
-      "active"
-    }
-  } else {
-    "inactive"
-  }
-}
-
-/// Pretty print the response of a status command.
-fn print_status(status: &nitrokey::StorageStatus) {
-  // We omit displaying information about the smartcard here as this
-  // program really is only about the SD card portion of the device.
-  println!(
-    r#"Status:
-  SD card ID:        {id:#x}
-  firmware version:  {fwv0}.{fwv1}
-  firmware:          {fw}
-  storage keys:      {sk}
-  user retry count:  {urc}
-  admin retry count: {arc}
-  volumes:
-    unencrypted:     {vu}
-    encrypted:       {ve}
-    hidden:          {vh}"#,
-    id = status.serial_number_sd_card,
-    fwv0 = status.firmware_version_major,
-    fwv1 = status.firmware_version_minor,
-    fw = if status.firmware_locked {
-      "locked"
-    } else {
-      "unlocked"
-    },
-    sk = if status.stick_initialized {
-      "created"
-    } else {
-      "not created"
-    },
-    urc = status.user_retry_count,
-    arc = status.admin_retry_count,
-    vu = get_volume_status(&status.unencrypted_volume),
-    ve = get_volume_status(&status.encrypted_volume),
-    vh = get_volume_status(&status.hidden_volume),
-  );
-}
-
-/// Inquire the status of the nitrokey.
-fn status() -> Result<()> {
-  let status = get_storage_device()?
-    .get_status()
-    .map_err(|err| get_error("Getting Storage status failed", &err))?;
-
-  print_status(&status);
-  Ok(())
-}
-
-/// Open the encrypted volume on the nitrokey.
-fn open() -> Result<()> {
-  let device = get_storage_device()?;
-
-  let mut retry = 3;
-  let mut error_msg: Option<&str> = None;
-  loop {
-    // TODO: Rethink the usage of String::from_utf8_lossy here. We may
-    //       not want to silently modify the password!
-    let passphrase = pinentry::inquire_passphrase(PIN_TYPE, error_msg)?;
-    let passphrase = String::from_utf8_lossy(&passphrase);
-    match device.enable_encrypted_volume(&passphrase) {
-      Ok(()) => return Ok(()),
-      Err(err) => match err {
-        nitrokey::CommandError::WrongPassword => {
-          pinentry::clear_passphrase(PIN_TYPE)?;
-          retry -= 1;
-
-          if retry > 0 {
-            error_msg = Some("Wrong password, please reenter");
-            continue;
-          }
-          let error = "Opening encrypted volume failed: Wrong password";
-          return Err(Error::Error(error.to_string()));
-        }
-        err => return Err(get_error("Opening encrypted volume failed", &err)),
-      },
-    };
-  }
-}
-
-#[link(name = "c")]
-extern "C" {
-  fn sync();
-}
-
-/// Close the previously opened encrypted volume.
-fn close() -> Result<()> {
-  // Flush all filesystem caches to disk. We are mostly interested in
-  // making sure that the encrypted volume on the nitrokey we are
-  // about to close is not closed while not all data was written to
-  // it.
-  unsafe { sync() };
-
-  get_storage_device()?
-    .disable_encrypted_volume()
-    .map_err(|err| get_error("Closing encrypted volume failed", &err))
-}
-
-/// Clear the PIN stored when opening the nitrokey's encrypted volume.
-fn clear() -> Result<()> {
-  pinentry::clear_passphrase(PIN_TYPE)
-}
-
-// A macro for generating a match of the different supported commands.
-// Each supplied command is converted into a string and matched against.
-macro_rules! commands {
-  ( $str:expr, [ $( $command:expr), *] ) => {
-    match &*$str.to_string() {
-      $(
-        stringify!($command) => {
-          if let Err(err) = $command() {
-            println!("{}", err);
-            return 1
-          }
-          return 0
-        },
-      )*
-      x => {
-        println!("Invalid command: {}", x);
-        println!("Available commands: {}", stringify!( $($command)* ));
-        return 1
-      },
-    }
-  }
-}
 
 fn run() -> i32 {
-  let argv: Vec<String> = std::env::args().collect();
-  if argv.len() != 2 {
-    println!("Usage: {} <command>", argv[0]);
-    return 1;
+  let argv: Vec<String> = env::args().collect();
+  match args::handle_arguments(&argv) {
+    Ok(()) => 0,
+    // The child process is responsible for reporting its own errors.
+    Err(error::Error::ExitStatus(code)) => code,
+    Err(err) => {
+      match err.root_cause() {
+        // The watchdog reported the timeout already.
+        error::Error::TimedOut => (),
+        _ => println!("{}", err),
+      }
+      err.exit_code()
+    }
   }
-
-  commands!(&argv[1], [status, open, close, clear]);
 }
 
 fn main() {
@@ -0,0 +1,374 @@
+// manpage.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::completions;
+use crate::completions::Command;
+use crate::completions::Opt;
+
+/// The template the man page is generated from.
+///
+/// Comments of the form `.\" @KEYWORD ARGUMENT` are directives: a line
+/// `.\" @option NAME` is replaced by the synopsis of the global
+/// option with the given long name and a line `.\" @command PATH` by the
+/// one of the command with the given path, e.g., `otp get`. The lines
+/// up to the next such line or section describe the option or command.
+/// Options and commands not present in the template are described by
+/// their help text at the end of the section containing the others,
+/// and options and commands the template mentions but the program does
+/// not provide, e.g., because they are disabled, are left out. A line
+/// `.\" @synopsis` is replaced by the list of all commands.
+const TEMPLATE: &str = include_str!("../doc/nitrocli.1.in");
+
+/// Parse the given line of the template as a directive, returning its
+/// keyword and argument.
+fn directive(line: &str) -> Option<(&str, &str)> {
+  let mut parts = line.splitn(3, ' ');
+  match (parts.next(), parts.next()) {
+    (Some(".\\\""), Some(keyword)) if keyword.starts_with('@') => {
+      Some((keyword.trim_start_matches('@'), parts.next().unwrap_or("")))
+    }
+    _ => None,
+  }
+}
+
+/// Escape the given text for roff.
+fn escape(s: &str) -> String {
+  s.replace('\\', "\\e").replace('-', "\\-")
+}
+
+/// Format the given text in bold.
+fn bold(s: &str) -> String {
+  format!("\\fB{}\\fR", escape(s))
+}
+
+/// Format the given text in italics.
+fn italic(s: &str) -> String {
+  format!("\\fI{}\\fR", escape(s))
+}
+
+/// Format the names of the given option, separated by `separator`, and
+/// its argument, if any.
+fn option_synopsis(opt: &Opt, separator: &str) -> String {
+  let mut synopsis = String::new();
+  if let Some(short) = opt.short {
+    let _ = write!(synopsis, "{}{}", bold(&format!("-{}", short)), separator);
+  }
+  synopsis.push_str(&bold(&format!("--{}", opt.long)));
+  if let Some(arg) = opt.arg {
+    let _ = write!(synopsis, " {}", italic(&arg.name.to_lowercase()));
+  }
+  synopsis
+}
+
+/// Format the given usage of positional arguments, e.g., `[<file>]`,
+/// `<admin|user>`, or `[<slot>...]`.
+fn args_synopsis(usage: &str) -> String {
+  let optional = usage.starts_with('[');
+  let usage = usage.trim_start_matches('[').trim_end_matches(']');
+  let repeated = usage.ends_with("...");
+  let usage = usage
+    .trim_end_matches("...")
+    .trim_start_matches('<')
+    .trim_end_matches('>');
+  let mut synopsis = if usage.contains('|') {
+    usage.split('|').map(bold).collect::<Vec<_>>().join("|")
+  } else {
+    italic(usage)
+  };
+  if repeated {
+    synopsis.push_str("...");
+  }
+  if optional {
+    format!("[{}]", synopsis)
+  } else {
+    synopsis
+  }
+}
+
+/// Format the synopsis of the command with the given path.
+fn command_synopsis(path: &str, command: &Command) -> String {
+  let mut synopsis = bold(path);
+  for opt in &command.options {
+    let _ = write!(synopsis, " [{}]", option_synopsis(opt, "|"));
+  }
+  if !command.usage.is_empty() {
+    let _ = write!(synopsis, " {}", args_synopsis(command.usage));
+  }
+  synopsis
+}
+
+/// The kind of item a section of the man page describes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+  Option,
+  Command,
+}
+
+/// The state of the description of an item of the man page.
+#[derive(Clone, Copy, Debug)]
+enum Item<'p> {
+  /// The item has the given help text and no description yet.
+  Undescribed(&'p str),
+  /// The item has a description.
+  Described,
+  /// The item is left out.
+  Omitted,
+}
+
+/// The state of the generation of a man page.
+struct Page<'p> {
+  /// The generated text.
+  text: String,
+  /// The global options not yet described.
+  options: BTreeMap<&'p str, &'p Opt>,
+  /// The commands without subcommands not yet described, by path.
+  commands: BTreeMap<String, &'p Command>,
+  /// The kind of items described in the current section, if any.
+  section: Option<Kind>,
+  /// The item currently described, if any.
+  item: Option<Item<'p>>,
+}
+
+impl<'p> Page<'p> {
+  /// Start describing the given item.
+  fn start_item(&mut self, synopsis: String, help: &'p str) {
+    let _ = writeln!(self.text, ".TP\n{}", synopsis);
+    self.item = Some(Item::Undescribed(help));
+  }
+
+  /// Finish the description of the current item, if any.
+  fn finish_item(&mut self) {
+    if let Some(Item::Undescribed(help)) = self.item.take() {
+      let _ = writeln!(self.text, "{}.", escape(help));
+    }
+  }
+
+  /// Finish the current section, describing the items of its kind that
+  /// the template does not mention.
+  fn finish_section(&mut self) {
+    self.finish_item();
+    match self.section.take() {
+      Some(Kind::Option) => {
+        let options = self
+          .options
+          .values()
+          .map(|opt| (option_synopsis(opt, ", "), opt.help))
+          .collect::<Vec<_>>();
+        self.options.clear();
+        for (synopsis, help) in options {
+          self.start_item(synopsis, help);
+          self.finish_item();
+        }
+      }
+      Some(Kind::Command) => {
+        let commands = self
+          .commands
+          .iter()
+          .map(|(path, command)| (command_synopsis(path, command), command.help))
+          .collect::<Vec<_>>();
+        self.commands.clear();
+        for (synopsis, help) in commands {
+          self.start_item(synopsis, help);
+          self.finish_item();
+        }
+      }
+      None => (),
+    }
+  }
+
+  /// Process the given line of the template.
+  fn process(&mut self, program: &'p Command, line: &str) {
+    match directive(line) {
+      Some(("option", name)) => {
+        self.finish_item();
+        self.section = Some(Kind::Option);
+        match self.options.remove(name) {
+          Some(opt) => self.start_item(option_synopsis(opt, ", "), opt.help),
+          None => self.item = Some(Item::Omitted),
+        }
+      }
+      Some(("command", path)) => {
+        self.finish_item();
+        self.section = Some(Kind::Command);
+        match self.commands.remove(path) {
+          Some(command) => self.start_item(command_synopsis(path, command), command.help),
+          None => self.item = Some(Item::Omitted),
+        }
+      }
+      Some(("synopsis", _)) => {
+        let commands = program
+          .subcommands
+          .iter()
+          .map(|command| bold(command.name))
+          .collect::<Vec<_>>();
+        let _ = writeln!(self.text, "{}", commands.join("|"));
+      }
+      _ => {
+        if line.starts_with(".SH") {
+          self.finish_section();
+        }
+        match self.item {
+          Some(Item::Omitted) => return,
+          Some(Item::Undescribed(_)) => self.item = Some(Item::Described),
+          _ => (),
+        }
+        let _ = writeln!(self.text, "{}", line);
+      }
+    }
+  }
+}
+
+/// Generate the man page of the given program from the given template.
+fn render(template: &str, program: &Command) -> String {
+  let mut commands = Vec::new();
+  completions::walk(program, "", &mut commands);
+
+  let mut page = Page {
+    text: String::new(),
+    options: program.options.iter().map(|opt| (opt.long, opt)).collect(),
+    commands: commands
+      .into_iter()
+      .filter(|(path, command)| !path.is_empty() && command.subcommands.is_empty())
+      .collect(),
+    section: None,
+    item: None,
+  };
+  for line in template.lines() {
+    page.process(program, line);
+  }
+  page.finish_section();
+  page.text
+}
+
+/// Generate the man page of the given program.
+pub fn generate(program: &Command) -> String {
+  render(TEMPLATE, program)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::completions::Arg;
+  use crate::completions::Values;
+
+  fn command(name: &'static str, options: Vec<Opt>, usage: &'static str) -> Command {
+    Command {
+      name,
+      help: "Do something",
+      options,
+      subcommands: Vec::new(),
+      usage,
+      args: Values::None,
+    }
+  }
+
+  #[test]
+  fn synopses() {
+    let opt = Opt {
+      long: "model",
+      short: Some('m'),
+      arg: Some(Arg {
+        name: "MODEL",
+        values: Values::None,
+      }),
+      help: "Only connect to a device of the given model",
+    };
+    assert_eq!(
+      option_synopsis(&opt, ", "),
+      "\\fB\\-m\\fR, \\fB\\-\\-model\\fR \\fImodel\\fR"
+    );
+    assert_eq!(args_synopsis("<slot>"), "\\fIslot\\fR");
+    assert_eq!(args_synopsis("[<task-file>]"), "[\\fItask\\-file\\fR]");
+    assert_eq!(args_synopsis("<admin|user>"), "\\fBadmin\\fR|\\fBuser\\fR");
+    assert_eq!(args_synopsis("[<slot>...]"), "[\\fIslot\\fR...]");
+    assert_eq!(
+      command_synopsis("pin set", &command("set", Vec::new(), "<admin|user>")),
+      "\\fBpin set\\fR \\fBadmin\\fR|\\fBuser\\fR"
+    );
+  }
+
+  #[test]
+  fn render_template() {
+    let template = r#".SH SYNOPSIS
+.\" @synopsis
+.SH OPTIONS
+.\" @option verbose
+Increase the log level.
+.\" @option removed
+Gone.
+.SH COMMANDS
+.\" @command open
+.\" @command close
+Close the volume.
+.SH FILES
+None."#;
+    let program = Command {
+      subcommands: vec![
+        command("close", Vec::new(), ""),
+        command("open", Vec::new(), ""),
+        command("status", Vec::new(), ""),
+      ],
+      ..command(
+        "nitrocli",
+        vec![
+          Opt {
+            long: "help",
+            short: Some('h'),
+            arg: None,
+            help: "Print this help message and exit",
+          },
+          Opt {
+            long: "verbose",
+            short: None,
+            arg: None,
+            help: "Increase the log level",
+          },
+        ],
+        "",
+      )
+    };
+    let expected = r#".SH SYNOPSIS
+\fBclose\fR|\fBopen\fR|\fBstatus\fR
+.SH OPTIONS
+.TP
+\fB\-\-verbose\fR
+Increase the log level.
+.TP
+\fB\-h\fR, \fB\-\-help\fR
+Print this help message and exit.
+.SH COMMANDS
+.TP
+\fBopen\fR
+Do something.
+.TP
+\fBclose\fR
+Close the volume.
+.TP
+\fBstatus\fR
+Do something.
+.SH FILES
+None.
+"#;
+    assert_eq!(render(template, &program), expected);
+  }
+}
@@ -0,0 +1,230 @@
+// metrics.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+#[cfg(feature = "otp")]
+use std::fmt::Write;
+#[cfg(feature = "otp")]
+use std::fs;
+#[cfg(feature = "otp")]
+use std::path;
+#[cfg(feature = "otp")]
+use std::result;
+#[cfg(feature = "otp")]
+use std::time;
+
+use crate::args;
+#[cfg(feature = "otp")]
+use crate::error::Error;
+
+#[cfg(feature = "otp")]
+type Result<T> = result::Result<T, Error>;
+
+/// The state of a connected device as reported in the metrics.
+#[derive(Debug, PartialEq)]
+pub struct DeviceState {
+  pub model: args::DeviceModel,
+  pub serial_number: String,
+  pub user_retry_count: u8,
+  pub admin_retry_count: u8,
+  /// Whether the encrypted volume is active, if the device has one.
+  pub encrypted_volume_active: Option<bool>,
+}
+
+/// The metrics collected by the agent.
+#[cfg(feature = "otp")]
+#[derive(Debug, Default)]
+pub struct Metrics {
+  requests_succeeded: u64,
+  requests_failed: u64,
+  /// The total time spent handling requests, in seconds.
+  request_seconds: f64,
+}
+
+/// Append the help and type lines of the metric with the given name.
+#[cfg(feature = "otp")]
+fn header(text: &mut String, name: &str, kind: &str, help: &str) {
+  let _ = writeln!(text, "# HELP {} {}", name, help);
+  let _ = writeln!(text, "# TYPE {} {}", name, kind);
+}
+
+#[cfg(feature = "otp")]
+impl Metrics {
+  /// Record a request that took the given time to handle.
+  pub fn record(&mut self, success: bool, duration: time::Duration) {
+    if success {
+      self.requests_succeeded += 1;
+    } else {
+      self.requests_failed += 1;
+    }
+    self.request_seconds += duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9;
+  }
+
+  /// Format the metrics, along with the given state of the device, in
+  /// the text format understood by Prometheus.
+  pub fn format(&self, device: Option<&DeviceState>) -> String {
+    let mut text = String::new();
+    header(
+      &mut text,
+      "nitrocli_device_present",
+      "gauge",
+      "Whether a matching Nitrokey device is connected.",
+    );
+    let _ = writeln!(text, "nitrocli_device_present {}", device.is_some() as u8);
+
+    if let Some(device) = device {
+      header(
+        &mut text,
+        "nitrocli_device_info",
+        "gauge",
+        "The model and serial number of the connected device.",
+      );
+      let _ = writeln!(
+        text,
+        "nitrocli_device_info{{model=\"{}\",serial_number=\"{}\"}} 1",
+        device.model, device.serial_number
+      );
+
+      header(
+        &mut text,
+        "nitrocli_pin_retry_count",
+        "gauge",
+        "The number of remaining attempts for entering a PIN.",
+      );
+      let _ = writeln!(
+        text,
+        "nitrocli_pin_retry_count{{pin=\"admin\"}} {}",
+        device.admin_retry_count
+      );
+      let _ = writeln!(
+        text,
+        "nitrocli_pin_retry_count{{pin=\"user\"}} {}",
+        device.user_retry_count
+      );
+
+      if let Some(active) = device.encrypted_volume_active {
+        header(
+          &mut text,
+          "nitrocli_encrypted_volume_active",
+          "gauge",
+          "Whether the encrypted volume is open.",
+        );
+        let _ = writeln!(text, "nitrocli_encrypted_volume_active {}", active as u8);
+      }
+    }
+
+    header(
+      &mut text,
+      "nitrocli_agent_requests_total",
+      "counter",
+      "The number of requests handled by the agent.",
+    );
+    let _ = writeln!(
+      text,
+      "nitrocli_agent_requests_total{{result=\"error\"}} {}",
+      self.requests_failed
+    );
+    let _ = writeln!(
+      text,
+      "nitrocli_agent_requests_total{{result=\"ok\"}} {}",
+      self.requests_succeeded
+    );
+
+    header(
+      &mut text,
+      "nitrocli_agent_request_duration_seconds",
+      "summary",
+      "The time spent handling requests.",
+    );
+    let _ = writeln!(
+      text,
+      "nitrocli_agent_request_duration_seconds_sum {}",
+      self.request_seconds
+    );
+    let _ = writeln!(
+      text,
+      "nitrocli_agent_request_duration_seconds_count {}",
+      self.requests_succeeded + self.requests_failed
+    );
+    text
+  }
+}
+
+/// Write the given metrics to the file at the given path.
+///
+/// The file is replaced atomically, so that a collector never reads a
+/// partially written file.
+#[cfg(feature = "otp")]
+pub fn write(path: &path::Path, text: &str) -> Result<()> {
+  let mut temp = path.as_os_str().to_owned();
+  temp.push(".tmp");
+  fs::write(&temp, text)
+    .and_then(|_| fs::rename(&temp, path))
+    .map_err(|err| {
+      let error = format!("Could not write metrics to {}: {}", path.display(), err);
+      Error::Error(error)
+    })
+}
+
+#[cfg(all(test, feature = "otp"))]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn format_without_device() {
+    let mut metrics = Metrics::default();
+    metrics.record(true, time::Duration::from_millis(250));
+    metrics.record(false, time::Duration::from_millis(500));
+
+    let text = metrics.format(None);
+    assert!(text.contains("nitrocli_device_present 0\n"));
+    assert!(!text.contains("nitrocli_pin_retry_count"));
+    assert!(text.contains("nitrocli_agent_requests_total{result=\"error\"} 1\n"));
+    assert!(text.contains("nitrocli_agent_requests_total{result=\"ok\"} 1\n"));
+    assert!(text.contains("nitrocli_agent_request_duration_seconds_sum 0.75\n"));
+    assert!(text.contains("nitrocli_agent_request_duration_seconds_count 2\n"));
+  }
+
+  #[test]
+  fn format_with_device() {
+    let device = DeviceState {
+      model: args::DeviceModel::Storage,
+      serial_number: "0x00053141".to_string(),
+      user_retry_count: 3,
+      admin_retry_count: 1,
+      encrypted_volume_active: Some(true),
+    };
+    let text = Metrics::default().format(Some(&device));
+    let expected = "\
+# HELP nitrocli_device_present Whether a matching Nitrokey device is connected.
+# TYPE nitrocli_device_present gauge
+nitrocli_device_present 1
+# HELP nitrocli_device_info The model and serial number of the connected device.
+# TYPE nitrocli_device_info gauge
+nitrocli_device_info{model=\"storage\",serial_number=\"0x00053141\"} 1
+# HELP nitrocli_pin_retry_count The number of remaining attempts for entering a PIN.
+# TYPE nitrocli_pin_retry_count gauge
+nitrocli_pin_retry_count{pin=\"admin\"} 1
+nitrocli_pin_retry_count{pin=\"user\"} 3
+# HELP nitrocli_encrypted_volume_active Whether the encrypted volume is open.
+# TYPE nitrocli_encrypted_volume_active gauge
+nitrocli_encrypted_volume_active 1
+";
+    assert!(text.starts_with(expected));
+  }
+}
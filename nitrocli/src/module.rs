@@ -0,0 +1,464 @@
+// module.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::io;
+use std::io::Read;
+use std::path;
+use std::result;
+
+use crate::args;
+use crate::commands;
+use crate::error::Context;
+use crate::error::Error;
+use crate::json;
+use crate::secret::Secret;
+use crate::vault;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The desired configuration of the device.
+///
+/// Settings that are `None` are left unchanged.
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigTask {
+  pub numlock: Option<Option<u8>>,
+  pub capslock: Option<Option<u8>>,
+  pub scrollock: Option<Option<u8>>,
+  pub user_password: Option<bool>,
+}
+
+/// The desired state of an OTP slot.
+#[cfg(feature = "otp")]
+#[derive(Clone, Debug, PartialEq)]
+pub enum OtpSlotState {
+  /// The slot is programmed with the given name and secret.
+  Present {
+    name: String,
+    secret: Secret,
+    digits: u8,
+    time_window: u16,
+    counter: u64,
+  },
+  /// The slot is not programmed.
+  Absent,
+}
+
+/// The desired state of the OTP slot with the given number.
+#[cfg(feature = "otp")]
+#[derive(Clone, Debug, PartialEq)]
+pub struct OtpSlotTask {
+  pub algorithm: args::OtpAlgorithm,
+  pub slot: u8,
+  pub state: OtpSlotState,
+}
+
+/// The desired state of a password safe slot.
+#[cfg(feature = "pws")]
+#[derive(Clone, Debug, PartialEq)]
+pub enum PwsSlotState {
+  /// The slot holds the given entry.
+  Present {
+    name: String,
+    login: String,
+    password: Secret,
+  },
+  /// The slot is empty.
+  Absent,
+}
+
+/// The desired state of the password safe slot with the given number.
+#[cfg(feature = "pws")]
+#[derive(Clone, Debug, PartialEq)]
+pub struct PwsSlotTask {
+  pub slot: u8,
+  pub state: PwsSlotState,
+}
+
+/// A task describing the desired state of a device.
+#[derive(Debug, Default, PartialEq)]
+pub struct Task {
+  pub config: Option<ConfigTask>,
+  #[cfg(feature = "otp")]
+  pub otp_slots: Vec<OtpSlotTask>,
+  #[cfg(feature = "pws")]
+  pub pws_slots: Vec<PwsSlotTask>,
+  /// Whether the encrypted volume is open.
+  #[cfg(feature = "storage")]
+  pub encrypted_volume: Option<bool>,
+  pub admin_pin: Option<Secret>,
+  #[cfg(any(feature = "pws", feature = "storage"))]
+  pub user_pin: Option<Secret>,
+  /// Whether to only report the changes instead of applying them.
+  pub check_mode: bool,
+}
+
+/// Check that the given object has no members other than the given
+/// ones, ignoring the ones Ansible adds to every task.
+fn check_keys(value: &json::Value, what: &str, keys: &[&str]) -> Result<()> {
+  let members = match value {
+    json::Value::Object(members) => members,
+    _ => return Err(Error::Error(format!("The {} must be an object", what))),
+  };
+  match members
+    .keys()
+    .find(|key| !key.starts_with("_ansible_") && !keys.contains(&key.as_str()))
+  {
+    Some(key) => Err(Error::Error(format!(
+      "Unsupported {} option: {}",
+      what, key
+    ))),
+    None => Ok(()),
+  }
+}
+
+/// Retrieve the member with the given name, treating `null` like an
+/// absent member.
+fn get<'v>(value: &'v json::Value, key: &str) -> Option<&'v json::Value> {
+  match value.get(key) {
+    Some(json::Value::Null) | None => None,
+    Some(value) => Some(value),
+  }
+}
+
+/// Create an error for the member with the given name having the wrong
+/// type.
+fn type_error(key: &str, expected: &str) -> Error {
+  Error::Error(format!("The {} option must be {}", key, expected))
+}
+
+fn get_str(value: &json::Value, key: &str) -> Result<Option<String>> {
+  match get(value, key) {
+    Some(value) => match value.as_str() {
+      Some(s) => Ok(Some(s.to_string())),
+      None => Err(type_error(key, "a string")),
+    },
+    None => Ok(None),
+  }
+}
+
+fn get_bool(value: &json::Value, key: &str) -> Result<Option<bool>> {
+  match get(value, key) {
+    Some(value) => match value.as_bool() {
+      Some(b) => Ok(Some(b)),
+      None => Err(type_error(key, "a boolean")),
+    },
+    None => Ok(None),
+  }
+}
+
+fn get_u64(value: &json::Value, key: &str) -> Result<Option<u64>> {
+  match get(value, key) {
+    Some(value) => match value.as_u64() {
+      Some(n) => Ok(Some(n)),
+      None => Err(type_error(key, "a non-negative integer")),
+    },
+    None => Ok(None),
+  }
+}
+
+fn get_bounded(value: &json::Value, key: &str, max: u64) -> Result<Option<u64>> {
+  match get_u64(value, key)? {
+    Some(n) if n > max => Err(type_error(
+      key,
+      &format!("an integer between 0 and {}", max),
+    )),
+    n => Ok(n),
+  }
+}
+
+/// Parse the HOTP slot a key of the keyboard is bound to, with `null`
+/// disabling the binding.
+fn parse_key_slot(config: &json::Value, key: &str) -> Result<Option<Option<u8>>> {
+  match config.get(key) {
+    None => Ok(None),
+    Some(json::Value::Null) => Ok(Some(None)),
+    Some(_) => get_bounded(config, key, 2).map(|slot| slot.map(|slot| Some(slot as u8))),
+  }
+}
+
+fn parse_config(config: &json::Value) -> Result<ConfigTask> {
+  check_keys(
+    config,
+    "config",
+    &["numlock", "capslock", "scrollock", "user_password"],
+  )?;
+  Ok(ConfigTask {
+    numlock: parse_key_slot(config, "numlock")?,
+    capslock: parse_key_slot(config, "capslock")?,
+    scrollock: parse_key_slot(config, "scrollock")?,
+    user_password: get_bool(config, "user_password")?,
+  })
+}
+
+#[cfg(feature = "otp")]
+fn parse_otp_slot(slot: &json::Value) -> Result<OtpSlotTask> {
+  check_keys(
+    slot,
+    "OTP slot",
+    &[
+      "algorithm",
+      "slot",
+      "state",
+      "name",
+      "secret",
+      "digits",
+      "time_window",
+      "counter",
+    ],
+  )?;
+  let algorithm = match get_str(slot, "algorithm")? {
+    Some(algorithm) => algorithm
+      .parse()
+      .map_err(|()| Error::Error(format!("Invalid OTP algorithm: {}", algorithm)))?,
+    None => args::OtpAlgorithm::Totp,
+  };
+  let number = get_bounded(slot, "slot", 0xff)?
+    .ok_or_else(|| Error::Error("An OTP slot lacks the slot option".to_string()))?
+    as u8;
+
+  let state = match get_str(slot, "state")?.as_ref().map(AsRef::as_ref) {
+    Some("present") | None => {
+      let name = get_str(slot, "name")?;
+      let secret = get_str(slot, "secret")?.map(Secret::from);
+      let (name, secret) = match (name, secret) {
+        (Some(name), Some(secret)) => (name, secret),
+        _ => {
+          let error = format!("The {} slot {} lacks a name or secret", algorithm, number);
+          return Err(Error::Error(error));
+        }
+      };
+      let digits = match get_u64(slot, "digits")? {
+        Some(digits) => args::parse_digits(&digits.to_string())? as u8,
+        None => 6,
+      };
+      OtpSlotState::Present {
+        name,
+        secret,
+        digits,
+        time_window: get_bounded(slot, "time_window", 0xffff)?.unwrap_or(30) as u16,
+        counter: get_u64(slot, "counter")?.unwrap_or(0),
+      }
+    }
+    Some("absent") => OtpSlotState::Absent,
+    Some(state) => return Err(Error::Error(format!("Invalid OTP slot state: {}", state))),
+  };
+
+  Ok(OtpSlotTask {
+    algorithm,
+    slot: number,
+    state,
+  })
+}
+
+/// Parse a task from its JSON representation.
+fn parse_task(task: &json::Value) -> Result<Task> {
+  check_keys(
+    task,
+    "task",
+    &[
+      "config",
+      #[cfg(feature = "otp")]
+      "otp_slots",
+      #[cfg(feature = "storage")]
+      "encrypted_volume",
+      "admin_pin",
+      #[cfg(any(feature = "pws", feature = "storage"))]
+      "user_pin",
+    ],
+  )?;
+  #[cfg(feature = "otp")]
+  let otp_slots = match get(task, "otp_slots") {
+    Some(slots) => slots
+      .as_array()
+      .ok_or_else(|| type_error("otp_slots", "an array"))?
+      .iter()
+      .map(parse_otp_slot)
+      .collect::<Result<Vec<_>>>()?,
+    None => Vec::new(),
+  };
+  #[cfg(feature = "storage")]
+  let encrypted_volume = get_str(task, "encrypted_volume")?;
+  #[cfg(feature = "storage")]
+  let encrypted_volume = match encrypted_volume.as_ref().map(AsRef::as_ref) {
+    Some("open") => Some(true),
+    Some("closed") => Some(false),
+    Some(state) => {
+      let error = format!("Invalid encrypted volume state: {}", state);
+      return Err(Error::Error(error));
+    }
+    None => None,
+  };
+
+  let config = match get(task, "config") {
+    Some(config) => Some(parse_config(config)?),
+    None => None,
+  };
+
+  Ok(Task {
+    config,
+    #[cfg(feature = "otp")]
+    otp_slots,
+    #[cfg(feature = "pws")]
+    pws_slots: Vec::new(),
+    #[cfg(feature = "storage")]
+    encrypted_volume,
+    admin_pin: get_str(task, "admin_pin")?.map(Secret::from),
+    #[cfg(any(feature = "pws", feature = "storage"))]
+    user_pin: get_str(task, "user_pin")?.map(Secret::from),
+    check_mode: get_bool(task, "_ansible_check_mode")?.unwrap_or(false),
+  })
+}
+
+/// Read the task from the file with the given path or, if there is
+/// none, from the standard input.
+///
+/// Tasks encrypted using gpg, e.g., by `vault encrypt`, are decrypted.
+fn read_task(path: Option<&String>) -> Result<json::Value> {
+  let text = match path {
+    Some(path) => vault::read(path::Path::new(path))?,
+    None => {
+      let mut data = Vec::new();
+      let _ = io::stdin().read_to_end(&mut data)?;
+      if vault::is_encrypted(&data) {
+        vault::decrypt(&data).context(|| "Could not decrypt the task")?
+      } else {
+        Secret::from(data)
+      }
+    }
+  };
+  text.as_str()?.parse()
+}
+
+/// Bring the device into the state described by a task and report the
+/// result in the format of an Ansible module.
+///
+/// The task is read from the file given as the only argument, as
+/// Ansible passes it to modules, or from the standard input. The
+/// result is printed as a JSON object, containing whether the device
+/// was `changed` and, on failure, `failed` and the error message.
+pub fn run(ctx: &args::ExecCtx, args: &[String]) -> Result<()> {
+  if args.len() > 1 {
+    return Err(Error::Error(args::Command::Module.usage()));
+  }
+
+  let result = read_task(args.first())
+    .and_then(|task| parse_task(&task))
+    .and_then(|task| {
+      let changes = commands::apply_task(ctx, &task)?;
+      Ok((task.check_mode || ctx.dry_run, changes))
+    });
+  match result {
+    Ok((check_mode, changes)) => {
+      let msg = if changes.is_empty() {
+        "The device is in the desired state".to_string()
+      } else if check_mode {
+        format!("Would apply: {}", changes.join(", "))
+      } else {
+        format!("Applied: {}", changes.join(", "))
+      };
+      let result = json::Value::object(vec![
+        ("changed", json::Value::from(!changes.is_empty())),
+        (
+          "changes",
+          json::Value::Array(changes.into_iter().map(json::Value::from).collect()),
+        ),
+        ("msg", json::Value::from(msg)),
+      ]);
+      println!("{}", result);
+      Ok(())
+    }
+    Err(err) => {
+      let result = json::Value::object(vec![
+        ("changed", json::Value::from(false)),
+        ("failed", json::Value::from(true)),
+        ("msg", json::Value::from(err.to_string())),
+      ]);
+      println!("{}", result);
+      Err(Error::ExitStatus(1))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  #[cfg(all(feature = "otp", feature = "storage"))]
+  fn parse_full_task() {
+    let task = r#"{
+      "config": {"numlock": 0, "capslock": null, "user_password": true},
+      "otp_slots": [
+        {"slot": 1, "name": "github", "secret": "3132", "digits": 8},
+        {"algorithm": "hotp", "slot": 0, "state": "absent"}
+      ],
+      "encrypted_volume": "closed",
+      "admin_pin": "12345678",
+      "_ansible_check_mode": true,
+      "_ansible_verbosity": 0
+    }"#;
+    let task = parse_task(&task.parse().unwrap()).unwrap();
+    let expected = Task {
+      config: Some(ConfigTask {
+        numlock: Some(Some(0)),
+        capslock: Some(None),
+        scrollock: None,
+        user_password: Some(true),
+      }),
+      otp_slots: vec![
+        OtpSlotTask {
+          algorithm: args::OtpAlgorithm::Totp,
+          slot: 1,
+          state: OtpSlotState::Present {
+            name: "github".to_string(),
+            secret: Secret::from("3132"),
+            digits: 8,
+            time_window: 30,
+            counter: 0,
+          },
+        },
+        OtpSlotTask {
+          algorithm: args::OtpAlgorithm::Hotp,
+          slot: 0,
+          state: OtpSlotState::Absent,
+        },
+      ],
+      #[cfg(feature = "pws")]
+      pws_slots: Vec::new(),
+      encrypted_volume: Some(false),
+      admin_pin: Some(Secret::from("12345678")),
+      user_pin: None,
+      check_mode: true,
+    };
+    assert_eq!(task, expected);
+  }
+
+  #[test]
+  fn parse_invalid_tasks() {
+    let parse = |task: &str| parse_task(&task.parse().unwrap());
+    assert_eq!(parse("{}").unwrap(), Task::default());
+    assert!(parse("[]").is_err());
+    assert!(parse(r#"{"pin": "123456"}"#).is_err());
+    assert!(parse(r#"{"config": {"numlock": 3}}"#).is_err());
+    assert!(parse(r#"{"config": {"user_password": "yes"}}"#).is_err());
+    assert!(parse(r#"{"otp_slots": [{"slot": 1, "name": "github"}]}"#).is_err());
+    assert!(parse(r#"{"otp_slots": [{"slot": 1, "state": "gone"}]}"#).is_err());
+    assert!(parse(r#"{"encrypted_volume": "mounted"}"#).is_err());
+  }
+}
@@ -0,0 +1,584 @@
+// monitor.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+#[cfg(target_os = "linux")]
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path;
+use std::result;
+use std::time;
+
+use crate::args;
+use crate::commands;
+use crate::config;
+use crate::error::Error;
+use crate::hooks;
+use crate::json;
+use crate::metrics;
+#[cfg(feature = "storage")]
+use crate::session;
+use crate::storage;
+#[cfg(target_os = "linux")]
+use crate::uevent;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The default interval at which the device is polled, in seconds.
+pub const DEFAULT_INTERVAL: u64 = 2;
+
+/// The maximum interval at which the device is polled, in seconds.
+pub const MAX_INTERVAL: u64 = 24 * 60 * 60;
+
+/// Retrieve the path of the socket the monitor listens on.
+///
+/// The path can be configured using the `socket` option of the
+/// `monitor` command, so that other invocations find the monitor, too.
+pub fn socket_path(ctx: &args::ExecCtx) -> Result<path::PathBuf> {
+  if let Ok(path) = env::var(config::env_var("monitor", "socket")) {
+    return Ok(path::PathBuf::from(path));
+  }
+  match ctx.config.get_string("monitor", "socket")? {
+    Some(path) => Ok(path::PathBuf::from(path)),
+    None => storage::MONITOR_SOCKET.path(),
+  }
+}
+
+/// Create an event of the given kind with the given members.
+fn event(kind: &str, members: Vec<(&str, json::Value)>) -> json::Value {
+  let mut event = vec![
+    ("event", json::Value::from(kind)),
+    ("time", json::Value::from(storage::now())),
+  ];
+  event.extend(members);
+  json::Value::object(event)
+}
+
+/// Publish an event about the execution of the given command to the
+/// monitor, if one is running.
+///
+/// As events are informational, failure to publish one is not an
+/// error.
+pub fn publish_command(ctx: &args::ExecCtx, command: &str, success: bool) {
+  let path = match socket_path(ctx) {
+    Ok(path) => path,
+    Err(_) => return,
+  };
+  if let Ok(mut stream) = UnixStream::connect(path) {
+    let result = if success { "ok" } else { "error" };
+    let event = event(
+      "command",
+      vec![
+        ("command", json::Value::from(command)),
+        ("result", json::Value::from(result)),
+      ],
+    );
+    let _ = writeln!(stream, "{}", event);
+  }
+}
+
+/// A change of the state of the device.
+#[derive(Debug, PartialEq)]
+enum Change<'s> {
+  Plugged(&'s metrics::DeviceState),
+  Unplugged(&'s metrics::DeviceState),
+  VolumeOpened(&'s metrics::DeviceState),
+  VolumeClosed(&'s metrics::DeviceState),
+}
+
+impl<'s> Change<'s> {
+  /// Create the event reporting this change.
+  fn to_event(&self) -> json::Value {
+    let (kind, device) = match self {
+      Change::Plugged(device) => ("device-plugged", device),
+      Change::Unplugged(device) => ("device-unplugged", device),
+      Change::VolumeOpened(device) => ("volume-opened", device),
+      Change::VolumeClosed(device) => ("volume-closed", device),
+    };
+    event(
+      kind,
+      vec![
+        ("model", json::Value::from(device.model.to_string())),
+        (
+          "serial_number",
+          json::Value::from(device.serial_number.as_str()),
+        ),
+      ],
+    )
+  }
+
+  /// Retrieve the device event corresponding to this change, along
+  /// with the affected device, if any.
+  fn device_event(&self) -> Option<(hooks::DeviceEvent, &'s metrics::DeviceState)> {
+    match *self {
+      Change::Plugged(device) => Some((hooks::DeviceEvent::Attach, device)),
+      Change::Unplugged(device) => Some((hooks::DeviceEvent::Detach, device)),
+      Change::VolumeOpened(_) | Change::VolumeClosed(_) => None,
+    }
+  }
+}
+
+/// Determine the changes between the given previous and current states
+/// of the device.
+fn changes<'s>(
+  previous: Option<&'s metrics::DeviceState>,
+  current: Option<&'s metrics::DeviceState>,
+) -> Vec<Change<'s>> {
+  match (previous, current) {
+    (Some(previous), Some(current)) if previous.serial_number == current.serial_number => {
+      match (
+        previous.encrypted_volume_active,
+        current.encrypted_volume_active,
+      ) {
+        (Some(false), Some(true)) => vec![Change::VolumeOpened(current)],
+        (Some(true), Some(false)) => vec![Change::VolumeClosed(current)],
+        _ => Vec::new(),
+      }
+    }
+    (previous, current) => {
+      let mut changes = Vec::new();
+      if let Some(previous) = previous {
+        changes.push(Change::Unplugged(previous));
+      }
+      if let Some(current) = current {
+        changes.push(Change::Plugged(current));
+      }
+      changes
+    }
+  }
+}
+
+/// Create the event reporting the given hotplug event.
+#[cfg(target_os = "linux")]
+fn hotplug_event(hotplug: &uevent::Event) -> json::Value {
+  let kind = match hotplug.action {
+    uevent::Action::Attached => "device-attached",
+    uevent::Action::Detached => "device-detached",
+  };
+  let mut members = vec![
+    ("model", json::Value::from(hotplug.model.to_string())),
+    ("devpath", json::Value::from(hotplug.devpath.as_str())),
+  ];
+  if let Some(ref serial_number) = hotplug.serial_number {
+    members.push(("serial_number", json::Value::from(serial_number.as_str())));
+  }
+  event(kind, members)
+}
+
+/// Record the serial number of the device attached according to the
+/// given hotplug event or, if it was detached, complete the event with
+/// the serial number recorded for it, as it can no longer be read.
+#[cfg(target_os = "linux")]
+fn track(attached: &mut BTreeMap<String, Option<String>>, hotplug: &mut uevent::Event) {
+  match hotplug.action {
+    uevent::Action::Attached => {
+      let _ = attached.insert(hotplug.devpath.clone(), hotplug.serial_number.clone());
+    }
+    uevent::Action::Detached => {
+      if let Some(serial_number) = attached.remove(&hotplug.devpath) {
+        hotplug.serial_number = serial_number;
+      }
+    }
+  }
+}
+
+/// Run the hook for the given event of the given device, reporting a
+/// failure as a warning only, so that the monitor keeps running.
+fn run_device_hook(
+  ctx: &args::ExecCtx,
+  event: hooks::DeviceEvent,
+  model: args::DeviceModel,
+  serial_number: Option<&str>,
+  devpath: Option<&str>,
+) {
+  if let Err(err) = hooks::run_device(ctx, event, model, serial_number, devpath) {
+    warn!("{}", err);
+  }
+}
+
+/// Publish the given hotplug event and run the corresponding hook.
+#[cfg(target_os = "linux")]
+fn handle_hotplug(
+  ctx: &args::ExecCtx,
+  clients: &mut Vec<Client>,
+  attached: &mut BTreeMap<String, Option<String>>,
+  mut hotplug: uevent::Event,
+  print: bool,
+) {
+  debug!("Received a hotplug event: {:?}", hotplug);
+  track(attached, &mut hotplug);
+  publish(clients, &hotplug_event(&hotplug), print);
+  let event = match hotplug.action {
+    uevent::Action::Attached => hooks::DeviceEvent::Attach,
+    uevent::Action::Detached => hooks::DeviceEvent::Detach,
+  };
+  let serial_number = hotplug.serial_number.as_ref().map(AsRef::as_ref);
+  run_device_hook(
+    ctx,
+    event,
+    hotplug.model,
+    serial_number,
+    Some(&hotplug.devpath),
+  );
+}
+
+/// A client connected to the monitor.
+#[derive(Debug)]
+struct Client {
+  stream: UnixStream,
+  /// Data received from the client that does not form a complete line
+  /// yet.
+  buffer: Vec<u8>,
+}
+
+/// Send the given event to all clients, dropping the ones that cannot
+/// receive it.
+///
+/// Client sockets are non-blocking, so a client that does not read its
+/// events is dropped instead of stalling the monitor.
+fn broadcast(clients: &mut Vec<Client>, event: &json::Value) {
+  let line = format!("{}\n", event);
+  clients.retain(|client| (&client.stream).write_all(line.as_bytes()).is_ok());
+}
+
+/// Send the given event to all clients and, if requested, print it.
+fn publish(clients: &mut Vec<Client>, event: &json::Value, print: bool) {
+  if print {
+    println!("{}", event);
+  }
+  broadcast(clients, event);
+}
+
+/// Read the data available from the given client and extract the
+/// events it published.
+///
+/// Returns the events along with whether the client is still
+/// connected.
+fn receive(client: &mut Client) -> (Vec<json::Value>, bool) {
+  let mut data = [0; 4096];
+  let connected = loop {
+    match client.stream.read(&mut data) {
+      Ok(0) => break false,
+      Ok(count) => client.buffer.extend_from_slice(&data[..count]),
+      Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break true,
+      Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+      Err(_) => break false,
+    }
+  };
+
+  let mut events = Vec::new();
+  while let Some(end) = client.buffer.iter().position(|&b| b == b'\n') {
+    let line = client.buffer.drain(..=end).collect::<Vec<_>>();
+    // Anything but an object describing an event is ignored.
+    let event = String::from_utf8(line)
+      .ok()
+      .and_then(|line| line.parse::<json::Value>().ok());
+    if let Some(event) = event {
+      if event.get("event").and_then(json::Value::as_str).is_some() {
+        events.push(event);
+      }
+    }
+  }
+  (events, connected)
+}
+
+/// Create the socket to listen on at the given path.
+fn bind(path: &path::Path) -> Result<UnixListener> {
+  if UnixStream::connect(path).is_ok() {
+    let error = format!("A monitor is already listening on {}", path.display());
+    return Err(Error::Error(error));
+  }
+  if let Err(err) = fs::remove_file(path) {
+    if err.kind() != io::ErrorKind::NotFound {
+      return Err(err.into());
+    }
+  }
+  storage::create_parent(path)?;
+  let listener = UnixListener::bind(path)?;
+  listener.set_nonblocking(true)?;
+  Ok(listener)
+}
+
+/// Wait until one of the given file descriptors becomes readable, at
+/// most for the given time, and return the readable ones.
+fn wait_readable(fds: &[i32], timeout: time::Duration) -> Result<Vec<bool>> {
+  let mut pollfds = fds
+    .iter()
+    .map(|&fd| libc::pollfd {
+      fd,
+      events: libc::POLLIN,
+      revents: 0,
+    })
+    .collect::<Vec<_>>();
+  // Waiting for at most a day at once keeps the timeout within the
+  // range poll(2) accepts; waking up early is harmless.
+  let secs = timeout.as_secs().min(24 * 60 * 60);
+  let millis = secs as libc::c_int * 1000 + timeout.subsec_millis() as libc::c_int;
+  let rc = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, millis) };
+  if rc < 0 {
+    let err = io::Error::last_os_error();
+    if err.kind() != io::ErrorKind::Interrupted {
+      return Err(err.into());
+    }
+  }
+  Ok(pollfds.iter().map(|fd| rc > 0 && fd.revents != 0).collect())
+}
+
+/// Run the monitor.
+///
+/// The monitor polls the device at the given interval and publishes
+/// events about changes of its state, as well as the events published
+/// by other invocations, as JSON objects, one per line, to all clients
+/// connected to the socket at the given path and, if `print` is set, to
+/// the standard output.
+///
+/// On Linux, it also publishes events about any Nitrokey device being
+/// attached or detached, as announced by the kernel, and polls the
+/// device right away in this case.
+///
+/// If configured, it also closes the open volume when the desktop
+/// session is locked, see `session::Session`.
+pub fn run(
+  ctx: &args::ExecCtx,
+  path: &path::Path,
+  interval: time::Duration,
+  print: bool,
+) -> Result<()> {
+  let listener = bind(path)?;
+  #[cfg(target_os = "linux")]
+  let hotplug = match uevent::Socket::open() {
+    Ok(socket) => Some(socket),
+    Err(err) => {
+      warn!("Could not watch for hotplug events, only polling: {}", err);
+      None
+    }
+  };
+  #[cfg(feature = "storage")]
+  let mut session = session::Session::start(&ctx.config)?;
+  let mut clients = Vec::<Client>::new();
+  let mut connection = None;
+  let mut state = None;
+  let mut next_poll = time::Instant::now();
+
+  // Hotplug events cover all devices, so the hooks of devices being
+  // attached or detached are only run for the polled device without
+  // them.
+  #[cfg(target_os = "linux")]
+  let poll_hooks = hotplug.is_none();
+  #[cfg(not(target_os = "linux"))]
+  let poll_hooks = true;
+  #[cfg(target_os = "linux")]
+  let mut attached = BTreeMap::new();
+  #[cfg(target_os = "linux")]
+  {
+    if hotplug.is_some() {
+      for hotplug in uevent::connected() {
+        handle_hotplug(ctx, &mut clients, &mut attached, hotplug, print);
+      }
+    }
+  }
+
+  loop {
+    let now = time::Instant::now();
+    if now >= next_poll {
+      let current = commands::device_state(ctx, &mut connection);
+      commands::unlock_device(ctx);
+      for change in changes(state.as_ref(), current.as_ref()) {
+        debug!("Device state changed: {:?}", change);
+        publish(&mut clients, &change.to_event(), print);
+        if let (true, Some((device_event, device))) = (poll_hooks, change.device_event()) {
+          // The hook may well run nitrocli itself.
+          connection = None;
+          let serial_number = Some(device.serial_number.as_str());
+          run_device_hook(ctx, device_event, device.model, serial_number, None);
+        }
+      }
+      state = current;
+      next_poll = now + interval;
+      continue;
+    }
+
+    let mut fds = vec![listener.as_raw_fd()];
+    #[cfg(target_os = "linux")]
+    fds.extend(hotplug.as_ref().map(AsRawFd::as_raw_fd));
+    #[cfg(feature = "storage")]
+    let first_session = fds.len();
+    #[cfg(feature = "storage")]
+    fds.extend(session.fds());
+    let first_client = fds.len();
+    fds.extend(clients.iter().map(|client| client.stream.as_raw_fd()));
+    let readable = wait_readable(&fds, next_poll - now)?;
+
+    #[cfg(target_os = "linux")]
+    {
+      if let Some(ref hotplug) = hotplug {
+        if readable[1] {
+          for hotplug in hotplug.receive() {
+            // The hook may well run nitrocli itself.
+            connection = None;
+            handle_hotplug(ctx, &mut clients, &mut attached, hotplug, print);
+            // The device is polled right away to report its state.
+            next_poll = time::Instant::now();
+          }
+        }
+      }
+    }
+
+    #[cfg(feature = "storage")]
+    for change in session.receive(&readable[first_session..first_client]) {
+      debug!("Session lock state changed: {:?}", change);
+      publish(&mut clients, &event(change.name(), Vec::new()), print);
+      // The connection used for polling has to be closed for the
+      // session to be able to connect to the device.
+      connection = None;
+      session.handle(ctx, change);
+      next_poll = time::Instant::now();
+    }
+
+    let mut events = Vec::new();
+    let mut connected = Vec::with_capacity(clients.len());
+    for (mut client, &readable) in clients.drain(..).zip(&readable[first_client..]) {
+      if readable {
+        let (received, connected) = receive(&mut client);
+        events.extend(received);
+        if !connected {
+          continue;
+        }
+      }
+      connected.push(client);
+    }
+    clients = connected;
+
+    if readable[0] {
+      while let Ok((stream, _)) = listener.accept() {
+        if stream.set_nonblocking(true).is_ok() {
+          debug!("Accepted a client, {} connected now", clients.len() + 1);
+          clients.push(Client {
+            stream,
+            buffer: Vec::new(),
+          });
+        }
+      }
+    }
+
+    for event in events {
+      publish(&mut clients, &event, print);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn device(serial_number: &str, active: Option<bool>) -> metrics::DeviceState {
+    metrics::DeviceState {
+      model: args::DeviceModel::Storage,
+      serial_number: serial_number.to_string(),
+      user_retry_count: 3,
+      admin_retry_count: 3,
+      encrypted_volume_active: active,
+    }
+  }
+
+  #[test]
+  fn state_changes() {
+    let closed = device("0x1", Some(false));
+    let open = device("0x1", Some(true));
+    let other = device("0x2", None);
+
+    assert_eq!(changes(None, None), vec![]);
+    assert_eq!(changes(None, Some(&open)), vec![Change::Plugged(&open)]);
+    assert_eq!(changes(Some(&open), None), vec![Change::Unplugged(&open)]);
+    assert_eq!(changes(Some(&closed), Some(&closed)), vec![]);
+    assert_eq!(
+      changes(Some(&closed), Some(&open)),
+      vec![Change::VolumeOpened(&open)]
+    );
+    assert_eq!(
+      changes(Some(&open), Some(&closed)),
+      vec![Change::VolumeClosed(&closed)]
+    );
+    assert_eq!(
+      changes(Some(&open), Some(&other)),
+      vec![Change::Unplugged(&open), Change::Plugged(&other)]
+    );
+
+    let attach = Some((hooks::DeviceEvent::Attach, &other));
+    assert_eq!(Change::Plugged(&other).device_event(), attach);
+    let detach = Some((hooks::DeviceEvent::Detach, &open));
+    assert_eq!(Change::Unplugged(&open).device_event(), detach);
+    assert_eq!(Change::VolumeOpened(&open).device_event(), None);
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn track_serial_numbers() {
+    let hotplug = |action, serial_number: Option<&str>| uevent::Event {
+      action,
+      model: args::DeviceModel::Storage,
+      devpath: "/devices/usb1/1-1".to_string(),
+      serial_number: serial_number.map(ToString::to_string),
+    };
+    let mut attached = BTreeMap::new();
+    let mut event = hotplug(uevent::Action::Attached, Some("0000c0de"));
+    track(&mut attached, &mut event);
+    assert_eq!(event.serial_number, Some("0000c0de".to_string()));
+
+    let mut event = hotplug(uevent::Action::Detached, None);
+    track(&mut attached, &mut event);
+    assert_eq!(event.serial_number, Some("0000c0de".to_string()));
+    assert!(attached.is_empty());
+
+    let mut event = hotplug(uevent::Action::Detached, None);
+    track(&mut attached, &mut event);
+    assert_eq!(event.serial_number, None);
+  }
+
+  #[test]
+  fn receive_events() {
+    let (a, b) = UnixStream::pair().unwrap();
+    b.set_nonblocking(true).unwrap();
+    let mut client = Client {
+      stream: b,
+      buffer: Vec::new(),
+    };
+
+    (&a)
+      .write_all(b"{\"event\":\"command\"}\ngarbage\n{\"x\":1}\n{\"event\":")
+      .unwrap();
+    let (events, connected) = receive(&mut client);
+    assert!(connected);
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].get("event").unwrap().as_str(), Some("command"));
+    assert_eq!(client.buffer, b"{\"event\":");
+
+    (&a).write_all(b"\"x\"}\n").unwrap();
+    drop(a);
+    let (events, connected) = receive(&mut client);
+    assert!(!connected);
+    assert_eq!(events[0].get("event").unwrap().as_str(), Some("x"));
+  }
+}
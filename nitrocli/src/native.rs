@@ -0,0 +1,292 @@
+// native.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::result;
+
+use crate::args;
+use crate::commands;
+use crate::error::Error;
+use crate::json;
+use crate::pinentry;
+use crate::secret;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The maximum size of a message we accept, in bytes.
+///
+/// Browsers allow for larger messages, but the requests we understand
+/// are tiny.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Convert the given bytes in native byte order into a message length.
+fn length_from_bytes(mut bytes: [u8; 4]) -> usize {
+  if cfg!(target_endian = "little") {
+    bytes.reverse();
+  }
+  bytes
+    .iter()
+    .fold(0, |length, &b| (length << 8) | usize::from(b))
+}
+
+/// Convert the given message length into bytes in native byte order.
+fn length_to_bytes(length: u32) -> [u8; 4] {
+  let mut bytes = [
+    (length >> 24) as u8,
+    (length >> 16) as u8,
+    (length >> 8) as u8,
+    length as u8,
+  ];
+  if cfg!(target_endian = "little") {
+    bytes.reverse();
+  }
+  bytes
+}
+
+/// Read a message from the given reader.
+///
+/// Messages consist of their length as a 32 bit integer in native byte
+/// order, followed by the message in JSON format. `None` is returned if
+/// the reader reached its end, i.e., the browser closed the connection.
+fn read_message<R>(reader: &mut R) -> Result<Option<json::Value>>
+where
+  R: Read,
+{
+  let mut length = [0; 4];
+  match reader.read_exact(&mut length) {
+    Ok(()) => (),
+    Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+    Err(err) => return Err(err.into()),
+  }
+
+  let length = length_from_bytes(length);
+  if length > MAX_MESSAGE_SIZE {
+    let error = format!("Message of {} bytes exceeds the maximum size", length);
+    return Err(Error::Error(error));
+  }
+
+  let mut message = vec![0; length];
+  reader.read_exact(&mut message)?;
+  String::from_utf8(message)?.parse().map(Some)
+}
+
+/// A sink for formatted text that merely counts its length.
+struct Counter(usize);
+
+impl fmt::Write for Counter {
+  fn write_str(&mut self, s: &str) -> fmt::Result {
+    self.0 += s.len();
+    Ok(())
+  }
+}
+
+/// Write the given message to the given writer.
+///
+/// The message may contain a password, so it is formatted into a
+/// buffer of the exact size, which is not reallocated and thus does not
+/// leave copies behind, and the buffer is wiped afterwards.
+fn write_message<W>(writer: &mut W, message: &json::Value) -> Result<()>
+where
+  W: Write,
+{
+  let mut counter = Counter(0);
+  let error = |_| Error::Error("Could not format the message".to_string());
+  fmt::Write::write_fmt(&mut counter, format_args!("{}", message)).map_err(error)?;
+  let mut buffer = String::with_capacity(counter.0);
+  fmt::Write::write_fmt(&mut buffer, format_args!("{}", message)).map_err(error)?;
+  let mut buffer = buffer.into_bytes();
+
+  let result = writer
+    .write_all(&length_to_bytes(buffer.len() as u32))
+    .and_then(|()| writer.write_all(&buffer))
+    .and_then(|()| writer.flush());
+  secret::wipe(&mut buffer);
+  result.map_err(Error::from)
+}
+
+/// Wipe the strings of the given value, which may be secrets.
+fn wipe_value(value: json::Value) {
+  match value {
+    json::Value::String(s) => secret::wipe(&mut s.into_bytes()),
+    json::Value::Array(values) => values.into_iter().for_each(wipe_value),
+    json::Value::Object(members) => members.into_iter().for_each(|(_, value)| wipe_value(value)),
+    _ => (),
+  }
+}
+
+/// Retrieve the string member with the given name of the given
+/// message.
+fn get_str<'m>(message: &'m json::Value, key: &str) -> Result<&'m str> {
+  message
+    .get(key)
+    .and_then(json::Value::as_str)
+    .ok_or_else(|| Error::Error(format!("The request lacks the string member {}", key)))
+}
+
+/// Ask the user to confirm that the given caller may retrieve the
+/// given credential.
+fn confirm(caller: &str, credential: &str) -> Result<()> {
+  let description = format!("The browser extension {} requests {}.", caller, credential);
+  if pinentry::confirm(&description)? {
+    Ok(())
+  } else {
+    Err(Error::Error("The request was denied".to_string()))
+  }
+}
+
+/// The state of a native messaging session.
+#[derive(Debug)]
+struct Session<'c> {
+  ctx: &'c args::ExecCtx,
+  /// The identifier of the browser extension we talk to.
+  caller: String,
+  /// The connection to the device, reused between requests.
+  connection: Option<commands::Connection>,
+}
+
+impl<'c> Session<'c> {
+  /// Handle a request for a one-time password.
+  #[cfg(feature = "otp")]
+  fn otp(&mut self, request: &json::Value) -> Result<json::Value> {
+    let slot = get_str(request, "slot")?;
+    let algorithm = match request.get("algorithm") {
+      Some(algorithm) => {
+        let algorithm = algorithm.as_str().unwrap_or_default();
+        algorithm
+          .parse::<args::OtpAlgorithm>()
+          .map_err(|()| Error::Error(format!("Invalid OTP algorithm: {}", algorithm)))?
+      }
+      None => args::OtpAlgorithm::Totp,
+    };
+    confirm(
+      &self.caller,
+      &format!("a one-time password for slot {}", slot),
+    )?;
+
+    let connection = match self.connection.take() {
+      Some(connection) => connection,
+      None => commands::Connection::connect(self.ctx)?,
+    };
+    let (connection, otp) = commands::generate_otp(self.ctx, connection, slot, algorithm)?;
+    self.connection = Some(connection);
+    Ok(json::Value::object(vec![("otp", json::Value::from(otp))]))
+  }
+
+  /// Handle a request for a password safe entry.
+  #[cfg(feature = "pws")]
+  fn pws(&mut self, request: &json::Value) -> Result<json::Value> {
+    let name = get_str(request, "name")?;
+    confirm(&self.caller, &format!("the credentials for {}", name))?;
+
+    let connection = match self.connection.take() {
+      Some(connection) => connection,
+      None => commands::Connection::connect(self.ctx)?,
+    };
+    let entry = commands::get_pws_entry(self.ctx, &connection, name);
+    self.connection = Some(connection);
+    let (login, password) = entry?;
+    Ok(json::Value::object(vec![
+      ("login", json::Value::from(login)),
+      ("password", json::Value::from(password.as_str()?)),
+    ]))
+  }
+
+  /// Handle the given request and create the reply to it.
+  fn handle(&mut self, request: &json::Value) -> json::Value {
+    let result = match get_str(request, "type") {
+      #[cfg(feature = "otp")]
+      Ok("otp") => self.otp(request),
+      #[cfg(feature = "pws")]
+      Ok("pws") => self.pws(request),
+      Ok(kind) => Err(Error::Error(format!("Unsupported request type: {}", kind))),
+      Err(err) => Err(err),
+    };
+    // Any failure invalidates the connection, as the device may have
+    // been removed.
+    if result.is_err() {
+      self.connection = None;
+    }
+
+    let mut reply = match result {
+      Ok(reply) => reply,
+      Err(err) => json::Value::object(vec![("error", json::Value::from(err.to_string()))]),
+    };
+    if let (json::Value::Object(members), Some(id)) = (&mut reply, request.get("id")) {
+      let _ = members.insert("id".to_string(), id.clone());
+    }
+    reply
+  }
+}
+
+/// Act as a native messaging host for a browser extension.
+///
+/// Requests are read from the standard input and replies written to
+/// the standard output until the browser closes the connection. The
+/// given arguments are the ones the browser passes to the host, the
+/// last of which identifies the extension.
+pub fn run(ctx: &args::ExecCtx, args: &[String]) -> Result<()> {
+  let mut session = Session {
+    ctx,
+    caller: args
+      .last()
+      .cloned()
+      .unwrap_or_else(|| "unknown".to_string()),
+    connection: None,
+  };
+
+  let stdin = io::stdin();
+  let stdout = io::stdout();
+  let mut stdin = stdin.lock();
+  let mut stdout = stdout.lock();
+  while let Some(request) = read_message(&mut stdin)? {
+    debug!("Received a request from {}", session.caller);
+    let reply = session.handle(&request);
+    commands::unlock_device(ctx);
+    let result = write_message(&mut stdout, &reply);
+    wipe_value(reply);
+    result?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn message_round_trip() {
+    let message = json::Value::object(vec![("type", json::Value::from("otp"))]);
+    let mut data = Vec::new();
+    write_message(&mut data, &message).unwrap();
+    assert_eq!(length_from_bytes([data[0], data[1], data[2], data[3]]), 14);
+
+    let mut reader = &data[..];
+    assert_eq!(read_message(&mut reader).unwrap(), Some(message));
+    assert_eq!(read_message(&mut reader).unwrap(), None);
+  }
+
+  #[test]
+  fn message_too_large() {
+    let data = length_to_bytes(MAX_MESSAGE_SIZE as u32 + 1);
+    assert!(read_message(&mut &data[..]).is_err());
+  }
+}
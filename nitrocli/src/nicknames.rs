@@ -0,0 +1,195 @@
+// nicknames.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::result;
+use std::str;
+
+use crate::error::Error;
+use crate::storage;
+
+type Result<T> = result::Result<T, Error>;
+
+/// Check whether the given serial numbers refer to the same device.
+fn same_serial_number(a: &str, b: &str) -> bool {
+  nitrocli_core::device::normalize_serial_number(a)
+    == nitrocli_core::device::normalize_serial_number(b)
+}
+
+/// The nicknames of devices, indexed by their serial numbers.
+///
+/// Nicknames are local to the system and can be used wherever a serial
+/// number is expected.
+#[derive(Debug, Default, PartialEq)]
+pub struct Nicknames {
+  names: BTreeMap<String, String>,
+}
+
+impl Nicknames {
+  /// Load the nicknames from their file.
+  pub fn load() -> Result<Self> {
+    let data = storage::DEVICE_NAMES.read()?.unwrap_or_default();
+    // The nicknames are parsed leniently, so parsing cannot fail.
+    Ok(data.parse().unwrap())
+  }
+
+  /// Save the nicknames to their file.
+  pub fn save(&self) -> Result<()> {
+    storage::DEVICE_NAMES.write(&self.to_string())
+  }
+
+  /// Set the nickname of the device with the given serial number,
+  /// replacing its previous one, if any.
+  ///
+  /// A nickname can only refer to a single device.
+  pub fn set(&mut self, serial: &str, name: &str) -> Result<()> {
+    if let Some(other) = self.serial(name) {
+      if !same_serial_number(other, serial) {
+        let error = format!("The name {} is used for the device {} already", name, other);
+        return Err(Error::Error(error));
+      }
+    }
+    let _ = self.remove(serial);
+    let _ = self.names.insert(serial.to_string(), name.to_string());
+    Ok(())
+  }
+
+  /// Remove the nickname of the device with the given serial number.
+  ///
+  /// Returns whether the device had a nickname.
+  pub fn remove(&mut self, serial: &str) -> bool {
+    let len = self.names.len();
+    self.names.retain(|s, _| !same_serial_number(s, serial));
+    self.names.len() != len
+  }
+
+  /// Look up the nickname of the device with the given serial number.
+  pub fn name(&self, serial: &str) -> Option<&str> {
+    self
+      .names
+      .iter()
+      .find(|(s, _)| same_serial_number(s, serial))
+      .map(|(_, name)| name.as_str())
+  }
+
+  /// Look up the serial number of the device with the given nickname.
+  pub fn serial(&self, name: &str) -> Option<&str> {
+    self
+      .names
+      .iter()
+      .find(|(_, n)| n.as_str() == name)
+      .map(|(serial, _)| serial.as_str())
+  }
+
+  /// Resolve the given nickname or serial number to a serial number.
+  pub fn resolve(&self, serial: &str) -> String {
+    self.serial(serial).unwrap_or(serial).to_string()
+  }
+
+  /// Check whether no device has a nickname.
+  #[cfg_attr(not(feature = "storage"), allow(dead_code))]
+  pub fn is_empty(&self) -> bool {
+    self.names.is_empty()
+  }
+
+  /// Retrieve the serial numbers of the devices with a nickname along
+  /// with the nicknames.
+  pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+    self
+      .names
+      .iter()
+      .map(|(serial, name)| (serial.as_str(), name.as_str()))
+  }
+}
+
+/// The nicknames are stored with one line per device, containing the
+/// serial number and the nickname, separated by a space.
+impl fmt::Display for Nicknames {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for (serial, name) in &self.names {
+      writeln!(f, "{} {}", serial, name)?;
+    }
+    Ok(())
+  }
+}
+
+impl str::FromStr for Nicknames {
+  type Err = ();
+
+  /// Parse nicknames, ignoring malformed lines.
+  fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+    let mut nicknames = Nicknames::default();
+    for line in s.lines() {
+      let mut parts = line.splitn(2, ' ');
+      if let (Some(serial), Some(name)) = (parts.next(), parts.next()) {
+        if !serial.is_empty() && !name.is_empty() {
+          let _ = nicknames.set(serial, name);
+        }
+      }
+    }
+    Ok(nicknames)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn lookup() {
+    let mut nicknames = Nicknames::default();
+    assert!(nicknames.is_empty());
+    nicknames.set("0x0000c0de", "backup-stick").unwrap();
+    nicknames.set("0x1234abcd", "work").unwrap();
+    assert!(nicknames.set("0x1", "work").is_err());
+    assert_eq!(nicknames.name("c0de"), Some("backup-stick"));
+    assert_eq!(nicknames.name("0x1234ABCD"), Some("work"));
+    assert_eq!(nicknames.name("0x1"), None);
+    assert_eq!(nicknames.serial("work"), Some("0x1234abcd"));
+    assert_eq!(nicknames.resolve("backup-stick"), "0x0000c0de");
+    assert_eq!(nicknames.resolve("0x1"), "0x1");
+
+    nicknames.set("c0de", "spare").unwrap();
+    assert_eq!(nicknames.name("0x0000c0de"), Some("spare"));
+    assert_eq!(nicknames.serial("backup-stick"), None);
+    assert!(nicknames.remove("0xc0de"));
+    assert!(!nicknames.remove("0xc0de"));
+    assert_eq!(
+      nicknames.iter().collect::<Vec<_>>(),
+      vec![("0x1234abcd", "work")]
+    );
+  }
+
+  #[test]
+  fn round_trip() {
+    let mut nicknames = Nicknames::default();
+    nicknames.set("0x0000c0de", "backup-stick").unwrap();
+    nicknames.set("0x1234abcd", "work").unwrap();
+    let s = nicknames.to_string();
+    assert_eq!(s, "0x0000c0de backup-stick\n0x1234abcd work\n");
+    assert_eq!(s.parse::<Nicknames>().unwrap(), nicknames);
+  }
+
+  #[test]
+  fn parse_malformed() {
+    let nicknames = "0x1\n\n0x2 two\n two\n".parse::<Nicknames>().unwrap();
+    assert_eq!(nicknames.iter().collect::<Vec<_>>(), vec![("0x2", "two")]);
+  }
+}
@@ -0,0 +1,88 @@
+// notify.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::process;
+use std::result;
+
+use crate::args;
+use crate::config;
+use crate::error::Error;
+use crate::pinentry;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The configuration keys controlling notifications, along with the
+/// kinds of their values.
+pub const CONFIG_KEYS: &[(&str, &str, config::Kind)] = &[
+  ("notifications", "enabled", config::Kind::Boolean),
+  ("notifications", "retry-threshold", config::Kind::Integer),
+];
+
+/// The number of remaining PIN attempts at or below which a
+/// notification is sent, unless configured otherwise.
+const DEFAULT_RETRY_THRESHOLD: i64 = 1;
+
+/// Send a desktop notification with the given summary, if notifications
+/// are enabled.
+///
+/// Notifications are sent using `notify-send`. As they are merely
+/// informational, failure to send one is not an error.
+pub fn notify(ctx: &args::ExecCtx, summary: &str) -> Result<()> {
+  let enabled = ctx.config.get_bool("notifications", "enabled")?;
+  if !enabled.unwrap_or(false) {
+    return Ok(());
+  }
+
+  debug!("Sending notification: {}", summary);
+  let result = process::Command::new("notify-send")
+    .arg("--app-name=nitrocli")
+    .arg(summary)
+    .stdin(process::Stdio::null())
+    .stdout(process::Stdio::null())
+    .stderr(process::Stdio::null())
+    .status();
+  match result {
+    Ok(ref status) if status.success() => (),
+    Ok(status) => warn!(
+      "Could not send notification: notify-send failed with {}",
+      status
+    ),
+    Err(err) => warn!("Could not send notification: {}", err),
+  }
+  Ok(())
+}
+
+/// Send a notification if the given number of remaining attempts for
+/// the PIN of the given type reached the configured threshold.
+pub fn retry_count(ctx: &args::ExecCtx, pin_type: pinentry::PinType, remaining: u8) -> Result<()> {
+  let threshold = ctx
+    .config
+    .get_integer("notifications", "retry-threshold")?
+    .unwrap_or(DEFAULT_RETRY_THRESHOLD);
+  if i64::from(remaining) > threshold {
+    return Ok(());
+  }
+
+  let summary = match remaining {
+    0 => format!("The {} is blocked", pin_type),
+    1 => format!("1 attempt left for the {}", pin_type),
+    _ => format!("{} attempts left for the {}", remaining, pin_type),
+  };
+  notify(ctx, &summary)
+}
@@ -0,0 +1,232 @@
+// oath.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use crate::secret::Secret;
+
+/// Compute the SHA-1 digest of the given data.
+///
+/// The padded copy of the data is a `Secret`, as the data may be a key.
+fn sha1(data: &[u8]) -> [u8; 20] {
+  let mut h: [u32; 5] = [
+    0x6745_2301,
+    0xefcd_ab89,
+    0x98ba_dcfe,
+    0x1032_5476,
+    0xc3d2_e1f0,
+  ];
+  let mut message = Secret::new();
+  message.extend_from_slice(data);
+  message.push(0x80);
+  while message.as_bytes().len() % 64 != 56 {
+    message.push(0);
+  }
+  let bits = (data.len() as u64).wrapping_mul(8);
+  for i in (0..8).rev() {
+    message.push((bits >> (i * 8)) as u8);
+  }
+
+  for chunk in message.as_bytes().chunks(64) {
+    let mut w = [0u32; 80];
+    for i in 0..16 {
+      w[i] = chunk[i * 4..i * 4 + 4]
+        .iter()
+        .fold(0, |w, &b| (w << 8) | u32::from(b));
+    }
+    for i in 16..80 {
+      w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+    }
+
+    let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+    for (i, w) in w.iter().enumerate() {
+      let (f, k) = match i {
+        0..=19 => ((b & c) | (!b & d), 0x5a82_7999),
+        20..=39 => (b ^ c ^ d, 0x6ed9_eba1),
+        40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1b_bcdc),
+        _ => (b ^ c ^ d, 0xca62_c1d6),
+      };
+      let temp = a
+        .rotate_left(5)
+        .wrapping_add(f)
+        .wrapping_add(e)
+        .wrapping_add(k)
+        .wrapping_add(*w);
+      e = d;
+      d = c;
+      c = b.rotate_left(30);
+      b = a;
+      a = temp;
+    }
+    for (h, x) in h.iter_mut().zip(&[a, b, c, d, e]) {
+      *h = h.wrapping_add(*x);
+    }
+  }
+
+  let mut digest = [0; 20];
+  for (i, h) in h.iter().enumerate() {
+    for j in 0..4 {
+      digest[i * 4 + j] = (h >> (24 - j * 8)) as u8;
+    }
+  }
+  digest
+}
+
+/// Compute the HMAC-SHA-1 of the given message using the given key.
+///
+/// The buffers derived from the key are `Secret`s, so that they are
+/// wiped once the digest is computed.
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+  let mut block = Secret::new();
+  if key.len() > 64 {
+    block.extend_from_slice(&sha1(key));
+  } else {
+    block.extend_from_slice(key);
+  }
+  while block.as_bytes().len() < 64 {
+    block.push(0);
+  }
+
+  let mut inner = Secret::new();
+  for b in block.as_bytes() {
+    inner.push(b ^ 0x36);
+  }
+  inner.extend_from_slice(message);
+  let mut outer = Secret::new();
+  for b in block.as_bytes() {
+    outer.push(b ^ 0x5c);
+  }
+  outer.extend_from_slice(&sha1(inner.as_bytes()));
+  sha1(outer.as_bytes())
+}
+
+/// Compute the HOTP value for the given secret and counter, as
+/// specified in RFC 4226.
+pub fn hotp(secret: &[u8], counter: u64, digits: u32) -> String {
+  let counter = (0..8)
+    .rev()
+    .map(|i| (counter >> (i * 8)) as u8)
+    .collect::<Vec<_>>();
+  let hash = hmac_sha1(secret, &counter);
+  let offset = usize::from(hash[19] & 0xf);
+  let code = hash[offset..offset + 4]
+    .iter()
+    .fold(0u32, |code, &b| (code << 8) | u32::from(b))
+    & 0x7fff_ffff;
+  format!(
+    "{:0width$}",
+    code % 10u32.pow(digits),
+    width = digits as usize
+  )
+}
+
+/// Decode the given base32 string, as commonly used for the secrets of
+/// OTP accounts.
+///
+/// Following RFC 4648, but leniently, the letters may be lower case
+/// and whitespace and padding are ignored.
+#[cfg(feature = "otp")]
+pub fn decode_base32(s: &str) -> Option<Secret> {
+  let mut data = Secret::new();
+  let mut buffer = 0u32;
+  let mut bits = 0;
+  for c in s.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+    let value = match c.to_ascii_uppercase() {
+      c @ 'A'..='Z' => c as u32 - 'A' as u32,
+      c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+      _ => return None,
+    };
+    buffer = (buffer << 5) | value;
+    bits += 5;
+    if bits >= 8 {
+      bits -= 8;
+      data.push((buffer >> bits) as u8);
+      buffer &= (1 << bits) - 1;
+    }
+  }
+  Some(data)
+}
+
+/// Encode the given data as a hex string, as expected by the device
+/// for the secrets of OTP slots.
+#[cfg(feature = "otp")]
+pub fn encode_hex(data: &[u8]) -> Secret {
+  const DIGITS: &[u8; 16] = b"0123456789abcdef";
+  let mut hex = Secret::new();
+  for byte in data {
+    hex.push(DIGITS[usize::from(byte >> 4)]);
+    hex.push(DIGITS[usize::from(byte & 0xf)]);
+  }
+  hex
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+  }
+
+  #[test]
+  fn sha1_digests() {
+    assert_eq!(
+      to_hex(&sha1(b"")),
+      "da39a3ee5e6b4b0d3255bfef95601890afd80709"
+    );
+    assert_eq!(
+      to_hex(&sha1(b"abc")),
+      "a9993e364706816aba3e25717850c26c9cd0d89d"
+    );
+    assert_eq!(
+      to_hex(&sha1(&[b'a'; 1000])),
+      "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+    );
+  }
+
+  #[test]
+  fn otp_values() {
+    // Test vectors from RFC 4226 and RFC 6238.
+    let secret = b"12345678901234567890";
+    let expected = ["755224", "287082", "359152", "969429", "338314"];
+    for (counter, expected) in expected.iter().enumerate() {
+      assert_eq!(&hotp(secret, counter as u64, 6), expected);
+    }
+    assert_eq!(hotp(secret, 59 / 30, 8), "94287082");
+    assert_eq!(hotp(secret, 1_111_111_109 / 30, 8), "07081804");
+  }
+
+  #[test]
+  #[cfg(feature = "otp")]
+  fn base32_decoding() {
+    let decode = |s| decode_base32(s).map(|secret| secret.as_bytes().to_vec());
+    assert_eq!(
+      decode("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap(),
+      b"12345678901234567890"
+    );
+    assert_eq!(decode("mzxw 6yq=").unwrap(), b"foob");
+    assert_eq!(decode("").unwrap(), b"");
+    assert_eq!(decode("MZXW1"), None);
+  }
+
+  #[test]
+  #[cfg(feature = "otp")]
+  fn hex_encoding() {
+    assert_eq!(encode_hex(b"\x01\xab").as_str().unwrap(), "01ab");
+    assert_eq!(encode_hex(b"12").as_str().unwrap(), "3132");
+  }
+}
@@ -0,0 +1,155 @@
+// pager.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::env;
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::process;
+use std::result;
+
+use crate::args;
+use crate::config;
+use crate::error::Error;
+use crate::signal;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The configuration keys controlling the pager, along with the kinds
+/// of their values.
+pub const CONFIG_KEYS: &[(&str, &str, config::Kind)] = &[("", "pager", config::Kind::String)];
+
+/// The pager used if neither the configuration nor `PAGER` name one.
+const DEFAULT_PAGER: &str = "less";
+
+/// The options for `less` used unless `LESS` is set: quit if the text
+/// fits on the screen, pass through colors, and do not clear the
+/// screen.
+const DEFAULT_LESS: &str = "FRX";
+
+/// Retrieve the pager command to use in the given context, if any.
+///
+/// An empty command or `cat` disable paging, as with git.
+fn command(ctx: &args::ExecCtx) -> Result<Option<String>> {
+  if ctx.no_pager {
+    return Ok(None);
+  }
+  let command = match ctx.config.get_string("", "pager")? {
+    Some(command) => command.to_string(),
+    None => env::var("PAGER").unwrap_or_else(|_| DEFAULT_PAGER.to_string()),
+  };
+  if command.trim().is_empty() || command.trim() == "cat" {
+    Ok(None)
+  } else {
+    Ok(Some(command))
+  }
+}
+
+/// Retrieve the number of rows and columns of the terminal the
+/// standard output is connected to, if it is one.
+fn terminal_size() -> Option<(usize, usize)> {
+  if unsafe { libc::isatty(libc::STDOUT_FILENO) } != 1 {
+    return None;
+  }
+  let mut size = unsafe { mem::zeroed::<libc::winsize>() };
+  let rc = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+  if rc == 0 && size.ws_row > 0 && size.ws_col > 0 {
+    Some((usize::from(size.ws_row), usize::from(size.ws_col)))
+  } else {
+    // A terminal that does not report its size still is one, so
+    // let the pager decide.
+    Some((0, 0))
+  }
+}
+
+/// Count the rows of a terminal with the given number of columns the
+/// given text occupies, taking wrapped lines into account.
+fn rows(text: &str, columns: usize) -> usize {
+  text
+    .lines()
+    .map(|line| {
+      let chars = line.chars().count();
+      if chars == 0 || columns == 0 {
+        1
+      } else {
+        (chars - 1) / columns + 1
+      }
+    })
+    .sum()
+}
+
+/// Pass the given text to the given pager.
+///
+/// An error is only reported if the pager could not be started.
+fn run(command: &str, text: &str) -> Result<()> {
+  let mut child = process::Command::new("sh")
+    .arg("-c")
+    .arg(command)
+    .env(
+      "LESS",
+      env::var("LESS").unwrap_or_else(|_| DEFAULT_LESS.to_string()),
+    )
+    .stdin(process::Stdio::piped())
+    .spawn()
+    .map_err(|err| Error::Error(format!("Failed to run the pager {}: {}", command, err)))?;
+
+  // The user may interrupt the pager, e.g., to stop a search, which
+  // must not terminate us while the pager still uses the terminal.
+  let _guard = signal::defer();
+  if let Some(mut stdin) = child.stdin.take() {
+    // The pager may be quit before it read all of the text.
+    let _ = stdin.write_all(text.as_bytes());
+  }
+  let _ = child.wait();
+  let _ = signal::check();
+  Ok(())
+}
+
+/// Print the given text, passing it to the pager if the standard output
+/// is a terminal the text does not fit on.
+pub fn page(ctx: &args::ExecCtx, text: &str) -> Result<()> {
+  if let Some((rows, columns)) = terminal_size() {
+    if self::rows(text, columns) >= rows {
+      if let Some(command) = command(ctx)? {
+        match run(&command, text) {
+          Ok(()) => return Ok(()),
+          Err(err) => debug!("{}", err),
+        }
+      }
+    }
+  }
+  print!("{}", text);
+  io::stdout().flush()?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn count_rows() {
+    assert_eq!(rows("", 80), 0);
+    assert_eq!(rows("a\n\nb\n", 80), 3);
+    assert_eq!(rows(&"x".repeat(80), 80), 1);
+    assert_eq!(rows(&"x".repeat(81), 80), 2);
+    assert_eq!(rows(&"ä".repeat(160), 80), 2);
+    assert_eq!(rows("a\nb", 0), 2);
+  }
+}
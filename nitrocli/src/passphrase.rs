@@ -0,0 +1,122 @@
+// passphrase.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::fs;
+use std::io::Read;
+use std::result;
+
+use crate::error::Error;
+use crate::secret::Secret;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The words passphrases are made of, one per line.
+///
+/// All of them have four letters, so that the length of a passphrase
+/// only depends on the number of its words.
+const WORDLIST: &str = include_str!("wordlist.txt");
+
+/// The length of the words in the word list.
+const WORD_LENGTH: usize = 4;
+
+/// The separator between the words of a passphrase.
+const SEPARATOR: u8 = b'-';
+
+/// The number of words in a passphrase by default.
+pub const DEFAULT_WORDS: usize = 4;
+
+/// Retrieve the length of a passphrase with the given number of words.
+pub fn length(words: usize) -> usize {
+  words * (WORD_LENGTH + 1) - 1
+}
+
+/// Pick words from the given list using the given source of random
+/// bytes.
+///
+/// Random values beyond the largest multiple of the list length are
+/// discarded so that all words are equally likely.
+fn choose<R>(list: &[&str], words: usize, random: &mut R) -> Result<Secret>
+where
+  R: Read,
+{
+  let count = list.len() as u32;
+  let limit = (1 << 16) - (1 << 16) % count;
+  let mut passphrase = Secret::new();
+  let mut buffer = [0u8; 2];
+  while passphrase.as_bytes().len() < length(words) {
+    random.read_exact(&mut buffer)?;
+    let value = u32::from(buffer[0]) << 8 | u32::from(buffer[1]);
+    if value >= limit {
+      continue;
+    }
+    if !passphrase.as_bytes().is_empty() {
+      passphrase.push(SEPARATOR);
+    }
+    passphrase.extend_from_slice(list[(value % count) as usize].as_bytes());
+  }
+  Ok(passphrase)
+}
+
+/// Generate a diceware-style passphrase consisting of the given number
+/// of words, using the random number generator of the operating system.
+pub fn generate(words: usize) -> Result<Secret> {
+  let list = WORDLIST.lines().collect::<Vec<_>>();
+  let mut random = fs::File::open("/dev/urandom")?;
+  choose(&list, words, &mut random)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::collections::BTreeSet;
+  use std::io;
+
+  #[test]
+  fn wordlist() {
+    let list = WORDLIST.lines().collect::<Vec<_>>();
+    assert_eq!(list.len(), 1024);
+    assert_eq!(list.iter().collect::<BTreeSet<_>>().len(), list.len());
+    for word in list {
+      assert_eq!(word.len(), WORD_LENGTH, "{}", word);
+      assert!(word.bytes().all(|b| b.is_ascii_lowercase()), "{}", word);
+    }
+  }
+
+  #[test]
+  fn choose_words() {
+    let list = ["abcd", "efgh", "ijkl"];
+    // 0xffff is above the largest multiple of three and skipped.
+    let mut random = io::Cursor::new(vec![0x00, 0x01, 0xff, 0xff, 0x00, 0x05, 0x00, 0x03]);
+    let passphrase = choose(&list, 3, &mut random).unwrap();
+    assert_eq!(passphrase.as_str().unwrap(), "efgh-ijkl-abcd");
+    assert_eq!(passphrase.as_bytes().len(), length(3));
+
+    let mut random = io::Cursor::new(vec![0x00]);
+    assert!(choose(&list, 1, &mut random).is_err());
+  }
+
+  #[test]
+  fn generate_passphrase() {
+    let passphrase = generate(DEFAULT_WORDS).unwrap();
+    let passphrase = passphrase.as_str().unwrap();
+    assert_eq!(passphrase.len(), length(DEFAULT_WORDS));
+    assert_eq!(passphrase.split('-').count(), DEFAULT_WORDS);
+  }
+}
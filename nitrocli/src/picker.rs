@@ -0,0 +1,509 @@
+// picker.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::result;
+
+use crate::error::Error;
+use crate::tty;
+use crate::watchdog;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The maximum number of candidates shown at once.
+const MAX_VISIBLE: usize = 10;
+/// The time to wait for the rest of an escape sequence before treating
+/// an escape character as a key press of its own, in milliseconds.
+const ESCAPE_TIMEOUT: libc::c_int = 50;
+
+/// The score of a matched character.
+const SCORE_MATCH: i64 = 16;
+/// The bonus for a character matched at the start of a word.
+const BONUS_WORD_START: i64 = 8;
+/// The bonus for a character matched right after the previous one.
+const BONUS_CONSECUTIVE: i64 = 12;
+/// The penalty for every character skipped between two matches.
+const PENALTY_GAP: i64 = 1;
+
+/// Check whether the character at the given index of the given
+/// characters starts a word, e.g., the `h` in `github-work` and
+/// the `W` in `gitWork`.
+fn is_word_start(chars: &[char], index: usize) -> bool {
+  match index.checked_sub(1).map(|prev| chars[prev]) {
+    None => true,
+    Some(prev) if !prev.is_alphanumeric() => true,
+    Some(prev) => prev.is_lowercase() && chars[index].is_uppercase(),
+  }
+}
+
+/// Score how well the given candidate matches the given query.
+///
+/// The query matches if its characters occur in the candidate in the
+/// same order, not necessarily next to each other. Characters matched
+/// at the start of words or right after each other score higher, and
+/// the gaps between matches lower the score. Unless the query contains
+/// upper case characters, case is ignored. `None` is returned if the
+/// candidate does not match.
+pub fn score(query: &str, candidate: &str) -> Option<i64> {
+  let fold = !query.chars().any(char::is_uppercase);
+  let normalize = |c: char| {
+    if fold {
+      c.to_lowercase().next().unwrap_or(c)
+    } else {
+      c
+    }
+  };
+  let query = query.chars().map(normalize).collect::<Vec<_>>();
+  let chars = candidate.chars().collect::<Vec<_>>();
+  if query.is_empty() {
+    return Some(0);
+  }
+
+  // The best score of matching the query up to the current character
+  // with that character matched at the given index of the candidate.
+  let mut best = vec![None; chars.len()];
+  for (i, &q) in query.iter().enumerate() {
+    let mut next = vec![None; chars.len()];
+    for (j, &c) in chars.iter().enumerate() {
+      if normalize(c) != q {
+        continue;
+      }
+      let mut bonus = SCORE_MATCH;
+      if is_word_start(&chars, j) {
+        bonus += BONUS_WORD_START;
+      }
+      next[j] = if i == 0 {
+        Some(bonus)
+      } else {
+        (0..j)
+          .filter_map(|prev| best[prev].map(|score: i64| (prev, score)))
+          .map(|(prev, score)| {
+            let gap = (j - prev - 1) as i64;
+            if gap == 0 {
+              score + bonus + BONUS_CONSECUTIVE
+            } else {
+              score + bonus - gap * PENALTY_GAP
+            }
+          })
+          .max()
+      };
+    }
+    best = next;
+  }
+  best.into_iter().flatten().max()
+}
+
+/// Retrieve the indices of the given candidates matching the given
+/// query, best match first.
+///
+/// Candidates scoring the same are ordered by length and then by their
+/// position, so that all candidates keep their order for an empty
+/// query.
+pub fn filter<S>(query: &str, candidates: &[S]) -> Vec<usize>
+where
+  S: AsRef<str>,
+{
+  let mut matches = candidates
+    .iter()
+    .enumerate()
+    .filter_map(|(index, candidate)| {
+      let candidate = candidate.as_ref();
+      let length = if query.is_empty() {
+        0
+      } else {
+        candidate.chars().count()
+      };
+      score(query, candidate).map(|score| (-score, length, index))
+    })
+    .collect::<Vec<_>>();
+  matches.sort();
+  matches.into_iter().map(|(_, _, index)| index).collect()
+}
+
+/// A key pressed in the picker.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Key {
+  /// A character extending the query.
+  Char(char),
+  /// Remove the last character of the query.
+  Backspace,
+  /// Clear the query.
+  Clear,
+  /// Select the previous candidate.
+  Up,
+  /// Select the next candidate.
+  Down,
+  /// Pick the selected candidate.
+  Enter,
+  /// Abort picking.
+  Abort,
+  /// Interrupt the program.
+  Interrupt,
+}
+
+/// A decoder of the bytes read from a terminal in raw mode into keys.
+#[derive(Debug, Default)]
+struct Decoder {
+  /// The bytes of an incomplete escape sequence or UTF-8 character.
+  pending: Vec<u8>,
+}
+
+impl Decoder {
+  /// Feed the given byte to the decoder, returning the key completed by
+  /// it, if any.
+  fn feed(&mut self, byte: u8) -> Option<Key> {
+    if let Some(&first) = self.pending.first() {
+      self.pending.push(byte);
+      return if first == 0x1b {
+        self.escape()
+      } else {
+        self.utf8()
+      };
+    }
+
+    match byte {
+      b'\r' | b'\n' => Some(Key::Enter),
+      0x03 => Some(Key::Interrupt),
+      0x04 | 0x07 => Some(Key::Abort),
+      0x08 | 0x7f => Some(Key::Backspace),
+      0x0e => Some(Key::Down),
+      0x10 => Some(Key::Up),
+      0x15 => Some(Key::Clear),
+      0x1b => {
+        self.pending.push(byte);
+        None
+      }
+      byte if byte < 0x20 => None,
+      byte if byte < 0x80 => Some(Key::Char(byte as char)),
+      byte => {
+        self.pending.push(byte);
+        None
+      }
+    }
+  }
+
+  /// Decode the pending escape sequence, if it is complete.
+  ///
+  /// Control sequences other than the ones of the arrow keys, e.g., the
+  /// ones of function keys, are ignored once their final byte arrived.
+  fn escape(&mut self) -> Option<Key> {
+    let sequence = &self.pending[1..];
+    let last = sequence[sequence.len() - 1];
+    let key = match sequence {
+      [b'['] | [b'O'] => return None,
+      [b'[', b'A'] | [b'O', b'A'] => Some(Key::Up),
+      [b'[', b'B'] | [b'O', b'B'] => Some(Key::Down),
+      // Parameter and intermediate bytes are in the range 0x20 to 0x3f.
+      _ if sequence[0] == b'[' && last & 0xe0 == 0x20 => return None,
+      _ => None,
+    };
+    self.pending.clear();
+    key
+  }
+
+  /// Decode the pending UTF-8 character, if it is complete.
+  fn utf8(&mut self) -> Option<Key> {
+    let length = match self.pending[0] {
+      byte if byte >= 0xf0 => 4,
+      byte if byte >= 0xe0 => 3,
+      _ => 2,
+    };
+    if self.pending.len() < length {
+      return None;
+    }
+    let key = String::from_utf8(self.pending.clone())
+      .ok()
+      .and_then(|s| s.chars().next())
+      .map(Key::Char);
+    self.pending.clear();
+    key
+  }
+
+  /// Flush a pending escape character not followed by the rest of a
+  /// sequence in time, which aborts picking.
+  fn timeout(&mut self) -> Option<Key> {
+    if self.pending == [0x1b] {
+      self.pending.clear();
+      Some(Key::Abort)
+    } else {
+      None
+    }
+  }
+
+  /// Check whether a lone escape character is pending.
+  fn is_escape_pending(&self) -> bool {
+    self.pending == [0x1b]
+  }
+}
+
+/// The state of the picker.
+#[derive(Debug)]
+struct Picker<'c, S> {
+  /// The candidates to pick from.
+  candidates: &'c [S],
+  /// The query entered.
+  query: String,
+  /// The indices of the candidates matching the query, best match
+  /// first.
+  matches: Vec<usize>,
+  /// The position of the selected candidate in the matches.
+  selected: usize,
+}
+
+impl<'c, S> Picker<'c, S>
+where
+  S: AsRef<str>,
+{
+  /// Create a picker for the given candidates.
+  fn new(candidates: &'c [S]) -> Self {
+    Picker {
+      candidates,
+      query: String::new(),
+      matches: filter("", candidates),
+      selected: 0,
+    }
+  }
+
+  /// Update the matches after the query changed.
+  fn update(&mut self) {
+    self.matches = filter(&self.query, self.candidates);
+    self.selected = 0;
+  }
+
+  /// Handle the given key, returning the index of the picked candidate
+  /// once one was picked.
+  fn handle(&mut self, key: Key) -> Result<Option<usize>> {
+    match key {
+      Key::Char(c) => {
+        self.query.push(c);
+        self.update();
+      }
+      Key::Backspace => {
+        if self.query.pop().is_some() {
+          self.update();
+        }
+      }
+      Key::Clear => {
+        self.query.clear();
+        self.update();
+      }
+      Key::Up => self.selected = self.selected.saturating_sub(1),
+      Key::Down => {
+        if self.selected + 1 < self.matches.len().min(MAX_VISIBLE) {
+          self.selected += 1;
+        }
+      }
+      Key::Enter => {
+        if let Some(&index) = self.matches.get(self.selected) {
+          return Ok(Some(index));
+        }
+      }
+      Key::Abort => return Err(Error::Error("Selection aborted".to_string())),
+      Key::Interrupt => return Err(Error::Interrupted(libc::SIGINT)),
+    }
+    Ok(None)
+  }
+
+  /// Render the picker to the given terminal, leaving the cursor at the
+  /// end of the query.
+  fn render<W>(&self, tty: &mut W, prompt: &str) -> io::Result<()>
+  where
+    W: Write,
+  {
+    let visible = &self.matches[..self.matches.len().min(MAX_VISIBLE)];
+    write!(tty, "\r\x1b[J{}{}", prompt, self.query)?;
+    for (position, &index) in visible.iter().enumerate() {
+      let candidate = self.candidates[index].as_ref();
+      if position == self.selected {
+        write!(tty, "\r\n\x1b[7m> {}\x1b[0m", candidate)?;
+      } else {
+        write!(tty, "\r\n  {}", candidate)?;
+      }
+    }
+    if !visible.is_empty() {
+      write!(tty, "\x1b[{}A", visible.len())?;
+    }
+    write!(tty, "\r{}{}", prompt, self.query)?;
+    tty.flush()
+  }
+}
+
+/// Wait for input on the given terminal for the escape timeout,
+/// returning whether there is some.
+fn poll_input(tty: &fs::File) -> io::Result<bool> {
+  let mut fd = libc::pollfd {
+    fd: tty.as_raw_fd(),
+    events: libc::POLLIN,
+    revents: 0,
+  };
+  match unsafe { libc::poll(&mut fd, 1, ESCAPE_TIMEOUT) } {
+    -1 => Err(io::Error::last_os_error()),
+    0 => Ok(false),
+    _ => Ok(true),
+  }
+}
+
+/// Let the user pick one of the given candidates on the controlling
+/// terminal of the process, filtering them by fuzzy matching a query.
+///
+/// The best matches are listed below the query. The arrow keys, `^P`,
+/// and `^N` move the selection, and the enter key picks the selected
+/// candidate, whose index is returned. Escape, `^D`, and `^G` abort
+/// picking, while `^C` interrupts the program, as the terminal is in
+/// raw mode.
+pub fn pick<S>(prompt: &str, candidates: &[S]) -> Result<usize>
+where
+  S: AsRef<str>,
+{
+  if candidates.is_empty() {
+    return Err(Error::Error("There is nothing to pick from".to_string()));
+  }
+
+  let _suspension = watchdog::suspend();
+  let mut tty = fs::OpenOptions::new()
+    .read(true)
+    .write(true)
+    .open("/dev/tty")?;
+  let mut picker = Picker::new(candidates);
+  let mut decoder = Decoder::default();
+  let result = {
+    let _raw = tty::Raw::new(tty.as_raw_fd())?;
+    let mut result = Ok(None);
+    picker.render(&mut tty, prompt)?;
+    while let Ok(None) = result {
+      let key = if decoder.is_escape_pending() && !poll_input(&tty)? {
+        decoder.timeout()
+      } else {
+        let mut byte = [0; 1];
+        match tty.read(&mut byte) {
+          Ok(0) => Some(Key::Abort),
+          Ok(_) => decoder.feed(byte[0]),
+          Err(ref err) if err.kind() == io::ErrorKind::Interrupted => None,
+          Err(err) => return Err(err.into()),
+        }
+      };
+      if let Some(key) = key {
+        result = picker.handle(key);
+        if let Ok(None) = result {
+          picker.render(&mut tty, prompt)?;
+        }
+      }
+    }
+    result
+  };
+  // The list is removed once picking is over.
+  write!(tty, "\r\x1b[J")?;
+  tty.flush()?;
+  result.map(|index| index.unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn decode(bytes: &[u8]) -> Vec<Key> {
+    let mut decoder = Decoder::default();
+    bytes
+      .iter()
+      .filter_map(|&byte| decoder.feed(byte))
+      .collect()
+  }
+
+  #[test]
+  fn fuzzy_scores() {
+    assert_eq!(score("", "github"), Some(0));
+    assert_eq!(score("gh", "github"), Some(16 + 8 + 16 - 2));
+    assert_eq!(score("git", "github"), Some(3 * 16 + 8 + 2 * 12));
+    assert_eq!(score("hg", "github"), None);
+    assert_eq!(score("GH", "github"), None);
+    assert!(score("GH", "GitHub").is_some());
+    assert!(score("gh", "git-hub") > score("gh", "github"));
+    assert!(score("mail", "mail") > score("mail", "my-ail"));
+  }
+
+  #[test]
+  fn filter_candidates() {
+    let candidates = ["gitlab", "github", "mail", "bank-github"];
+    assert_eq!(filter("", &candidates), vec![0, 1, 2, 3]);
+    assert_eq!(filter("gh", &candidates), vec![1, 3]);
+    assert_eq!(filter("ghb", &candidates), vec![1, 3]);
+    assert_eq!(filter("gl", &candidates), vec![0]);
+    assert_eq!(filter("xyz", &candidates), Vec::<usize>::new());
+  }
+
+  #[test]
+  fn decode_keys() {
+    assert_eq!(
+      decode(b"a\x7f\x15\r\x03\x07\x0e\x10"),
+      vec![
+        Key::Char('a'),
+        Key::Backspace,
+        Key::Clear,
+        Key::Enter,
+        Key::Interrupt,
+        Key::Abort,
+        Key::Down,
+        Key::Up,
+      ]
+    );
+    assert_eq!(decode(b"\x1b[A\x1bOB"), vec![Key::Up, Key::Down]);
+    assert_eq!(decode(b"\x1b[15~x"), vec![Key::Char('x')]);
+    assert_eq!(decode("ä".as_bytes()), vec![Key::Char('ä')]);
+
+    let mut decoder = Decoder::default();
+    assert_eq!(decoder.feed(0x1b), None);
+    assert_eq!(decoder.timeout(), Some(Key::Abort));
+    assert_eq!(decoder.timeout(), None);
+  }
+
+  #[test]
+  fn pick_candidate() {
+    let candidates = ["gitlab", "github", "mail"];
+    let mut picker = Picker::new(&candidates);
+    assert_eq!(picker.handle(Key::Char('g')).unwrap(), None);
+    assert_eq!(picker.matches, vec![0, 1]);
+    assert_eq!(picker.handle(Key::Down).unwrap(), None);
+    assert_eq!(picker.handle(Key::Down).unwrap(), None);
+    assert_eq!(picker.handle(Key::Enter).unwrap(), Some(1));
+
+    assert_eq!(picker.handle(Key::Char('x')).unwrap(), None);
+    assert_eq!(picker.handle(Key::Enter).unwrap(), None);
+    assert_eq!(picker.handle(Key::Backspace).unwrap(), None);
+    assert_eq!(picker.handle(Key::Up).unwrap(), None);
+    assert_eq!(picker.handle(Key::Enter).unwrap(), Some(0));
+    assert!(picker.handle(Key::Abort).is_err());
+  }
+
+  #[test]
+  fn render_picker() {
+    let candidates = ["gitlab", "github", "mail"];
+    let mut picker = Picker::new(&candidates);
+    let _ = picker.handle(Key::Char('g')).unwrap();
+    let mut output = Vec::new();
+    picker.render(&mut output, "> ").unwrap();
+    assert_eq!(
+      String::from_utf8(output).unwrap(),
+      "\r\x1b[J> g\r\n\x1b[7m> gitlab\x1b[0m\r\n  github\x1b[2A\r> g"
+    );
+  }
+}
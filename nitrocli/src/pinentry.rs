@@ -17,9 +17,40 @@
 // * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
 // *************************************************************************
 
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
 use std::process;
 
+use crate::args;
+use crate::config;
 use crate::error::Error;
+use crate::keychain;
+use crate::policy;
+use crate::secret::Secret;
+use crate::storage;
+use crate::tty;
+use crate::watchdog;
+
+/// The error code gpg-agent reports if no pinentry program is available.
+const GPG_ERR_NO_PIN_ENTRY: u32 = 85;
+
+/// The configuration keys of the `pinentry` table, along with the kinds of their values.
+pub const CONFIG_KEYS: &[(&str, &str, config::Kind)] = &[
+  ("pinentry", "admin-cache-ttl", config::Kind::Integer),
+  ("pinentry", "cache", config::Kind::String),
+  ("pinentry", "echo", config::Kind::String),
+  ("pinentry", "user-cache-ttl", config::Kind::Integer),
+];
+
+/// The place PINs are cached in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Cache {
+  /// The cache of gpg-agent.
+  Agent,
+  /// The macOS keychain.
+  Keychain,
+}
 
 /// PIN type requested from pinentry.
 ///
@@ -28,7 +59,6 @@ use crate::error::Error;
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum PinType {
   /// The admin PIN.
-  #[allow(unused)]
   Admin,
   /// The user PIN.
   User,
@@ -42,6 +72,15 @@ impl PinType {
     }
   }
 
+  /// The configuration key holding the time in seconds for which a PIN of this type may be
+  /// cached.
+  fn cache_ttl_key(self) -> &'static str {
+    match self {
+      PinType::Admin => "admin-cache-ttl",
+      PinType::User => "user-cache-ttl",
+    }
+  }
+
   fn prompt(self) -> &'static str {
     match self {
       PinType::Admin => "Admin PIN",
@@ -49,16 +88,54 @@ impl PinType {
     }
   }
 
-  fn description(self) -> &'static str {
-    match self {
-      PinType::Admin => "Please enter admin PIN",
-      PinType::User => "Please enter user PIN",
+  fn description(self, mode: Mode) -> &'static str {
+    match (self, mode) {
+      (PinType::Admin, Mode::Choose) => "Please enter a new admin PIN",
+      (PinType::Admin, Mode::Confirm) => "Please reenter the new admin PIN",
+      (PinType::Admin, Mode::Query) => "Please enter admin PIN",
+      (PinType::User, Mode::Choose) => "Please enter a new user PIN",
+      (PinType::User, Mode::Confirm) => "Please reenter the new user PIN",
+      (PinType::User, Mode::Query) => "Please enter user PIN",
     }
   }
 }
 
-fn parse_pinentry_passphrase(response: Vec<u8>) -> Result<Vec<u8>, Error> {
-  let string = String::from_utf8(response)?;
+impl fmt::Display for PinType {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let s = match *self {
+      PinType::Admin => "admin PIN",
+      PinType::User => "user PIN",
+    };
+    write!(f, "{}", s)
+  }
+}
+
+/// The mode in which a PIN is inquired.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+  /// Let the user choose a new PIN.
+  Choose,
+  /// Let the user confirm a newly chosen PIN.
+  Confirm,
+  /// Query an existing PIN.
+  Query,
+}
+
+impl Mode {
+  /// Check whether a PIN inquired in this mode may be cached.
+  ///
+  /// Only existing PINs are cached. A newly chosen PIN is not valid
+  /// until the device accepted it and so it must not end up in the
+  /// cache.
+  fn is_cached(self) -> bool {
+    self == Mode::Query
+  }
+}
+
+fn parse_pinentry_passphrase(response: Vec<u8>) -> Result<Secret, Error> {
+  // The response contains the passphrase, so it is wiped as well.
+  let response = Secret::from(response);
+  let string = response.as_str()?;
   let lines: Vec<&str> = string.lines().collect();
 
   // We expect the response to be of the form:
@@ -69,7 +146,7 @@ fn parse_pinentry_passphrase(response: Vec<u8>) -> Result<Vec<u8>, Error> {
   if lines.len() == 2 && lines[1] == "OK" && lines[0].starts_with("D ") {
     // We got the only valid answer we accept.
     let (_, pass) = lines[0].split_at(2);
-    return Ok(pass.to_string().into_bytes());
+    return Ok(Secret::from(pass));
   }
 
   // Check if we are dealing with a special "ERR " line and report that
@@ -78,22 +155,248 @@ fn parse_pinentry_passphrase(response: Vec<u8>) -> Result<Vec<u8>, Error> {
     let (_, error) = lines[0].split_at(4);
     return Err(Error::Error(error.to_string()));
   }
-  Err(Error::Error("Unexpected response: ".to_string() + &string))
+  Err(Error::Error("Unexpected response: ".to_string() + string))
+}
+
+/// Check whether a response of gpg-connect-agent indicates that gpg-agent or pinentry is not
+/// available.
+///
+/// gpg-connect-agent does not print anything to stdout if it failed to connect to gpg-agent.
+fn is_agent_unavailable(response: &[u8]) -> bool {
+  let string = String::from_utf8_lossy(response);
+  match string.lines().next() {
+    Some(line) if line.starts_with("ERR ") => {
+      let code = line[4..]
+        .split(' ')
+        .next()
+        .and_then(|code| code.parse::<u32>().ok());
+      // The lower 16 bits contain the actual error code, the upper ones
+      // the error source.
+      code.map(|code| code & 0xffff) == Some(GPG_ERR_NO_PIN_ENTRY)
+    }
+    Some(_) => false,
+    None => true,
+  }
+}
+
+/// Send the given command to gpg-agent using gpg-connect-agent.
+///
+/// The function returns the response or `None` if gpg-agent or pinentry is not available.
+fn connect_agent(command: &str) -> Result<Option<Vec<u8>>, Error> {
+  // Responses may contain PINs, so only the command is logged.
+  trace!("Sending command to gpg-agent: {}", command);
+  let output = process::Command::new("gpg-connect-agent")
+    .arg(command)
+    .arg("/bye")
+    .output();
+
+  match output {
+    Ok(ref output) if is_agent_unavailable(&output.stdout) => {
+      debug!("gpg-agent or pinentry is not available");
+      Ok(None)
+    }
+    Ok(output) => Ok(Some(output.stdout)),
+    Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+      debug!("gpg-connect-agent is not installed");
+      Ok(None)
+    }
+    Err(err) => Err(Error::IoError(err)),
+  }
+}
+
+/// Inquire a PIN of the given type from the terminal.
+///
+/// This function is used as a fallback if no agent or pinentry is available.  PINs read this way
+/// are only cached if the keychain is used.  The input is echoed as configured in the `pinentry`
+/// table of the given configuration.
+fn inquire_tty(
+  config: &config::Config,
+  pin_type: PinType,
+  mode: Mode,
+  error_msg: Option<&str>,
+) -> Result<Secret, Error> {
+  let echo = echo(config)?;
+  debug!("Inquiring the {} from the terminal", pin_type.prompt());
+  let mut prompt = String::new();
+  if let Some(error_msg) = error_msg {
+    prompt += error_msg;
+    prompt += "\n";
+  }
+  prompt += pin_type.description(mode);
+  prompt += ": ";
+  tty::read_passphrase(&prompt, echo)
+}
+
+/// Retrieve how PINs read from the terminal are echoed, as configured in the `pinentry` table of
+/// the given configuration.
+fn echo(config: &config::Config) -> Result<tty::Echo, Error> {
+  match config.get_string("pinentry", "echo")? {
+    None | Some("none") => Ok(tty::Echo::None),
+    Some("mask") => Ok(tty::Echo::Mask),
+    Some(echo) => {
+      let error = format!(
+        "Invalid value for configuration key pinentry.echo: {}",
+        echo
+      );
+      Err(Error::Error(error))
+    }
+  }
+}
+
+/// Retrieve the time in seconds for which a PIN of the given type may be cached.
+///
+/// `None` indicates that the lifetime is not restricted by us but only by the configuration of
+/// gpg-agent.  A lifetime of zero disables caching, as does the `no-cache` setting.
+fn cache_ttl(ctx: &args::ExecCtx, pin_type: PinType) -> Result<Option<u64>, Error> {
+  if ctx.no_cache {
+    return Ok(Some(0));
+  }
+
+  let key = pin_type.cache_ttl_key();
+  match ctx.config.get_integer("pinentry", key)? {
+    Some(ttl) if ttl < 0 => {
+      let error = format!(
+        "Invalid value for configuration key pinentry.{}: {}",
+        key, ttl
+      );
+      Err(Error::Error(error))
+    }
+    Some(ttl) => Ok(Some(ttl as u64)),
+    None => Ok(None),
+  }
+}
+
+/// Retrieve the place PINs are cached in, as configured in the `pinentry` table of the given
+/// configuration.
+fn cache(config: &config::Config) -> Result<Cache, Error> {
+  match config.get_string("pinentry", "cache")? {
+    None | Some("gpg-agent") => Ok(Cache::Agent),
+    Some("keychain") if cfg!(target_os = "macos") => Ok(Cache::Keychain),
+    Some("keychain") => Err(Error::Error(
+      "The keychain PIN cache is only available on macOS".to_string(),
+    )),
+    Some(cache) => {
+      let error = format!(
+        "Invalid value for configuration key pinentry.cache: {}",
+        cache
+      );
+      Err(Error::Error(error))
+    }
+  }
+}
+
+/// Read the times at which PINs were cached, indexed by cache ID.
+fn read_cache_times() -> Result<BTreeMap<String, u64>, Error> {
+  let data = storage::PIN_CACHE.read()?.unwrap_or_default();
+
+  // We are lenient and just ignore any malformed lines: at worst a PIN
+  // stays cached for longer than configured, up to the limit gpg-agent
+  // imposes.
+  let times = data
+    .lines()
+    .filter_map(|line| {
+      let mut parts = line.split(' ');
+      match (parts.next(), parts.next().map(str::parse::<u64>)) {
+        (Some(id), Some(Ok(time))) => Some((id.to_string(), time)),
+        _ => None,
+      }
+    })
+    .collect();
+  Ok(times)
+}
+
+/// Write the times at which PINs were cached.
+fn write_cache_times(times: &BTreeMap<String, u64>) -> Result<(), Error> {
+  let data = times
+    .iter()
+    .map(|(id, time)| format!("{} {}\n", id, time))
+    .collect::<String>();
+  storage::PIN_CACHE.write(&data)
+}
+
+/// Clear the cached passphrase of the given type if it has been cached for at least `ttl` seconds.
+fn expire_passphrase(ctx: &args::ExecCtx, pin_type: PinType, ttl: u64) -> Result<(), Error> {
+  match read_cache_times()?.get(pin_type.cache_id()) {
+    Some(time) if storage::now().saturating_sub(*time) >= ttl => {
+      info!("The cached {} expired", pin_type.prompt());
+      clear_passphrase(ctx, pin_type)
+    }
+    _ => Ok(()),
+  }
+}
+
+/// Record the current time as the time the passphrase of the given type got cached, unless a
+/// time is recorded already.
+fn record_passphrase(pin_type: PinType) -> Result<(), Error> {
+  let mut times = read_cache_times()?;
+  if !times.contains_key(pin_type.cache_id()) {
+    let _ = times.insert(pin_type.cache_id().to_string(), storage::now());
+    write_cache_times(&times)?;
+  }
+  Ok(())
 }
 
 /// Inquire a PIN of the given type from the user.
 ///
 /// This function inquires a PIN of the given type from the user or returns the cached passphrase,
-/// if available.  If an error message is set, it is displayed in the passphrase dialog.
-pub fn inquire_passphrase(pin_type: PinType, error_msg: Option<&str>) -> Result<Vec<u8>, Error> {
-  let cache_id = pin_type.cache_id();
-  let error_msg = error_msg
+/// if available.  If an error message is set, it is displayed in the passphrase dialog.  PINs
+/// inquired in a mode other than `Mode::Query` are never read from or stored in the cache.  For
+/// queried PINs, the cache lifetime configured for the PIN type is honored.  If gpg-agent or
+/// pinentry are not available, the PIN is inquired from the terminal instead.  If the keychain
+/// is configured as the cache, gpg-agent only serves for inquiring the PIN and the PIN is cached
+/// in the keychain.
+pub fn inquire_passphrase(
+  ctx: &args::ExecCtx,
+  pin_type: PinType,
+  mode: Mode,
+  error_msg: Option<&str>,
+) -> Result<Secret, Error> {
+  let _suspension = watchdog::suspend();
+  let ttl = if mode.is_cached() {
+    cache_ttl(ctx, pin_type)?
+  } else {
+    Some(0)
+  };
+  // gpg-agent interprets a cache ID of "X" as a request to bypass the
+  // cache altogether.
+  let cache_id = match ttl {
+    Some(0) => "X",
+    Some(ttl) => {
+      expire_passphrase(ctx, pin_type, ttl)?;
+      pin_type.cache_id()
+    }
+    None => pin_type.cache_id(),
+  };
+  if cache_id != "X" {
+    let session = ctx.session.borrow();
+    if let Some(passphrase) = session.as_ref().and_then(|s| s.pin(pin_type, ttl)) {
+      debug!(
+        "Using the {} entered earlier in the shell",
+        pin_type.prompt()
+      );
+      return Ok(passphrase);
+    }
+  }
+  let keychain = cache(&ctx.config)? == Cache::Keychain && cache_id != "X";
+  if keychain {
+    if let Some(passphrase) = keychain::find(pin_type.cache_id())? {
+      debug!("Using the {} from the keychain", pin_type.prompt());
+      return Ok(passphrase);
+    }
+  }
+  let cache_id = if keychain { "X" } else { cache_id };
+  let escaped_error_msg = error_msg
     .map(|msg| msg.replace(" ", "+"))
     .unwrap_or_else(|| String::from("+"));
   let prompt = pin_type.prompt().replace(" ", "+");
-  let description = pin_type.description().replace(" ", "+");
+  let description = pin_type.description(mode).replace(" ", "+");
 
-  let args = vec![cache_id, &error_msg, &prompt, &description].join(" ");
+  debug!(
+    "Inquiring the {} using gpg-agent with cache ID {}",
+    pin_type.prompt(),
+    cache_id
+  );
+  let args = vec![cache_id, &escaped_error_msg, &prompt, &description].join(" ");
   let command = "GET_PASSPHRASE --data ".to_string() + &args;
   // We could also use the --data parameter here to have a more direct
   // representation of the passphrase but the resulting response was
@@ -101,11 +404,40 @@ pub fn inquire_passphrase(pin_type: PinType, error_msg: Option<&str>) -> Result<
   // reported for the GET_PASSPHRASE command does not actually cause
   // gpg-connect-agent to exit with a non-zero error code, we have to
   // evaluate the output to determine success/failure.
-  let output = process::Command::new("gpg-connect-agent")
-    .arg(command)
-    .arg("/bye")
-    .output()?;
-  parse_pinentry_passphrase(output.stdout)
+  let passphrase = match connect_agent(&command)? {
+    Some(response) => parse_pinentry_passphrase(response)?,
+    None if keychain => inquire_tty(&ctx.config, pin_type, mode, error_msg)?,
+    None => return inquire_tty(&ctx.config, pin_type, mode, error_msg),
+  };
+
+  if keychain {
+    keychain::store(pin_type.cache_id(), &passphrase)?;
+  }
+
+  if let Some(ttl) = ttl {
+    if ttl > 0 {
+      record_passphrase(pin_type)?;
+    }
+  }
+  Ok(passphrase)
+}
+
+/// Let the user choose a new PIN of the given type.
+///
+/// The PIN is inquired twice and an error is returned if the two entries do not match, before
+/// the new PIN is used for anything.  A PIN not satisfying the configured PIN policy is rejected
+/// before it is inquired for the second time.
+pub fn choose_passphrase(ctx: &args::ExecCtx, pin_type: PinType) -> Result<Secret, Error> {
+  let policy = policy::Policy::load(&ctx.config)?;
+  let new_pin = inquire_passphrase(ctx, pin_type, Mode::Choose, None)?;
+  policy.check(new_pin.as_str()?)?;
+  let confirm_pin = inquire_passphrase(ctx, pin_type, Mode::Confirm, None)?;
+
+  if new_pin != confirm_pin {
+    Err(Error::Error("Entered PINs do not match".to_string()))
+  } else {
+    Ok(new_pin)
+  }
 }
 
 fn parse_pinentry_response(response: Vec<u8>) -> Result<(), Error> {
@@ -119,25 +451,96 @@ fn parse_pinentry_response(response: Vec<u8>) -> Result<(), Error> {
   Err(Error::Error("Unexpected response: ".to_string() + &string))
 }
 
+/// Escape the given string for use as an argument to a gpg-agent
+/// command.
+#[cfg(any(feature = "otp", feature = "pws"))]
+fn escape_argument(s: &str) -> String {
+  s.replace('%', "%25").replace('+', "%2B").replace(' ', "+")
+}
+
+/// Ask the user to confirm the action with the given description using
+/// pinentry.
+///
+/// The function returns whether the user confirmed the action. As there
+/// is no terminal fallback, it is an error if gpg-agent is not
+/// available.
+#[cfg(any(feature = "otp", feature = "pws"))]
+pub fn confirm(description: &str) -> Result<bool, Error> {
+  let _suspension = watchdog::suspend();
+  let command = "GET_CONFIRMATION ".to_string() + &escape_argument(description);
+  match connect_agent(&command)? {
+    Some(response) => {
+      let response = String::from_utf8(response)?;
+      Ok(response.lines().any(|line| line == "OK"))
+    }
+    None => Err(Error::Error(
+      "Asking for confirmation requires gpg-agent".to_string(),
+    )),
+  }
+}
+
+/// Remember the given passphrase of the given type, which was used
+/// successfully, for the remainder of the shell the command is executed
+/// in, if any, unless caching is disabled.
+pub fn remember_passphrase(
+  ctx: &args::ExecCtx,
+  pin_type: PinType,
+  passphrase: &Secret,
+) -> Result<(), Error> {
+  if let Some(session) = ctx.session.borrow_mut().as_mut() {
+    if cache_ttl(ctx, pin_type)? != Some(0) {
+      session.remember(pin_type, passphrase)
+    }
+  }
+  Ok(())
+}
+
 /// Clear the cached passphrase of the given type.
-pub fn clear_passphrase(pin_type: PinType) -> Result<(), Error> {
+pub fn clear_passphrase(ctx: &args::ExecCtx, pin_type: PinType) -> Result<(), Error> {
+  if let Some(session) = ctx.session.borrow_mut().as_mut() {
+    session.forget(pin_type)
+  }
+
   let command = "CLEAR_PASSPHRASE ".to_string() + pin_type.cache_id();
-  let output = process::Command::new("gpg-connect-agent")
-    .arg(command)
-    .arg("/bye")
-    .output()?;
+  // If gpg-agent is not available there is nothing that could be
+  // cached.
+  if let Some(response) = connect_agent(&command)? {
+    parse_pinentry_response(response)?;
+  }
+  if cfg!(target_os = "macos") {
+    keychain::delete(pin_type.cache_id())?;
+  }
 
-  parse_pinentry_response(output.stdout)
+  let mut times = read_cache_times()?;
+  if times.remove(pin_type.cache_id()).is_some() {
+    write_cache_times(&times)?;
+  }
+  Ok(())
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
 
+  #[test]
+  fn cache_from_config() {
+    let cache = |config: &str| cache(&config.parse::<config::Config>().unwrap());
+    assert_eq!(cache("").unwrap(), Cache::Agent);
+    assert_eq!(
+      cache("[pinentry]\ncache = 'gpg-agent'").unwrap(),
+      Cache::Agent
+    );
+    assert_eq!(
+      cache("[pinentry]\ncache = 'keychain'").is_ok(),
+      cfg!(target_os = "macos")
+    );
+    assert!(cache("[pinentry]\ncache = 'wallet'").is_err());
+  }
+
   #[test]
   fn parse_pinentry_passphrase_good() {
     let response = "D passphrase\nOK\n".to_string().into_bytes();
-    let expected = "passphrase".to_string().into_bytes();
+    let expected = Secret::from("passphrase");
 
     assert_eq!(parse_pinentry_passphrase(response).unwrap(), expected)
   }
@@ -171,6 +574,26 @@ mod tests {
     }
   }
 
+  #[test]
+  fn agent_unavailable_no_response() {
+    assert!(is_agent_unavailable(b""))
+  }
+
+  #[test]
+  fn agent_unavailable_no_pinentry() {
+    assert!(is_agent_unavailable(
+      b"ERR 67108949 No pinentry <GPG Agent>\n"
+    ))
+  }
+
+  #[test]
+  fn agent_available() {
+    assert!(!is_agent_unavailable(b"D passphrase\nOK\n"));
+    assert!(!is_agent_unavailable(
+      b"ERR 83886179 Operation cancelled <Pinentry>\n"
+    ));
+  }
+
   #[test]
   fn parse_pinentry_response_ok() {
     let response = "OK\n".to_string().into_bytes();
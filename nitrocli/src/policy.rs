@@ -0,0 +1,216 @@
+// policy.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::fs;
+use std::result;
+
+use crate::config;
+use crate::error::Context;
+use crate::error::Error;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The table of the configuration defining the PIN policy.
+const SECTION: &str = "pin-policy";
+
+/// The configuration keys defining the PIN policy, along with the kinds
+/// of their values.
+pub const CONFIG_KEYS: &[(&str, &str, config::Kind)] = &[
+  (SECTION, "deny", config::Kind::Strings),
+  (SECTION, "file", config::Kind::String),
+  (SECTION, "min-length", config::Kind::Integer),
+  (SECTION, "require", config::Kind::Strings),
+];
+
+/// A class of characters a PIN may be required to contain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CharClass {
+  Digits,
+  Lowercase,
+  Uppercase,
+  Symbols,
+}
+
+impl CharClass {
+  /// Parse a character class from the name used in the configuration.
+  fn parse(name: &str) -> Result<Self> {
+    match name {
+      "digits" => Ok(CharClass::Digits),
+      "lowercase" => Ok(CharClass::Lowercase),
+      "uppercase" => Ok(CharClass::Uppercase),
+      "symbols" => Ok(CharClass::Symbols),
+      _ => Err(Error::Error(format!(
+        "Invalid character class in {}.require: {} (expected one of digits, lowercase, uppercase, symbols)",
+        SECTION, name
+      ))),
+    }
+  }
+
+  /// Retrieve a description of the characters in this class.
+  fn description(self) -> &'static str {
+    match self {
+      CharClass::Digits => "digit",
+      CharClass::Lowercase => "lowercase letter",
+      CharClass::Uppercase => "uppercase letter",
+      CharClass::Symbols => "symbol",
+    }
+  }
+
+  /// Check whether the given character belongs to this class.
+  fn contains(self, c: char) -> bool {
+    match self {
+      CharClass::Digits => c.is_ascii_digit(),
+      CharClass::Lowercase => c.is_lowercase(),
+      CharClass::Uppercase => c.is_uppercase(),
+      CharClass::Symbols => !c.is_alphanumeric(),
+    }
+  }
+}
+
+/// The requirements new PINs have to satisfy.
+#[derive(Debug, Default, PartialEq)]
+pub struct Policy {
+  /// The minimum number of characters.
+  min_length: usize,
+  /// The classes of characters that have to be contained.
+  require: Vec<CharClass>,
+  /// PINs that may not be used, compared ignoring case.
+  deny: Vec<String>,
+}
+
+impl Policy {
+  /// Load the policy from the `pin-policy` table of the given
+  /// configuration.
+  ///
+  /// If the table references a policy file, the policy is read from the
+  /// `pin-policy` table of that file instead, so that a shared policy
+  /// can be referenced rather than copied. As the reference is part of
+  /// the user's configuration, the policy is a safeguard against
+  /// accidentally choosing weak PINs and not enforced against the user.
+  pub fn load(config: &config::Config) -> Result<Self> {
+    match config.get_string(SECTION, "file")? {
+      Some(path) => {
+        let text =
+          fs::read_to_string(path).context(|| format!("Could not read the PIN policy {}", path))?;
+        let policy = text.parse::<config::Config>().map_err(|err| {
+          Error::Error(format!("Could not parse the PIN policy {}: {}", path, err))
+        })?;
+        Self::from_config(&policy)
+      }
+      None => Self::from_config(config),
+    }
+  }
+
+  /// Read the policy from the `pin-policy` table of the given
+  /// configuration, ignoring any policy file.
+  fn from_config(config: &config::Config) -> Result<Self> {
+    let min_length = match config.get_integer(SECTION, "min-length")? {
+      Some(length) if length < 0 => {
+        let error = format!("Invalid value for {}.min-length: {}", SECTION, length);
+        return Err(Error::Error(error));
+      }
+      Some(length) => length as usize,
+      None => 0,
+    };
+    let require = config
+      .get_strings(SECTION, "require")?
+      .unwrap_or_default()
+      .into_iter()
+      .map(CharClass::parse)
+      .collect::<Result<Vec<_>>>()?;
+    let deny = config
+      .get_strings(SECTION, "deny")?
+      .unwrap_or_default()
+      .into_iter()
+      .map(str::to_lowercase)
+      .collect();
+    Ok(Policy {
+      min_length,
+      require,
+      deny,
+    })
+  }
+
+  /// Check that the given PIN satisfies the policy.
+  ///
+  /// The error lists all requirements the PIN violates, without
+  /// revealing the PIN.
+  pub fn check(&self, pin: &str) -> Result<()> {
+    let mut violations = Vec::new();
+    if pin.chars().count() < self.min_length {
+      violations.push(format!("is shorter than {} characters", self.min_length));
+    }
+    for class in &self.require {
+      if !pin.chars().any(|c| class.contains(c)) {
+        violations.push(format!("contains no {}", class.description()));
+      }
+    }
+    if self.deny.contains(&pin.to_lowercase()) {
+      violations.push("is on the deny-list".to_string());
+    }
+
+    if violations.is_empty() {
+      Ok(())
+    } else {
+      let error = format!(
+        "The PIN does not satisfy the PIN policy: it {}",
+        violations.join(", ")
+      );
+      Err(Error::Error(error))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn policy(text: &str) -> Result<Policy> {
+    Policy::load(&text.parse::<config::Config>().unwrap())
+  }
+
+  #[test]
+  fn empty_policy() {
+    let policy = policy("").unwrap();
+    assert_eq!(policy, Policy::default());
+    assert!(policy.check("123456").is_ok());
+  }
+
+  #[test]
+  fn check_pins() {
+    let policy = policy(
+      "[pin-policy]\nmin-length = 8\nrequire = [\"digits\", \"uppercase\"]\ndeny = [\"Passw0rdX\"]",
+    )
+    .unwrap();
+    assert!(policy.check("Secret123").is_ok());
+    assert_eq!(
+      policy.check("123456").unwrap_err().to_string(),
+      "The PIN does not satisfy the PIN policy: it is shorter than 8 characters, contains no uppercase letter"
+    );
+    assert!(policy.check("secret123").is_err());
+    assert!(policy.check("passw0rdx").is_err());
+  }
+
+  #[test]
+  fn invalid_policy() {
+    assert!(policy("[pin-policy]\nmin-length = -1").is_err());
+    assert!(policy("[pin-policy]\nrequire = [\"emoji\"]").is_err());
+    assert!(policy("[pin-policy]\nfile = \"/nonexistent/policy.toml\"").is_err());
+  }
+}
@@ -0,0 +1,375 @@
+// provision.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::path;
+use std::result;
+
+#[cfg(feature = "otp")]
+use crate::args;
+use crate::config;
+use crate::error::Error;
+use crate::module;
+use crate::secret::Secret;
+use crate::vault;
+
+type Result<T> = result::Result<T, Error>;
+
+/// Check that the given section of the profile has no keys other than
+/// the given ones.
+fn check_keys(profile: &config::Config, section: &str, keys: &[&str]) -> Result<()> {
+  match profile
+    .entries(section)
+    .find(|(key, _)| !keys.contains(key))
+  {
+    Some((key, _)) if section.is_empty() => {
+      Err(Error::Error(format!("Unsupported profile key: {}", key)))
+    }
+    Some((key, _)) => Err(Error::Error(format!(
+      "Unsupported profile key: {}.{}",
+      section, key
+    ))),
+    None => Ok(()),
+  }
+}
+
+/// Parse the slot number in the name of the given table.
+#[cfg(any(feature = "otp", feature = "pws"))]
+fn parse_slot(section: &str, slot: &str) -> Result<u8> {
+  slot
+    .parse()
+    .map_err(|_| Error::Error(format!("Invalid slot in profile table: {}", section)))
+}
+
+/// Retrieve the integer value of the given key, which must be between
+/// zero and the given maximum, if any.
+fn get_bounded(
+  profile: &config::Config,
+  section: &str,
+  key: &str,
+  max: Option<i64>,
+) -> Result<Option<i64>> {
+  match (profile.get_integer(section, key)?, max) {
+    (Some(n), _) if n < 0 => {
+      let value = config::Value::Integer(n);
+      Err(config::type_error(
+        section,
+        key,
+        &value,
+        "a non-negative integer",
+      ))
+    }
+    (Some(n), Some(max)) if n > max => {
+      let value = config::Value::Integer(n);
+      let expected = format!("an integer between 0 and {}", max);
+      Err(config::type_error(section, key, &value, &expected))
+    }
+    (n, _) => Ok(n),
+  }
+}
+
+/// Retrieve the state of a slot, which is either present or absent.
+#[cfg(any(feature = "otp", feature = "pws"))]
+fn is_present(profile: &config::Config, section: &str) -> Result<bool> {
+  match profile.get_string(section, "state")? {
+    Some("present") | None => Ok(true),
+    Some("absent") => Ok(false),
+    Some(state) => Err(Error::Error(format!(
+      "Invalid slot state in profile table {}: {}",
+      section, state
+    ))),
+  }
+}
+
+/// Retrieve a string value that a present slot requires.
+#[cfg(any(feature = "otp", feature = "pws"))]
+fn get_required(profile: &config::Config, section: &str, key: &str) -> Result<String> {
+  profile
+    .get_string(section, key)?
+    .map(ToString::to_string)
+    .ok_or_else(|| {
+      Error::Error(format!(
+        "The profile table {} lacks the {} key",
+        section, key
+      ))
+    })
+}
+
+/// Parse the HOTP slot a key of the keyboard is bound to, with `false`
+/// disabling the binding.
+fn parse_key_slot(profile: &config::Config, key: &str) -> Result<Option<Option<u8>>> {
+  match profile.get("config", key) {
+    Some(config::Value::Boolean(false)) => Ok(Some(None)),
+    Some(config::Value::Integer(_)) => {
+      Ok(get_bounded(profile, "config", key, Some(2))?.map(|slot| Some(slot as u8)))
+    }
+    Some(value) => Err(config::type_error(
+      "config",
+      key,
+      value,
+      "a slot number or false",
+    )),
+    None => Ok(None),
+  }
+}
+
+fn parse_config(profile: &config::Config) -> Result<module::ConfigTask> {
+  check_keys(
+    profile,
+    "config",
+    &["numlock", "capslock", "scrollock", "user-password"],
+  )?;
+  Ok(module::ConfigTask {
+    numlock: parse_key_slot(profile, "numlock")?,
+    capslock: parse_key_slot(profile, "capslock")?,
+    scrollock: parse_key_slot(profile, "scrollock")?,
+    user_password: profile.get_bool("config", "user-password")?,
+  })
+}
+
+#[cfg(feature = "otp")]
+fn parse_otp_slot(
+  profile: &config::Config,
+  section: &str,
+  algorithm: args::OtpAlgorithm,
+  slot: u8,
+) -> Result<module::OtpSlotTask> {
+  check_keys(
+    profile,
+    section,
+    &[
+      "state",
+      "name",
+      "secret",
+      "digits",
+      "time-window",
+      "counter",
+    ],
+  )?;
+  let state = if is_present(profile, section)? {
+    let digits = match profile.get_integer(section, "digits")? {
+      Some(digits) => args::parse_digits(&digits.to_string())? as u8,
+      None => 6,
+    };
+    let counter = get_bounded(profile, section, "counter", None)?;
+    module::OtpSlotState::Present {
+      name: get_required(profile, section, "name")?,
+      secret: Secret::from(get_required(profile, section, "secret")?),
+      digits,
+      time_window: get_bounded(profile, section, "time-window", Some(0xffff))?.unwrap_or(30) as u16,
+      counter: counter.unwrap_or(0) as u64,
+    }
+  } else {
+    module::OtpSlotState::Absent
+  };
+  Ok(module::OtpSlotTask {
+    algorithm,
+    slot,
+    state,
+  })
+}
+
+#[cfg(feature = "pws")]
+fn parse_pws_slot(
+  profile: &config::Config,
+  section: &str,
+  slot: u8,
+) -> Result<module::PwsSlotTask> {
+  check_keys(profile, section, &["state", "name", "login", "password"])?;
+  let state = if is_present(profile, section)? {
+    module::PwsSlotState::Present {
+      name: get_required(profile, section, "name")?,
+      login: profile
+        .get_string(section, "login")?
+        .unwrap_or_default()
+        .to_string(),
+      password: Secret::from(get_required(profile, section, "password")?),
+    }
+  } else {
+    module::PwsSlotState::Absent
+  };
+  Ok(module::PwsSlotTask { slot, state })
+}
+
+/// Parse a provisioning profile into a task describing the desired
+/// state of the device.
+pub fn parse(profile: &config::Config) -> Result<module::Task> {
+  check_keys(
+    profile,
+    "",
+    &[
+      "admin-pin",
+      #[cfg(any(feature = "pws", feature = "storage"))]
+      "user-pin",
+      #[cfg(feature = "storage")]
+      "encrypted-volume",
+    ],
+  )?;
+
+  let mut task = module::Task::default();
+  for section in profile.sections() {
+    let parts = section.split('.').collect::<Vec<_>>();
+    match parts.as_slice() {
+      [""] => (),
+      ["config"] => task.config = Some(parse_config(profile)?),
+      #[cfg(feature = "otp")]
+      ["otp", algorithm, slot] => {
+        let algorithm = algorithm
+          .parse()
+          .map_err(|()| Error::Error(format!("Unsupported profile table: {}", section)))?;
+        let slot = parse_slot(section, slot)?;
+        task
+          .otp_slots
+          .push(parse_otp_slot(profile, section, algorithm, slot)?);
+      }
+      #[cfg(feature = "pws")]
+      ["pws", slot] => {
+        let slot = parse_slot(section, slot)?;
+        task.pws_slots.push(parse_pws_slot(profile, section, slot)?);
+      }
+      _ => {
+        let error = format!("Unsupported profile table: {}", section);
+        return Err(Error::Error(error));
+      }
+    }
+  }
+
+  #[cfg(feature = "storage")]
+  {
+    task.encrypted_volume = match profile.get_string("", "encrypted-volume")? {
+      Some("open") => Some(true),
+      Some("closed") => Some(false),
+      Some(state) => {
+        let error = format!("Invalid encrypted volume state: {}", state);
+        return Err(Error::Error(error));
+      }
+      None => None,
+    };
+  }
+  task.admin_pin = profile
+    .get_string("", "admin-pin")?
+    .map(|pin| Secret::from(pin.to_string()));
+  #[cfg(any(feature = "pws", feature = "storage"))]
+  {
+    task.user_pin = profile
+      .get_string("", "user-pin")?
+      .map(|pin| Secret::from(pin.to_string()));
+  }
+  Ok(task)
+}
+
+/// Read the provisioning profile from the file with the given path.
+///
+/// Profiles encrypted using gpg, e.g., by `vault encrypt`, are
+/// decrypted.
+pub fn read(path: &str) -> Result<module::Task> {
+  let text = vault::read(path::Path::new(path))?;
+  let profile = text.as_str()?.parse::<config::Config>().map_err(|err| {
+    let error = format!("Invalid profile {}: {}", path, err);
+    Error::Error(error)
+  })?;
+  parse(&profile).map_err(|err| Error::Error(format!("Invalid profile {}: {}", path, err)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse_str(profile: &str) -> Result<module::Task> {
+    parse(&profile.parse::<config::Config>().unwrap())
+  }
+
+  #[test]
+  #[cfg(all(feature = "otp", feature = "pws", feature = "storage"))]
+  fn parse_full_profile() {
+    let profile = r#"
+      admin-pin = "12345678"
+      encrypted-volume = "closed"
+
+      [config]
+      numlock = 0
+      capslock = false
+      user-password = true
+
+      [otp.totp.1]
+      name = "github"
+      secret = "3132"
+      digits = 8
+
+      [otp.hotp.0]
+      state = "absent"
+
+      [pws.2]
+      name = "mail"
+      password = "hunter2"
+    "#;
+    let expected = module::Task {
+      config: Some(module::ConfigTask {
+        numlock: Some(Some(0)),
+        capslock: Some(None),
+        scrollock: None,
+        user_password: Some(true),
+      }),
+      otp_slots: vec![
+        module::OtpSlotTask {
+          algorithm: args::OtpAlgorithm::Hotp,
+          slot: 0,
+          state: module::OtpSlotState::Absent,
+        },
+        module::OtpSlotTask {
+          algorithm: args::OtpAlgorithm::Totp,
+          slot: 1,
+          state: module::OtpSlotState::Present {
+            name: "github".to_string(),
+            secret: Secret::from("3132"),
+            digits: 8,
+            time_window: 30,
+            counter: 0,
+          },
+        },
+      ],
+      pws_slots: vec![module::PwsSlotTask {
+        slot: 2,
+        state: module::PwsSlotState::Present {
+          name: "mail".to_string(),
+          login: String::new(),
+          password: Secret::from("hunter2"),
+        },
+      }],
+      encrypted_volume: Some(false),
+      admin_pin: Some(Secret::from("12345678")),
+      user_pin: None,
+      check_mode: false,
+    };
+    assert_eq!(parse_str(profile).unwrap(), expected);
+  }
+
+  #[test]
+  fn parse_invalid_profiles() {
+    assert_eq!(parse_str("").unwrap(), module::Task::default());
+    assert!(parse_str("pin = \"123456\"").is_err());
+    assert!(parse_str("[config]\nnumlock = 3").is_err());
+    assert!(parse_str("[config]\ncapslock = true").is_err());
+    assert!(parse_str("[config]\nuser-password = \"yes\"").is_err());
+    assert!(parse_str("[firmware]\nversion = \"0.53\"").is_err());
+    assert!(parse_str("[otp.totp.1]\nname = \"github\"").is_err());
+    assert!(parse_str("[otp.totp.x]\nstate = \"absent\"").is_err());
+    assert!(parse_str("[otp.totp.1]\nstate = \"gone\"").is_err());
+    assert!(parse_str("[pws.1]\nname = \"mail\"\nemail = \"a@b\"").is_err());
+  }
+}
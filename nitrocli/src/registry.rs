@@ -0,0 +1,285 @@
+// registry.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::result;
+use std::str;
+
+use crate::error::Error;
+use crate::storage;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The kinds of slots an alias can refer to.
+pub const KINDS: &[&str] = &["otp", "pws"];
+
+/// A slot an alias refers to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Target {
+  /// The kind of the slot, one of `KINDS`.
+  pub kind: String,
+  /// The number of the slot.
+  pub slot: u8,
+  /// The offset in seconds added to the current time when generating
+  /// a TOTP using the slot.
+  pub offset: i64,
+}
+
+impl fmt::Display for Target {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}/{}", self.kind, self.slot)?;
+    if self.offset != 0 {
+      write!(f, "@{:+}", self.offset)?;
+    }
+    Ok(())
+  }
+}
+
+impl str::FromStr for Target {
+  type Err = Error;
+
+  /// Parse a target of the form `<kind>/<slot>`, e.g., `otp/3`,
+  /// optionally followed by `@<offset>` for OTP slots, e.g.,
+  /// `otp/3@-15`.
+  fn from_str(s: &str) -> Result<Self> {
+    let mut parts = s.splitn(2, '@');
+    let (slot, offset) = (parts.next().unwrap_or(""), parts.next());
+    let mut parts = slot.splitn(2, '/');
+    let parsed = (
+      parts.next(),
+      parts.next().map(str::parse::<u8>),
+      offset.map(str::parse::<i64>),
+    );
+    match parsed {
+      (Some(kind), Some(Ok(slot)), None) if KINDS.contains(&kind) => Ok(Target {
+        kind: kind.to_string(),
+        slot,
+        offset: 0,
+      }),
+      (Some("otp"), Some(Ok(slot)), Some(Ok(offset))) => Ok(Target {
+        kind: "otp".to_string(),
+        slot,
+        offset,
+      }),
+      _ => {
+        let error = format!(
+          "Invalid slot: {} (expected <{}>/<number>[@<offset>])",
+          s,
+          KINDS.join("|")
+        );
+        Err(Error::Error(error))
+      }
+    }
+  }
+}
+
+/// A registry of aliases for the slots of devices.
+///
+/// The names that fit into the name fields of the slots are short, so
+/// users can register aliases of their own. Aliases are local to the
+/// system and indexed by the serial number of the device, so that the
+/// same alias can refer to different slots on different devices.
+#[derive(Debug, Default, PartialEq)]
+pub struct Registry {
+  aliases: BTreeMap<(String, String), Target>,
+}
+
+impl Registry {
+  /// Load the registry from its file.
+  pub fn load() -> Result<Self> {
+    let data = storage::SLOT_ALIASES.read()?.unwrap_or_default();
+    // The registry is parsed leniently, so parsing cannot fail.
+    Ok(data.parse().unwrap())
+  }
+
+  /// Save the registry to its file.
+  pub fn save(&self) -> Result<()> {
+    storage::SLOT_ALIASES.write(&self.to_string())
+  }
+
+  /// Register the given alias for the given slot of the device with the
+  /// given serial number, replacing the slot it referred to, if any.
+  pub fn add(&mut self, serial: &str, alias: &str, target: Target) {
+    let _ = self
+      .aliases
+      .insert((serial.to_string(), alias.to_string()), target);
+  }
+
+  /// Remove the given alias of the device with the given serial number.
+  ///
+  /// Returns whether the alias was registered.
+  pub fn remove(&mut self, serial: &str, alias: &str) -> bool {
+    self
+      .aliases
+      .remove(&(serial.to_string(), alias.to_string()))
+      .is_some()
+  }
+
+  /// Look up the time offset registered for the given alias of the
+  /// device with the given serial number, in seconds.
+  #[cfg_attr(not(feature = "otp"), allow(dead_code))]
+  pub fn offset(&self, serial: &str, alias: &str) -> i64 {
+    self
+      .aliases
+      .get(&(serial.to_string(), alias.to_string()))
+      .map(|target| target.offset)
+      .unwrap_or(0)
+  }
+
+  /// Check whether the given alias is registered for any device.
+  #[cfg_attr(not(feature = "pws"), allow(dead_code))]
+  pub fn contains(&self, alias: &str) -> bool {
+    self.aliases.keys().any(|(_, a)| a == alias)
+  }
+
+  /// Look up the number of the slot of the given kind the given alias
+  /// refers to on the device with the given serial number.
+  pub fn lookup(&self, serial: &str, kind: &str, alias: &str) -> Option<u8> {
+    self
+      .aliases
+      .get(&(serial.to_string(), alias.to_string()))
+      .filter(|target| target.kind == kind)
+      .map(|target| target.slot)
+  }
+
+  /// Retrieve the aliases of the device with the given serial number
+  /// along with the slots they refer to.
+  pub fn aliases<'s>(&'s self, serial: &'s str) -> impl Iterator<Item = (&'s str, &'s Target)> {
+    self
+      .aliases
+      .iter()
+      .filter(move |((s, _), _)| s == serial)
+      .map(|((_, alias), target)| (alias.as_str(), target))
+  }
+
+  /// Retrieve the aliases for slots of the given kind, of any device.
+  #[cfg_attr(not(feature = "otp"), allow(dead_code))]
+  pub fn names<'s>(&'s self, kind: &'s str) -> impl Iterator<Item = &'s str> {
+    self
+      .aliases
+      .iter()
+      .filter(move |(_, target)| target.kind == kind)
+      .map(|((_, alias), _)| alias.as_str())
+  }
+}
+
+/// The registry is stored with one line per alias, containing the
+/// serial number, the slot, and the alias, separated by a space.
+impl fmt::Display for Registry {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for ((serial, alias), target) in &self.aliases {
+      writeln!(f, "{} {} {}", serial, target, alias)?;
+    }
+    Ok(())
+  }
+}
+
+impl str::FromStr for Registry {
+  type Err = ();
+
+  /// Parse a registry, ignoring malformed lines.
+  fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+    let mut registry = Registry::default();
+    for line in s.lines() {
+      let mut parts = line.splitn(3, ' ');
+      let parsed = (
+        parts.next(),
+        parts
+          .next()
+          .and_then(|target| target.parse::<Target>().ok()),
+        parts.next(),
+      );
+      if let (Some(serial), Some(target), Some(alias)) = parsed {
+        registry.add(serial, alias, target);
+      }
+    }
+    Ok(registry)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn target(s: &str) -> Target {
+    s.parse().unwrap()
+  }
+
+  #[test]
+  fn parse_targets() {
+    assert_eq!(
+      target("otp/3"),
+      Target {
+        kind: "otp".to_string(),
+        slot: 3,
+        offset: 0,
+      }
+    );
+    assert_eq!(target("pws/15").to_string(), "pws/15");
+    assert_eq!(target("otp/2@+30").offset, 30);
+    assert_eq!(target("otp/2@-15").to_string(), "otp/2@-15");
+    assert_eq!(target("otp/2@0").to_string(), "otp/2");
+    assert!("otp".parse::<Target>().is_err());
+    assert!("otp/x".parse::<Target>().is_err());
+    assert!("foo/1".parse::<Target>().is_err());
+    assert!("pws/1@10".parse::<Target>().is_err());
+    assert!("otp/1@x".parse::<Target>().is_err());
+  }
+
+  #[test]
+  fn lookup() {
+    let mut registry = Registry::default();
+    registry.add("0x1", "github", target("otp/3"));
+    registry.add("0x1", "mail", target("pws/2"));
+    registry.add("0x2", "github", target("otp/1@-20"));
+
+    assert_eq!(registry.lookup("0x1", "otp", "github"), Some(3));
+    assert_eq!(registry.offset("0x1", "github"), 0);
+    assert_eq!(registry.offset("0x2", "github"), -20);
+    assert_eq!(registry.lookup("0x2", "otp", "github"), Some(1));
+    assert_eq!(registry.lookup("0x1", "pws", "github"), None);
+    assert_eq!(registry.lookup("0x3", "otp", "github"), None);
+    assert!(registry.contains("mail"));
+    assert!(!registry.contains("bank"));
+    assert_eq!(
+      registry.names("otp").collect::<Vec<_>>(),
+      vec!["github", "github"]
+    );
+
+    assert!(registry.remove("0x1", "github"));
+    assert!(!registry.remove("0x1", "github"));
+    assert_eq!(registry.lookup("0x1", "otp", "github"), None);
+  }
+
+  #[test]
+  fn round_trip() {
+    let mut registry = Registry::default();
+    registry.add("0x1", "my bank", target("otp/3"));
+    registry.add("0x1", "mail", target("pws/2"));
+
+    let data = registry.to_string();
+    assert_eq!(data, "0x1 pws/2 mail\n0x1 otp/3 my bank\n");
+    assert_eq!(data.parse::<Registry>().unwrap(), registry);
+    let parsed = "0x1 otp/3 github\n0x1 otp github\ngarbage\n"
+      .parse::<Registry>()
+      .unwrap();
+    assert_eq!(parsed.aliases("0x1").count(), 1);
+  }
+}
@@ -0,0 +1,255 @@
+// retry.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::fmt;
+use std::result;
+use std::thread;
+use std::time;
+
+use crate::config;
+use crate::error::Error;
+use crate::watchdog;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The configuration keys controlling retries, along with the kinds of
+/// their values.
+pub const CONFIG_KEYS: &[(&str, &str, config::Kind)] = &[
+  ("retry", "attempts", config::Kind::Integer),
+  ("retry", "delay", config::Kind::Integer),
+];
+
+/// The number of attempts for an operation, unless configured
+/// otherwise.
+const DEFAULT_ATTEMPTS: u32 = 3;
+/// The time to wait before the first retry, in milliseconds, unless
+/// configured otherwise.
+const DEFAULT_DELAY: u64 = 100;
+
+/// An error that may go away when the failed operation is retried.
+pub trait Transient {
+  /// Check whether the error is transient.
+  fn is_transient(&self) -> bool;
+}
+
+impl Transient for nitrokey::CommandError {
+  fn is_transient(&self) -> bool {
+    // A wrong checksum indicates a garbled exchange, e.g., because
+    // another program talked to the device at the same time.
+    *self == nitrokey::CommandError::WrongCrc
+  }
+}
+
+impl Transient for nitrocli_core::Error {
+  fn is_transient(&self) -> bool {
+    match self {
+      // Right after it was plugged in or while another program holds
+      // it, the device may not accept connections.
+      nitrocli_core::Error::NotFound => true,
+      nitrocli_core::Error::Command(_, err) => err.is_transient(),
+      _ => false,
+    }
+  }
+}
+
+impl Transient for Error {
+  fn is_transient(&self) -> bool {
+    match self.root_cause() {
+      Error::Command(_, err) => err.is_transient(),
+      Error::Core(err) => err.is_transient(),
+      _ => false,
+    }
+  }
+}
+
+/// The policy for retrying operations failing with a transient error.
+///
+/// The delay between two attempts starts at the configured one and
+/// doubles with every retry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Policy {
+  /// The maximum number of attempts, including the first one.
+  attempts: u32,
+  /// The time to wait before the first retry.
+  delay: time::Duration,
+}
+
+impl Policy {
+  /// Create the policy configured in the `retry` table of the given
+  /// configuration.
+  pub fn from_config(config: &config::Config) -> Result<Self> {
+    let attempts = match config.get_integer("retry", "attempts")? {
+      Some(attempts) if attempts < 1 => {
+        let error = format!(
+          "Invalid value for configuration key retry.attempts: {}",
+          attempts
+        );
+        return Err(Error::Error(error));
+      }
+      // More attempts than that would keep the user waiting for ages
+      // anyway.
+      Some(attempts) => attempts.min(1000) as u32,
+      None => DEFAULT_ATTEMPTS,
+    };
+    let delay = match config.get_integer("retry", "delay")? {
+      Some(delay) if delay < 0 => {
+        let error = format!("Invalid value for configuration key retry.delay: {}", delay);
+        return Err(Error::Error(error));
+      }
+      Some(delay) => delay as u64,
+      None => DEFAULT_DELAY,
+    };
+    Ok(Policy {
+      attempts,
+      delay: time::Duration::from_millis(delay),
+    })
+  }
+
+  /// Retrieve the time to wait after the given failed attempt, counted
+  /// from zero.
+  fn backoff(&self, attempt: u32) -> time::Duration {
+    // The shift is capped so that the delay cannot overflow.
+    self.delay * (1 << attempt.min(16))
+  }
+
+  /// Check whether the operation that failed with the given error in
+  /// the given attempt, counted from zero, is to be retried and, if so,
+  /// wait before the next attempt.
+  pub fn retry<E>(&self, attempt: u32, err: &E) -> bool
+  where
+    E: Transient + fmt::Display,
+  {
+    if !err.is_transient() || attempt + 1 >= self.attempts {
+      return false;
+    }
+    let backoff = self.backoff(attempt);
+    debug!(
+      "Attempt {} of {} failed: {}; retrying in {} ms",
+      attempt + 1,
+      self.attempts,
+      err,
+      backoff.as_secs() * 1000 + u64::from(backoff.subsec_millis())
+    );
+    thread::sleep(backoff);
+    true
+  }
+
+  /// Run the given operation, retrying it as long as it fails with a
+  /// transient error, attempts are left, and the command did not time
+  /// out.
+  pub fn run<F, R, E>(&self, mut op: F) -> result::Result<R, E>
+  where
+    F: FnMut() -> result::Result<R, E>,
+    E: Transient + fmt::Display,
+  {
+    let mut attempt = 0;
+    loop {
+      match op() {
+        Err(ref err) if !watchdog::expired() && self.retry(attempt, err) => attempt += 1,
+        result => return result,
+      }
+    }
+  }
+}
+
+impl Default for Policy {
+  fn default() -> Self {
+    Policy {
+      attempts: DEFAULT_ATTEMPTS,
+      delay: time::Duration::from_millis(DEFAULT_DELAY),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn policy(attempts: u32) -> Policy {
+    Policy {
+      attempts,
+      delay: time::Duration::from_millis(0),
+    }
+  }
+
+  #[test]
+  fn transient_errors() {
+    assert!(nitrokey::CommandError::WrongCrc.is_transient());
+    assert!(!nitrokey::CommandError::WrongPassword.is_transient());
+    assert!(nitrocli_core::Error::NotFound.is_transient());
+    assert!(!nitrocli_core::Error::NoSuchSlot("x".to_string()).is_transient());
+
+    let err = Error::Command("test".to_string(), nitrokey::CommandError::WrongCrc);
+    assert!(err.context("context").is_transient());
+    assert!(!Error::Error("test".to_string()).is_transient());
+  }
+
+  #[test]
+  fn retry_transient_errors() {
+    let mut calls = 0;
+    let result = policy(3).run(|| {
+      calls += 1;
+      if calls < 3 {
+        Err(nitrokey::CommandError::WrongCrc)
+      } else {
+        Ok(calls)
+      }
+    });
+    assert_eq!(result, Ok(3));
+
+    let mut calls = 0;
+    let result = policy(2).run(|| {
+      calls += 1;
+      Err::<(), _>(nitrokey::CommandError::WrongCrc)
+    });
+    assert_eq!(result, Err(nitrokey::CommandError::WrongCrc));
+    assert_eq!(calls, 2);
+
+    let mut calls = 0;
+    let result = policy(3).run(|| {
+      calls += 1;
+      Err::<(), _>(nitrokey::CommandError::WrongPassword)
+    });
+    assert_eq!(result, Err(nitrokey::CommandError::WrongPassword));
+    assert_eq!(calls, 1);
+  }
+
+  #[test]
+  fn backoff() {
+    let policy = Policy::default();
+    assert_eq!(policy.backoff(0), time::Duration::from_millis(100));
+    assert_eq!(policy.backoff(1), time::Duration::from_millis(200));
+    assert_eq!(policy.backoff(3), time::Duration::from_millis(800));
+  }
+
+  #[test]
+  fn policy_from_config() {
+    let config = "[retry]\nattempts = 5\ndelay = 20"
+      .parse::<config::Config>()
+      .unwrap();
+    let policy = Policy::from_config(&config).unwrap();
+    assert_eq!(policy.attempts, 5);
+    assert_eq!(policy.delay, time::Duration::from_millis(20));
+
+    let config = "[retry]\nattempts = 0".parse::<config::Config>().unwrap();
+    assert!(Policy::from_config(&config).is_err());
+    let config = "[retry]\ndelay = -1".parse::<config::Config>().unwrap();
+    assert!(Policy::from_config(&config).is_err());
+  }
+}
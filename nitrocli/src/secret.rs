@@ -0,0 +1,274 @@
+// secret.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::fmt;
+use std::io;
+use std::ptr;
+use std::str;
+use std::sync::atomic;
+
+use crate::error::Error;
+
+/// Overwrite the memory of the given buffer, including its spare
+/// capacity, with zeros.
+///
+/// The writes are volatile, so that the compiler cannot optimize them
+/// away even though the buffer is not read afterwards.
+pub fn wipe(data: &mut Vec<u8>) {
+  let ptr = data.as_mut_ptr();
+  for i in 0..data.capacity() {
+    unsafe { ptr::write_volatile(ptr.add(i), 0) };
+  }
+  atomic::compiler_fence(atomic::Ordering::SeqCst);
+  data.clear();
+}
+
+/// Whether core dumps of the process have been disabled.
+static NO_CORE_DUMPS: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+/// Disable core dumps of the process, as they could reveal secrets.
+///
+/// Once disabled, core dumps stay disabled for the lifetime of the
+/// process, because copies of secrets made by libraries may outlive
+/// the `Secret` objects. The limit on the size of core dumps is
+/// inherited by spawned programs.
+fn disable_core_dumps() {
+  if NO_CORE_DUMPS.swap(true, atomic::Ordering::SeqCst) {
+    return;
+  }
+
+  let mut limit = libc::rlimit {
+    rlim_cur: 0,
+    rlim_max: 0,
+  };
+  unsafe {
+    if libc::getrlimit(libc::RLIMIT_CORE, &mut limit) == 0 {
+      limit.rlim_cur = 0;
+      if libc::setrlimit(libc::RLIMIT_CORE, &limit) != 0 {
+        debug!(
+          "Could not limit the size of core dumps: {}",
+          io::Error::last_os_error()
+        );
+      }
+    }
+  }
+  // Core dump handlers configured through a pipe in
+  // /proc/sys/kernel/core_pattern do not honor the size limit, but they
+  // do honor the dumpable flag.
+  #[cfg(target_os = "linux")]
+  unsafe {
+    if libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) != 0 {
+      debug!(
+        "Could not make the process non-dumpable: {}",
+        io::Error::last_os_error()
+      );
+    }
+  }
+}
+
+/// Lock the memory of the given buffer, including its spare capacity,
+/// into RAM, so that it is never written to swap space.
+///
+/// Failure to lock the memory, e.g., because the `RLIMIT_MEMLOCK` limit
+/// was hit, is not an error.
+fn lock(data: &[u8], capacity: usize) {
+  if capacity == 0 {
+    return;
+  }
+  disable_core_dumps();
+  if unsafe { libc::mlock(data.as_ptr() as *const libc::c_void, capacity) } != 0 {
+    debug!(
+      "Could not lock the memory of a secret: {}",
+      io::Error::last_os_error()
+    );
+  }
+}
+
+/// Unlock the memory of the given buffer locked by `lock`.
+fn unlock(data: &[u8], capacity: usize) {
+  if capacity > 0 {
+    let _ = unsafe { libc::munlock(data.as_ptr() as *const libc::c_void, capacity) };
+  }
+}
+
+/// A secret, such as a PIN or a password.
+///
+/// The memory holding the secret is locked into RAM and wiped when it
+/// is dropped, and the `Debug` representation does not reveal it. There
+/// deliberately is no `Display` implementation, so that printing the
+/// secret requires an explicit `as_str`.
+#[derive(Default, PartialEq)]
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+  /// Create a secret taking ownership of the given buffer.
+  fn from_vec(data: Vec<u8>) -> Self {
+    lock(&data, data.capacity());
+    Secret(data)
+  }
+
+  /// Create a new, empty secret.
+  pub fn new() -> Self {
+    Default::default()
+  }
+
+  /// Retrieve the secret as bytes.
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.0
+  }
+
+  /// Retrieve the secret as a string, failing if it is not valid
+  /// UTF-8.
+  pub fn as_str(&self) -> Result<&str, Error> {
+    str::from_utf8(&self.0).map_err(|_| Error::Error("The secret is not valid UTF-8".to_string()))
+  }
+
+  /// Retrieve the last byte of the secret, if any.
+  pub fn last(&self) -> Option<u8> {
+    self.0.last().cloned()
+  }
+
+  /// Append the given byte to the secret.
+  ///
+  /// Unlike pushing to a `Vec`, growing the secret does not leave a
+  /// copy of it behind in the memory released.
+  pub fn push(&mut self, byte: u8) {
+    if self.0.len() == self.0.capacity() {
+      let mut data = Vec::with_capacity((self.0.capacity() * 2).max(32));
+      lock(&data, data.capacity());
+      data.extend_from_slice(&self.0);
+      let capacity = self.0.capacity();
+      wipe(&mut self.0);
+      unlock(&self.0, capacity);
+      self.0 = data;
+    }
+    self.0.push(byte)
+  }
+
+  /// Append the given bytes to the secret.
+  pub fn extend_from_slice(&mut self, data: &[u8]) {
+    for &byte in data {
+      self.push(byte)
+    }
+  }
+
+  /// Remove the last byte of the secret and return it, if any.
+  pub fn pop(&mut self) -> Option<u8> {
+    self.0.pop()
+  }
+}
+
+impl Clone for Secret {
+  fn clone(&self) -> Self {
+    let mut data = Vec::with_capacity(self.0.len());
+    lock(&data, data.capacity());
+    data.extend_from_slice(&self.0);
+    Secret(data)
+  }
+}
+
+impl Drop for Secret {
+  fn drop(&mut self) {
+    let capacity = self.0.capacity();
+    wipe(&mut self.0);
+    unlock(&self.0, capacity);
+  }
+}
+
+impl fmt::Debug for Secret {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("Secret(..)")
+  }
+}
+
+impl From<Vec<u8>> for Secret {
+  fn from(data: Vec<u8>) -> Self {
+    Secret::from_vec(data)
+  }
+}
+
+impl From<String> for Secret {
+  fn from(data: String) -> Self {
+    Secret::from_vec(data.into_bytes())
+  }
+}
+
+impl<'s> From<&'s str> for Secret {
+  fn from(data: &'s str) -> Self {
+    let mut secret = Secret::new();
+    secret.extend_from_slice(data.as_bytes());
+    secret
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn wipe_buffer() {
+    let mut data = b"123456".to_vec();
+    let _ = data.pop();
+    let ptr = data.as_ptr();
+    let capacity = data.capacity();
+    wipe(&mut data);
+    assert!(data.is_empty());
+    let wiped = unsafe { std::slice::from_raw_parts(ptr, capacity) };
+    assert!(wiped.iter().all(|&byte| byte == 0));
+  }
+
+  #[test]
+  fn push_and_pop() {
+    let mut secret = Secret::new();
+    for &byte in b"a rather long passphrase that needs to grow the secret\n" {
+      secret.push(byte);
+    }
+    assert_eq!(secret.pop(), Some(b'\n'));
+    assert_eq!(secret.last(), Some(b't'));
+    assert_eq!(
+      secret.as_str().unwrap(),
+      "a rather long passphrase that needs to grow the secret"
+    );
+  }
+
+  #[test]
+  fn clone_secret() {
+    let secret = Secret::from("123456");
+    assert_eq!(secret.clone(), secret);
+    assert_eq!(Secret::new().clone(), Secret::new());
+  }
+
+  #[test]
+  fn core_dumps_disabled() {
+    let _ = Secret::from("123456");
+    let mut limit = libc::rlimit {
+      rlim_cur: 1,
+      rlim_max: 1,
+    };
+    assert_eq!(unsafe { libc::getrlimit(libc::RLIMIT_CORE, &mut limit) }, 0);
+    assert_eq!(limit.rlim_cur, 0);
+  }
+
+  #[test]
+  fn debug_is_redacted() {
+    let secret = Secret::from("123456");
+    assert_eq!(format!("{:?}", secret), "Secret(..)");
+    assert!(Secret::from(vec![0xff]).as_str().is_err());
+  }
+}
@@ -0,0 +1,317 @@
+// session.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::io::Read;
+use std::os::unix::io::AsRawFd;
+use std::process;
+use std::result;
+
+use crate::args;
+use crate::commands;
+use crate::config;
+use crate::error::Error;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The configuration keys controlling the reaction to the desktop
+/// session being locked, along with the kinds of their values.
+pub const CONFIG_KEYS: &[(&str, &str, config::Kind)] = &[
+  ("session", "close-on-lock", config::Kind::Boolean),
+  ("session", "reopen-on-unlock", config::Kind::Boolean),
+];
+
+/// The interface of sessions managed by logind.
+const LOGIND_SESSION: &str = "org.freedesktop.login1.Session";
+/// The interfaces of the screen savers of GNOME and of KDE (along with
+/// other desktops implementing the freedesktop.org one).
+const SCREEN_SAVERS: &[&str] = &["org.gnome.ScreenSaver", "org.freedesktop.ScreenSaver"];
+
+/// A change of the lock state of the desktop session.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+  Locked,
+  Unlocked,
+}
+
+impl Event {
+  /// Retrieve the name of the monitor event reporting this change.
+  pub fn name(self) -> &'static str {
+    match self {
+      Event::Locked => "session-locked",
+      Event::Unlocked => "session-unlocked",
+    }
+  }
+}
+
+/// Retrieve the value of the given field of a line of `dbus-monitor`
+/// describing a signal.
+fn field<'l>(line: &'l str, name: &str) -> Option<&'l str> {
+  line
+    .split_whitespace()
+    .filter(|word| word.starts_with(name) && word[name.len()..].starts_with('='))
+    .map(|word| word[name.len() + 1..].trim_end_matches(';'))
+    .next()
+}
+
+/// A parser for the output of `dbus-monitor`.
+#[derive(Debug, Default)]
+struct Parser {
+  /// Whether the last line started an `ActiveChanged` signal of a
+  /// screen saver, whose argument follows on the next line.
+  active_changed: bool,
+}
+
+impl Parser {
+  /// Parse the given line, returning the lock state change it
+  /// completes, if any.
+  fn parse(&mut self, line: &str) -> Option<Event> {
+    let line = line.trim();
+    let active_changed = self.active_changed;
+    self.active_changed = false;
+
+    if line.starts_with("signal ") {
+      match (field(line, "interface"), field(line, "member")) {
+        (Some(LOGIND_SESSION), Some("Lock")) => Some(Event::Locked),
+        (Some(LOGIND_SESSION), Some("Unlock")) => Some(Event::Unlocked),
+        (Some(interface), Some("ActiveChanged")) if SCREEN_SAVERS.contains(&interface) => {
+          self.active_changed = true;
+          None
+        }
+        _ => None,
+      }
+    } else if active_changed {
+      match line {
+        "boolean true" => Some(Event::Locked),
+        "boolean false" => Some(Event::Unlocked),
+        _ => None,
+      }
+    } else {
+      None
+    }
+  }
+}
+
+/// A `dbus-monitor` process reporting the signals of one bus.
+#[derive(Debug)]
+struct Source {
+  child: process::Child,
+  /// Output of the process that does not form a complete line yet.
+  buffer: Vec<u8>,
+  parser: Parser,
+}
+
+impl Source {
+  /// Start watching the signals matching the given rules on the given
+  /// bus.
+  fn spawn(bus: &str, rules: &[String]) -> Result<Self> {
+    let child = process::Command::new("dbus-monitor")
+      .arg(bus)
+      .args(rules)
+      .stdin(process::Stdio::null())
+      .stdout(process::Stdio::piped())
+      .stderr(process::Stdio::null())
+      .spawn()
+      .map_err(|err| {
+        Error::Error(format!(
+          "Could not watch the session lock using dbus-monitor: {}",
+          err
+        ))
+      })?;
+    Ok(Source {
+      child,
+      buffer: Vec::new(),
+      parser: Default::default(),
+    })
+  }
+
+  /// Read the output available and extract the lock state changes it
+  /// reports.
+  ///
+  /// Returns the changes along with whether the process is still
+  /// running.
+  fn receive(&mut self) -> (Vec<Event>, bool) {
+    let mut data = [0; 4096];
+    let count = match self
+      .child
+      .stdout
+      .as_mut()
+      .map(|stdout| stdout.read(&mut data))
+    {
+      Some(Ok(count)) => count,
+      _ => 0,
+    };
+    self.buffer.extend_from_slice(&data[..count]);
+
+    let mut events = Vec::new();
+    while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+      let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+      if let Some(event) = self.parser.parse(&String::from_utf8_lossy(&line)) {
+        events.push(event);
+      }
+    }
+    (events, count > 0)
+  }
+}
+
+impl Drop for Source {
+  fn drop(&mut self) {
+    let _ = self.child.kill();
+    let _ = self.child.wait();
+  }
+}
+
+/// The integration with the lock of the desktop session.
+///
+/// If enabled using the `close-on-lock` key of the `session` table, the
+/// open encrypted or hidden volume is closed when the session is
+/// locked, as reported by logind or the screen saver of GNOME or KDE.
+/// If the `reopen-on-unlock` key is set, too, the user is asked for the
+/// PIN to open it again once the session is unlocked.
+#[derive(Debug)]
+pub struct Session {
+  sources: Vec<Source>,
+  reopen: bool,
+  /// Whether a volume was closed because the session was locked.
+  closed: bool,
+}
+
+impl Session {
+  /// Start watching the lock of the session, if configured in the
+  /// given configuration.
+  pub fn start(config: &config::Config) -> Result<Self> {
+    let mut sources = Vec::new();
+    if config
+      .get_bool("session", "close-on-lock")?
+      .unwrap_or(false)
+    {
+      let rule = |interface: &str, member: &str| {
+        format!(
+          "type='signal',interface='{}',member='{}'",
+          interface, member
+        )
+      };
+      let logind = [rule(LOGIND_SESSION, "Lock"), rule(LOGIND_SESSION, "Unlock")];
+      sources.push(Source::spawn("--system", &logind)?);
+      let screen_savers = SCREEN_SAVERS
+        .iter()
+        .map(|interface| rule(interface, "ActiveChanged"))
+        .collect::<Vec<_>>();
+      sources.push(Source::spawn("--session", &screen_savers)?);
+      debug!("Watching the lock of the session");
+    }
+    Ok(Session {
+      sources,
+      reopen: config
+        .get_bool("session", "reopen-on-unlock")?
+        .unwrap_or(false),
+      closed: false,
+    })
+  }
+
+  /// Retrieve the file descriptors to wait for before calling
+  /// `receive`.
+  pub fn fds(&self) -> Vec<i32> {
+    self
+      .sources
+      .iter()
+      .filter_map(|source| source.child.stdout.as_ref())
+      .map(AsRawFd::as_raw_fd)
+      .collect()
+  }
+
+  /// Extract the lock state changes reported by the readable file
+  /// descriptors, given in the order of `fds`.
+  pub fn receive(&mut self, readable: &[bool]) -> Vec<Event> {
+    let mut events = Vec::new();
+    let mut running = Vec::with_capacity(self.sources.len());
+    for (mut source, &readable) in self.sources.drain(..).zip(readable) {
+      if readable {
+        let (received, alive) = source.receive();
+        events.extend(received);
+        if !alive {
+          warn!("Stopped watching the lock of the session as dbus-monitor exited");
+          continue;
+        }
+      }
+      running.push(source);
+    }
+    self.sources = running;
+    events
+  }
+
+  /// React to the given lock state change.
+  ///
+  /// Failures are reported but do not stop the caller, as the session
+  /// may well be locked again later.
+  pub fn handle(&mut self, ctx: &args::ExecCtx, event: Event) {
+    let result = match event {
+      Event::Locked => commands::close_open_volume(ctx).map(|closed| {
+        self.closed |= closed;
+      }),
+      Event::Unlocked if self.closed => {
+        self.closed = false;
+        if self.reopen {
+          commands::open(ctx)
+        } else {
+          Ok(())
+        }
+      }
+      Event::Unlocked => Ok(()),
+    };
+    if let Err(err) = result {
+      warn!("Could not react to the {}: {}", event.name(), err);
+    }
+    commands::unlock_device(ctx);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(lines: &[&str]) -> Vec<Event> {
+    let mut parser = Parser::default();
+    lines.iter().filter_map(|line| parser.parse(line)).collect()
+  }
+
+  #[test]
+  fn parse_logind() {
+    let lines = [
+      "signal time=1545000000.120143 sender=org.freedesktop.DBus -> destination=:1.42 serial=2 path=/org/freedesktop/DBus; interface=org.freedesktop.DBus; member=NameAcquired",
+      "   string \":1.42\"",
+      "signal time=1545000012.411093 sender=:1.3 -> destination=(null destination) serial=2210 path=/org/freedesktop/login1/session/_32; interface=org.freedesktop.login1.Session; member=Lock",
+      "signal time=1545000107.503716 sender=:1.3 -> destination=(null destination) serial=2213 path=/org/freedesktop/login1/session/_32; interface=org.freedesktop.login1.Session; member=Unlock",
+    ];
+    assert_eq!(parse(&lines), vec![Event::Locked, Event::Unlocked]);
+  }
+
+  #[test]
+  fn parse_screen_saver() {
+    let lines = [
+      "signal time=1545000012.411093 sender=:1.20 -> destination=(null destination) serial=341 path=/org/gnome/ScreenSaver; interface=org.gnome.ScreenSaver; member=ActiveChanged",
+      "   boolean true",
+      "   boolean false",
+      "signal time=1545000107.503716 sender=:1.27 -> destination=(null destination) serial=95 path=/ScreenSaver; interface=org.freedesktop.ScreenSaver; member=ActiveChanged",
+      "   boolean false",
+      "signal time=1545000110.503716 sender=:1.27 -> destination=(null destination) serial=96 path=/ScreenSaver; interface=org.example.Other; member=ActiveChanged",
+      "   boolean true",
+    ];
+    assert_eq!(parse(&lines), vec![Event::Locked, Event::Unlocked]);
+  }
+}
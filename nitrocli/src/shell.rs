@@ -0,0 +1,490 @@
+// shell.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use std::result;
+use std::time;
+
+use crate::completions;
+use crate::error::Error;
+use crate::pinentry;
+use crate::secret::Secret;
+use crate::tty;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The state kept across the commands executed in a shell.
+#[derive(Debug, Default)]
+pub struct Session {
+  /// The PINs that were used successfully, along with the time they
+  /// were entered at.
+  pins: Vec<(pinentry::PinType, Secret, time::Instant)>,
+}
+
+impl Session {
+  /// Retrieve the PIN of the given type, unless it was entered more
+  /// than `ttl` seconds ago.
+  pub fn pin(&self, pin_type: pinentry::PinType, ttl: Option<u64>) -> Option<Secret> {
+    self
+      .pins
+      .iter()
+      .find(|(t, _, _)| *t == pin_type)
+      .filter(|(_, _, time)| match ttl {
+        Some(ttl) => time.elapsed() < time::Duration::from_secs(ttl),
+        None => true,
+      })
+      .map(|(_, pin, _)| pin.clone())
+  }
+
+  /// Remember the given PIN for the remainder of the session.
+  pub fn remember(&mut self, pin_type: pinentry::PinType, pin: &Secret) {
+    if self.pin(pin_type, None).as_ref() != Some(pin) {
+      self.forget(pin_type);
+      self
+        .pins
+        .push((pin_type, pin.clone(), time::Instant::now()));
+    }
+  }
+
+  /// Forget the PIN of the given type, if it is known.
+  pub fn forget(&mut self, pin_type: pinentry::PinType) {
+    self.pins.retain(|(t, _, _)| *t != pin_type)
+  }
+}
+
+/// Split the given line into words the way a POSIX shell does, i.e.,
+/// at unquoted white space, honoring single and double quotes and
+/// backslash escapes. A `#` at the start of a word starts a comment.
+pub fn split(line: &str) -> Result<Vec<String>> {
+  let mut words = Vec::new();
+  let mut word: Option<String> = None;
+  let mut chars = line.chars();
+  while let Some(c) = chars.next() {
+    match c {
+      ' ' | '\t' => words.extend(word.take()),
+      '#' if word.is_none() => break,
+      '\'' => {
+        let word = word.get_or_insert_with(String::new);
+        loop {
+          match chars.next() {
+            Some('\'') => break,
+            Some(c) => word.push(c),
+            None => return Err(Error::Error("Unterminated single quote".to_string())),
+          }
+        }
+      }
+      '"' => {
+        let word = word.get_or_insert_with(String::new);
+        loop {
+          match chars.next() {
+            Some('"') => break,
+            Some('\\') => match chars.next() {
+              Some(c @ '"') | Some(c @ '\\') | Some(c @ '$') | Some(c @ '`') => word.push(c),
+              Some(c) => {
+                word.push('\\');
+                word.push(c)
+              }
+              None => break,
+            },
+            Some(c) => word.push(c),
+            None => return Err(Error::Error("Unterminated double quote".to_string())),
+          }
+        }
+      }
+      '\\' => {
+        let word = word.get_or_insert_with(String::new);
+        if let Some(c) = chars.next() {
+          word.push(c)
+        }
+      }
+      c => word.get_or_insert_with(String::new).push(c),
+    }
+  }
+  words.extend(word);
+  Ok(words)
+}
+
+/// Determine the completions of the last word of the given beginning
+/// of a command line.
+fn complete(program: &completions::Command, line: &str) -> Vec<String> {
+  let mut words = line.split_whitespace().collect::<Vec<_>>();
+  let partial = if line.ends_with(char::is_whitespace) {
+    ""
+  } else {
+    words.pop().unwrap_or("")
+  };
+  let mut candidates = completions::complete(program, &words, partial);
+  if words.is_empty() {
+    candidates.extend(
+      BUILTINS
+        .iter()
+        .filter(|(name, _)| name.starts_with(partial))
+        .map(|(name, _)| name.to_string()),
+    );
+    candidates.sort();
+  }
+  candidates
+}
+
+/// The commands of the shell in addition to the ones of the program,
+/// along with their descriptions.
+pub const BUILTINS: &[(&str, &str)] = &[("exit", "Leave the shell"), ("help", "Print this help")];
+
+/// Determine the longest common prefix of the given strings.
+fn common_prefix(strings: &[String]) -> String {
+  let mut prefix = strings.first().cloned().unwrap_or_default();
+  for s in strings {
+    while !s.starts_with(&prefix) {
+      let _ = prefix.pop();
+    }
+  }
+  prefix
+}
+
+/// A line editor for the shell.
+///
+/// If the standard input is a terminal, the editor supports moving the
+/// cursor, the usual Emacs style key bindings, browsing the history of
+/// lines entered before, and completing commands, options, and their
+/// arguments. Otherwise lines are read as they are.
+#[derive(Debug)]
+pub struct Editor {
+  /// Whether the standard input is a terminal.
+  interactive: bool,
+  /// The lines entered before.
+  history: Vec<String>,
+}
+
+/// The state of the line being edited.
+#[derive(Debug)]
+struct Line<'p> {
+  /// The prompt printed before the line.
+  prompt: &'p str,
+  /// The characters of the line.
+  chars: Vec<char>,
+  /// The position of the cursor.
+  pos: usize,
+}
+
+impl<'p> Line<'p> {
+  /// Print the line, placing the cursor at its position.
+  fn redraw(&self, out: &mut dyn Write) -> io::Result<()> {
+    let line = self.chars.iter().collect::<String>();
+    write!(out, "\r{}{}\x1b[K", self.prompt, line)?;
+    if self.pos < self.chars.len() {
+      write!(out, "\x1b[{}D", self.chars.len() - self.pos)?;
+    }
+    out.flush()
+  }
+
+  /// Replace the line with the given text, placing the cursor at its
+  /// end.
+  fn set(&mut self, text: &str) {
+    self.chars = text.chars().collect();
+    self.pos = self.chars.len();
+  }
+
+  /// Insert the given text at the cursor.
+  fn insert(&mut self, text: &str) {
+    for c in text.chars() {
+      self.chars.insert(self.pos, c);
+      self.pos += 1;
+    }
+  }
+
+  /// Remove the characters in the given range, placing the cursor at
+  /// its start.
+  fn remove(&mut self, start: usize, end: usize) {
+    let _ = self.chars.drain(start..end);
+    self.pos = start;
+  }
+
+  /// Delete the character at the cursor, if any.
+  fn delete(&mut self) {
+    if self.pos < self.chars.len() {
+      let _ = self.chars.remove(self.pos);
+    }
+  }
+
+  /// Retrieve the text of the line.
+  fn text(&self) -> String {
+    self.chars.iter().collect()
+  }
+}
+
+/// Read a byte from the given reader, returning `None` at end-of-file.
+fn read_byte(input: &mut dyn Read) -> Result<Option<u8>> {
+  let mut byte = [0; 1];
+  loop {
+    match input.read(&mut byte) {
+      Ok(0) => return Ok(None),
+      Ok(_) => return Ok(Some(byte[0])),
+      Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+      Err(err) => return Err(err.into()),
+    }
+  }
+}
+
+impl Editor {
+  /// Create a line editor reading from the standard input.
+  pub fn new() -> Self {
+    Editor {
+      interactive: tty::is_stdin_tty(),
+      history: Vec::new(),
+    }
+  }
+
+  /// Read a line, printing the given prompt and completing words using
+  /// the given description of the program.
+  ///
+  /// `None` is returned once the input ends.
+  pub fn read_line(
+    &mut self,
+    prompt: &str,
+    program: &completions::Command,
+  ) -> Result<Option<String>> {
+    if !self.interactive {
+      let mut line = String::new();
+      return match io::stdin().lock().read_line(&mut line)? {
+        0 => Ok(None),
+        _ => Ok(Some(line.trim_end_matches(&['\r', '\n'][..]).to_string())),
+      };
+    }
+
+    let _raw = tty::Raw::new(libc::STDIN_FILENO)?;
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut line = Line {
+      prompt,
+      chars: Vec::new(),
+      pos: 0,
+    };
+    // The index of the history entry shown and the line edited before
+    // browsing the history.
+    let mut index = self.history.len();
+    let mut edited = String::new();
+    line.redraw(&mut out)?;
+
+    loop {
+      let byte = match read_byte(&mut input)? {
+        Some(byte) => byte,
+        None if line.chars.is_empty() => {
+          write!(out, "\r\n")?;
+          return Ok(None);
+        }
+        None => b'\n',
+      };
+      match byte {
+        b'\r' | b'\n' => {
+          write!(out, "\r\n")?;
+          let text = line.text();
+          if !text.trim().is_empty() && self.history.last() != Some(&text) {
+            self.history.push(text.clone());
+          }
+          return Ok(Some(text));
+        }
+        // Ctrl-A
+        0x01 => line.pos = 0,
+        // Ctrl-B
+        0x02 => line.pos = line.pos.saturating_sub(1),
+        // Ctrl-C
+        0x03 => {
+          write!(out, "^C\r\n")?;
+          line.set("");
+          index = self.history.len();
+        }
+        // Ctrl-D
+        0x04 if line.chars.is_empty() => {
+          write!(out, "\r\n")?;
+          return Ok(None);
+        }
+        0x04 => line.delete(),
+        // Ctrl-E
+        0x05 => line.pos = line.chars.len(),
+        // Ctrl-F
+        0x06 => line.pos = (line.pos + 1).min(line.chars.len()),
+        // Backspace
+        0x08 | 0x7f if line.pos > 0 => {
+          line.pos -= 1;
+          line.delete()
+        }
+        // Tab
+        0x09 => {
+          let before = line.chars[..line.pos].iter().collect::<String>();
+          let partial = before
+            .rsplit(char::is_whitespace)
+            .next()
+            .unwrap_or("")
+            .to_string();
+          let candidates = complete(program, &before);
+          let prefix = common_prefix(&candidates);
+          if candidates.len() == 1 {
+            line.insert(&prefix[partial.len()..]);
+            line.insert(" ");
+          } else if prefix.len() > partial.len() {
+            line.insert(&prefix[partial.len()..]);
+          } else if candidates.is_empty() {
+            write!(out, "\x07")?;
+          } else {
+            write!(out, "\r\n{}\r\n", candidates.join("  "))?;
+          }
+        }
+        // Ctrl-K
+        0x0b => {
+          let (pos, len) = (line.pos, line.chars.len());
+          line.remove(pos, len)
+        }
+        // Ctrl-L
+        0x0c => write!(out, "\x1b[H\x1b[2J")?,
+        // Ctrl-P and Ctrl-N
+        0x10 => self.browse(&mut line, &mut index, &mut edited, true),
+        0x0e => self.browse(&mut line, &mut index, &mut edited, false),
+        // Ctrl-U
+        0x15 => {
+          let pos = line.pos;
+          line.remove(0, pos)
+        }
+        // Ctrl-W
+        0x17 => {
+          let end = line.pos;
+          let mut start = end;
+          while start > 0 && line.chars[start - 1].is_whitespace() {
+            start -= 1;
+          }
+          while start > 0 && !line.chars[start - 1].is_whitespace() {
+            start -= 1;
+          }
+          line.remove(start, end)
+        }
+        // Escape sequences of cursor and editing keys.
+        0x1b => {
+          let mut sequence = Vec::new();
+          match read_byte(&mut input)? {
+            Some(b'[') | Some(b'O') => {
+              while let Some(byte) = read_byte(&mut input)? {
+                sequence.push(byte);
+                if !byte.is_ascii_digit() && byte != b';' {
+                  break;
+                }
+              }
+            }
+            _ => (),
+          }
+          match sequence.as_slice() {
+            b"A" => self.browse(&mut line, &mut index, &mut edited, true),
+            b"B" => self.browse(&mut line, &mut index, &mut edited, false),
+            b"C" => line.pos = (line.pos + 1).min(line.chars.len()),
+            b"D" => line.pos = line.pos.saturating_sub(1),
+            b"H" | b"1~" | b"7~" => line.pos = 0,
+            b"F" | b"4~" | b"8~" => line.pos = line.chars.len(),
+            b"3~" => line.delete(),
+            _ => (),
+          }
+        }
+        byte if byte >= 0x20 => {
+          // Multi-byte UTF-8 sequences are read completely before they
+          // are inserted.
+          let len = match byte {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 1,
+          };
+          let mut bytes = vec![byte];
+          while bytes.len() < len {
+            match read_byte(&mut input)? {
+              Some(byte) => bytes.push(byte),
+              None => break,
+            }
+          }
+          if let Ok(text) = String::from_utf8(bytes) {
+            line.insert(&text);
+          }
+        }
+        _ => (),
+      }
+      line.redraw(&mut out)?;
+    }
+  }
+
+  /// Show the previous or next line of the history.
+  fn browse(&self, line: &mut Line<'_>, index: &mut usize, edited: &mut String, previous: bool) {
+    if previous && *index > 0 {
+      if *index == self.history.len() {
+        *edited = line.text();
+      }
+      *index -= 1;
+      line.set(&self.history[*index]);
+    } else if !previous && *index < self.history.len() {
+      *index += 1;
+      match self.history.get(*index) {
+        Some(text) => line.set(text),
+        None => line.set(edited),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn split_words() {
+    assert_eq!(split("").unwrap(), Vec::<String>::new());
+    assert_eq!(split("  otp get  rfc ").unwrap(), vec!["otp", "get", "rfc"]);
+    assert_eq!(
+      split(r#"pass show 'my mail' "a \"b\"" c\ d"#).unwrap(),
+      vec!["pass", "show", "my mail", "a \"b\"", "c d"]
+    );
+    assert_eq!(split("status # comment").unwrap(), vec!["status"]);
+    assert_eq!(split("a#b ''").unwrap(), vec!["a#b", ""]);
+    assert!(split("pass show 'mail").is_err());
+    assert!(split("pass show \"mail").is_err());
+  }
+
+  #[test]
+  fn session_pins() {
+    let mut session = Session::default();
+    assert_eq!(session.pin(pinentry::PinType::User, None), None);
+    session.remember(pinentry::PinType::User, &Secret::from("123456"));
+    assert_eq!(
+      session.pin(pinentry::PinType::User, None),
+      Some(Secret::from("123456"))
+    );
+    assert_eq!(session.pin(pinentry::PinType::User, Some(0)), None);
+    assert_eq!(session.pin(pinentry::PinType::Admin, None), None);
+    session.forget(pinentry::PinType::User);
+    assert_eq!(session.pin(pinentry::PinType::User, None), None);
+  }
+
+  #[test]
+  fn prefixes() {
+    let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    assert_eq!(common_prefix(&strings(&[])), "");
+    assert_eq!(common_prefix(&strings(&["pass", "pin"])), "p");
+    assert_eq!(common_prefix(&strings(&["close", "clear"])), "cl");
+    assert_eq!(common_prefix(&strings(&["status"])), "status");
+  }
+}
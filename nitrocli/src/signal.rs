@@ -0,0 +1,137 @@
+// signal.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::mem;
+use std::ptr;
+use std::result;
+use std::sync::atomic;
+
+use crate::error::Error;
+use crate::watchdog;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The signals terminating the program that are caught while an
+/// operation must not be interrupted.
+const SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+/// The last signal caught and not yet reported, or zero.
+static PENDING: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+/// The number of guards deferring signals that are alive.
+static DEFERRED: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+extern "C" fn handle(signal: libc::c_int) {
+  PENDING.store(signal as usize, atomic::Ordering::SeqCst);
+}
+
+/// Retrieve the name of the given signal.
+pub fn name(signal: i32) -> String {
+  match signal {
+    libc::SIGINT => "SIGINT".to_string(),
+    libc::SIGTERM => "SIGTERM".to_string(),
+    _ => format!("signal {}", signal),
+  }
+}
+
+/// A guard deferring the signals terminating the program for as long as
+/// it is alive.
+///
+/// A signal arriving in the meantime no longer kills the program in the
+/// middle of an exchange with the device. Instead, it is recorded and
+/// reported by the next call to `check`, which is supposed to happen
+/// once the device is in a defined state again.
+pub struct Guard {
+  /// The actions for the caught signals before the guard was created.
+  previous: Vec<(libc::c_int, libc::sigaction)>,
+}
+
+/// Defer the signals terminating the program until the returned guard
+/// is dropped.
+pub fn defer() -> Guard {
+  let mut previous = Vec::with_capacity(SIGNALS.len());
+  for &signal in &SIGNALS {
+    unsafe {
+      let mut action = mem::zeroed::<libc::sigaction>();
+      action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+      // Blocking system calls, e.g., the ones reading the response of
+      // the device, continue after the signal was handled.
+      action.sa_flags = libc::SA_RESTART;
+      let _ = libc::sigemptyset(&mut action.sa_mask);
+
+      let mut old = mem::zeroed::<libc::sigaction>();
+      if libc::sigaction(signal, &action, &mut old) == 0 {
+        previous.push((signal, old));
+      }
+    }
+  }
+  let _ = DEFERRED.fetch_add(1, atomic::Ordering::SeqCst);
+  Guard { previous }
+}
+
+impl Drop for Guard {
+  fn drop(&mut self) {
+    for (signal, action) in &self.previous {
+      let _ = unsafe { libc::sigaction(*signal, action, ptr::null_mut()) };
+    }
+    let _ = DEFERRED.fetch_sub(1, atomic::Ordering::SeqCst);
+  }
+}
+
+/// Check whether signals are deferred, i.e., whether an operation that
+/// must not be interrupted is in progress.
+pub fn is_deferred() -> bool {
+  DEFERRED.load(atomic::Ordering::SeqCst) > 0
+}
+
+/// Report a signal caught while signals were deferred, if any, as an
+/// `Error::Interrupted`, or an expired timeout as an `Error::TimedOut`.
+///
+/// A signal is reported only once.
+pub fn check() -> Result<()> {
+  match PENDING.swap(0, atomic::Ordering::SeqCst) {
+    0 => watchdog::check(),
+    signal => Err(Error::Interrupted(signal as i32)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn defer_signals() {
+    let guard = defer();
+    assert_eq!(unsafe { libc::raise(libc::SIGTERM) }, 0);
+    drop(guard);
+
+    match check() {
+      Err(Error::Interrupted(signal)) => assert_eq!(signal, libc::SIGTERM),
+      result => panic!("Unexpected result: {:?}", result),
+    }
+    assert!(check().is_ok());
+  }
+
+  #[test]
+  fn signal_names() {
+    assert_eq!(name(libc::SIGINT), "SIGINT");
+    assert_eq!(name(libc::SIGTERM), "SIGTERM");
+    assert_eq!(name(libc::SIGHUP), "signal 1");
+  }
+}
@@ -0,0 +1,238 @@
+// slots.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::result;
+use std::str;
+
+use crate::error::Error;
+use crate::storage;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The time in seconds after which cached slot names are considered
+/// stale.
+const MAX_AGE: u64 = 24 * 60 * 60;
+
+/// The names of the slots of one kind on one device, as cached at a
+/// certain time.
+#[derive(Debug, PartialEq)]
+struct Entry {
+  /// The time at which the names were retrieved.
+  time: u64,
+  /// The names of all programmed slots, indexed by slot number.
+  names: BTreeMap<u8, String>,
+}
+
+/// A cache of the names of the slots of devices.
+///
+/// Entries are indexed by the serial number of the device and the kind
+/// of the slots (e.g., `totp`). The cache is only used for resolving
+/// slot names to slot numbers and every resolved slot has to be
+/// verified against the device before use, as the slots may have been
+/// reprogrammed in the meantime.
+#[derive(Debug, Default, PartialEq)]
+pub struct SlotCache {
+  entries: BTreeMap<(String, String), Entry>,
+}
+
+impl SlotCache {
+  /// Load the cache from its file.
+  pub fn load() -> Result<Self> {
+    let data = storage::SLOT_CACHE.read()?.unwrap_or_default();
+    // The cache is parsed leniently, so parsing cannot fail.
+    Ok(data.parse().unwrap())
+  }
+
+  /// Save the cache to its file.
+  pub fn save(&self) -> Result<()> {
+    storage::SLOT_CACHE.write(&self.to_string())
+  }
+
+  /// Look up the number of the slot of the given kind with the given
+  /// name on the device with the given serial number.
+  ///
+  /// `None` is returned if no such slot is cached or if the cached
+  /// names of the device are older than `MAX_AGE` at the given time.
+  pub fn lookup(&self, serial: &str, kind: &str, name: &str, now: u64) -> Option<u8> {
+    let entry = self.entries.get(&(serial.to_string(), kind.to_string()))?;
+    if now.saturating_sub(entry.time) >= MAX_AGE {
+      return None;
+    }
+    entry
+      .names
+      .iter()
+      .find(|&(_, n)| n == name)
+      .map(|(&slot, _)| slot)
+  }
+
+  /// Check whether the names of the slots of the given kind on the
+  /// device with the given serial number are cached and not older than
+  /// `MAX_AGE` at the given time.
+  pub fn is_current(&self, serial: &str, kind: &str, now: u64) -> bool {
+    match self.entries.get(&(serial.to_string(), kind.to_string())) {
+      Some(entry) => now.saturating_sub(entry.time) < MAX_AGE,
+      None => false,
+    }
+  }
+
+  /// Retrieve the names of all cached slots, of any kind and device.
+  pub fn names(&self) -> BTreeSet<&str> {
+    self
+      .entries
+      .values()
+      .flat_map(|entry| entry.names.values())
+      .map(String::as_str)
+      .collect()
+  }
+
+  /// Retrieve the serial numbers of all devices with cached slots.
+  pub fn serial_numbers(&self) -> BTreeSet<&str> {
+    self
+      .entries
+      .keys()
+      .map(|(serial, _)| serial.as_str())
+      .collect()
+  }
+
+  /// Replace the cached names of the slots of the given kind on the
+  /// device with the given serial number.
+  pub fn update(&mut self, serial: &str, kind: &str, names: BTreeMap<u8, String>, now: u64) {
+    let entry = Entry { time: now, names };
+    let _ = self
+      .entries
+      .insert((serial.to_string(), kind.to_string()), entry);
+  }
+}
+
+/// The cache is stored with one line per slot, containing the serial
+/// number, the kind, the time of retrieval, the slot number, and the
+/// name, separated by a space.
+impl fmt::Display for SlotCache {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for ((serial, kind), entry) in &self.entries {
+      for (slot, name) in &entry.names {
+        writeln!(f, "{} {} {} {} {}", serial, kind, entry.time, slot, name)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+impl str::FromStr for SlotCache {
+  type Err = ();
+
+  /// Parse a cache, ignoring malformed lines.
+  fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+    let mut cache = SlotCache::default();
+    for line in s.lines() {
+      let mut parts = line.splitn(5, ' ');
+      let parsed = (
+        parts.next(),
+        parts.next(),
+        parts.next().and_then(|time| time.parse::<u64>().ok()),
+        parts.next().and_then(|slot| slot.parse::<u8>().ok()),
+        parts.next(),
+      );
+      if let (Some(serial), Some(kind), Some(time), Some(slot), Some(name)) = parsed {
+        let entry = cache
+          .entries
+          .entry((serial.to_string(), kind.to_string()))
+          .or_insert_with(|| Entry {
+            time,
+            names: BTreeMap::new(),
+          });
+        let _ = entry.names.insert(slot, name.to_string());
+      }
+    }
+    Ok(cache)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn names(names: &[(u8, &str)]) -> BTreeMap<u8, String> {
+    names
+      .iter()
+      .map(|&(slot, name)| (slot, name.to_string()))
+      .collect()
+  }
+
+  #[test]
+  fn lookup() {
+    let mut cache = SlotCache::default();
+    cache.update("0x1", "totp", names(&[(0, "github"), (3, "my bank")]), 100);
+
+    assert_eq!(cache.lookup("0x1", "totp", "my bank", 100), Some(3));
+    assert_eq!(cache.lookup("0x1", "totp", "gitlab", 100), None);
+    assert_eq!(cache.lookup("0x1", "hotp", "github", 100), None);
+    assert_eq!(cache.lookup("0x2", "totp", "github", 100), None);
+    assert_eq!(cache.lookup("0x1", "totp", "github", 100 + MAX_AGE), None);
+  }
+
+  #[test]
+  fn current_entries() {
+    let mut cache = SlotCache::default();
+    cache.update("0x1", "totp", names(&[(0, "github")]), 100);
+
+    assert!(cache.is_current("0x1", "totp", 100 + MAX_AGE - 1));
+    assert!(!cache.is_current("0x1", "totp", 100 + MAX_AGE));
+    assert!(!cache.is_current("0x1", "hotp", 100));
+  }
+
+  #[test]
+  fn names_and_serial_numbers() {
+    let mut cache = SlotCache::default();
+    cache.update("0x1", "totp", names(&[(0, "github"), (3, "my bank")]), 100);
+    cache.update("0x2", "hotp", names(&[(1, "github")]), 100);
+
+    let names = cache.names().into_iter().collect::<Vec<_>>();
+    assert_eq!(names, vec!["github", "my bank"]);
+    let serials = cache.serial_numbers().into_iter().collect::<Vec<_>>();
+    assert_eq!(serials, vec!["0x1", "0x2"]);
+  }
+
+  #[test]
+  fn round_trip() {
+    let mut cache = SlotCache::default();
+    cache.update("0x1", "totp", names(&[(0, "github"), (3, "my bank")]), 100);
+    cache.update("0x1", "hotp", names(&[(1, "ssh")]), 200);
+
+    let data = cache.to_string();
+    assert_eq!(
+      data,
+      "0x1 hotp 200 1 ssh\n0x1 totp 100 0 github\n0x1 totp 100 3 my bank\n"
+    );
+    assert_eq!(data.parse::<SlotCache>().unwrap(), cache);
+  }
+
+  #[test]
+  fn parse_malformed() {
+    let cache = "0x1 totp 100 0 github\n0x1 totp x 1 foo\ngarbage\n"
+      .parse::<SlotCache>()
+      .unwrap();
+    let mut expected = SlotCache::default();
+    expected.update("0x1", "totp", names(&[(0, "github")]), 100);
+    assert_eq!(cache, expected);
+  }
+}
@@ -0,0 +1,260 @@
+// storage.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path;
+use std::result;
+use std::time;
+
+use crate::error::Context;
+use crate::error::Error;
+use crate::tempfile;
+
+type Result<T> = result::Result<T, Error>;
+
+/// A base directory for files stored by the program.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dir {
+  /// The directory for data that can be recreated if lost, located at
+  /// `$XDG_CACHE_HOME/nitrocli`.
+  Cache,
+  /// The directory for data that should persist between invocations,
+  /// located at `$XDG_STATE_HOME/nitrocli`.
+  State,
+  /// The directory for sockets and other runtime files, located at
+  /// `$XDG_RUNTIME_DIR/nitrocli`, or in the cache directory if the
+  /// former is not set.
+  Runtime,
+}
+
+impl Dir {
+  /// Retrieve the path of the directory.
+  pub fn path(self) -> Result<path::PathBuf> {
+    let dir = match self {
+      Dir::Cache => xdg_dir("XDG_CACHE_HOME", ".cache")?,
+      Dir::State => xdg_dir("XDG_STATE_HOME", ".local/state")?,
+      Dir::Runtime => xdg_dir("XDG_RUNTIME_DIR", ".cache")?,
+    };
+    Ok(dir.join("nitrocli"))
+  }
+}
+
+/// A file stored by the program.
+#[derive(Debug)]
+pub struct File {
+  /// The directory the file is stored in.
+  dir: Dir,
+  /// The name of the file.
+  name: &'static str,
+}
+
+/// The file recording when PINs were cached.
+pub const PIN_CACHE: File = File {
+  dir: Dir::State,
+  name: "pin-cache",
+};
+
+/// The file caching the names of the slots of devices.
+#[cfg(feature = "otp")]
+pub const SLOT_CACHE: File = File {
+  dir: Dir::Cache,
+  name: "slot-names",
+};
+
+/// The file registering aliases for the slots of devices.
+#[cfg(any(feature = "otp", feature = "pws"))]
+pub const SLOT_ALIASES: File = File {
+  dir: Dir::State,
+  name: "slot-aliases",
+};
+
+/// The file recording the decoy hidden volumes of devices and their
+/// passwords, encrypted using gpg.
+#[cfg(feature = "storage")]
+pub const DECOYS: File = File {
+  dir: Dir::State,
+  name: "decoys.gpg",
+};
+
+/// The file recording the nicknames of devices.
+pub const DEVICE_NAMES: File = File {
+  dir: Dir::State,
+  name: "device-names",
+};
+
+/// The file recording the devices the program connected to.
+pub const INVENTORY: File = File {
+  dir: Dir::State,
+  name: "inventory",
+};
+
+/// The file caching facts about the connected devices.
+pub const DEVICE_CACHE: File = File {
+  dir: Dir::Cache,
+  name: "devices",
+};
+
+/// The socket the agent listens on.
+#[cfg(feature = "otp")]
+pub const AGENT_SOCKET: File = File {
+  dir: Dir::Runtime,
+  name: "agent.socket",
+};
+
+/// The log of the commands executed, if enabled.
+pub const AUDIT_LOG: File = File {
+  dir: Dir::State,
+  name: "audit.log",
+};
+
+/// The socket the monitor listens on by default.
+pub const MONITOR_SOCKET: File = File {
+  dir: Dir::Runtime,
+  name: "monitor.socket",
+};
+
+impl File {
+  /// Retrieve the path of the file.
+  pub fn path(&self) -> Result<path::PathBuf> {
+    Ok(self.dir.path()?.join(self.name))
+  }
+
+  /// Read the contents of the file, if it exists.
+  pub fn read(&self) -> Result<Option<String>> {
+    let path = self.path()?;
+    match fs::read_to_string(&path) {
+      Ok(data) => Ok(Some(data)),
+      Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(err) => Err(Error::IoError(err).context(format!("Could not read {}", path.display()))),
+    }
+  }
+
+  /// Replace the contents of the file with the given data.
+  pub fn write(&self, data: &str) -> Result<()> {
+    let path = self.path()?;
+    write(&path, data).context(|| format!("Could not write {}", path.display()))
+  }
+
+  /// Append the given line to the file, creating it if it does not
+  /// exist.
+  pub fn append(&self, line: &str) -> Result<()> {
+    let path = self.path()?;
+    append(&path, line).context(|| format!("Could not write {}", path.display()))
+  }
+}
+
+/// Create the parent directory of the given path, accessible only by
+/// the current user, if it does not exist.
+pub fn create_parent(path: &path::Path) -> Result<()> {
+  if let Some(dir) = path.parent() {
+    fs::DirBuilder::new()
+      .recursive(true)
+      .mode(0o700)
+      .create(dir)?;
+  }
+  Ok(())
+}
+
+/// Atomically replace the contents of the file at the given path with
+/// the given data. The file is only accessible by the current user.
+fn write(path: &path::Path, data: &str) -> Result<()> {
+  let dir = path.parent().unwrap_or_else(|| path::Path::new("."));
+  let name = path
+    .file_name()
+    .map(|name| name.to_string_lossy().into_owned())
+    .unwrap_or_default();
+  let mut file = tempfile::TempFile::new_in(dir, &name)?;
+  file.write_all(data.as_bytes())?;
+  file.persist(path)
+}
+
+/// Append the given line to the file at the given path. A newly
+/// created file is only accessible by the current user.
+fn append(path: &path::Path, line: &str) -> Result<()> {
+  create_parent(path)?;
+
+  let mut file = fs::OpenOptions::new()
+    .append(true)
+    .create(true)
+    .mode(0o600)
+    .open(path)?;
+  writeln!(file, "{}", line)?;
+  Ok(())
+}
+
+/// Retrieve the current time in seconds since the Unix epoch, as used
+/// for timestamps in stored files.
+pub fn now() -> u64 {
+  time::SystemTime::now()
+    .duration_since(time::UNIX_EPOCH)
+    .map(|duration| duration.as_secs())
+    .unwrap_or(0)
+}
+
+/// Retrieve the directory specified by the given XDG environment
+/// variable, falling back to the given path relative to the user's
+/// home directory.
+pub fn xdg_dir(var: &str, fallback: &str) -> Result<path::PathBuf> {
+  match env::var_os(var) {
+    Some(ref dir) if !dir.is_empty() => Ok(path::PathBuf::from(dir)),
+    _ => env::var_os("HOME")
+      .map(|home| path::Path::new(&home).join(fallback))
+      .ok_or_else(|| Error::Error("Could not determine the home directory".to_string())),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::process;
+
+  fn test_dir(name: &str) -> path::PathBuf {
+    let dir = env::temp_dir().join(format!("nitrocli-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+  }
+
+  #[test]
+  fn write_replaces() {
+    let dir = test_dir("write");
+    let path = dir.join("file");
+    write(&path, "foo").unwrap();
+    write(&path, "bar").unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "bar");
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn append_lines() {
+    let dir = test_dir("append");
+    let path = dir.join("file");
+    append(&path, "foo").unwrap();
+    append(&path, "bar").unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "foo\nbar\n");
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,171 @@
+// tempfile.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path;
+use std::process;
+use std::result;
+use std::sync::atomic;
+
+use crate::error::Error;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The number of temporary files created by the process so far, used to
+/// make their names unique.
+static COUNTER: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+/// A temporary file that is only accessible by the current user.
+///
+/// Unless it was persisted, the file is overwritten with zeros and
+/// removed when the object is dropped, which also happens if the
+/// operation it is used for fails or is interrupted by a deferred
+/// signal.
+#[derive(Debug)]
+pub struct TempFile {
+  /// The path of the file, or `None` once it was persisted.
+  path: Option<path::PathBuf>,
+  /// The open file.
+  file: fs::File,
+}
+
+impl TempFile {
+  /// Create a temporary file in the given directory.
+  ///
+  /// The directory is created, only accessible by the current user, if
+  /// it does not exist. The file is named after the given prefix and
+  /// is guaranteed not to have existed before.
+  pub fn new_in(dir: &path::Path, prefix: &str) -> Result<Self> {
+    fs::DirBuilder::new()
+      .recursive(true)
+      .mode(0o700)
+      .create(dir)?;
+
+    loop {
+      let count = COUNTER.fetch_add(1, atomic::Ordering::SeqCst);
+      let name = format!(".{}.{}-{}.tmp", prefix, process::id(), count);
+      let path = dir.join(name);
+      let result = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path);
+      match result {
+        Ok(file) => {
+          return Ok(TempFile {
+            path: Some(path),
+            file,
+          })
+        }
+        // A file left behind by a crashed process with the same ID.
+        Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+        Err(err) => return Err(err.into()),
+      }
+    }
+  }
+
+  /// Retrieve the path of the file.
+  pub fn path(&self) -> &path::Path {
+    self.path.as_ref().unwrap()
+  }
+
+  /// Write the given data to the file.
+  pub fn write_all(&mut self, data: &[u8]) -> Result<()> {
+    self.file.write_all(data)?;
+    Ok(())
+  }
+
+  /// Move the file to the given path, atomically replacing any file
+  /// there, and keep it.
+  ///
+  /// The given path has to reside on the same file system as the file.
+  pub fn persist(mut self, path: &path::Path) -> Result<()> {
+    self.file.sync_all()?;
+    fs::rename(self.path(), path)?;
+    self.path = None;
+    Ok(())
+  }
+}
+
+impl Drop for TempFile {
+  fn drop(&mut self) {
+    if let Some(path) = &self.path {
+      if let Err(err) = shred(path) {
+        debug!("Could not remove {}: {}", path.display(), err);
+      }
+    }
+  }
+}
+
+/// Overwrite the file at the given path with zeros and remove it.
+///
+/// Overwriting the file does not guarantee that its data is gone on
+/// copy-on-write file systems and flash storage, but it does on most
+/// others.
+pub fn shred(path: &path::Path) -> Result<()> {
+  let mut file = fs::OpenOptions::new().write(true).open(path)?;
+  let mut remaining = file.metadata()?.len();
+  let zeros = [0; 4096];
+  while remaining > 0 {
+    let count = remaining.min(zeros.len() as u64) as usize;
+    file.write_all(&zeros[..count])?;
+    remaining -= count as u64;
+  }
+  file.sync_all()?;
+  fs::remove_file(path)?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::env;
+  use std::os::unix::fs::PermissionsExt;
+
+  #[test]
+  fn remove_on_drop() {
+    let dir = env::temp_dir().join(format!("nitrocli-tempfile-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+
+    let mut file = TempFile::new_in(&dir, "test").unwrap();
+    file.write_all(b"secret").unwrap();
+    let path = file.path().to_path_buf();
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let mode = fs::metadata(&dir).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o700);
+    drop(file);
+    assert!(!path.exists());
+
+    let mut file = TempFile::new_in(&dir, "test").unwrap();
+    file.write_all(b"data").unwrap();
+    let target = dir.join("target");
+    file.persist(&target).unwrap();
+    assert_eq!(fs::read_to_string(&target).unwrap(), "data");
+
+    shred(&target).unwrap();
+    assert!(!target.exists());
+    fs::remove_dir(&dir).unwrap();
+  }
+}
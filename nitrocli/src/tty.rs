@@ -0,0 +1,266 @@
+// tty.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
+
+use crate::error::Error;
+use crate::secret::Secret;
+use crate::watchdog;
+
+/// A guard putting a terminal into raw mode for as long as it is alive.
+///
+/// In raw mode, input is neither echoed nor buffered until the end of
+/// the line, and control characters, e.g., `^C`, do not cause signals
+/// but are read like other characters.
+pub struct Raw {
+  fd: RawFd,
+  termios: libc::termios,
+}
+
+impl Raw {
+  /// Put the terminal with the given file descriptor into raw mode.
+  pub fn new(fd: RawFd) -> io::Result<Self> {
+    let mut termios = unsafe { mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+      return Err(io::Error::last_os_error());
+    }
+
+    let mut raw = termios;
+    raw.c_iflag &= !(libc::ICRNL | libc::IXON);
+    raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::IEXTEN | libc::ISIG);
+    raw.c_cc[libc::VMIN] = 1;
+    raw.c_cc[libc::VTIME] = 0;
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(Raw { fd, termios })
+  }
+}
+
+impl Drop for Raw {
+  fn drop(&mut self) {
+    let _ = unsafe { libc::tcsetattr(self.fd, libc::TCSANOW, &self.termios) };
+  }
+}
+
+/// How the input of a passphrase is echoed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Echo {
+  /// The input is not echoed at all.
+  None,
+  /// Every character entered is echoed as an asterisk.
+  Mask,
+}
+
+/// Create the error reporting that the user aborted the entry of a
+/// passphrase.
+fn aborted() -> Error {
+  Error::Error("Passphrase entry aborted".to_string())
+}
+
+/// Remove the last character of the given passphrase, returning whether
+/// there was one.
+fn pop_char(passphrase: &mut Secret) -> bool {
+  while let Some(byte) = passphrase.pop() {
+    // Continuation bytes of a UTF-8 sequence do not start a character.
+    if byte & 0xc0 != 0x80 {
+      return true;
+    }
+  }
+  false
+}
+
+/// Read a passphrase terminated by the end of the line from the given
+/// terminal in raw mode and append it to the given secret.
+///
+/// Backspace removes the last character, `^U` the whole input, and
+/// `^C` interrupts the program, as raw mode does not turn it into a
+/// signal.
+fn read_raw<T>(tty: &mut T, passphrase: &mut Secret, echo: Echo) -> Result<(), Error>
+where
+  T: Read + Write,
+{
+  // The input is read byte by byte, so that no buffer other than the
+  // secret ever holds the passphrase.
+  let mut byte = [0; 1];
+  loop {
+    match tty.read(&mut byte) {
+      Ok(0) => return Err(aborted()),
+      Ok(_) => (),
+      Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+      Err(err) => return Err(err.into()),
+    }
+
+    let mut erased = 0;
+    match byte[0] {
+      b'\r' | b'\n' => return Ok(()),
+      0x03 => return Err(Error::Interrupted(libc::SIGINT)),
+      0x04 if passphrase.as_bytes().is_empty() => return Err(aborted()),
+      0x08 | 0x7f => {
+        if pop_char(passphrase) {
+          erased += 1;
+        }
+      }
+      0x15 => {
+        while pop_char(passphrase) {
+          erased += 1;
+        }
+      }
+      byte if byte < 0x20 => (),
+      byte => {
+        passphrase.push(byte);
+        if echo == Echo::Mask && byte & 0xc0 != 0x80 {
+          tty.write_all(b"*")?;
+        }
+      }
+    }
+    if echo == Echo::Mask {
+      for _ in 0..erased {
+        tty.write_all(b"\x08 \x08")?;
+      }
+    }
+    tty.flush()?;
+  }
+}
+
+/// Inquire a passphrase from the controlling terminal of the process.
+///
+/// The given prompt is printed and the input is read in raw mode,
+/// either without being echoed or echoing an asterisk per character,
+/// as requested. The terminal is accessed through `/dev/tty` and not
+/// through the standard input and output streams, so that inquiry
+/// works even if those are redirected.
+pub fn read_passphrase(prompt: &str, echo: Echo) -> Result<Secret, Error> {
+  let _suspension = watchdog::suspend();
+  let mut tty = fs::OpenOptions::new()
+    .read(true)
+    .write(true)
+    .open("/dev/tty")?;
+  write!(tty, "{}", prompt)?;
+  tty.flush()?;
+
+  let mut passphrase = Secret::new();
+  let result = {
+    let _raw = Raw::new(tty.as_raw_fd())?;
+    read_raw(&mut tty, &mut passphrase, echo)
+  };
+  // Subsequent output starts on a line of its own.
+  writeln!(tty)?;
+  result.map(|_| passphrase)
+}
+
+/// Inquire a line of input from the controlling terminal of the
+/// process.
+///
+/// As for `read_passphrase`, the terminal is accessed through
+/// `/dev/tty`. The input is echoed and returned without the trailing
+/// newline.
+pub fn read_line(prompt: &str) -> Result<String, Error> {
+  let _suspension = watchdog::suspend();
+  let mut tty = fs::OpenOptions::new()
+    .read(true)
+    .write(true)
+    .open("/dev/tty")?;
+  write!(tty, "{}", prompt)?;
+  tty.flush()?;
+
+  let mut line = String::new();
+  if io::BufReader::new(&tty).read_line(&mut line)? == 0 {
+    return Err(Error::Error("Input aborted".to_string()));
+  }
+  Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+/// Check whether the standard input is connected to a terminal.
+pub fn is_stdin_tty() -> bool {
+  unsafe { libc::isatty(libc::STDIN_FILENO) == 1 }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A terminal reading the given input and recording the output.
+  struct Terminal {
+    input: io::Cursor<Vec<u8>>,
+    output: Vec<u8>,
+  }
+
+  impl Read for Terminal {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      self.input.read(buf)
+    }
+  }
+
+  impl Write for Terminal {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  fn read(input: &[u8], echo: Echo) -> (Result<String, Error>, String) {
+    let mut tty = Terminal {
+      input: io::Cursor::new(input.to_vec()),
+      output: Vec::new(),
+    };
+    let mut passphrase = Secret::new();
+    let result =
+      read_raw(&mut tty, &mut passphrase, echo).map(|_| passphrase.as_str().unwrap().to_string());
+    (result, String::from_utf8(tty.output).unwrap())
+  }
+
+  #[test]
+  fn read_masked() {
+    let (result, output) = read(b"ab\x7fc\xc3\xa4\r", Echo::Mask);
+    assert_eq!(result.unwrap(), "ac\u{e4}");
+    assert_eq!(output, "**\x08 \x08**");
+
+    let (result, output) = read("12\u{e4}\x15x\n".as_bytes(), Echo::Mask);
+    assert_eq!(result.unwrap(), "x");
+    assert_eq!(output, "***\x08 \x08\x08 \x08\x08 \x08*");
+  }
+
+  #[test]
+  fn read_without_echo() {
+    let (result, output) = read(b"123\x08456\r", Echo::None);
+    assert_eq!(result.unwrap(), "12456");
+    assert_eq!(output, "");
+  }
+
+  #[test]
+  fn read_aborted() {
+    match read(b"12\x03", Echo::None).0 {
+      Err(Error::Interrupted(signal)) => assert_eq!(signal, libc::SIGINT),
+      result => panic!("Unexpected result: {:?}", result),
+    }
+    assert!(read(b"\x04", Echo::None).0.is_err());
+    assert!(read(b"123", Echo::Mask).0.is_err());
+  }
+}
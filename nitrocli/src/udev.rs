@@ -0,0 +1,304 @@
+// udev.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::fs;
+use std::path;
+use std::process;
+use std::result;
+
+use crate::error::Error;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The USB vendor ID of Nitrokey devices.
+const VENDOR_ID: &str = "20a0";
+
+/// The names of the supported models along with their USB product IDs.
+const PRODUCTS: &[(&str, &str)] = &[("Nitrokey Pro", "4108"), ("Nitrokey Storage", "4109")];
+
+/// The default location of the udev rules.
+pub const RULES_PATH: &str = "/etc/udev/rules.d/41-nitrokey.rules";
+
+/// The mount point of sysfs.
+const SYSFS: &str = "/sys";
+
+/// The directory listing the USB devices in sysfs.
+const SYSFS_USB_DEVICES: &str = "/sys/bus/usb/devices";
+
+/// Retrieve udev rules granting the logged in user access to all
+/// supported models.
+///
+/// Access is granted to both the USB device, which is used by
+/// `nitrocli`, and the hidraw device, which is used by other tools.
+pub fn rules() -> String {
+  let mut rules = String::from("# Nitrokey udev rules, installed by nitrocli\n");
+  for &(name, product) in PRODUCTS {
+    rules += &format!("\n# {}\n", name);
+    for subsystem in &["usb", "hidraw"] {
+      rules += &format!(
+        "SUBSYSTEM==\"{}\", ATTRS{{idVendor}}==\"{}\", ATTRS{{idProduct}}==\"{}\", \
+         TAG+=\"uaccess\"\n",
+        subsystem, VENDOR_ID, product
+      );
+    }
+  }
+  rules
+}
+
+/// Install the udev rules at the given path and make udev apply them
+/// to connected devices.
+pub fn install(path: &path::Path) -> Result<()> {
+  fs::write(path, rules()).map_err(|err| {
+    let error = format!("Could not write {}: {}", path.display(), err);
+    Error::Error(error)
+  })?;
+
+  let vendor = format!("--attr-match=idVendor={}", VENDOR_ID);
+  udevadm(&["control", "--reload-rules"])?;
+  udevadm(&["trigger", "--subsystem-match=usb", &vendor])
+}
+
+/// Run `udevadm` with the given arguments.
+fn udevadm(args: &[&str]) -> Result<()> {
+  let status = process::Command::new("udevadm")
+    .args(args)
+    .status()
+    .map_err(|err| Error::Error(format!("Could not run udevadm: {}", err)))?;
+  if status.success() {
+    Ok(())
+  } else {
+    let error = format!("udevadm {} failed", args.join(" "));
+    Err(Error::Error(error))
+  }
+}
+
+/// Read the value of the given attribute of the sysfs device at the
+/// given path.
+fn attr(dir: &path::Path, name: &str) -> Option<String> {
+  fs::read_to_string(dir.join(name))
+    .ok()
+    .map(|value| value.trim().to_string())
+}
+
+/// Retrieve the name of the supported model of the sysfs device at the
+/// given path, if it is one.
+fn device_model(dir: &path::Path) -> Option<&'static str> {
+  if attr(dir, "idVendor")? != VENDOR_ID {
+    return None;
+  }
+  let product = attr(dir, "idProduct")?;
+  PRODUCTS
+    .iter()
+    .find(|&&(_, id)| id == product)
+    .map(|&(name, _)| name)
+}
+
+/// Find the supported devices listed in the given sysfs directory,
+/// along with the paths of their USB device nodes.
+fn find_devices_in(sysfs: &path::Path) -> Vec<(&'static str, path::PathBuf)> {
+  let entries = match fs::read_dir(sysfs) {
+    Ok(entries) => entries,
+    Err(_) => return Vec::new(),
+  };
+
+  let mut devices = entries
+    .filter_map(|entry| entry.ok())
+    .filter_map(|entry| {
+      let dir = entry.path();
+      let name = device_model(&dir)?;
+      let bus = attr(&dir, "busnum")?.parse::<u32>().ok()?;
+      let dev = attr(&dir, "devnum")?.parse::<u32>().ok()?;
+      let node = format!("/dev/bus/usb/{:03}/{:03}", bus, dev);
+      Some((name, path::PathBuf::from(node)))
+    })
+    .collect::<Vec<_>>();
+  devices.sort_by(|a, b| a.1.cmp(&b.1));
+  devices
+}
+
+/// Retrieve the name of the supported model with the given USB vendor
+/// and product IDs, given in hexadecimal.
+pub fn model_name(vendor: &str, product: &str) -> Option<&'static str> {
+  let parse = |id: &str| u16::from_str_radix(id, 16).ok();
+  if parse(vendor)? != parse(VENDOR_ID)? {
+    return None;
+  }
+  let product = parse(product)?;
+  PRODUCTS
+    .iter()
+    .find(|&&(_, id)| parse(id) == Some(product))
+    .map(|&(name, _)| name)
+}
+
+/// Find the connected devices of a supported model, along with the
+/// paths of their USB device nodes.
+pub fn find_devices() -> Vec<(&'static str, path::PathBuf)> {
+  find_devices_in(path::Path::new(SYSFS_USB_DEVICES))
+}
+
+/// Find the connected devices of a supported model, along with their
+/// paths in sysfs, relative to its mount point, as used by hotplug
+/// events.
+#[cfg(target_os = "linux")]
+pub fn find_devpaths() -> Vec<(&'static str, String)> {
+  find_devpaths_in(path::Path::new(SYSFS))
+}
+
+/// Find the supported USB devices in the sysfs mounted at the given
+/// path, along with their paths relative to it.
+#[cfg(target_os = "linux")]
+fn find_devpaths_in(sysfs: &path::Path) -> Vec<(&'static str, String)> {
+  let entries = match fs::read_dir(sysfs.join("bus/usb/devices")) {
+    Ok(entries) => entries,
+    Err(_) => return Vec::new(),
+  };
+
+  let mut devices = entries
+    .filter_map(|entry| entry.ok())
+    .filter_map(|entry| {
+      // The entries are links to the actual devices.
+      let dir = fs::canonicalize(entry.path()).ok()?;
+      let name = device_model(&dir)?;
+      let devpath = dir.strip_prefix(sysfs).ok()?;
+      Some((name, format!("/{}", devpath.display())))
+    })
+    .collect::<Vec<_>>();
+  devices.sort_by(|a, b| a.1.cmp(&b.1));
+  devices
+}
+
+/// Read the serial number the USB device with the given path in sysfs
+/// announces, formatted like the ones reported by the device itself.
+#[cfg(target_os = "linux")]
+pub fn serial_number(devpath: &str) -> Option<String> {
+  let dir = path::Path::new(SYSFS).join(devpath.trim_start_matches('/'));
+  attr(&dir, "serial").map(|serial| {
+    let serial = nitrocli_core::device::normalize_serial_number(&serial);
+    format!("{:0>8}", serial)
+  })
+}
+
+/// Check whether the current user can access all connected devices of
+/// a supported model, printing the result for each of them.
+pub fn check() -> Result<()> {
+  let devices = find_devices();
+  if devices.is_empty() {
+    return Err(Error::Error("No Nitrokey device found".to_string()));
+  }
+
+  let mut accessible = true;
+  for (name, node) in devices {
+    let result = fs::OpenOptions::new().read(true).write(true).open(&node);
+    match result {
+      Ok(_) => println!("{} at {}: accessible", name, node.display()),
+      Err(err) => {
+        accessible = false;
+        println!("{} at {}: {}", name, node.display(), err)
+      }
+    }
+  }
+
+  if accessible {
+    Ok(())
+  } else {
+    let error = "The current user cannot access all connected devices. \
+                 Install the udev rules using `nitrocli setup udev` as root \
+                 and reconnect the devices.";
+    Err(Error::Error(error.to_string()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::env;
+
+  #[test]
+  fn rules_cover_models() {
+    let rules = rules();
+    for &(_, product) in PRODUCTS {
+      assert!(rules.contains(&format!("ATTRS{{idProduct}}==\"{}\"", product)));
+    }
+  }
+
+  #[test]
+  fn find_devices() {
+    let dir = env::temp_dir().join(format!("nitrocli-udev-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+
+    let device = |name: &str, vendor: &str, product: &str, bus: &str, dev: &str| {
+      let device = dir.join(name);
+      fs::create_dir_all(&device).unwrap();
+      fs::write(device.join("idVendor"), format!("{}\n", vendor)).unwrap();
+      fs::write(device.join("idProduct"), format!("{}\n", product)).unwrap();
+      fs::write(device.join("busnum"), format!("{}\n", bus)).unwrap();
+      fs::write(device.join("devnum"), format!("{}\n", dev)).unwrap();
+    };
+    device("1-1", "20a0", "4109", "1", "12");
+    device("1-2", "20a0", "4287", "1", "13");
+    device("2-1", "1d6b", "4108", "2", "1");
+    device("3-4", "20a0", "4108", "3", "7");
+    fs::create_dir_all(dir.join("usb1")).unwrap();
+
+    let devices = find_devices_in(&dir);
+    let expected = vec![
+      (
+        "Nitrokey Storage",
+        path::PathBuf::from("/dev/bus/usb/001/012"),
+      ),
+      ("Nitrokey Pro", path::PathBuf::from("/dev/bus/usb/003/007")),
+    ];
+    assert_eq!(devices, expected);
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn find_devpaths() {
+    let dir = env::temp_dir().join(format!("nitrocli-sysfs-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let links = dir.join("bus/usb/devices");
+    fs::create_dir_all(&links).unwrap();
+    let dir = fs::canonicalize(&dir).unwrap();
+
+    let device = |name: &str, vendor: &str, product: &str| {
+      let device = dir.join("devices/pci0000:00/usb1").join(name);
+      fs::create_dir_all(&device).unwrap();
+      fs::write(device.join("idVendor"), format!("{}\n", vendor)).unwrap();
+      fs::write(device.join("idProduct"), format!("{}\n", product)).unwrap();
+      std::os::unix::fs::symlink(&device, links.join(name)).unwrap();
+    };
+    device("1-2", "20a0", "4108");
+    device("1-1", "20a0", "4109");
+    device("1-3", "1d6b", "4108");
+
+    let expected = vec![
+      (
+        "Nitrokey Storage",
+        "/devices/pci0000:00/usb1/1-1".to_string(),
+      ),
+      ("Nitrokey Pro", "/devices/pci0000:00/usb1/1-2".to_string()),
+    ];
+    assert_eq!(find_devpaths_in(&dir), expected);
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
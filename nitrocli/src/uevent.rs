@@ -0,0 +1,301 @@
+// uevent.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Hotplug events of Nitrokey devices, as announced by the kernel.
+//!
+//! The kernel broadcasts an event for every device that is added or
+//! removed on a netlink socket, which is the same source udev listens
+//! on. The events are received directly, so that neither udev nor
+//! libudev is required.
+
+use std::io;
+use std::mem;
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
+use std::result;
+use std::str;
+
+use crate::args;
+use crate::error::Error;
+use crate::udev;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The netlink protocol of kernel object events.
+const NETLINK_KOBJECT_UEVENT: libc::c_int = 15;
+/// The multicast group the kernel broadcasts its events to.
+const KERNEL_GROUP: u32 = 1;
+
+/// Whether a device was attached or detached.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+  Attached,
+  Detached,
+}
+
+/// A Nitrokey device being attached or detached.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+  pub action: Action,
+  pub model: args::DeviceModel,
+  /// The path of the device in sysfs, relative to `/sys`.
+  pub devpath: String,
+  /// The serial number of the device, if known.
+  pub serial_number: Option<String>,
+}
+
+/// Retrieve the model with the given name, as used by `udev`.
+fn model(name: &str) -> Option<args::DeviceModel> {
+  match name {
+    "Nitrokey Pro" => Some(args::DeviceModel::Pro),
+    "Nitrokey Storage" => Some(args::DeviceModel::Storage),
+    _ => None,
+  }
+}
+
+/// Retrieve the events of attaching the devices of a supported model
+/// that are connected already.
+pub fn connected() -> Vec<Event> {
+  udev::find_devpaths()
+    .into_iter()
+    .filter_map(|(name, devpath)| {
+      Some(Event {
+        action: Action::Attached,
+        model: model(name)?,
+        serial_number: udev::serial_number(&devpath),
+        devpath,
+      })
+    })
+    .collect()
+}
+
+/// Parse the given kernel event, returning it if it concerns a USB
+/// device of a supported model.
+///
+/// An event consists of a header, e.g., `add@/devices/...`, followed by
+/// `KEY=value` pairs, all terminated by a NUL byte.
+fn parse(data: &[u8]) -> Option<Event> {
+  let mut action = None;
+  let mut devpath = None;
+  let mut subsystem = None;
+  let mut devtype = None;
+  let mut product = None;
+  for field in data.split(|&b| b == 0).skip(1) {
+    let field = match str::from_utf8(field) {
+      Ok(field) => field,
+      Err(_) => continue,
+    };
+    let (key, value) = match field.find('=') {
+      Some(idx) => (&field[..idx], &field[idx + 1..]),
+      None => continue,
+    };
+    match key {
+      "ACTION" => action = Some(value),
+      "DEVPATH" => devpath = Some(value),
+      "SUBSYSTEM" => subsystem = Some(value),
+      "DEVTYPE" => devtype = Some(value),
+      "PRODUCT" => product = Some(value),
+      _ => (),
+    }
+  }
+  if subsystem != Some("usb") || devtype != Some("usb_device") {
+    return None;
+  }
+
+  let action = match action? {
+    "add" => Action::Attached,
+    "remove" => Action::Detached,
+    _ => return None,
+  };
+  // The product is given as vendor ID, product ID, and device release
+  // number, in hexadecimal without leading zeros.
+  let mut ids = product?.split('/');
+  let model = model(udev::model_name(ids.next()?, ids.next()?)?)?;
+  Some(Event {
+    action,
+    model,
+    devpath: devpath?.to_string(),
+    serial_number: None,
+  })
+}
+
+/// A socket receiving the hotplug events of the kernel.
+#[derive(Debug)]
+pub struct Socket {
+  fd: RawFd,
+}
+
+impl Socket {
+  /// Open a socket receiving the hotplug events of the kernel.
+  ///
+  /// Virtual devices are not announced by the kernel, so no socket is
+  /// opened for them.
+  pub fn open() -> Result<Self> {
+    if cfg!(feature = "virtual") {
+      let error = "Virtual devices have no hotplug events";
+      return Err(Error::Error(error.to_string()));
+    }
+    let fd = unsafe {
+      libc::socket(
+        libc::AF_NETLINK,
+        libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+        NETLINK_KOBJECT_UEVENT,
+      )
+    };
+    if fd < 0 {
+      return Err(io::Error::last_os_error().into());
+    }
+    let socket = Socket { fd };
+
+    let mut addr = unsafe { mem::zeroed::<libc::sockaddr_nl>() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    addr.nl_groups = KERNEL_GROUP;
+    let rc = unsafe {
+      libc::bind(
+        fd,
+        &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+        size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+      )
+    };
+    if rc < 0 {
+      return Err(io::Error::last_os_error().into());
+    }
+    Ok(socket)
+  }
+
+  /// Receive the pending events concerning devices of a supported
+  /// model.
+  ///
+  /// The serial numbers of attached devices are read from sysfs. A
+  /// detached device cannot be queried anymore, so its serial number
+  /// is left to the caller.
+  pub fn receive(&self) -> Vec<Event> {
+    let mut events = Vec::new();
+    let mut data = [0; 8192];
+    loop {
+      let count = unsafe {
+        libc::recv(
+          self.fd,
+          data.as_mut_ptr() as *mut libc::c_void,
+          data.len(),
+          0,
+        )
+      };
+      match count {
+        count if count > 0 => {
+          if let Some(mut event) = parse(&data[..count as usize]) {
+            if event.action == Action::Attached {
+              event.serial_number = udev::serial_number(&event.devpath);
+            }
+            events.push(event);
+          }
+        }
+        _ if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+        _ => break,
+      }
+    }
+    events
+  }
+}
+
+impl AsRawFd for Socket {
+  fn as_raw_fd(&self) -> RawFd {
+    self.fd
+  }
+}
+
+impl Drop for Socket {
+  fn drop(&mut self) {
+    let _ = unsafe { libc::close(self.fd) };
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn event(fields: &[&str]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for field in fields {
+      data.extend_from_slice(field.as_bytes());
+      data.push(0);
+    }
+    data
+  }
+
+  #[test]
+  fn parse_events() {
+    let devpath = "/devices/pci0000:00/0000:00:14.0/usb1/1-2";
+    let header = format!("add@{}", devpath);
+    let data = event(&[
+      &header,
+      "ACTION=add",
+      &format!("DEVPATH={}", devpath),
+      "SUBSYSTEM=usb",
+      "DEVTYPE=usb_device",
+      "PRODUCT=20a0/4109/101",
+    ]);
+    assert_eq!(
+      parse(&data),
+      Some(Event {
+        action: Action::Attached,
+        model: args::DeviceModel::Storage,
+        devpath: devpath.to_string(),
+        serial_number: None,
+      })
+    );
+
+    let data = event(&[
+      "remove@/devices/x",
+      "ACTION=remove",
+      "DEVPATH=/devices/x",
+      "SUBSYSTEM=usb",
+      "DEVTYPE=usb_device",
+      "PRODUCT=20a0/4108/100",
+    ]);
+    let event = parse(&data).unwrap();
+    assert_eq!(event.action, Action::Detached);
+    assert_eq!(event.model, args::DeviceModel::Pro);
+  }
+
+  #[test]
+  fn ignore_other_events() {
+    let fields = [
+      "add@/devices/x",
+      "ACTION=add",
+      "DEVPATH=/devices/x",
+      "SUBSYSTEM=usb",
+      "DEVTYPE=usb_interface",
+      "PRODUCT=20a0/4109/101",
+    ];
+    assert_eq!(parse(&event(&fields)), None);
+    let fields = [
+      "add@/devices/x",
+      "ACTION=add",
+      "DEVPATH=/devices/x",
+      "SUBSYSTEM=usb",
+      "DEVTYPE=usb_device",
+      "PRODUCT=46d/c52b/1211",
+    ];
+    assert_eq!(parse(&event(&fields)), None);
+    assert_eq!(parse(b"bind@/devices/x\0ACTION=bind\0"), None);
+    assert_eq!(parse(b""), None);
+  }
+}
@@ -0,0 +1,697 @@
+// update.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::fs;
+use std::path;
+use std::process;
+use std::result;
+use std::str;
+
+use crate::config;
+use crate::error::Context;
+use crate::error::Error;
+use crate::json;
+use crate::storage;
+use crate::tempfile;
+use crate::vault;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The configuration table of the firmware update.
+const SECTION: &str = "update";
+
+/// The configuration keys of the firmware update, along with the kinds
+/// of their values.
+pub const CONFIG_KEYS: &[(&str, &str, config::Kind)] =
+  &[(SECTION, "signing-keys", config::Kind::Strings)];
+
+/// Retrieve the fingerprints of the keys trusted to sign firmware
+/// images from the given configuration.
+pub fn signing_keys(config: &config::Config) -> Result<Vec<&str>> {
+  match config.get_strings(SECTION, "signing-keys")? {
+    Some(ref keys) if !keys.is_empty() => Ok(keys.clone()),
+    _ => Err(Error::Error(format!(
+      "No keys trusted to sign firmware images are configured in {}.signing-keys",
+      SECTION
+    ))),
+  }
+}
+
+/// Retrieve the GitHub repository the firmware of the given model is
+/// released in.
+fn repository(model: nitrocli_core::device::Model) -> &'static str {
+  match model {
+    nitrocli_core::device::Model::Pro => "Nitrokey/nitrokey-pro-firmware",
+    nitrocli_core::device::Model::Storage => "Nitrokey/nitrokey-storage-firmware",
+  }
+}
+
+/// A file attached to a firmware release.
+#[derive(Clone, Debug, PartialEq)]
+struct Asset {
+  name: String,
+  url: String,
+}
+
+/// A firmware release.
+#[derive(Clone, Debug, PartialEq)]
+struct Release {
+  /// The version, without the `v` prefix of the tag.
+  version: String,
+  prerelease: bool,
+  assets: Vec<Asset>,
+}
+
+impl Release {
+  /// Find the asset with the given name.
+  fn asset(&self, name: &str) -> Option<&Asset> {
+    self.assets.iter().find(|asset| asset.name == name)
+  }
+}
+
+/// The name of the microcontroller of the Nitrokey Storage as known to
+/// dfu-programmer.
+const STORAGE_TARGET: &str = "at32uc3a3256s";
+
+/// Run dfu-programmer with the given arguments for the microcontroller
+/// of the Nitrokey Storage.
+fn dfu_programmer(args: &[&str]) -> Result<()> {
+  trace!(
+    "Running dfu-programmer {} {}",
+    STORAGE_TARGET,
+    args.join(" ")
+  );
+  let status = process::Command::new("dfu-programmer")
+    .arg(STORAGE_TARGET)
+    .args(args)
+    .status()
+    .map_err(|err| Error::Error(format!("Could not run dfu-programmer: {}", err)))?;
+  if status.success() {
+    Ok(())
+  } else {
+    let error = format!("dfu-programmer {} failed ({})", args.join(" "), status);
+    Err(Error::Error(error))
+  }
+}
+
+/// Launch the firmware of a Nitrokey Storage in update mode, which
+/// makes it leave update mode.
+pub fn launch() -> Result<()> {
+  dfu_programmer(&["launch"])
+}
+
+/// Erase the firmware of a Nitrokey Storage in update mode.
+pub fn erase() -> Result<()> {
+  dfu_programmer(&["erase"])
+}
+
+/// Write the given image to a Nitrokey Storage in update mode, leaving
+/// the memory of the bootloader untouched.
+pub fn write(image: &path::Path) -> Result<()> {
+  let path = image
+    .to_str()
+    .ok_or_else(|| Error::Error(format!("Invalid image path: {}", image.display())))?;
+  dfu_programmer(&["flash", "--suppress-bootloader-mem", path])
+}
+
+/// Run curl for retrieving the given URL, failing on HTTP errors.
+fn curl(url: &str) -> process::Command {
+  trace!("Running curl {}", url);
+  let mut command = process::Command::new("curl");
+  let _ = command
+    .arg("--fail")
+    .arg("--silent")
+    .arg("--show-error")
+    .arg("--location")
+    .arg(url);
+  command
+}
+
+/// Check the status of a curl process that retrieved the given URL.
+fn check_curl(url: &str, status: process::ExitStatus) -> Result<()> {
+  if status.success() {
+    Ok(())
+  } else {
+    Err(Error::Error(format!(
+      "Could not download {} ({})",
+      url, status
+    )))
+  }
+}
+
+/// Create the error reporting that curl could not be run.
+fn curl_error(err: std::io::Error) -> Error {
+  Error::Error(format!("Could not run curl: {}", err))
+}
+
+/// Retrieve the given URL as a string.
+fn fetch(url: &str) -> Result<String> {
+  let output = curl(url)
+    .stdin(process::Stdio::null())
+    .stderr(process::Stdio::inherit())
+    .output()
+    .map_err(curl_error)?;
+  check_curl(url, output.status)?;
+  String::from_utf8(output.stdout)
+    .map_err(|_| Error::Error(format!("The data at {} is not valid UTF-8", url)))
+}
+
+/// Download the given URL to the file with the given path.
+fn download_to(url: &str, path: &path::Path) -> Result<()> {
+  let status = curl(url)
+    .arg("--output")
+    .arg(path)
+    .stdin(process::Stdio::null())
+    .status()
+    .map_err(curl_error)?;
+  check_curl(url, status)
+}
+
+/// Parse the releases as listed by the GitHub API.
+///
+/// Drafts are skipped, as they have no published assets.
+fn parse_releases(value: &json::Value) -> Option<Vec<Release>> {
+  let mut releases = Vec::new();
+  for release in value.as_array()? {
+    if release.get("draft").and_then(json::Value::as_bool) == Some(true) {
+      continue;
+    }
+    let tag = release.get("tag_name")?.as_str()?;
+    let mut assets = Vec::new();
+    for asset in release.get("assets")?.as_array()? {
+      assets.push(Asset {
+        name: asset.get("name")?.as_str()?.to_string(),
+        url: asset.get("browser_download_url")?.as_str()?.to_string(),
+      });
+    }
+    releases.push(Release {
+      version: tag.trim_start_matches(&['v', 'V'][..]).to_string(),
+      prerelease: release
+        .get("prerelease")
+        .and_then(json::Value::as_bool)
+        .unwrap_or(false),
+      assets,
+    });
+  }
+  Some(releases)
+}
+
+/// Find the release with the given version or, if none is given, the
+/// latest one that is not a pre-release.
+///
+/// The GitHub API lists releases starting with the latest.
+fn find_release<'r>(releases: &'r [Release], version: Option<&str>) -> Result<&'r Release> {
+  match version {
+    Some(version) => {
+      let version = version.trim_start_matches(&['v', 'V'][..]);
+      releases
+        .iter()
+        .find(|release| release.version == version)
+        .ok_or_else(|| Error::Error(format!("Firmware version {} not found", version)))
+    }
+    None => releases
+      .iter()
+      .find(|release| !release.prerelease)
+      .ok_or_else(|| Error::Error("No firmware release found".to_string())),
+  }
+}
+
+/// Find the firmware image with the given extension, e.g., `hex`,
+/// among the assets of the given release.
+fn find_image<'r>(release: &'r Release, extension: &str) -> Result<&'r Asset> {
+  let suffix = format!(".{}", extension);
+  let images = release
+    .assets
+    .iter()
+    .filter(|asset| asset.name.ends_with(&suffix))
+    .collect::<Vec<_>>();
+  match images.as_slice() {
+    [image] => Ok(image),
+    [] => Err(Error::Error(format!(
+      "Firmware version {} provides no {} image",
+      release.version, extension
+    ))),
+    _ => {
+      let names = images
+        .iter()
+        .map(|image| image.name.as_str())
+        .collect::<Vec<_>>();
+      Err(Error::Error(format!(
+        "Firmware version {} provides multiple images: {}",
+        release.version,
+        names.join(", ")
+      )))
+    }
+  }
+}
+
+/// Find the SHA-256 checksum of the file with the given name in the
+/// given checksum file in the format of `sha256sum`.
+///
+/// A line without a file name applies to any file, as used for
+/// checksum files accompanying a single file.
+fn find_checksum(checksums: &str, name: &str) -> Option<String> {
+  checksums.lines().find_map(|line| {
+    let mut words = line.split_whitespace();
+    let checksum = words.next()?;
+    match words.next() {
+      Some(file) if file.trim_start_matches('*') != name => None,
+      _ if checksum.len() == 64 => Some(checksum.to_lowercase()),
+      _ => None,
+    }
+  })
+}
+
+/// Find the checksum file accompanying the given image among the assets
+/// of the given release.
+fn find_checksum_asset<'r>(release: &'r Release, image: &Asset) -> Option<&'r Asset> {
+  release
+    .asset(&format!("{}.sha256", image.name))
+    .or_else(|| {
+      release
+        .assets
+        .iter()
+        .find(|asset| asset.name.to_lowercase().starts_with("sha256sums"))
+    })
+}
+
+/// Compute the SHA-256 digest of the given data.
+fn sha256(data: &[u8]) -> [u8; 32] {
+  const K: [u32; 64] = [
+    0x428a_2f98,
+    0x7137_4491,
+    0xb5c0_fbcf,
+    0xe9b5_dba5,
+    0x3956_c25b,
+    0x59f1_11f1,
+    0x923f_82a4,
+    0xab1c_5ed5,
+    0xd807_aa98,
+    0x1283_5b01,
+    0x2431_85be,
+    0x550c_7dc3,
+    0x72be_5d74,
+    0x80de_b1fe,
+    0x9bdc_06a7,
+    0xc19b_f174,
+    0xe49b_69c1,
+    0xefbe_4786,
+    0x0fc1_9dc6,
+    0x240c_a1cc,
+    0x2de9_2c6f,
+    0x4a74_84aa,
+    0x5cb0_a9dc,
+    0x76f9_88da,
+    0x983e_5152,
+    0xa831_c66d,
+    0xb003_27c8,
+    0xbf59_7fc7,
+    0xc6e0_0bf3,
+    0xd5a7_9147,
+    0x06ca_6351,
+    0x1429_2967,
+    0x27b7_0a85,
+    0x2e1b_2138,
+    0x4d2c_6dfc,
+    0x5338_0d13,
+    0x650a_7354,
+    0x766a_0abb,
+    0x81c2_c92e,
+    0x9272_2c85,
+    0xa2bf_e8a1,
+    0xa81a_664b,
+    0xc24b_8b70,
+    0xc76c_51a3,
+    0xd192_e819,
+    0xd699_0624,
+    0xf40e_3585,
+    0x106a_a070,
+    0x19a4_c116,
+    0x1e37_6c08,
+    0x2748_774c,
+    0x34b0_bcb5,
+    0x391c_0cb3,
+    0x4ed8_aa4a,
+    0x5b9c_ca4f,
+    0x682e_6ff3,
+    0x748f_82ee,
+    0x78a5_636f,
+    0x84c8_7814,
+    0x8cc7_0208,
+    0x90be_fffa,
+    0xa450_6ceb,
+    0xbef9_a3f7,
+    0xc671_78f2,
+  ];
+  let mut h: [u32; 8] = [
+    0x6a09_e667,
+    0xbb67_ae85,
+    0x3c6e_f372,
+    0xa54f_f53a,
+    0x510e_527f,
+    0x9b05_688c,
+    0x1f83_d9ab,
+    0x5be0_cd19,
+  ];
+  let mut message = data.to_vec();
+  message.push(0x80);
+  while message.len() % 64 != 56 {
+    message.push(0);
+  }
+  let bits = (data.len() as u64).wrapping_mul(8);
+  message.extend((0..8).rev().map(|i| (bits >> (i * 8)) as u8));
+
+  for chunk in message.chunks(64) {
+    let mut w = [0u32; 64];
+    for i in 0..16 {
+      w[i] = chunk[i * 4..i * 4 + 4]
+        .iter()
+        .fold(0, |w, &b| (w << 8) | u32::from(b));
+    }
+    for i in 16..64 {
+      let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+      let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+      w[i] = w[i - 16]
+        .wrapping_add(s0)
+        .wrapping_add(w[i - 7])
+        .wrapping_add(s1);
+    }
+
+    let mut v = h;
+    for (k, w) in K.iter().zip(w.iter()) {
+      let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+      let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+      let temp1 = v[7]
+        .wrapping_add(s1)
+        .wrapping_add(ch)
+        .wrapping_add(*k)
+        .wrapping_add(*w);
+      let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+      let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+      let temp2 = s0.wrapping_add(maj);
+      v = [
+        temp1.wrapping_add(temp2),
+        v[0],
+        v[1],
+        v[2],
+        v[3].wrapping_add(temp1),
+        v[4],
+        v[5],
+        v[6],
+      ];
+    }
+    for (h, x) in h.iter_mut().zip(&v) {
+      *h = h.wrapping_add(*x);
+    }
+  }
+
+  let mut digest = [0; 32];
+  for (i, h) in h.iter().enumerate() {
+    for j in 0..4 {
+      digest[i * 4 + j] = (h >> (24 - j * 8)) as u8;
+    }
+  }
+  digest
+}
+
+/// Format the given bytes as a lower case hex string.
+fn to_hex(data: &[u8]) -> String {
+  data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Verify the downloaded image of the given release, stored at the
+/// given path, using the signature published along with it.
+///
+/// The signature has to be made by one of the keys with the given
+/// fingerprints. A checksum published along with the image has to match
+/// as well, but is not sufficient on its own, as it could have been
+/// replaced along with the image.
+fn verify(
+  release: &Release,
+  image: &Asset,
+  path: &path::Path,
+  dir: &path::Path,
+  keys: &[&str],
+) -> Result<()> {
+  let mut verified = false;
+  for extension in &["asc", "sig"] {
+    if let Some(signature) = release.asset(&format!("{}.{}", image.name, extension)) {
+      let temp = tempfile::TempFile::new_in(dir, "signature")?;
+      download_to(&signature.url, temp.path())?;
+      vault::verify(temp.path(), path, keys)?;
+      debug!("Verified the signature {}", signature.name);
+      verified = true;
+    }
+  }
+  if !verified {
+    let error = format!(
+      "Firmware version {} provides no signature of {}",
+      release.version, image.name
+    );
+    return Err(Error::Error(error));
+  }
+
+  if let Some(checksums) = find_checksum_asset(release, image) {
+    let checksum = find_checksum(&fetch(&checksums.url)?, &image.name).ok_or_else(|| {
+      let error = format!("{} lists no checksum of {}", checksums.name, image.name);
+      Error::Error(error)
+    })?;
+    let data = fs::read(path)?;
+    if to_hex(&sha256(&data)) != checksum {
+      let error = format!(
+        "The checksum of {} does not match {}",
+        image.name, checksums.name
+      );
+      return Err(Error::Error(error));
+    }
+    debug!("Verified the checksum in {}", checksums.name);
+  }
+  Ok(())
+}
+
+/// Retrieve the firmware releases for the given model.
+fn fetch_releases(model: nitrocli_core::device::Model) -> Result<Vec<Release>> {
+  let url = format!(
+    "https://api.github.com/repos/{}/releases",
+    repository(model)
+  );
+  fetch(&url)?
+    .parse::<json::Value>()
+    .ok()
+    .and_then(|value| parse_releases(&value))
+    .ok_or_else(|| Error::Error(format!("Could not parse the releases listed at {}", url)))
+}
+
+/// Download the image of the firmware with the given version or, if
+/// none is given, of the latest firmware for the given model.
+///
+/// The image is only stored in the cache directory once it was
+/// verified to be signed by one of the keys with the given
+/// fingerprints. The version and the path of the image are returned.
+pub fn download(
+  model: nitrocli_core::device::Model,
+  version: Option<&str>,
+  keys: &[&str],
+) -> Result<(String, path::PathBuf)> {
+  let releases = fetch_releases(model)?;
+  let release = find_release(&releases, version)?;
+  let image = find_image(release, "hex")?;
+
+  let dir = storage::Dir::Cache.path()?.join("firmware");
+  let temp = tempfile::TempFile::new_in(&dir, "firmware")?;
+  debug!("Downloading {}", image.url);
+  download_to(&image.url, temp.path())?;
+  verify(release, image, temp.path(), &dir, keys)
+    .context(|| format!("Could not verify {}", image.name))?;
+
+  let path = dir.join(&image.name);
+  temp.persist(&path)?;
+  Ok((release.version.clone(), path))
+}
+
+/// Compare the SHA-256 checksum of the image at the given path with
+/// the one published for the firmware of the given model and version.
+///
+/// The image is compared with the image of the release that has the
+/// same extension, e.g., `bin` for an image exported by the device, and
+/// whose checksum is published along with it. The names of the image
+/// of the release and of the file listing its checksum are returned.
+pub fn check_image(
+  model: nitrocli_core::device::Model,
+  version: &str,
+  path: &path::Path,
+) -> Result<(String, String)> {
+  let extension = path
+    .extension()
+    .and_then(|extension| extension.to_str())
+    .ok_or_else(|| {
+      let error = format!(
+        "Cannot tell the kind of the image {} without an extension",
+        path.display()
+      );
+      Error::Error(error)
+    })?;
+  let data = fs::read(path).context(|| format!("Could not read {}", path.display()))?;
+
+  let releases = fetch_releases(model)?;
+  let release = find_release(&releases, Some(version))?;
+  let image = find_image(release, extension)?;
+  let checksums = find_checksum_asset(release, image).ok_or_else(|| {
+    let error = format!(
+      "Firmware version {} publishes no checksum of {}",
+      release.version, image.name
+    );
+    Error::Error(error)
+  })?;
+  let checksum = find_checksum(&fetch(&checksums.url)?, &image.name).ok_or_else(|| {
+    let error = format!("{} lists no checksum of {}", checksums.name, image.name);
+    Error::Error(error)
+  })?;
+  let actual = to_hex(&sha256(&data));
+  if actual != checksum {
+    let error = format!(
+      "The SHA-256 checksum of {} is {}, but {} lists {} for {} of firmware version {}",
+      path.display(),
+      actual,
+      checksums.name,
+      checksum,
+      image.name,
+      release.version
+    );
+    return Err(Error::Error(error));
+  }
+  Ok((image.name.clone(), checksums.name.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn release(version: &str, prerelease: bool, assets: &[&str]) -> Release {
+    Release {
+      version: version.to_string(),
+      prerelease,
+      assets: assets
+        .iter()
+        .map(|name| Asset {
+          name: name.to_string(),
+          url: format!("https://example.com/{}", name),
+        })
+        .collect(),
+    }
+  }
+
+  #[test]
+  fn parse_github_releases() {
+    let json = r#"[
+      {"tag_name": "V0.58", "draft": true, "assets": []},
+      {"tag_name": "V0.57-RC.1", "prerelease": true, "assets": []},
+      {"tag_name": "V0.56", "prerelease": false, "assets": [
+        {"name": "storage.hex", "browser_download_url": "https://example.com/storage.hex"}
+      ]}
+    ]"#;
+    let releases = parse_releases(&json.parse().unwrap()).unwrap();
+    assert_eq!(
+      releases,
+      vec![
+        release("0.57-RC.1", true, &[]),
+        release("0.56", false, &["storage.hex"]),
+      ]
+    );
+    assert_eq!(find_release(&releases, None).unwrap().version, "0.56");
+    let found = find_release(&releases, Some("v0.57-RC.1")).unwrap();
+    assert_eq!(found.version, "0.57-RC.1");
+    assert_eq!(
+      find_release(&releases, Some("0.55"))
+        .unwrap_err()
+        .to_string(),
+      "Firmware version 0.55 not found"
+    );
+    assert!(parse_releases(&r#"[{"assets": []}]"#.parse().unwrap()).is_none());
+  }
+
+  #[test]
+  fn find_images() {
+    let found = release(
+      "0.56",
+      false,
+      &[
+        "storage.hex",
+        "storage.hex.sha256",
+        "storage.bin",
+        "notes.txt",
+      ],
+    );
+    assert_eq!(find_image(&found, "hex").unwrap().name, "storage.hex");
+    assert_eq!(find_image(&found, "bin").unwrap().name, "storage.bin");
+    let checksums = find_checksum_asset(&found, find_image(&found, "hex").unwrap());
+    assert_eq!(checksums.unwrap().name, "storage.hex.sha256");
+
+    let found = release("0.56", false, &["a.hex", "b.hex", "SHA256SUMS"]);
+    assert_eq!(
+      find_image(&found, "hex").unwrap_err().to_string(),
+      "Firmware version 0.56 provides multiple images: a.hex, b.hex"
+    );
+    let image = &found.assets[0];
+    assert_eq!(
+      find_checksum_asset(&found, image).unwrap().name,
+      "SHA256SUMS"
+    );
+    let found = release("0.56", false, &["notes.txt"]);
+    assert_eq!(
+      find_image(&found, "hex").unwrap_err().to_string(),
+      "Firmware version 0.56 provides no hex image"
+    );
+  }
+
+  #[test]
+  fn checksums() {
+    let sum = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    let list = format!(
+      "{}  other.hex\n{}  *storage.hex\n",
+      "0".repeat(64),
+      sum.to_uppercase()
+    );
+    assert_eq!(find_checksum(&list, "storage.hex"), Some(sum.to_string()));
+    assert_eq!(find_checksum(&list, "missing.hex"), None);
+    assert_eq!(
+      find_checksum(&format!("{}\n", sum), "storage.hex"),
+      Some(sum.to_string())
+    );
+    assert_eq!(find_checksum("abc  storage.hex\n", "storage.hex"), None);
+  }
+
+  #[test]
+  fn sha256_digests() {
+    assert_eq!(
+      to_hex(&sha256(b"")),
+      "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    assert_eq!(
+      to_hex(&sha256(b"abc")),
+      "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert_eq!(
+      to_hex(&sha256(
+        b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+      )),
+      "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+    );
+  }
+}
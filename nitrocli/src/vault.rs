@@ -0,0 +1,326 @@
+// vault.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path;
+use std::process;
+use std::result;
+use std::str;
+use std::thread;
+
+use crate::error::Context;
+use crate::error::Error;
+use crate::secret::Secret;
+use crate::signal;
+#[cfg(feature = "storage")]
+use crate::storage;
+use crate::tempfile;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The first line of an ASCII-armored OpenPGP message.
+const ARMOR_HEADER: &[u8] = b"-----BEGIN PGP MESSAGE-----";
+
+/// The extension of the files created by `encrypt`.
+const EXTENSION: &str = "gpg";
+
+/// Check whether the given data is an OpenPGP message.
+///
+/// Binary messages start with a packet header, which always has the
+/// most significant bit set. Text, such as a JSON document, is never
+/// mistaken for one as long as it is valid UTF-8.
+pub fn is_encrypted(data: &[u8]) -> bool {
+  if data.starts_with(ARMOR_HEADER) {
+    return true;
+  }
+  match data.first() {
+    Some(byte) => byte & 0x80 != 0 && str::from_utf8(data).is_err(),
+    None => false,
+  }
+}
+
+/// Run gpg with the given arguments.
+fn gpg(args: &[&str]) -> process::Command {
+  trace!("Running gpg {}", args.join(" "));
+  let mut command = process::Command::new("gpg");
+  let _ = command.arg("--quiet").args(args);
+  command
+}
+
+/// Check that gpg exited with the given successful status.
+fn check(status: process::ExitStatus) -> Result<()> {
+  if status.success() {
+    Ok(())
+  } else {
+    Err(Error::Error(format!("gpg failed ({})", status)))
+  }
+}
+
+/// Create the error reporting that gpg could not be run.
+fn spawn_error(err: io::Error) -> Error {
+  Error::Error(format!("Could not run gpg: {}", err))
+}
+
+/// Decrypt the given OpenPGP message using gpg.
+///
+/// gpg asks for the passphrase or the PIN of the key, if required,
+/// using its pinentry and reports its errors on the standard error.
+pub fn decrypt(data: &[u8]) -> Result<Secret> {
+  let mut child = gpg(&["--decrypt"])
+    .stdin(process::Stdio::piped())
+    .stdout(process::Stdio::piped())
+    .spawn()
+    .map_err(spawn_error)?;
+
+  // gpg may block writing the plaintext before it has read all of the
+  // message, so the message is written by a separate thread.
+  let mut stdin = child.stdin.take().unwrap();
+  let data = data.to_vec();
+  let writer = thread::spawn(move || stdin.write_all(&data));
+
+  let mut plaintext = Secret::new();
+  let mut stdout = child.stdout.take().unwrap();
+  let mut buffer = [0; 4096];
+  loop {
+    match stdout.read(&mut buffer) {
+      Ok(0) => break,
+      Ok(count) => plaintext.extend_from_slice(&buffer[..count]),
+      Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+      Err(err) => return Err(err.into()),
+    }
+  }
+  for byte in buffer.iter_mut() {
+    *byte = 0;
+  }
+
+  // A failure to write the message is reported by gpg.
+  let _ = writer.join();
+  check(child.wait()?)?;
+  Ok(plaintext)
+}
+
+/// Read the file with the given path, decrypting it if it is encrypted.
+pub fn read(path: &path::Path) -> Result<Secret> {
+  let data = fs::read(path).context(|| format!("Could not read {}", path.display()))?;
+  if is_encrypted(&data) {
+    debug!("Decrypting {}", path.display());
+    decrypt(&data).context(|| format!("Could not decrypt {}", path.display()))
+  } else {
+    Ok(Secret::from(data))
+  }
+}
+
+/// Encrypt the given input, a path or `-` for the given data, to the
+/// given output path using gpg.
+///
+/// The input is encrypted for the given recipients or, if there are
+/// none, with a passphrase. The output is only created once gpg
+/// succeeded, so that an aborted encryption does not leave a partial
+/// file behind.
+fn encrypt_to(
+  input: &str,
+  data: Option<&[u8]>,
+  output: &path::Path,
+  recipients: &[String],
+) -> Result<()> {
+  let dir = match output.parent() {
+    Some(dir) if !dir.as_os_str().is_empty() => dir,
+    _ => path::Path::new("."),
+  };
+  let temp = tempfile::TempFile::new_in(dir, "vault")?;
+  let mut args = vec!["--yes", "--output"];
+  let temp_str = temp.path().to_string_lossy();
+  args.push(&temp_str);
+  if recipients.is_empty() {
+    args.push("--symmetric");
+  } else {
+    args.push("--encrypt");
+    for recipient in recipients {
+      args.push("--recipient");
+      args.push(recipient);
+    }
+  }
+  args.push("--");
+  args.push(input);
+
+  let status = {
+    // An interrupted gpg fails, and the temporary file is removed.
+    let _guard = signal::defer();
+    let stdin = if data.is_some() {
+      process::Stdio::piped()
+    } else {
+      process::Stdio::null()
+    };
+    let mut child = gpg(&args).stdin(stdin).spawn().map_err(spawn_error)?;
+    if let Some(data) = data {
+      // gpg writes to a file, so it cannot block us while we write the
+      // data. A failure to read it is reported by gpg.
+      let mut stdin = child.stdin.take().unwrap();
+      let _ = stdin.write_all(data);
+    }
+    child.wait()?
+  };
+  check(status)?;
+  signal::check()?;
+  temp.persist(output)
+}
+
+/// Encrypt the file with the given path using gpg and return the path
+/// of the encrypted file.
+///
+/// The file is encrypted like by `encrypt_to`. The encrypted file is
+/// the given file with the `.gpg` extension appended. If `remove` is
+/// set, the given file is overwritten and removed once it was
+/// encrypted.
+pub fn encrypt(path: &path::Path, recipients: &[String], remove: bool) -> Result<path::PathBuf> {
+  let mut output = path.as_os_str().to_os_string();
+  output.push(".");
+  output.push(EXTENSION);
+  let output = path::PathBuf::from(output);
+
+  encrypt_to(&path.to_string_lossy(), None, &output, recipients)
+    .context(|| format!("Could not encrypt {}", path.display()))?;
+
+  if remove {
+    tempfile::shred(path).context(|| format!("Could not remove {}", path.display()))?;
+  }
+  Ok(output)
+}
+
+/// Encrypt the given data using gpg, as described for `encrypt_to`,
+/// and store it at the given path.
+///
+/// The data is passed to gpg through a pipe, so that its plaintext is
+/// never written to disk.
+#[cfg(feature = "storage")]
+pub fn encrypt_data(data: &[u8], output: &path::Path, recipients: &[String]) -> Result<()> {
+  storage::create_parent(output)?;
+  encrypt_to("-", Some(data), output, recipients)
+    .context(|| format!("Could not encrypt {}", output.display()))
+}
+
+/// Normalize the given key fingerprint for comparing it with the ones
+/// reported by gpg.
+#[cfg(feature = "storage")]
+fn normalize_fingerprint(fingerprint: &str) -> String {
+  let fingerprint = fingerprint.trim();
+  let fingerprint = if fingerprint.starts_with("0x") || fingerprint.starts_with("0X") {
+    &fingerprint[2..]
+  } else {
+    fingerprint
+  };
+  fingerprint
+    .chars()
+    .filter(|c| !c.is_whitespace())
+    .collect::<String>()
+    .to_uppercase()
+}
+
+/// Extract the fingerprints of the keys that made a valid signature
+/// from the given status output of gpg.
+///
+/// The `VALIDSIG` line contains the fingerprint of the signing key and,
+/// as its last field, the one of its primary key.
+#[cfg(feature = "storage")]
+fn valid_signers(status: &str) -> Vec<String> {
+  status
+    .lines()
+    .filter_map(|line| {
+      let mut words = line.split_whitespace();
+      match (words.next(), words.next(), words.next()) {
+        (Some("[GNUPG:]"), Some("VALIDSIG"), Some(fingerprint)) => {
+          let mut fingerprints = vec![fingerprint.to_string()];
+          if let Some(primary) = words.nth(8) {
+            fingerprints.push(primary.to_string());
+          }
+          Some(fingerprints)
+        }
+        _ => None,
+      }
+    })
+    .flatten()
+    .collect()
+}
+
+/// Verify the given detached signature of the file with the given path
+/// using gpg, requiring it to be made by one of the keys with the given
+/// fingerprints.
+///
+/// The signing key has to be in the keyring of the user, and gpg
+/// reports the result of the verification on the standard error. A
+/// valid signature by any other key of the keyring is rejected.
+#[cfg(feature = "storage")]
+pub fn verify(signature: &path::Path, path: &path::Path, fingerprints: &[&str]) -> Result<()> {
+  let signature = signature.to_string_lossy();
+  let path_str = path.to_string_lossy();
+  let output = gpg(&["--status-fd", "1", "--verify", "--", &signature, &path_str])
+    .stdin(process::Stdio::null())
+    .stderr(process::Stdio::inherit())
+    .output()
+    .map_err(spawn_error)?;
+  check(output.status)
+    .context(|| format!("Could not verify the signature of {}", path.display()))?;
+
+  let fingerprints = fingerprints
+    .iter()
+    .map(|fingerprint| normalize_fingerprint(fingerprint))
+    .collect::<Vec<_>>();
+  let signers = valid_signers(&String::from_utf8_lossy(&output.stdout));
+  if signers.iter().any(|signer| fingerprints.contains(signer)) {
+    Ok(())
+  } else {
+    let error = format!(
+      "The signature of {} was not made by a trusted key",
+      path.display()
+    );
+    Err(Error::Error(error))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn detect_messages() {
+    assert!(is_encrypted(b"-----BEGIN PGP MESSAGE-----\n\nhQEMA..."));
+    assert!(is_encrypted(&[0x85, 0x01, 0x0c, 0x03, 0xff]));
+    assert!(is_encrypted(&[0xc3, 0x0d, 0x04, 0x09, 0x03, 0x08]));
+    assert!(!is_encrypted(b"{\"user_pin\": \"123456\"}"));
+    assert!(!is_encrypted("\u{feff}{\"slot\": 1}".as_bytes()));
+    assert!(!is_encrypted(b""));
+  }
+
+  #[test]
+  #[cfg(feature = "storage")]
+  fn parse_valid_signers() {
+    let status = "[GNUPG:] NEWSIG\n\
+      [GNUPG:] GOODSIG 1234567890ABCDEF Nitrokey\n\
+      [GNUPG:] VALIDSIG AAAA1111 2019-01-01 1546300800 0 4 0 1 10 00 BBBB2222\n";
+    assert_eq!(valid_signers(status), vec!["AAAA1111", "BBBB2222"]);
+    assert_eq!(
+      valid_signers("[GNUPG:] BADSIG 1234 Nitrokey\n"),
+      Vec::<String>::new()
+    );
+    assert_eq!(normalize_fingerprint("0xaaaa 1111"), "AAAA1111");
+  }
+}
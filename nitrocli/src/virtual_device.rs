@@ -0,0 +1,1498 @@
+// virtual_device.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+// A virtual Nitrokey device for testing without hardware.
+//
+// The device is emulated at the level of the C API of libnitrokey: the
+// `NK_*` functions used by the `nitrokey` crate are defined here, so
+// that the linker does not pull in the actual library. Everything
+// above, from the `nitrokey` crate to our commands, works unchanged.
+// The state of the device is kept in the file named by the
+// `NITROCLI_VIRTUAL_DEVICE` environment variable, so that it persists
+// across invocations. If the variable is not set, no device is
+// connected. It may name several files, separated like the entries of
+// `PATH`, to emulate multiple connected devices, the first of which is
+// connected to by default.
+
+#![allow(non_snake_case)]
+
+use std::cell;
+use std::env;
+use std::ffi;
+use std::fs;
+use std::os::raw::c_char;
+use std::os::raw::c_int;
+use std::path;
+use std::ptr;
+use std::slice;
+
+use crate::json;
+use crate::oath;
+
+/// The environment variable holding the paths of the state files.
+const STATE_VAR: &str = "NITROCLI_VIRTUAL_DEVICE";
+
+/// The factory default admin PIN.
+const DEFAULT_ADMIN_PIN: &str = "12345678";
+/// The factory default user PIN.
+const DEFAULT_USER_PIN: &str = "123456";
+/// The factory default firmware password of the Nitrokey Storage.
+const DEFAULT_UPDATE_PASSWORD: &str = "12345678";
+/// The number of attempts for entering a PIN.
+const PIN_RETRY_COUNT: u8 = 3;
+/// The number of HOTP slots.
+const HOTP_SLOT_COUNT: usize = 3;
+/// The number of TOTP slots.
+const TOTP_SLOT_COUNT: usize = 15;
+/// The number of password safe slots.
+const PWS_SLOT_COUNT: usize = 16;
+/// The number of hidden volume slots.
+const HIDDEN_VOLUME_COUNT: usize = 4;
+/// The length of the temporary passwords created by the `nitrokey`
+/// crate, which are not NUL-terminated.
+const TEMPORARY_PASSWORD_LENGTH: usize = 25;
+
+// The status codes reported by libnitrokey.
+const OK: u8 = 0;
+const WRONG_CRC: u8 = 1;
+const WRONG_SLOT: u8 = 2;
+const SLOT_NOT_PROGRAMMED: u8 = 3;
+const WRONG_PASSWORD: u8 = 4;
+const NOT_AUTHORIZED: u8 = 5;
+const NO_NAME: u8 = 7;
+const NOT_SUPPORTED: u8 = 8;
+const INVALID_SLOT: u8 = 201;
+const INVALID_HEX_STRING: u8 = 202;
+/// The status reported if no device is connected, like libnitrokey
+/// does for communication errors.
+const NOT_CONNECTED: u8 = 255;
+
+// The device models as reported by `NK_get_device_model`.
+const MODEL_DISCONNECTED: u32 = 0;
+const MODEL_PRO: u32 = 1;
+const MODEL_STORAGE: u32 = 2;
+
+/// A programmed OTP slot.
+#[derive(Clone, Debug, PartialEq)]
+struct OtpSlot {
+  name: String,
+  /// The secret, as a hex string.
+  secret: String,
+  digits: u32,
+  /// The counter of an HOTP slot or the time window of a TOTP slot.
+  counter: u64,
+}
+
+/// A programmed password safe slot.
+#[derive(Clone, Debug, PartialEq)]
+struct PwsSlot {
+  name: String,
+  login: String,
+  password: String,
+}
+
+/// A hidden volume.
+#[derive(Clone, Debug, PartialEq)]
+struct HiddenVolume {
+  /// The start of the volume, in percent of the storage.
+  start: u8,
+  /// The end of the volume, in percent of the storage.
+  end: u8,
+  password: String,
+}
+
+/// The state of the virtual device.
+#[derive(Debug, PartialEq)]
+struct State {
+  /// Either `MODEL_PRO` or `MODEL_STORAGE`.
+  model: u32,
+  serial_number: String,
+  admin_pin: String,
+  user_pin: String,
+  admin_retry_count: u8,
+  user_retry_count: u8,
+  /// The raw configuration, as returned by `NK_read_config`.
+  config: [u8; 5],
+  hotp_slots: Vec<Option<OtpSlot>>,
+  totp_slots: Vec<Option<OtpSlot>>,
+  pws_slots: Vec<Option<PwsSlot>>,
+  encrypted_volume_active: bool,
+  hidden_volumes: Vec<Option<HiddenVolume>>,
+  hidden_volume_active: bool,
+  /// Whether the AES keys were built, as they are during the initial
+  /// setup of a device.
+  aes_key_built: bool,
+  /// The time last set using `NK_totp_set_time`.
+  time: u64,
+  /// The minor firmware version, the major one being 0.
+  firmware_version: u8,
+  update_password: String,
+  /// Whether the device is in update mode, in which it cannot be
+  /// connected to.
+  update_mode: bool,
+  /// The number of commands that fail with a wrong checksum before the
+  /// device works again, emulating a flaky connection.
+  crc_errors: u64,
+  /// Whether the device is listed but cannot be connected to by its ID,
+  /// emulating a device that stopped responding.
+  unresponsive: bool,
+  // The members below describe the session and are not persisted.
+  admin_temp_password: Option<Vec<u8>>,
+  user_temp_password: Option<Vec<u8>>,
+  pws_enabled: bool,
+}
+
+impl Default for State {
+  fn default() -> Self {
+    State {
+      model: MODEL_STORAGE,
+      serial_number: "0000c0de".to_string(),
+      admin_pin: DEFAULT_ADMIN_PIN.to_string(),
+      user_pin: DEFAULT_USER_PIN.to_string(),
+      admin_retry_count: PIN_RETRY_COUNT,
+      user_retry_count: PIN_RETRY_COUNT,
+      config: [255, 255, 255, 0, 0],
+      hotp_slots: vec![None; HOTP_SLOT_COUNT],
+      totp_slots: vec![None; TOTP_SLOT_COUNT],
+      pws_slots: vec![None; PWS_SLOT_COUNT],
+      encrypted_volume_active: false,
+      hidden_volumes: vec![None; HIDDEN_VOLUME_COUNT],
+      hidden_volume_active: false,
+      aes_key_built: true,
+      time: 0,
+      firmware_version: 53,
+      update_password: DEFAULT_UPDATE_PASSWORD.to_string(),
+      update_mode: false,
+      crc_errors: 0,
+      unresponsive: false,
+      admin_temp_password: None,
+      user_temp_password: None,
+      pws_enabled: false,
+    }
+  }
+}
+
+/// Check the given PIN against the expected one, counting failed
+/// attempts in the given retry counter.
+fn check_pin(pin: &str, expected: &str, retry_count: &mut u8) -> Result<(), u8> {
+  if *retry_count == 0 {
+    Err(WRONG_PASSWORD)
+  } else if pin != expected {
+    *retry_count -= 1;
+    Err(WRONG_PASSWORD)
+  } else {
+    *retry_count = PIN_RETRY_COUNT;
+    Ok(())
+  }
+}
+
+/// Retrieve the given slot, checking that it is valid and programmed.
+fn get_slot<T>(slots: &[Option<T>], slot: u8) -> Result<&T, u8> {
+  match slots.get(usize::from(slot)) {
+    Some(Some(slot)) => Ok(slot),
+    Some(None) => Err(SLOT_NOT_PROGRAMMED),
+    None => Err(INVALID_SLOT),
+  }
+}
+
+/// Retrieve the given slot for writing, checking that it is valid.
+fn get_slot_mut<T>(slots: &mut [Option<T>], slot: u8) -> Result<&mut Option<T>, u8> {
+  slots.get_mut(usize::from(slot)).ok_or(INVALID_SLOT)
+}
+
+impl State {
+  /// Authenticate as admin, storing the given temporary password.
+  fn authenticate_admin(&mut self, pin: &str, temp_password: Vec<u8>) -> Result<(), u8> {
+    check_pin(pin, &self.admin_pin, &mut self.admin_retry_count)?;
+    self.admin_temp_password = Some(temp_password);
+    Ok(())
+  }
+
+  /// Authenticate as user, storing the given temporary password.
+  fn authenticate_user(&mut self, pin: &str, temp_password: Vec<u8>) -> Result<(), u8> {
+    check_pin(pin, &self.user_pin, &mut self.user_retry_count)?;
+    self.user_temp_password = Some(temp_password);
+    Ok(())
+  }
+
+  /// Check that the given temporary password authenticates the admin.
+  fn check_admin(&self, temp_password: &[u8]) -> Result<(), u8> {
+    match self.admin_temp_password {
+      Some(ref expected) if expected.as_slice() == temp_password => Ok(()),
+      _ => Err(NOT_AUTHORIZED),
+    }
+  }
+
+  /// Check that the given temporary password authenticates the user,
+  /// if the configuration requires the user PIN for generating OTPs.
+  fn check_user(&self, temp_password: Option<&[u8]>) -> Result<(), u8> {
+    if self.config[3] == 0 {
+      return Ok(());
+    }
+    match (&self.user_temp_password, temp_password) {
+      (Some(expected), Some(temp_password)) if expected.as_slice() == temp_password => Ok(()),
+      _ => Err(NOT_AUTHORIZED),
+    }
+  }
+
+  /// Retrieve the OTP slots of the given kind.
+  fn otp_slots(&mut self, totp: bool) -> &mut [Option<OtpSlot>] {
+    if totp {
+      &mut self.totp_slots
+    } else {
+      &mut self.hotp_slots
+    }
+  }
+
+  /// Program the given OTP slot.
+  fn write_otp_slot(
+    &mut self,
+    totp: bool,
+    slot: u8,
+    data: OtpSlot,
+    temp_password: &[u8],
+  ) -> Result<(), u8> {
+    self.check_admin(temp_password)?;
+    if data.name.is_empty() {
+      return Err(NO_NAME);
+    }
+    if from_hex(&data.secret).is_none() {
+      return Err(INVALID_HEX_STRING);
+    }
+    *get_slot_mut(self.otp_slots(totp), slot)? = Some(data);
+    Ok(())
+  }
+
+  /// Erase the given OTP slot.
+  fn erase_otp_slot(&mut self, totp: bool, slot: u8, temp_password: &[u8]) -> Result<(), u8> {
+    self.check_admin(temp_password)?;
+    *get_slot_mut(self.otp_slots(totp), slot)? = None;
+    Ok(())
+  }
+
+  /// Generate a one-time password using the given OTP slot.
+  fn generate_otp(
+    &mut self,
+    totp: bool,
+    slot: u8,
+    temp_password: Option<&[u8]>,
+  ) -> Result<String, u8> {
+    self.check_user(temp_password)?;
+    let time = self.time;
+    let slots = self.otp_slots(totp);
+    // Only the HOTP slots are validated here, the TOTP ones being
+    // checked by `get_slot` below.
+    if !totp && usize::from(slot) >= slots.len() {
+      return Err(WRONG_SLOT);
+    }
+    let data = get_slot(slots, slot)?.clone();
+    let secret = from_hex(&data.secret).ok_or(INVALID_HEX_STRING)?;
+    let counter = if totp {
+      time / data.counter.max(1)
+    } else {
+      data.counter
+    };
+    if !totp {
+      if let Some(Some(slot)) = slots.get_mut(usize::from(slot)) {
+        slot.counter += 1;
+      }
+    }
+    Ok(oath::hotp(&secret, counter, data.digits))
+  }
+
+  /// Check that the password safe is enabled.
+  fn check_pws(&self) -> Result<(), u8> {
+    if self.pws_enabled {
+      Ok(())
+    } else {
+      Err(NOT_AUTHORIZED)
+    }
+  }
+
+  /// Lock the device, forgetting all authentications.
+  fn lock(&mut self) {
+    self.admin_temp_password = None;
+    self.user_temp_password = None;
+    self.pws_enabled = false;
+    // Like the firmware, the Nitrokey Storage closes the encrypted
+    // volume when locked.
+    if self.model == MODEL_STORAGE {
+      self.encrypted_volume_active = false;
+      self.hidden_volume_active = false;
+    }
+  }
+
+  /// Create the JSON representation of the persistent part of the
+  /// state.
+  fn to_json(&self) -> json::Value {
+    let otp_slots = |slots: &[Option<OtpSlot>], counter: &str| {
+      json::Value::Array(
+        slots
+          .iter()
+          .map(|slot| match slot {
+            Some(slot) => json::Value::object(vec![
+              ("name", json::Value::from(slot.name.as_str())),
+              ("secret", json::Value::from(slot.secret.as_str())),
+              ("digits", json::Value::from(u64::from(slot.digits))),
+              (counter, json::Value::from(slot.counter)),
+            ]),
+            None => json::Value::Null,
+          })
+          .collect(),
+      )
+    };
+    let pws_slots = json::Value::Array(
+      self
+        .pws_slots
+        .iter()
+        .map(|slot| match slot {
+          Some(slot) => json::Value::object(vec![
+            ("name", json::Value::from(slot.name.as_str())),
+            ("login", json::Value::from(slot.login.as_str())),
+            ("password", json::Value::from(slot.password.as_str())),
+          ]),
+          None => json::Value::Null,
+        })
+        .collect(),
+    );
+    let hidden_volumes = json::Value::Array(
+      self
+        .hidden_volumes
+        .iter()
+        .map(|volume| match volume {
+          Some(volume) => json::Value::object(vec![
+            ("start", json::Value::from(u64::from(volume.start))),
+            ("end", json::Value::from(u64::from(volume.end))),
+            ("password", json::Value::from(volume.password.as_str())),
+          ]),
+          None => json::Value::Null,
+        })
+        .collect(),
+    );
+    let model = if self.model == MODEL_PRO {
+      "pro"
+    } else {
+      "storage"
+    };
+
+    json::Value::object(vec![
+      ("model", json::Value::from(model)),
+      (
+        "serial_number",
+        json::Value::from(self.serial_number.as_str()),
+      ),
+      ("admin_pin", json::Value::from(self.admin_pin.as_str())),
+      ("user_pin", json::Value::from(self.user_pin.as_str())),
+      (
+        "admin_retry_count",
+        json::Value::from(u64::from(self.admin_retry_count)),
+      ),
+      (
+        "user_retry_count",
+        json::Value::from(u64::from(self.user_retry_count)),
+      ),
+      (
+        "config",
+        json::Value::Array(
+          self
+            .config
+            .iter()
+            .map(|&b| json::Value::from(u64::from(b)))
+            .collect(),
+        ),
+      ),
+      ("hotp_slots", otp_slots(&self.hotp_slots, "counter")),
+      ("totp_slots", otp_slots(&self.totp_slots, "time_window")),
+      ("pws_slots", pws_slots),
+      (
+        "encrypted_volume_active",
+        json::Value::from(self.encrypted_volume_active),
+      ),
+      ("hidden_volumes", hidden_volumes),
+      (
+        "hidden_volume_active",
+        json::Value::from(self.hidden_volume_active),
+      ),
+      ("aes_key_built", json::Value::from(self.aes_key_built)),
+      ("time", json::Value::from(self.time)),
+      (
+        "firmware_version",
+        json::Value::from(u64::from(self.firmware_version)),
+      ),
+      (
+        "update_password",
+        json::Value::from(self.update_password.as_str()),
+      ),
+      ("update_mode", json::Value::from(self.update_mode)),
+      ("crc_errors", json::Value::from(self.crc_errors)),
+      ("unresponsive", json::Value::from(self.unresponsive)),
+    ])
+  }
+
+  /// Create the state described by the given JSON object.
+  ///
+  /// Missing members keep their factory defaults, so that a state file
+  /// only needs to describe what differs from a new device.
+  fn from_json(value: &json::Value) -> Option<State> {
+    let mut state = State::default();
+    let get_str = |key| {
+      value
+        .get(key)
+        .and_then(json::Value::as_str)
+        .map(String::from)
+    };
+    let get_u8 = |key| {
+      value
+        .get(key)
+        .and_then(json::Value::as_u64)
+        .map(|n| n.min(255) as u8)
+    };
+
+    match value.get("model").map(json::Value::as_str) {
+      Some(Some("pro")) => {
+        state.model = MODEL_PRO;
+        state.firmware_version = 9;
+      }
+      Some(Some("storage")) | None => (),
+      Some(_) => return None,
+    }
+    if let Some(serial_number) = get_str("serial_number") {
+      state.serial_number = serial_number;
+    }
+    if let Some(pin) = get_str("admin_pin") {
+      state.admin_pin = pin;
+    }
+    if let Some(pin) = get_str("user_pin") {
+      state.user_pin = pin;
+    }
+    if let Some(count) = get_u8("admin_retry_count") {
+      state.admin_retry_count = count;
+    }
+    if let Some(count) = get_u8("user_retry_count") {
+      state.user_retry_count = count;
+    }
+    if let Some(config) = value.get("config").and_then(json::Value::as_array) {
+      for (byte, value) in state.config.iter_mut().zip(config) {
+        *byte = value.as_u64()?.min(255) as u8;
+      }
+    }
+    otp_slots_from_json(value.get("hotp_slots"), "counter", &mut state.hotp_slots)?;
+    otp_slots_from_json(
+      value.get("totp_slots"),
+      "time_window",
+      &mut state.totp_slots,
+    )?;
+    if let Some(slots) = value.get("pws_slots").and_then(json::Value::as_array) {
+      for (slot, value) in state.pws_slots.iter_mut().zip(slots) {
+        if let json::Value::Null = value {
+          continue;
+        }
+        let get = |key| {
+          value
+            .get(key)
+            .and_then(json::Value::as_str)
+            .map(String::from)
+        };
+        *slot = Some(PwsSlot {
+          name: get("name")?,
+          login: get("login").unwrap_or_default(),
+          password: get("password").unwrap_or_default(),
+        });
+      }
+    }
+    if let Some(active) = value
+      .get("encrypted_volume_active")
+      .and_then(json::Value::as_bool)
+    {
+      state.encrypted_volume_active = active;
+    }
+    if let Some(volumes) = value.get("hidden_volumes").and_then(json::Value::as_array) {
+      for (volume, value) in state.hidden_volumes.iter_mut().zip(volumes) {
+        if let json::Value::Null = value {
+          continue;
+        }
+        let get_u8 = |key| value.get(key)?.as_u64().map(|n| n.min(100) as u8);
+        *volume = Some(HiddenVolume {
+          start: get_u8("start")?,
+          end: get_u8("end")?,
+          password: value.get("password")?.as_str()?.to_string(),
+        });
+      }
+    }
+    if let Some(active) = value
+      .get("hidden_volume_active")
+      .and_then(json::Value::as_bool)
+    {
+      state.hidden_volume_active = active;
+    }
+    if let Some(built) = value.get("aes_key_built").and_then(json::Value::as_bool) {
+      state.aes_key_built = built;
+    }
+    if let Some(time) = value.get("time").and_then(json::Value::as_u64) {
+      state.time = time;
+    }
+    if let Some(version) = get_u8("firmware_version") {
+      state.firmware_version = version;
+    }
+    if let Some(password) = get_str("update_password") {
+      state.update_password = password;
+    }
+    if let Some(active) = value.get("update_mode").and_then(json::Value::as_bool) {
+      state.update_mode = active;
+    }
+    if let Some(count) = value.get("crc_errors").and_then(json::Value::as_u64) {
+      state.crc_errors = count;
+    }
+    if let Some(unresponsive) = value.get("unresponsive").and_then(json::Value::as_bool) {
+      state.unresponsive = unresponsive;
+    }
+    Some(state)
+  }
+}
+
+/// Read the OTP slots from the given JSON array, if any.
+fn otp_slots_from_json(
+  value: Option<&json::Value>,
+  counter: &str,
+  slots: &mut [Option<OtpSlot>],
+) -> Option<()> {
+  if let Some(values) = value.and_then(json::Value::as_array) {
+    for (slot, value) in slots.iter_mut().zip(values) {
+      if let json::Value::Null = value {
+        continue;
+      }
+      let default_counter = if counter == "counter" { 0 } else { 30 };
+      *slot = Some(OtpSlot {
+        name: value.get("name")?.as_str()?.to_string(),
+        secret: value.get("secret")?.as_str()?.to_string(),
+        digits: value
+          .get("digits")
+          .and_then(json::Value::as_u64)
+          .unwrap_or(6) as u32,
+        counter: value
+          .get(counter)
+          .and_then(json::Value::as_u64)
+          .unwrap_or(default_counter),
+      });
+    }
+  }
+  Some(())
+}
+
+/// Decode the given hex string.
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+  if s.len() % 2 == 1 {
+    return None;
+  }
+  (0..s.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+    .collect()
+}
+
+/// The connection to the virtual device.
+#[derive(Debug, Default)]
+struct Connection {
+  /// The state of the connected device, along with the path of the
+  /// file it is persisted in.
+  device: Option<(path::PathBuf, State)>,
+  /// The status of the last command.
+  last_status: u8,
+}
+
+thread_local! {
+  static CONNECTION: cell::RefCell<Connection> = cell::RefCell::new(Connection::default());
+}
+
+/// Retrieve the status of the last command.
+fn last_status() -> u8 {
+  CONNECTION.with(|connection| connection.borrow().last_status)
+}
+
+/// Retrieve the paths of the files holding the states of the virtual
+/// devices.
+fn device_paths() -> Vec<path::PathBuf> {
+  match env::var_os(STATE_VAR) {
+    Some(paths) => env::split_paths(&paths).collect(),
+    None => Vec::new(),
+  }
+}
+
+/// Load the state of the virtual device persisted in the given file.
+///
+/// A device in update mode only talks to flashing software, so `None`
+/// is returned for it.
+fn load_state(path: &path::Path) -> Option<State> {
+  let state = match fs::read_to_string(path) {
+    Ok(data) => data
+      .parse::<json::Value>()
+      .ok()
+      .and_then(|value| State::from_json(&value)),
+    Err(_) => Some(State::default()),
+  };
+  state.filter(|state| !state.update_mode)
+}
+
+/// Retrieve the ID libnitrokey reports for the Nitrokey Storage with
+/// the given state, connected at the given index.
+fn device_id(state: &State, index: usize) -> String {
+  format!(
+    "{}:00000000_p_0001:{:04x}:02",
+    state.serial_number,
+    index + 1
+  )
+}
+
+/// Connect to the first virtual device, loading its state, and return
+/// its model.
+fn connect() -> u32 {
+  match device_paths().into_iter().next() {
+    Some(path) => connect_path(path),
+    None => MODEL_DISCONNECTED,
+  }
+}
+
+/// Connect to the virtual device persisted in the given file, loading
+/// its state, and return its model.
+fn connect_path(path: path::PathBuf) -> u32 {
+  let state = load_state(&path);
+  let model = state
+    .as_ref()
+    .map(|state| state.model)
+    .unwrap_or(MODEL_DISCONNECTED);
+  CONNECTION.with(|connection| connection.borrow_mut().device = state.map(|state| (path, state)));
+  model
+}
+
+/// Run the given function on the state of the connected device,
+/// recording its status and persisting the state afterwards.
+///
+/// If the function fails or no device is connected, the given fallback
+/// value is returned.
+fn run<R, F>(fallback: R, f: F) -> R
+where
+  F: FnOnce(&mut State) -> Result<R, u8>,
+{
+  CONNECTION.with(|connection| {
+    let mut connection = connection.borrow_mut();
+    let result = match connection.device {
+      Some((ref path, ref mut state)) => {
+        let result = f(state);
+        // A test device losing its state is not worth failing the
+        // command, so errors are ignored.
+        let _ = fs::write(path, format!("{}\n", state.to_json()));
+        result
+      }
+      None => Err(NOT_CONNECTED),
+    };
+    match result {
+      Ok(result) => {
+        connection.last_status = OK;
+        result
+      }
+      Err(status) => {
+        connection.last_status = status;
+        fallback
+      }
+    }
+  })
+}
+
+/// Run the given command on the state of the connected device and
+/// return its status.
+///
+/// As long as checksum errors are pending, the command fails with a
+/// wrong checksum instead.
+fn command<F>(f: F) -> c_int
+where
+  F: FnOnce(&mut State) -> Result<(), u8>,
+{
+  run((), |state| {
+    if state.crc_errors > 0 {
+      state.crc_errors -= 1;
+      return Err(WRONG_CRC);
+    }
+    f(state)
+  });
+  c_int::from(last_status())
+}
+
+/// Read the C string at the given pointer.
+unsafe fn read_string(ptr: *const c_char) -> String {
+  ffi::CStr::from_ptr(ptr).to_string_lossy().into_owned()
+}
+
+/// Read the temporary password at the given pointer.
+unsafe fn read_temp_password(ptr: *const c_char) -> Vec<u8> {
+  slice::from_raw_parts(ptr as *const u8, TEMPORARY_PASSWORD_LENGTH).to_vec()
+}
+
+/// Copy the given bytes into a buffer allocated using `malloc`, as the
+/// `nitrokey` crate frees the results of libnitrokey.
+fn to_malloc(data: &[u8]) -> *mut u8 {
+  unsafe {
+    let ptr = libc::malloc(data.len().max(1)) as *mut u8;
+    if !ptr.is_null() {
+      ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len());
+    }
+    ptr
+  }
+}
+
+/// Copy the given string into a C string allocated using `malloc`.
+fn to_c_string(s: &str) -> *mut c_char {
+  let mut data = s.as_bytes().to_vec();
+  data.push(0);
+  to_malloc(&data) as *mut c_char
+}
+
+/// Run the given function returning a string on the connected device.
+///
+/// Like libnitrokey, an empty string is returned on failure.
+fn string_result<F>(f: F) -> *mut c_char
+where
+  F: FnOnce(&mut State) -> Result<String, u8>,
+{
+  to_c_string(&run(String::new(), f))
+}
+
+/// Emulates `NK_set_debug`, which has no effect here.
+#[no_mangle]
+pub extern "C" fn NK_set_debug(_state: bool) {}
+
+/// Emulates `NK_set_debug_level`, which has no effect here.
+#[no_mangle]
+pub extern "C" fn NK_set_debug_level(_level: c_int) {}
+
+/// Emulates `NK_login_auto`.
+#[no_mangle]
+pub extern "C" fn NK_login_auto() -> c_int {
+  (connect() != MODEL_DISCONNECTED) as c_int
+}
+
+/// Emulates `NK_login_enum`.
+#[no_mangle]
+pub extern "C" fn NK_login_enum(device_model: u32) -> c_int {
+  let model = connect();
+  (model != MODEL_DISCONNECTED && model == device_model) as c_int
+}
+
+/// Emulates `NK_logout`.
+#[no_mangle]
+pub extern "C" fn NK_logout() -> c_int {
+  0
+}
+
+/// Emulates `NK_list_devices_by_cpuID`, which only reports Nitrokey
+/// Storage devices.
+#[no_mangle]
+pub extern "C" fn NK_list_devices_by_cpuID() -> *mut c_char {
+  let ids = device_paths()
+    .iter()
+    .enumerate()
+    .filter_map(|(index, path)| {
+      let state = load_state(path)?;
+      if state.model == MODEL_STORAGE {
+        Some(device_id(&state, index))
+      } else {
+        None
+      }
+    })
+    .collect::<Vec<_>>();
+  to_c_string(&ids.join(";"))
+}
+
+/// Emulates `NK_connect_with_ID`.
+#[no_mangle]
+pub unsafe extern "C" fn NK_connect_with_ID(id: *const c_char) -> c_int {
+  let id = read_string(id);
+  let path = device_paths()
+    .into_iter()
+    .enumerate()
+    .find(|(index, path)| match load_state(path) {
+      Some(ref state) => {
+        state.model == MODEL_STORAGE && !state.unresponsive && device_id(state, *index) == id
+      }
+      None => false,
+    });
+  match path {
+    Some((_, path)) => (connect_path(path) != MODEL_DISCONNECTED) as c_int,
+    None => 0,
+  }
+}
+
+/// Emulates `NK_get_device_model`.
+#[no_mangle]
+pub extern "C" fn NK_get_device_model() -> u32 {
+  run(MODEL_DISCONNECTED, |state| Ok(state.model))
+}
+
+/// Emulates `NK_get_last_command_status`.
+#[no_mangle]
+pub extern "C" fn NK_get_last_command_status() -> u8 {
+  last_status()
+}
+
+/// Emulates `NK_device_serial_number`.
+#[no_mangle]
+pub extern "C" fn NK_device_serial_number() -> *mut c_char {
+  string_result(|state| Ok(state.serial_number.clone()))
+}
+
+/// Emulates `NK_get_major_firmware_version`.
+#[no_mangle]
+pub extern "C" fn NK_get_major_firmware_version() -> c_int {
+  run(0, |_| Ok(0))
+}
+
+/// Emulates `NK_get_minor_firmware_version`.
+#[no_mangle]
+pub extern "C" fn NK_get_minor_firmware_version() -> c_int {
+  run(0, |state| Ok(c_int::from(state.firmware_version)))
+}
+
+/// Emulates `NK_get_user_retry_count`.
+#[no_mangle]
+pub extern "C" fn NK_get_user_retry_count() -> u8 {
+  run(0, |state| Ok(state.user_retry_count))
+}
+
+/// Emulates `NK_get_admin_retry_count`.
+#[no_mangle]
+pub extern "C" fn NK_get_admin_retry_count() -> u8 {
+  run(0, |state| Ok(state.admin_retry_count))
+}
+
+/// Emulates `NK_read_config`.
+#[no_mangle]
+pub extern "C" fn NK_read_config() -> *mut u8 {
+  run(ptr::null_mut(), |state| Ok(to_malloc(&state.config)))
+}
+
+/// Emulates `NK_write_config`.
+#[no_mangle]
+pub unsafe extern "C" fn NK_write_config(
+  numlock: u8,
+  capslock: u8,
+  scrolllock: u8,
+  enable_user_password: bool,
+  delete_user_password: bool,
+  admin_temporary_password: *const c_char,
+) -> c_int {
+  let temp_password = read_temp_password(admin_temporary_password);
+  command(|state| {
+    state.check_admin(&temp_password)?;
+    state.config = [
+      numlock,
+      capslock,
+      scrolllock,
+      enable_user_password as u8,
+      delete_user_password as u8,
+    ];
+    Ok(())
+  })
+}
+
+/// Emulates `NK_first_authenticate`.
+#[no_mangle]
+pub unsafe extern "C" fn NK_first_authenticate(
+  admin_password: *const c_char,
+  admin_temporary_password: *const c_char,
+) -> c_int {
+  let pin = read_string(admin_password);
+  let temp_password = read_temp_password(admin_temporary_password);
+  command(|state| state.authenticate_admin(&pin, temp_password))
+}
+
+/// Emulates `NK_user_authenticate`.
+#[no_mangle]
+pub unsafe extern "C" fn NK_user_authenticate(
+  user_password: *const c_char,
+  user_temporary_password: *const c_char,
+) -> c_int {
+  let pin = read_string(user_password);
+  let temp_password = read_temp_password(user_temporary_password);
+  command(|state| state.authenticate_user(&pin, temp_password))
+}
+
+/// Emulates `NK_lock_device`.
+#[no_mangle]
+pub extern "C" fn NK_lock_device() -> c_int {
+  command(|state| {
+    state.lock();
+    Ok(())
+  })
+}
+
+/// Emulates `NK_change_admin_PIN`.
+#[no_mangle]
+pub unsafe extern "C" fn NK_change_admin_PIN(
+  current_PIN: *const c_char,
+  new_PIN: *const c_char,
+) -> c_int {
+  let (current, new) = (read_string(current_PIN), read_string(new_PIN));
+  command(|state| {
+    check_pin(&current, &state.admin_pin, &mut state.admin_retry_count)?;
+    state.admin_pin = new;
+    Ok(())
+  })
+}
+
+/// Emulates `NK_change_user_PIN`.
+#[no_mangle]
+pub unsafe extern "C" fn NK_change_user_PIN(
+  current_PIN: *const c_char,
+  new_PIN: *const c_char,
+) -> c_int {
+  let (current, new) = (read_string(current_PIN), read_string(new_PIN));
+  command(|state| {
+    check_pin(&current, &state.user_pin, &mut state.user_retry_count)?;
+    state.user_pin = new;
+    Ok(())
+  })
+}
+
+/// Emulates `NK_unlock_user_password`.
+#[no_mangle]
+pub unsafe extern "C" fn NK_unlock_user_password(
+  admin_password: *const c_char,
+  new_user_password: *const c_char,
+) -> c_int {
+  let (admin_pin, user_pin) = (read_string(admin_password), read_string(new_user_password));
+  command(|state| {
+    check_pin(&admin_pin, &state.admin_pin, &mut state.admin_retry_count)?;
+    state.user_pin = user_pin;
+    state.user_retry_count = PIN_RETRY_COUNT;
+    Ok(())
+  })
+}
+
+/// Emulates `NK_build_aes_key`.
+///
+/// The password safe and the hidden volumes are encrypted with the AES
+/// key, so they are lost along with the old key.
+#[no_mangle]
+pub unsafe extern "C" fn NK_build_aes_key(admin_password: *const c_char) -> c_int {
+  let pin = read_string(admin_password);
+  command(|state| {
+    check_pin(&pin, &state.admin_pin, &mut state.admin_retry_count)?;
+    state.pws_slots = vec![None; PWS_SLOT_COUNT];
+    state.pws_enabled = false;
+    state.encrypted_volume_active = false;
+    state.hidden_volumes = vec![None; HIDDEN_VOLUME_COUNT];
+    state.hidden_volume_active = false;
+    state.aes_key_built = true;
+    Ok(())
+  })
+}
+
+/// Emulates `NK_fill_SD_card_with_random_data`, which completes
+/// immediately here and overwrites the hidden volumes.
+#[no_mangle]
+pub unsafe extern "C" fn NK_fill_SD_card_with_random_data(admin_pin: *const c_char) -> c_int {
+  let pin = read_string(admin_pin);
+  command(|state| {
+    if state.model != MODEL_STORAGE {
+      return Err(NOT_SUPPORTED);
+    }
+    check_pin(&pin, &state.admin_pin, &mut state.admin_retry_count)?;
+    state.encrypted_volume_active = false;
+    state.hidden_volumes = vec![None; HIDDEN_VOLUME_COUNT];
+    state.hidden_volume_active = false;
+    Ok(())
+  })
+}
+
+/// Emulates `NK_get_progress_bar_value`.
+///
+/// Filling the storage completes immediately here, so the device is
+/// never busy.
+#[no_mangle]
+pub extern "C" fn NK_get_progress_bar_value() -> c_int {
+  run(-1, |state| {
+    if state.model != MODEL_STORAGE {
+      return Err(NOT_SUPPORTED);
+    }
+    Ok(-1)
+  })
+}
+
+/// Emulates `NK_export_firmware`, which has no effect besides checking
+/// the admin PIN here.
+#[no_mangle]
+pub unsafe extern "C" fn NK_export_firmware(admin_pin: *const c_char) -> c_int {
+  let pin = read_string(admin_pin);
+  command(|state| {
+    if state.model != MODEL_STORAGE {
+      return Err(NOT_SUPPORTED);
+    }
+    check_pin(&pin, &state.admin_pin, &mut state.admin_retry_count)
+  })
+}
+
+/// Emulates `NK_enable_firmware_update`.
+///
+/// The device leaves update mode once `update_mode` is reset in the
+/// state file, like it does when the firmware is launched again.
+#[no_mangle]
+pub unsafe extern "C" fn NK_enable_firmware_update(update_password: *const c_char) -> c_int {
+  let password = read_string(update_password);
+  command(|state| {
+    if state.model != MODEL_STORAGE {
+      return Err(NOT_SUPPORTED);
+    }
+    if password != state.update_password {
+      return Err(WRONG_PASSWORD);
+    }
+    state.lock();
+    state.update_mode = true;
+    Ok(())
+  })
+}
+
+/// Emulates `NK_totp_set_time`.
+#[no_mangle]
+pub extern "C" fn NK_totp_set_time(time: u64) -> c_int {
+  command(|state| {
+    state.time = time;
+    Ok(())
+  })
+}
+
+/// Emulates `NK_get_hotp_slot_name`.
+#[no_mangle]
+pub extern "C" fn NK_get_hotp_slot_name(slot_number: u8) -> *mut c_char {
+  string_result(|state| Ok(get_slot(&state.hotp_slots, slot_number)?.name.clone()))
+}
+
+/// Emulates `NK_get_totp_slot_name`.
+#[no_mangle]
+pub extern "C" fn NK_get_totp_slot_name(slot_number: u8) -> *mut c_char {
+  string_result(|state| Ok(get_slot(&state.totp_slots, slot_number)?.name.clone()))
+}
+
+/// Emulates `NK_get_hotp_code`.
+#[no_mangle]
+pub extern "C" fn NK_get_hotp_code(slot_number: u8) -> *mut c_char {
+  string_result(|state| state.generate_otp(false, slot_number, None))
+}
+
+/// Emulates `NK_get_hotp_code_PIN`.
+#[no_mangle]
+pub unsafe extern "C" fn NK_get_hotp_code_PIN(
+  slot_number: u8,
+  user_temporary_password: *const c_char,
+) -> *mut c_char {
+  let temp_password = read_temp_password(user_temporary_password);
+  string_result(|state| state.generate_otp(false, slot_number, Some(&temp_password)))
+}
+
+/// Emulates `NK_get_totp_code`, using the time last set instead of the
+/// given challenge.
+#[no_mangle]
+pub extern "C" fn NK_get_totp_code(
+  slot_number: u8,
+  _challenge: u64,
+  _last_totp_time: u64,
+  _last_interval: u8,
+) -> *mut c_char {
+  string_result(|state| state.generate_otp(true, slot_number, None))
+}
+
+/// Emulates `NK_get_totp_code_PIN`, using the time last set instead of
+/// the given challenge.
+#[no_mangle]
+pub unsafe extern "C" fn NK_get_totp_code_PIN(
+  slot_number: u8,
+  _challenge: u64,
+  _last_totp_time: u64,
+  _last_interval: u8,
+  user_temporary_password: *const c_char,
+) -> *mut c_char {
+  let temp_password = read_temp_password(user_temporary_password);
+  string_result(|state| state.generate_otp(true, slot_number, Some(&temp_password)))
+}
+
+/// Emulates `NK_write_hotp_slot`.
+#[no_mangle]
+pub unsafe extern "C" fn NK_write_hotp_slot(
+  slot_number: u8,
+  slot_name: *const c_char,
+  secret: *const c_char,
+  hotp_counter: u64,
+  use_8_digits: bool,
+  _use_enter: bool,
+  _use_tokenID: bool,
+  _token_ID: *const c_char,
+  temporary_password: *const c_char,
+) -> c_int {
+  let data = OtpSlot {
+    name: read_string(slot_name),
+    secret: read_string(secret),
+    digits: if use_8_digits { 8 } else { 6 },
+    counter: hotp_counter,
+  };
+  let temp_password = read_temp_password(temporary_password);
+  command(|state| state.write_otp_slot(false, slot_number, data, &temp_password))
+}
+
+/// Emulates `NK_write_totp_slot`.
+#[no_mangle]
+pub unsafe extern "C" fn NK_write_totp_slot(
+  slot_number: u8,
+  slot_name: *const c_char,
+  secret: *const c_char,
+  time_window: u16,
+  use_8_digits: bool,
+  _use_enter: bool,
+  _use_tokenID: bool,
+  _token_ID: *const c_char,
+  temporary_password: *const c_char,
+) -> c_int {
+  let data = OtpSlot {
+    name: read_string(slot_name),
+    secret: read_string(secret),
+    digits: if use_8_digits { 8 } else { 6 },
+    counter: u64::from(time_window),
+  };
+  let temp_password = read_temp_password(temporary_password);
+  command(|state| state.write_otp_slot(true, slot_number, data, &temp_password))
+}
+
+/// Emulates `NK_erase_hotp_slot`.
+#[no_mangle]
+pub unsafe extern "C" fn NK_erase_hotp_slot(
+  slot_number: u8,
+  temporary_password: *const c_char,
+) -> c_int {
+  let temp_password = read_temp_password(temporary_password);
+  command(|state| state.erase_otp_slot(false, slot_number, &temp_password))
+}
+
+/// Emulates `NK_erase_totp_slot`.
+#[no_mangle]
+pub unsafe extern "C" fn NK_erase_totp_slot(
+  slot_number: u8,
+  temporary_password: *const c_char,
+) -> c_int {
+  let temp_password = read_temp_password(temporary_password);
+  command(|state| state.erase_otp_slot(true, slot_number, &temp_password))
+}
+
+/// Emulates `NK_enable_password_safe`.
+#[no_mangle]
+pub unsafe extern "C" fn NK_enable_password_safe(user_pin: *const c_char) -> c_int {
+  let pin = read_string(user_pin);
+  command(|state| {
+    check_pin(&pin, &state.user_pin, &mut state.user_retry_count)?;
+    state.pws_enabled = true;
+    Ok(())
+  })
+}
+
+/// Emulates `NK_get_password_safe_slot_status`.
+#[no_mangle]
+pub extern "C" fn NK_get_password_safe_slot_status() -> *mut u8 {
+  run(ptr::null_mut(), |state| {
+    let status = state
+      .pws_slots
+      .iter()
+      .map(|slot| slot.is_some() as u8)
+      .collect::<Vec<_>>();
+    Ok(to_malloc(&status))
+  })
+}
+
+/// Emulates `NK_get_password_safe_slot_name`.
+#[no_mangle]
+pub extern "C" fn NK_get_password_safe_slot_name(slot_number: u8) -> *mut c_char {
+  string_result(|state| {
+    state.check_pws()?;
+    Ok(get_slot(&state.pws_slots, slot_number)?.name.clone())
+  })
+}
+
+/// Emulates `NK_get_password_safe_slot_login`.
+#[no_mangle]
+pub extern "C" fn NK_get_password_safe_slot_login(slot_number: u8) -> *mut c_char {
+  string_result(|state| {
+    state.check_pws()?;
+    Ok(get_slot(&state.pws_slots, slot_number)?.login.clone())
+  })
+}
+
+/// Emulates `NK_get_password_safe_slot_password`.
+#[no_mangle]
+pub extern "C" fn NK_get_password_safe_slot_password(slot_number: u8) -> *mut c_char {
+  string_result(|state| {
+    state.check_pws()?;
+    Ok(get_slot(&state.pws_slots, slot_number)?.password.clone())
+  })
+}
+
+/// Emulates `NK_write_password_safe_slot`.
+#[no_mangle]
+pub unsafe extern "C" fn NK_write_password_safe_slot(
+  slot_number: u8,
+  slot_name: *const c_char,
+  slot_login: *const c_char,
+  slot_password: *const c_char,
+) -> c_int {
+  let slot = PwsSlot {
+    name: read_string(slot_name),
+    login: read_string(slot_login),
+    password: read_string(slot_password),
+  };
+  command(|state| {
+    state.check_pws()?;
+    *get_slot_mut(&mut state.pws_slots, slot_number)? = Some(slot);
+    Ok(())
+  })
+}
+
+/// Emulates `NK_erase_password_safe_slot`.
+#[no_mangle]
+pub extern "C" fn NK_erase_password_safe_slot(slot_number: u8) -> c_int {
+  command(|state| {
+    state.check_pws()?;
+    *get_slot_mut(&mut state.pws_slots, slot_number)? = None;
+    Ok(())
+  })
+}
+
+/// Emulates `NK_get_status_storage`.
+#[no_mangle]
+pub unsafe extern "C" fn NK_get_status_storage(out: *mut nitrokey_sys::NK_storage_status) -> c_int {
+  command(|state| {
+    if state.model != MODEL_STORAGE {
+      return Err(NOT_SUPPORTED);
+    }
+    let status = &mut *out;
+    status.unencrypted_volume_read_only = false;
+    status.unencrypted_volume_active = true;
+    status.encrypted_volume_read_only = false;
+    status.encrypted_volume_active = state.encrypted_volume_active;
+    status.hidden_volume_read_only = false;
+    status.hidden_volume_active = state.hidden_volume_active;
+    status.firmware_version_major = 0;
+    status.firmware_version_minor = state.firmware_version;
+    status.firmware_locked = false;
+    status.serial_number_sd_card = 0x1234_5678;
+    status.serial_number_smart_card = u32::from_str_radix(&state.serial_number, 16).unwrap_or(0);
+    status.user_retry_count = state.user_retry_count;
+    status.admin_retry_count = state.admin_retry_count;
+    status.new_sd_card_found = false;
+    status.filled_with_random = true;
+    status.stick_initialized = state.aes_key_built;
+    Ok(())
+  })
+}
+
+/// Emulates `NK_unlock_encrypted_volume`.
+#[no_mangle]
+pub unsafe extern "C" fn NK_unlock_encrypted_volume(user_pin: *const c_char) -> c_int {
+  let pin = read_string(user_pin);
+  command(|state| {
+    if state.model != MODEL_STORAGE {
+      return Err(NOT_SUPPORTED);
+    }
+    check_pin(&pin, &state.user_pin, &mut state.user_retry_count)?;
+    state.encrypted_volume_active = true;
+    state.hidden_volume_active = false;
+    Ok(())
+  })
+}
+
+/// Emulates `NK_lock_encrypted_volume`, which closes the hidden volume,
+/// too.
+#[no_mangle]
+pub extern "C" fn NK_lock_encrypted_volume() -> c_int {
+  command(|state| {
+    if state.model != MODEL_STORAGE {
+      return Err(NOT_SUPPORTED);
+    }
+    state.encrypted_volume_active = false;
+    state.hidden_volume_active = false;
+    Ok(())
+  })
+}
+
+/// Emulates `NK_create_hidden_volume`.
+#[no_mangle]
+pub unsafe extern "C" fn NK_create_hidden_volume(
+  slot_nr: u8,
+  start_percent: u8,
+  end_percent: u8,
+  hidden_volume_password: *const c_char,
+) -> c_int {
+  let password = read_string(hidden_volume_password);
+  command(|state| {
+    if state.model != MODEL_STORAGE {
+      return Err(NOT_SUPPORTED);
+    }
+    if !state.encrypted_volume_active {
+      return Err(NOT_AUTHORIZED);
+    }
+    if start_percent >= end_percent || end_percent > 100 {
+      return Err(WRONG_SLOT);
+    }
+    *get_slot_mut(&mut state.hidden_volumes, slot_nr)? = Some(HiddenVolume {
+      start: start_percent,
+      end: end_percent,
+      password,
+    });
+    Ok(())
+  })
+}
+
+/// Emulates `NK_unlock_hidden_volume`, which closes the encrypted
+/// volume in favor of the hidden one.
+#[no_mangle]
+pub unsafe extern "C" fn NK_unlock_hidden_volume(hidden_volume_password: *const c_char) -> c_int {
+  let password = read_string(hidden_volume_password);
+  command(|state| {
+    if state.model != MODEL_STORAGE {
+      return Err(NOT_SUPPORTED);
+    }
+    if !state.encrypted_volume_active {
+      return Err(NOT_AUTHORIZED);
+    }
+    let found = state
+      .hidden_volumes
+      .iter()
+      .flatten()
+      .any(|volume| volume.password == password);
+    if !found {
+      return Err(WRONG_PASSWORD);
+    }
+    state.encrypted_volume_active = false;
+    state.hidden_volume_active = true;
+    Ok(())
+  })
+}
+
+/// Emulates `NK_lock_hidden_volume`.
+#[no_mangle]
+pub extern "C" fn NK_lock_hidden_volume() -> c_int {
+  command(|state| {
+    if state.model != MODEL_STORAGE {
+      return Err(NOT_SUPPORTED);
+    }
+    state.hidden_volume_active = false;
+    Ok(())
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+  }
+
+  #[test]
+  fn pin_retry_counter() {
+    let mut state = State::default();
+    assert_eq!(
+      state.authenticate_user("000000", vec![1]),
+      Err(WRONG_PASSWORD)
+    );
+    assert_eq!(
+      state.authenticate_user("000000", vec![1]),
+      Err(WRONG_PASSWORD)
+    );
+    assert_eq!(state.user_retry_count, 1);
+    assert_eq!(state.authenticate_user(DEFAULT_USER_PIN, vec![1]), Ok(()));
+    assert_eq!(state.user_retry_count, PIN_RETRY_COUNT);
+
+    for _ in 0..PIN_RETRY_COUNT {
+      assert!(state.authenticate_admin("0", vec![2]).is_err());
+    }
+    assert_eq!(state.admin_retry_count, 0);
+    assert_eq!(
+      state.authenticate_admin(DEFAULT_ADMIN_PIN, vec![2]),
+      Err(WRONG_PASSWORD)
+    );
+  }
+
+  #[test]
+  fn otp_slots() {
+    let mut state = State::default();
+    let slot = OtpSlot {
+      name: "test".to_string(),
+      secret: to_hex(b"12345678901234567890"),
+      digits: 6,
+      counter: 0,
+    };
+    assert_eq!(
+      state.write_otp_slot(false, 0, slot.clone(), &[1]),
+      Err(NOT_AUTHORIZED)
+    );
+    state
+      .authenticate_admin(DEFAULT_ADMIN_PIN, vec![1])
+      .unwrap();
+    assert_eq!(
+      state.write_otp_slot(false, 3, slot.clone(), &[1]),
+      Err(INVALID_SLOT)
+    );
+    state.write_otp_slot(false, 0, slot.clone(), &[1]).unwrap();
+    assert_eq!(state.generate_otp(false, 0, None), Ok("755224".to_string()));
+    assert_eq!(state.generate_otp(false, 0, None), Ok("287082".to_string()));
+    assert_eq!(state.generate_otp(false, 1, None), Err(SLOT_NOT_PROGRAMMED));
+
+    state.config[3] = 1;
+    assert_eq!(state.generate_otp(false, 0, None), Err(NOT_AUTHORIZED));
+  }
+
+  #[test]
+  fn json_round_trip() {
+    let mut state = State {
+      model: MODEL_PRO,
+      user_retry_count: 2,
+      encrypted_volume_active: true,
+      time: 1_546_300_800,
+      ..Default::default()
+    };
+    state.totp_slots[3] = Some(OtpSlot {
+      name: "totp".to_string(),
+      secret: "abcd".to_string(),
+      digits: 8,
+      counter: 60,
+    });
+    state.pws_slots[15] = Some(PwsSlot {
+      name: "name".to_string(),
+      login: "login".to_string(),
+      password: "password".to_string(),
+    });
+    state.hidden_volumes[2] = Some(HiddenVolume {
+      start: 10,
+      end: 40,
+      password: "hidden".to_string(),
+    });
+
+    let value = state.to_json().to_string().parse::<json::Value>().unwrap();
+    assert_eq!(State::from_json(&value), Some(state));
+
+    let value = "{\"model\":\"pro\"}".parse::<json::Value>().unwrap();
+    let state = State::from_json(&value).unwrap();
+    assert_eq!(state.model, MODEL_PRO);
+    assert_eq!(state.user_pin, DEFAULT_USER_PIN);
+  }
+}
@@ -0,0 +1,161 @@
+// watchdog.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::result;
+use std::sync;
+use std::sync::atomic;
+use std::thread;
+use std::time;
+
+use crate::error::Error;
+use crate::signal;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The status the program exits with if it timed out, as used by
+/// `timeout(1)`.
+pub const EXIT_STATUS: i32 = 124;
+
+/// The interval at which the watchdog checks the time left, in
+/// milliseconds.
+const TICK: u64 = 100;
+
+/// The number of active suspensions of the watchdog.
+static SUSPENDED: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+/// Whether a watchdog expired.
+static EXPIRED: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+/// A watchdog aborting the program if it is not dropped in time.
+///
+/// The time the watchdog is suspended, e.g., while waiting for the user
+/// to enter a PIN, does not count towards the timeout. Once the timeout
+/// expired, the watchdog reports it and the next call to `check` fails,
+/// so that the program stops at the next point at which it is safe to
+/// do so. While signals are deferred because an operation must not be
+/// interrupted, the watchdog waits for the operation to complete.
+#[derive(Debug)]
+pub struct Watchdog {
+  /// Whether the watchdog was dropped and its thread has to stop.
+  stopped: sync::Arc<atomic::AtomicBool>,
+}
+
+/// The time left until a watchdog expires.
+#[derive(Debug)]
+struct Timer {
+  left: time::Duration,
+}
+
+impl Timer {
+  /// Account for the given time having passed, unless the watchdog was
+  /// suspended, and check whether the timer expired.
+  fn tick(&mut self, elapsed: time::Duration, suspended: bool) -> bool {
+    if !suspended {
+      self.left = self
+        .left
+        .checked_sub(elapsed)
+        .unwrap_or_else(|| time::Duration::from_secs(0));
+    }
+    self.left == time::Duration::from_secs(0)
+  }
+}
+
+/// Start a watchdog aborting the program after the given timeout,
+/// reporting the given operation as timed out.
+pub fn start(timeout: time::Duration, operation: String) -> Watchdog {
+  let stopped = sync::Arc::new(atomic::AtomicBool::new(false));
+  let thread_stopped = stopped.clone();
+  let _ = thread::spawn(move || {
+    let tick = time::Duration::from_millis(TICK);
+    let mut timer = Timer { left: timeout };
+    loop {
+      thread::sleep(tick);
+      if thread_stopped.load(atomic::Ordering::SeqCst) {
+        break;
+      }
+      let suspended = SUSPENDED.load(atomic::Ordering::SeqCst) > 0;
+      if timer.tick(tick, suspended) && !signal::is_deferred() {
+        eprintln!(
+          "{} timed out after {} seconds",
+          operation,
+          timeout.as_secs()
+        );
+        EXPIRED.store(true, atomic::Ordering::SeqCst);
+        break;
+      }
+    }
+  });
+  Watchdog { stopped }
+}
+
+impl Drop for Watchdog {
+  fn drop(&mut self) {
+    self.stopped.store(true, atomic::Ordering::SeqCst);
+  }
+}
+
+/// Check whether a watchdog expired.
+pub fn expired() -> bool {
+  EXPIRED.load(atomic::Ordering::SeqCst)
+}
+
+/// Report an expired watchdog, if any, as an `Error::TimedOut`.
+pub fn check() -> Result<()> {
+  if expired() {
+    Err(Error::TimedOut)
+  } else {
+    Ok(())
+  }
+}
+/// A guard suspending all watchdogs for as long as it is alive.
+#[derive(Debug)]
+pub struct Suspension(());
+
+/// Suspend all watchdogs until the returned guard is dropped.
+///
+/// Waiting for user input is not supposed to time out.
+pub fn suspend() -> Suspension {
+  let _ = SUSPENDED.fetch_add(1, atomic::Ordering::SeqCst);
+  Suspension(())
+}
+
+impl Drop for Suspension {
+  fn drop(&mut self) {
+    let _ = SUSPENDED.fetch_sub(1, atomic::Ordering::SeqCst);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn expire_timer() {
+    let tick = time::Duration::from_millis(TICK);
+    let mut timer = Timer {
+      left: time::Duration::from_millis(2 * TICK),
+    };
+    assert!(!timer.tick(tick, false));
+    // Suspended time does not count towards the timeout.
+    assert!(!timer.tick(tick, true));
+    assert!(!timer.tick(tick * 10, true));
+    assert!(timer.tick(tick, false));
+    assert!(timer.tick(tick, false));
+  }
+}
@@ -0,0 +1,1991 @@
+// cli.rs
+
+// *************************************************************************
+// * Copyright (C) 2018 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! End-to-end tests running the nitrocli binary against the virtual
+//! device.
+//!
+//! Every test runs the binary in a directory of its own, holding the
+//! state of the virtual device, the configuration and cache
+//! directories, as well as a scripted `gpg-connect-agent` that answers
+//! PIN inquiries with the default PINs of the virtual device.
+
+#![cfg(feature = "virtual")]
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::path;
+use std::process;
+use std::sync::atomic;
+use std::thread;
+use std::time;
+
+/// The script answering the requests nitrocli sends to gpg-agent and
+/// logging them to agent.log.
+const AGENT: &str = "#!/bin/sh
+echo \"$1\" >> \"$HOME/agent.log\"
+case \"$1\" in
+  CLEAR_PASSPHRASE*|GET_CONFIRMATION*) echo OK ;;
+  *Admin+PIN*) printf 'D 12345678\\nOK\\n' ;;
+  *) printf 'D 123456\\nOK\\n' ;;
+esac
+";
+
+/// A stand-in for gpg that "encrypts" files by prepending the header of
+/// an ASCII-armored message.
+const GPG: &str = "#!/bin/sh
+while [ $# -gt 0 ]; do
+  case \"$1\" in
+    --decrypt) decrypt=1 ;;
+    --output) output=\"$2\"; shift ;;
+    --) input=\"$2\"; shift ;;
+  esac
+  shift
+done
+if [ -n \"$decrypt\" ]; then
+  sed 1d
+else
+  { echo '-----BEGIN PGP MESSAGE-----'; cat \"$input\"; } > \"$output\"
+fi
+";
+
+/// A stand-in for curl that serves every URL from the www directory,
+/// keyed by its last path component.
+const CURL: &str = "#!/bin/sh
+while [ $# -gt 0 ]; do
+  case \"$1\" in
+    --output) output=\"$2\"; shift;;
+    http*) url=\"$1\";;
+  esac
+  shift
+done
+file=\"$HOME/www/${url##*/}\"
+[ -f \"$file\" ] || { echo \"curl: (22) 404 Not Found\" >&2; exit 22; }
+if [ -n \"$output\" ]; then cp \"$file\" \"$output\"; else cat \"$file\"; fi
+";
+
+/// The state of the virtual device with an HOTP slot programmed with
+/// the secret from RFC 4226 and a password safe entry.
+const STATE: &str = r#"{
+  "hotp_slots": [{"name": "rfc", "secret": "3132333435363738393031323334353637383930"}],
+  "pws_slots": [{"name": "mail", "login": "me", "password": "hunter2"}]
+}"#;
+
+static COUNTER: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+/// The result of running nitrocli.
+#[derive(Debug)]
+struct Output {
+  code: i32,
+  stdout: String,
+  stderr: String,
+}
+
+/// An environment for running nitrocli against a virtual device.
+#[derive(Debug)]
+struct Nitrocli {
+  dir: path::PathBuf,
+}
+
+impl Nitrocli {
+  /// Create an environment with a virtual device in its default state.
+  fn new() -> Self {
+    let count = COUNTER.fetch_add(1, atomic::Ordering::SeqCst);
+    let name = format!("nitrocli-test-{}-{}", process::id(), count);
+    let dir = env::temp_dir().join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("bin")).unwrap();
+
+    let agent = dir.join("bin").join("gpg-connect-agent");
+    fs::write(&agent, AGENT).unwrap();
+    fs::set_permissions(&agent, fs::Permissions::from_mode(0o755)).unwrap();
+    Nitrocli { dir }
+  }
+
+  /// Create an environment with a virtual device in the given state.
+  fn with_state(state: &str) -> Self {
+    let nitrocli = Self::new();
+    fs::write(nitrocli.device(), state).unwrap();
+    nitrocli
+  }
+
+  /// Retrieve the path of the file storing the state of the device.
+  fn device(&self) -> path::PathBuf {
+    self.dir.join("device.json")
+  }
+
+  /// Read the state of the device.
+  fn state(&self) -> String {
+    fs::read_to_string(self.device()).unwrap()
+  }
+
+  /// Retrieve the path of the file storing the state of the additional
+  /// device with the given index, starting at one.
+  fn extra_device(&self, index: usize) -> path::PathBuf {
+    self.dir.join(format!("device-{}.json", index))
+  }
+
+  /// Connect another virtual device in the given state and return the
+  /// path of the file storing it.
+  fn add_device(&self, state: &str) -> path::PathBuf {
+    let path = (1..)
+      .map(|index| self.extra_device(index))
+      .find(|path| !path.exists())
+      .unwrap();
+    fs::write(&path, state).unwrap();
+    path
+  }
+
+  /// Create a command running nitrocli with the given arguments.
+  fn command(&self, args: &[&str]) -> process::Command {
+    // Integration tests are located in the deps directory next to the
+    // binary.
+    let exe = env::current_exe().unwrap();
+    let binary = exe.parent().unwrap().parent().unwrap().join("nitrocli");
+
+    let path = match env::var_os("PATH") {
+      Some(path) => {
+        let mut paths = vec![self.dir.join("bin")];
+        paths.extend(env::split_paths(&path));
+        env::join_paths(paths).unwrap()
+      }
+      None => self.dir.join("bin").into_os_string(),
+    };
+
+    let mut devices = vec![self.device()];
+    devices.extend(
+      (1..)
+        .map(|index| self.extra_device(index))
+        .take_while(|path| path.exists()),
+    );
+    let devices = env::join_paths(devices).unwrap();
+
+    let mut command = process::Command::new(binary);
+    let _ = command
+      .args(args)
+      .env_clear()
+      .env("PATH", path)
+      .env("HOME", &self.dir)
+      .env("NITROCLI_VIRTUAL_DEVICE", devices)
+      .stdin(process::Stdio::null());
+    for name in &[
+      "XDG_CACHE_HOME",
+      "XDG_CONFIG_HOME",
+      "XDG_RUNTIME_DIR",
+      "XDG_STATE_HOME",
+    ] {
+      let _ = command.env(name, self.dir.join(name.to_lowercase()));
+    }
+    // Builds against a libnitrokey in a custom location may need it.
+    if let Some(path) = env::var_os("LD_LIBRARY_PATH") {
+      let _ = command.env("LD_LIBRARY_PATH", path);
+    }
+    command
+  }
+
+  /// Run nitrocli with the given arguments and the given data on the
+  /// standard input.
+  fn run_with_input(&self, args: &[&str], input: &[u8]) -> Output {
+    let mut child = self
+      .command(args)
+      .stdin(process::Stdio::piped())
+      .stdout(process::Stdio::piped())
+      .stderr(process::Stdio::piped())
+      .spawn()
+      .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    Output {
+      code: output.status.code().unwrap(),
+      stdout: String::from_utf8(output.stdout).unwrap(),
+      stderr: String::from_utf8(output.stderr).unwrap(),
+    }
+  }
+
+  /// Run nitrocli with the given arguments.
+  fn run(&self, args: &[&str]) -> Output {
+    self.run_with_input(args, b"")
+  }
+
+  /// Run nitrocli with the given arguments, expecting it to succeed,
+  /// and return its output.
+  fn ok(&self, args: &[&str]) -> String {
+    let output = self.run(args);
+    assert_eq!(output.code, 0, "{:?}", output);
+    assert_eq!(output.stderr, "", "{:?}", output);
+    output.stdout
+  }
+
+  /// Run nitrocli with the given arguments, expecting it to fail, and
+  /// return the error message.
+  fn err(&self, args: &[&str]) -> String {
+    let output = self.run(args);
+    assert_eq!(output.code, 1, "{:?}", output);
+    assert_eq!(output.stderr, "", "{:?}", output);
+    output.stdout
+  }
+}
+
+impl Drop for Nitrocli {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(&self.dir);
+  }
+}
+
+#[test]
+fn usage() {
+  let nitrocli = Nitrocli::new();
+  let usage = nitrocli.ok(&["--help"]);
+  // The usage refers to the program by the path it was invoked with.
+  assert!(usage.starts_with("Usage: "));
+  assert!(usage.contains("nitrocli [<options>] <command> [<args>]\n"));
+  assert!(usage.contains("  status  Print the status of the Nitrokey device\n"));
+
+  let version = nitrocli.ok(&["--version"]);
+  assert_eq!(version, format!("nitrocli {}\n", env!("CARGO_PKG_VERSION")));
+  assert_eq!(nitrocli.ok(&["-V"]), version);
+}
+
+#[test]
+fn command_usage() {
+  let nitrocli = Nitrocli::new();
+  assert_eq!(
+    nitrocli.ok(&["status", "--help"]),
+    "Usage: nitrocli status [<options>]\n\nOptions:\n      --only FIELDS           Only query the given comma-separated parts (volumes|sd|firmware|retries)\n      --all-devices           Print the status of every connected Nitrokey Storage\n\nExamples:\n  Print the status of the device with a given serial number:\n    $ nitrocli --serial-number 0x1234abcd status\n  Print only the state of the volumes:\n    $ nitrocli status --only volumes\n  Print the status of every connected Nitrokey Storage:\n    $ nitrocli status --all-devices\n"
+  );
+  assert!(nitrocli
+    .ok(&["otp", "get", "--help"])
+    .ends_with("\n\nExamples:\n  Generate a TOTP using the slot named mail:\n    $ nitrocli otp get mail\n  Copy an eight-digit HOTP of slot 1 to the clipboard:\n    $ nitrocli otp get --hotp --digits 8 --clip 1\n  Print the TOTPs of slot 1 and all named TOTP slots:\n    $ nitrocli otp get 1 --all-totp\n  Enroll a new account, asking for its settings:\n    $ nitrocli otp set --interactive\n"));
+  assert!(nitrocli
+    .ok(&["pin", "-h"])
+    .starts_with("Usage: nitrocli pin {set} [<options>] <admin|user>\n"));
+  assert!(nitrocli
+    .ok(&["agent", "--help"])
+    .starts_with("Usage: nitrocli agent [<options>]\n"));
+  assert!(nitrocli
+    .ok(&["monitor", "--help"])
+    .starts_with("Usage: nitrocli monitor [<options>]\n"));
+}
+
+#[test]
+fn help() {
+  let nitrocli = Nitrocli::new();
+  assert!(nitrocli.ok(&["help"]).starts_with("Usage: nitrocli "));
+  assert_eq!(
+    nitrocli.ok(&["help", "pin"]),
+    nitrocli.ok(&["pin", "--help"])
+  );
+  assert!(nitrocli
+    .ok(&["help", "exit-codes"])
+    .starts_with("Exit statuses:\n"));
+  assert!(nitrocli
+    .ok(&["help", "pinen"])
+    .starts_with("PINs are queried"));
+  assert_eq!(
+    nitrocli.err(&["help", "bogus"]),
+    "Invalid command or topic: bogus\nAvailable topics: exit-codes pinentry\n"
+  );
+}
+
+#[test]
+fn invalid_arguments() {
+  let nitrocli = Nitrocli::new();
+  let error = nitrocli.err(&["bogus"]);
+  assert!(error.starts_with("Invalid command: bogus\n"));
+  let error = nitrocli.err(&["statsu"]);
+  assert!(error.starts_with("Invalid command: statsu, did you mean status?\n"));
+  assert_eq!(
+    nitrocli.err(&["clear", "extra"]),
+    "Command clear does not accept arguments\n"
+  );
+  assert_eq!(
+    nitrocli.err(&["pin", "set", "foo"]),
+    "Invalid PIN type: foo\n"
+  );
+  assert_eq!(
+    nitrocli.err(&["otp", "get", "-a", "xyz", "1"]),
+    "Invalid OTP algorithm: xyz (expected one of hotp, totp)\n"
+  );
+  assert_eq!(
+    nitrocli.err(&["otp", "get", "-d", "7", "1"]),
+    "Invalid number of digits: 7 (expected 6 or 8)\n"
+  );
+  assert_eq!(
+    nitrocli.err(&["setup", "udev", "--check", "--print"]),
+    "The --check and --print options are mutually exclusive\n"
+  );
+  assert_eq!(
+    nitrocli.err(&["agent", "--metrics-interval", "18446744073709551615"]),
+    "Invalid metrics interval: 18446744073709551615 (expected 1 to 86400)\n"
+  );
+  assert_eq!(
+    nitrocli.err(&["monitor", "--interval", "18446744073709551615"]),
+    "Invalid interval: 18446744073709551615 (expected 1 to 86400)\n"
+  );
+}
+
+#[test]
+fn abbreviations() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  assert!(nitrocli.ok(&["stat"]).starts_with("Status:\n"));
+  assert_eq!(nitrocli.ok(&["ot", "g", "--hotp", "rfc"]), "755224\n");
+  assert_eq!(
+    nitrocli.err(&["o"]),
+    "Ambiguous command: o\nCandidates: open otp\n"
+  );
+  assert!(nitrocli
+    .err(&["pass", "x"])
+    .starts_with("Usage: nitrocli pass"));
+}
+
+#[test]
+fn status() {
+  let nitrocli = Nitrocli::new();
+  let status = nitrocli.ok(&["status"]);
+  assert!(status.starts_with("Status:\n"));
+  assert!(status.contains("  user retry count:  3\n"));
+  assert!(status.contains("  admin retry count: 3\n"));
+  assert!(status.contains("    encrypted:       inactive\n"));
+}
+
+#[test]
+fn status_only() {
+  let nitrocli = Nitrocli::new();
+  assert_eq!(
+    nitrocli.ok(&["status", "--only", "retries"]),
+    "Status:\n  user retry count:  3\n  admin retry count: 3\n"
+  );
+  let status = nitrocli.ok(&["status", "--only", "volumes,retries"]);
+  assert!(status.starts_with("Status:\n  user retry count:  3\n"));
+  assert!(status.ends_with("    hidden:          inactive\n"));
+  assert!(!status.contains("firmware"));
+  assert_eq!(
+    nitrocli.err(&["status", "--only", "pins"]),
+    "Invalid status field: pins (expected one of volumes, sd, firmware, retries)\n"
+  );
+}
+
+#[test]
+fn status_factory_state() {
+  let nitrocli = Nitrocli::with_state(r#"{"aes_key_built": false}"#);
+  let output = nitrocli.run(&["status", "--only", "sd"]);
+  assert_eq!(output.code, 0, "{:?}", output);
+  assert!(output.stdout.contains("  storage keys:      not created\n"));
+  assert!(output.stderr.contains("default user PIN (123456)"));
+  assert!(output.stderr.contains("nitrocli setup device"));
+  // Querying the retry counts does not read the status of the storage.
+  assert!(!nitrocli.ok(&["status", "--only", "retries"]).is_empty());
+}
+
+#[test]
+fn status_without_device() {
+  let nitrocli = Nitrocli::new();
+  let output = nitrocli
+    .command(&["status"])
+    .env_remove("NITROCLI_VIRTUAL_DEVICE")
+    .output()
+    .unwrap();
+  assert_eq!(output.status.code(), Some(1));
+  assert_eq!(output.stderr, b"");
+  assert_eq!(output.stdout, b"Nitrokey device not found\n");
+}
+
+#[test]
+fn update_enter_leave() {
+  let nitrocli = Nitrocli::new();
+  let output = nitrocli.run_with_input(&["update", "enter"], b"wrong\n");
+  assert_eq!(output.code, 1);
+  assert_eq!(output.stdout, "Wrong firmware password\n");
+  assert_eq!(
+    nitrocli.ok(&["--dry-run", "update", "enter"]),
+    "Would put the device into update mode\n"
+  );
+
+  let output = nitrocli.run_with_input(&["update", "enter"], b"12345678\n");
+  assert_eq!(output.code, 0, "{:?}", output);
+  assert_eq!(nitrocli.err(&["status"]), "Nitrokey device not found\n");
+
+  let dfu_programmer = nitrocli.dir.join("bin").join("dfu-programmer");
+  fs::write(
+    &dfu_programmer,
+    "#!/bin/sh\necho \"$*\" >> \"$HOME/dfu.log\"\n\
+     sed -i 's/\"update_mode\":true/\"update_mode\":false/' \"$NITROCLI_VIRTUAL_DEVICE\"\n",
+  )
+  .unwrap();
+  fs::set_permissions(&dfu_programmer, fs::Permissions::from_mode(0o755)).unwrap();
+  assert_eq!(nitrocli.ok(&["update", "leave"]), "");
+  let log = fs::read_to_string(nitrocli.dir.join("dfu.log")).unwrap();
+  assert_eq!(log, "at32uc3a3256s launch\n");
+  assert!(nitrocli.ok(&["status"]).starts_with("Status:\n"));
+
+  let nitrocli = Nitrocli::with_state(r#"{"model": "pro"}"#);
+  assert_eq!(
+    nitrocli.err(&["update", "enter"]),
+    "The Nitrokey Pro has no update mode that can be entered by a command\n"
+  );
+}
+
+#[test]
+fn update_download() {
+  let nitrocli = Nitrocli::new();
+  let www = nitrocli.dir.join("www");
+  fs::create_dir(&www).unwrap();
+  let base = "https://github.com/Nitrokey/nitrokey-storage-firmware/releases/download/V0.57";
+  let releases = format!(
+    r#"[{{"tag_name": "V0.57", "draft": false, "prerelease": false, "assets": [
+        {{"name": "storage.hex", "browser_download_url": "{0}/storage.hex"}},
+        {{"name": "storage.hex.asc", "browser_download_url": "{0}/storage.hex.asc"}},
+        {{"name": "storage.hex.sha256", "browser_download_url": "{0}/storage.hex.sha256"}}
+      ]}}]"#,
+    base
+  );
+  fs::write(www.join("releases"), &releases).unwrap();
+  fs::write(www.join("storage.hex"), ":00000001FF\n").unwrap();
+  fs::write(
+    www.join("storage.hex.sha256"),
+    "9e2df0a1190a1205c098889c455e5b76c4df18b5ccac2b7605da1575f05b64c5  storage.hex\n",
+  )
+  .unwrap();
+
+  let curl = nitrocli.dir.join("bin").join("curl");
+  fs::write(&curl, CURL).unwrap();
+  fs::set_permissions(&curl, fs::Permissions::from_mode(0o755)).unwrap();
+
+  // The signature merely contains the fingerprint of its key.
+  fs::write(www.join("storage.hex.asc"), "AAAA1111\n").unwrap();
+  let gpg = nitrocli.dir.join("bin").join("gpg");
+  fs::write(
+    &gpg,
+    "#!/bin/sh
+while [ \"$1\" != -- ]; do shift; done
+     echo \"[GNUPG:] VALIDSIG $(cat \"$2\") 2019-01-01 1546300800 0 4 0 1 10 00 $(cat \"$2\")\"\n",
+  )
+  .unwrap();
+  fs::set_permissions(&gpg, fs::Permissions::from_mode(0o755)).unwrap();
+
+  let image = nitrocli
+    .dir
+    .join("xdg_cache_home")
+    .join("nitrocli")
+    .join("firmware")
+    .join("storage.hex");
+  assert_eq!(
+    nitrocli.err(&["update", "download"]),
+    "No keys trusted to sign firmware images are configured in update.signing-keys\n"
+  );
+
+  let dir = nitrocli.dir.join("xdg_config_home/nitrocli");
+  fs::create_dir_all(&dir).unwrap();
+  let config = dir.join("config.toml");
+  fs::write(&config, "[update]\nsigning-keys = [\"BBBB2222\"]\n").unwrap();
+  assert!(nitrocli
+    .err(&["update", "download"])
+    .ends_with(" was not made by a trusted key\n"));
+  assert!(!image.exists());
+
+  fs::write(&config, "[update]\nsigning-keys = [\"aaaa 1111\"]\n").unwrap();
+  assert_eq!(
+    nitrocli.ok(&["update", "download"]),
+    format!(
+      "Downloaded firmware version 0.57 for the Nitrokey Storage to {}\n",
+      image.display()
+    )
+  );
+  assert_eq!(fs::read_to_string(&image).unwrap(), ":00000001FF\n");
+
+  assert_eq!(
+    nitrocli.err(&["update", "download", "--version", "0.58"]),
+    "Firmware version 0.58 not found\n"
+  );
+
+  fs::remove_file(&image).unwrap();
+  fs::write(www.join("storage.hex"), ":00000000FF\n").unwrap();
+  assert_eq!(
+    nitrocli.err(&["update", "download", "--version", "0.57"]),
+    "Could not verify storage.hex: The checksum of storage.hex does not match \
+     storage.hex.sha256\n"
+  );
+  assert!(!image.exists());
+
+  let unsigned = releases
+    .lines()
+    .filter(|line| !line.contains(".asc"))
+    .collect::<Vec<_>>()
+    .join("\n");
+  fs::write(www.join("releases"), unsigned).unwrap();
+  assert_eq!(
+    nitrocli.err(&["update", "download"]),
+    "Could not verify storage.hex: Firmware version 0.57 provides no signature of storage.hex\n"
+  );
+}
+
+#[test]
+fn update_export_verify() {
+  let nitrocli = Nitrocli::new();
+  assert_eq!(
+    nitrocli.ok(&["update", "export"]),
+    "Exported the firmware to the unencrypted volume\n"
+  );
+
+  let www = nitrocli.dir.join("www");
+  fs::create_dir(&www).unwrap();
+  let base = "https://github.com/Nitrokey/nitrokey-storage-firmware/releases/download";
+  let releases = format!(
+    r#"[{{"tag_name": "V0.53", "draft": false, "prerelease": false, "assets": [
+        {{"name": "storage.bin", "browser_download_url": "{0}/V0.53/storage.bin"}},
+        {{"name": "SHA256SUMS", "browser_download_url": "{0}/V0.53/SHA256SUMS"}}
+      ]}}, {{"tag_name": "V0.52", "draft": false, "prerelease": false, "assets": [
+        {{"name": "storage.bin", "browser_download_url": "{0}/V0.52/storage.bin"}}
+      ]}}]"#,
+    base
+  );
+  fs::write(www.join("releases"), &releases).unwrap();
+  fs::write(
+    www.join("SHA256SUMS"),
+    "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  storage.bin\n",
+  )
+  .unwrap();
+  let curl = nitrocli.dir.join("bin").join("curl");
+  fs::write(&curl, CURL).unwrap();
+  fs::set_permissions(&curl, fs::Permissions::from_mode(0o755)).unwrap();
+
+  // The virtual device runs firmware version 0.53.
+  let image = nitrocli.dir.join("firmware.bin");
+  fs::write(&image, "abc").unwrap();
+  assert_eq!(
+    nitrocli.ok(&["update", "verify", image.to_str().unwrap()]),
+    format!(
+      "Verified: {} matches storage.bin of firmware version 0.53 as listed in SHA256SUMS\n",
+      image.display()
+    )
+  );
+
+  fs::write(&image, "abd").unwrap();
+  let error = nitrocli.err(&["update", "verify", image.to_str().unwrap()]);
+  assert!(
+    error.starts_with(&format!(
+      "Not verified: The SHA-256 checksum of {} is ",
+      image.display()
+    )),
+    "{}",
+    error
+  );
+  assert_eq!(
+    nitrocli.err(&[
+      "update",
+      "verify",
+      "--version",
+      "0.52",
+      image.to_str().unwrap()
+    ]),
+    "Not verified: Firmware version 0.52 publishes no checksum of storage.bin\n"
+  );
+}
+
+#[test]
+fn update_flash() {
+  let nitrocli = Nitrocli::new();
+  let image = nitrocli.dir.join("storage.hex");
+  fs::write(&image, ":00000001FF\n").unwrap();
+  assert_eq!(
+    nitrocli.ok(&["--dry-run", "update", "flash", image.to_str().unwrap()]),
+    format!("Would flash {}\n", image.display())
+  );
+  assert_eq!(
+    nitrocli.err(&["update", "flash", "missing.hex"]),
+    "Could not read missing.hex: IO error: No such file or directory (os error 2)\n"
+  );
+
+  // Launching the firmware makes the device leave update mode with the
+  // version of the flashed image.
+  let dfu_programmer = nitrocli.dir.join("bin").join("dfu-programmer");
+  fs::write(
+    &dfu_programmer,
+    "#!/bin/sh\necho \"$*\" >> \"$HOME/dfu.log\"\n\
+     [ \"$2\" = launch ] && sed -i \
+     -e 's/\"update_mode\":true/\"update_mode\":false/' \
+     -e 's/\"firmware_version\":[0-9]*/\"firmware_version\":57/' \
+     \"$NITROCLI_VIRTUAL_DEVICE\"\n\
+     exit 0\n",
+  )
+  .unwrap();
+  fs::set_permissions(&dfu_programmer, fs::Permissions::from_mode(0o755)).unwrap();
+
+  let output = nitrocli.run_with_input(&["update", "enter"], b"12345678\n");
+  assert_eq!(output.code, 0, "{:?}", output);
+  let error = nitrocli.err(&["--no-prompt", "update", "flash", image.to_str().unwrap()]);
+  assert!(error.starts_with("Refusing to erase the firmware of the device in update mode"));
+  assert!(!nitrocli.dir.join("dfu.log").exists());
+
+  let args = [
+    "update",
+    "flash",
+    "--force",
+    "--version",
+    "0.57",
+    image.to_str().unwrap(),
+  ];
+  assert_eq!(
+    nitrocli.ok(&args),
+    format!(
+      "Erasing the firmware\nFlashing {}\nLaunching the new firmware\n\
+       Waiting for the device\nThe device runs firmware version 0.57\n",
+      image.display()
+    )
+  );
+  let log = fs::read_to_string(nitrocli.dir.join("dfu.log")).unwrap();
+  assert_eq!(
+    log,
+    format!(
+      "at32uc3a3256s erase\nat32uc3a3256s flash --suppress-bootloader-mem {}\n\
+       at32uc3a3256s launch\n",
+      image.display()
+    )
+  );
+  assert!(nitrocli
+    .ok(&["status"])
+    .contains("firmware version:  0.57\n"));
+
+  let output = nitrocli.run_with_input(&["update", "enter"], b"12345678\n");
+  assert_eq!(output.code, 0, "{:?}", output);
+  let args = [
+    "update",
+    "flash",
+    "--force",
+    "--version",
+    "v0.58",
+    image.to_str().unwrap(),
+  ];
+  let output = nitrocli.run(&args);
+  assert_eq!(output.code, 1);
+  assert!(output
+    .stdout
+    .ends_with("The device came back with firmware version 0.57 instead of 0.58\n"));
+}
+
+#[test]
+fn device_names() {
+  let nitrocli = Nitrocli::new();
+  assert_eq!(
+    nitrocli.ok(&["device", "name", "0x0000c0de", "backup-stick"]),
+    ""
+  );
+  assert_eq!(
+    nitrocli.ok(&["device", "list"]),
+    "backup-stick: 0x0000c0de\n"
+  );
+  assert!(nitrocli
+    .ok(&["--serial-number", "backup-stick", "status"])
+    .starts_with("Status:\n  name:              backup-stick\n  SD card ID:"));
+  assert!(nitrocli
+    .ok(&["status", "--only", "retries"])
+    .starts_with("Status:\n  name:              backup-stick\n"));
+  assert_eq!(
+    nitrocli.ok(&["completions", "--list", "serial-numbers"]),
+    "backup-stick\n"
+  );
+
+  assert_eq!(
+    nitrocli.err(&["device", "name", "0x1", "backup-stick"]),
+    "The name backup-stick is used for the device 0x0000c0de already\n"
+  );
+  assert_eq!(
+    nitrocli.err(&["device", "name", "0x1", "backup stick"]),
+    "Invalid device name: 'backup stick' (names must not be empty or contain whitespace)\n"
+  );
+  assert_eq!(
+    nitrocli.ok(&["device", "name", "backup-stick", "spare"]),
+    ""
+  );
+  assert_eq!(nitrocli.ok(&["device", "list"]), "spare: 0x0000c0de\n");
+  assert!(nitrocli
+    .err(&["--serial-number", "backup-stick", "status"])
+    .starts_with("Nitrokey device with serial number 0000c0de does not match"));
+
+  assert_eq!(nitrocli.ok(&["device", "unname", "spare"]), "");
+  assert_eq!(nitrocli.ok(&["device", "list"]), "");
+  assert_eq!(
+    nitrocli.err(&["device", "unname", "spare"]),
+    "The device spare has no name\n"
+  );
+  assert!(nitrocli
+    .ok(&["status"])
+    .starts_with("Status:\n  SD card ID:"));
+}
+
+#[test]
+fn fleet_report() {
+  let nitrocli = Nitrocli::new();
+  let dir = nitrocli.dir.join("xdg_config_home/nitrocli");
+  fs::create_dir_all(&dir).unwrap();
+  fs::write(
+    dir.join("config.toml"),
+    "[device.work]\nserial-numbers = [\"0xabcd\"]\n",
+  )
+  .unwrap();
+  assert_eq!(nitrocli.ok(&["device", "name", "0x1234", "spare"]), "");
+
+  let report = nitrocli.ok(&["fleet", "report"]);
+  assert!(report.starts_with(
+    "[{\"admin_retry_count\":null,\"connected\":false,\"firmware\":null,\
+     \"last_seen\":null,\"model\":null,\"name\":\"spare\",\"serial\":\"0x1234\",\
+     \"user_retry_count\":null},"
+  ));
+  assert!(report.contains(
+    "{\"admin_retry_count\":3,\"connected\":true,\"firmware\":\"0.53\",\"last_seen\":\"20"
+  ));
+  assert!(report.ends_with(
+    "Z\",\"model\":\"storage\",\"name\":null,\"serial\":\"0000c0de\",\"user_retry_count\":3}]\n"
+  ));
+
+  // A device that is not connected is reported with the facts recorded
+  // when it was last seen.
+  let report = nitrocli.ok(&[
+    "--serial-number",
+    "0x1",
+    "fleet",
+    "report",
+    "--format",
+    "csv",
+  ]);
+  let lines = report.lines().collect::<Vec<_>>();
+  assert_eq!(lines.len(), 5, "{}", report);
+  assert_eq!(
+    lines[0],
+    "serial,name,model,firmware,user_retry_count,admin_retry_count,last_seen,connected"
+  );
+  assert_eq!(lines[1], "0x1,,,,,,,false");
+  assert_eq!(lines[2], "0x1234,spare,,,,,,false");
+  assert_eq!(lines[3], "0xabcd,,,,,,,false");
+  assert!(lines[4].starts_with("0000c0de,,storage,0.53,3,3,20"));
+  assert!(lines[4].ends_with("Z,false"));
+
+  assert_eq!(
+    nitrocli.err(&["fleet", "report", "--format", "xml"]),
+    "Invalid report format: xml (expected csv or json)\n"
+  );
+}
+
+#[test]
+fn provision_apply() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  let profile = nitrocli.dir.join("profile.toml");
+  fs::write(
+    &profile,
+    r#"admin-pin = "12345678"
+user-pin = "123456"
+
+[config]
+numlock = 0
+
+[otp.totp.1]
+name = "github"
+secret = "3132"
+
+[pws.0]
+state = "absent"
+
+[pws.1]
+name = "wiki"
+login = "me"
+password = "secret"
+"#,
+  )
+  .unwrap();
+  let profile = profile.to_str().unwrap();
+
+  assert_eq!(
+    nitrocli.ok(&["--dry-run", "provision", "apply", profile]),
+    "Would erase password safe slot 0\n\
+     Would write password safe slot 1\n\
+     Would write totp slot 1\n\
+     Would write configuration\n"
+  );
+  assert!(nitrocli.state().contains(r#""name":"mail""#));
+
+  let output = nitrocli.ok(&["provision", "apply", profile]);
+  assert_eq!(output.lines().count(), 4, "{}", output);
+  assert!(output.starts_with("Applied: erase password safe slot 0\n"));
+  let state = nitrocli.state();
+  assert!(
+    state.contains(r#""pws_slots":[null,{"login":"me","name":"wiki""#),
+    "{}",
+    state
+  );
+  assert!(state.contains(r#""name":"github""#), "{}", state);
+  assert!(state.contains(r#""config":[0,"#), "{}", state);
+
+  assert_eq!(
+    nitrocli.ok(&["provision", "apply", profile]),
+    "The device is in the desired state\n"
+  );
+
+  fs::write(
+    nitrocli.dir.join("profile.toml"),
+    "[pws.1]\nemail = \"me\"\n",
+  )
+  .unwrap();
+  let error = nitrocli.err(&["provision", "apply", profile]);
+  assert!(
+    error.ends_with("Unsupported profile key: pws.1.email\n"),
+    "{}",
+    error
+  );
+}
+
+#[test]
+fn provision_verify() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  let profile = nitrocli.dir.join("profile.toml");
+  fs::write(
+    &profile,
+    r#"user-pin = "123456"
+encrypted-volume = "closed"
+
+[config]
+numlock = 0
+
+[otp.hotp.0]
+name = "rfc"
+secret = "3132"
+
+[otp.totp.1]
+name = "github"
+secret = "3132"
+
+[pws.0]
+name = "mail"
+login = "you"
+password = "hunter2"
+
+[pws.1]
+state = "absent"
+"#,
+  )
+  .unwrap();
+  let profile = profile.to_str().unwrap();
+
+  let output = nitrocli.run(&["provision", "verify", profile]);
+  assert_eq!(output.code, 1, "{:?}", output);
+  assert!(
+    output.stdout.starts_with(
+      "config.numlock: disabled, expected HOTP slot 0\n\
+       totp slot 1: missing, expected github\n\
+       password safe slot 0: login me, expected you\n"
+    ),
+    "{:?}",
+    output
+  );
+
+  let _ = nitrocli.ok(&["provision", "apply", profile]);
+  assert_eq!(
+    nitrocli.ok(&["provision", "verify", profile]),
+    "The device matches the profile\n"
+  );
+}
+
+#[test]
+fn all_devices() {
+  let nitrocli = Nitrocli::new();
+  let second = nitrocli.add_device(r#"{"serial_number": "0000beef"}"#);
+  let _ = nitrocli.add_device(r#"{"model": "pro", "serial_number": "0000f00d"}"#);
+
+  let output = nitrocli.ok(&["status", "--all-devices", "--only", "retries"]);
+  assert!(output.starts_with("Device 0000c0de:\n"), "{}", output);
+  assert!(output.contains("\nDevice 0000beef:\n"), "{}", output);
+  assert!(!output.contains("0000f00d"), "{}", output);
+  assert!(
+    output.ends_with("Succeeded on all 2 devices\n"),
+    "{}",
+    output
+  );
+
+  let profile = nitrocli.dir.join("profile.toml");
+  fs::write(&profile, "encrypted-volume = \"open\"\n").unwrap();
+  let profile = profile.to_str().unwrap();
+  fs::write(
+    &second,
+    r#"{"serial_number": "0000beef", "user_pin": "654321"}"#,
+  )
+  .unwrap();
+  let output = nitrocli.run_with_input(&["provision", "apply", "--all-devices", profile], b"");
+  assert_eq!(output.code, 1, "{:?}", output);
+  assert!(
+    output
+      .stdout
+      .contains("Device 0000c0de:\nApplied: open encrypted volume\n"),
+    "{:?}",
+    output
+  );
+  assert!(
+    output
+      .stdout
+      .ends_with("Failed on 1 of 2 devices: 0000beef\n"),
+    "{:?}",
+    output
+  );
+  assert!(nitrocli
+    .state()
+    .contains("\"encrypted_volume_active\":true"));
+
+  let output = nitrocli.ok(&[
+    "--serial-number",
+    "0xbeef",
+    "status",
+    "--all-devices",
+    "--only",
+    "volumes",
+  ]);
+  assert!(output.starts_with("Device 0000beef:\n"), "{}", output);
+  assert!(
+    output.ends_with("Succeeded on all 1 devices\n"),
+    "{}",
+    output
+  );
+
+  // A device that does not respond is reported without stopping the
+  // others.
+  fs::write(
+    &second,
+    r#"{"serial_number": "0000beef", "unresponsive": true}"#,
+  )
+  .unwrap();
+  let output = nitrocli.run(&["status", "--all-devices", "--only", "retries"]);
+  assert_eq!(output.code, 1, "{:?}", output);
+  assert!(
+    output.stdout.starts_with("Device 0000c0de:\n"),
+    "{:?}",
+    output
+  );
+  assert!(
+    output
+      .stdout
+      .ends_with("Failed on 1 of 2 devices: 0000beef:00000000_p_0001:0002:02\n"),
+    "{:?}",
+    output
+  );
+}
+
+#[test]
+fn close_all_devices() {
+  let nitrocli = Nitrocli::with_state(r#"{"encrypted_volume_active": true}"#);
+  let second =
+    nitrocli.add_device(r#"{"serial_number": "0000beef", "encrypted_volume_active": true}"#);
+
+  let output = nitrocli.ok(&["close", "--all-devices"]);
+  assert!(output.starts_with("Device 0000c0de:\n"), "{}", output);
+  assert!(
+    output.ends_with("Succeeded on all 2 devices\n"),
+    "{}",
+    output
+  );
+  assert!(nitrocli
+    .state()
+    .contains("\"encrypted_volume_active\":false"));
+  let state = fs::read_to_string(second).unwrap();
+  assert!(
+    state.contains("\"encrypted_volume_active\":false"),
+    "{}",
+    state
+  );
+}
+
+#[test]
+fn agent_selector() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  let dir = nitrocli.dir.join("xdg_runtime_dir").join("nitrocli");
+  fs::create_dir_all(&dir).unwrap();
+  // An agent that answers every request with the same password.
+  let listener = UnixListener::bind(dir.join("agent.socket")).unwrap();
+  let _ = thread::spawn(move || {
+    for stream in listener.incoming() {
+      let stream = stream.unwrap();
+      let mut line = String::new();
+      let _ = io::BufReader::new(&stream).read_line(&mut line).unwrap();
+      (&stream).write_all(b"ok 999999\n").unwrap();
+    }
+  });
+
+  assert_eq!(nitrocli.ok(&["otp", "get", "--hotp", "rfc"]), "999999\n");
+  assert_eq!(
+    nitrocli.ok(&["--serial-number", "0000c0de", "otp", "get", "--hotp", "rfc"]),
+    "755224\n"
+  );
+  assert_eq!(
+    nitrocli.ok(&["--model", "storage", "otp", "get", "--hotp", "rfc"]),
+    "287082\n"
+  );
+}
+
+#[test]
+fn wrong_pin() {
+  let nitrocli = Nitrocli::with_state(r#"{"user_pin": "654321"}"#);
+  let err = nitrocli.err(&["open"]);
+  assert!(
+    err.ends_with(": Wrong password, 1 attempt left\n"),
+    "{}",
+    err
+  );
+  assert!(nitrocli.state().contains("\"user_retry_count\":1"));
+}
+
+#[test]
+fn pin_prompt_attempts() {
+  let nitrocli = Nitrocli::new();
+  assert_eq!(nitrocli.ok(&["open"]), "");
+  let log = fs::read_to_string(nitrocli.dir.join("agent.log")).unwrap();
+  assert!(log.contains("3+attempts+left"), "{}", log);
+
+  let nitrocli = Nitrocli::with_state(r#"{"user_retry_count": 1}"#);
+  assert_eq!(nitrocli.ok(&["open"]), "");
+  let log = fs::read_to_string(nitrocli.dir.join("agent.log")).unwrap();
+  assert!(
+    log.contains("Last+attempt,+a+wrong+PIN+blocks+the+user+PIN"),
+    "{}",
+    log
+  );
+}
+
+#[test]
+fn open_close() {
+  let nitrocli = Nitrocli::new();
+  assert_eq!(nitrocli.ok(&["open"]), "");
+  assert!(nitrocli
+    .state()
+    .contains("\"encrypted_volume_active\":true"));
+  assert!(nitrocli
+    .ok(&["status"])
+    .contains("    encrypted:       active\n"));
+
+  assert_eq!(nitrocli.ok(&["close"]), "");
+  assert!(nitrocli
+    .state()
+    .contains("\"encrypted_volume_active\":false"));
+}
+
+#[test]
+fn close_on_lock() {
+  let nitrocli = Nitrocli::new();
+  assert_eq!(nitrocli.ok(&["open"]), "");
+  let monitor = nitrocli.dir.join("bin").join("dbus-monitor");
+  let script = "#!/bin/sh
+if [ \"$1\" = --system ]; then
+  echo 'signal time=1545000012.4 sender=:1.3 -> destination=(null destination) serial=2210 \
+path=/org/freedesktop/login1/session/_32; interface=org.freedesktop.login1.Session; member=Lock'
+fi
+exec sleep 10
+";
+  fs::write(&monitor, script).unwrap();
+  fs::set_permissions(&monitor, fs::Permissions::from_mode(0o755)).unwrap();
+
+  let mut child = nitrocli
+    .command(&["monitor"])
+    .env("NITROCLI_SESSION_CLOSE_ON_LOCK", "true")
+    .spawn()
+    .unwrap();
+  let mut closed = false;
+  for _ in 0..100 {
+    closed = nitrocli
+      .state()
+      .contains("\"encrypted_volume_active\":false");
+    if closed {
+      break;
+    }
+    thread::sleep(time::Duration::from_millis(100));
+  }
+  child.kill().unwrap();
+  let _ = child.wait().unwrap();
+  assert!(closed);
+}
+
+#[test]
+fn attach_hook() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  let hook =
+    "echo \"$NITROCLI_MODEL $NITROCLI_SERIAL_NUMBERS\" > attached && \"$NITROCLI_BINARY\" open";
+  let mut child = nitrocli
+    .command(&["monitor"])
+    .current_dir(&nitrocli.dir)
+    .env("NITROCLI_HOOKS_ON_ATTACH", hook)
+    .spawn()
+    .unwrap();
+  let mut opened = false;
+  for _ in 0..100 {
+    opened = nitrocli
+      .state()
+      .contains("\"encrypted_volume_active\":true");
+    if opened {
+      break;
+    }
+    thread::sleep(time::Duration::from_millis(100));
+  }
+  child.kill().unwrap();
+  let _ = child.wait().unwrap();
+  assert!(opened);
+  let attached = fs::read_to_string(nitrocli.dir.join("attached")).unwrap();
+  assert_eq!(attached, "storage 0000c0de\n");
+}
+
+#[test]
+fn dry_run() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  assert_eq!(
+    nitrocli.ok(&["--dry-run", "open"]),
+    "Would open the encrypted volume\n"
+  );
+  assert!(nitrocli
+    .state()
+    .contains("\"encrypted_volume_active\":false"));
+
+  assert_eq!(
+    nitrocli.ok(&["--dry-run", "otp", "get", "--hotp", "rfc"]),
+    "Would generate a one-time password using HOTP slot 0 and increment its counter\n"
+  );
+  assert_eq!(nitrocli.ok(&["otp", "get", "--hotp", "rfc"]), "755224\n");
+}
+
+#[test]
+fn otp_get() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  assert_eq!(nitrocli.ok(&["otp", "get", "--hotp", "rfc"]), "755224\n");
+  assert_eq!(nitrocli.ok(&["otp", "get", "-a", "hotp", "0"]), "287082\n");
+  assert!(nitrocli.state().contains("\"counter\":2"));
+
+  assert_eq!(
+    nitrocli.err(&["otp", "get", "rfc"]),
+    "No totp slot with name rfc found\n"
+  );
+  assert_eq!(
+    nitrocli.err(&["otp", "get", "--hotp", "-d", "8", "rfc"]),
+    "The slot rfc generates OTPs with 6 digits instead of 8\n"
+  );
+}
+
+#[test]
+fn otp_get_validity() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  let secret = b"GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ\n";
+  let output = nitrocli.run_with_input(&["otp", "set", "0", "totp"], secret);
+  assert_eq!(output.code, 0, "{:?}", output);
+
+  let output = nitrocli.ok(&["otp", "get", "--fresh", "1", "--validity", "totp"]);
+  let lines = output.lines().collect::<Vec<_>>();
+  assert_eq!(lines.len(), 2, "{}", output);
+  assert_eq!(lines[0].len(), 6);
+  let validity = lines[1].trim_start_matches("validity: ");
+  let validity = validity.parse::<u64>().unwrap();
+  assert!(validity >= 1 && validity <= 30, "{}", output);
+
+  assert_eq!(
+    nitrocli.err(&["otp", "get", "--fresh", "30", "totp"]),
+    "Invalid number of seconds: 30 (expected less than 30)\n"
+  );
+  assert_eq!(
+    nitrocli.err(&["otp", "get", "--hotp", "--validity", "rfc"]),
+    "--at, --fresh, --offset, and --validity only apply to TOTP slots\n"
+  );
+}
+
+#[test]
+fn otp_get_many() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  assert_eq!(
+    nitrocli.ok(&["otp", "get", "--hotp", "rfc", "0"]),
+    "rfc  755224\n0    287082\n"
+  );
+  assert_eq!(
+    nitrocli.err(&["otp", "get", "--hotp", "--clip", "rfc", "0"]),
+    "Only the OTP of a single slot can be copied to the clipboard\n"
+  );
+
+  let secret = b"GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ\n";
+  let output = nitrocli.run_with_input(&["otp", "set", "1", "totp"], secret);
+  assert_eq!(output.code, 0, "{:?}", output);
+  let output = nitrocli.ok(&["otp", "get", "--all-totp"]);
+  assert!(output.starts_with("totp  "), "{}", output);
+  assert_eq!(output.len(), "totp  123456\n".len(), "{}", output);
+  let output = nitrocli.ok(&["otp", "get", "--all-totp", "1"]);
+  assert!(output.starts_with("1  "), "{}", output);
+  assert_eq!(output.lines().count(), 1, "{}", output);
+}
+
+#[test]
+fn pick() {
+  let nitrocli = Nitrocli::new();
+  assert_eq!(
+    nitrocli.err(&["otp", "pick"]),
+    "There is nothing to pick from\n"
+  );
+  assert_eq!(
+    nitrocli.err(&["pass", "pick", "--type", "--clip"]),
+    "--type cannot be combined with --clip or --force-show\n"
+  );
+}
+
+#[test]
+fn otp_set() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  let secret = b"GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ\n";
+  let output = nitrocli.run_with_input(&["otp", "set", "-a", "hotp", "1", "test"], secret);
+  assert_eq!(output.code, 0, "{:?}", output);
+  assert_eq!(nitrocli.ok(&["otp", "get", "--hotp", "test"]), "755224\n");
+
+  let output = nitrocli.run_with_input(&["otp", "set", "1", "test"], b"not base32!\n");
+  assert_eq!(output.code, 1);
+  assert_eq!(output.stdout, "The secret is not a valid base32 string\n");
+  assert_eq!(
+    nitrocli.err(&["otp", "set", "1", "a rather long name"]),
+    "The name must not be longer than 15 characters, but is 18\n"
+  );
+  assert_eq!(
+    nitrocli.err(&["otp", "set", "-a", "hotp", "3", "test"]),
+    "Invalid hotp slot: 3 (expected 0 to 2)\n"
+  );
+}
+
+#[test]
+fn pass_show_and_ls() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  assert_eq!(nitrocli.ok(&["pass", "ls"]), "Password Store\n└── mail\n");
+  assert_eq!(
+    nitrocli.ok(&["pass", "show", "mail"]),
+    "hunter2\nlogin: me\n"
+  );
+  assert_eq!(
+    nitrocli.err(&["pass", "show", "other"]),
+    "other is not in the password safe\n"
+  );
+}
+
+#[test]
+fn config_test_binding() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  assert_eq!(
+    nitrocli.err(&["config", "test-binding", "numlock"]),
+    "No HOTP slot is bound to numlock\n"
+  );
+  assert!(nitrocli
+    .err(&["config", "test-binding", "shift"])
+    .starts_with("Usage: nitrocli config"));
+}
+
+#[test]
+fn slot_aliases() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  assert_eq!(nitrocli.ok(&["alias", "add", "my-hotp", "otp/0"]), "");
+  assert_eq!(nitrocli.ok(&["alias", "add", "work", "pws/0"]), "");
+  assert_eq!(
+    nitrocli.ok(&["alias", "list"]),
+    "my-hotp: otp/0\nwork: pws/0\n"
+  );
+
+  assert_eq!(
+    nitrocli.ok(&["otp", "get", "--hotp", "my-hotp"]),
+    "755224\n"
+  );
+  assert_eq!(
+    nitrocli.ok(&["pass", "show", "work"]),
+    "hunter2\nlogin: me\n"
+  );
+
+  assert_eq!(nitrocli.ok(&["alias", "remove", "work"]), "");
+  assert_eq!(nitrocli.ok(&["alias", "list"]), "my-hotp: otp/0\n");
+  assert!(nitrocli
+    .err(&["pass", "show", "work"])
+    .contains("work is not in the password safe"));
+  assert!(nitrocli
+    .err(&["alias", "add", "bank", "otp"])
+    .contains("Invalid slot: otp (expected <otp|pws>/<number>[@<offset>])"));
+}
+
+#[test]
+fn otp_get_offset() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  let secret = b"GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ\n";
+  let output = nitrocli.run_with_input(&["otp", "set", "1", "totp"], secret);
+  assert_eq!(output.code, 0, "{:?}", output);
+  assert_eq!(nitrocli.ok(&["alias", "add", "ahead", "otp/1@+60"]), "");
+  assert_eq!(nitrocli.ok(&["alias", "list"]), "ahead: otp/1@+60\n");
+
+  // Leave enough time for all codes to be generated in the same
+  // window.
+  let current = nitrocli.ok(&["otp", "get", "--fresh", "5", "1"]);
+  let shifted = nitrocli.ok(&["otp", "get", "--offset", "60", "1"]);
+  assert_ne!(current, shifted);
+  assert_eq!(nitrocli.ok(&["otp", "get", "ahead"]), shifted);
+  assert_eq!(nitrocli.ok(&["otp", "get", "--offset=0", "ahead"]), current);
+  assert_eq!(
+    nitrocli.err(&["otp", "get", "--offset", "soon", "1"]),
+    "Invalid offset: soon\n"
+  );
+}
+
+#[test]
+fn otp_get_at() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  let secret = b"GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ\n";
+  let output = nitrocli.run_with_input(&["otp", "set", "1", "totp"], secret);
+  assert_eq!(output.code, 0, "{:?}", output);
+
+  // The test vectors of RFC 6238, truncated to six digits.
+  assert_eq!(nitrocli.ok(&["otp", "get", "--at", "59", "1"]), "287082\n");
+  let at = ["--at", "2009-02-13T23:31:30Z"];
+  assert_eq!(nitrocli.ok(&["otp", "get", at[0], at[1], "1"]), "005924\n");
+  let at = ["--at", "2009-02-14T00:31:30+01:00"];
+  assert_eq!(nitrocli.ok(&["otp", "get", at[0], at[1], "1"]), "005924\n");
+  let args = ["otp", "get", "--at=1234567860", "--offset=30", "1"];
+  assert_eq!(nitrocli.ok(&args), "005924\n");
+
+  assert_eq!(
+    nitrocli.err(&["otp", "get", "--at", "yesterday", "1"]),
+    "Invalid time: yesterday (expected an RFC 3339 timestamp or a Unix time)\n"
+  );
+  assert_eq!(
+    nitrocli.err(&["otp", "get", "--at", "59", "--validity", "1"]),
+    "--at cannot be combined with --fresh or --validity\n"
+  );
+}
+
+#[test]
+fn pass_type() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  let xdotool = nitrocli.dir.join("bin").join("xdotool");
+  fs::write(
+    &xdotool,
+    "#!/bin/sh\necho \"$*:$(cat)\" >> \"$HOME/typed\"\n",
+  )
+  .unwrap();
+  fs::set_permissions(&xdotool, fs::Permissions::from_mode(0o755)).unwrap();
+  let typed = nitrocli.dir.join("typed");
+
+  let output = nitrocli
+    .command(&["pass", "type", "mail"])
+    .env("DISPLAY", ":0")
+    .output()
+    .unwrap();
+  assert!(output.status.success(), "{:?}", output);
+  assert_eq!(output.stdout, b"");
+  assert_eq!(
+    fs::read_to_string(&typed).unwrap(),
+    "type --clearmodifiers --file -:me
+key --clearmodifiers Tab:
+type --clearmodifiers --file -:hunter2
+key --clearmodifiers Return:
+"
+  );
+
+  fs::remove_file(&typed).unwrap();
+  let output = nitrocli
+    .command(&["pass", "type", "mail"])
+    .env("DISPLAY", ":0")
+    .env("NITROCLI_AUTOTYPE_SEQUENCE", "{password}")
+    .output()
+    .unwrap();
+  assert!(output.status.success(), "{:?}", output);
+  assert_eq!(
+    fs::read_to_string(&typed).unwrap(),
+    "type --clearmodifiers --file -:hunter2\n"
+  );
+}
+
+#[test]
+fn hide_secrets() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  let xclip = nitrocli.dir.join("bin").join("xclip");
+  fs::write(&xclip, "#!/bin/sh\ncat > \"$HOME/clipboard\"\n").unwrap();
+  fs::set_permissions(&xclip, fs::Permissions::from_mode(0o755)).unwrap();
+  let run = |args: &[&str]| {
+    nitrocli
+      .command(args)
+      .env("DISPLAY", ":0")
+      .env("NITROCLI_HIDE_SECRETS", "true")
+      .output()
+      .unwrap()
+  };
+
+  let output = run(&["pass", "show", "mail"]);
+  assert!(output.status.success(), "{:?}", output);
+  assert_eq!(output.stdout, b"");
+  let clipboard = nitrocli.dir.join("clipboard");
+  assert_eq!(fs::read_to_string(&clipboard).unwrap(), "hunter2");
+
+  let output = run(&["otp", "get", "--hotp", "rfc"]);
+  assert_eq!(output.stdout, b"");
+  assert_eq!(fs::read_to_string(&clipboard).unwrap(), "755224");
+
+  let output = run(&["pass", "show", "--force-show", "mail"]);
+  assert_eq!(output.stdout, b"hunter2\nlogin: me\n");
+}
+
+#[test]
+fn pass_insert() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  let output = nitrocli.run_with_input(&["pass", "insert", "web"], b"secret\n");
+  assert_eq!(output.code, 0, "{:?}", output);
+  assert_eq!(nitrocli.ok(&["pass", "show", "web"]), "secret\n");
+
+  let output = nitrocli.run_with_input(&["pass", "insert", "mail"], b"other\n");
+  assert_eq!(output.code, 1);
+  assert_eq!(
+    output.stdout,
+    "An entry already exists for mail, use --force to overwrite it\n"
+  );
+
+  let output = nitrocli.run_with_input(&["pass", "insert", "--force", "mail"], b"other\n");
+  assert_eq!(output.code, 0, "{:?}", output);
+  assert_eq!(nitrocli.ok(&["pass", "show", "mail"]), "other\nlogin: me\n");
+}
+
+#[test]
+fn pass_insert_generate_passphrase() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  let passphrase = nitrocli.ok(&["pass", "insert", "--generate-passphrase", "web"]);
+  assert_eq!(passphrase.len(), 20, "{}", passphrase);
+  assert_eq!(passphrase.split('-').count(), 4);
+  assert_eq!(nitrocli.ok(&["pass", "show", "web"]), passphrase);
+
+  let args = ["pass", "insert", "--generate-passphrase=3", "news"];
+  let passphrase = nitrocli.ok(&args);
+  assert_eq!(passphrase.trim_end().split('-').count(), 3);
+
+  assert_eq!(
+    nitrocli.err(&["pass", "insert", "--generate-passphrase=5", "blog"]),
+    "Invalid number of words: 5 (expected 1 to 4)\n"
+  );
+}
+
+#[test]
+fn askpass() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  assert_eq!(nitrocli.ok(&["askpass", "Password for mail"]), "hunter2\n");
+  assert_eq!(
+    nitrocli.err(&["askpass"]),
+    "Usage: nitrocli askpass <prompt>\n"
+  );
+}
+
+#[test]
+fn pin_set_and_clear() {
+  let nitrocli = Nitrocli::new();
+  assert_eq!(nitrocli.ok(&["pin", "set", "user"]), "");
+  let error = nitrocli.err(&["--no-prompt", "pin", "set", "admin"]);
+  assert!(error.contains("Refusing to change the admin PIN without confirmation"));
+  assert_eq!(nitrocli.ok(&["pin", "set", "--force", "admin"]), "");
+  assert!(nitrocli.state().contains("\"user_retry_count\":3"));
+  assert_eq!(nitrocli.ok(&["clear"]), "");
+}
+
+#[test]
+fn pin_policy() {
+  let nitrocli = Nitrocli::new();
+  let policy = nitrocli.dir.join("policy.toml");
+  fs::write(&policy, "[pin-policy]\nmin-length = 8\n").unwrap();
+  let policy = policy.to_str().unwrap();
+  // The agent stub always enters 123456 as the new user PIN.
+  assert_eq!(
+    nitrocli.err(&["--policy-file", policy, "pin", "set", "user"]),
+    "The PIN does not satisfy the PIN policy: it is shorter than 8 characters\n"
+  );
+  assert_eq!(
+    nitrocli.ok(&["--policy-file", policy, "pin", "set", "--force", "admin"]),
+    ""
+  );
+
+  let output = nitrocli
+    .command(&["pin", "set", "user"])
+    .env("NITROCLI_PIN_POLICY_DENY", "123456,password")
+    .output()
+    .unwrap();
+  assert_eq!(output.status.code(), Some(1));
+  let error = String::from_utf8(output.stdout).unwrap();
+  assert!(error.ends_with("it is on the deny-list\n"), "{}", error);
+}
+
+#[test]
+fn config_show_effective() {
+  let nitrocli = Nitrocli::new();
+  let config = nitrocli.ok(&["config", "show-effective"]);
+  assert!(config.starts_with("# Configuration file: "));
+  assert!(config.contains("# model is not set\n"));
+
+  let output = nitrocli
+    .command(&["config", "show-effective"])
+    .env("NITROCLI_MODEL", "storage")
+    .output()
+    .unwrap();
+  let config = String::from_utf8(output.stdout).unwrap();
+  assert!(config.contains("model = \"storage\""), "{}", config);
+}
+
+#[test]
+fn alias() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  let dir = nitrocli.dir.join("xdg_config_home/nitrocli");
+  fs::create_dir_all(&dir).unwrap();
+  let config =
+    "[alias]\nhotp = \"otp get --algorithm hotp\"\nrfc = \"hotp rfc\"\nloop = \"loop\"\n";
+  fs::write(dir.join("config.toml"), config).unwrap();
+
+  assert_eq!(nitrocli.ok(&["rfc"]), "755224\n");
+  assert_eq!(nitrocli.ok(&["hotp", "0"]), "287082\n");
+  assert_eq!(
+    nitrocli.err(&["loop"]),
+    "The alias loop expands to itself\n"
+  );
+}
+
+#[test]
+fn audit_log() {
+  let nitrocli = Nitrocli::new();
+  let output = nitrocli
+    .command(&["status"])
+    .env("NITROCLI_AUDIT_TARGET", "file")
+    .output()
+    .unwrap();
+  assert!(output.status.success(), "{:?}", output);
+
+  let path = nitrocli.dir.join("xdg_state_home/nitrocli/audit.log");
+  let log = fs::read_to_string(path).unwrap();
+  assert_eq!(log.lines().count(), 1, "{}", log);
+  assert!(log.contains("\"command\":\"status\""), "{}", log);
+  assert!(log.contains("\"result\":\"ok\""), "{}", log);
+  assert!(log.contains("\"serial_number\":\""), "{}", log);
+}
+
+#[test]
+fn hooks() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  let hook = "echo \"$NITROCLI_COMMAND: $NITROCLI_SERIAL_NUMBERS\"";
+  let output = nitrocli
+    .command(&["status"])
+    .env("NITROCLI_HOOKS_PRE_STATUS", hook)
+    .output()
+    .unwrap();
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  assert!(stdout.starts_with("status: 0000c0de\n"), "{}", stdout);
+
+  // Commands not using a device do not connect to one for their hooks.
+  let output = nitrocli
+    .command(&["config", "show-effective"])
+    .env("NITROCLI_HOOKS_PRE_CONFIG", hook)
+    .output()
+    .unwrap();
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  assert!(stdout.starts_with("config: \n"), "{}", stdout);
+}
+
+#[test]
+fn timeout() {
+  let nitrocli = Nitrocli::new();
+  let start = time::Instant::now();
+  // The hook must not keep the output pipe open once nitrocli exited.
+  let output = nitrocli
+    .command(&["--timeout", "1", "status"])
+    .env("NITROCLI_HOOKS_PRE_STATUS", "exec sleep 10 >/dev/null 2>&1")
+    .output()
+    .unwrap();
+  assert!(start.elapsed() < time::Duration::from_secs(5));
+  assert_eq!(output.status.code(), Some(124), "{:?}", output);
+  assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+  assert_eq!(
+    String::from_utf8(output.stderr).unwrap(),
+    "The command status timed out after 1 seconds\n"
+  );
+}
+
+#[test]
+fn setup_device() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  assert_eq!(
+    nitrocli.err(&["setup", "device"]),
+    "The device setup requires a terminal and may not be used with --no-prompt\n"
+  );
+}
+
+#[test]
+fn setup_udev_print() {
+  let nitrocli = Nitrocli::new();
+  let rules = nitrocli.ok(&["setup", "udev", "--print"]);
+  assert!(rules.starts_with("# Nitrokey udev rules, installed by nitrocli\n"));
+}
+
+#[test]
+fn bench() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  let output = nitrocli.ok(&["bench", "--iterations", "2"]);
+  let mut lines = output.lines();
+  assert_eq!(
+    lines.next(),
+    Some("Nitrokey Storage 0000c0de, firmware v0.53, 2 iterations")
+  );
+  assert_eq!(lines.next(), Some(""));
+  let operations = lines
+    .map(|line| line.split_whitespace().next().unwrap())
+    .collect::<Vec<_>>();
+  assert_eq!(operations, ["operation", "connect", "status", "pws", "otp"]);
+  assert!(output.ends_with("otp       skipped, no TOTP slot is programmed\n"));
+
+  assert_eq!(
+    nitrocli.err(&["bench", "-n", "0"]),
+    "Invalid number of iterations: 0\n"
+  );
+}
+
+#[test]
+fn shell() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  let input = "pass show mail\n\n# A comment\npass show 'mail'\nstatsu\nshell\nexit\nstatus\n";
+  let output = nitrocli.run_with_input(&["shell"], input.as_bytes());
+  assert_eq!(output.code, 0, "{:?}", output);
+  let mut lines = output.stdout.lines();
+  assert_eq!(lines.next(), Some("hunter2"));
+  assert_eq!(lines.next(), Some("login: me"));
+  assert_eq!(lines.next(), Some("hunter2"));
+  assert_eq!(lines.next(), Some("login: me"));
+  assert_eq!(
+    lines.next(),
+    Some("Invalid command: statsu, did you mean status?")
+  );
+  assert_eq!(
+    lines.next().map(|line| line.starts_with("Available")),
+    Some(true)
+  );
+  assert_eq!(lines.next(), Some("Already running a shell"));
+  assert_eq!(lines.next(), None);
+
+  // The user PIN is only inquired once.
+  let log = fs::read_to_string(nitrocli.dir.join("agent.log")).unwrap();
+  assert_eq!(log.matches("User+PIN").count(), 1, "{}", log);
+}
+
+#[test]
+fn shell_script() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  let script = nitrocli.dir.join("script");
+  fs::write(
+    &script,
+    "otp set -a hotp 1 one\notp set -a hotp 2 two\nstatsu\n",
+  )
+  .unwrap();
+  let secrets = b"GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ\nGEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ\n";
+  let output = nitrocli.run_with_input(&["shell", script.to_str().unwrap()], secrets);
+  assert_eq!(output.code, 1, "{:?}", output);
+  assert!(
+    output.stdout.starts_with("Line 3 of ")
+      && output.stdout.contains("failed: Invalid command: statsu")
+  );
+  assert_eq!(nitrocli.ok(&["otp", "get", "--hotp", "two"]), "755224\n");
+
+  // The admin PIN is only inquired once.
+  let log = fs::read_to_string(nitrocli.dir.join("agent.log")).unwrap();
+  assert_eq!(log.matches("Admin+PIN").count(), 1, "{}", log);
+}
+
+#[test]
+fn completions() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  assert!(nitrocli
+    .ok(&["completions", "bash"])
+    .ends_with("complete -F _nitrocli nitrocli\n"));
+  assert!(nitrocli
+    .ok(&["completions", "zsh"])
+    .starts_with("#compdef nitrocli\n"));
+  assert!(nitrocli
+    .ok(&["completions", "fish"])
+    .contains("complete -c nitrocli -f\n"));
+  assert!(nitrocli
+    .err(&["completions", "tcsh"])
+    .starts_with("Invalid shell: tcsh"));
+
+  assert_eq!(nitrocli.ok(&["completions", "--list", "otp-slots"]), "");
+  let _ = nitrocli.ok(&["otp", "get", "--hotp", "rfc"]);
+  assert!(nitrocli
+    .ok(&["completions", "--list", "otp-slots"])
+    .contains("rfc\n"));
+}
+
+#[test]
+#[cfg(all(
+  feature = "extensions",
+  feature = "otp",
+  feature = "pws",
+  feature = "storage"
+))]
+fn generate_manpage() {
+  let nitrocli = Nitrocli::new();
+  assert_eq!(
+    nitrocli.ok(&["--generate-manpage"]),
+    include_str!("../doc/nitrocli.1"),
+    "doc/nitrocli.1 is outdated, regenerate it using make doc/nitrocli.1"
+  );
+}
+
+#[test]
+fn module() {
+  let nitrocli = Nitrocli::new();
+  let task = br#"{"encrypted_volume": "open", "user_pin": "123456"}"#;
+  let output = nitrocli.run_with_input(&["module"], task);
+  assert_eq!(output.code, 0, "{:?}", output);
+  assert!(output.stdout.contains("\"changed\":true"), "{:?}", output);
+  assert!(nitrocli
+    .state()
+    .contains("\"encrypted_volume_active\":true"));
+
+  let output = nitrocli.run_with_input(&["module"], task);
+  assert!(output.stdout.contains("\"changed\":false"), "{:?}", output);
+}
+
+#[test]
+fn vault() {
+  let nitrocli = Nitrocli::new();
+  let gpg = nitrocli.dir.join("bin").join("gpg");
+  fs::write(&gpg, GPG).unwrap();
+  fs::set_permissions(&gpg, fs::Permissions::from_mode(0o755)).unwrap();
+
+  let task = nitrocli.dir.join("task.json");
+  let encrypted = nitrocli.dir.join("task.json.gpg");
+  fs::write(
+    &task,
+    r#"{"encrypted_volume": "open", "user_pin": "123456"}"#,
+  )
+  .unwrap();
+  let output = nitrocli.ok(&["vault", "encrypt", "--remove", task.to_str().unwrap()]);
+  assert!(output.starts_with("Encrypted "), "{}", output);
+  assert!(!task.exists());
+  assert!(fs::read_to_string(&encrypted)
+    .unwrap()
+    .starts_with("-----BEGIN PGP MESSAGE-----\n"));
+
+  let output = nitrocli.ok(&["vault", "decrypt", encrypted.to_str().unwrap()]);
+  assert_eq!(
+    output,
+    r#"{"encrypted_volume": "open", "user_pin": "123456"}"#
+  );
+  let output = nitrocli.run(&["module", encrypted.to_str().unwrap()]);
+  assert_eq!(output.code, 0, "{:?}", output);
+  assert!(nitrocli
+    .state()
+    .contains("\"encrypted_volume_active\":true"));
+
+  let error = nitrocli.err(&["vault", "decrypt", nitrocli.device().to_str().unwrap()]);
+  assert!(
+    error.ends_with("device.json is not encrypted\n"),
+    "{}",
+    error
+  );
+}
+
+#[test]
+fn hidden_decoy() {
+  let nitrocli = Nitrocli::new();
+  let gpg = nitrocli.dir.join("bin").join("gpg");
+  fs::write(&gpg, GPG).unwrap();
+  fs::set_permissions(&gpg, fs::Permissions::from_mode(0o755)).unwrap();
+
+  let output = nitrocli.run_with_input(
+    &["hidden", "create", "--decoy", "1", "20", "60"],
+    b"decoy\n",
+  );
+  assert_eq!(output.code, 0, "{:?}", output);
+  let state = nitrocli.state();
+  assert!(state.contains("\"password\":\"decoy\""), "{}", state);
+  assert!(
+    state.contains("\"encrypted_volume_active\":true"),
+    "{}",
+    state
+  );
+  let decoys = nitrocli
+    .dir
+    .join("xdg_state_home")
+    .join("nitrocli")
+    .join("decoys.gpg");
+  assert_eq!(
+    fs::read_to_string(&decoys).unwrap(),
+    "-----BEGIN PGP MESSAGE-----\n0000c0de 1 decoy\n"
+  );
+
+  let _ = nitrocli.ok(&["close"]);
+  let _ = nitrocli.ok(&["open", "--panic"]);
+  let state = nitrocli.state();
+  assert!(state.contains("\"hidden_volume_active\":true"), "{}", state);
+  assert!(
+    state.contains("\"encrypted_volume_active\":false"),
+    "{}",
+    state
+  );
+
+  let _ = nitrocli.ok(&["hidden", "close"]);
+  assert!(nitrocli.state().contains("\"hidden_volume_active\":false"));
+  let output = nitrocli.run_with_input(&["hidden", "open"], b"wrong\n");
+  assert_eq!(output.code, 1, "{:?}", output);
+  assert!(
+    output.stdout.contains("Opening hidden volume failed"),
+    "{:?}",
+    output
+  );
+  let output = nitrocli.run_with_input(&["hidden", "open"], b"decoy\n");
+  assert_eq!(output.code, 0, "{:?}", output);
+  assert!(nitrocli.state().contains("\"hidden_volume_active\":true"));
+
+  let error = nitrocli.err(&["hidden", "create", "4", "0", "10"]);
+  assert!(error.contains("Invalid hidden volume slot: 4"), "{}", error);
+}
+
+#[test]
+fn native_messaging() {
+  let nitrocli = Nitrocli::with_state(STATE);
+  let request = br#"{"type":"pws","name":"mail","id":1}"#;
+  // Messages are prefixed with their length in native byte order.
+  let length = request.len() as u32;
+  let mut input = (0..4)
+    .map(|i| (length >> (8 * i)) as u8)
+    .collect::<Vec<_>>();
+  if cfg!(target_endian = "big") {
+    input.reverse();
+  }
+  input.extend_from_slice(request);
+
+  let output = nitrocli.run_with_input(&["native-messaging", "test@example.org"], &input);
+  assert_eq!(output.code, 0, "{:?}", output);
+  assert_eq!(
+    &output.stdout[4..],
+    r#"{"id":1,"login":"me","password":"hunter2"}"#
+  );
+}
+
+#[test]
+fn logging() {
+  let nitrocli = Nitrocli::new();
+  let output = nitrocli
+    .command(&["status"])
+    .env("NITROCLI_LOG", "warn,commands=debug")
+    .output()
+    .unwrap();
+  assert_eq!(output.status.code(), Some(0));
+  let stderr = String::from_utf8(output.stderr).unwrap();
+  assert!(
+    stderr.contains(" DEBUG nitrocli::commands: Connecting to a Nitrokey Storage"),
+    "{}",
+    stderr
+  );
+  assert!(
+    stderr.contains(" INFO  nitrocli::commands: Connected to Nitrokey Storage 0000c0de\n"),
+    "{}",
+    stderr
+  );
+  assert!(!stderr.contains("nitrocli::args"), "{}", stderr);
+
+  let output = nitrocli
+    .command(&["status"])
+    .env("NITROCLI_LOG", "commands=loud")
+    .output()
+    .unwrap();
+  assert_eq!(output.status.code(), Some(1));
+  assert_eq!(output.stdout, b"Invalid log directive: commands=loud\n");
+}
+
+#[test]
+fn device_lock() {
+  let nitrocli = Nitrocli::new();
+  let dir = nitrocli.dir.join("xdg_runtime_dir").join("nitrocli");
+  fs::create_dir_all(&dir).unwrap();
+  let path = dir.join("device-0000c0de.lock");
+  fs::write(&path, "4242").unwrap();
+  let file = fs::File::open(&path).unwrap();
+  assert_eq!(unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) }, 0);
+
+  let output = nitrocli
+    .command(&["status"])
+    .env("NITROCLI_LOCK_TIMEOUT", "0")
+    .output()
+    .unwrap();
+  assert_eq!(output.status.code(), Some(1));
+  assert_eq!(
+    String::from_utf8(output.stdout).unwrap(),
+    "The device 0000c0de is in use by PID 4242\n"
+  );
+
+  let child = nitrocli
+    .command(&["status"])
+    .env("NITROCLI_LOCK_TIMEOUT", "10")
+    .stdout(process::Stdio::piped())
+    .stderr(process::Stdio::piped())
+    .spawn()
+    .unwrap();
+  let pid = child.id();
+  thread::sleep(time::Duration::from_millis(500));
+  drop(file);
+  let output = child.wait_with_output().unwrap();
+  assert_eq!(output.status.code(), Some(0));
+  let stderr = String::from_utf8(output.stderr).unwrap();
+  assert!(
+    stderr.contains("Waiting for the device 0000c0de, which is in use by PID 4242"),
+    "{}",
+    stderr
+  );
+  assert_eq!(fs::read_to_string(&path).unwrap(), pid.to_string());
+}
+
+#[test]
+fn reuse_connection() {
+  let nitrocli = Nitrocli::new();
+  let output = nitrocli
+    .command(&["status"])
+    .env("NITROCLI_HOOKS_PRE_STATUS", "true")
+    .env("NITROCLI_HOOKS_POST_STATUS", "true")
+    .env("NITROCLI_LOG", "commands=debug")
+    .output()
+    .unwrap();
+  assert_eq!(output.status.code(), Some(0));
+  let stderr = String::from_utf8(output.stderr).unwrap();
+  assert_eq!(stderr.matches("Connected to").count(), 1, "{}", stderr);
+  assert_eq!(
+    stderr.matches("Reusing the connection").count(),
+    2,
+    "{}",
+    stderr
+  );
+}
+
+#[test]
+fn retry_transient_errors() {
+  let nitrocli = Nitrocli::with_state(r#"{"crc_errors": 2}"#);
+  let output = nitrocli
+    .command(&["status"])
+    .env("NITROCLI_RETRY_DELAY", "1")
+    .output()
+    .unwrap();
+  assert_eq!(output.status.code(), Some(0));
+  assert!(nitrocli.state().contains("\"crc_errors\":0"));
+
+  let nitrocli = Nitrocli::with_state(r#"{"crc_errors": 2}"#);
+  let output = nitrocli
+    .command(&["status"])
+    .env("NITROCLI_RETRY_ATTEMPTS", "2")
+    .env("NITROCLI_RETRY_DELAY", "1")
+    .output()
+    .unwrap();
+  assert_eq!(output.status.code(), Some(1));
+  assert_eq!(output.stdout, b"Getting Storage status failed: WrongCrc\n");
+}
@@ -100,5 +100,11 @@ fn main() {
         .file(version_source)
         .compile("libnitrokey.a");
 
-    println!("cargo:rustc-link-lib=hidapi-libusb");
+    // On macOS, hidapi talks to devices through IOKit and there is no
+    // libusb flavor of the library.
+    let hidapi = match env::var("CARGO_CFG_TARGET_OS") {
+        Ok(ref os) if os == "macos" => "hidapi",
+        _ => "hidapi-libusb",
+    };
+    println!("cargo:rustc-link-lib={}", hidapi);
 }